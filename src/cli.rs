@@ -1,15 +1,25 @@
-use clap::{ArgAction, Args, Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 use pixeldike::pixmap::Color;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
 /// Command-Line arguments as a well formatted struct, parsed using clap.
 #[derive(Parser, Debug, Clone)]
-#[command(author, version, about)]
+#[command(author, about, disable_version_flag = true)]
 pub(crate) struct CliOpts {
     #[command(subcommand)]
-    pub command: Command,
+    pub command: Option<Command>,
+
+    /// Print version information and exit
+    #[arg(short = 'V', long = "version")]
+    pub version: bool,
+
+    /// Together with --version, also print the target and enabled features this binary was built with
+    #[arg(long = "build-info", requires = "version")]
+    pub build_info: bool,
 
     /// Increase program verbosity
     ///
@@ -22,70 +32,562 @@ pub(crate) struct CliOpts {
     /// The default verbosity level is INFO.
     #[arg(short = 'q', long = "quiet", action = ArgAction::Count, default_value = "0")]
     pub quiet: u8,
+
+    /// Tune resource usage for the machine on which pixeldike runs
+    #[arg(
+        long = "runtime-profile",
+        env = "PIXELDIKE_RUNTIME_PROFILE",
+        value_enum,
+        default_value = "default",
+        global = true
+    )]
+    pub runtime_profile: Profile,
+
+    /// Load client options from a named profile in `~/.config/pixeldike/profiles.toml`
+    ///
+    /// The file contains one table per profile whose keys are option names, e.g. `server = "tcp://host:1234"`.
+    /// Options which are given on the command-line take precedence over the profile.
+    /// Resource usage is tuned with `--runtime-profile` instead.
+    #[arg(long = "profile", value_name = "NAME", global = true)]
+    pub profile: Option<String>,
+
+    /// How client commands and tools print their results
+    ///
+    /// With `json`, results are printed to stdout as a single JSON object while log lines keep going to stderr.
+    /// This must be given before the subcommand, e.g. `pixeldike --output json bench`.
+    #[arg(long = "output", value_enum, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+/// Formats in which results of client commands are printed
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object for consumption by scripts
+    Json,
+}
+
+/// Profiles for how many resources pixeldike should use
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Profile {
+    /// Use all CPU cores and generously sized buffers
+    Default,
+    /// Run everything on a single worker thread and use small buffers, e.g. for Raspberry Pis
+    LowPower,
+}
+
+/// Optional protocol features which `--grant` enables on `--hardened` servers
+#[cfg(feature = "server")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Grant {
+    /// Downloading many pixels at once via `STATE REGION`, `PXRECT` and batched `PX` reads
+    StateDumps,
+    /// Receiving pixel changes via `SUBSCRIBE`
+    Subscriptions,
+    /// Describing the protocol via `HELP`
+    Help,
+    /// Selecting one of the `--canvas` canvases via `CANVAS`
+    Canvases,
+    /// Coordinating as a team via `AUTH`, `RESERVE`, `CLAIM` and `CLAIMS`
+    Teams,
+    /// Looking up written pixels via `STATS`
+    Stats,
+}
+
+/// How `--slow-subscribers` are treated
+#[cfg(feature = "server")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum SlowSubscribers {
+    /// Drop the changes which the client missed and send it the current canvas instead
+    Resync,
+    /// Disconnect the client
+    Disconnect,
+}
+
+/// The kinds of sinks which can be attached to a canvas via `--sink-canvas`
+#[cfg(feature = "server")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum SinkKind {
+    /// The snapshot file of `--snapshot`
+    Snapshot,
+    /// The PNG snapshot of `--snapshot-png`
+    SnapshotPng,
+    /// The RTMP and RTSP streams
+    Stream,
+    /// The framebuffer device of `--fb-device`
+    Framebuffer,
+    /// All ambient lighting targets
+    Ambient,
+    /// The live window of `--open-window`
+    Window,
+    /// The animated image of `--timelapse`
+    Timelapse,
+    /// The change log of `--event-log`
+    EventLog,
+    /// The regions watched by `--webhook`
+    Webhook,
+    /// The image which `--dlna` offers to renderers
+    Dlna,
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub(crate) enum Command {
     /// Start a pixelflut server
-    Server(ServerOpts),
+    ///
+    /// Every option can also be set through a `PIXELDIKE_<OPTION>` environment variable, e.g. `PIXELDIKE_WIDTH=1920`.
+    /// Options which can be given multiple times take a space separated list,
+    /// e.g. `PIXELDIKE_LISTEN="tcp://0.0.0.0:1234 udp://0.0.0.0:1234"`.
+    /// Command-line arguments take precedence over environment variables.
+    #[cfg(feature = "server")]
+    Server(Box<ServerOpts>),
     /// Run a pixelflut client to project a colored rectangle onto a servers pixmap
     PutRectangle(PutRectangleData),
     /// Upload an image to a pixelflut server
     PutImage(PutImageData),
     /// Render a string onto the server (with transparent background)
     PutText(PutTextOpts),
+    /// Download the current canvas of a server and show it in the terminal or save it as an image
+    Show(ShowOpts),
+    /// Perform operator tasks on a running server via its control socket
+    Ctl(CtlOpts),
+    /// Stress an in-process server with many synthetic clients and report its throughput
+    #[cfg(feature = "server")]
+    Bench(BenchOpts),
+    /// Replay an event log which was recorded with `--event-log` into a server or an animated image
+    #[cfg(feature = "server")]
+    Replay(ReplayOpts),
+    /// List the pixelflut servers which announce themselves in the local network via mDNS
+    #[cfg(feature = "mdns")]
+    Discover(DiscoverOpts),
+    /// Generate an ed25519 key pair with which snapshots are signed
+    #[cfg(feature = "signing")]
+    Keygen(KeygenOpts),
+    /// Check that a snapshot or exported canvas was signed with a given key and not modified since
+    ///
+    /// Exits with a non-zero status if the signature does not match.
+    #[cfg(feature = "signing")]
+    Verify(VerifyOpts),
+    /// Print a shell completion script to stdout
+    Completions {
+        /// The shell for which completions are generated
+        shell: clap_complete::Shell,
+    },
+    /// Check a listen or ambient lighting url and describe how pixeldike interprets it
+    ///
+    /// Every part of the url is listed as either recognized or ignored so that typos in options which pixeldike
+    /// would otherwise only warn about (or silently drop) become visible.
+    ExplainUrl {
+        /// The url to explain, e.g. `tcp://0.0.0.0:1234?multiplex=true`
+        url: Url,
+    },
 }
 
+#[cfg(feature = "server")]
 #[derive(Args, Debug, Clone)]
 pub(crate) struct ServerOpts {
     /// Url on which to bind a server
     ///
-    /// Valid protocols are "tcp://", "udp://" and "ws://".
-    #[arg(long = "listen")]
+    /// Valid protocols are "tcp://", "tcps://", "udp://", "ws://", "http://", "unix://" and "unix-abstract://".
+    ///
+    /// "tcps://" listeners behave like "tcp://" listeners but encrypt connections with TLS. They require
+    /// `?cert=<path>` and `?key=<path>` query parameters naming PEM files with the certificate chain and private key.
+    ///
+    /// TCP listeners accept a `?multiplex=true` query parameter which enables channel-prefixed lines
+    /// (`@<channel> <command>`) so that one connection can carry several independent command streams.
+    ///
+    /// IPv6 addresses are written in brackets like "tcp://[::]:1234". Such a wildcard listener also accepts IPv4
+    /// connections unless the IPv4 wildcard is given on the same port as well.
+    /// Hostnames which resolve to multiple addresses are listened on at all of them.
+    ///
+    /// Unix socket listeners ("unix://") accept `mode` (octal), `owner` (uid) and `group` (gid) query parameters
+    /// which are applied to the created socket file.
+    /// On Linux, "unix-abstract://@<name>" listens on a socket in the abstract namespace which needs no socket file.
+    ///
+    /// All listeners tolerate `\r\n` line endings and extra whitespace between tokens unless the `?strict=true`
+    /// query parameter is given, in which case tokens must be separated by exactly one space.
+    ///
+    /// With the `?quiet=true` query parameter, clients start out in quiet mode in which no errors or
+    /// acknowledgements are sent. Clients can toggle this for their connection with `QUIET on|off`.
+    ///
+    /// The `?dialect=` query parameter restricts which form of the protocol a listener speaks so that a strictly
+    /// compatible port can be offered to legacy clients besides a feature-rich one. `extended` (the default) accepts
+    /// all commands, `classic` only the text commands `PX`, `SIZE` and `HELP` and `binary` only binary `PB` commands
+    /// as well as `SIZE` and `HELP`.
+    ///
+    /// "ws://" listeners speak the protocol on the `/ws` path, push the canvas and all of its changes on `/stream` and
+    /// push canvas statistics as JSON on `/stats` so that one port can serve bots as well as browser viewers.
+    /// `/stream?encoding=delta` sends the canvas and its changes as compact binary messages instead of text and
+    /// `/stream?encoding=delta&progressive=true` additionally sends a low resolution preview of the canvas first.
+    ///
+    /// "http://" listeners serve the canvas as `/canvas.png` together with its `/size`, `/stats`, `/reservations`,
+    /// advisory `/claims` (and a `/claims.png` map of them), `/activity.png` and latency `/metrics` for web dashboards.
+    /// `/canvas.raw` serves the raw RGB bytes of the canvas, brotli or gzip compressed if the client accepts it.
+    /// They also serve `/healthz` and `/readyz` endpoints for orchestrators like Kubernetes.
+    /// `/healthz` fails while the watchdog (see `--watchdog-timeout`) considers a task stalled and `/readyz`
+    /// additionally fails until all listeners and sinks have been started.
+    /// With the `?ws=true` query parameter, they additionally serve the routes of "ws://" listeners to clients which
+    /// upgrade their connection to a WebSocket so that a single port can be exposed behind a reverse proxy.
+    #[arg(long = "listen", env = "PIXELDIKE_LISTEN", value_delimiter = ' ')]
     pub listen: Vec<Url>,
 
     /// width of the pixmap
-    #[arg(short = 'x', long = "width", default_value = "800")]
+    #[arg(short = 'x', long = "width", env = "PIXELDIKE_WIDTH", default_value = "800")]
     pub width: usize,
 
     /// height of the pixmap
-    #[arg(short = 'y', long = "height", default_value = "600")]
+    #[arg(short = 'y', long = "height", env = "PIXELDIKE_HEIGHT", default_value = "600")]
     pub height: usize,
 
+    /// An additional canvas in the format `<name>=<width>x<height>` which clients select with `CANVAS <name>`
+    ///
+    /// The canvas given by `--width` and `--height` is called `default` and is used by clients until they select
+    /// another one. Sinks show the default canvas unless they are attached to another one with `--sink-canvas`.
+    /// HTTP and WebSocket listeners offer each canvas below `/canvas/<name>`, e.g. `/canvas/<name>/stream`.
+    #[arg(long = "canvas", env = "PIXELDIKE_CANVAS", value_delimiter = ' ', value_parser = parse_canvas)]
+    pub canvases: Vec<(String, (usize, usize))>,
+
+    /// Attach a sink to one of the additional canvases in the format `<sink>=<canvas>`, e.g. `framebuffer=stage`
+    ///
+    /// Valid sinks are `snapshot`, `snapshot-png`, `stream`, `framebuffer`, `ambient`, `window`, `timelapse`,
+    /// `event-log`, `webhook` and `dlna`.
+    /// Attached sinks keep running while the main canvas is resized via the control socket.
+    #[arg(long = "sink-canvas", env = "PIXELDIKE_SINK_CANVAS", value_delimiter = ' ', value_parser = parse_sink_canvas)]
+    pub sink_canvases: Vec<(SinkKind, String)>,
+
+    /// Maximum number of bytes which are buffered for a single tcp or unix socket client
+    ///
+    /// Defaults to 64KiB or to 8KiB with `--runtime-profile low-power`.
+    #[arg(long = "read-buffer-limit", env = "PIXELDIKE_READ_BUFFER_LIMIT")]
+    pub read_buffer_limit: Option<usize>,
+
+    /// Maximum number of bytes which are buffered for all clients of one tcp or unix socket listener combined
+    ///
+    /// Defaults to 64MiB or to 4MiB with `--runtime-profile low-power`.
+    #[arg(long = "read-buffer-budget", env = "PIXELDIKE_READ_BUFFER_BUDGET")]
+    pub read_buffer_budget: Option<usize>,
+
+    /// How many buffers and session states of disconnected tcp or unix socket clients are kept for reuse
+    ///
+    /// Reusing them avoids allocations when many short-lived clients connect.
+    /// The pool statistics are exported as `pixeldike_connection_pool_*` metrics to help with tuning.
+    /// Defaults to 256 or to 16 with `--runtime-profile low-power`.
+    #[arg(long = "connection-pool-size", env = "PIXELDIKE_CONNECTION_POOL_SIZE")]
+    pub connection_pool_size: Option<usize>,
+
+    /// Maximum number of response bytes which are queued for a single tcp, unix socket or websocket client
+    ///
+    /// Once that many are queued, the server sends them and waits until the client received them before handling
+    /// more of its requests, so clients which don't read their responses cannot make the server buffer them
+    /// indefinitely. This also limits how many subscription updates are batched into one write.
+    #[arg(
+        long = "write-queue-capacity",
+        env = "PIXELDIKE_WRITE_QUEUE_CAPACITY",
+        default_value = "1048576"
+    )]
+    pub write_queue_capacity: usize,
+
+    /// What happens to subscribed clients which cannot keep up with the changes of the canvas
+    #[arg(
+        long = "slow-subscribers",
+        env = "PIXELDIKE_SLOW_SUBSCRIBERS",
+        default_value = "resync"
+    )]
+    pub slow_subscribers: SlowSubscribers,
+
+    /// Maximum number of clients which one tcp or unix socket listener serves at once
+    ///
+    /// Clients which connect while the limit is reached receive an error message and are disconnected.
+    #[arg(long = "max-connections", env = "PIXELDIKE_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// Close tcp and unix socket connections which neither sent requests nor received changes for this many seconds
+    #[arg(long = "idle-timeout", env = "PIXELDIKE_IDLE_TIMEOUT")]
+    pub idle_timeout_secs: Option<u64>,
+
     #[command(flatten)]
     pub stream_opts: StreamOpts,
 
     #[command(flatten)]
     pub file_opts: FileOpts,
 
+    #[command(flatten)]
+    pub timelapse_opts: TimelapseOpts,
+
+    #[command(flatten)]
+    pub event_log_opts: EventLogOpts,
+
+    #[command(flatten)]
+    pub webhook_opts: WebhookOpts,
+
+    #[command(flatten)]
+    pub dlna_opts: DlnaOpts,
+
+    #[command(flatten)]
+    pub access_log_opts: AccessLogOpts,
+
     #[command(flatten)]
     pub fb_opts: FramebufferOpts,
 
+    #[command(flatten)]
+    pub ambient_opts: AmbientOpts,
+
+    #[command(flatten)]
+    pub watchdog_opts: WatchdogOpts,
+
+    #[command(flatten)]
+    pub effect_opts: EffectOpts,
+
+    /// Path at which a control socket for operator tasks (see `pixeldike ctl`) is created
+    #[arg(long = "control", env = "PIXELDIKE_CONTROL")]
+    pub control: Option<PathBuf>,
+
+    /// The origin of web pages (e.g. `https://viewer.example.org`) which may use the http and ws listeners
+    ///
+    /// By default pages of all origins may embed the canvas. Once an origin is given, browser requests from pages of
+    /// other origins are rejected while requests without an `Origin` header like those of bots are still served.
+    #[arg(
+        long = "allowed-origin",
+        env = "PIXELDIKE_ALLOWED_ORIGIN",
+        value_delimiter = ' '
+    )]
+    pub allowed_origins: Vec<String>,
+
+    /// A team in the format `<name>:<token>` which may reserve parts of the canvas for itself
+    ///
+    /// Clients authenticate as a team with `AUTH <name> <token>` and can then claim a rectangle with
+    /// `RESERVE <x> <y> <width> <height> <seconds>` in which only members of the team may set pixels until the
+    /// reservation expires.
+    #[arg(long = "team", env = "PIXELDIKE_TEAM", value_delimiter = ' ', value_parser = parse_team)]
+    pub teams: Vec<(String, String)>,
+
+    /// A limit on how many pixels each client may set per second inside a rectangle of the canvas
+    ///
+    /// The format is `<x>,<y>,<width>,<height>:<pixels-per-second>`, e.g. `350,250,100,100:50` to make the center of an
+    /// 800x600 canvas harder to claim. Limits are tracked per connection and thus don't affect UDP clients.
+    #[arg(long = "region-rate-limit", env = "PIXELDIKE_REGION_RATE_LIMIT", value_delimiter = ' ', value_parser = parse_region_rate_limit)]
+    pub region_limits: Vec<pixeldike::net::servers::RegionRateLimit>,
+
+    /// A rectangle in the format `<x>,<y>,<width>,<height>` which clients may not draw on, e.g. to protect a logo
+    ///
+    /// Writes into protected rectangles are silently dropped unless `--reject-protected-writes` is given.
+    #[arg(long = "protect", env = "PIXELDIKE_PROTECT", value_delimiter = ' ', value_parser = parse_protected_region)]
+    pub protected_regions: Vec<pixeldike::pixmap::ProtectedRegion>,
+
+    /// Answer writes into protected rectangles with an error instead of silently dropping them
+    #[arg(long = "reject-protected-writes", env = "PIXELDIKE_REJECT_PROTECTED_WRITES")]
+    pub reject_protected_writes: bool,
+
+    /// Allow clients to overwrite the whole canvas with `CLEAR` or `FILL <color>`
+    ///
+    /// The commands are refused while parts of the canvas are protected or reserved by another team.
+    #[arg(long = "allow-fill", env = "PIXELDIKE_ALLOW_FILL")]
+    pub allow_fill: bool,
+
+    /// Disable all optional protocol features unless they are granted via `--grant`, e.g. for servers which are
+    /// exposed directly to the internet
+    ///
+    /// Clients can then only identify themselves via `HELLO`, query the canvas size, get and set single pixels and
+    /// switch to quiet mode.
+    /// `CLEAR` and `FILL` still require `--allow-fill` and interfaces like HTTP listeners or the control socket are
+    /// only started when they are configured anyway.
+    #[arg(long = "hardened", env = "PIXELDIKE_HARDENED")]
+    pub hardened: bool,
+
+    /// Optional protocol features which clients of a `--hardened` server may use, as comma separated list
+    #[arg(
+        long = "grant",
+        env = "PIXELDIKE_GRANT",
+        value_enum,
+        value_delimiter = ',',
+        requires = "hardened"
+    )]
+    pub grants: Vec<Grant>,
+
+    /// The maximum number of seconds for which a team can reserve a part of the canvas at once
+    #[arg(
+        long = "max-reservation-secs",
+        env = "PIXELDIKE_MAX_RESERVATION_SECS",
+        default_value = "600"
+    )]
+    pub max_reservation_secs: u64,
+
+    /// Record per-command request handling latencies which can be retrieved with `pixeldike ctl metrics`
+    #[arg(long = "latency-metrics", env = "PIXELDIKE_LATENCY_METRICS")]
+    pub latency_metrics: bool,
+
+    /// Count the pixels which each client writes and export the busiest ones as metrics
+    ///
+    /// Clients can look up their own count with the `STATS` command and the HTTP server lists the busiest ones at
+    /// `/leaderboard`.
+    /// Memory stays bounded even when spoofed UDP datagrams pretend to come from countless clients because counts
+    /// are kept in a sketch of fixed size and only the busiest client networks are remembered by name.
+    #[arg(long = "client-stats", env = "PIXELDIKE_CLIENT_STATS")]
+    pub client_stats: bool,
+
+    /// The prefix length of the networks into which `--client-stats` groups IPv4 clients, e.g. 24 for `/24` networks
+    #[arg(
+        long = "client-stats-ipv4-prefix",
+        env = "PIXELDIKE_CLIENT_STATS_IPV4_PREFIX",
+        default_value = "32",
+        value_parser = clap::value_parser!(u8).range(0..=32)
+    )]
+    pub client_stats_ipv4_prefix: u8,
+
+    /// The prefix length of the networks into which `--client-stats` groups IPv6 clients
+    #[arg(
+        long = "client-stats-ipv6-prefix",
+        env = "PIXELDIKE_CLIENT_STATS_IPV6_PREFIX",
+        default_value = "64",
+        value_parser = clap::value_parser!(u8).range(0..=128)
+    )]
+    pub client_stats_ipv6_prefix: u8,
+
+    /// How many of the busiest client networks `--client-stats` remembers and exports by name
+    #[arg(
+        long = "client-stats-top",
+        env = "PIXELDIKE_CLIENT_STATS_TOP",
+        default_value = "32"
+    )]
+    pub client_stats_top: usize,
+
+    /// How many seconds pass between log messages about the busiest clients of `--client-stats`, 0 to disable them
+    #[arg(
+        long = "client-stats-log-interval",
+        env = "PIXELDIKE_CLIENT_STATS_LOG_INTERVAL",
+        default_value = "60"
+    )]
+    pub client_stats_log_interval_secs: u64,
+
+    /// The interval in milliseconds in which the color statistics of every canvas are recomputed
+    ///
+    /// The http and websocket `/stats` endpoints report the latest statistics and ambient lighting devices show
+    /// their dominant color.
+    #[arg(
+        long = "color-stats-interval",
+        env = "PIXELDIKE_COLOR_STATS_INTERVAL",
+        default_value = "1000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub color_stats_interval_ms: u64,
+
+    /// Announce the TCP listeners in the local network via mDNS so that `pixeldike discover` finds them
+    ///
+    /// The announcement includes the size of the canvas.
+    #[cfg(feature = "mdns")]
+    #[arg(long = "mdns", env = "PIXELDIKE_MDNS")]
+    pub mdns: bool,
+
+    /// The name under which `--mdns` announces the server, e.g. the name of the event
+    #[cfg(feature = "mdns")]
+    #[arg(long = "mdns-name", env = "PIXELDIKE_MDNS_NAME", default_value = "pixeldike")]
+    pub mdns_name: String,
+
+    /// Keep track of when each pixel was last written, e.g. to spot active bots
+    ///
+    /// In the window opened by `--open-window`, pressing `A` toggles a view of this activity.
+    #[arg(long = "track-activity", env = "PIXELDIKE_TRACK_ACTIVITY")]
+    pub track_activity: bool,
+
+    /// Reset every pixel to the background color once it was not written for this many seconds
+    ///
+    /// This makes the canvas clean itself up, e.g. for installations which run unattended for weeks.
+    /// Pixels are reset up to a sixteenth of this time late. Protected regions are never reset.
+    #[arg(
+        long = "pixel-ttl",
+        env = "PIXELDIKE_PIXEL_TTL",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub pixel_ttl_secs: Option<u64>,
+
+    /// The color as hex RGB value to which pixels are reset after `--pixel-ttl`
+    #[arg(
+        long = "pixel-ttl-background",
+        env = "PIXELDIKE_PIXEL_TTL_BACKGROUND",
+        default_value = "000000",
+        requires = "pixel_ttl_secs",
+        value_parser = parse_hex_color
+    )]
+    pub pixel_ttl_background: Color,
+
+    /// How many seconds it takes for a written pixel to fade out of the activity view
+    #[arg(
+        long = "activity-decay",
+        env = "PIXELDIKE_ACTIVITY_DECAY",
+        default_value = "30"
+    )]
+    pub activity_decay_secs: u64,
+
+    /// Render the canvas live in a window
+    ///
+    /// The window can be resized freely. Pressing `F` in it toggles fullscreen mode and `Q` closes it which stops
+    /// the server.
     #[cfg(feature = "windowing")]
-    #[arg(long = "open-window")]
+    #[arg(long = "open-window", env = "PIXELDIKE_OPEN_WINDOW")]
     pub open_window: bool,
+
+    /// The size with which the window of `--open-window` is opened instead of the size of the canvas
+    #[cfg(feature = "windowing")]
+    #[arg(
+        long = "window-size",
+        value_name = "WIDTHxHEIGHT",
+        value_parser = parse_frame_size,
+        requires = "open_window"
+    )]
+    pub window_size: Option<(usize, usize)>,
+
+    /// How the canvas is scaled when the window has a different size
+    #[cfg(feature = "windowing")]
+    #[arg(long = "window-filter", value_enum, default_value = "nearest")]
+    pub window_filter: WindowFilter,
+
+    /// Stretch the canvas over the whole window instead of keeping its aspect ratio with black bars
+    #[cfg(feature = "windowing")]
+    #[arg(long = "window-stretch")]
+    pub window_stretch: bool,
+
+    /// Open the window of `--open-window` in fullscreen mode
+    #[cfg(feature = "windowing")]
+    #[arg(long = "fullscreen", requires = "open_window")]
+    pub fullscreen: bool,
+}
+
+/// Filters with which the window of `--open-window` scales the canvas
+#[cfg(feature = "windowing")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum WindowFilter {
+    /// Show the closest canvas pixel which keeps pixels crisp
+    Nearest,
+    /// Interpolate between neighbouring canvas pixels which looks smoother
+    Bilinear,
 }
 
+#[cfg(feature = "server")]
 /// Specific options for sinking the pixmap data into something else (e.g. streaming it somewhere)
 #[derive(Args, Debug, Clone)]
 pub(crate) struct StreamOpts {
     /// An RTMP url to which pixmap data should be streamed
     ///
     /// Must be in a form understood by ffmpeg i.e. `rtmp://[username:password@]server[:port][/app][/instance][/playpath]`
-    #[arg(long = "rtmp-stream")]
+    #[arg(long = "rtmp-stream", env = "PIXELDIKE_RTMP_STREAM")]
     pub rtmp_dst_addr: Option<String>,
 
     /// An RTSP url to which pixmap data should be streamed
     ///
     /// Must be in a form understood by ffmpeg i.e. `rtsp://hostname[:port]/path`
-    #[arg(long = "rtsp-stream")]
+    #[arg(long = "rtsp-stream", env = "PIXELDIKE_RTSP_STREAM")]
     pub rtsp_dst_addr: Option<String>,
 
     /// The target framerate with which the pixmap stream should be emitted
-    #[arg(long = "stream-framerate", default_value = "30")]
+    #[arg(
+        long = "stream-framerate",
+        env = "PIXELDIKE_STREAM_FRAMERATE",
+        default_value = "30"
+    )]
     pub framerate: usize,
 }
 
+#[cfg(feature = "server")]
 /// Specific options regarding snapshot files
 #[derive(Args, Debug, Clone)]
 pub(crate) struct FileOpts {
@@ -93,34 +595,348 @@ pub(crate) struct FileOpts {
     ///
     /// If the stored snapshot has different dimensions than the ones given via --width and --height, the snapshot is
     /// not loaded and an empty canvas is created instead.
-    #[arg(long = "load-snapshot")]
+    ///
+    /// Both snapshot files and PNG images can be loaded.
+    ///
+    /// Use `-` to read snapshots from stdin. The last snapshot is loaded once stdin is closed.
+    #[arg(
+        long = "load-snapshot",
+        env = "PIXELDIKE_LOAD_SNAPSHOT",
+        group = "initial_canvas"
+    )]
     pub load_snapshot: Option<PathBuf>,
 
+    /// Another pixelflut server from which the initial canvas content is downloaded, e.g. `tcp://old-host:1234`
+    ///
+    /// This simplifies moving an event to a different host.
+    /// The canvas is downloaded in chunks via `STATE REGION` or pixel by pixel if the other server doesn't support
+    /// that.
+    /// Like with `--load-snapshot`, the canvas stays empty if the other server's canvas has different dimensions.
+    #[arg(
+        long = "bootstrap-from",
+        env = "PIXELDIKE_BOOTSTRAP_FROM",
+        group = "initial_canvas"
+    )]
+    pub bootstrap_from: Option<Url>,
+
+    /// Spread the first frame of all sinks over this many seconds after a snapshot was loaded or bootstrapped
+    ///
+    /// This avoids a spike of CPU and bandwidth usage at startup on constrained hosts.
+    /// When using `--watchdog-timeout`, keep the warm-up shorter than the timeout.
+    #[arg(
+        long = "warm-up",
+        env = "PIXELDIKE_WARM_UP",
+        default_value = "0",
+        requires = "initial_canvas"
+    )]
+    pub warm_up_secs: u64,
+
     /// A path into which snapshots are stored
-    #[arg(long = "snapshot")]
+    ///
+    /// Use `-` to continuously append snapshots to stdout, e.g. to pipe them into other tools.
+    #[arg(long = "snapshot", env = "PIXELDIKE_SNAPSHOT", alias = "snapshot-file")]
     pub snapshot_file: Option<PathBuf>,
 
+    /// A path into which snapshots are stored as PNG images
+    ///
+    /// This can be combined with `--snapshot` and uses the same interval.
+    /// Use `-` to continuously append images to stdout.
+    #[arg(long = "snapshot-png", env = "PIXELDIKE_SNAPSHOT_PNG")]
+    pub snapshot_png: Option<PathBuf>,
+
     /// The interval in seconds with which snapshots are written to disk
-    #[arg(long = "snapshot-interval", default_value = "5")]
+    #[arg(
+        long = "snapshot-interval",
+        env = "PIXELDIKE_SNAPSHOT_INTERVAL",
+        default_value = "5"
+    )]
     pub snapshot_interval_secs: usize,
+
+    /// A file containing an ed25519 secret key with which every snapshot is signed
+    ///
+    /// The signature is stored next to each snapshot file with an additional `.sig` extension and can be checked
+    /// with `pixeldike verify`. Generate a key pair with `pixeldike keygen`.
+    #[cfg(feature = "signing")]
+    #[arg(long = "snapshot-signing-key", env = "PIXELDIKE_SNAPSHOT_SIGNING_KEY")]
+    pub snapshot_signing_key: Option<PathBuf>,
+}
+
+#[cfg(feature = "server")]
+/// Specific options for recording a timelapse of the canvas
+#[derive(Args, Debug, Clone)]
+pub(crate) struct TimelapseOpts {
+    /// A path at which an animated image of the canvas is recorded
+    ///
+    /// The format is chosen by the file extension, ".gif" records an animated GIF and ".png" or ".apng" an
+    /// animated PNG which keeps all colors exactly. An existing file, e.g. the recording from before the canvas was
+    /// resized, is moved to a numbered file next to it like `recap.1.gif`.
+    #[arg(long = "timelapse", env = "PIXELDIKE_TIMELAPSE")]
+    pub timelapse: Option<PathBuf>,
+
+    /// The interval in seconds with which frames are captured
+    #[arg(
+        long = "timelapse-interval",
+        env = "PIXELDIKE_TIMELAPSE_INTERVAL",
+        default_value = "60"
+    )]
+    pub timelapse_interval_secs: u64,
+
+    /// How many milliseconds each frame is shown when the timelapse is played
+    #[arg(
+        long = "timelapse-frame-delay",
+        env = "PIXELDIKE_TIMELAPSE_FRAME_DELAY",
+        default_value = "100"
+    )]
+    pub timelapse_frame_delay_ms: u64,
+
+    /// Stop recording after this many frames
+    #[arg(long = "timelapse-max-frames", env = "PIXELDIKE_TIMELAPSE_MAX_FRAMES")]
+    pub timelapse_max_frames: Option<usize>,
+
+    /// How often the timelapse is played by viewers, 0 plays it forever
+    #[arg(
+        long = "timelapse-loops",
+        env = "PIXELDIKE_TIMELAPSE_LOOPS",
+        default_value = "0"
+    )]
+    pub timelapse_loops: u16,
+}
+
+#[cfg(feature = "server")]
+/// Specific options for recording the history of the canvas
+#[derive(Args, Debug, Clone)]
+pub(crate) struct EventLogOpts {
+    /// A path at which every change of the canvas is recorded together with its time, see `pixeldike replay`
+    ///
+    /// An existing log of a canvas with the same size is continued while one of a canvas with a different size is
+    /// moved to a numbered file next to it like `canvas.1.evlog`.
+    #[arg(long = "event-log", env = "PIXELDIKE_EVENT_LOG")]
+    pub event_log: Option<PathBuf>,
+
+    /// The interval in seconds with which recorded changes are written to the file at the latest
+    #[arg(
+        long = "event-log-flush-interval",
+        env = "PIXELDIKE_EVENT_LOG_FLUSH_INTERVAL",
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub event_log_flush_interval_secs: u64,
+}
+
+/// Specific options for notifying a webhook about changed regions of the canvas
+#[cfg(feature = "server")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct WebhookOpts {
+    /// An "http://" url to which a PNG image of a watched region is posted when it changed and then stabilized
+    ///
+    /// The name and position of the region are sent in the `X-Pixeldike-Region` and `X-Pixeldike-Rect` headers.
+    #[arg(long = "webhook", env = "PIXELDIKE_WEBHOOK", requires = "webhook_regions")]
+    pub webhook: Option<Url>,
+
+    /// A region in the format `<name>=<x>,<y>,<width>,<height>` which is watched for `--webhook`
+    #[arg(
+        long = "webhook-region",
+        env = "PIXELDIKE_WEBHOOK_REGION",
+        value_delimiter = ' ',
+        value_parser = parse_webhook_region,
+        requires = "webhook"
+    )]
+    pub webhook_regions: Vec<pixeldike::sinks::webhook::WebhookRegion>,
+
+    /// How many seconds a watched region must stay unchanged after a change before the webhook is notified
+    #[arg(
+        long = "webhook-settle",
+        env = "PIXELDIKE_WEBHOOK_SETTLE",
+        default_value = "10"
+    )]
+    pub webhook_settle_secs: u64,
+}
+
+/// Specific options for offering the canvas to smart TVs and other DLNA renderers in the local network
+#[cfg(feature = "server")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct DlnaOpts {
+    /// Announce the canvas as DLNA media server via SSDP and serve its content from this address, e.g. `0.0.0.0:8200`
+    ///
+    /// Renderers list the server under `--dlna-name` and can show the current canvas as image.
+    /// Chromecast devices don't support DLNA and are not reached this way.
+    #[arg(long = "dlna", env = "PIXELDIKE_DLNA")]
+    pub dlna: Option<std::net::SocketAddr>,
+
+    /// The name under which `--dlna` announces the canvas
+    #[arg(
+        long = "dlna-name",
+        env = "PIXELDIKE_DLNA_NAME",
+        default_value = "pixeldike",
+        requires = "dlna"
+    )]
+    pub dlna_name: String,
+
+    /// The url of a live stream of the canvas which `--dlna` offers next to the image, e.g. an HLS playlist which is
+    /// published from the `--rtmp-stream`
+    #[arg(long = "dlna-stream", env = "PIXELDIKE_DLNA_STREAM", requires = "dlna")]
+    pub dlna_stream: Option<Url>,
+}
+
+/// Specific options for recording client connections in an access log
+#[cfg(feature = "server")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct AccessLogOpts {
+    /// A file in which every connection of a tcp, unix socket or ws client is logged together with what the client
+    /// did, e.g. to investigate abuse after an event
+    ///
+    /// UDP clients have no connections and are not logged.
+    #[arg(long = "access-log", env = "PIXELDIKE_ACCESS_LOG")]
+    pub access_log: Option<PathBuf>,
+
+    /// The format of the lines in the `--access-log`
+    #[arg(
+        long = "access-log-format",
+        env = "PIXELDIKE_ACCESS_LOG_FORMAT",
+        value_enum,
+        default_value = "common",
+        requires = "access_log"
+    )]
+    pub access_log_format: AccessLogFormat,
+
+    /// Rotate the `--access-log` once it grows larger than this many bytes
+    #[arg(
+        long = "access-log-max-size",
+        env = "PIXELDIKE_ACCESS_LOG_MAX_SIZE",
+        requires = "access_log"
+    )]
+    pub access_log_max_size: Option<u64>,
+
+    /// Rotate the `--access-log` after this many seconds
+    #[arg(
+        long = "access-log-rotate-secs",
+        env = "PIXELDIKE_ACCESS_LOG_ROTATE_SECS",
+        requires = "access_log"
+    )]
+    pub access_log_rotate_secs: Option<u64>,
+
+    /// How many rotated files of the `--access-log` are kept as `<path>.1` to `<path>.<n>`
+    #[arg(
+        long = "access-log-keep",
+        env = "PIXELDIKE_ACCESS_LOG_KEEP",
+        default_value = "5",
+        requires = "access_log"
+    )]
+    pub access_log_keep: usize,
+}
+
+/// Formats of the `--access-log`
+#[cfg(feature = "server")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum AccessLogFormat {
+    /// Lines similar to the common log format of web servers with additional `key=value` fields
+    Common,
+    /// One JSON object per line
+    Json,
 }
 
+#[cfg(feature = "server")]
 /// Specific options for rendering onto a framebuffer
 #[derive(Args, Debug, Clone)]
 pub(crate) struct FramebufferOpts {
     /// A framebuffer device onto which pixmap data should be rendered
-    #[arg(long = "fb-device")]
+    #[arg(long = "fb-device", env = "PIXELDIKE_FB_DEVICE")]
     pub fb_device: Option<PathBuf>,
 
     /// The target framerate which the framebuffer rendering should target
-    #[arg(long = "fb-framerate", default_value = "30")]
+    #[arg(long = "fb-framerate", env = "PIXELDIKE_FB_FRAMERATE", default_value = "30")]
     pub fb_framerate: usize,
 }
 
+#[cfg(feature = "server")]
+/// Specific options for driving ambient lighting devices
+#[derive(Args, Debug, Clone)]
+pub(crate) struct AmbientOpts {
+    /// A lighting device which should be driven with the canvas colors
+    ///
+    /// Valid forms are "wled://<host>[:port][?leds=<n>]" for WLED controllers (using the UDP realtime protocol) and
+    /// "hue://<api-username>@<bridge>[:port]/<light-id>" for Philips Hue lights.
+    /// WLED strips with more than one LED are driven ambilight style with the colors of the canvas edges.
+    #[arg(long = "ambient", env = "PIXELDIKE_AMBIENT", value_delimiter = ' ')]
+    pub ambient: Vec<Url>,
+
+    /// The interval in milliseconds in which ambient lighting devices are updated
+    #[arg(
+        long = "ambient-interval",
+        env = "PIXELDIKE_AMBIENT_INTERVAL",
+        default_value = "200"
+    )]
+    pub ambient_interval_ms: u64,
+}
+
+#[cfg(feature = "server")]
+/// Specific options for showing the canvas through visual effects
+#[derive(Args, Debug, Clone)]
+pub(crate) struct EffectOpts {
+    /// Show the canvas through a visual effect on the stream, framebuffer, window, ambient, timelapse and DLNA sinks
+    ///
+    /// Valid effects are `none`, `mirror-horizontal`, `mirror-vertical`, `quadrants`, `kaleidoscope[:<segments>]`
+    /// (6 segments by default) and `rotation[:<folds>]` (4 folds by default).
+    /// Clients keep drawing on the unchanged canvas which snapshots, webhooks and listeners also serve.
+    /// Sinks which are attached to another canvas with `--sink-canvas` are not affected.
+    /// The effect can be changed at runtime with `pixeldike ctl effect`, so `none` enables this without an effect.
+    #[arg(long = "effect", env = "PIXELDIKE_EFFECT")]
+    pub effect: Option<pixeldike::pixmap::Effect>,
+
+    /// How many times per second the effect is rendered
+    #[arg(
+        long = "effect-fps",
+        env = "PIXELDIKE_EFFECT_FPS",
+        default_value = "30",
+        requires = "effect",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub effect_fps: u32,
+}
+
+#[cfg(feature = "server")]
+/// Specific options for detecting stalled background tasks
+#[derive(Args, Debug, Clone)]
+pub(crate) struct WatchdogOpts {
+    /// Enable a watchdog which reacts when a sink or the listeners make no progress for this many seconds
+    ///
+    /// Sinks are allowed this much time in addition to their usual interval, e.g. the snapshot interval.
+    #[arg(long = "watchdog-timeout", env = "PIXELDIKE_WATCHDOG_TIMEOUT")]
+    pub watchdog_timeout_secs: Option<u64>,
+
+    /// What the watchdog does when it detects a stalled task
+    #[arg(
+        long = "watchdog-action",
+        env = "PIXELDIKE_WATCHDOG_ACTION",
+        default_value = "log",
+        requires = "watchdog_timeout_secs"
+    )]
+    pub watchdog_action: WatchdogAction,
+
+    /// An "http://" url to which the watchdog posts a JSON alert whenever a task stalls or recovers
+    #[arg(
+        long = "watchdog-webhook",
+        env = "PIXELDIKE_WATCHDOG_WEBHOOK",
+        requires = "watchdog_timeout_secs"
+    )]
+    pub watchdog_webhook: Option<Url>,
+}
+
+#[cfg(feature = "server")]
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum WatchdogAction {
+    /// Only log an error
+    Log,
+    /// Exit with a non-zero status so that a service manager like systemd restarts the server
+    Restart,
+}
+
 /// Arguments common to all client commands
 #[derive(Args, Debug, Clone)]
 pub(crate) struct CommonClientOps {
     /// Address of the pixelflut server
+    ///
+    /// TLS servers are reached with "tcps://host:port?ca=<path>" where the PEM file contains the trusted certificates.
     #[arg(short = 's', long = "server")]
     pub server: Url,
     /// The width of the rectangle that should be drawn
@@ -142,6 +958,71 @@ pub(crate) struct CommonClientOps {
     /// Only draw the rectangle once
     #[arg(long = "once", action = ArgAction::SetFalse)]
     pub do_loop: bool,
+    /// Simulate a bad network link by delaying and dropping commands
+    ///
+    /// Given as comma separated list of `latency=<duration>`, `jitter=<duration>` and `loss=<percent>%`
+    /// e.g. `latency=50ms,loss=1%`.
+    #[arg(long = "simulate")]
+    pub simulate: Option<Impairment>,
+    /// Set pixels with the binary `PB` command which is faster to parse but not supported by all servers
+    #[arg(long = "binary", conflicts_with = "simulate")]
+    pub binary: bool,
+    /// The order in which the prepared commands are sent
+    #[arg(long = "order", default_value = "sequential")]
+    pub order: CommandOrder,
+    /// Read back the last written pixel after every N commands
+    ///
+    /// The responses are received after each send which makes the client look less like a pure flood.
+    /// Responses over UDP are not awaited.
+    #[arg(long = "interleave-reads", value_name = "N", conflicts_with = "simulate")]
+    pub interleave_reads: Option<NonZeroUsize>,
+    /// Wait a random duration of up to this long before every send, e.g. `5ms`
+    #[arg(long = "send-jitter", value_parser = parse_duration)]
+    pub send_jitter: Option<Duration>,
+    /// Download the target region before the first send and skip pixels which already have the right color
+    ///
+    /// This makes re-running a client after a brief disconnect nearly free instead of repainting everything.
+    /// Later sends of a looping client repaint the whole region again. Not supported over UDP.
+    #[arg(long = "resume")]
+    pub resume: bool,
+    /// Open this many connections and split the commands between them so that they are sent in parallel
+    ///
+    /// Animations and frames from stdin are always sent over a single connection.
+    #[arg(long = "connections", default_value = "1")]
+    pub connections: NonZeroUsize,
+    /// The MTU of the network path to a UDP server including IP and UDP headers
+    ///
+    /// Commands are split into datagrams which fit into it so that they are not fragmented.
+    /// By default, the path MTU which the operating system discovered is used on Linux and 1400 bytes elsewhere.
+    #[arg(long = "mtu")]
+    pub mtu: Option<usize>,
+    /// Wait this long between sending two UDP datagrams, e.g. `100us`
+    #[arg(long = "udp-pacing", value_parser = parse_duration)]
+    pub udp_pacing: Option<Duration>,
+    /// Send at most this many UDP datagrams per second, including duplicates, so that the server is not overwhelmed
+    ///
+    /// This is an alternative to `--udp-pacing` which is easier to derive from what the server can handle.
+    #[arg(long = "pps", conflicts_with = "udp_pacing")]
+    pub pps: Option<NonZeroU32>,
+    /// Send every UDP datagram this many additional times so that fewer commands are lost on lossy networks
+    #[arg(long = "udp-duplicates", default_value = "0")]
+    pub udp_duplicates: usize,
+    /// Log the achieved pixels, bytes and loops per second in this interval, e.g. `1s`
+    ///
+    /// This helps to tune `--connections` and pacing options to what the network and server can take.
+    #[arg(long = "stats-interval", value_parser = parse_duration)]
+    pub stats_interval: Option<Duration>,
+}
+
+/// Orders in which clients send their prepared commands
+#[derive(ValueEnum, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum CommandOrder {
+    /// In the order in which they were generated, e.g. row by row
+    Sequential,
+    /// In a random order which is chosen once whenever the commands are prepared
+    Shuffle,
+    /// In a new random order for every send
+    ShufflePerSend,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -152,8 +1033,20 @@ pub(crate) struct PutRectangleData {
     /// The color which the rectangle should have.
     ///
     /// Available values are 'random', 'random-per-iteration' or a specific hex encoded color.
+    /// Gradients start with this color.
     #[arg(long = "color", default_value = "random")]
     pub color: TargetColor,
+
+    /// How the pixels of the rectangle are colored, as `MODE[:KEY=VALUE,...]`
+    ///
+    /// Available modes are
+    /// 'solid' which draws `--color`,
+    /// 'noise[:animate=true]' which draws random pixels that change every iteration if animated,
+    /// 'value-noise[:scale=PIXELS]' which draws smooth random colors with features of about the given size,
+    /// 'gradient[:to=COLOR,direction=horizontal|vertical|diagonal]' which fades from `--color` to another color
+    /// and 'rainbow[:step=DEGREES]' which draws a rainbow that shifts its hues by the given amount every iteration.
+    #[arg(long = "fill", default_value = "solid")]
+    pub fill: FillMode,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -162,8 +1055,28 @@ pub(crate) struct PutImageData {
     pub common: CommonClientOps,
 
     /// Path to an image file that should be uploaded
-    #[arg(short = 'f', long = "file")]
-    pub path: PathBuf,
+    #[arg(short = 'f', long = "file", required_unless_present = "stdin_rgba")]
+    pub path: Option<PathBuf>,
+
+    /// Read a stream of raw RGBA frames with the given size from stdin instead of uploading an image file
+    ///
+    /// Every frame consists of WIDTH * HEIGHT pixels of 4 bytes each, ordered row by row.
+    /// Only pixels which changed since the previous frame are sent to the server and pixels which are not fully
+    /// opaque are blended onto the canvas.
+    /// The client exits once stdin is closed.
+    #[arg(
+        long = "stdin-rgba",
+        value_name = "WIDTHxHEIGHT",
+        value_parser = parse_frame_size,
+        conflicts_with = "path"
+    )]
+    pub stdin_rgba: Option<(usize, usize)>,
+
+    /// Play animated GIFs at this many frames per second instead of their own frame timing
+    ///
+    /// Animations are played repeatedly unless `--once` is given.
+    #[arg(long = "fps", requires = "path")]
+    pub fps: Option<f64>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -180,6 +1093,347 @@ pub(crate) struct PutTextOpts {
     pub color: TargetColor,
 }
 
+#[derive(Args, Debug, Clone)]
+pub(crate) struct ShowOpts {
+    /// Address of the pixelflut server
+    #[arg(short = 's', long = "server")]
+    pub server: Url,
+
+    /// Save the canvas into an image file instead of rendering it in the terminal
+    ///
+    /// The image format is determined by the file extension.
+    #[arg(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// How many terminal columns the rendered canvas should span
+    #[arg(long = "columns", default_value = "80")]
+    pub columns: usize,
+}
+
+#[cfg(feature = "mdns")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct DiscoverOpts {
+    /// How long to listen for announcements, e.g. `3s`
+    #[arg(long = "duration", default_value = "3s", value_parser = parse_duration)]
+    pub duration: Duration,
+}
+
+#[cfg(feature = "signing")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct KeygenOpts {
+    /// The file into which the secret key is written, it is only readable by the current user
+    #[arg(long = "secret-key")]
+    pub secret_key: PathBuf,
+
+    /// The file into which the public key is written, it is printed to stdout if omitted
+    #[arg(long = "public-key")]
+    pub public_key: Option<PathBuf>,
+}
+
+#[cfg(feature = "signing")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct VerifyOpts {
+    /// The signed file
+    pub file: PathBuf,
+
+    /// The public key of the signer, either as base64 text or as a path to a file containing it
+    #[arg(long = "public-key")]
+    pub public_key: String,
+
+    /// The file containing the signature, defaults to the signed file with an additional `.sig` extension
+    #[arg(long = "signature")]
+    pub signature: Option<PathBuf>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Args, Debug, Clone)]
+pub(crate) struct BenchOpts {
+    /// Width of the benchmarked canvas
+    #[arg(long = "width", default_value = "800")]
+    pub width: usize,
+
+    /// Height of the benchmarked canvas
+    #[arg(long = "height", default_value = "600")]
+    pub height: usize,
+
+    /// Number of clients which set random pixels as fast as possible
+    #[arg(long = "flooders", default_value = "16")]
+    pub flooders: usize,
+
+    /// Number of clients which slowly set single pixels
+    #[arg(long = "painters", default_value = "1000")]
+    pub painters: usize,
+
+    /// Number of clients which read random pixels
+    #[arg(long = "readers", default_value = "100")]
+    pub readers: usize,
+
+    /// Number of clients which periodically download the whole canvas
+    #[arg(long = "subscribers", default_value = "10")]
+    pub subscribers: usize,
+
+    /// How long the load should be generated, e.g. `30s` or `3600s`
+    #[arg(long = "duration", default_value = "10s", value_parser = parse_duration)]
+    pub duration: Duration,
+
+    /// Repeat the benchmark on runtimes with each of the given numbers of worker threads, e.g. `1,2,4,8`
+    ///
+    /// This shows how request handling scales with the number of cores since clients are distributed across all
+    /// worker threads and only wait for each other while they write into the same band of rows of the pixmap.
+    /// Without this option, the benchmark runs once on the runtime of the selected `--runtime-profile`.
+    #[arg(long = "threads", value_delimiter = ',', value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Vec<u16>,
+}
+
+#[cfg(feature = "server")]
+#[derive(Args, Debug, Clone)]
+#[command(group = clap::ArgGroup::new("target").required(true).args(["server", "output"]))]
+pub(crate) struct ReplayOpts {
+    /// The event log which is replayed
+    pub log: PathBuf,
+
+    /// Address of the pixelflut server onto which the changes are drawn
+    #[arg(short = 's', long = "server")]
+    pub server: Option<Url>,
+
+    /// Render the replay into an animated image instead, ".gif" records an animated GIF and ".png" or ".apng" an
+    /// animated PNG
+    #[arg(short = 'o', long = "output")]
+    pub output: Option<PathBuf>,
+
+    /// How many times faster than recorded the changes are replayed, 0 replays them as fast as possible
+    #[arg(long = "speed", default_value = "1", value_parser = parse_replay_speed)]
+    pub speed: f64,
+
+    /// How many frames per second of replay time are rendered into `--output`
+    #[arg(long = "fps", default_value = "10", value_parser = clap::value_parser!(u16).range(1..))]
+    pub fps: u16,
+
+    /// How often the animated image is played by viewers, 0 plays it forever
+    #[arg(long = "loops", default_value = "0")]
+    pub loops: u16,
+}
+
+#[derive(Args, Debug, Clone)]
+pub(crate) struct CtlOpts {
+    /// Path of the servers control socket
+    #[arg(short = 's', long = "socket")]
+    pub socket: PathBuf,
+
+    /// A unique id for this command so that running it again with the same id does not apply it twice
+    ///
+    /// This makes it safe to retry commands like `import-canvas` or `resize` after the connection broke before the
+    /// server answered. The server remembers the ids of its most recently applied commands.
+    #[arg(long = "request-id", value_parser = parse_request_id)]
+    pub request_id: Option<String>,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum CtlCommand {
+    /// Save the current canvas into an image file
+    ///
+    /// The image format is determined by the file extension.
+    ExportCanvas {
+        /// The image file to write
+        output: PathBuf,
+
+        /// A file containing an ed25519 secret key with which the image is signed
+        ///
+        /// The signature is stored next to the image with an additional `.sig` extension.
+        #[cfg(feature = "signing")]
+        #[arg(long = "sign-key")]
+        sign_key: Option<PathBuf>,
+    },
+    /// Draw an image file onto the canvas
+    ImportCanvas {
+        /// The image file to read
+        input: PathBuf,
+
+        /// Position of the images top-left corner on the canvas in the format `x,y`
+        #[arg(long = "at", default_value = "0,0", value_parser = parse_position)]
+        at: (usize, usize),
+    },
+    /// Print request latency metrics in the prometheus text format
+    ///
+    /// The server only records them when started with `--latency-metrics`.
+    Metrics,
+    /// Reject all writes of clients while the canvas can still be viewed, e.g. during an award ceremony
+    Freeze,
+    /// Allow clients to draw again after `freeze`
+    Unfreeze,
+    /// Change the size of the main canvas while keeping its content
+    ///
+    /// The content stays in the top-left corner and is cropped or padded with black pixels.
+    /// All listeners and sinks are restarted with the new size, which disconnects all clients.
+    /// The `--width` and `--height` of the server should be updated as well so that snapshots still fit after a
+    /// restart.
+    Resize {
+        /// The new width of the canvas
+        width: usize,
+
+        /// The new height of the canvas
+        height: usize,
+    },
+    /// Change the visual effect with which the display sinks show the canvas
+    ///
+    /// The server must have been started with `--effect`.
+    Effect {
+        /// The new effect, e.g. `none`, `quadrants` or `kaleidoscope:8`
+        effect: pixeldike::pixmap::Effect,
+    },
+}
+
+#[cfg(feature = "server")]
+fn parse_team(s: &str) -> Result<(String, String), String> {
+    match s.split_once(':') {
+        Some((name, token))
+            if !name.is_empty() && !token.is_empty() && !s.contains(|c: char| c.is_ascii_whitespace()) =>
+        {
+            Ok((name.to_string(), token.to_string()))
+        }
+        _ => Err(format!(
+            "team {:?} is not in the format <name>:<token> without whitespace",
+            s
+        )),
+    }
+}
+
+#[cfg(feature = "server")]
+fn parse_region_rate_limit(s: &str) -> Result<pixeldike::net::servers::RegionRateLimit, String> {
+    let err = || {
+        format!(
+            "{} is not in the format <x>,<y>,<width>,<height>:<pixels-per-second>",
+            s
+        )
+    };
+    let (rect, rate) = s.split_once(':').ok_or_else(err)?;
+    let rect = rect
+        .split(',')
+        .map(|i| i.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| err())?;
+    let [x, y, width, height] = rect[..] else {
+        return Err(err());
+    };
+    Ok(pixeldike::net::servers::RegionRateLimit {
+        x,
+        y,
+        width,
+        height,
+        pixels_per_sec: rate.trim().parse().map_err(|_| err())?,
+    })
+}
+
+#[cfg(feature = "server")]
+fn parse_hex_color(s: &str) -> Result<Color, String> {
+    match u32::from_str_radix(s.trim_start_matches('#'), 16) {
+        Ok(color) if color <= 0xffffff => Ok(color.into()),
+        _ => Err(format!("{} is not a hex RGB color like ff8800", s)),
+    }
+}
+
+#[cfg(feature = "server")]
+fn parse_protected_region(s: &str) -> Result<pixeldike::pixmap::ProtectedRegion, String> {
+    let rect = s
+        .split(',')
+        .map(|i| i.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("{} is not in the format <x>,<y>,<width>,<height>", s))?;
+    let [x, y, width, height] = rect[..] else {
+        return Err(format!("{} is not in the format <x>,<y>,<width>,<height>", s));
+    };
+    Ok(pixeldike::pixmap::ProtectedRegion { x, y, width, height })
+}
+
+#[cfg(feature = "server")]
+fn parse_webhook_region(s: &str) -> Result<pixeldike::sinks::webhook::WebhookRegion, String> {
+    let (name, rect) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} is not in the format <name>=<x>,<y>,<width>,<height>", s))?;
+    let rect = parse_protected_region(rect)?;
+    Ok(pixeldike::sinks::webhook::WebhookRegion {
+        name: name.to_string(),
+        region: pixeldike::pixmap::WatchedRegion {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        },
+    })
+}
+
+#[cfg(feature = "server")]
+fn parse_canvas(s: &str) -> Result<(String, (usize, usize)), String> {
+    let (name, size) = s
+        .split_once('=')
+        .ok_or_else(|| format!("canvas {:?} is not in the format <name>=<width>x<height>", s))?;
+    if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+        return Err(format!(
+            "canvas name {:?} must not be empty or contain whitespace",
+            name
+        ));
+    }
+    if name == pixeldike::pixmap::DEFAULT_CANVAS {
+        return Err(format!(
+            "the canvas name {} is reserved for the main canvas",
+            name
+        ));
+    }
+    Ok((name.to_string(), parse_frame_size(size)?))
+}
+
+#[cfg(feature = "server")]
+fn parse_replay_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed >= 0.0 => Ok(speed),
+        _ => Err(format!("{} is not a non-negative number", s)),
+    }
+}
+
+#[cfg(feature = "server")]
+fn parse_sink_canvas(s: &str) -> Result<(SinkKind, String), String> {
+    let (sink, canvas) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} is not in the format <sink>=<canvas>", s))?;
+    Ok((SinkKind::from_str(sink, false)?, canvas.to_string()))
+}
+
+fn parse_frame_size(s: &str) -> Result<(usize, usize), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("{} is not in the format WIDTHxHEIGHT", s))?;
+    let width = width.parse().map_err(|e| format!("invalid width: {}", e))?;
+    let height = height.parse().map_err(|e| format!("invalid height: {}", e))?;
+    match (width, height) {
+        (0, _) | (_, 0) => Err("frames must not be empty".to_string()),
+        size => Ok(size),
+    }
+}
+
+fn parse_request_id(s: &str) -> Result<String, String> {
+    match s.is_empty() || s.contains(|c: char| c.is_ascii_whitespace()) {
+        true => Err("request ids must not be empty or contain whitespace".to_string()),
+        false => Ok(s.to_string()),
+    }
+}
+
+fn parse_position(s: &str) -> Result<(usize, usize), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| format!("{} is not in the format x,y", s))?;
+    Ok((
+        x.trim()
+            .parse()
+            .map_err(|e| format!("invalid x coordinate: {}", e))?,
+        y.trim()
+            .parse()
+            .map_err(|e| format!("invalid y coordinate: {}", e))?,
+    ))
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum TargetDimension {
     /// Fill all available space
@@ -201,7 +1455,7 @@ impl FromStr for TargetDimension {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum TargetColor {
     RandomPerIteration,
     RandomOnce,
@@ -222,3 +1476,143 @@ impl FromStr for TargetColor {
         }
     }
 }
+
+/// How `put-rectangle` colors the pixels of its rectangle
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FillMode {
+    /// Every pixel has the target color
+    Solid,
+    /// Every pixel has a random color which is chosen again for every iteration if `animate` is set
+    Noise { animate: bool },
+    /// Smoothly changing random colors with features of about `scale` pixels
+    ValueNoise { scale: usize },
+    /// A linear gradient from the target color to another one
+    Gradient {
+        to: TargetColor,
+        direction: GradientDirection,
+    },
+    /// A rainbow whose hues are shifted by `step` degrees in every iteration
+    Rainbow { step: f32 },
+}
+
+/// The direction in which a gradient fades from one color to the other
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum GradientDirection {
+    /// From the left to the right edge
+    Horizontal,
+    /// From the top to the bottom edge
+    Vertical,
+    /// From the top-left to the bottom-right corner
+    Diagonal,
+}
+
+impl FromStr for FillMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, params) = s.split_once(':').unwrap_or((s, ""));
+        let mut result = match mode.trim() {
+            "solid" => FillMode::Solid,
+            "noise" => FillMode::Noise { animate: false },
+            "value-noise" => FillMode::ValueNoise { scale: 16 },
+            "gradient" => FillMode::Gradient {
+                to: TargetColor::RandomOnce,
+                direction: GradientDirection::Horizontal,
+            },
+            "rainbow" => FillMode::Rainbow { step: 10.0 },
+            mode => return Err(format!("unknown fill mode {}", mode)),
+        };
+        for part in params.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not in the format key=value", part))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {} {}: {}", key, value, e);
+            match (&mut result, key.trim()) {
+                (FillMode::Noise { animate }, "animate") => {
+                    *animate = value.trim().parse().map_err(|e| invalid(&e))?
+                }
+                (FillMode::ValueNoise { scale }, "scale") => {
+                    *scale = value.trim().parse().map_err(|e| invalid(&e))?;
+                    if *scale == 0 {
+                        return Err("the noise scale must be greater than zero".to_string());
+                    }
+                }
+                (FillMode::Gradient { to, .. }, "to") => {
+                    *to = value.trim().parse().map_err(|e| invalid(&e))?
+                }
+                (FillMode::Gradient { direction, .. }, "direction") => {
+                    *direction = match value.trim() {
+                        "horizontal" => GradientDirection::Horizontal,
+                        "vertical" => GradientDirection::Vertical,
+                        "diagonal" => GradientDirection::Diagonal,
+                        _ => return Err(format!("unknown gradient direction {}", value)),
+                    }
+                }
+                (FillMode::Rainbow { step }, "step") => {
+                    *step = value.trim().parse().map_err(|e| invalid(&e))?
+                }
+                (_, key) => return Err(format!("fill mode {} has no parameter {}", mode, key)),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Artificial network impairment which is applied to client commands
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Impairment {
+    /// How long each write is delayed
+    pub latency: Duration,
+    /// Maximum random deviation which is added on top of `latency`
+    pub jitter: Duration,
+    /// Probability between 0 and 1 with which each command is dropped
+    pub loss: f64,
+}
+
+impl FromStr for Impairment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Impairment::default();
+        for part in s.split(',').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("{} is not in the format key=value", part))?;
+            match key.trim() {
+                "latency" => result.latency = parse_duration(value)?,
+                "jitter" => result.jitter = parse_duration(value)?,
+                "loss" => {
+                    let percent = value
+                        .trim()
+                        .trim_end_matches('%')
+                        .parse::<f64>()
+                        .map_err(|e| format!("invalid loss {}: {}", value, e))?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(format!("loss {} is not between 0% and 100%", value));
+                    }
+                    result.loss = percent / 100.0;
+                }
+                key => return Err(format!("unknown impairment {}", key)),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Parse a duration like `50ms`, `2s` or `100us`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(unit_start);
+    let value = value
+        .parse::<f64>()
+        .map_err(|e| format!("invalid duration {}: {}", s, e))?;
+    match unit {
+        "us" => Ok(Duration::from_secs_f64(value / 1_000_000.0)),
+        "ms" => Ok(Duration::from_secs_f64(value / 1000.0)),
+        "s" => Ok(Duration::from_secs_f64(value)),
+        _ => Err(format!("duration {} needs a unit of us, ms or s", s)),
+    }
+}
@@ -1,8 +1,7 @@
-#![feature(never_type)]
-#![feature(cursor_remaining)]
+#![cfg_attr(feature = "server", feature(never_type))]
+#![cfg_attr(feature = "server", feature(portable_simd))]
 #![feature(sync_unsafe_cell)]
-#![feature(int_roundings)]
-#![feature(test)]
+#![cfg_attr(test, feature(test))]
 #![deny(trivial_casts)]
 #![warn(
     rustdoc::missing_crate_level_docs,
@@ -28,10 +27,20 @@ extern crate quickcheck;
 #[cfg(test)]
 extern crate test;
 
+#[cfg(feature = "server")]
+pub mod loadgen;
+#[cfg(feature = "server")]
+pub mod metrics;
 pub mod net;
 pub mod pixmap;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "server")]
 pub mod sinks;
 mod texts;
+#[cfg(feature = "server")]
+pub mod watchdog;
 
 /// The result type which all background tasks return
+#[cfg(feature = "server")]
 pub type DaemonResult = anyhow::Result<!>;
@@ -0,0 +1,295 @@
+//!
+//! Synthetic load generation for soak-testing the server implementation
+//!
+//! Load is generated by many in-process clients which are connected to a [`MemoryServer`] so that no sockets
+//! are involved and thousands of clients can run concurrently.
+//!
+
+use crate::net::clients::MemoryClient;
+use crate::net::protocol::{Request, StateEncoding, MAX_REGION_PIXELS};
+use crate::net::servers::MemoryServer;
+use crate::pixmap::Color;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// The behavior of a synthetic client
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ClientProfile {
+    /// Sets random pixels as fast as possible
+    Flooder,
+    /// Sets one random pixel every few milliseconds
+    PolitePainter,
+    /// Reads random pixels in small batches
+    Reader,
+    /// Periodically downloads the whole canvas like a live viewer would
+    Subscriber,
+}
+
+impl ClientProfile {
+    const ALL: [ClientProfile; 4] = [
+        ClientProfile::Flooder,
+        ClientProfile::PolitePainter,
+        ClientProfile::Reader,
+        ClientProfile::Subscriber,
+    ];
+}
+
+impl Display for ClientProfile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            ClientProfile::Flooder => "flooder",
+            ClientProfile::PolitePainter => "polite painter",
+            ClientProfile::Reader => "reader",
+            ClientProfile::Subscriber => "subscriber",
+        })
+    }
+}
+
+/// Options for configuring a load generation run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoadgenOptions {
+    /// How many clients of each profile should be spawned
+    pub clients: Vec<(ClientProfile, usize)>,
+    /// How long the load should be generated
+    pub duration: Duration,
+}
+
+/// Statistics about a finished load generation run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoadgenReport {
+    /// The time during which load was generated
+    pub duration: Duration,
+    /// Number of sent requests and received errors per client profile
+    pub profiles: Vec<(ClientProfile, u64, u64)>,
+}
+
+impl LoadgenReport {
+    /// The total number of requests which were sent by all clients
+    pub fn total_requests(&self) -> u64 {
+        self.profiles.iter().map(|(_, requests, _)| requests).sum()
+    }
+
+    /// The total number of errors which were returned to all clients
+    pub fn total_errors(&self) -> u64 {
+        self.profiles.iter().map(|(_, _, errors)| errors).sum()
+    }
+
+    /// Render the report as a JSON object for consumption by scripts
+    pub fn to_json(&self) -> String {
+        let secs = self.duration.as_secs_f64();
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(profile, requests, errors)| {
+                format!(
+                    "{{\"profile\":\"{}\",\"requests\":{},\"requests_per_sec\":{:.0},\"errors\":{}}}",
+                    profile,
+                    requests,
+                    *requests as f64 / secs,
+                    errors
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"duration_secs\":{},\"profiles\":[{}],\"total_requests\":{},\"requests_per_sec\":{:.0},\"total_errors\":{}}}",
+            secs,
+            profiles,
+            self.total_requests(),
+            self.total_requests() as f64 / secs,
+            self.total_errors()
+        )
+    }
+}
+
+impl Display for LoadgenReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        for (profile, requests, errors) in &self.profiles {
+            writeln!(
+                f,
+                "{:>15}: {:>12} requests ({:>12.0} req/s), {} errors",
+                profile,
+                requests,
+                *requests as f64 / secs,
+                errors
+            )?;
+        }
+        write!(
+            f,
+            "{:>15}: {:>12} requests ({:>12.0} req/s), {} errors",
+            "total",
+            self.total_requests(),
+            self.total_requests() as f64 / secs,
+            self.total_errors()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// A tiny xorshift random number generator so that load generation does not depend on an rng crate
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, max: usize) -> usize {
+        (self.next() % max as u64) as usize
+    }
+}
+
+/// Generate load on the given server and report how many requests could be handled
+pub async fn run(server: &MemoryServer, options: &LoadgenOptions) -> LoadgenReport {
+    let deadline = Instant::now() + options.duration;
+    let counters = ClientProfile::ALL.map(|_| Arc::new(Counters::default()));
+
+    let mut join_set = JoinSet::new();
+    let mut seed = 0x2545_F491_4F6C_DD1D;
+    for &(profile, count) in &options.clients {
+        let i_profile = ClientProfile::ALL.iter().position(|p| *p == profile).unwrap();
+        for _ in 0..count {
+            seed += 1;
+            let client = server.connect();
+            let counters = counters[i_profile].clone();
+            let rng = Rng(seed);
+            join_set.spawn(async move { run_client(profile, client, rng, &counters, deadline).await });
+        }
+    }
+    while join_set.join_next().await.is_some() {}
+
+    LoadgenReport {
+        duration: options.duration,
+        profiles: options
+            .clients
+            .iter()
+            .map(|(profile, _)| {
+                let i_profile = ClientProfile::ALL.iter().position(|p| p == profile).unwrap();
+                let counters = &counters[i_profile];
+                (
+                    *profile,
+                    counters.requests.load(Ordering::Relaxed),
+                    counters.errors.load(Ordering::Relaxed),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Execute the behavior of a single synthetic client until the deadline is reached
+async fn run_client(
+    profile: ClientProfile,
+    mut client: MemoryClient,
+    mut rng: Rng,
+    counters: &Counters,
+    deadline: Instant,
+) {
+    let (width, height) = match client.exchange(Request::GetSize).await {
+        Ok(crate::net::protocol::Response::Size { width, height }) => (width, height),
+        _ => {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    while Instant::now() < deadline {
+        let mut requests = Vec::new();
+        let pause = match profile {
+            ClientProfile::Flooder => {
+                for _ in 0..1000 {
+                    requests.push(Request::SetPixel {
+                        x: rng.below(width),
+                        y: rng.below(height),
+                        color: Color::from(rng.next() as u32 & 0xFFFFFF),
+                    });
+                }
+                None
+            }
+            ClientProfile::PolitePainter => {
+                requests.push(Request::SetPixel {
+                    x: rng.below(width),
+                    y: rng.below(height),
+                    color: Color::from(rng.next() as u32 & 0xFFFFFF),
+                });
+                Some(Duration::from_millis(10))
+            }
+            ClientProfile::Reader => {
+                for _ in 0..100 {
+                    requests.push(Request::GetPixel {
+                        x: rng.below(width),
+                        y: rng.below(height),
+                    });
+                }
+                Some(Duration::from_millis(10))
+            }
+            ClientProfile::Subscriber => {
+                let tile_size = (MAX_REGION_PIXELS as f64).sqrt() as usize;
+                for y in (0..height).step_by(tile_size) {
+                    for x in (0..width).step_by(tile_size) {
+                        requests.push(Request::GetRegion {
+                            x,
+                            y,
+                            width: usize::min(tile_size, width - x),
+                            height: usize::min(tile_size, height - y),
+                            encoding: StateEncoding::Rgb64,
+                        });
+                    }
+                }
+                Some(Duration::from_millis(100))
+            }
+        };
+
+        for request in requests {
+            let expects_response = !matches!(request, Request::SetPixel { .. } | Request::BlendPixel { .. });
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            let result = match expects_response {
+                true => client.exchange(request).await.map(|_| ()),
+                false => client.send_request(request).await.map_err(Into::into),
+            };
+            if result.is_err() {
+                counters.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        match pause {
+            Some(pause) => tokio::time::sleep(pause).await,
+            None => tokio::task::yield_now().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+
+    #[tokio::test]
+    async fn test_all_profiles() {
+        let server = MemoryServer::new(Arc::new(Pixmap::new(200, 200).unwrap()));
+        let report = run(
+            &server,
+            &LoadgenOptions {
+                clients: ClientProfile::ALL.iter().map(|profile| (*profile, 2)).collect(),
+                duration: Duration::from_millis(200),
+            },
+        )
+        .await;
+
+        assert_eq!(report.total_errors(), 0);
+        assert!(report.profiles.iter().all(|(_, requests, _)| *requests > 0));
+    }
+}
@@ -1,63 +1,91 @@
-#![feature(never_type)]
-
 use ab_glyph::{Font, FontRef};
 use bytes::buf::Writer;
-use bytes::{BufMut, BytesMut};
-use clap::Parser;
-use image::imageops::FilterType;
+use bytes::BytesMut;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 use rand::prelude::*;
-use std::net::ToSocketAddrs;
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tokio::task::{JoinSet, LocalSet};
-use tokio::time::interval;
+use std::io::Read;
+use std::time::{Duration, Instant};
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::filter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::cli::{CliOpts, TargetColor, TargetDimension};
+use crate::cli::{CliOpts, OutputFormat, TargetColor};
 use image::io::Reader as ImageReader;
 use itertools::Itertools;
-use pixeldike::net::clients::{TcpClient, UdpClient, UnixSocketClient};
-use pixeldike::net::protocol::{Request, Response};
-use pixeldike::net::servers::{GenServer, TcpServer, TcpServerOptions, UnixSocketOptions, UnixSocketServer};
-#[cfg(feature = "udp")]
-use pixeldike::net::servers::{UdpServer, UdpServerOptions};
-#[cfg(feature = "ws")]
-use pixeldike::net::servers::{WsServer, WsServerOptions};
-use pixeldike::pixmap::{Color, Pixmap};
-use pixeldike::sinks::ffmpeg::{FfmpegOptions, FfmpegSink};
-use pixeldike::sinks::framebuffer::{FramebufferSink, FramebufferSinkOptions};
-use pixeldike::sinks::pixmap_file::{FileSink, FileSinkOptions};
-use pixeldike::DaemonResult;
-use url::Url;
+use pixeldike::net::clients::ControlClient;
+use pixeldike::net::protocol::Request;
+use pixeldike::pixmap::Color;
 
 mod cli;
+mod main_explain;
+mod main_fill;
+mod main_order;
+mod main_presets;
+#[cfg(feature = "server")]
+mod main_server;
 mod main_utils;
 
 const FONT_HERMIT_REGULAR: &[u8] = include_bytes!("../resources/Hermit-Regular.otf");
 
-#[tokio::main]
-async fn main() {
-    let args = cli::CliOpts::parse();
+fn main() {
+    let args = main_presets::expand_presets(std::env::args_os().collect()).unwrap_or_else(|e| {
+        cli::CliOpts::command()
+            .error(ErrorKind::InvalidValue, format!("{:#}", e))
+            .exit()
+    });
+    let args = cli::CliOpts::parse_from(args);
+    if args.version {
+        match args.build_info {
+            true => print!("{}", main_utils::build_info()),
+            false => println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+        return;
+    }
+    let Some(command) = &args.command else {
+        cli::CliOpts::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit();
+    };
     init_logger(&args);
 
     // prepare async environment and run the specified program action
-    let local_set = LocalSet::new();
-    local_set
-        .run_until(async move {
-            match &args.command {
-                cli::Command::Server(opts) => start_server(opts).await,
-                cli::Command::PutRectangle(opts) => put_rectangle(opts).await,
-                cli::Command::PutImage(opts) => put_image(opts).await,
-                cli::Command::PutText(opts) => put_text(opts).await,
-            };
-        })
-        .await;
+    let runtime = match args.runtime_profile {
+        cli::Profile::Default => tokio::runtime::Builder::new_multi_thread(),
+        cli::Profile::LowPower => tokio::runtime::Builder::new_current_thread(),
+    }
+    .enable_all()
+    .build()
+    .expect("Could not create async runtime");
+    runtime.block_on(async move {
+        match command {
+            #[cfg(feature = "server")]
+            cli::Command::Server(opts) => main_server::run_server(opts, args.runtime_profile).await,
+            cli::Command::PutRectangle(opts) => put_rectangle(opts, args.output).await,
+            cli::Command::PutImage(opts) => put_image(opts, args.output).await,
+            cli::Command::PutText(opts) => put_text(opts, args.output).await,
+            cli::Command::Show(opts) => show_canvas(opts, args.output).await,
+            cli::Command::Ctl(opts) => ctl(opts, args.output).await,
+            #[cfg(feature = "server")]
+            cli::Command::Bench(opts) => bench(opts, args.output).await,
+            #[cfg(feature = "server")]
+            cli::Command::Replay(opts) => replay(opts, args.output).await,
+            #[cfg(feature = "mdns")]
+            cli::Command::Discover(opts) => discover(opts, args.output).await,
+            #[cfg(feature = "signing")]
+            cli::Command::Keygen(opts) => keygen(opts, args.output),
+            #[cfg(feature = "signing")]
+            cli::Command::Verify(opts) => verify(opts, args.output),
+            cli::Command::Completions { shell } => clap_complete::generate(
+                *shell,
+                &mut CliOpts::command(),
+                "pixeldike",
+                &mut std::io::stdout(),
+            ),
+            cli::Command::ExplainUrl { url } => explain_url(url, args.output),
+        };
+    });
 }
 
 #[inline]
@@ -83,296 +111,194 @@ fn init_logger(args: &CliOpts) {
         .with_target("tokio", Ord::min(LevelFilter::WARN, log_level))
         .with_target("runtime", Ord::min(LevelFilter::WARN, log_level));
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .with(filter)
         .init();
 }
 
-async fn start_server(opts: &cli::ServerOpts) {
-    // create a pixmap or load an existing snapshot
-    let pixmap = match &opts.file_opts.load_snapshot {
-        None => Arc::new(Pixmap::new(opts.width, opts.height).unwrap()),
-        Some(path) => {
-            let loaded_pixmap = pixeldike::sinks::pixmap_file::load_pixmap_file(path).await;
-            match loaded_pixmap {
-                Err(e) => {
-                    tracing::error!(
-                        "Could not load snapshot from {}, using empty pixmap instead: {}",
-                        path.display(),
-                        e
-                    );
-                    Arc::new(Pixmap::new(opts.width, opts.height).unwrap())
-                }
-                Ok(loaded_pixmap) => {
-                    let (width, height) = loaded_pixmap.get_size();
-                    if width != opts.width || height != opts.height {
-                        tracing::warn!(
-                    "Stored snapshot has different dimensions than {}x{}, creating an empty pixmap instead",
-                    opts.width,
-                    opts.height
-                );
-                        Arc::new(Pixmap::new(opts.width, opts.height).unwrap())
-                    } else {
-                        Arc::new(loaded_pixmap)
-                    }
-                }
-            }
-        }
-    };
-
-    let mut join_set: JoinSet<DaemonResult> = JoinSet::new();
-
-    // configure snapshotting
-    if let Some(path) = &opts.file_opts.snapshot_file {
-        let pixmap = pixmap.clone();
-        let sink = FileSink::new(
-            FileSinkOptions {
-                path: path.to_owned(),
-                interval: interval(Duration::from_secs(opts.file_opts.snapshot_interval_secs as u64)),
-            },
-            pixmap,
-        );
-        sink.start(&mut join_set)
-            .await
-            .expect("Could not start persistence task");
-    }
-
-    // configure gui window
-    #[cfg(feature = "windowing")]
-    if opts.open_window {
-        let pixmap = pixmap.clone();
-        pixeldike::sinks::window::start(&mut join_set, pixmap)
-            .expect("Could not open window for live rendering");
-    }
-
-    // configure streaming sink
-    if opts.stream_opts.rtmp_dst_addr.is_some() || opts.stream_opts.rtsp_dst_addr.is_some() {
-        // construct output spec depending on cli options
-        let mut output_spec = Vec::new();
-        if let Some(rtsp_dst_addr) = &opts.stream_opts.rtsp_dst_addr {
-            output_spec.append(&mut FfmpegOptions::make_rtsp_out_spec(
-                rtsp_dst_addr,
-                opts.stream_opts.framerate,
-            ));
-        }
-        if let Some(rtmp_dst_addr) = &opts.stream_opts.rtmp_dst_addr {
-            output_spec.append(&mut FfmpegOptions::make_rtmp_out_spec(
-                rtmp_dst_addr,
-                opts.stream_opts.framerate,
-            ));
-        }
-
-        // start the ffmpeg subprocess
-        let pixmap = pixmap.clone();
-        let ffmpeg = FfmpegSink::new(
-            FfmpegOptions {
-                framerate: opts.stream_opts.framerate,
-                synthesize_audio: true,
-                log_level: "warning".to_string(),
-                output_spec,
-            },
-            pixmap,
-        );
-        ffmpeg
-            .start(&mut join_set)
-            .await
-            .expect("Could not start ffmpeg sink");
-    }
-
-    // configure framebuffer sink
-    if let Some(fb_device) = &opts.fb_opts.fb_device {
-        let pixmap = pixmap.clone();
-        let sink = FramebufferSink::new(
-            FramebufferSinkOptions {
-                path: fb_device.to_owned(),
-                framerate: opts.fb_opts.fb_framerate,
-            },
-            pixmap,
-        );
-        sink.start(&mut join_set)
-            .await
-            .expect("Coult not start task for framebuffer rendering");
-    }
-
-    // configure and start all servers
-    for url in &opts.listen {
-        match url.scheme() {
-            #[cfg(feature = "tcp")]
-            "tcp" => {
-                if !url.username().is_empty() {
-                    tracing::warn!(
-                        "{} listen directive specifies credentials which is not supported by the TCP server",
-                        url
-                    )
-                }
-                if !url.path().is_empty() {
-                    tracing::warn!(
-                        "{} listen directive specifies a path which is not supported by the TCP server",
-                        url
-                    );
-                }
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1234))
-                    .to_socket_addrs()
-                    .expect("Could not resolve socket addr from listener url")
-                {
-                    TcpServer::new(TcpServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
-                }
-            }
-            "unix" => {
-                let path = PathBuf::from_str(url.path()).expect("Could not turn url path into system path");
-                UnixSocketServer::new(UnixSocketOptions { path })
-                    .start(pixmap.clone(), &mut join_set)
-                    .await
-                    .expect(&format!("Could not start unix socket listener on {}", url));
-            }
-            #[cfg(feature = "udp")]
-            "udp" => {
-                if !url.username().is_empty() {
-                    tracing::info!("{}", url.authority());
-                    tracing::warn!(
-                        "{} listen directive specifies credentials which is not supported by the UDP server",
-                        url
-                    )
-                }
-                if !url.path().is_empty() {
-                    tracing::warn!(
-                        "{} listen directive specifies a path which is not supported by the UDP server",
-                        url
-                    );
-                }
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1234))
-                    .to_socket_addrs()
-                    .expect("Could not resolve socket addr from listener url")
-                {
-                    UdpServer::new(UdpServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
-                }
-            }
-            #[cfg(feature = "ws")]
-            "ws" => {
-                if !url.username().is_empty() {
-                    tracing::info!("{}", url.authority());
-                    tracing::warn!(
-                        "{} listen directive specifies credentials which is not supported by the WebSocket server",
-                        url
-                    )
-                }
-                if url.path() != "/" {
-                    tracing::warn!(
-                        "{} listen directive specifies a path which is not supported by the WebSocket server. The WebSocket is instead available on all paths.",
-                        url
-                    );
-                }
-
-                for bind_addr in (url.host_str().unwrap(), url.port().unwrap_or(1235))
-                    .to_socket_addrs()
-                    .expect("Could not resolve socket addr from listener url")
-                {
-                    WsServer::new(WsServerOptions { bind_addr })
-                        .start(pixmap.clone(), &mut join_set)
-                        .await
-                        .expect(&format!("Could not start tcp server on {}", url));
-                }
-            }
-            proto => {
-                panic!("Unsupported server protocol {}", proto);
-            }
-        }
-    }
-
-    // wait until one tasks exits
-    let result = join_set
-        .join_next()
-        .await
-        .expect("Nothing is supposed to be started which makes no sense. Review commandline flags.")
-        .expect("Could not join background task")
-        .unwrap_err();
-    tracing::error!("A background task exited unexpectedly: {}", result);
-
-    // cancel all other tasks
-    join_set.shutdown().await;
-}
+async fn put_rectangle(opts: &cli::PutRectangleData, output: OutputFormat) {
+    let mut strategy = opts.fill.strategy(&opts.color);
+    let per_iteration = strategy.per_iteration();
+    let mut iteration = 0;
 
-async fn put_rectangle(opts: &cli::PutRectangleData) {
     // define how a request buffer is filled
     let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
-        // select a color
-        let color = match opts.color {
-            TargetColor::RandomPerIteration | TargetColor::RandomOnce => {
-                Color::from((random(), random(), random()))
-            }
-            TargetColor::Specific(c) => c,
-        };
+        strategy.begin_iteration(iteration);
+        iteration += 1;
+        let strategy = &*strategy;
+        let (width, height) = (x_max - x_min, y_max - y_min);
 
         // accumulate color commands into one large buffer buffer
-        tracing::debug!("Filling command-buffer to draw #{color:X} from {x_min},{y_min} to {x_max},{y_max}");
+        tracing::debug!(
+            "Filling command-buffer to draw {:?} from {x_min},{y_min} to {x_max},{y_max}",
+            opts.fill
+        );
         let mut coords = (x_min..x_max).cartesian_product(y_min..y_max).collect::<Vec<_>>();
         coords.shuffle(&mut thread_rng());
-        for (x, y) in coords {
-            Request::SetPixel { x, y, color }.write(buf).unwrap();
-        }
+        main_utils::write_requests_parallel(&coords, buf, &opts.common, |x, y| Request::SetPixel {
+            x,
+            y,
+            color: strategy.color(x - x_min, y - y_min, width, height),
+        })
+        .unwrap();
     };
 
     // run main client loop
-    main_utils::DynClient::connect(&opts.common.server)
+    let summary = main_utils::DynClient::connect(&opts.common.server)
         .await
         .expect("Could not connect to pixelflut server")
-        .run_loop(
-            fill_buf,
-            &opts.common,
-            matches!(opts.color, TargetColor::RandomPerIteration),
-        )
+        .run_loop(fill_buf, &opts.common, per_iteration)
         .await;
+    print_client_summary(&summary, output);
 }
 
-async fn put_image(opts: &cli::PutImageData) {
+async fn put_image(opts: &cli::PutImageData, output: OutputFormat) {
+    if let Some(frame_size) = opts.stdin_rgba {
+        return put_image_stream(opts, frame_size, output).await;
+    }
+    let path = opts
+        .path
+        .as_ref()
+        .expect("either --file or --stdin-rgba is required");
+    if let Some(frames) = main_utils::load_animation(path).expect("Could not decode image") {
+        return put_animation(opts, frames, output).await;
+    }
+
     // define how a request buffer is filled
     let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
-        tracing::debug!("Opening image at {}", &opts.path.display());
-        let img = ImageReader::open(&opts.path)
+        tracing::debug!("Opening image at {}", path.display());
+        let img = ImageReader::open(path)
             .expect("Could not open image file")
             .decode()
             .expect("Could not decode image")
             .to_rgb8();
 
         tracing::debug!("Resizing image to dimensions {}x{}", x_max - x_min, y_max - y_min);
-        let img = image::imageops::resize(
-            &img,
-            (x_max - x_min) as u32,
-            (y_max - y_min) as u32,
-            FilterType::Triangle,
-        );
+        let img = main_utils::resize_parallel(&img, (x_max - x_min) as u32, (y_max - y_min) as u32);
 
         // accumulate color commands into one large buffer buffer
         tracing::debug!("Converting image to pixelflut commands");
         let mut coords = (x_min..x_max).cartesian_product(y_min..y_max).collect::<Vec<_>>();
         coords.shuffle(&mut thread_rng());
-        for (x, y) in coords {
-            let color = img.get_pixel(x as u32, y as u32);
-            Request::SetPixel {
-                x,
-                y,
-                color: color.0.into(),
+        main_utils::write_requests_parallel(&coords, buf, &opts.common, |x, y| Request::SetPixel {
+            x,
+            y,
+            color: img.get_pixel((x - x_min) as u32, (y - y_min) as u32).0.into(),
+        })
+        .unwrap();
+    };
+
+    // run main client loop
+    let summary = main_utils::DynClient::connect(&opts.common.server)
+        .await
+        .expect("Could not connect to pixelflut server")
+        .run_loop(fill_buf, &opts.common, false)
+        .await;
+    print_client_summary(&summary, output);
+}
+
+/// Play the frames of an animated image back at their own timing or at the rate given by `--fps`
+///
+/// Only pixels which changed since the previous frame are sent, except for the first frame which is drawn completely
+/// every time the animation starts over.
+async fn put_animation(
+    opts: &cli::PutImageData,
+    frames: Vec<(image::RgbaImage, Duration)>,
+    output: OutputFormat,
+) {
+    let frame_delay = opts.fps.map(|fps| {
+        assert!(fps > 0.0, "--fps must be greater than zero");
+        Duration::from_secs_f64(1.0 / fps)
+    });
+    let mut resized = None;
+    let mut i = 0;
+    let mut next_frame_at = Instant::now();
+
+    // define how a request buffer is filled
+    let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
+        if i == frames.len() {
+            if !opts.common.do_loop {
+                return false;
             }
-            .write(buf)
-            .unwrap();
+            i = 0;
         }
+        let (width, height) = ((x_max - x_min) as u32, (y_max - y_min) as u32);
+        let resized: &Vec<image::RgbaImage> = resized.get_or_insert_with(|| {
+            tracing::debug!(
+                "Resizing {} frames to dimensions {}x{}",
+                frames.len(),
+                width,
+                height
+            );
+            frames
+                .iter()
+                .map(|(frame, _)| main_utils::resize_parallel(frame, width, height))
+                .collect()
+        });
+
+        // the client has nothing else to do while waiting for the next frame so blocking is fine
+        std::thread::sleep(next_frame_at.saturating_duration_since(Instant::now()));
+        let previous = i.checked_sub(1).map(|previous| &resized[previous]);
+        main_utils::write_changed_pixels(previous, &resized[i], x_min, y_min, buf, &opts.common);
+        // frames which are sent late keep their full duration instead of rushing through the following ones
+        next_frame_at = next_frame_at.max(Instant::now()) + frame_delay.unwrap_or(frames[i].1);
+        i += 1;
+        true
     };
 
     // run main client loop
-    main_utils::DynClient::connect(&opts.common.server)
+    let summary = main_utils::DynClient::connect(&opts.common.server)
         .await
         .expect("Could not connect to pixelflut server")
-        .run_loop(fill_buf, &opts.common, false)
+        .run_frames(fill_buf, &opts.common)
+        .await;
+    print_client_summary(&summary, output);
+}
+
+/// Draw raw RGBA frames from stdin while only sending the pixels which changed since the previous frame
+async fn put_image_stream(
+    opts: &cli::PutImageData,
+    (frame_width, frame_height): (usize, usize),
+    output: OutputFormat,
+) {
+    let mut stdin = std::io::stdin();
+    let mut raw = vec![0u8; frame_width * frame_height * 4];
+    let mut previous = None;
+
+    // define how a request buffer is filled
+    let fill_buf = |buf: &mut Writer<BytesMut>, x_min: usize, x_max: usize, y_min: usize, y_max: usize| {
+        // the client has nothing else to do while waiting for the next frame so blocking is fine
+        match stdin.read_exact(&mut raw) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                tracing::info!("Stdin was closed, no more frames to draw");
+                return false;
+            }
+            Err(e) => panic!("Could not read frame from stdin: {}", e),
+        }
+
+        let frame = image::RgbaImage::from_raw(frame_width as u32, frame_height as u32, raw.clone())
+            .expect("frame buffer has the size of a frame");
+        let (width, height) = ((x_max - x_min) as u32, (y_max - y_min) as u32);
+        let frame = match frame.dimensions() == (width, height) {
+            true => frame,
+            false => main_utils::resize_parallel(&frame, width, height),
+        };
+        main_utils::write_changed_pixels(previous.as_ref(), &frame, x_min, y_min, buf, &opts.common);
+        previous = Some(frame);
+        true
+    };
+
+    // run main client loop
+    let summary = main_utils::DynClient::connect(&opts.common.server)
+        .await
+        .expect("Could not connect to pixelflut server")
+        .run_frames(fill_buf, &opts.common)
         .await;
+    print_client_summary(&summary, output);
 }
 
-async fn put_text(opts: &cli::PutTextOpts) {
+async fn put_text(opts: &cli::PutTextOpts, output: OutputFormat) {
     let font = FontRef::try_from_slice(FONT_HERMIT_REGULAR).unwrap();
 
     // define how a request buffer is filled
@@ -407,20 +333,19 @@ async fn put_text(opts: &cli::PutTextOpts) {
             let outline = font.outline_glyph(glyph).unwrap();
             outline.draw(|x, y, coverage| {
                 if coverage >= 0.5 {
-                    Request::SetPixel {
+                    let request = Request::SetPixel {
                         x: x_min + (x as usize + i * glyph_width),
                         y: y_min + (y as usize),
                         color,
-                    }
-                    .write(buf)
-                    .unwrap();
+                    };
+                    main_utils::write_request(&request, buf, &opts.common).unwrap();
                 }
             });
         }
     };
 
     // run main client loop
-    main_utils::DynClient::connect(&opts.common.server)
+    let summary = main_utils::DynClient::connect(&opts.common.server)
         .await
         .expect("Could not connect to pixelflut server")
         .run_loop(
@@ -429,4 +354,551 @@ async fn put_text(opts: &cli::PutTextOpts) {
             matches!(opts.color, TargetColor::RandomPerIteration),
         )
         .await;
+    print_client_summary(&summary, output);
+}
+
+/// Print what a client loop did in the requested format
+///
+/// There is nothing to report in text form that wasn't already logged so only JSON output produces anything.
+fn print_client_summary(summary: &main_utils::ClientLoopSummary, output: OutputFormat) {
+    if output == OutputFormat::Json {
+        println!("{}", summary.to_json());
+    }
+}
+
+async fn show_canvas(opts: &cli::ShowOpts, output: OutputFormat) {
+    let (width, height, data) = main_utils::DynClient::connect(&opts.server)
+        .await
+        .expect("Could not connect to pixelflut server")
+        .fetch_canvas()
+        .await
+        .expect("Could not download canvas from pixelflut server");
+
+    match &opts.output {
+        Some(path) => {
+            let img = image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
+                image::Rgb(data[y as usize * width + x as usize].into())
+            });
+            img.save(path).expect("Could not save canvas image");
+            match output {
+                OutputFormat::Text => tracing::info!("Saved canvas to {}", path.display()),
+                OutputFormat::Json => println!(
+                    "{{\"width\":{},\"height\":{},\"path\":{}}}",
+                    width,
+                    height,
+                    main_utils::json_string(&path.to_string_lossy())
+                ),
+            }
+        }
+        None if output == OutputFormat::Json => {
+            let pixels = data.iter().map(|color| format!("\"{}\"", color)).join(",");
+            println!(
+                "{{\"width\":{},\"height\":{},\"pixels\":[{}]}}",
+                width, height, pixels
+            );
+        }
+        None => {
+            // every character cell displays two vertically stacked pixels using the upper half block character
+            let columns = usize::min(opts.columns, width);
+            let rows = height * columns / width;
+            let sample = |col: usize, row: usize| -> (u8, u8, u8) {
+                data[(row * height / rows.max(1)) * width + col * width / columns].into()
+            };
+            let mut out = String::new();
+            for row in (0..rows).step_by(2) {
+                for col in 0..columns {
+                    let (r1, g1, b1) = sample(col, row);
+                    let (r2, g2, b2) = sample(col, usize::min(row + 1, rows - 1));
+                    out.push_str(&format!(
+                        "\x1b[38;2;{r1};{g1};{b1}m\x1b[48;2;{r2};{g2};{b2}m\u{2580}"
+                    ));
+                }
+                out.push_str("\x1b[0m\n");
+            }
+            print!("{}", out);
+        }
+    }
+}
+
+async fn ctl(opts: &cli::CtlOpts, output: OutputFormat) {
+    let mut client = ControlClient::connect(&opts.socket)
+        .await
+        .expect("Could not connect to control socket");
+    if let Some(id) = &opts.request_id {
+        client.set_request_id(id);
+    }
+    match &opts.command {
+        cli::CtlCommand::ExportCanvas {
+            output: path,
+            #[cfg(feature = "signing")]
+            sign_key,
+        } => {
+            let (width, height, data) = client.export_canvas().await.expect("Could not export canvas");
+            let img = image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
+                image::Rgb(data[y as usize * width + x as usize].into())
+            });
+            img.save(path).expect("Could not save canvas image");
+            #[cfg(feature = "signing")]
+            if let Some(sign_key) = sign_key {
+                let signer = pixeldike::signing::Signer::load(sign_key).expect("Could not load signing key");
+                let content = std::fs::read(path).expect("Could not read exported canvas image");
+                signer
+                    .write_signature(path, &content)
+                    .await
+                    .expect("Could not write signature");
+            }
+            match output {
+                OutputFormat::Text => {
+                    tracing::info!("Exported {}x{} canvas to {}", width, height, path.display())
+                }
+                OutputFormat::Json => println!(
+                    "{{\"width\":{},\"height\":{},\"path\":{}}}",
+                    width,
+                    height,
+                    main_utils::json_string(&path.to_string_lossy())
+                ),
+            }
+        }
+        cli::CtlCommand::ImportCanvas { input, at } => {
+            let img = ImageReader::open(input)
+                .expect("Could not open image file")
+                .decode()
+                .expect("Could not decode image")
+                .into_rgb8();
+            let data = img.pixels().map(|px| Color::from(px.0)).collect::<Vec<_>>();
+            client
+                .import_canvas(at.0, at.1, img.width() as usize, img.height() as usize, &data)
+                .await
+                .expect("Could not import canvas");
+            match output {
+                OutputFormat::Text => tracing::info!("Imported {} at {},{}", input.display(), at.0, at.1),
+                OutputFormat::Json => println!(
+                    "{{\"path\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{}}}",
+                    main_utils::json_string(&input.to_string_lossy()),
+                    at.0,
+                    at.1,
+                    img.width(),
+                    img.height()
+                ),
+            }
+        }
+        cli::CtlCommand::Freeze | cli::CtlCommand::Unfreeze => {
+            let frozen = matches!(opts.command, cli::CtlCommand::Freeze);
+            client
+                .set_frozen(frozen)
+                .await
+                .expect("Could not change whether the canvas is frozen");
+            match output {
+                OutputFormat::Text => match frozen {
+                    true => tracing::info!("Froze the canvas"),
+                    false => tracing::info!("Unfroze the canvas"),
+                },
+                OutputFormat::Json => println!("{{\"frozen\":{}}}", frozen),
+            }
+        }
+        cli::CtlCommand::Resize { width, height } => {
+            client
+                .resize_canvas(*width, *height)
+                .await
+                .expect("Could not resize canvas");
+            match output {
+                OutputFormat::Text => tracing::info!("Resized the canvas to {}x{}", width, height),
+                OutputFormat::Json => println!("{{\"width\":{},\"height\":{}}}", width, height),
+            }
+        }
+        cli::CtlCommand::Effect { effect } => {
+            client.set_effect(*effect).await.expect("Could not change effect");
+            match output {
+                OutputFormat::Text => tracing::info!("Changed the effect to {}", effect),
+                OutputFormat::Json => println!("{{\"effect\":\"{}\"}}", effect),
+            }
+        }
+        cli::CtlCommand::Metrics => {
+            let metrics = client.metrics().await.expect("Could not retrieve metrics");
+            match output {
+                OutputFormat::Text => print!("{}", metrics),
+                OutputFormat::Json => println!("{}", main_utils::metrics_to_json(&metrics)),
+            }
+        }
+    }
+}
+
+fn explain_url(url: &url::Url, output: OutputFormat) {
+    let explanation = main_explain::explain_url(url);
+    match output {
+        OutputFormat::Text => print!("{}", explanation.to_text()),
+        OutputFormat::Json => println!("{}", explanation.to_json()),
+    }
+    if !explanation.is_valid() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "signing")]
+fn keygen(opts: &cli::KeygenOpts, output: OutputFormat) {
+    use pixeldike::signing::Signer;
+
+    let mut seed = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut seed);
+    let signer = Signer::from_seed(seed);
+    let public_key = signer.public_key();
+
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&opts.secret_key)
+        .expect("Could not create secret key file");
+    std::io::Write::write_all(&mut file, format!("{}\n", signer.secret_key_string()).as_bytes())
+        .expect("Could not write secret key");
+    if let Some(path) = &opts.public_key {
+        std::fs::write(path, format!("{}\n", public_key)).expect("Could not write public key");
+    }
+
+    match output {
+        OutputFormat::Text => println!("{}", public_key),
+        OutputFormat::Json => println!(
+            "{{\"public_key\":{}}}",
+            main_utils::json_string(&public_key.to_string())
+        ),
+    }
+}
+
+#[cfg(feature = "signing")]
+fn verify(opts: &cli::VerifyOpts, output: OutputFormat) {
+    use pixeldike::signing::{signature_path, PublicKey};
+
+    let public_key = opts.public_key.parse::<PublicKey>().unwrap_or_else(|_| {
+        PublicKey::load(std::path::Path::new(&opts.public_key))
+            .expect("Could not read public key, expected a base64 key or a path to a file containing one")
+    });
+    let content = std::fs::read(&opts.file).expect("Could not read signed file");
+    let signature_path = opts
+        .signature
+        .clone()
+        .unwrap_or_else(|| signature_path(&opts.file));
+    let signature = std::fs::read_to_string(&signature_path).expect("Could not read signature");
+    let result = public_key.verify(&content, &signature);
+
+    match output {
+        OutputFormat::Text => match &result {
+            Ok(()) => println!("{}: valid signature by {}", opts.file.display(), public_key),
+            Err(e) => println!("{}: {}", opts.file.display(), e),
+        },
+        OutputFormat::Json => println!(
+            "{{\"file\":{},\"valid\":{},\"error\":{}}}",
+            main_utils::json_string(&opts.file.to_string_lossy()),
+            result.is_ok(),
+            result
+                .as_ref()
+                .err()
+                .map_or("null".to_string(), |e| main_utils::json_string(&e.to_string())),
+        ),
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "mdns")]
+async fn discover(opts: &cli::DiscoverOpts, output: OutputFormat) {
+    let servers = pixeldike::net::discovery::discover(opts.duration)
+        .await
+        .expect("Could not listen for mDNS announcements");
+    match output {
+        OutputFormat::Text => {
+            for server in &servers {
+                let size = match server.size {
+                    Some((width, height)) => format!("{}x{}", width, height),
+                    None => "unknown size".to_string(),
+                };
+                println!(
+                    "{}: {}:{} ({}) {}",
+                    server.name,
+                    server.host,
+                    server.port,
+                    server.addresses.iter().join(", "),
+                    size
+                );
+            }
+        }
+        OutputFormat::Json => {
+            println!(
+                "{{\"servers\":[{}]}}",
+                servers
+                    .iter()
+                    .map(|server| format!(
+                    "{{\"name\":{},\"host\":{},\"port\":{},\"addresses\":[{}],\"width\":{},\"height\":{}}}",
+                    main_utils::json_string(&server.name),
+                    main_utils::json_string(&server.host),
+                    server.port,
+                    server
+                        .addresses
+                        .iter()
+                        .map(|i| main_utils::json_string(&i.to_string()))
+                        .join(","),
+                    server.size.map_or("null".to_string(), |(width, _)| width.to_string()),
+                    server.size.map_or("null".to_string(), |(_, height)| height.to_string()),
+                ))
+                    .join(",")
+            )
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+async fn replay(opts: &cli::ReplayOpts, output: OutputFormat) {
+    use pixeldike::sinks::eventlog::EventLogReader;
+
+    let file = std::fs::File::open(&opts.log).expect("Could not open event log");
+    let mut log = EventLogReader::new(std::io::BufReader::new(file)).expect("Could not read event log");
+    let (width, height) = log.size();
+    // a log which is still being recorded may end with a partially written entry
+    let mut entries = std::iter::from_fn(|| match log.next()? {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            tracing::warn!("Stopping replay at a damaged or incomplete entry: {}", e);
+            None
+        }
+    });
+
+    let (replayed, frames) = match (&opts.server, &opts.output) {
+        (Some(url), _) => (replay_to_server(url, &mut entries, opts.speed).await, None),
+        (None, Some(path)) => {
+            let (replayed, frames) = replay_to_image(path, &mut entries, width, height, opts);
+            (replayed, Some(frames))
+        }
+        (None, None) => unreachable!("clap requires a replay target"),
+    };
+
+    match output {
+        OutputFormat::Text => match frames {
+            None => tracing::info!("Replayed {} logged changes", replayed),
+            Some(frames) => tracing::info!(
+                "Rendered {} logged changes into {} frames of {}",
+                replayed,
+                frames,
+                opts.output.as_ref().unwrap().display()
+            ),
+        },
+        OutputFormat::Json => println!(
+            "{{\"width\":{},\"height\":{},\"changes\":{},\"frames\":{}}}",
+            width,
+            height,
+            replayed,
+            frames.map_or("null".to_string(), |frames| frames.to_string())
+        ),
+    }
+}
+
+/// Draw the logged changes onto a server with the recorded pauses between them divided by `speed`
+///
+/// Returns how many changes were replayed.
+#[cfg(feature = "server")]
+async fn replay_to_server(
+    url: &url::Url,
+    entries: &mut impl Iterator<Item = pixeldike::sinks::eventlog::LogEntry>,
+    speed: f64,
+) -> usize {
+    use pixeldike::sinks::eventlog::Event;
+
+    /// How many bytes of commands are collected before they are sent even if the next change is not due yet
+    const MAX_BATCH: usize = 64 * 1024;
+
+    let mut client = main_utils::DynClient::connect(url)
+        .await
+        .expect("Could not connect to pixelflut server");
+    let started = tokio::time::Instant::now();
+    let mut first = None;
+    let mut buf = Vec::new();
+    let mut replayed = 0;
+    for entry in entries {
+        let first = *first.get_or_insert(entry.timestamp);
+        if speed > 0.0 {
+            let due = started
+                + entry
+                    .timestamp
+                    .duration_since(first)
+                    .unwrap_or_default()
+                    .div_f64(speed);
+            if due > tokio::time::Instant::now() {
+                if !buf.is_empty() {
+                    client
+                        .send_raw(&buf)
+                        .await
+                        .expect("Could not send commands to server");
+                    buf.clear();
+                }
+                tokio::time::sleep_until(due).await;
+            }
+        }
+
+        let mut set_pixel = |x, y, color| {
+            Request::SetPixel { x, y, color }
+                .write(&mut buf)
+                .expect("Could not encode command")
+        };
+        match entry.event {
+            Event::Pixel { x, y, color } => set_pixel(x, y, color),
+            Event::Region {
+                x, y, width, data, ..
+            } => {
+                for (i, color) in data.into_iter().enumerate() {
+                    set_pixel(x + i % width, y + i / width, color);
+                }
+            }
+        }
+        replayed += 1;
+        if buf.len() >= MAX_BATCH {
+            client
+                .send_raw(&buf)
+                .await
+                .expect("Could not send commands to server");
+            buf.clear();
+        }
+    }
+    client
+        .send_raw(&buf)
+        .await
+        .expect("Could not send commands to server");
+    replayed
+}
+
+/// Render the logged changes into an animated image with `--fps` frames per second of replay time
+///
+/// Nothing is waited for, only the timestamps of the log determine which changes are visible in which frame.
+/// Replaying as fast as possible renders one frame per change.
+/// Returns how many changes were replayed and how many frames were rendered.
+#[cfg(feature = "server")]
+fn replay_to_image(
+    path: &std::path::Path,
+    entries: &mut impl Iterator<Item = pixeldike::sinks::eventlog::LogEntry>,
+    width: usize,
+    height: usize,
+    opts: &cli::ReplayOpts,
+) -> (usize, usize) {
+    use pixeldike::pixmap::Pixmap;
+    use pixeldike::sinks::timelapse::{TimelapseFormat, TimelapseRecorder};
+
+    let format = TimelapseFormat::from_path(path).unwrap_or_else(|| {
+        panic!(
+            "Could not determine the format of {}, use a .gif, .png or .apng extension",
+            path.display()
+        )
+    });
+    let frame_delay = Duration::from_secs(1) / u32::from(opts.fps);
+    let mut recorder = TimelapseRecorder::create(path, format, width, height, frame_delay, opts.loops)
+        .expect("Could not create output image");
+    let pixmap = Pixmap::new(width, height).expect("Could not create pixmap");
+    // how much log time passes between two frames
+    let frame_period = frame_delay.mul_f64(opts.speed);
+
+    let mut first = None;
+    let mut next_frame = frame_period;
+    let mut replayed = 0;
+    for entry in entries {
+        let first = *first.get_or_insert(entry.timestamp);
+        let offset = entry.timestamp.duration_since(first).unwrap_or_default();
+        if frame_period.is_zero() {
+            if replayed > 0 {
+                recorder.write_frame(&pixmap).expect("Could not write frame");
+            }
+        } else {
+            while offset >= next_frame {
+                recorder.write_frame(&pixmap).expect("Could not write frame");
+                next_frame += frame_period;
+            }
+        }
+        if let Err(e) = entry.event.apply(&pixmap) {
+            tracing::warn!("Skipping logged change which does not fit onto the canvas: {}", e);
+        }
+        replayed += 1;
+    }
+    recorder.write_frame(&pixmap).expect("Could not write frame");
+    (replayed, recorder.frames())
+}
+
+#[cfg(feature = "server")]
+async fn bench(opts: &cli::BenchOpts, output: OutputFormat) {
+    use pixeldike::loadgen::{ClientProfile, LoadgenOptions};
+    use pixeldike::net::servers::MemoryServer;
+    use pixeldike::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    let options = LoadgenOptions {
+        clients: vec![
+            (ClientProfile::Flooder, opts.flooders),
+            (ClientProfile::PolitePainter, opts.painters),
+            (ClientProfile::Reader, opts.readers),
+            (ClientProfile::Subscriber, opts.subscribers),
+        ],
+        duration: opts.duration,
+    };
+    let run = || async {
+        let pixmap = Arc::new(Pixmap::new(opts.width, opts.height).expect("Could not create pixmap"));
+        let server = MemoryServer::new(pixmap);
+        pixeldike::loadgen::run(&server, &options).await
+    };
+
+    if opts.threads.is_empty() {
+        tracing::info!("Generating load for {:?}", opts.duration);
+        let report = run().await;
+        match output {
+            OutputFormat::Text => println!("{}", report),
+            OutputFormat::Json => println!("{}", report.to_json()),
+        }
+        return;
+    }
+
+    // every run gets a fresh runtime of its own which has to be driven from outside of the current one
+    let mut reports = Vec::new();
+    for &threads in &opts.threads {
+        tracing::info!(
+            "Generating load with {} worker threads for {:?}",
+            threads,
+            opts.duration
+        );
+        let report = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_multi_thread()
+                        .worker_threads(threads.into())
+                        .enable_all()
+                        .build()
+                        .expect("Could not create benchmark runtime")
+                        .block_on(run())
+                })
+                .join()
+                .expect("Benchmark runtime panicked")
+        });
+        reports.push((threads, report));
+    }
+
+    let baseline = reports[0].1.total_requests().max(1) as f64;
+    match output {
+        OutputFormat::Text => {
+            for (threads, report) in &reports {
+                println!(
+                    "{} worker threads ({:.2}x the throughput of {}):\n{}\n",
+                    threads,
+                    report.total_requests() as f64 / baseline,
+                    reports[0].0,
+                    report
+                );
+            }
+        }
+        OutputFormat::Json => println!(
+            "{{\"runs\":[{}]}}",
+            reports
+                .iter()
+                .map(|(threads, report)| format!(
+                    "{{\"worker_threads\":{},\"speedup\":{:.2},\"report\":{}}}",
+                    threads,
+                    report.total_requests() as f64 / baseline,
+                    report.to_json()
+                ))
+                .join(",")
+        ),
+    }
 }
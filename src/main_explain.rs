@@ -0,0 +1,330 @@
+use crate::main_utils::json_string;
+use url::Url;
+
+/// How pixeldike interprets a listen or ambient lighting url
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct UrlExplanation {
+    /// What the url configures, e.g. "TCP listener"
+    pub kind: &'static str,
+    /// Parts of the url which are used together with their effective value
+    pub recognized: Vec<(String, String)>,
+    /// Parts of the url which are present but have no effect together with the reason why
+    pub ignored: Vec<(String, String)>,
+    /// Problems which prevent pixeldike from using the url at all
+    pub errors: Vec<String>,
+}
+
+impl UrlExplanation {
+    fn recognize(&mut self, part: impl Into<String>, value: impl Into<String>) {
+        self.recognized.push((part.into(), value.into()));
+    }
+
+    fn ignore(&mut self, part: impl Into<String>, reason: impl Into<String>) {
+        self.ignored.push((part.into(), reason.into()));
+    }
+
+    /// Whether pixeldike can use the url
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Render the explanation as human readable text
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{}\n", self.kind);
+        for (part, value) in &self.recognized {
+            out.push_str(&format!("  recognized {part}: {value}\n"));
+        }
+        for (part, reason) in &self.ignored {
+            out.push_str(&format!("  ignored {part}: {reason}\n"));
+        }
+        for error in &self.errors {
+            out.push_str(&format!("  error: {error}\n"));
+        }
+        out
+    }
+
+    /// Render the explanation as JSON object
+    pub fn to_json(&self) -> String {
+        let pairs = |pairs: &[(String, String)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{{\"kind\":{},\"valid\":{},\"recognized\":{{{}}},\"ignored\":{{{}}},\"errors\":[{}]}}",
+            json_string(self.kind),
+            self.is_valid(),
+            pairs(&self.recognized),
+            pairs(&self.ignored),
+            self.errors
+                .iter()
+                .map(|e| json_string(e))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+/// Describe how a `--listen` or `--ambient` url would be interpreted by `pixeldike server`
+pub fn explain_url(url: &Url) -> UrlExplanation {
+    let mut explanation = UrlExplanation::default();
+    match url.scheme() {
+        "tcp" => {
+            explanation.kind = "TCP listener";
+            require_feature(&mut explanation, "tcp", cfg!(feature = "tcp"));
+            explain_socket_addr(&mut explanation, url, 1234);
+            explain_no_path(&mut explanation, url);
+            explain_query(
+                &mut explanation,
+                url,
+                &["multiplex", "strict", "quiet", "dialect"],
+            );
+        }
+        "tcps" => {
+            explanation.kind = "TLS encrypted TCP listener";
+            require_feature(&mut explanation, "tls", cfg!(feature = "tls"));
+            explain_socket_addr(&mut explanation, url, 1234);
+            explain_no_path(&mut explanation, url);
+            explain_query(
+                &mut explanation,
+                url,
+                &["multiplex", "strict", "quiet", "dialect", "cert", "key"],
+            );
+            for key in ["cert", "key"] {
+                if !url.query_pairs().any(|(k, _)| k == key) {
+                    explanation
+                        .errors
+                        .push(format!("the ?{}=<path> parameter is required", key));
+                }
+            }
+        }
+        "udp" => {
+            explanation.kind = "UDP listener";
+            require_feature(&mut explanation, "udp", cfg!(feature = "udp"));
+            explain_socket_addr(&mut explanation, url, 1234);
+            explain_no_path(&mut explanation, url);
+            explain_query(&mut explanation, url, &["strict", "quiet", "dialect"]);
+        }
+        "ws" => {
+            explanation.kind = "WebSocket listener";
+            require_feature(&mut explanation, "ws", cfg!(feature = "ws"));
+            explain_socket_addr(&mut explanation, url, 1235);
+            if url.path() != "/" {
+                explanation.ignore(
+                    "path",
+                    format!("the WebSocket is available on all paths, not only {}", url.path()),
+                );
+            }
+            explain_query(&mut explanation, url, &["strict", "quiet", "dialect"]);
+        }
+        "http" => {
+            explanation.kind = "HTTP listener";
+            require_feature(&mut explanation, "server", cfg!(feature = "server"));
+            explain_socket_addr(&mut explanation, url, 80);
+            explain_no_path(&mut explanation, url);
+            explain_query(&mut explanation, url, &[]);
+        }
+        "unix" => {
+            explanation.kind = "Unix socket listener";
+            require_feature(&mut explanation, "server", cfg!(feature = "server"));
+            explain_no_credentials(&mut explanation, url);
+            if url.host_str().is_some_and(|host| !host.is_empty()) {
+                explanation.ignore(
+                    "host",
+                    "unix socket paths must be absolute, e.g. unix:///run/pixeldike.sock",
+                );
+            }
+            match url.path() {
+                "" | "/" => explanation.errors.push("no socket path given".to_string()),
+                path => explanation.recognize("path", path),
+            }
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "mode" => match u32::from_str_radix(&value, 8) {
+                        Ok(mode) => explanation.recognize("mode", format!("{:o}", mode)),
+                        Err(_) => explanation
+                            .errors
+                            .push(format!("mode {:?} is not an octal number", value)),
+                    },
+                    "owner" | "group" => match value.parse::<u32>() {
+                        Ok(id) => explanation.recognize(key.as_ref(), id.to_string()),
+                        Err(_) => explanation
+                            .errors
+                            .push(format!("{} {:?} is not a numeric id", key, value)),
+                    },
+                    "strict" | "quiet" => explain_flag(&mut explanation, &key, &value),
+                    "dialect" => explain_dialect(&mut explanation, &value),
+                    _ => explanation.ignore(format!("option {}", key), "unknown option"),
+                }
+            }
+        }
+        "unix-abstract" => {
+            explanation.kind = "abstract unix socket listener";
+            require_feature(&mut explanation, "server", cfg!(feature = "server"));
+            if !cfg!(target_os = "linux") {
+                explanation
+                    .errors
+                    .push("abstract unix sockets are only available on Linux".to_string());
+            }
+            explain_no_credentials(&mut explanation, url);
+            match crate::main_utils::abstract_socket_name(url) {
+                name if name.is_empty() => explanation.errors.push("no socket name given".to_string()),
+                name => explanation.recognize("name", name),
+            }
+            explain_query(&mut explanation, url, &["strict", "quiet", "dialect"]);
+        }
+        "wled" => {
+            explanation.kind = "WLED ambient lighting device";
+            require_feature(&mut explanation, "server", cfg!(feature = "server"));
+            explain_socket_addr(&mut explanation, url, 21324);
+            explain_no_path(&mut explanation, url);
+            for (key, value) in url.query_pairs() {
+                match key.as_ref() {
+                    "leds" => match value.parse::<usize>() {
+                        Ok(leds) => explanation.recognize("leds", leds.to_string()),
+                        Err(_) => explanation
+                            .errors
+                            .push(format!("leds {:?} is not a number", value)),
+                    },
+                    _ => explanation.ignore(format!("option {}", key), "unknown option"),
+                }
+            }
+        }
+        "hue" => {
+            explanation.kind = "Philips Hue ambient lighting device";
+            require_feature(&mut explanation, "server", cfg!(feature = "server"));
+            match url.username() {
+                "" => explanation.errors.push("no api username given".to_string()),
+                username => explanation.recognize("username", username),
+            }
+            if url.password().is_some() {
+                explanation.ignore("password", "the Hue api only uses a username");
+            }
+            match url.host_str() {
+                Some(host) if !host.is_empty() => explanation.recognize("bridge", host),
+                _ => explanation.errors.push("no bridge address given".to_string()),
+            }
+            explanation.recognize("port", url.port().unwrap_or(80).to_string());
+            match url.path().trim_start_matches('/').parse::<u32>() {
+                Ok(light) => explanation.recognize("light", light.to_string()),
+                Err(_) => explanation
+                    .errors
+                    .push(format!("path {:?} is not a numeric light id", url.path())),
+            }
+            for (key, _) in url.query_pairs() {
+                explanation.ignore(format!("option {}", key), "unknown option");
+            }
+        }
+        scheme => {
+            explanation.kind = "unknown url";
+            explanation.errors.push(format!(
+                "unsupported scheme {:?}, expected one of tcp, udp, ws, http, unix, unix-abstract, wled or hue",
+                scheme
+            ));
+        }
+    }
+
+    if url.fragment().is_some() {
+        explanation.ignore("fragment", "fragments are never used");
+    }
+    explanation
+}
+
+fn require_feature(explanation: &mut UrlExplanation, feature: &str, enabled: bool) {
+    if !enabled {
+        explanation
+            .errors
+            .push(format!("this binary was built without the {:?} feature", feature));
+    }
+}
+
+fn explain_no_credentials(explanation: &mut UrlExplanation, url: &Url) {
+    if !url.username().is_empty() || url.password().is_some() {
+        explanation.ignore("credentials", "listeners do not support authentication via url");
+    }
+}
+
+fn explain_socket_addr(explanation: &mut UrlExplanation, url: &Url, default_port: u16) {
+    explain_no_credentials(explanation, url);
+    match url.host_str() {
+        Some(host) if !host.is_empty() => explanation.recognize("host", host),
+        _ => explanation.errors.push("no host given".to_string()),
+    }
+    match url.port() {
+        Some(port) => explanation.recognize("port", port.to_string()),
+        None => explanation.recognize("port", format!("{} (default)", default_port)),
+    }
+}
+
+fn explain_no_path(explanation: &mut UrlExplanation, url: &Url) {
+    if !url.path().is_empty() && url.path() != "/" {
+        explanation.ignore("path", "paths are not supported by this kind of url");
+    }
+}
+
+fn explain_query(explanation: &mut UrlExplanation, url: &Url, flags: &[&str]) {
+    for (key, value) in url.query_pairs() {
+        if key == "dialect" && flags.contains(&"dialect") {
+            explain_dialect(explanation, &value);
+        } else if flags.contains(&key.as_ref()) {
+            explain_flag(explanation, &key, &value);
+        } else {
+            explanation.ignore(format!("option {}", key), "unknown option");
+        }
+    }
+}
+
+/// The protocol dialect of a listener must be one of the known names
+fn explain_dialect(explanation: &mut UrlExplanation, value: &str) {
+    #[cfg(feature = "server")]
+    match value.parse::<pixeldike::net::servers::Dialect>() {
+        Ok(dialect) => explanation.recognize("dialect", dialect.to_string()),
+        Err(e) => explanation.errors.push(e.to_string()),
+    }
+    #[cfg(not(feature = "server"))]
+    explanation.recognize("dialect", value);
+}
+
+/// Boolean options are only enabled by the exact value `true`
+fn explain_flag(explanation: &mut UrlExplanation, key: &str, value: &str) {
+    match value {
+        "true" => explanation.recognize(key, "enabled"),
+        "false" => explanation.recognize(key, "disabled"),
+        _ => explanation.recognize(
+            key,
+            format!("disabled (only \"true\" enables it, got {:?})", value),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_explain_url() {
+        let explanation = explain_url(&Url::parse("tcp://0.0.0.0?multiplex=true&strcit=true").unwrap());
+        assert!(explanation.is_valid());
+        assert!(explanation
+            .recognized
+            .contains(&("port".into(), "1234 (default)".into())));
+        assert!(explanation
+            .recognized
+            .contains(&("multiplex".into(), "enabled".into())));
+        assert_eq!(
+            explanation.ignored,
+            vec![("option strcit".into(), "unknown option".into())]
+        );
+
+        assert!(!explain_url(&Url::parse("unix:///tmp/pixelflut.sock?mode=rw").unwrap()).is_valid());
+        assert!(!explain_url(&Url::parse("gopher://localhost").unwrap()).is_valid());
+        // dialect names are only known when the servers are built
+        #[cfg(feature = "server")]
+        assert!(!explain_url(&Url::parse("udp://0.0.0.0?dialect=modern").unwrap()).is_valid());
+        assert!(explain_url(&Url::parse("tcp://0.0.0.0?dialect=classic").unwrap())
+            .recognized
+            .contains(&("dialect".into(), "classic".into())));
+    }
+}
@@ -0,0 +1,288 @@
+//! Strategies by which `put-rectangle` colors the pixels of its rectangle
+//!
+//! Before the command buffer is filled for a loop iteration, the [`FillStrategy`] is told which iteration is about to
+//! be drawn and then asked for the color of every pixel.
+//! Strategies whose colors change between iterations make the client regenerate its commands before every send.
+
+use crate::cli::{self, GradientDirection, TargetColor};
+use pixeldike::pixmap::Color;
+use rand::random;
+
+/// A way of coloring the pixels of a rectangle
+pub trait FillStrategy: Send + Sync {
+    /// Prepare drawing the given loop iteration which counts up from 0
+    fn begin_iteration(&mut self, _iteration: usize) {}
+
+    /// The color of the pixel at (x,y) relative to the top-left corner of a rectangle with the given size
+    fn color(&self, x: usize, y: usize, width: usize, height: usize) -> Color;
+
+    /// Whether colors change between iterations so that the commands need to be generated again for every send
+    fn per_iteration(&self) -> bool {
+        false
+    }
+}
+
+/// A color given on the command-line which might be chosen randomly
+struct ChosenColor {
+    target: TargetColor,
+    color: Color,
+}
+
+impl ChosenColor {
+    fn new(target: TargetColor) -> Self {
+        Self {
+            target,
+            color: Color::default(),
+        }
+    }
+
+    /// Choose the color for an iteration
+    fn choose(&mut self, iteration: usize) {
+        self.color = match self.target {
+            TargetColor::Specific(color) => color,
+            TargetColor::RandomOnce if iteration > 0 => self.color,
+            TargetColor::RandomOnce | TargetColor::RandomPerIteration => {
+                Color::from((random(), random(), random()))
+            }
+        };
+    }
+
+    /// Whether a different color is chosen for every iteration
+    fn varies(&self) -> bool {
+        matches!(self.target, TargetColor::RandomPerIteration)
+    }
+}
+
+/// Draw every pixel in the same color
+struct Solid(ChosenColor);
+
+impl FillStrategy for Solid {
+    fn begin_iteration(&mut self, iteration: usize) {
+        self.0.choose(iteration);
+    }
+
+    fn color(&self, _x: usize, _y: usize, _width: usize, _height: usize) -> Color {
+        self.0.color
+    }
+
+    fn per_iteration(&self) -> bool {
+        self.0.varies()
+    }
+}
+
+/// Draw every pixel in a random color
+struct Noise {
+    animate: bool,
+}
+
+impl FillStrategy for Noise {
+    fn color(&self, _x: usize, _y: usize, _width: usize, _height: usize) -> Color {
+        Color::from((random(), random(), random()))
+    }
+
+    fn per_iteration(&self) -> bool {
+        self.animate
+    }
+}
+
+/// Draw smoothly changing random colors
+///
+/// Random colors are placed on a grid with `scale` pixels between its points and the pixels in between are
+/// interpolated from the four surrounding grid points.
+struct ValueNoise {
+    scale: usize,
+    seed: u64,
+}
+
+impl ValueNoise {
+    /// The random color channels at a point of the grid
+    fn grid_point(&self, x: usize, y: usize) -> [f32; 3] {
+        let [r, g, b, ..] = splitmix64(self.seed ^ splitmix64(((x as u64) << 32) | y as u64)).to_le_bytes();
+        [r as f32, g as f32, b as f32]
+    }
+}
+
+impl FillStrategy for ValueNoise {
+    fn color(&self, x: usize, y: usize, _width: usize, _height: usize) -> Color {
+        let (grid_x, grid_y) = (x / self.scale, y / self.scale);
+        let smooth = |offset: usize| {
+            let t = offset as f32 / self.scale as f32;
+            t * t * (3.0 - 2.0 * t)
+        };
+        let (tx, ty) = (smooth(x % self.scale), smooth(y % self.scale));
+
+        let top_left = self.grid_point(grid_x, grid_y);
+        let top_right = self.grid_point(grid_x + 1, grid_y);
+        let bottom_left = self.grid_point(grid_x, grid_y + 1);
+        let bottom_right = self.grid_point(grid_x + 1, grid_y + 1);
+        let channel = |i: usize| {
+            let top = lerp(top_left[i], top_right[i], tx);
+            let bottom = lerp(bottom_left[i], bottom_right[i], tx);
+            lerp(top, bottom, ty).round() as u8
+        };
+        Color::from((channel(0), channel(1), channel(2)))
+    }
+}
+
+/// Fade linearly from one color to another
+struct Gradient {
+    from: ChosenColor,
+    to: ChosenColor,
+    direction: GradientDirection,
+}
+
+impl FillStrategy for Gradient {
+    fn begin_iteration(&mut self, iteration: usize) {
+        self.from.choose(iteration);
+        self.to.choose(iteration);
+    }
+
+    fn color(&self, x: usize, y: usize, width: usize, height: usize) -> Color {
+        let (position, length) = match self.direction {
+            GradientDirection::Horizontal => (x, width - 1),
+            GradientDirection::Vertical => (y, height - 1),
+            GradientDirection::Diagonal => (x + y, width + height - 2),
+        };
+        let t = position as f32 / length.max(1) as f32;
+        let from: [u8; 3] = self.from.color.into();
+        let to: [u8; 3] = self.to.color.into();
+        let channel = |i: usize| lerp(from[i] as f32, to[i] as f32, t).round() as u8;
+        Color::from((channel(0), channel(1), channel(2)))
+    }
+
+    fn per_iteration(&self) -> bool {
+        self.from.varies() || self.to.varies()
+    }
+}
+
+/// Draw diagonal rainbow stripes whose hues move along with every iteration
+struct Rainbow {
+    step: f32,
+    offset: f32,
+}
+
+impl FillStrategy for Rainbow {
+    fn begin_iteration(&mut self, iteration: usize) {
+        self.offset = (iteration as f32 * self.step).rem_euclid(360.0);
+    }
+
+    fn color(&self, x: usize, y: usize, width: usize, height: usize) -> Color {
+        let hue = (x + y) as f32 / (width + height) as f32 * 360.0 + self.offset;
+        hue_to_color(hue.rem_euclid(360.0))
+    }
+
+    fn per_iteration(&self) -> bool {
+        self.step != 0.0
+    }
+}
+
+impl cli::FillMode {
+    /// Construct the strategy which implements this mode with `color` as its target color
+    pub fn strategy(&self, color: &TargetColor) -> Box<dyn FillStrategy> {
+        match self {
+            cli::FillMode::Solid => Box::new(Solid(ChosenColor::new(color.clone()))),
+            cli::FillMode::Noise { animate } => Box::new(Noise { animate: *animate }),
+            cli::FillMode::ValueNoise { scale } => Box::new(ValueNoise {
+                scale: *scale,
+                seed: random(),
+            }),
+            cli::FillMode::Gradient { to, direction } => Box::new(Gradient {
+                from: ChosenColor::new(color.clone()),
+                to: ChosenColor::new(to.clone()),
+                direction: *direction,
+            }),
+            cli::FillMode::Rainbow { step } => Box::new(Rainbow {
+                step: *step,
+                offset: 0.0,
+            }),
+        }
+    }
+}
+
+fn lerp(from: f32, to: f32, t: f32) -> f32 {
+    from + (to - from) * t
+}
+
+/// A fully saturated and bright color with the given hue in degrees
+fn hue_to_color(hue: f32) -> Color {
+    let x = 1.0 - ((hue / 60.0) % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let channel = |value: f32| (value * 255.0).round() as u8;
+    Color::from((channel(r), channel(g), channel(b)))
+}
+
+/// A fast integer hash which spreads the bits of its input over the whole output
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cli::FillMode;
+
+    #[test]
+    fn test_parse_fill_mode() {
+        assert_eq!("solid".parse(), Ok(FillMode::Solid));
+        assert_eq!(
+            "gradient:to=0000FF,direction=vertical".parse(),
+            Ok(FillMode::Gradient {
+                to: TargetColor::Specific(Color::from(0x0000FF)),
+                direction: GradientDirection::Vertical,
+            })
+        );
+        assert_eq!("rainbow:step=-5".parse(), Ok(FillMode::Rainbow { step: -5.0 }));
+        assert!("value-noise:scale=0".parse::<FillMode>().is_err());
+        assert!("noise:scale=4".parse::<FillMode>().is_err());
+        assert!("plaid".parse::<FillMode>().is_err());
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mode: FillMode = "gradient:to=0000FF,direction=diagonal".parse().unwrap();
+        let mut strategy = mode.strategy(&TargetColor::Specific(Color::from(0xFF0000)));
+        strategy.begin_iteration(0);
+        assert!(!strategy.per_iteration());
+        assert_eq!(strategy.color(0, 0, 5, 3), Color::from(0xFF0000));
+        assert_eq!(strategy.color(4, 2, 5, 3), Color::from(0x0000FF));
+        assert_eq!(strategy.color(2, 1, 5, 3), Color::from(0x800080));
+    }
+
+    #[test]
+    fn test_value_noise_is_smooth() {
+        let mut strategy = FillMode::ValueNoise { scale: 8 }.strategy(&TargetColor::RandomOnce);
+        strategy.begin_iteration(0);
+        for y in 0..32 {
+            for x in 0..31 {
+                let left: [u8; 3] = strategy.color(x, y, 32, 32).into();
+                let right: [u8; 3] = strategy.color(x + 1, y, 32, 32).into();
+                for (left, right) in left.into_iter().zip(right) {
+                    // the steepest part of the interpolation changes by 1.5 * 255 / scale per pixel
+                    assert!(left.abs_diff(right) <= 49, "{x},{y}: {left} {right}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rainbow_moves() {
+        let mut strategy = FillMode::Rainbow { step: 120.0 }.strategy(&TargetColor::RandomOnce);
+        assert!(strategy.per_iteration());
+        strategy.begin_iteration(0);
+        assert_eq!(strategy.color(0, 0, 10, 10), Color::from(0xFF0000));
+        strategy.begin_iteration(1);
+        assert_eq!(strategy.color(0, 0, 10, 10), Color::from(0x00FF00));
+        strategy.begin_iteration(2);
+        assert_eq!(strategy.color(0, 0, 10, 10), Color::from(0x0000FF));
+    }
+}
@@ -0,0 +1,173 @@
+//! Strategies by which clients rearrange their prepared commands before sending them
+//!
+//! Some servers treat clients which flood perfectly sequential commands differently from others.
+//! The client loops therefore pass their command buffer through a [`CommandArranger`] which reorders the commands
+//! according to an [`OrderingStrategy`] and optionally interleaves pixel reads.
+
+use crate::cli;
+use bytes::{BufMut, BytesMut};
+use pixeldike::net::protocol::{parse_request_bin, request_frame_len, Request};
+use rand::seq::SliceRandom;
+use std::num::NonZeroUsize;
+
+/// A way of ordering the commands of a client's buffer
+pub trait OrderingStrategy: Send + Sync {
+    /// Reorder the given commands in place
+    fn arrange(&mut self, commands: &mut [&[u8]]);
+
+    /// Whether the commands should be reordered before every send instead of only when the buffer is filled
+    fn per_send(&self) -> bool {
+        false
+    }
+}
+
+/// Keep commands in the order in which they were generated
+pub struct Sequential;
+
+impl OrderingStrategy for Sequential {
+    fn arrange(&mut self, _commands: &mut [&[u8]]) {}
+}
+
+/// Send commands in a random order
+pub struct Shuffle {
+    /// Whether a new order is chosen for every send
+    pub per_send: bool,
+}
+
+impl OrderingStrategy for Shuffle {
+    fn arrange(&mut self, commands: &mut [&[u8]]) {
+        commands.shuffle(&mut rand::thread_rng());
+    }
+
+    fn per_send(&self) -> bool {
+        self.per_send
+    }
+}
+
+impl cli::CommandOrder {
+    /// Construct the strategy which implements this order
+    pub fn strategy(self) -> Box<dyn OrderingStrategy> {
+        match self {
+            cli::CommandOrder::Sequential => Box::new(Sequential),
+            cli::CommandOrder::Shuffle => Box::new(Shuffle { per_send: false }),
+            cli::CommandOrder::ShufflePerSend => Box::new(Shuffle { per_send: true }),
+        }
+    }
+}
+
+/// Turns a client's command buffer into the data that is actually sent
+pub struct CommandArranger {
+    strategy: Box<dyn OrderingStrategy>,
+    /// After how many commands a read of the last written pixel is inserted
+    read_every: Option<NonZeroUsize>,
+    arranged: BytesMut,
+    /// How many reads were inserted into `arranged`
+    reads: usize,
+}
+
+impl CommandArranger {
+    /// Create an arranger as configured on the command-line
+    pub fn new(opts: &cli::CommonClientOps) -> Self {
+        Self {
+            strategy: opts.order.strategy(),
+            read_every: opts.interleave_reads,
+            arranged: BytesMut::new(),
+            reads: 0,
+        }
+    }
+
+    /// Whether [`arrange`](Self::arrange) needs to be called again before every send
+    pub fn per_send(&self) -> bool {
+        self.strategy.per_send()
+    }
+
+    /// Rearrange a buffer of commands and return the data which should be sent instead
+    pub fn arrange(&mut self, commands: &[u8]) -> &[u8] {
+        self.arranged.clear();
+        self.reads = 0;
+
+        let mut frames = Vec::new();
+        let mut rest = commands;
+        while let Some(len) = request_frame_len(rest) {
+            frames.push(&rest[..len]);
+            rest = &rest[len..];
+        }
+        self.strategy.arrange(&mut frames);
+
+        for (i, frame) in frames.into_iter().enumerate() {
+            self.arranged.extend_from_slice(frame);
+            if self.read_every.is_some_and(|n| (i + 1) % n.get() == 0) {
+                if let Ok(Request::SetPixel { x, y, .. } | Request::BlendPixel { x, y, .. }) =
+                    parse_request_bin(frame)
+                {
+                    let read = Request::GetPixel { x, y };
+                    read.write(&mut (&mut self.arranged).writer()).unwrap();
+                    self.reads += 1;
+                }
+            }
+        }
+        self.arranged.extend_from_slice(rest);
+        &self.arranged
+    }
+
+    /// The data produced by the last call to [`arrange`](Self::arrange)
+    pub fn commands(&self) -> &[u8] {
+        &self.arranged
+    }
+
+    /// How many reads the last arranged data contains whose responses need to be received
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pixeldike::pixmap::Color;
+
+    #[test]
+    fn test_interleave_reads() {
+        let mut arranger = CommandArranger {
+            strategy: Box::new(Sequential),
+            read_every: NonZeroUsize::new(2),
+            arranged: BytesMut::new(),
+            reads: 0,
+        };
+        let mut buf = Vec::new();
+        for x in 0..5 {
+            let color = Color::from(0xFF0000);
+            Request::SetPixel { x, y: 1, color }.write(&mut buf).unwrap();
+        }
+        Request::GetSize.write_binary(&mut buf).unwrap();
+
+        assert_eq!(
+            arranger.arrange(&buf),
+            b"PX 0 1 FF0000\nPX 1 1 FF0000\nPX 1 1\nPX 2 1 FF0000\nPX 3 1 FF0000\nPX 3 1\nPX 4 1 FF0000\nSIZE\n"
+        );
+        assert_eq!(arranger.reads(), 2);
+    }
+
+    #[test]
+    fn test_shuffle_keeps_commands() {
+        let mut arranger = CommandArranger {
+            strategy: Box::new(Shuffle { per_send: true }),
+            read_every: None,
+            arranged: BytesMut::new(),
+            reads: 0,
+        };
+        let mut buf = Vec::new();
+        for x in 0..100 {
+            let color = Color::from(0x00FF00);
+            Request::SetPixel { x, y: 0, color }
+                .write_binary(&mut buf)
+                .unwrap();
+        }
+
+        let mut arranged = arranger.arrange(&buf).chunks(10).collect::<Vec<_>>();
+        arranged.sort();
+        let mut original = buf.chunks(10).collect::<Vec<_>>();
+        original.sort();
+        assert_eq!(arranged, original);
+    }
+}
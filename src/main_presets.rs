@@ -0,0 +1,274 @@
+//! Named profiles of client options which are stored in a user config file
+//!
+//! Profiles are read from `$XDG_CONFIG_HOME/pixeldike/profiles.toml` (usually `~/.config/pixeldike/profiles.toml`)
+//! and consist of one table per profile whose keys are the names of command-line options.
+//! They are called presets here to tell them apart from the runtime [`Profile`](crate::cli::Profile):
+//!
+//! ```toml
+//! [event]
+//! server = "tcp://pixelflut.example.org:1234"
+//! x = 100
+//! width = 200
+//! send-jitter = "2ms"
+//! binary = true
+//! ```
+//!
+//! Selecting a preset with `--profile event` expands it into command-line arguments before they are parsed.
+//! Options which are given explicitly take precedence over the preset and options which the chosen subcommand
+//! doesn't accept are skipped so that one preset can be shared by e.g. `put-image` and `show`.
+
+use anyhow::{anyhow, Context};
+use clap::CommandFactory;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Options of one preset as pairs of option name and value
+type Preset = Vec<(String, PresetValue)>;
+
+/// A value which is assigned to an option in the presets file
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum PresetValue {
+    /// A string or number which is passed as value of the option
+    Value(String),
+    /// Whether a flag is given
+    Flag(bool),
+}
+
+/// Where presets are stored unless overridden via `PIXELDIKE_PROFILES_FILE`
+fn presets_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("PIXELDIKE_PROFILES_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("pixeldike").join("profiles.toml"))
+}
+
+/// Replace a `--profile <NAME>` argument by the options which are stored in the named preset
+pub fn expand_presets(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let Some(name) = preset_name(&args) else {
+        return Ok(args);
+    };
+    let path = presets_path().ok_or_else(|| anyhow!("Could not determine the config directory"))?;
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Could not read profiles from {}", path.display()))?;
+    let mut presets = parse_presets(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let preset = presets
+        .remove(&name)
+        .ok_or_else(|| anyhow!("{} contains no profile named {:?}", path.display(), name))?;
+    apply_preset(args, &preset)
+}
+
+/// Find the value of the `--profile` argument
+fn preset_name(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            return None;
+        } else if arg == "--profile" {
+            return args.next().map(|name| name.into_owned());
+        } else if let Some(name) = arg.strip_prefix("--profile=") {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+/// Append the options of a preset which are not already given to the arguments of the selected subcommand
+fn apply_preset(mut args: Vec<OsString>, preset: &Preset) -> anyhow::Result<Vec<OsString>> {
+    let command = crate::cli::CliOpts::command();
+    let subcommand = args
+        .iter()
+        .zip(std::iter::once(None).chain(args.iter().map(Some)))
+        .filter(|(_, previous)| *previous != Some(&OsString::from("--profile")))
+        .find_map(|(arg, _)| command.find_subcommand(arg))
+        .ok_or_else(|| anyhow!("--profile requires a subcommand"))?;
+
+    let end = args.iter().position(|arg| arg == "--").unwrap_or(args.len());
+    let mut expanded = Vec::new();
+    for (key, value) in preset {
+        let known = |cmd: &clap::Command| {
+            cmd.get_arguments().any(|arg| {
+                arg.get_long() == Some(key.as_str())
+                    || arg
+                        .get_short()
+                        .is_some_and(|short| key.len() == 1 && key.starts_with(short))
+            })
+        };
+        if !known(subcommand) {
+            if !command.get_subcommands().any(known) {
+                return Err(anyhow!("Profile contains unknown option {:?}", key));
+            }
+            continue;
+        }
+
+        let option = match key.len() {
+            1 => format!("-{}", key),
+            _ => format!("--{}", key),
+        };
+        let given = args[..end].iter().any(|arg| {
+            let arg = arg.to_string_lossy();
+            arg == option || arg.starts_with(&format!("{}=", option))
+        });
+        match value {
+            _ if given => {}
+            PresetValue::Flag(true) => expanded.push(OsString::from(option)),
+            PresetValue::Flag(false) => {}
+            PresetValue::Value(value) => {
+                expanded.push(OsString::from(option));
+                expanded.push(OsString::from(value));
+            }
+        }
+    }
+    args.splice(end..end, expanded);
+    Ok(args)
+}
+
+/// Parse the subset of TOML which is used by the presets file
+///
+/// Only tables containing strings, numbers and booleans are supported.
+fn parse_presets(content: &str) -> Result<HashMap<String, Preset>, String> {
+    let mut presets = HashMap::new();
+    let mut current: Option<&mut Preset> = None;
+    for (i, line) in content.lines().enumerate() {
+        let line_no = i + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = unquote(name.trim()).unwrap_or_else(|| name.trim().to_string());
+            if name.is_empty() {
+                return Err(format!("line {}: empty profile name", line_no));
+            }
+            current = Some(presets.entry(name).or_default());
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value` or `[profile]`", line_no))?;
+        let preset = current
+            .as_deref_mut()
+            .ok_or_else(|| format!("line {}: option outside of a [profile] table", line_no))?;
+        let key = key.trim();
+        let key = unquote(key).unwrap_or_else(|| key.to_string());
+        let value = match value.trim() {
+            "true" => PresetValue::Flag(true),
+            "false" => PresetValue::Flag(false),
+            value if value.starts_with('"') => PresetValue::Value(
+                unquote(value).ok_or_else(|| format!("line {}: invalid string {}", line_no, value))?,
+            ),
+            value
+                if !value.is_empty()
+                    && value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-._".contains(c)) =>
+            {
+                PresetValue::Value(value.replace('_', ""))
+            }
+            value => return Err(format!("line {}: unsupported value {}", line_no, value)),
+        };
+        preset.push((key, value));
+    }
+    Ok(presets)
+}
+
+/// Remove a trailing `#` comment which is not part of a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Decode a double quoted TOML string
+fn unquote(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            '"' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_preset() {
+        let presets = parse_presets(
+            r#"
+            # targets of the local event
+            [event]
+            server = "tcp://pixelflut.example.org:1234" # the big screen
+            x = 100
+            width = 200
+            order = "shuffle"
+            binary = true
+
+            ["other event"]
+            server = "udp://10.0.0.1:1234"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(presets["other event"].len(), 1);
+
+        let args = [
+            "pixeldike",
+            "put-rectangle",
+            "--profile",
+            "event",
+            "--width",
+            "50",
+            "--",
+            "ignored",
+        ]
+        .map(OsString::from)
+        .to_vec();
+        let args = apply_preset(args, &presets["event"]).unwrap();
+        assert_eq!(
+            args.join(" ".as_ref()),
+            "pixeldike put-rectangle --profile event --width 50 --server tcp://pixelflut.example.org:1234 -x 100 \
+             --order shuffle --binary -- ignored"
+        );
+
+        // options which the subcommand doesn't accept are skipped
+        let args = ["pixeldike", "show", "--profile", "event"]
+            .map(OsString::from)
+            .to_vec();
+        let args = apply_preset(args, &presets["event"]).unwrap();
+        assert_eq!(
+            args.join(" ".as_ref()),
+            "pixeldike show --profile event --server tcp://pixelflut.example.org:1234"
+        );
+
+        assert!(parse_presets("server = \"tcp://localhost\"").is_err());
+        let typo = parse_presets("[typo]\nservre = \"tcp://localhost\"").unwrap();
+        let args = ["pixeldike", "show"].map(OsString::from).to_vec();
+        assert!(apply_preset(args, &typo["typo"]).is_err());
+    }
+}
@@ -0,0 +1,1125 @@
+use crate::{cli, main_utils};
+use anyhow::Context;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{interval, interval_at, Instant};
+
+use pixeldike::net::servers::{
+    AccessLog, AccessLogFormat, AccessLogOptions, AllowedOrigins, Capabilities, Capability,
+    ClientStatsOptions, ConnectionHooks, ControlServer, ControlServerOptions, GenServer, HttpServer,
+    HttpServerOptions, LagPolicy, NoHooks, ReadBufferLimits, RegionRateLimit, RequestIds, Reservations,
+    TcpServer, TcpServerOptions, UnixSocketOptions, UnixSocketServer, WriteQueueLimits,
+    DEFAULT_CONNECTION_POOL_SIZE,
+};
+#[cfg(feature = "tls")]
+use pixeldike::net::servers::{TcpTlsServer, TcpTlsServerOptions};
+#[cfg(feature = "udp")]
+use pixeldike::net::servers::{UdpServer, UdpServerOptions};
+#[cfg(feature = "ws")]
+use pixeldike::net::servers::{WsServer, WsServerOptions};
+use pixeldike::pixmap::{
+    Canvases, Effect, Pixmap, ProtectedWrites, SharedPixmap, DEFAULT_CANVAS, DEFAULT_CHANGE_CAPACITY,
+};
+#[cfg(feature = "signing")]
+use pixeldike::signing::Signer;
+use pixeldike::sinks::ambient::{AmbientSink, AmbientSinkOptions, AmbientTarget};
+use pixeldike::sinks::color_stats::{ColorStatsSink, ColorStatsSinkOptions, LatestColorStats};
+use pixeldike::sinks::dlna::{DlnaOptions, DlnaSink};
+use pixeldike::sinks::effects::{EffectSink, EffectSinkOptions};
+use pixeldike::sinks::eventlog::{EventLogOptions, EventLogSink};
+use pixeldike::sinks::ffmpeg::{FfmpegOptions, FfmpegSink};
+use pixeldike::sinks::framebuffer::{FramebufferSink, FramebufferSinkOptions};
+use pixeldike::sinks::pixmap_file::{FileSink, FileSinkOptions, SnapshotFormat};
+use pixeldike::sinks::timelapse::{TimelapseFormat, TimelapseOptions, TimelapseSink};
+use pixeldike::sinks::webhook::{WebhookOptions, WebhookSink};
+#[cfg(feature = "windowing")]
+use pixeldike::sinks::window::{ScalingFilter, WindowSinkOptions};
+use pixeldike::watchdog::{Watchdog, WatchdogAction, WatchdogOptions};
+use pixeldike::DaemonResult;
+
+/// How often the async runtime proves to the watchdog that it is still responsive
+const RUNTIME_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many of the busiest clients are listed in the periodic log message of `--client-stats`
+const LEADERBOARD_LOG_LEN: usize = 5;
+
+/// Download the canvas of another pixelflut server, e.g. to migrate an event to a new host
+async fn bootstrap_pixmap(url: &url::Url) -> anyhow::Result<Pixmap> {
+    if !matches!(url.scheme(), "tcp" | "tcps" | "unix" | "unix-abstract") {
+        return Err(anyhow::anyhow!(
+            "only tcp://, tcps:// and unix socket urls can be used because the server needs to respond"
+        ));
+    }
+    let (width, height, colors) = main_utils::DynClient::connect(url).await?.fetch_canvas().await?;
+    let pixmap = Pixmap::new(width, height)?;
+    pixmap.set_region(0, 0, width, height, &colors)?;
+    tracing::info!("Bootstrapped {}x{} canvas from {}", width, height, url);
+    Ok(pixmap)
+}
+
+/// Run the server until it is stopped
+///
+/// All servers and sinks are spawned onto the worker threads of the runtime. Only the window has to stay on the
+/// thread which opened it, so it is the only reason for running the server inside a
+/// [`LocalSet`](tokio::task::LocalSet).
+pub(crate) async fn run_server(opts: &cli::ServerOpts, profile: cli::Profile) {
+    #[cfg(feature = "windowing")]
+    if opts.open_window {
+        return tokio::task::LocalSet::new()
+            .run_until(start_server(opts, profile))
+            .await;
+    }
+    start_server(opts, profile).await
+}
+
+async fn start_server(opts: &cli::ServerOpts, profile: cli::Profile) {
+    // create a pixmap or load its initial content from a snapshot or another server
+    let loaded_pixmap = match (&opts.file_opts.load_snapshot, &opts.file_opts.bootstrap_from) {
+        (Some(path), _) => Some(
+            pixeldike::sinks::pixmap_file::load_pixmap_file(path)
+                .await
+                .map_err(|e| format!("Could not load snapshot from {}: {}", path.display(), e)),
+        ),
+        (None, Some(url)) => Some(
+            bootstrap_pixmap(url)
+                .await
+                .map_err(|e| format!("Could not bootstrap canvas from {}: {}", url, e)),
+        ),
+        (None, None) => None,
+    };
+    let pixmap = match loaded_pixmap {
+        None => Pixmap::new(opts.width, opts.height).unwrap(),
+        Some(Err(e)) => {
+            tracing::error!("{}, using empty pixmap instead", e);
+            Pixmap::new(opts.width, opts.height).unwrap()
+        }
+        Some(Ok(loaded_pixmap)) => {
+            let (width, height) = loaded_pixmap.get_size();
+            if width != opts.width || height != opts.height {
+                tracing::warn!(
+                    "Loaded canvas has different dimensions than {}x{}, creating an empty pixmap instead",
+                    opts.width,
+                    opts.height
+                );
+                Pixmap::new(opts.width, opts.height).unwrap()
+            } else {
+                loaded_pixmap
+            }
+        }
+    };
+    let pixmap = match opts.track_activity {
+        true => pixmap.with_activity_tracking(),
+        false => pixmap,
+    };
+    let pixmap = match opts.pixel_ttl_secs {
+        Some(ttl) => pixmap.with_pixel_ttl(Duration::from_secs(ttl), opts.pixel_ttl_background),
+        None => pixmap,
+    };
+    let pixmap = pixmap.with_protected_writes(match opts.reject_protected_writes {
+        true => ProtectedWrites::Reject,
+        false => ProtectedWrites::Drop,
+    });
+    for region in &opts.protected_regions {
+        pixmap
+            .protect(*region)
+            .expect("Protected region must lie inside the canvas");
+    }
+
+    // spread the first frame of all sinks over the warm-up period so that they don't all start working at once
+    let warm_up = match opts.file_opts.load_snapshot.is_some() || opts.file_opts.bootstrap_from.is_some() {
+        true => Duration::from_secs(opts.file_opts.warm_up_secs),
+        false => Duration::ZERO,
+    };
+    // keyframes of subscribing clients are spread over the warm-up period as well
+    let change_capacity = match profile {
+        cli::Profile::Default => DEFAULT_CHANGE_CAPACITY,
+        cli::Profile::LowPower => DEFAULT_CHANGE_CAPACITY / 16,
+    };
+    let pixmap = Arc::new(pixmap.with_change_broadcast(change_capacity, warm_up));
+
+    // create the additional canvases which clients select via CANVAS
+    let mut canvases = Canvases::default();
+    for (name, (width, height)) in &opts.canvases {
+        let canvas = Pixmap::new(*width, *height).expect("Could not create canvas");
+        let canvas = match opts.track_activity {
+            true => canvas.with_activity_tracking(),
+            false => canvas,
+        };
+        let canvas = match opts.pixel_ttl_secs {
+            Some(ttl) => canvas.with_pixel_ttl(Duration::from_secs(ttl), opts.pixel_ttl_background),
+            None => canvas,
+        };
+        canvases
+            .insert(
+                name.clone(),
+                Arc::new(canvas.with_change_broadcast(change_capacity, Duration::ZERO)),
+            )
+            .unwrap_or_else(|e| panic!("Could not create canvas {}: {}", name, e));
+    }
+    for (sink, name) in &opts.sink_canvases {
+        if canvases.get(name).is_none() {
+            panic!("{:?} sink is attached to unknown canvas {}", sink, name);
+        }
+    }
+    let canvases = Arc::new(canvases);
+
+    // configure the watchdog which observes all other tasks
+    let watchdog = opts.watchdog_opts.watchdog_timeout_secs.map(|timeout| {
+        let webhook = opts.watchdog_opts.watchdog_webhook.clone();
+        if webhook.as_ref().is_some_and(|url| url.scheme() != "http") {
+            panic!("The watchdog webhook must be an http:// url");
+        }
+        Watchdog::new(WatchdogOptions {
+            timeout: Duration::from_secs(timeout),
+            action: match opts.watchdog_opts.watchdog_action {
+                cli::WatchdogAction::Log => WatchdogAction::Log,
+                cli::WatchdogAction::Restart => WatchdogAction::Restart,
+            },
+            webhook,
+        })
+    });
+
+    if opts.latency_metrics {
+        pixeldike::metrics::enable_latency_metrics();
+    }
+    if opts.client_stats {
+        pixeldike::net::servers::enable_client_stats(ClientStatsOptions {
+            ipv4_prefix: opts.client_stats_ipv4_prefix,
+            ipv6_prefix: opts.client_stats_ipv6_prefix,
+            top: opts.client_stats_top,
+        });
+    }
+
+    let reservations = Arc::new(Reservations::new(
+        opts.teams.iter().cloned().collect(),
+        Duration::from_secs(opts.max_reservation_secs),
+    ));
+    let region_limits: Arc<[RegionRateLimit]> = opts.region_limits.clone().into();
+    let allowed_origins = match opts.allowed_origins.is_empty() {
+        true => AllowedOrigins::any(),
+        false => AllowedOrigins::only(opts.allowed_origins.iter().cloned()),
+    };
+    let capabilities = match opts.hardened {
+        false => Capabilities::all(),
+        true => {
+            tracing::info!("Running hardened, only granting {:?}", opts.grants);
+            opts.grants
+                .iter()
+                .map(|grant| match grant {
+                    cli::Grant::StateDumps => Capability::StateDumps,
+                    cli::Grant::Subscriptions => Capability::Subscriptions,
+                    cli::Grant::Help => Capability::Help,
+                    cli::Grant::Canvases => Capability::Canvases,
+                    cli::Grant::Teams => Capability::Teams,
+                    cli::Grant::Stats => Capability::Stats,
+                })
+                .collect()
+        }
+    };
+
+    let hooks: Arc<dyn ConnectionHooks> = match &opts.access_log_opts.access_log {
+        None => NoHooks::shared(),
+        Some(path) => Arc::new(
+            AccessLog::open(AccessLogOptions {
+                path: path.clone(),
+                format: match opts.access_log_opts.access_log_format {
+                    cli::AccessLogFormat::Common => AccessLogFormat::Common,
+                    cli::AccessLogFormat::Json => AccessLogFormat::Json,
+                },
+                max_size: opts.access_log_opts.access_log_max_size,
+                rotate_interval: opts
+                    .access_log_opts
+                    .access_log_rotate_secs
+                    .map(Duration::from_secs),
+                keep: opts.access_log_opts.access_log_keep,
+            })
+            .await
+            .unwrap_or_else(|e| panic!("Could not open access log {}: {}", path.display(), e)),
+        ),
+    };
+
+    // the other canvases are never resized, so the tasks which analyze or show them are only started once
+    let mut kept_tasks: JoinSet<DaemonResult> = JoinSet::new();
+    let mut canvas_color_stats = LatestColorStats::default();
+    for (name, canvas) in canvases.iter() {
+        let sink = color_stats_sink(opts, canvas.clone());
+        canvas_color_stats.insert(name, &sink);
+        sink.start(&mut kept_tasks)
+            .await
+            .expect("Could not start color statistics task");
+    }
+
+    let (resize, mut resizes) = mpsc::channel(1);
+    let mut context = ServerContext {
+        pixmap,
+        canvases,
+        warm_up,
+        watchdog,
+        reservations,
+        region_limits,
+        allowed_origins,
+        capabilities,
+        hooks,
+        ready: Arc::new(AtomicBool::new(false)),
+        resize,
+        effect: opts
+            .effect_opts
+            .effect
+            .map(|effect| Arc::new(watch::channel(effect).0)),
+        request_ids: Arc::new(RequestIds::new()),
+        canvas_color_stats,
+    };
+    if let Some(watchdog) = &context.watchdog {
+        watchdog.start().expect("Could not start watchdog thread");
+    }
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not install SIGTERM handler");
+    let mut first_start = true;
+    loop {
+        let mut join_set: JoinSet<DaemonResult> = JoinSet::new();
+        let kept = first_start.then_some(&mut kept_tasks);
+        if let Err(e) = start_tasks(opts, profile, &context, &mut join_set, kept).await {
+            tracing::error!("{:#}", e);
+            join_set.shutdown().await;
+            kept_tasks.shutdown().await;
+            std::process::exit(1);
+        }
+        first_start = false;
+        if join_set.is_empty() && kept_tasks.is_empty() {
+            panic!("Nothing is supposed to be started which makes no sense. Review commandline flags.");
+        }
+
+        // everything is loaded, bound and running
+        context.ready.store(true, Ordering::Relaxed);
+
+        // wait until one tasks exits, the canvas is resized or the process is asked to terminate
+        let exited = |result: Result<DaemonResult, JoinError>| {
+            let result = result.expect("Could not join background task").unwrap_err();
+            tracing::error!("A background task exited unexpectedly: {}", result);
+            None
+        };
+        let resize = tokio::select! {
+            Some(result) = join_set.join_next() => exited(result),
+            Some(result) = kept_tasks.join_next() => exited(result),
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("Received interrupt, shutting down");
+                None
+            }
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, shutting down");
+                None
+            }
+            Some(size) = resizes.recv() => Some(size),
+        };
+
+        // cancel all other tasks
+        context.ready.store(false, Ordering::Relaxed);
+        join_set.shutdown().await;
+        let Some((width, height)) = resize else {
+            kept_tasks.shutdown().await;
+            break;
+        };
+
+        // restart everything which uses the main canvas with a resized copy of it and disconnect clients which still use the old one
+        match context.pixmap.resize(width, height) {
+            Ok(resized) => {
+                context.pixmap.retire();
+                context.pixmap = Arc::new(resized);
+                tracing::info!(
+                    "Resized canvas to {}x{}, restarting listeners and the sinks which show it",
+                    width,
+                    height
+                );
+            }
+            Err(e) => tracing::error!("Could not resize canvas: {}", e),
+        }
+        context.warm_up = Duration::ZERO;
+        if let Some(watchdog) = &context.watchdog {
+            watchdog.forget_heartbeats();
+        }
+    }
+}
+
+/// Everything which is kept while listeners and sinks are restarted to resize the canvas
+struct ServerContext {
+    pixmap: SharedPixmap,
+    canvases: Arc<Canvases>,
+    /// The period over which sinks and keyframes of subscribers spread their first frame
+    warm_up: Duration,
+    watchdog: Option<Watchdog>,
+    reservations: Arc<Reservations>,
+    region_limits: Arc<[RegionRateLimit]>,
+    allowed_origins: AllowedOrigins,
+    capabilities: Capabilities,
+    hooks: Arc<dyn ConnectionHooks>,
+    /// Whether all listeners and sinks have been started
+    ready: Arc<AtomicBool>,
+    /// Where the control socket passes on requests to resize the canvas
+    resize: mpsc::Sender<(usize, usize)>,
+    /// The effect with which display sinks show the canvas if `--effect` is given
+    effect: Option<Arc<watch::Sender<Effect>>>,
+    /// The ids of the commands which the control socket applied, kept across restarts of the control socket
+    request_ids: Arc<RequestIds>,
+    /// The latest color statistics of the other canvases which are analyzed by tasks that keep running
+    canvas_color_stats: LatestColorStats,
+}
+
+/// A sink which analyzes the colors of `canvas` in the configured interval
+fn color_stats_sink(opts: &cli::ServerOpts, canvas: SharedPixmap) -> ColorStatsSink {
+    let interval = Duration::from_millis(opts.color_stats_interval_ms);
+    ColorStatsSink::new(
+        ColorStatsSinkOptions {
+            interval: interval_at(Instant::now() + interval, interval),
+        },
+        canvas,
+    )
+}
+
+/// The tasks into which a sink is started
+///
+/// Sinks which show another canvas than the main one go into `kept_tasks` which keep running while the main canvas is
+/// resized. Those are only started once, so `None` is returned for them once `kept_tasks` is `None`.
+fn sink_tasks<'a>(
+    join_set: &'a mut JoinSet<DaemonResult>,
+    kept_tasks: &'a mut Option<&mut JoinSet<DaemonResult>>,
+    other_canvas: bool,
+) -> Option<&'a mut JoinSet<DaemonResult>> {
+    match other_canvas {
+        false => Some(join_set),
+        true => kept_tasks.as_deref_mut(),
+    }
+}
+
+/// Start all listeners and sinks which are configured by the command line options
+///
+/// Sinks which show another canvas than the main one are started into `kept_tasks` unless it is `None` because they
+/// are still running from the previous start.
+async fn start_tasks(
+    opts: &cli::ServerOpts,
+    profile: cli::Profile,
+    context: &ServerContext,
+    join_set: &mut JoinSet<DaemonResult>,
+    kept_tasks: Option<&mut JoinSet<DaemonResult>>,
+) -> anyhow::Result<()> {
+    let ServerContext {
+        pixmap,
+        canvases,
+        watchdog,
+        reservations,
+        region_limits,
+        allowed_origins,
+        hooks,
+        ready,
+        ..
+    } = context;
+    let (warm_up, capabilities) = (context.warm_up, context.capabilities);
+    let heartbeat = |name: &str, interval: Duration| watchdog.as_ref().map(|w| w.heartbeat(name, interval));
+
+    // configure the effect pipeline which display sinks show instead of the canvas
+    let effect_output = match &context.effect {
+        None => None,
+        Some(effect) => {
+            let interval = Duration::from_secs(1) / opts.effect_opts.effect_fps;
+            let sink = EffectSink::new(
+                EffectSinkOptions {
+                    interval: interval_at(Instant::now(), interval),
+                    effect: effect.subscribe(),
+                    heartbeat: heartbeat("effects", interval),
+                },
+                pixmap.clone(),
+            );
+            let output = sink.output();
+            sink.start(join_set)
+                .await
+                .context("Could not start effect pipeline")?;
+            Some(output)
+        }
+    };
+
+    // sinks which show another canvas than the main one are not affected by resizing it and thus keep running
+    let mut kept_tasks = kept_tasks;
+    let sink_pixmap = |kind: cli::SinkKind| -> (SharedPixmap, bool) {
+        let attached = opts
+            .sink_canvases
+            .iter()
+            .rfind(|(sink, _)| *sink == kind)
+            .and_then(|(_, name)| canvases.get(name).flatten());
+        let displays_effects = matches!(
+            kind,
+            cli::SinkKind::Stream
+                | cli::SinkKind::Framebuffer
+                | cli::SinkKind::Ambient
+                | cli::SinkKind::Window
+                | cli::SinkKind::Timelapse
+                | cli::SinkKind::Dlna
+        );
+        match (attached, &effect_output) {
+            (Some(canvas), _) => (canvas.clone(), true),
+            (None, Some(output)) if displays_effects => (output.clone(), false),
+            _ => (pixmap.clone(), false),
+        }
+    };
+
+    // the colors of the other canvases are analyzed by tasks which keep running
+    let mut color_stats = context.canvas_color_stats.clone();
+    let sink = color_stats_sink(opts, pixmap.clone());
+    color_stats.insert(DEFAULT_CANVAS, &sink);
+    sink.start(join_set)
+        .await
+        .context("Could not start color statistics task")?;
+    let color_stats = Arc::new(color_stats);
+
+    let sink_count = opts.file_opts.snapshot_file.is_some() as u32
+        + opts.file_opts.snapshot_png.is_some() as u32
+        + (opts.stream_opts.rtmp_dst_addr.is_some() || opts.stream_opts.rtsp_dst_addr.is_some()) as u32
+        + opts.timelapse_opts.timelapse.is_some() as u32
+        + opts.fb_opts.fb_device.is_some() as u32
+        + opts.ambient_opts.ambient.len() as u32;
+    let mut started_sinks = 0;
+    let mut warm_up_delay = || {
+        let delay = warm_up * started_sinks / sink_count.max(1);
+        started_sinks += 1;
+        delay
+    };
+
+    // configure snapshotting
+    let snapshots = [
+        (
+            &opts.file_opts.snapshot_file,
+            SnapshotFormat::Pixmap,
+            "snapshot",
+            cli::SinkKind::Snapshot,
+        ),
+        (
+            &opts.file_opts.snapshot_png,
+            SnapshotFormat::Png,
+            "png_snapshot",
+            cli::SinkKind::SnapshotPng,
+        ),
+    ];
+    #[cfg(feature = "signing")]
+    let signer = opts.file_opts.snapshot_signing_key.as_ref().map(|path| {
+        let signer = Signer::load(path)
+            .unwrap_or_else(|e| panic!("Could not load snapshot signing key {}: {}", path.display(), e));
+        tracing::info!("Signing snapshots with public key {}", signer.public_key());
+        Arc::new(signer)
+    });
+    for (path, format, name, kind) in snapshots {
+        let Some(path) = path else { continue };
+        let (pixmap, other_canvas) = sink_pixmap(kind);
+        let Some(tasks) = sink_tasks(join_set, &mut kept_tasks, other_canvas) else {
+            continue;
+        };
+        let sink = FileSink::new(
+            FileSinkOptions {
+                path: path.to_owned(),
+                interval: interval_at(
+                    Instant::now() + warm_up_delay(),
+                    Duration::from_secs(opts.file_opts.snapshot_interval_secs as u64),
+                ),
+                format,
+                heartbeat: heartbeat(
+                    name,
+                    Duration::from_secs(opts.file_opts.snapshot_interval_secs as u64),
+                ),
+                #[cfg(feature = "signing")]
+                signer: signer.clone(),
+            },
+            pixmap,
+        );
+        sink.start(tasks)
+            .await
+            .context("Could not start persistence task")?;
+    }
+
+    // configure timelapse recording
+    let (timelapse_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Timelapse);
+    if let (Some(path), Some(tasks)) = (
+        &opts.timelapse_opts.timelapse,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        let interval = Duration::from_secs(opts.timelapse_opts.timelapse_interval_secs);
+        let sink = TimelapseSink::new(
+            TimelapseOptions {
+                path: path.to_owned(),
+                format: TimelapseFormat::from_path(path).unwrap_or_else(|| {
+                    panic!(
+                        "Could not determine timelapse format of {}, use a .gif, .png or .apng extension",
+                        path.display()
+                    )
+                }),
+                interval: interval_at(Instant::now() + warm_up_delay(), interval),
+                frame_delay: Duration::from_millis(opts.timelapse_opts.timelapse_frame_delay_ms),
+                max_frames: opts.timelapse_opts.timelapse_max_frames,
+                loops: opts.timelapse_opts.timelapse_loops,
+                heartbeat: heartbeat("timelapse", interval),
+            },
+            timelapse_pixmap,
+        );
+        sink.start(tasks)
+            .await
+            .context("Could not start timelapse recording")?;
+    }
+
+    // configure recording of the canvas history
+    let (event_log_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::EventLog);
+    if let (Some(path), Some(tasks)) = (
+        &opts.event_log_opts.event_log,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        let sink = EventLogSink::new(
+            EventLogOptions {
+                path: path.to_owned(),
+                flush_interval: Duration::from_secs(opts.event_log_opts.event_log_flush_interval_secs),
+            },
+            event_log_pixmap,
+        );
+        sink.start(tasks).await.context("Could not start event log")?;
+    }
+
+    // configure webhook notifications about changed regions
+    let (webhook_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Webhook);
+    if let (Some(url), Some(tasks)) = (
+        &opts.webhook_opts.webhook,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        let sink = WebhookSink::new(
+            WebhookOptions {
+                url: url.clone(),
+                regions: opts.webhook_opts.webhook_regions.clone(),
+                settle: Duration::from_secs(opts.webhook_opts.webhook_settle_secs),
+            },
+            webhook_pixmap,
+        );
+        sink.start(tasks)
+            .await
+            .context("Could not start webhook notifications")?;
+    }
+
+    // configure the announcement to DLNA renderers
+    let (dlna_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Dlna);
+    if let (Some(listen), Some(tasks)) = (
+        opts.dlna_opts.dlna,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        let sink = DlnaSink::new(
+            DlnaOptions {
+                listen,
+                name: opts.dlna_opts.dlna_name.clone(),
+                stream: opts.dlna_opts.dlna_stream.clone(),
+            },
+            dlna_pixmap,
+        );
+        sink.start(tasks)
+            .await
+            .context("Could not start DLNA media server")?;
+    }
+
+    // configure gui window
+    #[cfg(feature = "windowing")]
+    let (window_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Window);
+    #[cfg(feature = "windowing")]
+    if let (true, Some(tasks)) = (
+        opts.open_window,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        pixeldike::sinks::window::start(
+            tasks,
+            window_pixmap,
+            WindowSinkOptions {
+                size: opts.window_size,
+                filter: match opts.window_filter {
+                    cli::WindowFilter::Nearest => ScalingFilter::Nearest,
+                    cli::WindowFilter::Bilinear => ScalingFilter::Bilinear,
+                },
+                keep_aspect_ratio: !opts.window_stretch,
+                fullscreen: opts.fullscreen,
+                activity_decay: Duration::from_secs(opts.activity_decay_secs),
+            },
+        )
+        .context("Could not open window for live rendering")?;
+    }
+
+    // configure streaming sink
+    let (stream_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Stream);
+    if let (true, Some(tasks)) = (
+        opts.stream_opts.rtmp_dst_addr.is_some() || opts.stream_opts.rtsp_dst_addr.is_some(),
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        // construct output spec depending on cli options
+        let mut output_spec = Vec::new();
+        if let Some(rtsp_dst_addr) = &opts.stream_opts.rtsp_dst_addr {
+            output_spec.append(&mut FfmpegOptions::make_rtsp_out_spec(
+                rtsp_dst_addr,
+                opts.stream_opts.framerate,
+            ));
+        }
+        if let Some(rtmp_dst_addr) = &opts.stream_opts.rtmp_dst_addr {
+            output_spec.append(&mut FfmpegOptions::make_rtmp_out_spec(
+                rtmp_dst_addr,
+                opts.stream_opts.framerate,
+            ));
+        }
+
+        // start the ffmpeg subprocess
+        let ffmpeg = FfmpegSink::new(
+            FfmpegOptions {
+                framerate: opts.stream_opts.framerate,
+                synthesize_audio: true,
+                log_level: "warning".to_string(),
+                output_spec,
+                heartbeat: heartbeat(
+                    "ffmpeg",
+                    Duration::from_secs_f64(1.0 / opts.stream_opts.framerate as f64),
+                ),
+                start_delay: warm_up_delay(),
+            },
+            stream_pixmap,
+        );
+        ffmpeg.start(tasks).await.context("Could not start ffmpeg sink")?;
+    }
+
+    // configure framebuffer sink
+    let (fb_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Framebuffer);
+    if let (Some(fb_device), Some(tasks)) = (
+        &opts.fb_opts.fb_device,
+        sink_tasks(join_set, &mut kept_tasks, other_canvas),
+    ) {
+        let sink = FramebufferSink::new(
+            FramebufferSinkOptions {
+                path: fb_device.to_owned(),
+                framerate: opts.fb_opts.fb_framerate,
+                heartbeat: heartbeat(
+                    "framebuffer",
+                    Duration::from_secs_f64(1.0 / opts.fb_opts.fb_framerate as f64),
+                ),
+                start_delay: warm_up_delay(),
+            },
+            fb_pixmap,
+        );
+        sink.start(tasks)
+            .await
+            .context("Could not start task for framebuffer rendering")?;
+    }
+
+    // configure ambient lighting sinks which show the dominant color of the statistics of what they display, which
+    // has to be analyzed separately if it is the output of an effect
+    let (ambient_pixmap, other_canvas) = sink_pixmap(cli::SinkKind::Ambient);
+    let analyzed_canvas = [(DEFAULT_CANVAS, pixmap)]
+        .into_iter()
+        .chain(canvases.iter())
+        .find(|(_, canvas)| Arc::ptr_eq(canvas, &ambient_pixmap));
+    let ambient_stats = match analyzed_canvas {
+        _ if opts.ambient_opts.ambient.is_empty() => None,
+        Some((name, _)) => color_stats.subscribe(name),
+        None => {
+            let sink = color_stats_sink(opts, ambient_pixmap.clone());
+            let stats = sink.subscribe();
+            sink.start(join_set)
+                .await
+                .context("Could not start color statistics task")?;
+            Some(stats)
+        }
+    };
+    for url in &opts.ambient_opts.ambient {
+        let target = match url.scheme() {
+            "wled" => AmbientTarget::Wled {
+                addr: url
+                    .socket_addrs(|| Some(21324))
+                    .expect("Could not resolve WLED address")[0],
+                leds: url
+                    .query_pairs()
+                    .find(|(k, _)| k == "leds")
+                    .map(|(_, v)| v.parse().expect("Could not parse WLED led count"))
+                    .unwrap_or(1),
+            },
+            "hue" => AmbientTarget::Hue {
+                bridge: url
+                    .socket_addrs(|| Some(80))
+                    .expect("Could not resolve Hue bridge address")[0],
+                username: url.username().to_string(),
+                light: url
+                    .path()
+                    .trim_start_matches('/')
+                    .parse()
+                    .expect("Could not parse Hue light id from url path"),
+            },
+            scheme => panic!("Unsupported ambient lighting scheme {}", scheme),
+        };
+        let sink = AmbientSink::new(
+            AmbientSinkOptions {
+                target,
+                interval: interval_at(
+                    Instant::now() + warm_up_delay(),
+                    Duration::from_millis(opts.ambient_opts.ambient_interval_ms),
+                ),
+                heartbeat: heartbeat(
+                    &format!("ambient {}", url),
+                    Duration::from_millis(opts.ambient_opts.ambient_interval_ms),
+                ),
+                color_stats: ambient_stats
+                    .clone()
+                    .expect("ambient lights show analyzed colors"),
+            },
+            ambient_pixmap.clone(),
+        );
+        let Some(tasks) = sink_tasks(join_set, &mut kept_tasks, other_canvas) else {
+            break;
+        };
+        sink.start(tasks)
+            .await
+            .context("Could not start ambient lighting sink")?;
+    }
+
+    // configure the control socket
+    if let Some(path) = &opts.control {
+        ControlServer::new(ControlServerOptions {
+            path: path.clone(),
+            resize: Some(context.resize.clone()),
+            effect: context.effect.clone(),
+            request_ids: context.request_ids.clone(),
+        })
+        .start(pixmap.clone(), join_set)
+        .await
+        .context("Could not start control socket")?;
+    }
+
+    for url in &opts.listen {
+        let default_read_buffer = match profile {
+            cli::Profile::Default => ReadBufferLimits::default(),
+            cli::Profile::LowPower => ReadBufferLimits {
+                per_connection: 8 * 1024,
+                total: 4 * 1024 * 1024,
+            },
+        };
+        let read_buffer = ReadBufferLimits {
+            per_connection: opts
+                .read_buffer_limit
+                .unwrap_or(default_read_buffer.per_connection),
+            total: opts.read_buffer_budget.unwrap_or(default_read_buffer.total),
+        };
+        let connection_pool_size = opts.connection_pool_size.unwrap_or(match profile {
+            cli::Profile::Default => DEFAULT_CONNECTION_POOL_SIZE,
+            cli::Profile::LowPower => 16,
+        });
+        let write_queue = WriteQueueLimits {
+            capacity: opts.write_queue_capacity,
+            lag_policy: match opts.slow_subscribers {
+                cli::SlowSubscribers::Resync => LagPolicy::Resync,
+                cli::SlowSubscribers::Disconnect => LagPolicy::Disconnect,
+            },
+        };
+        let idle_timeout = opts.idle_timeout_secs.map(Duration::from_secs);
+        #[cfg(feature = "ws")]
+        let ws_options = |bind_addrs| WsServerOptions {
+            bind_addrs,
+            strictness: main_utils::listener_strictness(url),
+            dialect: main_utils::listener_dialect(url),
+            quiet: main_utils::listener_quiet(url),
+            reservations: reservations.clone(),
+            region_limits: region_limits.clone(),
+            canvases: canvases.clone(),
+            color_stats: color_stats.clone(),
+            allow_fill: opts.allow_fill,
+            capabilities,
+            write_queue,
+            allowed_origins: allowed_origins.clone(),
+            hooks: hooks.clone(),
+        };
+        match url.scheme() {
+            #[cfg(feature = "tcp")]
+            "tcp" | "tcps" => {
+                if !url.username().is_empty() {
+                    tracing::warn!(
+                        "{} listen directive specifies credentials which is not supported by the TCP server",
+                        url
+                    )
+                }
+                if !url.path().is_empty() {
+                    tracing::warn!(
+                        "{} listen directive specifies a path which is not supported by the TCP server",
+                        url
+                    );
+                }
+                let options = TcpServerOptions {
+                    bind_addrs: main_utils::listener_addrs(url, 1234),
+                    multiplexing: url.query_pairs().any(|(k, v)| k == "multiplex" && v == "true"),
+                    read_buffer,
+                    strictness: main_utils::listener_strictness(url),
+                    dialect: main_utils::listener_dialect(url),
+                    quiet: main_utils::listener_quiet(url),
+                    reservations: reservations.clone(),
+                    region_limits: region_limits.clone(),
+                    canvases: canvases.clone(),
+                    allow_fill: opts.allow_fill,
+                    capabilities,
+                    write_queue,
+                    connection_pool_size,
+                    max_connections: opts.max_connections,
+                    idle_timeout,
+                    hooks: hooks.clone(),
+                };
+                match url.scheme() {
+                    #[cfg(feature = "tls")]
+                    "tcps" => {
+                        let path = |key: &str| {
+                            url.query_pairs()
+                                .find(|(k, _)| k == key)
+                                .map(|(_, v)| PathBuf::from(v.as_ref()))
+                                .unwrap_or_else(|| {
+                                    panic!("{} listen directive requires a ?{}=<path> parameter", url, key)
+                                })
+                        };
+                        TcpTlsServer::new(TcpTlsServerOptions {
+                            tcp: options,
+                            certificate_chain: path("cert"),
+                            private_key: path("key"),
+                        })
+                        .start(pixmap.clone(), join_set)
+                        .await
+                        .with_context(|| format!("Could not start tls server on {}", url))?;
+                    }
+                    #[cfg(not(feature = "tls"))]
+                    "tcps" => panic!("pixeldike was built without the tls feature"),
+                    _ => {
+                        TcpServer::new(options)
+                            .start(pixmap.clone(), join_set)
+                            .await
+                            .with_context(|| format!("Could not start tcp server on {}", url))?;
+                    }
+                }
+            }
+            "unix" => {
+                let path = PathBuf::from_str(url.path()).expect("Could not turn url path into system path");
+                let mut options = UnixSocketOptions::new(path);
+                options.read_buffer = read_buffer;
+                options.connection_pool_size = connection_pool_size;
+                options.write_queue = write_queue;
+                options.max_connections = opts.max_connections;
+                options.idle_timeout = idle_timeout;
+                options.strictness = main_utils::listener_strictness(url);
+                options.dialect = main_utils::listener_dialect(url);
+                options.quiet = main_utils::listener_quiet(url);
+                options.reservations = reservations.clone();
+                options.region_limits = region_limits.clone();
+                options.canvases = canvases.clone();
+                options.allow_fill = opts.allow_fill;
+                options.capabilities = capabilities;
+                options.hooks = hooks.clone();
+                for (key, value) in url.query_pairs() {
+                    match key.as_ref() {
+                        "mode" => {
+                            options.mode = Some(
+                                u32::from_str_radix(&value, 8)
+                                    .expect("Could not parse unix socket mode as octal"),
+                            )
+                        }
+                        "owner" => {
+                            options.owner =
+                                Some(value.parse().expect("Could not parse unix socket owner uid"))
+                        }
+                        "group" => {
+                            options.group =
+                                Some(value.parse().expect("Could not parse unix socket group gid"))
+                        }
+                        "strict" | "quiet" | "dialect" => {}
+                        _ => tracing::warn!("{} listen directive specifies unsupported option {}", url, key),
+                    }
+                }
+                UnixSocketServer::new(options)
+                    .start(pixmap.clone(), join_set)
+                    .await
+                    .with_context(|| format!("Could not start unix socket listener on {}", url))?;
+            }
+            #[cfg(target_os = "linux")]
+            "unix-abstract" => {
+                let mut options =
+                    UnixSocketOptions::new(PathBuf::from(main_utils::abstract_socket_name(url)));
+                options.abstract_namespace = true;
+                options.read_buffer = read_buffer;
+                options.connection_pool_size = connection_pool_size;
+                options.write_queue = write_queue;
+                options.max_connections = opts.max_connections;
+                options.idle_timeout = idle_timeout;
+                options.strictness = main_utils::listener_strictness(url);
+                options.dialect = main_utils::listener_dialect(url);
+                options.quiet = main_utils::listener_quiet(url);
+                options.reservations = reservations.clone();
+                options.region_limits = region_limits.clone();
+                options.canvases = canvases.clone();
+                options.allow_fill = opts.allow_fill;
+                options.capabilities = capabilities;
+                options.hooks = hooks.clone();
+                UnixSocketServer::new(options)
+                    .start(pixmap.clone(), join_set)
+                    .await
+                    .with_context(|| format!("Could not start unix socket listener on {}", url))?;
+            }
+            #[cfg(feature = "udp")]
+            "udp" => {
+                if !url.username().is_empty() {
+                    tracing::info!("{}", url.authority());
+                    tracing::warn!(
+                        "{} listen directive specifies credentials which is not supported by the UDP server",
+                        url
+                    )
+                }
+                if !url.path().is_empty() {
+                    tracing::warn!(
+                        "{} listen directive specifies a path which is not supported by the UDP server",
+                        url
+                    );
+                }
+                UdpServer::new(UdpServerOptions {
+                    bind_addrs: main_utils::listener_addrs(url, 1234),
+                    strictness: main_utils::listener_strictness(url),
+                    dialect: main_utils::listener_dialect(url),
+                    quiet: main_utils::listener_quiet(url),
+                    reservations: reservations.clone(),
+                    region_limits: region_limits.clone(),
+                    canvases: canvases.clone(),
+                    allow_fill: opts.allow_fill,
+                    capabilities,
+                })
+                .start(pixmap.clone(), join_set)
+                .await
+                .with_context(|| format!("Could not start udp server on {}", url))?;
+            }
+            #[cfg(feature = "ws")]
+            "ws" => {
+                if !url.username().is_empty() {
+                    tracing::info!("{}", url.authority());
+                    tracing::warn!(
+                        "{} listen directive specifies credentials which is not supported by the WebSocket server",
+                        url
+                    )
+                }
+                if url.path() != "/" {
+                    tracing::warn!(
+                        "{} listen directive specifies a path which is ignored by the WebSocket server. It instead serves the protocol on /ws, changes on /stream and statistics on /stats.",
+                        url
+                    );
+                }
+
+                WsServer::new(ws_options(main_utils::listener_addrs(url, 1235)))
+                    .start(pixmap.clone(), join_set)
+                    .await
+                    .with_context(|| format!("Could not start websocket server on {}", url))?;
+            }
+            "http" => {
+                if url.path() != "/" {
+                    tracing::warn!(
+                        "{} listen directive specifies a path which is not supported by the HTTP server",
+                        url
+                    );
+                }
+                let websocket = url.query_pairs().any(|(k, v)| k == "ws" && v == "true");
+                #[cfg(not(feature = "ws"))]
+                if websocket {
+                    panic!("pixeldike was built without the ws feature");
+                }
+                HttpServer::new(HttpServerOptions {
+                    bind_addrs: main_utils::listener_addrs(url, 80),
+                    watchdog: watchdog.clone(),
+                    ready: ready.clone(),
+                    reservations: reservations.clone(),
+                    canvases: canvases.clone(),
+                    color_stats: color_stats.clone(),
+                    activity_decay: Duration::from_secs(opts.activity_decay_secs),
+                    allowed_origins: allowed_origins.clone(),
+                    #[cfg(feature = "ws")]
+                    websocket: websocket.then(|| ws_options(Vec::new())),
+                })
+                .start(pixmap.clone(), join_set)
+                .await
+                .with_context(|| format!("Could not start http server on {}", url))?;
+            }
+            proto => {
+                panic!("Unsupported server protocol {}", proto);
+            }
+        }
+    }
+
+    #[cfg(feature = "mdns")]
+    if opts.mdns {
+        pixeldike::net::discovery::advertise(
+            join_set,
+            pixeldike::net::discovery::AdvertiseOptions {
+                name: opts.mdns_name.clone(),
+                addrs: opts
+                    .listen
+                    .iter()
+                    .filter(|url| matches!(url.scheme(), "tcp" | "tcps"))
+                    .flat_map(|url| main_utils::listener_addrs(url, 1234))
+                    .collect(),
+                size: pixmap.get_size(),
+            },
+        )
+        .context("Could not announce server via mDNS")?;
+    }
+
+    // periodically reset pixels which were not written for their TTL on all canvases
+    if let Some(period) = pixmap.expiry().map(|expiry| expiry.period()) {
+        let pixmaps = std::iter::once(pixmap.clone())
+            .chain(canvases.iter().map(|(_, canvas)| canvas.clone()))
+            .collect::<Vec<_>>();
+        let heartbeat = heartbeat("pixel_ttl", period);
+        join_set
+            .build_task()
+            .name("pixel_ttl")
+            .spawn(async move {
+                let mut interval = interval_at(Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if let Some(heartbeat) = &heartbeat {
+                        heartbeat.beat();
+                    }
+                    let expired = pixmaps.iter().map(|pixmap| pixmap.expire_pixels()).sum::<usize>();
+                    if expired > 0 {
+                        tracing::debug!("Reset {} expired pixels", expired);
+                    }
+                }
+            })
+            .context("Could not start pixel expiry")?;
+    }
+
+    // periodically log the busiest clients
+    let stats = pixeldike::net::servers::client_stats();
+    if let Some(stats) = stats.filter(|_| opts.client_stats_log_interval_secs > 0) {
+        let period = Duration::from_secs(opts.client_stats_log_interval_secs);
+        join_set
+            .build_task()
+            .name("client_stats_log")
+            .spawn(async move {
+                let mut interval = interval_at(Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    tracing::info!("Busiest clients: {}", stats.leaderboard(LEADERBOARD_LOG_LEN));
+                }
+            })
+            .context("Could not start client statistics log")?;
+    }
+
+    // listeners are observed indirectly by checking that the runtime which drives them is still responsive
+    if let Some(watchdog) = &watchdog {
+        let heartbeat = watchdog.heartbeat("runtime", RUNTIME_HEARTBEAT_INTERVAL);
+        join_set
+            .build_task()
+            .name("runtime_heartbeat")
+            .spawn(async move {
+                let mut interval = interval(RUNTIME_HEARTBEAT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    heartbeat.beat();
+                }
+            })
+            .context("Could not start runtime heartbeat")?;
+    }
+    Ok(())
+}
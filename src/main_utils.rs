@@ -1,13 +1,552 @@
 use crate::cli;
 use crate::cli::TargetDimension;
+use crate::main_order::CommandArranger;
 use bytes::buf::Writer;
 use bytes::{BufMut, BytesMut};
-use pixeldike::net::clients::{TcpClient, UdpClient, UnixSocketClient};
-use pixeldike::net::protocol::{Request, Response};
-use std::path::PathBuf;
+use image::codecs::gif::GifDecoder;
+use image::io::Reader as ImageReader;
+use image::{AnimationDecoder, ImageBuffer, ImageFormat, Pixel, RgbaImage};
+use pixeldike::net::clients::{BulkSendOptions, TcpClient, UdpClient, UnixSocketClient};
+use pixeldike::net::protocol::{
+    parse_request_bin, request_frame_len, Request, Response, StateEncoding, MAX_REGION_PIXELS,
+};
+use pixeldike::pixmap::Color;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{Instant, MissedTickBehavior};
 use url::Url;
 
+/// Determine how strictly a listener should parse whitespace from its `strict` url query parameter
+#[cfg(feature = "server")]
+pub fn listener_strictness(url: &Url) -> pixeldike::net::protocol::Strictness {
+    use pixeldike::net::protocol::Strictness;
+    match url.query_pairs().any(|(k, v)| k == "strict" && v == "true") {
+        true => Strictness::Strict,
+        false => Strictness::Lenient,
+    }
+}
+
+/// Determine which form of the protocol a listener speaks from its `dialect` url query parameter
+#[cfg(feature = "server")]
+pub fn listener_dialect(url: &Url) -> pixeldike::net::servers::Dialect {
+    match url.query_pairs().find(|(k, _)| k == "dialect") {
+        None => Default::default(),
+        Some((_, v)) => v
+            .parse()
+            .unwrap_or_else(|e| panic!("{} listen directive specifies an {}", url, e)),
+    }
+}
+
+/// Determine whether clients of a listener start out in quiet mode from its `quiet` url query parameter
+#[cfg(feature = "server")]
+pub fn listener_quiet(url: &Url) -> bool {
+    url.query_pairs().any(|(k, v)| k == "quiet" && v == "true")
+}
+
+/// Resolve the local addresses to which a network listener binds
+///
+/// Host names may resolve to multiple addresses, e.g. `localhost` to both `127.0.0.1` and `::1`, which are all bound.
+/// IPv6 addresses are given in brackets like `tcp://[::]:1234`.
+#[cfg(feature = "server")]
+pub fn listener_addrs(url: &Url, default_port: u16) -> Vec<std::net::SocketAddr> {
+    let mut addrs = url
+        .socket_addrs(|| Some(default_port))
+        .unwrap_or_else(|e| panic!("Could not resolve socket addr from listener url {}: {}", url, e));
+    addrs.dedup();
+    addrs
+}
+
+/// Extract the socket name from a `unix-abstract://@<name>` url
+///
+/// The name may be given either as host (`unix-abstract://@name`) or as path (`unix-abstract:///name`).
+pub fn abstract_socket_name(url: &Url) -> String {
+    match url.host_str() {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => url.path().trim_start_matches('/').to_string(),
+    }
+}
+
+/// Describe the version, target platform and enabled cargo features of this binary
+pub fn build_info() -> String {
+    let features = [
+        ("cli", cfg!(feature = "cli")),
+        ("server", cfg!(feature = "server")),
+        ("tcp", cfg!(feature = "tcp")),
+        ("udp", cfg!(feature = "udp")),
+        ("tls", cfg!(feature = "tls")),
+        ("ws", cfg!(feature = "ws")),
+        ("windowing", cfg!(feature = "windowing")),
+        ("mdns", cfg!(feature = "mdns")),
+        ("signing", cfg!(feature = "signing")),
+    ];
+    let linkage = match cfg!(target_feature = "crt-static") {
+        true => "static",
+        false => "dynamic",
+    };
+    let target_env = match cfg!(target_env = "musl") {
+        true => "musl",
+        false if cfg!(target_env = "gnu") => "gnu",
+        false => "unknown",
+    };
+
+    format!(
+        "{} {}\ntarget: {}-{}-{} ({} linking)\nfeatures: {}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        target_env,
+        linkage,
+        features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+/// Quote and escape a string so that it can be embedded into JSON output
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Convert metrics in the prometheus text format into a JSON array of samples
+///
+/// Every sample becomes an object with `name`, `labels` and `value` keys while comments are dropped.
+pub fn metrics_to_json(metrics: &str) -> String {
+    let samples = metrics
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let (name, labels) = match series.split_once('{') {
+                Some((name, labels)) => (name, labels.trim_end_matches('}')),
+                None => (series, ""),
+            };
+            let labels = labels
+                .split(',')
+                .filter_map(|label| label.split_once('='))
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v.trim_matches('"'))))
+                .collect::<Vec<_>>()
+                .join(",");
+            let value = match value.parse::<f64>() {
+                Ok(value) if value.is_finite() => value.to_string(),
+                _ => "null".to_string(),
+            };
+            Some(format!(
+                "{{\"name\":{},\"labels\":{{{}}},\"value\":{}}}",
+                json_string(name),
+                labels,
+                value
+            ))
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("[{}]", samples)
+}
+
+/// One of the connections over which [`DynClient::run_loop`] sends its share of the commands
+struct Connection {
+    client: DynClient,
+    arranger: CommandArranger,
+    /// The part of the command buffer which is sent over this connection
+    shard: Vec<u8>,
+    /// How many commands `shard` contains
+    commands: usize,
+}
+
+impl Connection {
+    fn new(client: DynClient, opts: &cli::CommonClientOps) -> Self {
+        Self {
+            client,
+            arranger: CommandArranger::new(opts),
+            shard: Vec::new(),
+            commands: 0,
+        }
+    }
+
+    /// Split a command buffer between all connections and arrange each share for sending
+    fn distribute(connections: &mut [Connection], commands: &[u8]) {
+        let shards = shard_commands(commands, connections.len());
+        for (connection, shard) in connections.iter_mut().zip(shards) {
+            connection.arranger.arrange(&shard);
+            connection.commands = count_commands(&shard);
+            connection.shard = shard;
+        }
+    }
+
+    /// Send the arranged commands and return the connection again together with how many bytes were sent
+    ///
+    /// The connection is moved in and out so that all connections can send in parallel tasks.
+    async fn send(mut self, opts: Arc<cli::CommonClientOps>) -> (Self, usize) {
+        let sent = self.client.send_commands(&self.arranger, &opts).await;
+        if !opts.do_loop {
+            self.client
+                .flush()
+                .await
+                .expect("Could not write commands to server");
+        }
+        (self, sent)
+    }
+}
+
+/// Split a command buffer into `n` parts of roughly the same size without splitting any command
+///
+/// Every part holds consecutive commands so that each connection draws a contiguous area if the commands were
+/// generated row by row.
+pub fn shard_commands(commands: &[u8], n: usize) -> Vec<Vec<u8>> {
+    let target_len = commands.len().div_ceil(n.max(1));
+    let mut shards = vec![Vec::with_capacity(target_len); n.max(1)];
+    let mut rest = commands;
+    let mut i = 0;
+    while let Some(len) = request_frame_len(rest) {
+        if shards[i].len() >= target_len && i + 1 < shards.len() {
+            i += 1;
+        }
+        shards[i].extend_from_slice(&rest[..len]);
+        rest = &rest[len..];
+    }
+    shards[i].extend_from_slice(rest);
+    shards
+}
+
+/// Count the complete commands in a buffer
+pub fn count_commands(commands: &[u8]) -> usize {
+    let mut rest = commands;
+    let mut count = 0;
+    while let Some(len) = request_frame_len(rest) {
+        rest = &rest[len..];
+        count += 1;
+    }
+    count
+}
+
+/// Counts what a client loop sent so that the achieved rates can be logged with `--stats-interval`
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    bytes: AtomicU64,
+    pixels: AtomicU64,
+    loops: AtomicU64,
+}
+
+impl ClientStats {
+    /// Start counting and log the rates of the last interval after each interval if configured
+    ///
+    /// The returned handle stops the logging.
+    fn start(opts: &cli::CommonClientOps) -> (Arc<Self>, Option<AbortHandle>) {
+        let stats = Arc::new(Self::default());
+        let reporter = opts.stats_interval.map(|interval| {
+            let stats = stats.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                interval.tick().await;
+                let mut last = (Instant::now(), 0, 0, 0);
+                loop {
+                    interval.tick().await;
+                    let now = (Instant::now(), stats.bytes(), stats.pixels(), stats.loops());
+                    let secs = (now.0 - last.0).as_secs_f64();
+                    tracing::info!(
+                        "Sending {:.0} pixels/s, {:.2} MB/s, {:.1} loops/s",
+                        (now.2 - last.2) as f64 / secs,
+                        (now.1 - last.1) as f64 / secs / 1_000_000.0,
+                        (now.3 - last.3) as f64 / secs
+                    );
+                    last = now;
+                }
+            })
+            .abort_handle()
+        });
+        (stats, reporter)
+    }
+
+    fn record_send(&self, bytes: usize, pixels: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.pixels.fetch_add(pixels as u64, Ordering::Relaxed);
+    }
+
+    fn record_loop(&self) {
+        self.loops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn pixels(&self) -> u64 {
+        self.pixels.load(Ordering::Relaxed)
+    }
+
+    fn loops(&self) -> u64 {
+        self.loops.load(Ordering::Relaxed)
+    }
+}
+
+/// What a client loop did before it finished
+#[derive(Debug, Copy, Clone)]
+pub struct ClientLoopSummary {
+    pub canvas_width: usize,
+    pub canvas_height: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub bytes_sent: usize,
+}
+
+impl ClientLoopSummary {
+    pub fn to_json(self) -> String {
+        format!(
+            "{{\"canvas_width\":{},\"canvas_height\":{},\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"bytes_sent\":{}}}",
+            self.canvas_width, self.canvas_height, self.x, self.y, self.width, self.height, self.bytes_sent
+        )
+    }
+}
+
+/// Encode a request into a clients command buffer in the form that was requested on the command-line
+pub fn write_request(
+    request: &Request,
+    buf: &mut impl std::io::Write,
+    opts: &cli::CommonClientOps,
+) -> std::io::Result<()> {
+    match opts.binary {
+        true => request.write_binary(buf),
+        false => request.write(buf),
+    }
+}
+
+/// How many pixels one worker encodes at once in [`write_requests_parallel`]
+const ENCODE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Encode the request which `request` returns for every coordinate into a command buffer using all CPU cores
+///
+/// Every worker encodes a chunk of coordinates into its own buffer and the buffers are concatenated afterwards so
+/// the commands keep the order of `coords`.
+pub fn write_requests_parallel(
+    coords: &[(usize, usize)],
+    buf: &mut impl std::io::Write,
+    opts: &cli::CommonClientOps,
+    request: impl Fn(usize, usize) -> Request + Sync,
+) -> std::io::Result<()> {
+    let chunks = coords
+        .par_chunks(ENCODE_CHUNK_SIZE)
+        .map(|chunk| {
+            let mut chunk_buf = Vec::with_capacity(chunk.len() * 20);
+            for &(x, y) in chunk {
+                write_request(&request(x, y), &mut chunk_buf, opts)?;
+            }
+            Ok(chunk_buf)
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    for chunk in chunks {
+        buf.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Compute which source pixels contribute to every pixel of a resized axis and how much
+///
+/// This is a triangle (bilinear) filter which is widened when downscaling so that every source pixel is taken
+/// into account. The returned weights of every target pixel start at the given source index and sum up to 1.
+fn triangle_weights(src_len: u32, dst_len: u32) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_len as f32 / dst_len as f32;
+    let support = ratio.max(1.0);
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * ratio;
+            let first = (center - support).floor().max(0.0) as usize;
+            let last = ((center + support).ceil() as usize).min(src_len as usize);
+            let mut weights = (first..last)
+                .map(|j| (1.0 - ((j as f32 + 0.5 - center) / support).abs()).max(0.0))
+                .collect::<Vec<_>>();
+            let sum: f32 = weights.iter().sum();
+            if sum > 0.0 {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            }
+            (first, weights)
+        })
+        .collect()
+}
+
+/// Resize an image with a triangle filter like [`image::imageops::resize`] does but using all CPU cores
+///
+/// Rows are first resized horizontally in parallel and the intermediate result is then resized vertically with
+/// every worker producing its own output rows.
+pub fn resize_parallel<P>(img: &ImageBuffer<P, Vec<u8>>, width: u32, height: u32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8>,
+{
+    if img.dimensions() == (width, height) {
+        return img.clone();
+    }
+    let channels = P::CHANNEL_COUNT as usize;
+    let (src_width, src_height) = img.dimensions();
+    let (src_row_len, row_len) = (src_width as usize * channels, width as usize * channels);
+    let columns = triangle_weights(src_width, width);
+    let rows = triangle_weights(src_height, height);
+
+    let mut horizontal = vec![0f32; src_height as usize * row_len];
+    horizontal
+        .par_chunks_mut(row_len.max(1))
+        .zip(img.as_raw().par_chunks(src_row_len.max(1)))
+        .for_each(|(out, src)| {
+            for (x, (first, weights)) in columns.iter().enumerate() {
+                for c in 0..channels {
+                    out[x * channels + c] = weights
+                        .iter()
+                        .enumerate()
+                        .map(|(i, w)| w * src[(first + i) * channels + c] as f32)
+                        .sum();
+                }
+            }
+        });
+
+    let mut resized = vec![0u8; height as usize * row_len];
+    resized
+        .par_chunks_mut(row_len.max(1))
+        .zip(rows.par_iter())
+        .for_each(|(out, (first, weights))| {
+            for (i, value) in out.iter_mut().enumerate() {
+                let sum: f32 = weights
+                    .iter()
+                    .enumerate()
+                    .map(|(j, w)| w * horizontal[(first + j) * row_len + i])
+                    .sum();
+                *value = sum.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    ImageBuffer::from_raw(width, height, resized).expect("buffer has the size of the resized image")
+}
+
+/// Encode requests for all pixels of `frame` which differ from the `previous` frame
+///
+/// The frame is drawn with its top-left corner at the given offset.
+/// Fully transparent pixels are skipped and partly transparent ones are blended onto the canvas.
+pub fn write_changed_pixels(
+    previous: Option<&RgbaImage>,
+    frame: &RgbaImage,
+    x_offset: usize,
+    y_offset: usize,
+    buf: &mut impl std::io::Write,
+    opts: &cli::CommonClientOps,
+) {
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        if previous.is_some_and(|previous| previous.get_pixel(x, y) == pixel) {
+            continue;
+        }
+        let [r, g, b, a] = pixel.0;
+        let (x, y) = (x_offset + x as usize, y_offset + y as usize);
+        let color = Color::from((r, g, b));
+        let request = match a {
+            0 => continue,
+            0xFF => Request::SetPixel { x, y, color },
+            alpha => Request::BlendPixel { x, y, color, alpha },
+        };
+        write_request(&request, buf, opts).unwrap();
+    }
+}
+
+/// Drop all commands from a command buffer which set a pixel to the color that it already has
+///
+/// `current` holds the pixels of the `width` x `height` region starting at (x_min, y_min) row by row.
+/// Commands for pixels outside of that region and all other commands are kept.
+pub fn skip_matching_pixels(
+    commands: &[u8],
+    current: &[Color],
+    x_min: usize,
+    y_min: usize,
+    width: usize,
+    height: usize,
+) -> Vec<u8> {
+    let mut remaining = Vec::with_capacity(commands.len());
+    let mut rest = commands;
+    while let Some(len) = request_frame_len(rest) {
+        let (frame, tail) = rest.split_at(len);
+        rest = tail;
+        let matches = match parse_request_bin(frame) {
+            Ok(Request::SetPixel { x, y, color }) => {
+                (x_min..x_min + width).contains(&x)
+                    && (y_min..y_min + height).contains(&y)
+                    && current[(y - y_min) * width + (x - x_min)] == color
+            }
+            _ => false,
+        };
+        if !matches {
+            remaining.extend_from_slice(frame);
+        }
+    }
+    remaining.extend_from_slice(rest);
+    remaining
+}
+
+/// How long frames of animations are shown which don't specify it, like browsers do
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Decode all frames of an animated GIF together with how long each one is shown
+///
+/// `None` is returned for other images and for GIFs with a single frame because those are drawn like still images.
+pub fn load_animation(path: &Path) -> anyhow::Result<Option<Vec<(RgbaImage, Duration)>>> {
+    let reader = ImageReader::open(path)?.with_guessed_format()?;
+    if reader.format() != Some(ImageFormat::Gif) {
+        return Ok(None);
+    }
+    let frames = GifDecoder::new(reader.into_inner())?
+        .into_frames()
+        .collect_frames()?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(
+        frames
+            .into_iter()
+            .map(|frame| {
+                // very short delays are treated as missing ones, again like browsers do
+                let delay = match Duration::from(frame.delay()) {
+                    delay if delay < Duration::from_millis(20) => DEFAULT_FRAME_DELAY,
+                    delay => delay,
+                };
+                (frame.into_buffer(), delay)
+            })
+            .collect(),
+    ))
+}
+
+/// Apply simulated network impairment to a buffer of newline separated commands
+///
+/// This waits for the configured latency and returns the commands which survived the configured loss.
+async fn impair(impairment: &cli::Impairment, commands: &[u8]) -> Vec<u8> {
+    let jitter = impairment.jitter.mul_f64(rand::random::<f64>());
+    tokio::time::sleep(impairment.latency + jitter).await;
+
+    commands
+        .split_inclusive(|&b| b == b'\n')
+        .filter(|_| rand::random::<f64>() >= impairment.loss)
+        .flatten()
+        .copied()
+        .collect()
+}
+
 pub enum DynClient {
     Tcp(TcpClient),
     Udp(UdpClient),
@@ -25,6 +564,23 @@ impl DynClient {
                     .expect("Could not resolve servers address")[0];
                 Ok(Self::Tcp(TcpClient::connect(&addr).await?))
             }
+            #[cfg(feature = "tls")]
+            "tcps" => {
+                let addr = url
+                    .socket_addrs(|| Some(1234))
+                    .expect("Could not resolve servers address")[0];
+                let ca = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "ca")
+                    .map(|(_, v)| PathBuf::from(v.as_ref()))
+                    .expect("tcps:// urls require a ?ca=<path> parameter naming the trusted certificates");
+                let config = pixeldike::net::tls::client_config(&ca)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                let server_name = url.host_str().unwrap_or_default();
+                Ok(Self::Tcp(
+                    TcpClient::connect_tls(&addr, server_name, config).await?,
+                ))
+            }
             #[cfg(feature = "udp")]
             "udp" => {
                 let addr = url
@@ -36,11 +592,17 @@ impl DynClient {
                 let path = PathBuf::from(url.path());
                 Ok(Self::Unix(UnixSocketClient::connect(&path).await?))
             }
+            #[cfg(target_os = "linux")]
+            "unix-abstract" => {
+                let name = abstract_socket_name(url);
+                Ok(Self::Unix(
+                    UnixSocketClient::connect_abstract(name.as_bytes()).await?,
+                ))
+            }
             scheme => panic!("Unsupported url scheme {}", scheme),
         }
     }
 
-    #[allow(unused)]
     async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
         match self {
             DynClient::Tcp(tcp) => tcp.send_request(request).await,
@@ -49,7 +611,6 @@ impl DynClient {
         }
     }
 
-    #[allow(unused)]
     async fn await_response(&mut self) -> anyhow::Result<Response> {
         match self {
             DynClient::Tcp(tcp) => tcp.await_response().await,
@@ -66,6 +627,155 @@ impl DynClient {
         }
     }
 
+    async fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DynClient::Tcp(tcp) => tcp.flush().await,
+            DynClient::Udp(_) => Ok(()),
+            DynClient::Unix(unix) => unix.flush().await,
+        }
+    }
+
+    /// Send a buffer of encoded commands and make sure that it is not held back in any buffer
+    #[cfg(feature = "server")]
+    pub async fn send_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            DynClient::Tcp(tcp) => tcp.get_writer().write_all(data).await?,
+            DynClient::Unix(unix) => unix.get_writer().write_all(data).await?,
+            DynClient::Udp(udp) => udp.send_bulk(data, &BulkSendOptions::default()).await?,
+        }
+        self.flush().await
+    }
+
+    /// Download the complete remote canvas
+    ///
+    /// Returns the canvas size as well as its pixel data ordered row by row.
+    pub async fn fetch_canvas(&mut self) -> anyhow::Result<(usize, usize, Vec<Color>)> {
+        let (width, height) = self.get_size().await;
+        Ok((width, height, self.fetch_region(0, 0, width, height).await?))
+    }
+
+    /// Download the pixels of a region of the remote canvas ordered row by row
+    ///
+    /// The region is transferred in chunks via `STATE REGION` and if the server does not support that, by reading
+    /// each pixel individually.
+    pub async fn fetch_region(
+        &mut self,
+        x_min: usize,
+        y_min: usize,
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<Vec<Color>> {
+        let len = width
+            .checked_mul(height)
+            .ok_or_else(|| anyhow::anyhow!("region of {}x{} pixels is too large", width, height))?;
+        let mut data = vec![Color::default(); len];
+
+        let tile_size = (MAX_REGION_PIXELS as f64).sqrt() as usize;
+        for tile_y in (0..height).step_by(tile_size) {
+            for tile_x in (0..width).step_by(tile_size) {
+                let request = Request::GetRegion {
+                    x: x_min + tile_x,
+                    y: y_min + tile_y,
+                    width: usize::min(tile_size, width - tile_x),
+                    height: usize::min(tile_size, height - tile_y),
+                    encoding: StateEncoding::Rgb64,
+                };
+                match self.exchange(request).await {
+                    Ok(Response::Region {
+                        x,
+                        y,
+                        width: region_width,
+                        height: region_height,
+                        data: region_data,
+                        ..
+                    }) => copy_region(
+                        &mut data,
+                        (x_min, y_min, width, height),
+                        (x, y, region_width, region_height),
+                        &region_data,
+                    )?,
+                    result => {
+                        tracing::info!(
+                            "Server does not support STATE REGION ({:?}), falling back to reading single pixels",
+                            result
+                        );
+                        return self.fetch_region_pixelwise(x_min, y_min, width, height).await;
+                    }
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Download a region of the remote canvas by requesting every pixel individually
+    async fn fetch_region_pixelwise(
+        &mut self,
+        x_min: usize,
+        y_min: usize,
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<Vec<Color>> {
+        let mut data = vec![Color::default(); width * height];
+        let (x_max, y_max) = (x_min + width, y_min + height);
+        for y in y_min..y_max {
+            for x in x_min..x_min + width {
+                self.send_request(Request::GetPixel { x, y }).await?;
+            }
+            self.flush().await?;
+            for _ in 0..width {
+                if let Response::PxData { x, y, color } = self.await_response().await? {
+                    if !(x_min..x_max).contains(&x) || !(y_min..y_max).contains(&y) {
+                        return Err(anyhow::anyhow!(
+                            "server sent pixel {},{} which lies outside the requested region",
+                            x,
+                            y
+                        ));
+                    }
+                    data[(y - y_min) * width + (x - x_min)] = color;
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Drop all commands which would set a pixel of the target region to the color it already has
+    ///
+    /// The current content of the region is downloaded for this which is much cheaper than repainting it, e.g. when a
+    /// client is restarted after a brief disconnect.
+    /// Over UDP no content can be downloaded and all commands are kept.
+    async fn resume(
+        &mut self,
+        commands: &[u8],
+        (x_min, x_max, y_min, y_max): (usize, usize, usize, usize),
+    ) -> Vec<u8> {
+        if matches!(self, DynClient::Udp(_)) {
+            tracing::warn!(
+                "Cannot resume over UDP because the server does not respond, repainting everything"
+            );
+            return commands.to_vec();
+        }
+        let (width, height) = (x_max - x_min, y_max - y_min);
+        tracing::info!("Downloading current content of the target region to resume drawing");
+        let current = match self.fetch_region(x_min, y_min, width, height).await {
+            Ok(current) => current,
+            Err(e) => {
+                tracing::warn!(
+                    "Could not download current content of the target region, repainting everything: {}",
+                    e
+                );
+                return commands.to_vec();
+            }
+        };
+        let remaining = skip_matching_pixels(commands, &current, x_min, y_min, width, height);
+        tracing::info!(
+            "Resuming with {} of {} bytes of commands",
+            remaining.len(),
+            commands.len()
+        );
+        remaining
+    }
+
     /// Run a generic client loop that fills its command buffer from the provided function.
     ///
     /// `fill_buf` should be a function that fills the provided buffer with pixelflut commands.
@@ -74,53 +784,192 @@ impl DynClient {
     ///
     /// If `requires_buf_refresh` is true, then the command is filled per iteration of the client loop.
     /// Otherwise it is only filled once.
-    pub async fn run_loop<F>(mut self, fill_buf: F, opts: &cli::CommonClientOps, requires_buf_refresh: bool)
+    ///
+    /// With `--connections`, additional connections are opened and every iteration sends a share of the commands
+    /// over each of them in parallel.
+    ///
+    /// The loop only returns if `--once` was given.
+    pub async fn run_loop<F>(
+        mut self,
+        mut fill_buf: F,
+        opts: &cli::CommonClientOps,
+        requires_buf_refresh: bool,
+    ) -> ClientLoopSummary
     where
-        F: Fn(&mut Writer<BytesMut>, usize, usize, usize, usize),
+        F: FnMut(&mut Writer<BytesMut>, usize, usize, usize, usize),
     {
         // preparation
         let (canvas_width, canvas_height) = self.get_size().await;
-        let (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
+        let bounds @ (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
         let mut buf = BytesMut::new().writer();
 
         tracing::info!("Preparing command buffer");
         fill_buf(&mut buf, x_min, x_max, y_min, y_max);
+        // only the first send skips pixels which are already drawn, later ones repaint everything
+        let mut resumed = match opts.resume {
+            true => Some(self.resume(buf.get_ref(), bounds).await),
+            false => None,
+        };
+        let mut connections = vec![Connection::new(self, opts)];
+        for _ in 1..opts.connections.get() {
+            let client = DynClient::connect(&opts.server)
+                .await
+                .expect("Could not open additional connection to pixelflut server");
+            connections.push(Connection::new(client, opts));
+        }
+        Connection::distribute(&mut connections, resumed.as_deref().unwrap_or(buf.get_ref()));
 
         // main loop
-        tracing::info!("Running client loop");
+        tracing::info!("Running client loop with {} connections", connections.len());
+        let shared_opts = Arc::new(opts.clone());
+        let (stats, reporter) = ClientStats::start(opts);
+        let mut bytes_sent = 0;
         loop {
-            // send whole buffer to server (using the most performant method available)
-            tracing::debug!("Sending prepared commands to server");
-            match &mut self {
-                DynClient::Tcp(tcp) => tcp
-                    .get_writer()
-                    .write_all(buf.get_ref())
-                    .await
-                    .expect("Could not write commands to server"),
-                DynClient::Unix(unix) => unix
-                    .get_writer()
-                    .write_all(buf.get_ref())
-                    .await
-                    .expect("Could not write commands to server"),
-                DynClient::Udp(udp) => udp
-                    .send_bulk(buf.get_ref())
-                    .await
-                    .expect("Could not send commands to server"),
+            let mut join_set = JoinSet::new();
+            for connection in connections.drain(..) {
+                join_set.spawn(connection.send(shared_opts.clone()));
+            }
+            while let Some(result) = join_set.join_next().await {
+                let (connection, sent) = result.expect("Could not send commands to server");
+                stats.record_send(sent, connection.commands);
+                connections.push(connection);
+                bytes_sent += sent;
             }
+            stats.record_loop();
 
             // abort loop if only one iteration is requested
             if !opts.do_loop {
-                break;
+                if let Some(reporter) = reporter {
+                    reporter.abort();
+                }
+                return ClientLoopSummary {
+                    canvas_width,
+                    canvas_height,
+                    x: x_min,
+                    y: y_min,
+                    width: x_max - x_min,
+                    height: y_max - y_min,
+                    bytes_sent,
+                };
             }
 
             // refresh buffer content if required
             if requires_buf_refresh {
                 buf.get_mut().clear();
                 fill_buf(&mut buf, x_min, x_max, y_min, y_max);
+                Connection::distribute(&mut connections, buf.get_ref());
+            } else if resumed.take().is_some() {
+                Connection::distribute(&mut connections, buf.get_ref());
+            } else {
+                for connection in &mut connections {
+                    if connection.arranger.per_send() {
+                        connection.arranger.arrange(&connection.shard);
+                    }
+                }
             }
         }
     }
 
+    /// Run a client loop that sends one frame of commands after another
+    ///
+    /// `fill_buf` is called like in [`DynClient::run_loop`] but with an empty buffer for every frame.
+    /// It returns `false` once there are no more frames, after which the loop returns.
+    pub async fn run_frames<F>(mut self, mut fill_buf: F, opts: &cli::CommonClientOps) -> ClientLoopSummary
+    where
+        F: FnMut(&mut Writer<BytesMut>, usize, usize, usize, usize) -> bool,
+    {
+        // preparation
+        let (canvas_width, canvas_height) = self.get_size().await;
+        let (x_min, x_max, y_min, y_max) = self.calc_bounds(canvas_width, canvas_height, opts);
+        let mut buf = BytesMut::new().writer();
+        let mut arranger = CommandArranger::new(opts);
+
+        // main loop
+        tracing::info!("Running client loop");
+        let (stats, reporter) = ClientStats::start(opts);
+        let mut bytes_sent = 0;
+        let mut resumed = opts.resume;
+        while fill_buf(&mut buf, x_min, x_max, y_min, y_max) {
+            match std::mem::take(&mut resumed) {
+                true => arranger.arrange(&self.resume(buf.get_ref(), (x_min, x_max, y_min, y_max)).await),
+                false => arranger.arrange(buf.get_ref()),
+            };
+            let sent = self.send_commands(&arranger, opts).await;
+            self.flush().await.expect("Could not write commands to server");
+            stats.record_send(sent, count_commands(arranger.commands()) - arranger.reads());
+            stats.record_loop();
+            bytes_sent += sent;
+            buf.get_mut().clear();
+        }
+        if let Some(reporter) = reporter {
+            reporter.abort();
+        }
+
+        ClientLoopSummary {
+            canvas_width,
+            canvas_height,
+            x: x_min,
+            y: y_min,
+            width: x_max - x_min,
+            height: y_max - y_min,
+            bytes_sent,
+        }
+    }
+
+    /// Send arranged commands to the server using the most performant method available
+    ///
+    /// Responses to interleaved reads are received before this returns, except over UDP.
+    /// Returns how many bytes were sent which may be less than the buffer if a lossy link is simulated.
+    async fn send_commands(&mut self, arranger: &CommandArranger, opts: &cli::CommonClientOps) -> usize {
+        if let Some(jitter) = opts.send_jitter {
+            tokio::time::sleep(jitter.mul_f64(rand::random::<f64>())).await;
+        }
+
+        let commands = arranger.commands();
+        let data = match &opts.simulate {
+            None => Cow::Borrowed(commands),
+            Some(impairment) => Cow::Owned(impair(impairment, commands).await),
+        };
+
+        tracing::debug!("Sending prepared commands to server");
+        match self {
+            DynClient::Tcp(tcp) => tcp
+                .get_writer()
+                .write_all(&data)
+                .await
+                .expect("Could not write commands to server"),
+            DynClient::Unix(unix) => unix
+                .get_writer()
+                .write_all(&data)
+                .await
+                .expect("Could not write commands to server"),
+            DynClient::Udp(udp) => udp
+                .send_bulk(
+                    &data,
+                    &BulkSendOptions {
+                        mtu: opts.mtu,
+                        pacing: opts.udp_pacing.or(opts.pps.map(|pps| {
+                            // duplicates are sent right after each other, so pauses are made after each group
+                            Duration::from_secs(1) * (opts.udp_duplicates as u32 + 1) / pps.get()
+                        })),
+                        duplicates: opts.udp_duplicates,
+                    },
+                )
+                .await
+                .expect("Could not send commands to server"),
+        }
+
+        if arranger.reads() > 0 && !matches!(self, DynClient::Udp(_)) {
+            self.flush().await.expect("Could not write commands to server");
+            for _ in 0..arranger.reads() {
+                self.await_response()
+                    .await
+                    .expect("Could not receive response to interleaved read");
+            }
+        }
+        data.len()
+    }
+
     /// Get the remote canvas's size
     async fn get_size(&mut self) -> (usize, usize) {
         let Response::Size { width, height } = self
@@ -193,3 +1042,225 @@ impl DynClient {
         (x_min, x_max, y_min, y_max)
     }
 }
+
+/// Copy the colors of a region which the server sent into the data of the requested area
+///
+/// Both are given as `(x, y, width, height)` and the data of the requested area is ordered row by row.
+/// Fails without copying anything if the region does not lie inside the requested area or its colors don't fill it,
+/// e.g. because the server is misbehaving.
+fn copy_region(
+    data: &mut [Color],
+    (x_min, y_min, width, height): (usize, usize, usize, usize),
+    (x, y, region_width, region_height): (usize, usize, usize, usize),
+    colors: &[Color],
+) -> anyhow::Result<()> {
+    let inside = x >= x_min
+        && y >= y_min
+        && (x - x_min)
+            .checked_add(region_width)
+            .is_some_and(|x_max| x_max <= width)
+        && (y - y_min)
+            .checked_add(region_height)
+            .is_some_and(|y_max| y_max <= height);
+    if !inside || region_width.checked_mul(region_height) != Some(colors.len()) {
+        return Err(anyhow::anyhow!(
+            "server sent region {},{} of {}x{} pixels with {} colors which does not fit into the requested region",
+            x,
+            y,
+            region_width,
+            region_height,
+            colors.len()
+        ));
+    }
+    for (i, row) in colors.chunks_exact(region_width.max(1)).enumerate() {
+        let start = (y - y_min + i) * width + (x - x_min);
+        data[start..start + region_width].copy_from_slice(row);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_write_changed_pixels() {
+        let opts = cli::CommonClientOps {
+            server: Url::parse("tcp://localhost:1234").unwrap(),
+            width: TargetDimension::Fill,
+            height: TargetDimension::Fill,
+            x_offset: 0,
+            y_offset: 0,
+            do_loop: false,
+            simulate: None,
+            binary: false,
+            order: cli::CommandOrder::Sequential,
+            interleave_reads: None,
+            send_jitter: None,
+            resume: false,
+            connections: NonZeroUsize::MIN,
+            mtu: None,
+            udp_pacing: None,
+            pps: None,
+            udp_duplicates: 0,
+            stats_interval: None,
+        };
+        let first = RgbaImage::from_raw(2, 1, vec![0xFF, 0, 0, 0xFF, 0, 0, 0, 0]).unwrap();
+        let second = RgbaImage::from_raw(2, 1, vec![0xFF, 0, 0, 0xFF, 0, 0xFF, 0, 0x80]).unwrap();
+
+        let mut buf = Vec::new();
+        write_changed_pixels(None, &first, 10, 20, &mut buf, &opts);
+        assert_eq!(buf, b"PX 10 20 FF0000\n");
+
+        buf.clear();
+        write_changed_pixels(Some(&first), &second, 10, 20, &mut buf, &opts);
+        assert_eq!(buf, b"PX 11 20 00FF0080\n");
+    }
+
+    #[test]
+    fn test_copy_region() {
+        let mut data = vec![Color::default(); 4 * 3];
+        let colors = [Color::from(0x111111); 4];
+        copy_region(&mut data, (10, 20, 4, 3), (12, 21, 2, 2), &colors).unwrap();
+        assert_eq!(data[4 + 2], Color::from(0x111111));
+        assert_eq!(data[2 * 4 + 3], Color::from(0x111111));
+        assert_eq!(data[0], Color::default());
+
+        // regions which the server should not have sent are rejected
+        assert!(copy_region(&mut data, (10, 20, 4, 3), (9, 21, 2, 2), &colors).is_err());
+        assert!(copy_region(&mut data, (10, 20, 4, 3), (13, 21, 2, 2), &colors).is_err());
+        assert!(copy_region(&mut data, (10, 20, 4, 3), (12, 22, 2, 2), &colors).is_err());
+        assert!(copy_region(&mut data, (10, 20, 4, 3), (12, 21, 1, 2), &colors).is_err());
+        assert!(copy_region(&mut data, (10, 20, 4, 3), (10, 20, usize::MAX, 2), &colors).is_err());
+    }
+
+    #[test]
+    fn test_shard_commands() {
+        let mut commands = Vec::new();
+        for x in 0..10 {
+            let request = Request::SetPixel {
+                x,
+                y: 0,
+                color: Color::from(0xFF0000),
+            };
+            match x % 2 {
+                0 => request.write(&mut commands).unwrap(),
+                _ => request.write_binary(&mut commands).unwrap(),
+            }
+        }
+
+        assert_eq!(count_commands(&commands), 10);
+        let shards = shard_commands(&commands, 3);
+        assert_eq!(shards.len(), 3);
+        assert_eq!(shards.concat(), commands);
+        assert_eq!(
+            shards.iter().map(|shard| count_commands(shard)).sum::<usize>(),
+            10
+        );
+        for shard in &shards {
+            assert!(!shard.is_empty());
+            let mut rest = &shard[..];
+            while let Some(len) = request_frame_len(rest) {
+                assert!(parse_request_bin(&rest[..len]).is_ok());
+                rest = &rest[len..];
+            }
+            assert!(rest.is_empty());
+        }
+        assert_eq!(shard_commands(&commands, 1), vec![commands.clone()]);
+    }
+
+    #[test]
+    fn test_skip_matching_pixels() {
+        let mut commands = Vec::new();
+        let red = Color::from(0xFF0000);
+        Request::SetPixel {
+            x: 10,
+            y: 20,
+            color: red,
+        }
+        .write(&mut commands)
+        .unwrap();
+        Request::SetPixel {
+            x: 11,
+            y: 20,
+            color: red,
+        }
+        .write_binary(&mut commands)
+        .unwrap();
+        Request::SetPixel {
+            x: 10,
+            y: 21,
+            color: red,
+        }
+        .write_binary(&mut commands)
+        .unwrap();
+        Request::SetPixel {
+            x: 12,
+            y: 20,
+            color: red,
+        }
+        .write(&mut commands)
+        .unwrap();
+
+        // the region covers the first three pixels of which only the last one is not red yet
+        let current = [red, red, Color::from(0), Color::from(0)];
+        let remaining = skip_matching_pixels(&commands, &current, 10, 20, 2, 2);
+        let mut expected = Vec::new();
+        Request::SetPixel {
+            x: 10,
+            y: 21,
+            color: red,
+        }
+        .write_binary(&mut expected)
+        .unwrap();
+        Request::SetPixel {
+            x: 12,
+            y: 20,
+            color: red,
+        }
+        .write(&mut expected)
+        .unwrap();
+        assert_eq!(remaining, expected);
+    }
+
+    #[test]
+    fn test_resize_parallel() {
+        let uniform = image::RgbImage::from_pixel(37, 23, image::Rgb([12, 34, 56]));
+        let resized = resize_parallel(&uniform, 10, 50);
+        assert_eq!(resized.dimensions(), (10, 50));
+        assert!(resized.pixels().all(|p| p.0 == [12, 34, 56]));
+
+        let gradient = RgbaImage::from_fn(64, 48, |x, y| image::Rgba([x as u8 * 4, y as u8 * 5, 0x80, 0xFF]));
+        for (width, height) in [(16, 12), (100, 70), (64, 20)] {
+            let expected =
+                image::imageops::resize(&gradient, width, height, image::imageops::FilterType::Triangle);
+            let resized = resize_parallel(&gradient, width, height);
+            for (a, b) in resized.as_raw().iter().zip(expected.as_raw()) {
+                assert!(a.abs_diff(*b) <= 3, "{a} differs from {b} at {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_animation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("animation.gif");
+        let frames = [0xFF, 0x00].map(|red| {
+            let image = RgbaImage::from_pixel(2, 2, image::Rgba([red, 0, 0, 0xFF]));
+            image::Frame::from_parts(image, 0, 0, image::Delay::from_numer_denom_ms(250, 1))
+        });
+        image::codecs::gif::GifEncoder::new(std::fs::File::create(&path).unwrap())
+            .encode_frames(frames)
+            .unwrap();
+
+        let animation = load_animation(&path).unwrap().unwrap();
+        assert_eq!(animation.len(), 2);
+        assert_eq!(animation[0].1, Duration::from_millis(250));
+        assert_eq!(animation[1].0.get_pixel(1, 1).0, [0, 0, 0, 0xFF]);
+
+        let still = dir.path().join("still.png");
+        RgbaImage::new(2, 2).save(&still).unwrap();
+        assert!(load_animation(&still).unwrap().is_none());
+    }
+}
@@ -0,0 +1,268 @@
+//!
+//! Latency instrumentation of request handling
+//!
+//! When enabled via [`enable_latency_metrics`], the time which is spent in each phase of handling a request is
+//! recorded into HDR histograms per command.
+//! Recording happens into thread-local histograms so that worker threads don't contend with each other and the
+//! histograms are only merged when they are rendered via [`write_prometheus`].
+//!
+
+use crate::net::protocol::{Request, Response};
+use hdrhistogram::Histogram;
+use std::cell::OnceCell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The request types which are distinguished in latency metrics
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// `HELLO`
+    Hello,
+    /// `HELP`
+    Help,
+    /// `SIZE`
+    Size,
+    /// `PX <x> <y>`
+    GetPixel,
+    /// `PX <x1> <y1> <x2> <y2> ...`
+    GetPixels,
+    /// `PX <x> <y> <color>`
+    SetPixel,
+    /// `PX <x> <y> <color><alpha>`
+    BlendPixel,
+    /// `STATE REGION`
+    GetRegion,
+    /// `AUTH`
+    Auth,
+    /// `RESERVE`
+    Reserve,
+    /// `CLAIM` and `CLAIMS`
+    Claim,
+    /// `QUIET`
+    Quiet,
+    /// `SUBSCRIBE` with or without a region and `UNSUBSCRIBE`
+    Subscribe,
+    /// `CANVAS`
+    Canvas,
+    /// `CLEAR` and `FILL`
+    Fill,
+    /// `STATS`
+    Stats,
+}
+
+impl Command {
+    pub(crate) const ALL: [Command; 16] = [
+        Command::Hello,
+        Command::Help,
+        Command::Size,
+        Command::GetPixel,
+        Command::GetPixels,
+        Command::SetPixel,
+        Command::BlendPixel,
+        Command::GetRegion,
+        Command::Auth,
+        Command::Reserve,
+        Command::Claim,
+        Command::Quiet,
+        Command::Subscribe,
+        Command::Canvas,
+        Command::Fill,
+        Command::Stats,
+    ];
+
+    /// The command of a request
+    pub fn of_request(request: &Request) -> Self {
+        match request {
+            Request::Hello { .. } => Command::Hello,
+            Request::Help(_) => Command::Help,
+            Request::GetSize => Command::Size,
+            Request::GetPixel { .. } => Command::GetPixel,
+            Request::GetPixels { .. } => Command::GetPixels,
+            Request::SetPixel { .. } => Command::SetPixel,
+            Request::BlendPixel { .. } => Command::BlendPixel,
+            Request::GetRegion { .. } | Request::GetRect { .. } => Command::GetRegion,
+            Request::Auth { .. } => Command::Auth,
+            Request::Reserve { .. } => Command::Reserve,
+            Request::Claim { .. } | Request::GetClaims => Command::Claim,
+            Request::Quiet(_) => Command::Quiet,
+            Request::Subscribe(_) | Request::SubscribeRegion { .. } => Command::Subscribe,
+            Request::SelectCanvas(_) => Command::Canvas,
+            Request::Clear | Request::Fill(_) => Command::Fill,
+            Request::GetStats => Command::Stats,
+        }
+    }
+
+    /// The command which a response answers
+    pub fn of_response(response: &Response) -> Self {
+        match response {
+            Response::Hello { .. } => Command::Hello,
+            Response::Help(_) => Command::Help,
+            Response::Size { .. } => Command::Size,
+            Response::PxData { .. } => Command::GetPixel,
+            Response::PxDataBatch { .. } => Command::GetPixels,
+            Response::Region { .. } | Response::Rect { .. } => Command::GetRegion,
+            Response::Authenticated { .. } => Command::Auth,
+            Response::Reserved { .. } => Command::Reserve,
+            Response::Claimed { .. } | Response::Claims { .. } => Command::Claim,
+            Response::Stats { .. } => Command::Stats,
+        }
+    }
+
+    /// The name of the command in metrics and logs, e.g. `set_pixel`
+    pub fn label(&self) -> &'static str {
+        match self {
+            Command::Hello => "hello",
+            Command::Help => "help",
+            Command::Size => "size",
+            Command::GetPixel => "get_pixel",
+            Command::GetPixels => "get_pixels",
+            Command::SetPixel => "set_pixel",
+            Command::BlendPixel => "blend_pixel",
+            Command::GetRegion => "get_region",
+            Command::Auth => "auth",
+            Command::Reserve => "reserve",
+            Command::Claim => "claim",
+            Command::Quiet => "quiet",
+            Command::Subscribe => "subscribe",
+            Command::Canvas => "canvas",
+            Command::Fill => "fill",
+            Command::Stats => "stats",
+        }
+    }
+}
+
+/// The phases of request handling which are measured separately
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Phase {
+    /// Turning the received line into a [`Request`]
+    Parse,
+    /// Reading from or writing to the pixmap
+    PixmapAccess,
+    /// Serializing the response into the output buffer
+    ResponseWrite,
+}
+
+impl Phase {
+    const ALL: [Phase; 3] = [Phase::Parse, Phase::PixmapAccess, Phase::ResponseWrite];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::PixmapAccess => "pixmap_access",
+            Phase::ResponseWrite => "response_write",
+        }
+    }
+}
+
+/// Latency histograms in nanoseconds, indexed by phase and command
+type Histograms = [[Histogram<u64>; Command::ALL.len()]; Phase::ALL.len()];
+
+fn new_histograms() -> Histograms {
+    // one hour with two significant digits is plenty for request handling
+    std::array::from_fn(|_| {
+        std::array::from_fn(|_| Histogram::new_with_bounds(1, 3_600_000_000_000, 2).unwrap())
+    })
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The histograms of every thread which has recorded anything so far
+static THREADS: Mutex<Vec<Arc<Mutex<Histograms>>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static LOCAL: OnceCell<Arc<Mutex<Histograms>>> = const { OnceCell::new() };
+}
+
+/// Start recording latencies of all requests that are handled by this process
+///
+/// Recording is disabled by default because measuring time has a noticeable cost for cheap requests like
+/// `PX <x> <y> <color>`.
+pub fn enable_latency_metrics() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether latencies are currently being recorded
+#[inline(always)]
+pub fn latency_metrics_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record the time which was spent in one phase of handling a command
+#[inline]
+pub fn record(command: Command, phase: Phase, elapsed: Duration) {
+    LOCAL.with(|local| {
+        let histograms = local.get_or_init(|| {
+            let histograms = Arc::new(Mutex::new(new_histograms()));
+            THREADS.lock().unwrap().push(histograms.clone());
+            histograms
+        });
+        histograms.lock().unwrap()[phase as usize][command as usize]
+            .saturating_record(elapsed.as_nanos().max(1) as u64);
+    });
+}
+
+/// Write all recorded latencies as summaries in the prometheus text exposition format
+///
+/// The statistics of the connection pools of all stream listeners and the pixel counts of the busiest clients are
+/// appended.
+pub fn write_prometheus(out: &mut impl Write) -> std::fmt::Result {
+    let mut merged = new_histograms();
+    for thread in THREADS.lock().unwrap().iter() {
+        let thread = thread.lock().unwrap();
+        for (merged, thread) in merged.iter_mut().flatten().zip(thread.iter().flatten()) {
+            merged.add(thread).unwrap();
+        }
+    }
+
+    const NAME: &str = "pixeldike_request_latency_seconds";
+    writeln!(
+        out,
+        "# HELP {NAME} Time spent in each phase of handling a request"
+    )?;
+    writeln!(out, "# TYPE {NAME} summary")?;
+    for phase in Phase::ALL {
+        for command in Command::ALL {
+            let histogram = &merged[phase as usize][command as usize];
+            if histogram.is_empty() {
+                continue;
+            }
+            let labels = format!("command=\"{}\",phase=\"{}\"", command.label(), phase.label());
+            for quantile in [0.5, 0.9, 0.99, 0.999] {
+                writeln!(
+                    out,
+                    "{NAME}{{{labels},quantile=\"{quantile}\"}} {:e}",
+                    histogram.value_at_quantile(quantile) as f64 / 1e9
+                )?;
+            }
+            writeln!(
+                out,
+                "{NAME}_sum{{{labels}}} {:e}",
+                histogram.mean() * histogram.len() as f64 / 1e9
+            )?;
+            writeln!(out, "{NAME}_count{{{labels}}} {}", histogram.len())?;
+        }
+    }
+    crate::net::servers::write_pool_metrics(out)?;
+    crate::net::servers::write_client_metrics(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_output() {
+        record(Command::GetPixel, Phase::Parse, Duration::from_micros(3));
+        std::thread::spawn(|| record(Command::GetPixel, Phase::Parse, Duration::from_micros(5)))
+            .join()
+            .unwrap();
+
+        let mut out = String::new();
+        write_prometheus(&mut out).unwrap();
+        assert!(out
+            .contains("pixeldike_request_latency_seconds_count{command=\"get_pixel\",phase=\"parse\"} 2\n"));
+        assert!(!out.contains("command=\"set_pixel\",phase=\"response_write\""));
+    }
+}
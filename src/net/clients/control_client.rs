@@ -0,0 +1,152 @@
+use crate::pixmap::{Color, Effect};
+use anyhow::anyhow;
+use itertools::Itertools;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+
+/// A client for the control socket of a running pixeldike server
+///
+/// See `ControlServer` for a description of the control protocol.
+#[derive(Debug)]
+pub struct ControlClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    request_id: Option<String>,
+}
+
+impl ControlClient {
+    /// Try to connect to the control socket at the given path
+    pub async fn connect(path: &Path) -> std::io::Result<Self> {
+        let (reader, writer) = UnixStream::connect(path).await?.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+            request_id: None,
+        })
+    }
+
+    /// Send the next command which changes the server state with the given request id
+    ///
+    /// The server does not apply a command again if it recently applied one with the same id, so the command can
+    /// safely be retried with the same id on a new connection if its answer did not arrive.
+    /// The id must not contain whitespace and may be at most `MAX_REQUEST_ID_LEN` bytes long.
+    pub fn set_request_id(&mut self, id: &str) {
+        self.request_id = Some(id.to_string());
+    }
+
+    /// Download the complete canvas
+    ///
+    /// Returns the canvas size as well as its pixel data ordered row by row.
+    pub async fn export_canvas(&mut self) -> anyhow::Result<(usize, usize, Vec<Color>)> {
+        self.writer.write_all(b"EXPORT\n").await?;
+        let line = self.read_line().await?;
+        let (width, height) = match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+            ["CANVAS", width, height] => (width.parse::<usize>()?, height.parse::<usize>()?),
+            _ => return Err(anyhow!("unexpected response {:?}", line.trim())),
+        };
+
+        let mut buf = vec![0u8; width * height * 3];
+        self.reader.read_exact(&mut buf).await?;
+        let colors = buf.into_iter().tuples::<(_, _, _)>().map(Color::from).collect();
+        Ok((width, height, colors))
+    }
+
+    /// Write the given pixel data, ordered row by row, onto the canvas at position (x,y)
+    pub async fn import_canvas(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        colors: &[Color],
+    ) -> anyhow::Result<()> {
+        let data = colors
+            .iter()
+            .flat_map(|c| Into::<[u8; 3]>::into(*c))
+            .collect::<Vec<_>>();
+        self.send_command(&format!("IMPORT {} {} {} {}\n", x, y, width, height))
+            .await?;
+        self.writer.write_all(&data).await?;
+
+        let line = self.read_line().await?;
+        match line.trim() {
+            "OK" => Ok(()),
+            response => Err(anyhow!("{}", response.strip_prefix("ERROR ").unwrap_or(response))),
+        }
+    }
+
+    /// Freeze the canvas so that the server rejects all writes of its clients or unfreeze it again
+    pub async fn set_frozen(&mut self, frozen: bool) -> anyhow::Result<()> {
+        let command = match frozen {
+            true => "FREEZE\n",
+            false => "UNFREEZE\n",
+        };
+        self.send_command(command).await?;
+
+        let line = self.read_line().await?;
+        match line.trim() {
+            "OK" => Ok(()),
+            response => Err(anyhow!("{}", response.strip_prefix("ERROR ").unwrap_or(response))),
+        }
+    }
+
+    /// Ask the server to resize the canvas while keeping its content
+    ///
+    /// This returns once the canvas was resized. The server then disconnects its clients, including this one, so that
+    /// they pick up the new size.
+    pub async fn resize_canvas(&mut self, width: usize, height: usize) -> anyhow::Result<()> {
+        self.send_command(&format!("RESIZE {} {}\n", width, height))
+            .await?;
+
+        let line = self.read_line().await?;
+        match line.trim() {
+            "OK" => Ok(()),
+            response => Err(anyhow!("{}", response.strip_prefix("ERROR ").unwrap_or(response))),
+        }
+    }
+
+    /// Change the effect with which the server displays the canvas
+    pub async fn set_effect(&mut self, effect: Effect) -> anyhow::Result<()> {
+        self.send_command(&format!("EFFECT {}\n", effect)).await?;
+
+        let line = self.read_line().await?;
+        match line.trim() {
+            "OK" => Ok(()),
+            response => Err(anyhow!("{}", response.strip_prefix("ERROR ").unwrap_or(response))),
+        }
+    }
+
+    /// Retrieve the request latency metrics of the server in the prometheus text format
+    pub async fn metrics(&mut self) -> anyhow::Result<String> {
+        self.writer.write_all(b"METRICS\n").await?;
+        let line = self.read_line().await?;
+        let len = match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+            ["METRICS", len] => len.parse::<usize>()?,
+            _ => return Err(anyhow!("unexpected response {:?}", line.trim())),
+        };
+
+        let mut buf = vec![0u8; len];
+        self.reader.read_exact(&mut buf).await?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    /// Send a command which changes the server state, prefixed with the request id if one was set
+    async fn send_command(&mut self, command: &str) -> anyhow::Result<()> {
+        let command = match self.request_id.take() {
+            Some(id) => format!("ID {} {}", id, command),
+            None => command.to_string(),
+        };
+        self.writer.write_all(command.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("control socket was closed"));
+        }
+        Ok(line)
+    }
+}
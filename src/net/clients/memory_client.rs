@@ -0,0 +1,58 @@
+use crate::net::protocol::{Request, Response};
+use crate::net::servers::{handle_request, Session};
+use crate::pixmap::SharedPixmap;
+use anyhow::anyhow;
+use std::collections::VecDeque;
+
+/// A pixelflut client that is connected to an in-process [`MemoryServer`](crate::net::servers::MemoryServer).
+///
+/// Requests are still encoded into their wire format and parsed again by the server so that the whole protocol
+/// handling is exercised, but no sockets or background tasks are involved.
+/// Every request is handled immediately when it is sent and its response is queued until it is retrieved via
+/// [`await_response()`](MemoryClient::await_response).
+#[derive(Debug)]
+pub struct MemoryClient {
+    pixmap: SharedPixmap,
+    session: Session,
+    responses: VecDeque<Result<Response, String>>,
+}
+
+impl MemoryClient {
+    pub(crate) fn new(pixmap: SharedPixmap) -> Self {
+        Self {
+            pixmap,
+            // responses are only produced by requests so there is no way to deliver pixel changes
+            session: Session::default().without_subscriptions(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Send a single request to the server
+    pub async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
+        let mut buf = Vec::with_capacity(32);
+        request.write(&mut buf)?;
+        match handle_request(&buf, &self.pixmap, &mut self.session) {
+            Ok(None) => {}
+            Ok(Some(response)) => self.responses.push_back(Ok(response)),
+            Err(e) => self.responses.push_back(Err(e)),
+        }
+        Ok(())
+    }
+
+    /// Retrieve the oldest response which has not been retrieved yet
+    ///
+    /// Since requests are handled synchronously, this returns an error instead of waiting if no response is queued.
+    pub async fn await_response(&mut self) -> anyhow::Result<Response> {
+        match self.responses.pop_front() {
+            None => Err(anyhow!("server has not sent a response")),
+            Some(Err(e)) => Err(anyhow!("server responded with an error: {}", e)),
+            Some(Ok(response)) => Ok(response),
+        }
+    }
+
+    /// Send a single request to the server and retrieve its response
+    pub async fn exchange(&mut self, request: Request) -> anyhow::Result<Response> {
+        self.send_request(request).await?;
+        self.await_response().await
+    }
+}
@@ -1,13 +1,19 @@
 //! Client implementation for different transport protocols
 
+mod control_client;
+#[cfg(feature = "server")]
+mod memory_client;
 #[cfg(feature = "tcp")]
 mod tcp_client;
 #[cfg(feature = "udp")]
 mod udp_client;
 mod unix_socket_client;
 
+pub use control_client::ControlClient;
+#[cfg(feature = "server")]
+pub use memory_client::MemoryClient;
 #[cfg(feature = "tcp")]
 pub use tcp_client::TcpClient;
 #[cfg(feature = "udp")]
-pub use udp_client::UdpClient;
+pub use udp_client::{BulkSendOptions, UdpClient};
 pub use unix_socket_client::UnixSocketClient;
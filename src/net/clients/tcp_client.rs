@@ -1,14 +1,19 @@
 use crate::net::protocol::{parse_response_str, Request, Response};
+use std::fmt::{Debug, Formatter};
 use std::net::SocketAddr;
-use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::TcpStream;
 
 /// A pixelflut client that uses TCP and buffered read/write for communication with a pixelflut server.
-#[derive(Debug)]
 pub struct TcpClient {
-    reader: BufReader<OwnedReadHalf>,
-    writer: BufWriter<OwnedWriteHalf>,
+    reader: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    writer: BufWriter<Box<dyn AsyncWrite + Send + Unpin>>,
+}
+
+impl Debug for TcpClient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TcpClient").finish_non_exhaustive()
+    }
 }
 
 impl TcpClient {
@@ -16,8 +21,31 @@ impl TcpClient {
     pub async fn connect(addr: &SocketAddr) -> std::io::Result<Self> {
         let (reader, writer) = TcpStream::connect(addr).await?.into_split();
         Ok(Self {
-            reader: BufReader::new(reader),
-            writer: BufWriter::new(writer),
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
+        })
+    }
+
+    /// Try to connect to the TLS server running at the given address
+    ///
+    /// The server must present a certificate for `server_name` which is trusted by `config`
+    /// (see [`client_config`](crate::net::tls::client_config)).
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls(
+        addr: &SocketAddr,
+        server_name: &str,
+        config: std::sync::Arc<tokio_rustls::rustls::ClientConfig>,
+    ) -> std::io::Result<Self> {
+        let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(server_name.to_string())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = crate::net::tls::TlsConnector::from(config)
+            .connect(server_name, stream)
+            .await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self {
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
         })
     }
 
@@ -3,7 +3,35 @@ use anyhow::anyhow;
 use bytes::{BufMut, BytesMut};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// The MTU which is assumed if none is configured and the path MTU cannot be determined
+///
+/// This is below the 1500 bytes of ethernet to leave room for the overhead of tunnels like VPNs or PPPoE.
+const DEFAULT_MTU: usize = 1400;
+
+/// How far sends may fall behind their pacing before the missed pauses are forgotten
+///
+/// Sleeps are only as precise as the timer resolution, so pauses that are shorter than it are kept on average by
+/// catching up afterwards.
+const MAX_PACING_LAG: Duration = Duration::from_millis(10);
+
+/// Options which control how [`UdpClient::send_bulk`] splits data into datagrams and sends them
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct BulkSendOptions {
+    /// The maximum transmission unit of the network path to the server, including IP and UDP headers
+    ///
+    /// If not given, the path MTU which the operating system discovered is used on Linux and 1400 bytes elsewhere.
+    pub mtu: Option<usize>,
+    /// How long to wait between sending two datagrams
+    ///
+    /// The pause is kept on average, so pauses below the timer resolution result in small bursts.
+    pub pacing: Option<Duration>,
+    /// How many additional copies of every datagram are sent so that fewer commands are lost on lossy links
+    pub duplicates: usize,
+}
 
 /// A pixelflut client that uses UDP for communication with a pixelflut server.
 ///
@@ -12,6 +40,7 @@ use tokio::net::UdpSocket;
 #[derive(Debug)]
 pub struct UdpClient {
     socket: UdpSocket,
+    peer: SocketAddr,
 }
 
 impl UdpClient {
@@ -23,14 +52,16 @@ impl UdpClient {
             UdpSocket::bind(SocketAddr::from_str("[::]:0").unwrap()).await?
         };
         socket.connect(addr).await?;
-        Ok(Self { socket })
+        #[cfg(target_os = "linux")]
+        path_mtu::enable_discovery(&socket, addr.is_ipv4())?;
+        Ok(Self { socket, peer: *addr })
     }
 
     /// Send a single request to the configured server
     pub async fn send_request(&mut self, request: Request) -> std::io::Result<()> {
         let mut buf = BytesMut::with_capacity(64).writer();
         request.write(&mut buf).unwrap();
-        self.socket.send(&buf.get_ref()).await?;
+        self.socket.send(buf.get_ref()).await?;
         Ok(())
     }
 
@@ -54,13 +85,182 @@ impl UdpClient {
         Ok(response)
     }
 
+    /// The largest datagram payload which fits into the MTU towards the server
+    fn max_payload(&self, options: &BulkSendOptions) -> usize {
+        #[cfg(target_os = "linux")]
+        let discovered = path_mtu::get(&self.socket, self.peer.is_ipv4()).ok();
+        #[cfg(not(target_os = "linux"))]
+        let discovered = None;
+
+        let mtu = options.mtu.or(discovered).unwrap_or(DEFAULT_MTU);
+        let headers = if self.peer.is_ipv4() { 20 + 8 } else { 40 + 8 };
+        mtu.saturating_sub(headers).max(1)
+    }
+
     /// Send pre-encoded commands in bulk
     ///
-    /// Note that because UDP is an unreliable transport mechanism, not all bytes might actually be sent.
-    /// If the buffer size is larger than the maximum size supported by UDP or the maximum transmission unit of the
-    /// underlying network layer, the packet might even be discarded completely.
-    pub async fn send_bulk(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        let _ = self.socket.send(buf).await?;
+    /// The commands are split into datagrams which fit into the MTU towards the server so that they are not
+    /// fragmented. Commands are never split across datagrams, so a single command which is larger than the MTU is
+    /// sent on its own.
+    ///
+    /// Note that because UDP is an unreliable transport mechanism, not all commands might actually arrive.
+    /// Sending duplicates of every datagram via [`BulkSendOptions::duplicates`] makes losses less likely.
+    pub async fn send_bulk(&mut self, buf: &[u8], options: &BulkSendOptions) -> std::io::Result<()> {
+        let mut max_payload = self.max_payload(options);
+        let mut next_send = Instant::now();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let len = datagram_len(rest, max_payload);
+            if let Some(pacing) = options.pacing {
+                if next_send > Instant::now() {
+                    tokio::time::sleep_until(next_send).await;
+                }
+                // sends which fell behind are caught up in a burst but only for a limited time
+                let earliest = Instant::now().checked_sub(pacing.max(MAX_PACING_LAG));
+                next_send = earliest.map_or(next_send, |i| next_send.max(i)) + pacing;
+            }
+
+            match self.send_datagram(&rest[..len], options.duplicates).await {
+                Ok(()) => {}
+                // the path MTU shrank since it was last queried, so the remaining commands are split again
+                #[cfg(target_os = "linux")]
+                Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) && options.mtu.is_none() => {
+                    let new_max_payload = self.max_payload(options);
+                    if new_max_payload < max_payload {
+                        max_payload = new_max_payload;
+                        continue;
+                    }
+                    tracing::warn!(
+                        "Dropping {} bytes of commands which do not fit into the path MTU",
+                        len
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+            rest = &rest[len..];
+        }
         Ok(())
     }
+
+    /// Send one datagram and the configured number of duplicates of it
+    async fn send_datagram(&self, datagram: &[u8], duplicates: usize) -> std::io::Result<()> {
+        for _ in 0..=duplicates {
+            self.socket.send(datagram).await?;
+        }
+        Ok(())
+    }
+}
+
+/// How many bytes from the start of `buf` are sent in the next datagram
+///
+/// This is as many complete newline terminated commands as fit into `max_payload` bytes, or the first command if it
+/// alone is larger than that.
+fn datagram_len(buf: &[u8], max_payload: usize) -> usize {
+    if buf.len() <= max_payload {
+        return buf.len();
+    }
+    match buf[..max_payload].iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .map(|i| i + 1)
+            .unwrap_or(buf.len()),
+    }
+}
+
+/// Access to the path MTU which the Linux kernel discovers for connected sockets
+#[cfg(target_os = "linux")]
+mod path_mtu {
+    use std::mem::size_of;
+    use std::os::fd::AsRawFd;
+    use tokio::net::UdpSocket;
+
+    /// Forbid fragmentation so that the kernel performs path MTU discovery and rejects datagrams which are too large
+    pub(super) fn enable_discovery(socket: &UdpSocket, ipv4: bool) -> std::io::Result<()> {
+        let (level, name, value) = match ipv4 {
+            true => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO),
+            false => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_DO,
+            ),
+        };
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                std::ptr::from_ref(&value).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    /// The currently known path MTU of a connected socket
+    pub(super) fn get(socket: &UdpSocket, ipv4: bool) -> std::io::Result<usize> {
+        let (level, name) = match ipv4 {
+            true => (libc::IPPROTO_IP, libc::IP_MTU),
+            false => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+        };
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                std::ptr::from_mut(&mut value).cast(),
+                &mut len,
+            )
+        };
+        match result {
+            0 => Ok(value as usize),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_datagram_len() {
+        assert_eq!(datagram_len(b"PX 0 0 ff0000\nPX 1 0 ff0000\n", 100), 28);
+        assert_eq!(datagram_len(b"PX 0 0 ff0000\nPX 1 0 ff0000\n", 20), 14);
+        assert_eq!(datagram_len(b"PX 10 10 ff0000\nPX 1 0 ff0000\n", 10), 16);
+        assert_eq!(datagram_len(b"PX 10 10 ff0000", 10), 15);
+    }
+
+    #[tokio::test]
+    async fn test_send_bulk() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = UdpClient::connect(&server.local_addr().unwrap()).await.unwrap();
+        let commands = (0..10)
+            .map(|i| format!("PX {} 0 ff0000\n", i))
+            .collect::<String>();
+        let options = BulkSendOptions {
+            mtu: Some(28 + 32),
+            pacing: Some(Duration::from_micros(100)),
+            duplicates: 1,
+        };
+        client.send_bulk(commands.as_bytes(), &options).await.unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            let mut buf = [0; 1024];
+            let len = server.recv(&mut buf).await.unwrap();
+            assert!(len <= 32);
+            assert!(buf[..len].ends_with(b"\n"));
+            received.push(buf[..len].to_vec());
+        }
+        assert!(received.chunks(2).all(|pair| pair[0] == pair[1]));
+        let unique = received.iter().step_by(2).flatten().copied().collect::<Vec<_>>();
+        assert_eq!(unique, commands.as_bytes());
+    }
 }
@@ -21,6 +21,21 @@ impl UnixSocketClient {
         })
     }
 
+    /// Try to connect to the server that listens on the given name in the abstract socket namespace (Linux only)
+    #[cfg(target_os = "linux")]
+    pub async fn connect_abstract(name: &[u8]) -> std::io::Result<Self> {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let stream = std::os::unix::net::UnixStream::connect_addr(&addr)?;
+        stream.set_nonblocking(true)?;
+        let (reader, writer) = UnixStream::from_std(stream)?.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        })
+    }
+
     /// Enqueue a single request to be sent to the connected server
     ///
     /// Note that because the TCP-Client uses buffered IO, your request may not be sent immediately.
@@ -0,0 +1,255 @@
+//!
+//! Discovery of pixelflut servers in the local network via mDNS, also known as zeroconf or Bonjour
+//!
+//! Servers announce each of their TCP ports as an instance of the [`SERVICE_TYPE`] service.
+//! The size of the canvas is included in the `width` and `height` TXT records and the server software in the
+//! `agent` record so that clients can pick a server without connecting to all of them first.
+//!
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use crate::DaemonResult;
+#[cfg(feature = "server")]
+use std::collections::HashMap;
+#[cfg(feature = "server")]
+use tokio::task::{AbortHandle, JoinSet};
+
+/// The mDNS service type under which pixelflut servers are announced
+pub const SERVICE_TYPE: &str = "_pixelflut._tcp.local.";
+
+/// How a server announces itself
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AdvertiseOptions {
+    /// The instance name which is shown to clients, e.g. the name of the event
+    pub name: String,
+    /// The addresses of the TCP listeners which are announced
+    ///
+    /// Listeners on an unspecified address like `0.0.0.0` are announced with all addresses of the host.
+    pub addrs: Vec<SocketAddr>,
+    /// The size of the canvas
+    pub size: (usize, usize),
+}
+
+/// A pixelflut server which was found in the local network
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiscoveredServer {
+    /// The instance name under which the server announced itself
+    pub name: String,
+    /// The mDNS host name of the server, e.g. `pixeldike.local.`
+    pub host: String,
+    /// The addresses at which the server can be reached
+    pub addresses: Vec<IpAddr>,
+    /// The TCP port of the server
+    pub port: u16,
+    /// The size of the canvas if the server announced it
+    pub size: Option<(usize, usize)>,
+    /// The server software if the server announced it
+    pub agent: Option<String>,
+}
+
+impl DiscoveredServer {
+    /// Extract the information of a resolved service instance
+    fn from_service(info: &ServiceInfo) -> Self {
+        let mut addresses = info.get_addresses().iter().copied().collect::<Vec<_>>();
+        addresses.sort();
+        let property = |key: &str| info.get_property_val_str(key);
+        let dimension = |key: &str| property(key).and_then(|i| i.parse::<usize>().ok());
+        Self {
+            name: info
+                .get_fullname()
+                .strip_suffix(&format!(".{}", SERVICE_TYPE))
+                .unwrap_or(info.get_fullname())
+                .to_string(),
+            host: info.get_hostname().to_string(),
+            addresses,
+            port: info.get_port(),
+            size: dimension("width").zip(dimension("height")),
+            agent: property("agent").map(str::to_string),
+        }
+    }
+}
+
+/// The service instances which announce the given listeners, one per port
+#[cfg(feature = "server")]
+fn services(options: &AdvertiseOptions) -> anyhow::Result<Vec<ServiceInfo>> {
+    let mut ports = BTreeMap::<u16, Vec<IpAddr>>::new();
+    for addr in &options.addrs {
+        ports.entry(addr.port()).or_default().push(addr.ip());
+    }
+    // mDNS host names may only contain letters, digits and hyphens
+    let host = options
+        .name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect::<String>();
+    let (width, height) = options.size;
+    let properties = HashMap::from([
+        ("width".to_string(), width.to_string()),
+        ("height".to_string(), height.to_string()),
+        (
+            "agent".to_string(),
+            concat!("pixeldike/", env!("CARGO_PKG_VERSION")).to_string(),
+        ),
+    ]);
+
+    ports
+        .iter()
+        .map(|(port, ips)| {
+            // instance names must be unique, so several ports are told apart by their number
+            let name = match ports.len() {
+                1 => options.name.clone(),
+                _ => format!("{} ({})", options.name, port),
+            };
+            let specific = match ips.iter().any(IpAddr::is_unspecified) {
+                true => Vec::new(),
+                false => ips.clone(),
+            };
+            let info = ServiceInfo::new(
+                SERVICE_TYPE,
+                &name,
+                &format!("{}.local.", host),
+                &specific[..],
+                *port,
+                properties.clone(),
+            )?;
+            Ok(match specific.is_empty() {
+                true => info.enable_addr_auto(),
+                false => info,
+            })
+        })
+        .collect()
+}
+
+/// Announce a server in the local network until the returned task is aborted
+///
+/// Clients are told that the server is gone once the task is aborted, e.g. because the join set is shut down.
+#[cfg(feature = "server")]
+pub fn advertise(
+    join_set: &mut JoinSet<DaemonResult>,
+    options: AdvertiseOptions,
+) -> anyhow::Result<AbortHandle> {
+    let daemon = Advertisement::new(services(&options)?)?;
+    tracing::info!("Announcing server as {} via mDNS", options.name);
+    let handle = join_set
+        .build_task()
+        .name("mdns_advertisement")
+        .spawn(async move {
+            // the daemon answers queries on its own thread for as long as it is kept alive
+            let _daemon = daemon;
+            std::future::pending().await
+        })?;
+    Ok(handle)
+}
+
+/// Registered service instances which are unregistered when this is dropped
+#[cfg(feature = "server")]
+struct Advertisement {
+    daemon: ServiceDaemon,
+    names: Vec<String>,
+}
+
+#[cfg(feature = "server")]
+impl Advertisement {
+    fn new(services: Vec<ServiceInfo>) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let names = services.iter().map(|i| i.get_fullname().to_string()).collect();
+        for service in services {
+            daemon.register(service)?;
+        }
+        Ok(Self { daemon, names })
+    }
+}
+
+#[cfg(feature = "server")]
+impl std::fmt::Debug for Advertisement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Advertisement")
+            .field("names", &self.names)
+            .finish()
+    }
+}
+
+#[cfg(feature = "server")]
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        for name in &self.names {
+            if let Err(e) = self.daemon.unregister(name) {
+                tracing::warn!("Could not withdraw mDNS announcement of {}: {}", name, e);
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Listen for announcements of pixelflut servers in the local network for the given duration
+///
+/// Servers are returned ordered by name.
+pub async fn discover(duration: Duration) -> anyhow::Result<Vec<DiscoveredServer>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let mut servers = BTreeMap::new();
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            event = events.recv_async() => match event? {
+                ServiceEvent::ServiceResolved(info) => {
+                    servers.insert(info.get_fullname().to_string(), DiscoveredServer::from_service(&info));
+                }
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    servers.remove(&fullname);
+                }
+                _ => {}
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(servers.into_values().collect())
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_services() {
+        let options = AdvertiseOptions {
+            name: "Pixel Party".to_string(),
+            addrs: vec![
+                "0.0.0.0:1234".parse().unwrap(),
+                "[::]:1234".parse().unwrap(),
+                "192.0.2.1:1337".parse().unwrap(),
+            ],
+            size: (800, 600),
+        };
+        let services = services(&options).unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(
+            services[0].get_fullname(),
+            "Pixel Party (1234)._pixelflut._tcp.local."
+        );
+        assert_eq!(services[0].get_hostname(), "pixel-party.local.");
+        assert!(services[0].is_addr_auto());
+
+        let server = DiscoveredServer::from_service(&services[1]);
+        assert_eq!(
+            server,
+            DiscoveredServer {
+                name: "Pixel Party (1337)".to_string(),
+                host: "pixel-party.local.".to_string(),
+                addresses: vec!["192.0.2.1".parse().unwrap()],
+                port: 1337,
+                size: Some((800, 600)),
+                agent: Some(concat!("pixeldike/", env!("CARGO_PKG_VERSION")).to_string()),
+            }
+        );
+    }
+}
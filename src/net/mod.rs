@@ -3,5 +3,10 @@
 //!
 
 pub mod clients;
+#[cfg(feature = "mdns")]
+pub mod discovery;
 pub mod protocol;
+#[cfg(feature = "server")]
 pub mod servers;
+#[cfg(feature = "tls")]
+pub mod tls;
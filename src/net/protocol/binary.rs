@@ -0,0 +1,98 @@
+//! The binary `PB` command which sets a pixel without any ASCII formatting
+//!
+//! A `PB` command consists of exactly [`BINARY_PX_LEN`] bytes: the ASCII characters `PB` followed by the x and y
+//! coordinates as little-endian `u16` and the color as `r`, `g`, `b` and `a` bytes.
+//! It is not terminated by a newline and can be freely mixed with newline terminated text commands.
+//! This is the same format that is used by other pixelflut servers like breakwater.
+//!
+//! Colors which are not fully opaque are blended onto the current pixel color.
+
+use crate::net::protocol::Request;
+use crate::pixmap::Color;
+use std::io::Write;
+
+/// The length of a binary `PB` command in bytes
+pub const BINARY_PX_LEN: usize = 10;
+
+/// Determine the length of the first complete request in `buf`
+///
+/// This is either a binary `PB` command or a line including its terminating `\n`.
+/// Returns `None` if `buf` does not yet contain a complete request.
+///
+/// The newline is searched with SIMD instructions where the platform supports them, which matters because this is
+/// done for every single request that a server receives.
+#[inline(always)]
+pub fn request_frame_len(buf: &[u8]) -> Option<usize> {
+    if buf.starts_with(b"PB") {
+        return match buf.len() >= BINARY_PX_LEN {
+            true => Some(BINARY_PX_LEN),
+            false => None,
+        };
+    }
+    memchr::memchr(b'\n', buf).map(|i| i + 1)
+}
+
+/// Parse a binary `PB` command
+///
+/// Returns `None` if `frame` is not exactly one `PB` command.
+#[inline(always)]
+pub(crate) fn parse_binary_px(frame: &[u8]) -> Option<Request> {
+    match frame {
+        [b'P', b'B', x1, x2, y1, y2, r, g, b, 0xFF] => Some(Request::SetPixel {
+            x: u16::from_le_bytes([*x1, *x2]) as usize,
+            y: u16::from_le_bytes([*y1, *y2]) as usize,
+            color: Color::from((*r, *g, *b)),
+        }),
+        [b'P', b'B', x1, x2, y1, y2, r, g, b, a] => Some(Request::BlendPixel {
+            x: u16::from_le_bytes([*x1, *x2]) as usize,
+            y: u16::from_le_bytes([*y1, *y2]) as usize,
+            color: Color::from((*r, *g, *b)),
+            alpha: *a,
+        }),
+        _ => None,
+    }
+}
+
+/// Write a binary `PB` command which blends `color` with the given opacity onto the pixel at (x,y)
+pub(crate) fn write_binary_px(
+    x: u16,
+    y: u16,
+    color: Color,
+    alpha: u8,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let [r, g, b]: [u8; 3] = color.into();
+    let [x1, x2] = x.to_le_bytes();
+    let [y1, y2] = y.to_le_bytes();
+    writer.write_all(&[b'P', b'B', x1, x2, y1, y2, r, g, b, alpha])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::parse_request_bin;
+
+    #[test]
+    fn test_binary_px_framing() {
+        let mut buf = Vec::new();
+        let request = Request::SetPixel {
+            x: 0x0a0b,
+            y: 10,
+            color: Color::from(0x0a0b0c),
+        };
+        request.write_binary(&mut buf).unwrap();
+        Request::GetSize.write_binary(&mut buf).unwrap();
+        request.write_binary(&mut buf).unwrap();
+
+        // binary commands may contain newline bytes which must not split them
+        let len = request_frame_len(&buf).unwrap();
+        assert_eq!(len, BINARY_PX_LEN);
+        assert_eq!(parse_request_bin(&buf[..len]).unwrap(), request);
+        let rest = &buf[len..];
+        let len = request_frame_len(rest).unwrap();
+        assert_eq!(parse_request_bin(&rest[..len]).unwrap(), Request::GetSize);
+        let rest = &rest[len..];
+        assert_eq!(request_frame_len(&rest[..BINARY_PX_LEN - 1]), None);
+        assert_eq!(request_frame_len(rest), Some(BINARY_PX_LEN));
+    }
+}
@@ -0,0 +1,256 @@
+//! A compact binary encoding of canvas content and its changes for viewers like browsers
+//!
+//! A message consists of any number of records which are concatenated without separators.
+//! Every record starts with a tag byte:
+//!
+//! - [`DELTA_REGION`] is followed by the x and y coordinates, width and height of a region as little-endian `u32`
+//!   and the `r`, `g`, `b` bytes of all pixels in the region row by row.
+//!   The first message of a stream contains the whole canvas as regions starting at (0,0) from which viewers learn
+//!   the canvas size. Later regions replace parts of the canvas which were overwritten at once or which the viewer
+//!   missed because it was too slow.
+//! - [`DELTA_PIXEL`] is followed by the x and y coordinates of a single changed pixel as little-endian `u16` and its
+//!   new `r`, `g`, `b` bytes.
+//!   Changes of pixels whose coordinates don't fit into a `u16` are sent as 1x1 regions instead.
+//! - [`DELTA_BLOCKS`] is followed by the x and y coordinates, width and height of a region and the size of square
+//!   blocks as little-endian `u32` and the `r`, `g`, `b` bytes of one color per block.
+//!   The blocks cover the region row by row starting at its top-left corner and blocks at its right and bottom edges
+//!   are cut off by the region. Viewers fill every pixel of a block with its color.
+//!   This is a low resolution preview of the region which is refined by later records, e.g. when the canvas is sent
+//!   progressively.
+
+use crate::net::protocol::compliant_parser::ParseErr;
+use crate::pixmap::Color;
+use bytes::BufMut;
+
+/// The tag of a record which contains the pixels of a whole region
+pub const DELTA_REGION: u8 = 0x01;
+
+/// The tag of a record which contains a single changed pixel
+pub const DELTA_PIXEL: u8 = 0x02;
+
+/// The tag of a record which contains one color per block of pixels in a region
+pub const DELTA_BLOCKS: u8 = 0x03;
+
+/// One record of a canvas delta message
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CanvasDelta {
+    /// The content of a region
+    Region {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+        /// The colors of all pixels in the region row by row
+        data: Vec<Color>,
+    },
+    /// The colors of blocks of pixels which cover a region
+    Blocks {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+        /// The width and height of each block
+        block: usize,
+        /// The color of every block row by row
+        data: Vec<Color>,
+    },
+    /// The new color of a single pixel
+    Pixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The new color of the pixel
+        color: Color,
+    },
+}
+
+/// Append a record containing the content of a region
+pub fn write_delta_region(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    data: &[Color],
+    buf: &mut impl BufMut,
+) {
+    debug_assert_eq!(data.len(), width * height);
+    buf.put_u8(DELTA_REGION);
+    for i in [x, y, width, height] {
+        buf.put_u32_le(i as u32);
+    }
+    for &color in data {
+        buf.put_slice(&<[u8; 3]>::from(color));
+    }
+}
+
+/// Append a record containing one color per block of `block` by `block` pixels of a region
+pub fn write_delta_blocks(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    block: usize,
+    data: &[Color],
+    buf: &mut impl BufMut,
+) {
+    debug_assert_eq!(data.len(), width.div_ceil(block) * height.div_ceil(block));
+    buf.put_u8(DELTA_BLOCKS);
+    for i in [x, y, width, height, block] {
+        buf.put_u32_le(i as u32);
+    }
+    for &color in data {
+        buf.put_slice(&<[u8; 3]>::from(color));
+    }
+}
+
+/// Append a record containing the new color of a single pixel
+pub fn write_delta_pixel(x: usize, y: usize, color: Color, buf: &mut impl BufMut) {
+    match (u16::try_from(x), u16::try_from(y)) {
+        (Ok(x), Ok(y)) => {
+            buf.put_u8(DELTA_PIXEL);
+            buf.put_u16_le(x);
+            buf.put_u16_le(y);
+            buf.put_slice(&<[u8; 3]>::from(color));
+        }
+        _ => write_delta_region(x, y, 1, 1, &[color], buf),
+    }
+}
+
+/// Parse all records of a canvas delta message
+pub fn parse_canvas_delta(mut msg: &[u8]) -> Result<Vec<CanvasDelta>, ParseErr> {
+    fn take<'a>(msg: &mut &'a [u8], n: usize) -> Result<&'a [u8], ParseErr> {
+        if msg.len() < n {
+            return Err(ParseErr::InvalidCommand);
+        }
+        let (head, rest) = msg.split_at(n);
+        *msg = rest;
+        Ok(head)
+    }
+    let u32_at = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
+    let color_at = |bytes: &[u8]| Color::from((bytes[0], bytes[1], bytes[2]));
+
+    let mut records = Vec::new();
+    while let Some((&tag, rest)) = msg.split_first() {
+        msg = rest;
+        match tag {
+            DELTA_REGION => {
+                let header = take(&mut msg, 16)?;
+                let (x, y, width, height) = (
+                    u32_at(header, 0),
+                    u32_at(header, 4),
+                    u32_at(header, 8),
+                    u32_at(header, 12),
+                );
+                let len = width
+                    .checked_mul(height)
+                    .and_then(|pixels| pixels.checked_mul(3))
+                    .ok_or(ParseErr::InvalidCommand)?;
+                let data = take(&mut msg, len)?.chunks_exact(3).map(color_at).collect();
+                records.push(CanvasDelta::Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data,
+                });
+            }
+            DELTA_BLOCKS => {
+                let header = take(&mut msg, 20)?;
+                let (x, y, width, height, block) = (
+                    u32_at(header, 0),
+                    u32_at(header, 4),
+                    u32_at(header, 8),
+                    u32_at(header, 12),
+                    u32_at(header, 16),
+                );
+                if block == 0 {
+                    return Err(ParseErr::InvalidCommand);
+                }
+                let len = width
+                    .div_ceil(block)
+                    .checked_mul(height.div_ceil(block))
+                    .and_then(|blocks| blocks.checked_mul(3))
+                    .ok_or(ParseErr::InvalidCommand)?;
+                let data = take(&mut msg, len)?.chunks_exact(3).map(color_at).collect();
+                records.push(CanvasDelta::Blocks {
+                    x,
+                    y,
+                    width,
+                    height,
+                    block,
+                    data,
+                });
+            }
+            DELTA_PIXEL => {
+                let pixel = take(&mut msg, 7)?;
+                records.push(CanvasDelta::Pixel {
+                    x: u16::from_le_bytes([pixel[0], pixel[1]]) as usize,
+                    y: u16::from_le_bytes([pixel[2], pixel[3]]) as usize,
+                    color: color_at(&pixel[4..]),
+                });
+            }
+            _ => return Err(ParseErr::UnknownCommand),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let mut buf = Vec::new();
+        let data = [Color::from(0x010203), Color::from(0x040506)];
+        write_delta_region(3, 4, 2, 1, &data, &mut buf);
+        write_delta_pixel(7, 8, Color::from(0xFF0000), &mut buf);
+        write_delta_pixel(70000, 8, Color::from(0x00FF00), &mut buf);
+        assert_eq!(buf.len(), 17 + 6 + 8 + 17 + 3);
+        // 3x2 blocks cover the 5x4 region
+        write_delta_blocks(0, 0, 5, 4, 2, &[Color::from(0x0000FF); 6], &mut buf);
+
+        assert_eq!(
+            parse_canvas_delta(&buf).unwrap(),
+            vec![
+                CanvasDelta::Region {
+                    x: 3,
+                    y: 4,
+                    width: 2,
+                    height: 1,
+                    data: data.to_vec()
+                },
+                CanvasDelta::Pixel {
+                    x: 7,
+                    y: 8,
+                    color: Color::from(0xFF0000)
+                },
+                CanvasDelta::Region {
+                    x: 70000,
+                    y: 8,
+                    width: 1,
+                    height: 1,
+                    data: vec![Color::from(0x00FF00)]
+                },
+                CanvasDelta::Blocks {
+                    x: 0,
+                    y: 0,
+                    width: 5,
+                    height: 4,
+                    block: 2,
+                    data: vec![Color::from(0x0000FF); 6]
+                },
+            ]
+        );
+        assert!(parse_canvas_delta(&buf[..buf.len() - 1]).is_err());
+    }
+}
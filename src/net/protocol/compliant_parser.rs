@@ -3,7 +3,8 @@
 use anyhow::anyhow;
 use thiserror::Error;
 
-use crate::net::protocol::{HelpTopic, Request, Response};
+use crate::net::protocol::binary::parse_binary_px;
+use crate::net::protocol::{Extension, HelpTopic, Request, Response, StateEncoding};
 use crate::pixmap::Color;
 
 /// Errors that can occur while parsing an input buffer
@@ -15,20 +16,147 @@ pub enum ParseErr {
     /// The passed pixelflut command is known but its invocation was invalid
     #[error("Invalid Command Invocation")]
     InvalidCommand,
+    /// The line contains whitespace which is not accepted in [`Strictness::Strict`] mode
+    #[error("Malformed Whitespace")]
+    MalformedWhitespace,
+}
+
+/// How forgiving the parser is about whitespace between and around tokens
+///
+/// In [`Strictness::Lenient`] mode, which is the default, tokens may be separated by any run of ascii whitespace and
+/// lines may have leading or trailing whitespace including a `\r\n` line ending.
+/// This matches the behavior of most other pixelflut servers and is what many third-party clients rely on.
+///
+/// In [`Strictness::Strict`] mode, tokens must be separated by exactly one space and lines must only be terminated by
+/// a single `\n`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Strictness {
+    /// Accept any ascii whitespace between and around tokens
+    #[default]
+    Lenient,
+    /// Only accept tokens that are separated by single spaces
+    Strict,
+}
+
+/// Check whether a line only contains the whitespace that is allowed in [`Strictness::Strict`] mode
+#[inline(always)]
+fn is_strictly_spaced(line: &[u8]) -> bool {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.split(|b| *b == b' ')
+        .all(|token| !token.is_empty() && !token.iter().any(u8::is_ascii_whitespace))
+}
+
+/// Parse an unsigned decimal number
+///
+/// This is a hand-rolled replacement for `str::parse()` which accepts exactly the same inputs (ascii digits with an
+/// optional leading `+`) but avoids the generic number parsing machinery because it is the hottest part of handling
+/// a `PX` command.
+#[inline(always)]
+fn parse_dec<T: TryFrom<u64>>(token: &str) -> Option<T> {
+    let digits = token.as_bytes();
+    let digits = digits.strip_prefix(b"+").unwrap_or(digits);
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut value: u64 = 0;
+    if digits.len() <= MAX_SAFE_DEC_DIGITS {
+        for &b in digits {
+            let digit = b.wrapping_sub(b'0');
+            if digit > 9 {
+                return None;
+            }
+            value = value * 10 + digit as u64;
+        }
+    } else {
+        for &b in digits {
+            let digit = b.wrapping_sub(b'0');
+            if digit > 9 {
+                return None;
+            }
+            value = value.checked_mul(10)?.checked_add(digit as u64)?;
+        }
+    }
+    T::try_from(value).ok()
+}
+
+/// How many decimal digits always fit into a `u64` without overflowing
+const MAX_SAFE_DEC_DIGITS: usize = 19;
+
+/// The value of every ascii hex digit or `0xFF` for bytes which aren't one
+const HEX_DIGITS: [u8; 256] = {
+    let mut table = [0xFF; 256];
+    let mut i = 0;
+    while i < 10 {
+        table[b'0' as usize + i] = i as u8;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 6 {
+        table[b'a' as usize + i] = 10 + i as u8;
+        table[b'A' as usize + i] = 10 + i as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Parse a hexadecimal color value
+///
+/// This accepts the same inputs as `u32::from_str_radix(token, 16)`, so the color may also be given with fewer than
+/// six digits or with an additional alpha byte.
+#[inline(always)]
+fn parse_hex_color(token: &str) -> Option<Color> {
+    let digits = token.as_bytes();
+    let digits = digits.strip_prefix(b"+").unwrap_or(digits);
+    if digits.is_empty() {
+        return None;
+    }
+
+    // more than 8 digits only fit into a u32 if the superfluous ones are leading zeros
+    let digits = match digits.len() {
+        0..=8 => digits,
+        _ => match digits.iter().position(|&b| b != b'0') {
+            Some(i) if digits.len() - i <= 8 => &digits[i..],
+            Some(_) => return None,
+            None => b"0",
+        },
+    };
+
+    let mut value: u32 = 0;
+    for &b in digits {
+        let digit = HEX_DIGITS[b as usize];
+        if digit == 0xFF {
+            return None;
+        }
+        value = value << 4 | digit as u32;
+    }
+    Some(Color::from(value))
 }
 
 /// Parse the arguments to a PxSet command
 #[inline(always)]
 fn parse_px_set_args(x: &str, y: &str, px: &str) -> Result<Request, ParseErr> {
-    let xres = x.parse();
-    let yres = y.parse();
-    let cres = u32::from_str_radix(px, 16);
+    let xres = parse_dec(x);
+    let yres = parse_dec(y);
+    let cres = parse_hex_color(px);
     match (xres, yres, cres) {
-        (Ok(x), Ok(y), Ok(color)) => Ok(Request::SetPixel {
-            x,
-            y,
-            color: Color::from(color),
-        }),
+        (Some(x), Some(y), Some(color)) => match px.len() {
+            // eight digits carry an additional alpha byte
+            8 => match u32::from(color) as u8 {
+                0xFF => Ok(Request::SetPixel {
+                    x,
+                    y,
+                    color: Color::from(u32::from(color) >> 8),
+                }),
+                alpha => Ok(Request::BlendPixel {
+                    x,
+                    y,
+                    color: Color::from(u32::from(color) >> 8),
+                    alpha,
+                }),
+            },
+            _ => Ok(Request::SetPixel { x, y, color }),
+        },
         (_, _, _) => Err(ParseErr::UnknownCommand),
     }
 }
@@ -36,21 +164,47 @@ fn parse_px_set_args(x: &str, y: &str, px: &str) -> Result<Request, ParseErr> {
 /// Parse the arguments to a PxGet command
 #[inline(always)]
 fn parse_px_get_args(x: &str, y: &str) -> Result<Request, ParseErr> {
-    let xres = x.parse();
-    let yres = y.parse();
+    let xres = parse_dec(x);
+    let yres = parse_dec(y);
     match (xres, yres) {
-        (Ok(x), Ok(y)) => Ok(Request::GetPixel { x, y }),
+        (Some(x), Some(y)) => Ok(Request::GetPixel { x, y }),
         (_, _) => Err(ParseErr::UnknownCommand),
     }
 }
 
+/// Parse a PxGet command which addresses multiple pixels
+///
+/// The complete line is parsed again because the token buffer cannot hold an arbitrary number of coordinates.
+#[inline(always)]
+fn parse_px_batch_get(line: &str) -> Result<Request, ParseErr> {
+    let coordinates = line
+        .split_ascii_whitespace()
+        .skip(1)
+        .map(parse_dec)
+        .collect::<Option<Vec<usize>>>()
+        .ok_or(ParseErr::InvalidCommand)?;
+    if coordinates.len() % 2 != 0 {
+        return Err(ParseErr::InvalidCommand);
+    }
+    Ok(Request::GetPixels {
+        pixels: coordinates.chunks_exact(2).map(|c| (c[0], c[1])).collect(),
+    })
+}
+
 /// Parse the arguments to a Help command
 #[inline(always)]
 fn parse_help_args(token: &str) -> Result<Request, ParseErr> {
     match token {
         "help" | "HELP" | "general" | "GENERAL" => Ok(Request::Help(HelpTopic::General)),
         "size" | "SIZE" => Ok(Request::Help(HelpTopic::Size)),
-        "px" | "PX" => Ok(Request::Help(HelpTopic::Px)),
+        "px" | "PX" | "pxrect" | "PXRECT" => Ok(Request::Help(HelpTopic::Px)),
+        "state" | "STATE" => Ok(Request::Help(HelpTopic::State)),
+        "quiet" | "QUIET" => Ok(Request::Help(HelpTopic::Quiet)),
+        "auth" | "AUTH" => Ok(Request::Help(HelpTopic::Auth)),
+        "reserve" | "RESERVE" => Ok(Request::Help(HelpTopic::Reserve)),
+        "claim" | "CLAIM" | "claims" | "CLAIMS" => Ok(Request::Help(HelpTopic::Claim)),
+        "fill" | "FILL" | "clear" | "CLEAR" => Ok(Request::Help(HelpTopic::Fill)),
+        "stats" | "STATS" => Ok(Request::Help(HelpTopic::Stats)),
         _ => Err(ParseErr::InvalidCommand),
     }
 }
@@ -58,25 +212,258 @@ fn parse_help_args(token: &str) -> Result<Request, ParseErr> {
 /// Parse the data part of a PxData response
 #[inline(always)]
 fn parse_px_data(x: &str, y: &str, px: &str) -> Result<Response, ParseErr> {
-    let xres = x.parse();
-    let yres = y.parse();
-    let cres = u32::from_str_radix(px, 16);
+    let xres = parse_dec(x);
+    let yres = parse_dec(y);
+    let cres = parse_hex_color(px);
     match (xres, yres, cres) {
-        (Ok(x), Ok(y), Ok(color)) => Ok(Response::PxData {
+        (Some(x), Some(y), Some(color)) => Ok(Response::PxData { x, y, color }),
+        (_, _, _) => Err(ParseErr::UnknownCommand),
+    }
+}
+
+/// Parse the data part of a PxDataBatch response
+#[inline(always)]
+fn parse_px_batch_data(line: &str) -> Result<Response, ParseErr> {
+    let tokens = line.split_whitespace().skip(1).collect::<Vec<_>>();
+    if tokens.len() % 3 != 0 {
+        return Err(ParseErr::InvalidCommand);
+    }
+    let pixels = tokens
+        .chunks_exact(3)
+        .map(|pixel| match parse_px_data(pixel[0], pixel[1], pixel[2]) {
+            Ok(Response::PxData { x, y, color }) => Ok((x, y, color)),
+            _ => Err(ParseErr::InvalidCommand),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Response::PxDataBatch { pixels })
+}
+
+/// Parse the arguments to a StateRegion command
+#[inline(always)]
+fn parse_region_args(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    encoding: &str,
+) -> Result<Request, ParseErr> {
+    let encoding = parse_state_encoding(encoding)?;
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) => Ok(Request::GetRegion {
             x,
             y,
-            color: Color::from(color),
+            width,
+            height,
+            encoding,
         }),
-        (_, _, _) => Err(ParseErr::UnknownCommand),
+        (_, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a PxRect command
+#[inline(always)]
+fn parse_rect_args(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    encoding: Option<&str>,
+) -> Result<Request, ParseErr> {
+    let encoding = match encoding {
+        Some(encoding) => parse_state_encoding(encoding)?,
+        None => StateEncoding::Rle64,
+    };
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) => Ok(Request::GetRect {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+        }),
+        (_, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the color argument of a Fill command
+///
+/// Unlike with `PX`, the color cannot carry an alpha value.
+#[inline(always)]
+fn parse_fill_args(color: &str) -> Result<Request, ParseErr> {
+    match parse_hex_color(color) {
+        Some(color) if u32::from(color) <= 0xFFFFFF => Ok(Request::Fill(color)),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a Subscribe command which names a region
+#[inline(always)]
+fn parse_subscribe_region_args(x: &str, y: &str, width: &str, height: &str) -> Result<Request, ParseErr> {
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) => Ok(Request::SubscribeRegion { x, y, width, height }),
+        (_, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a Reserve command
+#[inline(always)]
+fn parse_reserve_args(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    seconds: &str,
+) -> Result<Request, ParseErr> {
+    match (
+        parse_dec(x),
+        parse_dec(y),
+        parse_dec(width),
+        parse_dec(height),
+        parse_dec(seconds),
+    ) {
+        (Some(x), Some(y), Some(width), Some(height), Some(seconds)) => Ok(Request::Reserve {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        }),
+        (_, _, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the data part of a Reserved response
+#[inline(always)]
+fn parse_reserved_data(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    seconds: &str,
+) -> Result<Response, ParseErr> {
+    match (
+        parse_dec(x),
+        parse_dec(y),
+        parse_dec(width),
+        parse_dec(height),
+        parse_dec(seconds),
+    ) {
+        (Some(x), Some(y), Some(width), Some(height), Some(seconds)) => Ok(Response::Reserved {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        }),
+        (_, _, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the arguments to a Claim command
+#[inline(always)]
+fn parse_claim_args(x: &str, y: &str, width: &str, height: &str, seconds: &str) -> Result<Request, ParseErr> {
+    match (
+        parse_dec(x),
+        parse_dec(y),
+        parse_dec(width),
+        parse_dec(height),
+        parse_dec(seconds),
+    ) {
+        (Some(x), Some(y), Some(width), Some(height), Some(seconds)) => Ok(Request::Claim {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        }),
+        (_, _, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the data part of a Stats response
+#[inline(always)]
+fn parse_stats_data(pixels: &str, total: &str, rank: Option<&str>) -> Result<Response, ParseErr> {
+    let rank = match rank.map(parse_dec) {
+        None => None,
+        Some(Some(rank)) => Some(rank),
+        Some(None) => return Err(ParseErr::InvalidCommand),
+    };
+    match (parse_dec(pixels), parse_dec(total)) {
+        (Some(pixels), Some(total)) => Ok(Response::Stats { pixels, total, rank }),
+        _ => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the data part of a Claimed response
+#[inline(always)]
+fn parse_claimed_data(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    seconds: &str,
+) -> Result<Response, ParseErr> {
+    match (
+        parse_dec(x),
+        parse_dec(y),
+        parse_dec(width),
+        parse_dec(height),
+        parse_dec(seconds),
+    ) {
+        (Some(x), Some(y), Some(width), Some(height), Some(seconds)) => Ok(Response::Claimed {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        }),
+        (_, _, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the data part of a Claims response
+///
+/// The complete line is parsed again because the token buffer cannot hold an arbitrary number of claims.
+#[inline(always)]
+fn parse_claims_data(line: &str) -> Result<Response, ParseErr> {
+    let tokens = line.split_whitespace().skip(1).collect::<Vec<_>>();
+    if tokens.len() % 5 != 0 {
+        return Err(ParseErr::InvalidCommand);
+    }
+    let claims = tokens
+        .chunks_exact(5)
+        .map(
+            |claim| match parse_claimed_data(claim[0], claim[1], claim[2], claim[3], claim[4]) {
+                Ok(Response::Claimed {
+                    x,
+                    y,
+                    width,
+                    height,
+                    seconds,
+                }) => Ok((x, y, width, height, seconds)),
+                _ => Err(ParseErr::InvalidCommand),
+            },
+        )
+        .collect::<Result<_, _>>()?;
+    Ok(Response::Claims { claims })
+}
+
+/// Parse the name of a state encoding
+#[inline(always)]
+fn parse_state_encoding(encoding: &str) -> Result<StateEncoding, ParseErr> {
+    match encoding {
+        "rgb64" | "RGB64" => Ok(StateEncoding::Rgb64),
+        "rle64" | "RLE64" => Ok(StateEncoding::Rle64),
+        _ => Err(ParseErr::InvalidCommand),
     }
 }
 
 #[inline(always)]
 fn parse_size_data(width: &str, height: &str) -> Result<Response, ParseErr> {
-    let width = width.parse();
-    let height = height.parse();
+    let width = parse_dec(width);
+    let height = parse_dec(height);
     match (width, height) {
-        (Ok(width), Ok(height)) => Ok(Response::Size { width, height }),
+        (Some(width), Some(height)) => Ok(Response::Size { width, height }),
         (_, _) => Err(ParseErr::InvalidCommand),
     }
 }
@@ -87,15 +474,89 @@ fn parse_help_data(topic: &str) -> Result<Response, ParseErr> {
         "help" | "HELP" | "general" | "GENERAL" => Ok(Response::Help(HelpTopic::General)),
         "size" | "SIZE" => Ok(Response::Help(HelpTopic::Size)),
         "px" | "PX" => Ok(Response::Help(HelpTopic::Px)),
+        "state" | "STATE" => Ok(Response::Help(HelpTopic::State)),
+        "quiet" | "QUIET" => Ok(Response::Help(HelpTopic::Quiet)),
+        "auth" | "AUTH" => Ok(Response::Help(HelpTopic::Auth)),
+        "reserve" | "RESERVE" => Ok(Response::Help(HelpTopic::Reserve)),
+        "claim" | "CLAIM" => Ok(Response::Help(HelpTopic::Claim)),
+        "fill" | "FILL" => Ok(Response::Help(HelpTopic::Fill)),
+        "stats" | "STATS" => Ok(Response::Help(HelpTopic::Stats)),
         _ => Err(ParseErr::InvalidCommand),
     }
 }
 
+/// Parse the data part of a StateRegion response
+#[inline(always)]
+fn parse_region_data(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    encoding: &str,
+    data: &str,
+) -> Result<Response, ParseErr> {
+    let encoding = parse_state_encoding(encoding)?;
+    let data = encoding.decode(data).ok_or(ParseErr::InvalidCommand)?;
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) if data.len() == width * height => {
+            Ok(Response::Region {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+                data,
+            })
+        }
+        (_, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the data part of a PxRect response
+#[inline(always)]
+fn parse_rect_data(
+    x: &str,
+    y: &str,
+    width: &str,
+    height: &str,
+    encoding: &str,
+    data: &str,
+) -> Result<Response, ParseErr> {
+    let encoding = parse_state_encoding(encoding)?;
+    let data = encoding.decode(data).ok_or(ParseErr::InvalidCommand)?;
+    match (parse_dec(x), parse_dec(y), parse_dec(width), parse_dec(height)) {
+        (Some(x), Some(y), Some(width), Some(height)) if data.len() == width * height => Ok(Response::Rect {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+            data,
+        }),
+        (_, _, _, _) => Err(ParseErr::InvalidCommand),
+    }
+}
+
+/// Parse the agent identifier and extension list of a HELLO line
+///
+/// Unknown extensions are skipped so that newer clients can still talk to older servers and vice versa.
+#[inline(always)]
+fn parse_hello(line: &str) -> Option<(String, Vec<Extension>)> {
+    let mut tokens = line.split_whitespace();
+    match tokens.next() {
+        Some("HELLO" | "hello") => {}
+        _ => return None,
+    }
+    let agent = tokens.next()?.to_string();
+    let extensions = tokens.filter_map(Extension::from_name).collect();
+    Some((agent, extensions))
+}
+
 /// A statically sized buffer containing input tokens.
 ///
 /// This is useful during parsing because it can be allocated on the stack instead of the heap as a Vec would.
 struct TokBuf<'s, const MAX_TOKS: usize> {
-    /// Storage for up to 4 input tokens
+    /// Storage for up to `MAX_TOKS` input tokens
     tokens: [Option<&'s str>; MAX_TOKS],
     /// How many tokens are actually present in the buffer
     len: usize,
@@ -128,28 +589,63 @@ impl<'s, const MAX_TOKS: usize> FromIterator<&'s str> for TokBuf<'s, MAX_TOKS> {
 }
 
 /// Try to parse a single pixelflut request
+///
+/// Tokens are separated by ascii whitespace and numbers are parsed without allocating, independent of any locale.
 #[inline(always)]
 pub fn parse_request_str(line: &str) -> Result<Request, ParseErr> {
-    let tokens: TokBuf<'_, 4> = line.split_whitespace().collect();
-    let tokens = tokens.tokens();
-    match tokens.len() {
-        4 => parse_px_set_args(tokens[1], tokens[2], tokens[3]),
-        3 => parse_px_get_args(tokens[1], tokens[2]),
-        2 => parse_help_args(tokens[1]),
-        1 => match tokens[0] {
-            "SIZE" | "size" => Ok(Request::GetSize),
-            "HELP" | "help" => Ok(Request::Help(HelpTopic::General)),
-            _ => Err(ParseErr::UnknownCommand),
+    let tokens: TokBuf<'_, 7> = line.split_ascii_whitespace().collect();
+    match tokens.tokens() {
+        ["PX" | "px", x, y, color] => parse_px_set_args(x, y, color),
+        ["PX" | "px", x, y] => parse_px_get_args(x, y),
+        ["PX" | "px", _, _, _, _, ..] => parse_px_batch_get(line),
+        ["SIZE" | "size"] => Ok(Request::GetSize),
+        ["HELP" | "help"] => Ok(Request::Help(HelpTopic::General)),
+        ["HELP" | "help", topic] => parse_help_args(topic),
+        ["STATE" | "state", "REGION" | "region", x, y, width, height, encoding] => {
+            parse_region_args(x, y, width, height, encoding)
+        }
+        ["PXRECT" | "pxrect", x, y, width, height] => parse_rect_args(x, y, width, height, None),
+        ["PXRECT" | "pxrect", x, y, width, height, encoding] => {
+            parse_rect_args(x, y, width, height, Some(encoding))
+        }
+        ["QUIET" | "quiet", "ON" | "on"] => Ok(Request::Quiet(true)),
+        ["QUIET" | "quiet", "OFF" | "off"] => Ok(Request::Quiet(false)),
+        ["SUBSCRIBE" | "subscribe"] => Ok(Request::Subscribe(true)),
+        ["SUBSCRIBE" | "subscribe", x, y, width, height] => parse_subscribe_region_args(x, y, width, height),
+        ["UNSUBSCRIBE" | "unsubscribe"] => Ok(Request::Subscribe(false)),
+        ["CANVAS" | "canvas", name] => Ok(Request::SelectCanvas(name.to_string())),
+        ["AUTH" | "auth", team, token] => Ok(Request::Auth {
+            team: team.to_string(),
+            token: token.to_string(),
+        }),
+        ["RESERVE" | "reserve", x, y, width, height, seconds] => {
+            parse_reserve_args(x, y, width, height, seconds)
+        }
+        ["CLAIM" | "claim", x, y, width, height, seconds] => parse_claim_args(x, y, width, height, seconds),
+        ["CLAIMS" | "claims"] => Ok(Request::GetClaims),
+        ["STATS" | "stats"] => Ok(Request::GetStats),
+        ["CLEAR" | "clear"] => Ok(Request::Clear),
+        ["FILL" | "fill", color] => parse_fill_args(color),
+        ["HELLO" | "hello", ..] => match parse_hello(line) {
+            Some((user_agent, extensions)) => Ok(Request::Hello {
+                user_agent,
+                extensions,
+            }),
+            None => Err(ParseErr::InvalidCommand),
         },
-        0 => Err(ParseErr::InvalidCommand),
-        _ => unreachable!(),
+        [] => Err(ParseErr::InvalidCommand),
+        _ => Err(ParseErr::UnknownCommand),
     }
 }
 
 /// Parse a single request from a byte slice
+///
+/// Besides text lines this also accepts binary `PB` commands.
 #[inline(always)]
 pub fn parse_request_bin(line: &[u8]) -> anyhow::Result<Request> {
-    if line.is_ascii() {
+    if line.starts_with(b"PB") {
+        parse_binary_px(line).ok_or_else(|| ParseErr::InvalidCommand.into())
+    } else if line.is_ascii() {
         // Safety: This is fine because the bytes are already checked to be ascii
         let str = unsafe { std::str::from_utf8_unchecked(line) };
         Ok(parse_request_str(str)?)
@@ -158,15 +654,49 @@ pub fn parse_request_bin(line: &[u8]) -> anyhow::Result<Request> {
     }
 }
 
+/// Parse a single request from a byte slice while enforcing the given whitespace [`Strictness`]
+#[inline(always)]
+pub fn parse_request_bin_with(line: &[u8], strictness: Strictness) -> anyhow::Result<Request> {
+    if strictness == Strictness::Strict && !line.starts_with(b"PB") && !is_strictly_spaced(line) {
+        return Err(ParseErr::MalformedWhitespace.into());
+    }
+    parse_request_bin(line)
+}
+
 /// Try to parse a single pixelflut response
 #[inline(always)]
 pub fn parse_response_str(line: &str) -> Result<Response, ParseErr> {
-    let tokens: TokBuf<'_, 4> = line.split_whitespace().collect();
-    let tokens = tokens.tokens();
-    match tokens.len() {
-        4 => parse_px_data(tokens[1], tokens[2], tokens[3]),
-        3 => parse_size_data(tokens[1], tokens[2]),
-        2 => parse_help_data(tokens[1]),
+    let tokens: TokBuf<'_, 8> = line.split_whitespace().collect();
+    match tokens.tokens() {
+        ["PX" | "px", x, y, color] => parse_px_data(x, y, color),
+        ["PX" | "px", _, _, _, _, _, _, ..] => parse_px_batch_data(line),
+        ["SIZE" | "size", width, height] => parse_size_data(width, height),
+        ["HELP" | "help", topic] => parse_help_data(topic),
+        ["STATE" | "state", "REGION" | "region", x, y, width, height, encoding, data] => {
+            parse_region_data(x, y, width, height, encoding, data)
+        }
+        ["PXRECT" | "pxrect", x, y, width, height, encoding, data] => {
+            parse_rect_data(x, y, width, height, encoding, data)
+        }
+        ["AUTH" | "auth", team] => Ok(Response::Authenticated {
+            team: team.to_string(),
+        }),
+        ["RESERVED" | "reserved", x, y, width, height, seconds] => {
+            parse_reserved_data(x, y, width, height, seconds)
+        }
+        ["CLAIMED" | "claimed", x, y, width, height, seconds] => {
+            parse_claimed_data(x, y, width, height, seconds)
+        }
+        ["CLAIMS" | "claims", ..] => parse_claims_data(line),
+        ["STATS" | "stats", pixels, total] => parse_stats_data(pixels, total, None),
+        ["STATS" | "stats", pixels, total, rank] => parse_stats_data(pixels, total, Some(rank)),
+        ["HELLO" | "hello", ..] => match parse_hello(line) {
+            Some((server_agent, extensions)) => Ok(Response::Hello {
+                server_agent,
+                extensions,
+            }),
+            None => Err(ParseErr::InvalidCommand),
+        },
         _ => Err(ParseErr::UnknownCommand),
     }
 }
@@ -187,16 +717,18 @@ pub fn parse_response_bin(line: &[u8]) -> anyhow::Result<Response> {
 mod test {
     use super::*;
     use ::test::Bencher;
+    use bytes::BytesMut;
     use std::hint::black_box;
 
     #[test]
     fn test_parse_commands() {
         fn run_test(line: &str, res: Request) {
             let req = parse_request_str(line);
-            assert_eq!(req, Ok(res), "{:06x?} != Ok({:06x?})", req, res);
+            assert_eq!(req, Ok(res.clone()), "{:06x?} != Ok({:06x?})", req, res);
         }
 
         run_test("HELP", Request::Help(HelpTopic::General));
+        run_test("HELP state", Request::Help(HelpTopic::State));
         run_test("SIZE", Request::GetSize);
         run_test(
             "PX 42 128 AABBCC",
@@ -214,6 +746,359 @@ mod test {
                 color: Color::from((0xAA, 0xBB, 0xCC)),
             },
         );
+        run_test(
+            "PX 1 2 AABBCC80",
+            Request::BlendPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+                alpha: 0x80,
+            },
+        );
+        run_test(
+            "PX 1 2 AABBCCFF",
+            Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from((0xAA, 0xBB, 0xCC)),
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_region_request() {
+        assert_eq!(
+            parse_request_str("STATE REGION 10 20 30 40 rgb64"),
+            Ok(Request::GetRegion {
+                x: 10,
+                y: 20,
+                width: 30,
+                height: 40,
+                encoding: StateEncoding::Rgb64,
+            })
+        );
+        assert_eq!(
+            parse_request_str("STATE REGION 10 20 30 40 foo"),
+            Err(ParseErr::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_parse_rect_request() {
+        assert_eq!(
+            parse_request_str("PXRECT 1 2 3 4"),
+            Ok(Request::GetRect {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+                encoding: StateEncoding::Rle64,
+            })
+        );
+        assert_eq!(
+            parse_request_str("pxrect 1 2 3 4 rgb64"),
+            Ok(Request::GetRect {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+                encoding: StateEncoding::Rgb64,
+            })
+        );
+        assert_eq!(parse_request_str("PXRECT 1 2 3"), Err(ParseErr::UnknownCommand));
+    }
+
+    #[test]
+    fn test_parse_claims_response() {
+        assert_eq!(
+            parse_response_str("CLAIMS 1 2 3 4 5 10 20 30 40 50"),
+            Ok(Response::Claims {
+                claims: vec![(1, 2, 3, 4, 5), (10, 20, 30, 40, 50)],
+            })
+        );
+        assert_eq!(
+            parse_response_str("CLAIMS"),
+            Ok(Response::Claims { claims: Vec::new() })
+        );
+        assert_eq!(
+            parse_response_str("CLAIMS 1 2 3 4"),
+            Err(ParseErr::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_parse_stats_response() {
+        assert_eq!(
+            parse_response_str("STATS 1200 50000 3"),
+            Ok(Response::Stats {
+                pixels: 1200,
+                total: 50000,
+                rank: Some(3),
+            })
+        );
+        assert_eq!(
+            parse_response_str("STATS 0 50000"),
+            Ok(Response::Stats {
+                pixels: 0,
+                total: 50000,
+                rank: None,
+            })
+        );
+        assert_eq!(
+            parse_response_str("STATS 0 50000 first"),
+            Err(ParseErr::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn test_rle64() {
+        let mut data = vec![Color::from(0xFF0000); 300];
+        data.push(Color::from(0x00FF00));
+        data.extend([Color::from(0x0000FF); 1000]);
+        let encoded = StateEncoding::Rle64.encode(&data);
+        // 2 records for the first run, 1 for the second and 4 for the last one
+        assert_eq!(encoded.len(), (7 * 4usize).div_ceil(3) * 4);
+        assert_eq!(StateEncoding::Rle64.decode(&encoded), Some(data));
+        assert_eq!(StateEncoding::Rle64.encode(&[]), "");
+
+        // every pixel is its own run so the records span multiple encoding chunks
+        let data = (0..1000).map(|i| Color::from(i % 2)).collect::<Vec<_>>();
+        let encoded = StateEncoding::Rle64.encode(&data);
+        assert_eq!(StateEncoding::Rle64.decode(&encoded), Some(data));
+
+        // records with a length of 0 are invalid
+        assert_eq!(StateEncoding::Rle64.decode("AP8AAA=="), None);
+        assert_eq!(StateEncoding::Rle64.decode("Af8A"), None);
+    }
+
+    #[test]
+    fn test_parse_px_batch() {
+        assert_eq!(
+            parse_request_str("PX 1 2 3 4 5 6"),
+            Ok(Request::GetPixels {
+                pixels: vec![(1, 2), (3, 4), (5, 6)],
+            })
+        );
+        assert_eq!(parse_request_str("PX 1 2 3 4 5"), Err(ParseErr::InvalidCommand));
+        assert_eq!(
+            parse_request_str("PX 1 2 3 AABBCC"),
+            Err(ParseErr::InvalidCommand)
+        );
+        assert_eq!(
+            parse_response_str("PX 1 2 AABBCC 3 4 000000"),
+            Ok(Response::PxDataBatch {
+                pixels: vec![(1, 2, Color::from(0xAABBCC)), (3, 4, Color::from(0x000000))],
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_hello() {
+        assert_eq!(
+            parse_request_str("HELLO my-bot/1.0 state-region some-future-extension"),
+            Ok(Request::Hello {
+                user_agent: "my-bot/1.0".to_string(),
+                extensions: vec![Extension::StateRegion],
+            })
+        );
+        assert_eq!(parse_request_str("HELLO"), Err(ParseErr::InvalidCommand));
+
+        let response = Response::Hello {
+            server_agent: "pixeldike/0.1.0".to_string(),
+            extensions: vec![Extension::StateRegion],
+        };
+        assert_eq!(parse_response_str(&response.to_string()), Ok(response));
+    }
+
+    #[test]
+    fn test_region_response_round_trip() {
+        let response = Response::Region {
+            x: 1,
+            y: 2,
+            width: 2,
+            height: 2,
+            encoding: StateEncoding::Rgb64,
+            data: vec![
+                Color::from(0xAABBCC),
+                Color::from(0x000000),
+                Color::from(0xFFFFFF),
+                Color::from(0x123456),
+            ],
+        };
+        let line = response.to_string();
+        assert_eq!(parse_response_str(&line), Ok(response));
+
+        // data length must match the advertised region size
+        assert_eq!(
+            parse_response_str("STATE REGION 0 0 2 2 rgb64 qrvM"),
+            Err(ParseErr::InvalidCommand)
+        );
+
+        // regions larger than one encoding chunk must be encoded seamlessly
+        let data = (0..1000)
+            .map(|i| Color::from((i * 0x010203) & 0xFFFFFF))
+            .collect::<Vec<_>>();
+        let response = Response::Region {
+            x: 0,
+            y: 0,
+            width: 40,
+            height: 25,
+            encoding: StateEncoding::Rgb64,
+            data,
+        };
+        let mut buf = BytesMut::new();
+        response.encode(&mut buf);
+        assert_eq!(parse_response_bin(&buf).unwrap(), response);
+    }
+
+    #[test]
+    fn test_lenient_whitespace() {
+        let expected = Request::SetPixel {
+            x: 1,
+            y: 2,
+            color: Color::from(0xAABBCC),
+        };
+        for line in [
+            "PX 1 2 AABBCC\n",
+            "PX 1 2 AABBCC\r\n",
+            "PX  1   2 AABBCC\n",
+            "PX\t1\t2\tAABBCC\n",
+            "  PX 1 2 AABBCC\n",
+            "PX 1 2 AABBCC  \r\n",
+        ] {
+            assert_eq!(
+                parse_request_bin_with(line.as_bytes(), Strictness::Lenient).unwrap(),
+                expected,
+                "{:?} was not accepted",
+                line
+            );
+        }
+        assert_eq!(parse_request_str("SIZE\r\n"), Ok(Request::GetSize));
+        assert_eq!(parse_request_str(" \r\n"), Err(ParseErr::InvalidCommand));
+    }
+
+    #[test]
+    fn test_strict_whitespace() {
+        assert_eq!(
+            parse_request_bin_with(b"PX 1 2 AABBCC\n", Strictness::Strict).unwrap(),
+            Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from(0xAABBCC),
+            }
+        );
+        assert!(parse_request_bin_with(b"SIZE", Strictness::Strict).is_ok());
+        for line in [
+            "PX 1 2 AABBCC\r\n",
+            "PX  1 2 AABBCC\n",
+            "PX\t1 2 AABBCC\n",
+            " PX 1 2 AABBCC\n",
+            "PX 1 2 AABBCC \n",
+            "\n",
+        ] {
+            assert_eq!(
+                parse_request_bin_with(line.as_bytes(), Strictness::Strict)
+                    .unwrap_err()
+                    .downcast::<ParseErr>()
+                    .unwrap(),
+                ParseErr::MalformedWhitespace,
+                "{:?} was accepted",
+                line
+            );
+        }
+    }
+
+    quickcheck! {
+        fn test_request_round_trip(request: Request) -> bool {
+            let mut buf = Vec::new();
+            request.write(&mut buf).unwrap();
+            parse_request_bin(&buf).ok() == Some(request)
+        }
+
+        fn test_request_display_round_trip(request: Request) -> bool {
+            parse_request_str(&request.to_string()) == Ok(request)
+        }
+
+        fn test_response_round_trip(response: Response) -> bool {
+            // help responses span multiple lines of which only the first one identifies the response
+            let mut buf = Vec::new();
+            response.write(&mut buf).unwrap();
+            let first_line = buf.split_inclusive(|b| *b == b'\n').next().unwrap();
+            parse_response_bin(first_line).ok() == Some(response)
+        }
+
+        fn test_response_encode_matches_write(response: Response) -> bool {
+            let mut written = Vec::new();
+            response.write(&mut written).unwrap();
+            let mut encoded = BytesMut::new();
+            response.encode(&mut encoded);
+            written == encoded
+        }
+
+        fn test_parse_dec_matches_std(n: u64) -> bool {
+            parse_dec::<u64>(&n.to_string()) == Some(n) && parse_dec::<usize>(&n.to_string()) == n.to_string().parse().ok()
+        }
+
+        fn test_parse_hex_color_matches_std(n: u32) -> bool {
+            parse_hex_color(&format!("{:x}", n)) == Some(Color::from(n))
+                && parse_hex_color(&format!("{:08X}", n)) == Some(Color::from(n))
+        }
+    }
+
+    #[test]
+    fn test_number_edge_cases() {
+        for token in [
+            "",
+            "+",
+            "-1",
+            "+17",
+            "0017",
+            "1_000",
+            " 1",
+            "1 ",
+            "٣",
+            "18446744073709551615",
+            "18446744073709551616",
+            "99999999999999999999",
+        ] {
+            assert_eq!(parse_dec::<u64>(token), token.parse().ok(), "{:?}", token);
+            assert_eq!(parse_dec::<u16>(token), token.parse().ok(), "{:?}", token);
+        }
+        for token in [
+            "",
+            "+",
+            "-1",
+            "+ff",
+            "aBcDeF",
+            "ac196",
+            "ffffffff",
+            "100000000",
+            "000000000ff",
+            "fg",
+            "0x10",
+        ] {
+            assert_eq!(
+                parse_hex_color(token),
+                u32::from_str_radix(token, 16).ok().map(Color::from),
+                "{:?}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_corpus() {
+        // lines as they are sent by commonly used third-party clients
+        for line in include_str!("test_corpus.txt").lines() {
+            let request =
+                parse_request_str(line).unwrap_or_else(|e| panic!("{:?} failed to parse: {}", line, e));
+            assert_eq!(
+                parse_request_str(&request.to_string()),
+                Ok(request.clone()),
+                "{:?} does not survive re-serialization",
+                line
+            );
+        }
     }
 
     #[bench]
@@ -228,6 +1113,17 @@ mod test {
         b.iter(move || parse_request_str(black_box(cmd)).unwrap());
     }
 
+    #[bench]
+    fn bench_parse_numbers(b: &mut Bencher) {
+        let tokens = ["0", "17", "7632", "65535", "12FBA5", "ac196", "00ff00ff"];
+        b.iter(move || {
+            for token in black_box(tokens) {
+                black_box(parse_dec::<usize>(token));
+                black_box(parse_hex_color(token));
+            }
+        });
+    }
+
     #[bench]
     fn bench_parse_size(b: &mut Bencher) {
         let cmd = "SIZE";
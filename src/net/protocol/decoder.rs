@@ -0,0 +1,179 @@
+//! A transport independent decoder which splits received bytes into requests
+//!
+//! The decoder does not perform any I/O itself. Transports append whatever they receive to a buffer and then take
+//! [`DecoderEvent`]s out of it until no complete request is left, which makes the framing rules testable without any
+//! sockets and keeps them identical for all transports.
+
+use crate::net::protocol::{request_frame_len, split_channel, MAX_BATCH_PIXELS};
+use bytes::{Buf, BytesMut};
+
+/// How many digits the coordinates in a request have at most, enough for every `u32`
+const MAX_COORDINATE_DIGITS: usize = 10;
+
+/// How long the channel prefix of a multiplexed line (`@<u32> `) is at most
+const MAX_CHANNEL_PREFIX_LEN: usize = 12;
+
+/// How long incomplete data may become before it is discarded if the decoder is not configured otherwise
+///
+/// This is the length of the longest valid request, a `PX` batch read of [`MAX_BATCH_PIXELS`] pixels on a
+/// multiplexed channel, so clients which send more without a newline are misbehaving.
+pub const DEFAULT_MAX_LINE_LEN: usize =
+    MAX_CHANNEL_PREFIX_LEN + "PX".len() + MAX_BATCH_PIXELS * 2 * (1 + MAX_COORDINATE_DIGITS) + "\n".len();
+
+/// Something that a [`RequestDecoder`] found in the received data
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecoderEvent {
+    /// A complete request which should be handled
+    Request {
+        /// The channel on which the request was sent if multiplexing is enabled and the line carried a prefix
+        channel: Option<u32>,
+        /// The request itself, either a text line including its newline or a binary `PB` command
+        frame: BytesMut,
+    },
+    /// The buffer contained more than the maximum line length without a complete request and was cleared
+    LineTooLong {
+        /// How many bytes were discarded
+        discarded: usize,
+    },
+}
+
+/// The framing rules with which requests are split out of a byte stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RequestDecoder {
+    multiplexing: bool,
+    max_line_len: usize,
+}
+
+impl Default for RequestDecoder {
+    fn default() -> Self {
+        Self {
+            multiplexing: false,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+        }
+    }
+}
+
+impl RequestDecoder {
+    /// Create a decoder which accepts plain requests of at most [`DEFAULT_MAX_LINE_LEN`] bytes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept lines which are prefixed with a channel id (see [`split_channel`])
+    pub fn with_multiplexing(mut self, multiplexing: bool) -> Self {
+        self.multiplexing = multiplexing;
+        self
+    }
+
+    /// Discard incomplete data once it becomes longer than `max_line_len`
+    pub fn with_max_line_len(mut self, max_line_len: usize) -> Self {
+        self.max_line_len = max_line_len;
+        self
+    }
+
+    /// Take the next event out of `buf`
+    ///
+    /// Returns `None` once `buf` contains no complete request anymore, in which case the remaining data is kept until
+    /// more of it has been received.
+    #[inline(always)]
+    pub fn decode(&self, buf: &mut BytesMut) -> Option<DecoderEvent> {
+        let Some(len) = request_frame_len(buf) else {
+            return match buf.len() > self.max_line_len {
+                true => {
+                    let discarded = buf.len();
+                    buf.clear();
+                    Some(DecoderEvent::LineTooLong { discarded })
+                }
+                false => None,
+            };
+        };
+        let mut frame = buf.split_to(len);
+        let channel = match self.multiplexing {
+            true => {
+                let (channel, line) = split_channel(&frame);
+                let prefix_len = frame.len() - line.len();
+                frame.advance(prefix_len);
+                channel
+            }
+            false => None,
+        };
+        Some(DecoderEvent::Request { channel, frame })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(channel: Option<u32>, frame: &[u8]) -> Option<DecoderEvent> {
+        Some(DecoderEvent::Request {
+            channel,
+            frame: BytesMut::from(frame),
+        })
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let decoder = RequestDecoder::new();
+        let mut buf = BytesMut::from(&b"PX 1 2 ff0000\nPB\x01\x00\x02\x00\n\xcd\xef\xffSIZE"[..]);
+        assert_eq!(decoder.decode(&mut buf), request(None, b"PX 1 2 ff0000\n"));
+        // binary commands may contain newline bytes which must not split them
+        assert_eq!(
+            decoder.decode(&mut buf),
+            request(None, b"PB\x01\x00\x02\x00\n\xcd\xef\xff")
+        );
+        assert_eq!(decoder.decode(&mut buf), None);
+
+        // the incomplete request is completed by data which arrives later
+        buf.extend_from_slice(b"\n");
+        assert_eq!(decoder.decode(&mut buf), request(None, b"SIZE\n"));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_multiplexed() {
+        let decoder = RequestDecoder::new().with_multiplexing(true);
+        let mut buf = BytesMut::from(&b"@7 PX 1 2\nHELP\n"[..]);
+        assert_eq!(decoder.decode(&mut buf), request(Some(7), b"PX 1 2\n"));
+        assert_eq!(decoder.decode(&mut buf), request(None, b"HELP\n"));
+
+        let mut buf = BytesMut::from(&b"@7 PX 1 2\n"[..]);
+        assert_eq!(
+            RequestDecoder::new().decode(&mut buf),
+            request(None, b"@7 PX 1 2\n")
+        );
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let decoder = RequestDecoder::new().with_max_line_len(8);
+        let mut buf = BytesMut::from(&b"PX 1 2 ff0000"[..]);
+        assert_eq!(
+            decoder.decode(&mut buf),
+            Some(DecoderEvent::LineTooLong { discarded: 13 })
+        );
+        assert!(buf.is_empty());
+        assert_eq!(decoder.decode(&mut buf), None);
+    }
+
+    #[test]
+    fn test_long_batch_in_chunks() {
+        let mut line = String::from("@4294967295 PX");
+        for _ in 0..MAX_BATCH_PIXELS {
+            line.push_str(" 4294967295 4294967295");
+        }
+        line.push('\n');
+        assert_eq!(line.len(), DEFAULT_MAX_LINE_LEN);
+
+        let decoder = RequestDecoder::new().with_multiplexing(true);
+        let (first, second) = line.as_bytes().split_at(80);
+        let mut buf = BytesMut::from(first);
+        assert_eq!(decoder.decode(&mut buf), None);
+        buf.extend_from_slice(second);
+        assert_eq!(
+            decoder.decode(&mut buf),
+            request(Some(u32::MAX), &line.as_bytes()[12..])
+        );
+        assert!(buf.is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 //! Data types that describe all protocol interactions as safe-to-use structs
 
+use crate::net::protocol::binary::write_binary_px;
 use crate::pixmap::Color;
 use crate::texts;
-use std::fmt::{Display, Formatter};
+use base64::prelude::*;
+use bytes::BytesMut;
+use std::fmt::{Display, Formatter, Write as _};
 use std::io::Write;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+#[cfg(test)]
+use quickcheck::{Arbitrary, Gen};
+
 /// The help topics that can be requested from the server
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum HelpTopic {
@@ -15,22 +21,302 @@ pub enum HelpTopic {
     Size,
     /// Help about the *PX* command (both set and get variants)
     Px,
+    /// Help about the *STATE* command for downloading regions of the canvas
+    State,
+    /// Help about the *QUIET* command
+    Quiet,
+    /// Help about the *AUTH* command
+    Auth,
+    /// Help about the *RESERVE* command
+    Reserve,
+    /// Help about the *CLAIM* and *CLAIMS* commands
+    Claim,
+    /// Help about the *CLEAR* and *FILL* commands
+    Fill,
+    /// Help about the *STATS* command
+    Stats,
 }
 
-/// A request to a pixelflut server
+/// The maximum number of pixels that can be transferred with a single [`Request::GetRegion`]
+///
+/// Clients that want to download larger parts of the canvas need to split them into multiple regions which
+/// are requested one after another.
+pub const MAX_REGION_PIXELS: usize = 128 * 128;
+
+/// The maximum number of pixels that can be read with a single [`Request::GetPixels`]
+pub const MAX_BATCH_PIXELS: usize = 1024;
+
+/// The encodings in which canvas state can be transferred
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StateEncoding {
+    /// Pixels encoded as packed 3-byte RGB values which are then encoded as base64
+    Rgb64,
+    /// Runs of equally colored pixels encoded as 4-byte records which are then encoded as base64
+    ///
+    /// Every record consists of the number of pixels in the run (1 - 255) followed by their RGB value.
+    /// This is much shorter than [`StateEncoding::Rgb64`] for canvases with large areas of the same color.
+    Rle64,
+}
+
+impl StateEncoding {
+    /// Encode the given pixel data into its textual representation
+    pub fn encode(&self, data: &[Color]) -> String {
+        let mut result = String::new();
+        // writing into a String never fails
+        let _ = self.encode_into(data, &mut result);
+        result
+    }
+
+    /// Encode pixel data into its textual representation and write it into `f` without allocating
+    pub fn encode_into(&self, data: &[Color], f: &mut impl std::fmt::Write) -> std::fmt::Result {
+        match self {
+            StateEncoding::Rgb64 => {
+                // chunks of a multiple of 3 bytes encode to base64 without padding so they can simply be concatenated
+                const CHUNK_PIXELS: usize = 256;
+                let mut bytes = [0u8; CHUNK_PIXELS * 3];
+                let mut encoded = [0u8; CHUNK_PIXELS * 4];
+                for chunk in data.chunks(CHUNK_PIXELS) {
+                    for (i, color) in chunk.iter().enumerate() {
+                        let rgb: [u8; 3] = (*color).into();
+                        bytes[i * 3..i * 3 + 3].copy_from_slice(&rgb);
+                    }
+                    let len = BASE64_STANDARD
+                        .encode_slice(&bytes[..chunk.len() * 3], &mut encoded)
+                        .expect("base64 output buffer is large enough");
+                    // Safety: base64 output is always ascii
+                    f.write_str(unsafe { std::str::from_utf8_unchecked(&encoded[..len]) })?;
+                }
+                Ok(())
+            }
+            StateEncoding::Rle64 => {
+                // like above, chunks of a multiple of 3 records encode to base64 without padding
+                const CHUNK_RECORDS: usize = 192;
+                let mut records = [0u8; CHUNK_RECORDS * 4];
+                let mut encoded = [0u8; CHUNK_RECORDS * 4 / 3 * 4];
+                let mut len = 0;
+                let mut flush = |records: &[u8], f: &mut dyn std::fmt::Write| {
+                    let len = BASE64_STANDARD
+                        .encode_slice(records, &mut encoded)
+                        .expect("base64 output buffer is large enough");
+                    // Safety: base64 output is always ascii
+                    f.write_str(unsafe { std::str::from_utf8_unchecked(&encoded[..len]) })
+                };
+                let mut remaining = data;
+                while let Some(color) = remaining.first() {
+                    let run = remaining
+                        .iter()
+                        .take(u8::MAX as usize)
+                        .take_while(|i| *i == color)
+                        .count();
+                    let rgb: [u8; 3] = (*color).into();
+                    records[len] = run as u8;
+                    records[len + 1..len + 4].copy_from_slice(&rgb);
+                    len += 4;
+                    if len == records.len() {
+                        flush(&records, f)?;
+                        len = 0;
+                    }
+                    remaining = &remaining[run..];
+                }
+                flush(&records[..len], f)
+            }
+        }
+    }
+
+    /// Decode pixel data from its textual representation
+    ///
+    /// Returns `None` if the given string is not validly encoded.
+    pub fn decode(&self, data: &str) -> Option<Vec<Color>> {
+        match self {
+            StateEncoding::Rgb64 => {
+                let bytes = BASE64_STANDARD.decode(data).ok()?;
+                if bytes.len() % 3 != 0 {
+                    return None;
+                }
+                Some(
+                    bytes
+                        .chunks_exact(3)
+                        .map(|c| Color::from([c[0], c[1], c[2]]))
+                        .collect(),
+                )
+            }
+            StateEncoding::Rle64 => {
+                let bytes = BASE64_STANDARD.decode(data).ok()?;
+                if bytes.len() % 4 != 0 {
+                    return None;
+                }
+                let mut colors = Vec::new();
+                for record in bytes.chunks_exact(4) {
+                    if record[0] == 0 {
+                        return None;
+                    }
+                    let color = Color::from([record[1], record[2], record[3]]);
+                    colors.extend(std::iter::repeat_n(color, record[0] as usize));
+                }
+                Some(colors)
+            }
+        }
+    }
+}
+
+impl Display for StateEncoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateEncoding::Rgb64 => f.write_str("rgb64"),
+            StateEncoding::Rle64 => f.write_str("rle64"),
+        }
+    }
+}
+
+/// Optional protocol extensions which can be negotiated via a [`Request::Hello`] handshake
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Extension {
+    /// Downloading rectangular canvas regions via `STATE REGION`
+    StateRegion,
+    /// Reading multiple pixels at once via `PX <x1> <y1> <x2> <y2> ...`
+    PxBatch,
+    /// Setting pixels via the binary `PB` command (see [`crate::net::protocol::BINARY_PX_LEN`])
+    BinaryPx,
+    /// Receiving pixel changes via `SUBSCRIBE`
+    Subscribe,
+    /// Selecting one of multiple canvases via `CANVAS`
+    Canvas,
+    /// Overwriting the whole canvas via `CLEAR` and `FILL`
+    ///
+    /// Servers only support this if their operator enabled it.
+    Fill,
+    /// Reading rectangular blocks of pixels via `PXRECT`
+    PxRect,
+    /// Announcing and looking up advisory claims of canvas regions via `CLAIM` and `CLAIMS`
+    Claims,
+    /// Looking up how many pixels the client has written via `STATS`
+    Stats,
+}
+
+impl Extension {
+    /// Parse the wire name of an extension
+    ///
+    /// Returns `None` if the extension is not known.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "state-region" => Some(Extension::StateRegion),
+            "px-batch" => Some(Extension::PxBatch),
+            "binary-px" => Some(Extension::BinaryPx),
+            "subscribe" => Some(Extension::Subscribe),
+            "canvas" => Some(Extension::Canvas),
+            "fill" => Some(Extension::Fill),
+            "px-rect" => Some(Extension::PxRect),
+            "claims" => Some(Extension::Claims),
+            "stats" => Some(Extension::Stats),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Extension {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Extension::StateRegion => f.write_str("state-region"),
+            Extension::PxBatch => f.write_str("px-batch"),
+            Extension::BinaryPx => f.write_str("binary-px"),
+            Extension::Subscribe => f.write_str("subscribe"),
+            Extension::Canvas => f.write_str("canvas"),
+            Extension::Fill => f.write_str("fill"),
+            Extension::PxRect => f.write_str("px-rect"),
+            Extension::Claims => f.write_str("claims"),
+            Extension::Stats => f.write_str("stats"),
+        }
+    }
+}
+
+/// Write a `HELLO` line which consists of an agent identifier followed by a list of extensions
+fn fmt_hello(agent: &str, extensions: &[Extension]) -> String {
+    let mut line = format!("HELLO {agent}");
+    for extension in extensions {
+        line.push(' ');
+        line.push_str(&extension.to_string());
+    }
+    line
+}
+
+/// The wire representation of a boolean switch
+fn fmt_on_off(value: bool) -> &'static str {
+    match value {
+        true => "on",
+        false => "off",
+    }
+}
+
+/// Write the command which starts or stops a subscription
+fn fmt_subscribe(subscribe: bool) -> &'static str {
+    match subscribe {
+        true => "SUBSCRIBE",
+        false => "UNSUBSCRIBE",
+    }
+}
+
+/// Write a `PX` line which lists the coordinates of multiple pixels
+fn fmt_px_batch_get(pixels: &[(usize, usize)]) -> String {
+    let mut line = "PX".to_string();
+    for (x, y) in pixels {
+        line.push_str(&format!(" {x} {y}"));
+    }
+    line
+}
+
+/// Write a `PX` line which lists the coordinates and colors of multiple pixels
+fn fmt_px_batch_data(pixels: &[(usize, usize, Color)], f: &mut impl std::fmt::Write) -> std::fmt::Result {
+    f.write_str("PX")?;
+    for (x, y, color) in pixels {
+        f.write_fmt(format_args!(" {x} {y} {color:X}"))?;
+    }
+    Ok(())
+}
+
+/// Write a `CLAIMS` line which lists the position, size and remaining seconds of multiple claims
+fn fmt_claims(
+    claims: &[(usize, usize, usize, usize, u64)],
+    f: &mut impl std::fmt::Write,
+) -> std::fmt::Result {
+    f.write_str("CLAIMS")?;
+    for (x, y, width, height, seconds) in claims {
+        f.write_fmt(format_args!(" {x} {y} {width} {height} {seconds}"))?;
+    }
+    Ok(())
+}
+
+/// A request to a pixelflut server
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Request {
+    /// Introduce the client to the server and negotiate which protocol extensions should be used
+    ///
+    /// Sending this is optional and clients that never do so can use all basic commands as usual.
+    Hello {
+        /// A string identifying the client software e.g. `pixeldike/0.1.0`
+        user_agent: String,
+        /// The extensions which the client would like to use
+        extensions: Vec<Extension>,
+    },
     /// Request help about a specific topic
     Help(HelpTopic),
     /// Get the size of the canvas
     GetSize,
     /// Get the color of one pixel from the server
+    ///
+    /// The color always reflects all writes which were sent earlier over the same connection.
     GetPixel {
         /// The x coordinate of the pixel
         x: usize,
         /// The y coordinate of the pixel
         y: usize,
     },
+    /// Get the colors of multiple pixels with a single request
+    ///
+    /// This must contain at least two pixels because a single one is requested via [`Request::GetPixel`].
+    GetPixels {
+        /// The x and y coordinates of the pixels
+        pixels: Vec<(usize, usize)>,
+    },
     /// Set the color of one pixel
     SetPixel {
         /// The x coordinate of the pixel
@@ -40,40 +326,339 @@ pub enum Request {
         /// The color to which the pixel should be set
         color: Color,
     },
+    /// Blend a color onto the current color of one pixel
+    ///
+    /// On the wire this is a `PX` command whose color has an additional alpha byte, e.g. `PX 1 2 FF000080`.
+    /// A color with full opacity is always sent as [`Request::SetPixel`] instead.
+    BlendPixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The color which is blended onto the pixel
+        color: Color,
+        /// The opacity of `color` from 0 (fully transparent) to 255 (opaque)
+        alpha: u8,
+    },
+    /// Get the color data of a rectangular region of the canvas
+    GetRegion {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+        /// The encoding in which the server should send the pixel data
+        encoding: StateEncoding,
+    },
+    /// Get the color data of a rectangular block of pixels
+    ///
+    /// This is the same as [`Request::GetRegion`] but is answered with a `PXRECT` line so that clients which mirror
+    /// the canvas with `PX` reads can fetch whole blocks instead.
+    /// On the wire, the encoding may be omitted in which case [`StateEncoding::Rle64`] is used.
+    GetRect {
+        /// The x coordinate of the blocks top-left corner
+        x: usize,
+        /// The y coordinate of the blocks top-left corner
+        y: usize,
+        /// The width of the block
+        width: usize,
+        /// The height of the block
+        height: usize,
+        /// The encoding in which the server should send the pixel data
+        encoding: StateEncoding,
+    },
+    /// Enable or disable quiet mode for the connection
+    ///
+    /// In quiet mode, the server does not send errors or acknowledgements so that clients which never read from
+    /// the connection don't waste bandwidth.
+    /// Responses that carry requested data are still sent.
+    Quiet(bool),
+    /// Start or stop receiving pixel changes on the connection
+    ///
+    /// After `SUBSCRIBE`, the server sends the current canvas as `STATE` regions and then a `PX` response for every
+    /// pixel that is changed by anyone.
+    /// `UNSUBSCRIBE` stops this again.
+    Subscribe(bool),
+    /// Start receiving the changes of a rectangular region of the canvas on the connection
+    ///
+    /// This works like `SUBSCRIBE` except that the initial `STATE` regions only cover the rectangle and that
+    /// changes outside of it are not sent.
+    /// Subscribing again replaces the previous rectangle and `UNSUBSCRIBE` stops the subscription.
+    SubscribeRegion {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+    },
+    /// Select the canvas on which all following requests of the connection operate
+    ///
+    /// The main canvas of the server is called `default`.
+    SelectCanvas(String),
+    /// Authenticate the connection as a member of a team
+    Auth {
+        /// The name of the team
+        team: String,
+        /// The teams access token
+        token: String,
+    },
+    /// Reserve a rectangle of the canvas for the authenticated team so that only it may draw there
+    Reserve {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+        /// For how many seconds the rectangle should be reserved
+        seconds: u64,
+    },
+    /// Announce that the client is drawing on a rectangle of the canvas
+    ///
+    /// Claims are purely advisory and don't keep anybody from drawing on the rectangle.
+    /// They allow cooperative clients to look up with [`Request::GetClaims`] where others are drawing and avoid
+    /// painting over each other.
+    /// Claiming exactly the same rectangle again renews the claim while `seconds = 0` releases it.
+    Claim {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+        /// For how many seconds the rectangle should be claimed
+        seconds: u64,
+    },
+    /// Get all rectangles which are currently claimed
+    GetClaims,
+    /// Get how many pixels the client has written
+    ///
+    /// Servers only answer this if they count the pixels of their clients.
+    GetStats,
+    /// Set every pixel of the canvas to black
+    ///
+    /// Servers only accept this if their operator enabled [`Extension::Fill`].
+    Clear,
+    /// Set every pixel of the canvas to the given color
+    ///
+    /// Servers only accept this if their operator enabled [`Extension::Fill`].
+    Fill(Color),
 }
 
 impl Request {
     /// Write the binary representation of this request into the given writer
     pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
         match self {
+            Request::Hello {
+                user_agent,
+                extensions,
+            } => writer.write_all(format!("{}\n", fmt_hello(user_agent, extensions)).as_bytes()),
             Request::Help(topic) => match topic {
                 HelpTopic::General => writer.write_all("HELP\n".as_bytes()),
                 HelpTopic::Size => writer.write_all("HELP SIZE\n".as_bytes()),
                 HelpTopic::Px => writer.write_all("HELP PX\n".as_bytes()),
+                HelpTopic::State => writer.write_all("HELP STATE\n".as_bytes()),
+                HelpTopic::Quiet => writer.write_all("HELP QUIET\n".as_bytes()),
+                HelpTopic::Auth => writer.write_all("HELP AUTH\n".as_bytes()),
+                HelpTopic::Reserve => writer.write_all("HELP RESERVE\n".as_bytes()),
+                HelpTopic::Claim => writer.write_all("HELP CLAIM\n".as_bytes()),
+                HelpTopic::Fill => writer.write_all("HELP FILL\n".as_bytes()),
+                HelpTopic::Stats => writer.write_all("HELP STATS\n".as_bytes()),
             },
             Request::GetSize => writer.write_all("SIZE\n".as_bytes()),
             Request::GetPixel { x, y } => writer.write_all(format!("PX {} {}\n", x, y).as_bytes()),
+            Request::GetPixels { pixels } => {
+                writer.write_all(format!("{}\n", fmt_px_batch_get(pixels)).as_bytes())
+            }
             Request::SetPixel { x, y, color } => {
                 writer.write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
             }
+            Request::BlendPixel { x, y, color, alpha } => {
+                writer.write_all(format!("PX {} {} {:X}{:02X}\n", x, y, color, alpha).as_bytes())
+            }
+            Request::GetRegion {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => writer.write_all(format!("STATE REGION {x} {y} {width} {height} {encoding}\n").as_bytes()),
+            Request::GetRect {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => writer.write_all(format!("PXRECT {x} {y} {width} {height} {encoding}\n").as_bytes()),
+            Request::Quiet(quiet) => writer.write_all(format!("QUIET {}\n", fmt_on_off(*quiet)).as_bytes()),
+            Request::Subscribe(subscribe) => {
+                writer.write_all(format!("{}\n", fmt_subscribe(*subscribe)).as_bytes())
+            }
+            Request::SubscribeRegion { x, y, width, height } => {
+                writer.write_all(format!("SUBSCRIBE {x} {y} {width} {height}\n").as_bytes())
+            }
+            Request::SelectCanvas(name) => writer.write_all(format!("CANVAS {name}\n").as_bytes()),
+            Request::Auth { team, token } => writer.write_all(format!("AUTH {team} {token}\n").as_bytes()),
+            Request::Reserve {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => writer.write_all(format!("RESERVE {x} {y} {width} {height} {seconds}\n").as_bytes()),
+            Request::Claim {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => writer.write_all(format!("CLAIM {x} {y} {width} {height} {seconds}\n").as_bytes()),
+            Request::GetClaims => writer.write_all("CLAIMS\n".as_bytes()),
+            Request::GetStats => writer.write_all("STATS\n".as_bytes()),
+            Request::Clear => writer.write_all("CLEAR\n".as_bytes()),
+            Request::Fill(color) => writer.write_all(format!("FILL {:X}\n", color).as_bytes()),
+        }
+    }
+
+    /// Write this request into the given writer using the binary `PB` command where possible
+    ///
+    /// [`Request::SetPixel`] and [`Request::BlendPixel`] requests whose coordinates fit into 16 bits are written as `PB` command which servers
+    /// parse much faster.
+    /// All other requests are written in their text form like [`Request::write`] does.
+    /// The server must support [`Extension::BinaryPx`].
+    pub fn write_binary(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Request::SetPixel { x, y, color } => match (u16::try_from(*x), u16::try_from(*y)) {
+                (Ok(x), Ok(y)) => write_binary_px(x, y, *color, 0xFF, writer),
+                _ => self.write(writer),
+            },
+            Request::BlendPixel { x, y, color, alpha } => match (u16::try_from(*x), u16::try_from(*y)) {
+                (Ok(x), Ok(y)) => write_binary_px(x, y, *color, *alpha, writer),
+                _ => self.write(writer),
+            },
+            _ => self.write(writer),
         }
     }
 
     /// Write the binary representation of this request into the given async writer
     pub async fn write_async(&self, writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
         match self {
+            Request::Hello {
+                user_agent,
+                extensions,
+            } => {
+                writer
+                    .write_all(format!("{}\n", fmt_hello(user_agent, extensions)).as_bytes())
+                    .await
+            }
             Request::Help(topic) => match topic {
                 HelpTopic::General => writer.write_all("HELP\n".as_bytes()).await,
                 HelpTopic::Size => writer.write_all("HELP SIZE\n".as_bytes()).await,
                 HelpTopic::Px => writer.write_all("HELP PX\n".as_bytes()).await,
+                HelpTopic::State => writer.write_all("HELP STATE\n".as_bytes()).await,
+                HelpTopic::Quiet => writer.write_all("HELP QUIET\n".as_bytes()).await,
+                HelpTopic::Auth => writer.write_all("HELP AUTH\n".as_bytes()).await,
+                HelpTopic::Reserve => writer.write_all("HELP RESERVE\n".as_bytes()).await,
+                HelpTopic::Claim => writer.write_all("HELP CLAIM\n".as_bytes()).await,
+                HelpTopic::Fill => writer.write_all("HELP FILL\n".as_bytes()).await,
+                HelpTopic::Stats => writer.write_all("HELP STATS\n".as_bytes()).await,
             },
             Request::GetSize => writer.write_all("SIZE\n".as_bytes()).await,
             Request::GetPixel { x, y } => writer.write_all(format!("PX {} {}\n", x, y).as_bytes()).await,
+            Request::GetPixels { pixels } => {
+                writer
+                    .write_all(format!("{}\n", fmt_px_batch_get(pixels)).as_bytes())
+                    .await
+            }
             Request::SetPixel { x, y, color } => {
                 writer
                     .write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
                     .await
             }
+            Request::BlendPixel { x, y, color, alpha } => {
+                writer
+                    .write_all(format!("PX {} {} {:X}{:02X}\n", x, y, color, alpha).as_bytes())
+                    .await
+            }
+            Request::GetRegion {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => {
+                writer
+                    .write_all(format!("STATE REGION {x} {y} {width} {height} {encoding}\n").as_bytes())
+                    .await
+            }
+            Request::GetRect {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => {
+                writer
+                    .write_all(format!("PXRECT {x} {y} {width} {height} {encoding}\n").as_bytes())
+                    .await
+            }
+            Request::Quiet(quiet) => {
+                writer
+                    .write_all(format!("QUIET {}\n", fmt_on_off(*quiet)).as_bytes())
+                    .await
+            }
+            Request::Subscribe(subscribe) => {
+                writer
+                    .write_all(format!("{}\n", fmt_subscribe(*subscribe)).as_bytes())
+                    .await
+            }
+            Request::SubscribeRegion { x, y, width, height } => {
+                writer
+                    .write_all(format!("SUBSCRIBE {x} {y} {width} {height}\n").as_bytes())
+                    .await
+            }
+            Request::SelectCanvas(name) => writer.write_all(format!("CANVAS {name}\n").as_bytes()).await,
+            Request::Auth { team, token } => {
+                writer
+                    .write_all(format!("AUTH {team} {token}\n").as_bytes())
+                    .await
+            }
+            Request::Reserve {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => {
+                writer
+                    .write_all(format!("RESERVE {x} {y} {width} {height} {seconds}\n").as_bytes())
+                    .await
+            }
+            Request::Claim {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => {
+                writer
+                    .write_all(format!("CLAIM {x} {y} {width} {height} {seconds}\n").as_bytes())
+                    .await
+            }
+            Request::GetClaims => writer.write_all("CLAIMS\n".as_bytes()).await,
+            Request::GetStats => writer.write_all("STATS\n".as_bytes()).await,
+            Request::Clear => writer.write_all("CLEAR\n".as_bytes()).await,
+            Request::Fill(color) => writer.write_all(format!("FILL {:X}\n", color).as_bytes()).await,
         }
     }
 }
@@ -81,21 +666,82 @@ impl Request {
 impl Display for Request {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            Request::Hello {
+                user_agent,
+                extensions,
+            } => f.write_str(&fmt_hello(user_agent, extensions)),
             Request::Help(topic) => match topic {
                 HelpTopic::General => f.write_str("HELP"),
                 HelpTopic::Size => f.write_str("HELP SIZE"),
                 HelpTopic::Px => f.write_str("HELP PX"),
+                HelpTopic::State => f.write_str("HELP STATE"),
+                HelpTopic::Quiet => f.write_str("HELP QUIET"),
+                HelpTopic::Auth => f.write_str("HELP AUTH"),
+                HelpTopic::Reserve => f.write_str("HELP RESERVE"),
+                HelpTopic::Claim => f.write_str("HELP CLAIM"),
+                HelpTopic::Fill => f.write_str("HELP FILL"),
+                HelpTopic::Stats => f.write_str("HELP STATS"),
             },
             Request::GetSize => f.write_str("SIZE"),
             Request::GetPixel { x, y } => f.write_fmt(format_args!("PX {} {}", x, y)),
+            Request::GetPixels { pixels } => f.write_str(&fmt_px_batch_get(pixels)),
             Request::SetPixel { x, y, color } => f.write_fmt(format_args!("PX {} {} {:X}", x, y, color)),
+            Request::BlendPixel { x, y, color, alpha } => {
+                f.write_fmt(format_args!("PX {} {} {:X}{:02X}", x, y, color, alpha))
+            }
+            Request::GetRegion {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => f.write_fmt(format_args!("STATE REGION {x} {y} {width} {height} {encoding}")),
+            Request::GetRect {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+            } => f.write_fmt(format_args!("PXRECT {x} {y} {width} {height} {encoding}")),
+            Request::Quiet(quiet) => f.write_fmt(format_args!("QUIET {}", fmt_on_off(*quiet))),
+            Request::Subscribe(subscribe) => f.write_str(fmt_subscribe(*subscribe)),
+            Request::SubscribeRegion { x, y, width, height } => {
+                f.write_fmt(format_args!("SUBSCRIBE {x} {y} {width} {height}"))
+            }
+            Request::SelectCanvas(name) => f.write_fmt(format_args!("CANVAS {name}")),
+            Request::Auth { team, token } => f.write_fmt(format_args!("AUTH {team} {token}")),
+            Request::Reserve {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => f.write_fmt(format_args!("RESERVE {x} {y} {width} {height} {seconds}")),
+            Request::Claim {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => f.write_fmt(format_args!("CLAIM {x} {y} {width} {height} {seconds}")),
+            Request::GetClaims => f.write_str("CLAIMS"),
+            Request::GetStats => f.write_str("STATS"),
+            Request::Clear => f.write_str("CLEAR"),
+            Request::Fill(color) => f.write_fmt(format_args!("FILL {:X}", color)),
         }
     }
 }
 
 /// The response of a pixelflut server
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Response {
+    /// The servers answer to a [`Request::Hello`]
+    Hello {
+        /// A string identifying the server software
+        server_agent: String,
+        /// The extensions which were requested by the client and which the server supports
+        extensions: Vec<Extension>,
+    },
     /// Help about a specific topic with more information about that topic
     Help(HelpTopic),
     /// Size information about the servers canvas
@@ -114,58 +760,412 @@ pub enum Response {
         /// The color of the pixel
         color: Color,
     },
+    /// Color data of multiple pixels which were requested via [`Request::GetPixels`]
+    PxDataBatch {
+        /// The x and y coordinates as well as the color of each pixel, in the order in which they were requested
+        pixels: Vec<(usize, usize, Color)>,
+    },
+    /// Color data of a rectangular region of the canvas
+    Region {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+        /// The encoding with which the data is transferred
+        encoding: StateEncoding,
+        /// The colors of all pixels in the region, stored row by row
+        data: Vec<Color>,
+    },
+    /// Color data of a rectangular block of pixels which was requested via [`Request::GetRect`]
+    Rect {
+        /// The x coordinate of the blocks top-left corner
+        x: usize,
+        /// The y coordinate of the blocks top-left corner
+        y: usize,
+        /// The width of the block
+        width: usize,
+        /// The height of the block
+        height: usize,
+        /// The encoding with which the data is transferred
+        encoding: StateEncoding,
+        /// The colors of all pixels in the block, stored row by row
+        data: Vec<Color>,
+    },
+    /// Confirmation that the connection is now authenticated as a member of the team
+    Authenticated {
+        /// The name of the team
+        team: String,
+    },
+    /// Confirmation that a rectangle of the canvas is now reserved for the authenticated team
+    Reserved {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+        /// For how many seconds the rectangle is reserved
+        seconds: u64,
+    },
+    /// Confirmation that a rectangle of the canvas is now claimed
+    Claimed {
+        /// The x coordinate of the rectangles top-left corner
+        x: usize,
+        /// The y coordinate of the rectangles top-left corner
+        y: usize,
+        /// The width of the rectangle
+        width: usize,
+        /// The height of the rectangle
+        height: usize,
+        /// For how many seconds the rectangle is claimed
+        seconds: u64,
+    },
+    /// All rectangles which are currently claimed
+    Claims {
+        /// The x and y coordinates of each rectangles top-left corner, its width and height as well as the number of
+        /// seconds until the claim expires
+        claims: Vec<(usize, usize, usize, usize, u64)>,
+    },
+    /// How many pixels the client has written which was requested via [`Request::GetStats`]
+    Stats {
+        /// The pixels written from the clients network, which may be overestimated slightly
+        pixels: u64,
+        /// The pixels written by all clients together
+        total: u64,
+        /// The place of the clients network among the busiest ones or `None` if it is not among them
+        rank: Option<usize>,
+    },
 }
 
 impl Response {
+    /// Append the wire representation of this response to `buf`
+    ///
+    /// This does not allocate any intermediate strings so servers can serialize all responses to one batch of
+    /// requests into a single reused buffer and send it with one syscall.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        // writing into a BytesMut only fails if the Display implementation itself fails, which it never does
+        let _ = match self {
+            // help texts are already terminated by a newline
+            Response::Help(_) => write!(buf, "{}", self),
+            _ => writeln!(buf, "{}", self),
+        };
+    }
+
     /// Write the binary representation of this response into the given writer
     pub fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
         match self {
-            Response::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all(texts::HELP_GENERAL.as_bytes()),
-                HelpTopic::Size => writer.write_all(texts::HELP_SIZE.as_bytes()),
-                HelpTopic::Px => writer.write_all(texts::HELP_PX.as_bytes()),
-            },
-            Response::Size { width, height } => {
-                writer.write_all(format!("SIZE {} {}\n", width, height).as_bytes())
-            }
-            Response::PxData { x, y, color } => {
-                writer.write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
-            }
+            Response::Help(_) => write!(writer, "{}", self),
+            _ => writeln!(writer, "{}", self),
         }
     }
 
     /// Write the binary representation of this response into the given async writer
     pub async fn write_async(&self, writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
-        match self {
-            Response::Help(topic) => match topic {
-                HelpTopic::General => writer.write_all(texts::HELP_GENERAL.as_bytes()).await,
-                HelpTopic::Size => writer.write_all(texts::HELP_SIZE.as_bytes()).await,
-                HelpTopic::Px => writer.write_all(texts::HELP_PX.as_bytes()).await,
-            },
-            Response::Size { width, height } => {
-                writer
-                    .write_all(format!("SIZE {} {}\n", width, height).as_bytes())
-                    .await
-            }
-            Response::PxData { x, y, color } => {
-                writer
-                    .write_all(format!("PX {} {} {:X}\n", x, y, color).as_bytes())
-                    .await
-            }
-        }
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        writer.write_all(&buf).await
     }
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            Response::Hello {
+                server_agent,
+                extensions,
+            } => f.write_str(&fmt_hello(server_agent, extensions)),
             Response::Help(topic) => match topic {
                 HelpTopic::General => f.write_str(texts::HELP_GENERAL),
                 HelpTopic::Size => f.write_str(texts::HELP_SIZE),
                 HelpTopic::Px => f.write_str(texts::HELP_PX),
+                HelpTopic::State => f.write_str(texts::HELP_STATE),
+                HelpTopic::Quiet => f.write_str(texts::HELP_QUIET),
+                HelpTopic::Auth => f.write_str(texts::HELP_AUTH),
+                HelpTopic::Reserve => f.write_str(texts::HELP_RESERVE),
+                HelpTopic::Claim => f.write_str(texts::HELP_CLAIM),
+                HelpTopic::Fill => f.write_str(texts::HELP_FILL),
+                HelpTopic::Stats => f.write_str(texts::HELP_STATS),
             },
             Response::Size { width, height } => f.write_fmt(format_args!("SIZE {} {}", width, height)),
             Response::PxData { x, y, color } => f.write_fmt(format_args!("PX {} {} {:X}", x, y, color)),
+            Response::PxDataBatch { pixels } => fmt_px_batch_data(pixels, f),
+            Response::Region {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+                data,
+            } => {
+                f.write_fmt(format_args!("STATE REGION {x} {y} {width} {height} {encoding} "))?;
+                encoding.encode_into(data, f)
+            }
+            Response::Rect {
+                x,
+                y,
+                width,
+                height,
+                encoding,
+                data,
+            } => {
+                f.write_fmt(format_args!("PXRECT {x} {y} {width} {height} {encoding} "))?;
+                encoding.encode_into(data, f)
+            }
+            Response::Authenticated { team } => f.write_fmt(format_args!("AUTH {team}")),
+            Response::Reserved {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => f.write_fmt(format_args!("RESERVED {x} {y} {width} {height} {seconds}")),
+            Response::Claimed {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            } => f.write_fmt(format_args!("CLAIMED {x} {y} {width} {height} {seconds}")),
+            Response::Claims { claims } => fmt_claims(claims, f),
+            Response::Stats { pixels, total, rank } => {
+                f.write_fmt(format_args!("STATS {pixels} {total}"))?;
+                match rank {
+                    Some(rank) => f.write_fmt(format_args!(" {rank}")),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for HelpTopic {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&[
+            HelpTopic::General,
+            HelpTopic::Size,
+            HelpTopic::Px,
+            HelpTopic::State,
+            HelpTopic::Quiet,
+            HelpTopic::Auth,
+            HelpTopic::Reserve,
+            HelpTopic::Claim,
+            HelpTopic::Fill,
+            HelpTopic::Stats,
+        ])
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for StateEncoding {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&[StateEncoding::Rgb64, StateEncoding::Rle64]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for Extension {
+    fn arbitrary(g: &mut Gen) -> Self {
+        *g.choose(&[
+            Extension::StateRegion,
+            Extension::PxBatch,
+            Extension::BinaryPx,
+            Extension::Subscribe,
+            Extension::Canvas,
+            Extension::Fill,
+            Extension::PxRect,
+            Extension::Claims,
+            Extension::Stats,
+        ])
+        .unwrap()
+    }
+}
+
+/// Generate a color which can be represented on the wire (i.e. without data in the unused upper byte)
+#[cfg(test)]
+fn arbitrary_wire_color(g: &mut Gen) -> Color {
+    Color::from(<(u8, u8, u8)>::arbitrary(g))
+}
+
+/// Generate a non-empty agent identifier that does not contain whitespace
+#[cfg(test)]
+fn arbitrary_agent(g: &mut Gen) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789/.-_";
+    let len = usize::arbitrary(g) % 16 + 1;
+    (0..len).map(|_| *g.choose(ALPHABET).unwrap() as char).collect()
+}
+
+#[cfg(test)]
+impl Arbitrary for Request {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match u8::arbitrary(g) % 20 {
+            0 => Request::Hello {
+                user_agent: arbitrary_agent(g),
+                extensions: Vec::arbitrary(g),
+            },
+            1 => Request::Help(HelpTopic::arbitrary(g)),
+            2 => Request::GetSize,
+            3 => Request::GetPixel {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+            },
+            4 => Request::SetPixel {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                color: arbitrary_wire_color(g),
+            },
+            5 => Request::GetRegion {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                encoding: StateEncoding::arbitrary(g),
+            },
+            6 => Request::Auth {
+                team: arbitrary_agent(g),
+                token: arbitrary_agent(g),
+            },
+            7 => Request::GetPixels {
+                pixels: (0..usize::arbitrary(g) % 8 + 2)
+                    .map(|_| (usize::arbitrary(g), usize::arbitrary(g)))
+                    .collect(),
+            },
+            8 => Request::Quiet(bool::arbitrary(g)),
+            10 => Request::Subscribe(bool::arbitrary(g)),
+            11 => Request::SelectCanvas(arbitrary_agent(g)),
+            12 => Request::Clear,
+            13 => Request::Fill(arbitrary_wire_color(g)),
+            14 => Request::SubscribeRegion {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+            },
+            15 => Request::GetRect {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                encoding: StateEncoding::arbitrary(g),
+            },
+            16 => Request::Claim {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                seconds: u64::arbitrary(g),
+            },
+            17 => Request::GetClaims,
+            18 => Request::GetStats,
+            9 => Request::BlendPixel {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                color: arbitrary_wire_color(g),
+                // fully opaque colors are parsed as SetPixel
+                alpha: u8::arbitrary(g) % 0xFF,
+            },
+            _ => Request::Reserve {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                seconds: u64::arbitrary(g),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl Arbitrary for Response {
+    fn arbitrary(g: &mut Gen) -> Self {
+        match u8::arbitrary(g) % 12 {
+            0 => Response::Hello {
+                server_agent: arbitrary_agent(g),
+                extensions: Vec::arbitrary(g),
+            },
+            1 => Response::Help(HelpTopic::arbitrary(g)),
+            2 => Response::Size {
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+            },
+            3 => Response::PxData {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                color: arbitrary_wire_color(g),
+            },
+            5 => Response::Authenticated {
+                team: arbitrary_agent(g),
+            },
+            6 => Response::Reserved {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                seconds: u64::arbitrary(g),
+            },
+            9 => Response::Claimed {
+                x: usize::arbitrary(g),
+                y: usize::arbitrary(g),
+                width: usize::arbitrary(g),
+                height: usize::arbitrary(g),
+                seconds: u64::arbitrary(g),
+            },
+            10 => Response::Claims {
+                claims: (0..usize::arbitrary(g) % 4)
+                    .map(|_| {
+                        (
+                            usize::arbitrary(g),
+                            usize::arbitrary(g),
+                            usize::arbitrary(g),
+                            usize::arbitrary(g),
+                            u64::arbitrary(g),
+                        )
+                    })
+                    .collect(),
+            },
+            11 => Response::Stats {
+                pixels: u64::arbitrary(g),
+                total: u64::arbitrary(g),
+                rank: Option::arbitrary(g),
+            },
+            7 => Response::PxDataBatch {
+                pixels: (0..usize::arbitrary(g) % 8 + 2)
+                    .map(|_| (usize::arbitrary(g), usize::arbitrary(g), arbitrary_wire_color(g)))
+                    .collect(),
+            },
+            8 => {
+                let width = usize::arbitrary(g) % 8 + 1;
+                let height = usize::arbitrary(g) % 8 + 1;
+                // a few colors so that runs of equal pixels actually occur
+                let colors = [arbitrary_wire_color(g), arbitrary_wire_color(g)];
+                Response::Rect {
+                    x: usize::arbitrary(g),
+                    y: usize::arbitrary(g),
+                    width,
+                    height,
+                    encoding: StateEncoding::arbitrary(g),
+                    data: (0..width * height).map(|_| *g.choose(&colors).unwrap()).collect(),
+                }
+            }
+            _ => {
+                let width = usize::arbitrary(g) % 8 + 1;
+                let height = usize::arbitrary(g) % 8 + 1;
+                Response::Region {
+                    x: usize::arbitrary(g),
+                    y: usize::arbitrary(g),
+                    width,
+                    height,
+                    encoding: StateEncoding::arbitrary(g),
+                    data: (0..width * height).map(|_| arbitrary_wire_color(g)).collect(),
+                }
+            }
         }
     }
 }
@@ -1,9 +1,21 @@
 //! Definitions for the network protocol
 
+mod binary;
+mod canvas_delta;
 mod compliant_parser;
+mod decoder;
 mod dtypes;
+mod multiplexing;
 
 pub use dtypes::*;
 
-pub use compliant_parser::{parse_request_bin, parse_request_str};
+pub use binary::{request_frame_len, BINARY_PX_LEN};
+pub use canvas_delta::{
+    parse_canvas_delta, write_delta_blocks, write_delta_pixel, write_delta_region, CanvasDelta, DELTA_BLOCKS,
+    DELTA_PIXEL, DELTA_REGION,
+};
+
+pub use compliant_parser::{parse_request_bin, parse_request_bin_with, parse_request_str, Strictness};
 pub use compliant_parser::{parse_response_bin, parse_response_str};
+pub use decoder::{DecoderEvent, RequestDecoder, DEFAULT_MAX_LINE_LEN};
+pub use multiplexing::{split_channel, write_channel_framed};
@@ -0,0 +1,61 @@
+//! Lightweight framing that allows multiple logical command streams to share one connection
+//!
+//! When multiplexing is enabled on a listener, every line may be prefixed with `@<channel> ` where `<channel>` is
+//! a decimal number chosen by the client.
+//! Responses to such lines are prefixed with the same channel id so that e.g. a proxy can route them back to the
+//! correct downstream client.
+//! Every channel keeps its own state like a separate connection, e.g. whether it is quiet, its selected canvas and
+//! its team.
+//! Lines without a prefix are handled as usual.
+
+use std::io::Write;
+
+/// Split a channel prefix off of the given line
+///
+/// Returns the channel id (if the line carries a valid prefix) and the remaining line content.
+pub fn split_channel(line: &[u8]) -> (Option<u32>, &[u8]) {
+    let Some(rest) = line.strip_prefix(b"@") else {
+        return (None, line);
+    };
+    let Some(sep) = rest.iter().position(|b| *b == b' ') else {
+        return (None, line);
+    };
+
+    // Safety: the slice is checked to be ascii before it is interpreted as str
+    match rest[..sep].is_ascii() {
+        true => match unsafe { std::str::from_utf8_unchecked(&rest[..sep]) }.parse() {
+            Ok(channel) => (Some(channel), &rest[sep + 1..]),
+            Err(_) => (None, line),
+        },
+        false => (None, line),
+    }
+}
+
+/// Write the given response data into `writer`, prefixing every line with the given channel id
+pub fn write_channel_framed(channel: u32, data: &[u8], writer: &mut impl Write) -> std::io::Result<()> {
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        writer.write_fmt(format_args!("@{} ", channel))?;
+        writer.write_all(line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_channel() {
+        assert_eq!(split_channel(b"@12 PX 1 2\n"), (Some(12), &b"PX 1 2\n"[..]));
+        assert_eq!(split_channel(b"PX 1 2\n"), (None, &b"PX 1 2\n"[..]));
+        assert_eq!(split_channel(b"@abc PX 1 2\n"), (None, &b"@abc PX 1 2\n"[..]));
+        assert_eq!(split_channel(b"@12\n"), (None, &b"@12\n"[..]));
+    }
+
+    #[test]
+    fn test_write_channel_framed() {
+        let mut buf = Vec::new();
+        write_channel_framed(3, b"HELP SIZE\nline two\n", &mut buf).unwrap();
+        assert_eq!(buf, b"@3 HELP SIZE\n@3 line two\n");
+    }
+}
@@ -0,0 +1,331 @@
+use crate::net::servers::{Admission, ConnectionHooks, ConnectionSummary, PeerInfo};
+use async_trait::async_trait;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Formats in which an [`AccessLog`] writes its lines
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum AccessLogFormat {
+    /// Lines modeled after the common log format of web servers followed by `key=value` fields, e.g.
+    /// `192.0.2.1:4711 - - [17/Oct/2026:12:00:00 +0000] "CLOSE tcp://0.0.0.0:1234" duration_ms=1500 requests=2 ...`
+    ///
+    /// Clients without a network address, like those of unix sockets, are logged as `-`.
+    #[default]
+    Common,
+    /// One JSON object per line with `time`, `event`, `listener` and `peer` keys as well as the counters of
+    /// [`ConnectionSummary`] for `close` events
+    Json,
+}
+
+/// Options with which an [`AccessLog`] is configured
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccessLogOptions {
+    /// The file to which lines are appended
+    pub path: PathBuf,
+    /// The format of the lines
+    pub format: AccessLogFormat,
+    /// The size in bytes after which the file is rotated
+    pub max_size: Option<u64>,
+    /// How long a file is written to before it is rotated
+    pub rotate_interval: Option<Duration>,
+    /// How many rotated files are kept as `<path>.1` (the newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+/// [`ConnectionHooks`] which record when clients connect and disconnect together with what they did in a file
+///
+/// Unlike the tracing diagnostics, the access log has a stable format which is meant for investigating abuse after
+/// an event. It admits every client and covers the same servers as the hooks, so UDP clients are not logged.
+#[derive(Debug)]
+pub struct AccessLog {
+    options: AccessLogOptions,
+    file: Mutex<LogFile>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+/// Something that happened to a connection
+#[derive(Debug, Copy, Clone)]
+enum Event<'a> {
+    Open,
+    Close(&'a ConnectionSummary),
+}
+
+impl AccessLog {
+    /// Open the log file, appending to it if it already exists
+    pub async fn open(options: AccessLogOptions) -> anyhow::Result<Self> {
+        let file = LogFile::open(&options.path).await?;
+        Ok(Self {
+            options,
+            file: Mutex::new(file),
+        })
+    }
+
+    async fn log(&self, peer: &PeerInfo, event: Event<'_>) {
+        let line = format_line(self.options.format, SystemTime::now(), peer, event);
+        if let Err(e) = self.write(line.as_bytes()).await {
+            tracing::warn!(
+                "Could not write access log {}: {}",
+                self.options.path.display(),
+                e
+            );
+        }
+    }
+
+    async fn write(&self, line: &[u8]) -> anyhow::Result<()> {
+        let mut file = self.file.lock().await;
+        let too_large = self
+            .options
+            .max_size
+            .is_some_and(|max_size| file.size > 0 && file.size + line.len() as u64 > max_size);
+        let too_old = self
+            .options
+            .rotate_interval
+            .is_some_and(|interval| file.opened_at.elapsed() >= interval);
+        if too_large || too_old {
+            file.file.flush().await?;
+            rotate(&self.options.path, self.options.keep).await?;
+            *file = LogFile::open(&self.options.path).await?;
+        }
+        // tokio completes writes in the background, so lines are flushed to be visible right away
+        file.file.write_all(line).await?;
+        file.file.flush().await?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl LogFile {
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Self {
+            size: file.metadata().await?.len(),
+            file,
+            opened_at: Instant::now(),
+        })
+    }
+}
+
+#[async_trait]
+impl ConnectionHooks for AccessLog {
+    async fn on_connection_open(&self, peer: &PeerInfo) -> Result<Admission, String> {
+        self.log(peer, Event::Open).await;
+        Ok(Admission::default())
+    }
+
+    async fn on_connection_close(&self, peer: &PeerInfo, summary: &ConnectionSummary) {
+        self.log(peer, Event::Close(summary)).await;
+    }
+}
+
+/// Shift `<path>.1` to `<path>.<keep>` by one, dropping the oldest, and move `path` to `<path>.1`
+async fn rotate(path: &Path, keep: usize) -> std::io::Result<()> {
+    let numbered = |i: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    };
+    let result = match keep {
+        0 => tokio::fs::remove_file(path).await,
+        _ => {
+            for i in (1..keep).rev() {
+                match tokio::fs::rename(numbered(i), numbered(i + 1)).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            tokio::fs::rename(path, numbered(1)).await
+        }
+    };
+    match result {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn format_line(format: AccessLogFormat, time: SystemTime, peer: &PeerInfo, event: Event<'_>) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+    let name = match event {
+        Event::Open => "open",
+        Event::Close(_) => "close",
+    };
+    let duration_ms = peer.connected_at.elapsed().as_millis();
+    let mut line = String::new();
+    match format {
+        AccessLogFormat::Common => {
+            const MONTHS: [&str; 12] = [
+                "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+            ];
+            let _ = write!(
+                line,
+                "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {}\"",
+                peer.remote_addr.map_or("-".to_string(), |addr| addr.to_string()),
+                day,
+                MONTHS[month as usize - 1],
+                year,
+                hour,
+                minute,
+                second,
+                name.to_uppercase(),
+                peer.listener,
+            );
+            if let Event::Close(summary) = event {
+                let commands = summary
+                    .commands()
+                    .map(|(command, count)| format!("{}:{}", command.label(), count))
+                    .collect::<Vec<_>>();
+                let _ = write!(
+                    line,
+                    " duration_ms={} requests={} errors={} bytes_read={} bytes_written={} commands={}",
+                    duration_ms,
+                    summary.requests,
+                    summary.errors,
+                    summary.bytes_read,
+                    summary.bytes_written,
+                    match commands.is_empty() {
+                        true => "-".to_string(),
+                        false => commands.join(","),
+                    },
+                );
+            }
+        }
+        AccessLogFormat::Json => {
+            let _ = write!(
+                line,
+                "{{\"time\":\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\"event\":\"{}\",\"listener\":{},\"peer\":{}",
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                name,
+                json_string(&peer.listener),
+                peer.remote_addr
+                    .map_or("null".to_string(), |addr| json_string(&addr.to_string())),
+            );
+            if let Event::Close(summary) = event {
+                let commands = summary
+                    .commands()
+                    .map(|(command, count)| format!("\"{}\":{}", command.label(), count))
+                    .collect::<Vec<_>>();
+                let _ = write!(
+                    line,
+                    ",\"duration_ms\":{},\"requests\":{},\"errors\":{},\"bytes_read\":{},\"bytes_written\":{},\"commands\":{{{}}}",
+                    duration_ms,
+                    summary.requests,
+                    summary.errors,
+                    summary.bytes_read,
+                    summary.bytes_written,
+                    commands.join(","),
+                );
+            }
+            line.push('}');
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Split a point in time into its UTC year, month, day, hour, minute and second
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // the civil-from-days algorithm of Howard Hinnant which counts in eras of 400 years starting on March 1st
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::Command;
+
+    #[test]
+    fn test_format_line() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_238_400);
+        let peer = PeerInfo::new("tcp://0.0.0.0:1234", Some("192.0.2.1:4711".parse().unwrap()));
+        let mut summary = ConnectionSummary::default();
+        summary.requests = 3;
+        summary.errors = 1;
+        summary.bytes_read = 40;
+        summary.bytes_written = 12;
+        summary.count_command(Command::SetPixel);
+        summary.count_command(Command::SetPixel);
+
+        assert_eq!(
+            format_line(AccessLogFormat::Common, time, &peer, Event::Open),
+            "192.0.2.1:4711 - - [17/Oct/2026:12:00:00 +0000] \"OPEN tcp://0.0.0.0:1234\"\n"
+        );
+        let line = format_line(AccessLogFormat::Common, time, &peer, Event::Close(&summary));
+        assert!(line.ends_with(" requests=3 errors=1 bytes_read=40 bytes_written=12 commands=set_pixel:2\n"));
+
+        let peer = PeerInfo::new("unix:/run/pixelflut.sock", None);
+        let line = format_line(AccessLogFormat::Json, time, &peer, Event::Close(&summary));
+        assert!(line.starts_with(
+            "{\"time\":\"2026-10-17T12:00:00Z\",\"event\":\"close\",\"listener\":\"unix:/run/pixelflut.sock\",\"peer\":null,"
+        ));
+        assert!(line.ends_with(",\"bytes_written\":12,\"commands\":{\"set_pixel\":2}}\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(AccessLogOptions {
+            path: path.clone(),
+            format: AccessLogFormat::Common,
+            max_size: Some(100),
+            rotate_interval: None,
+            keep: 2,
+        })
+        .await
+        .unwrap();
+
+        let peer = PeerInfo::new("tcp://0.0.0.0:1234", Some("192.0.2.1:4711".parse().unwrap()));
+        for _ in 0..4 {
+            log.on_connection_open(&peer).await.unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("access.log").lines().count(), 1);
+        assert_eq!(read("access.log.1").lines().count(), 1);
+        assert_eq!(read("access.log.2").lines().count(), 1);
+        // the oldest line was dropped because only two rotated files are kept
+        assert!(!dir.path().join("access.log.3").exists());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::net::servers::Session;
 use crate::pixmap::{Pixmap, SharedPixmap};
 use std::hint::black_box;
 use test::Bencher;
@@ -5,13 +6,14 @@ use test::Bencher;
 #[bench]
 fn bench_1000_requests(b: &mut Bencher) {
     let pixmap = SharedPixmap::new(Pixmap::new(800, 600).unwrap());
+    let mut session = Session::default();
 
     // run the benchmark
     b.iter(|| {
         #[allow(clippy::needless_range_loop)]
         for i in 0..COMMANDS.len() {
             let line = black_box(COMMANDS[i]);
-            let result = super::handle_request(line, &pixmap);
+            let result = super::handle_request(line, &pixmap, &mut session);
             assert_eq!(result, Ok(None));
         }
     })
@@ -19,7 +21,7 @@ fn bench_1000_requests(b: &mut Bencher) {
 
 // generated in python with:
 // lines = (f"b\"PX {random.randrange(0, 800)} {random.randrange(0, 600)} {random.randrange(0, 0xFFFFFF):x}\",\n" for _ in range(0, 1000))
-const COMMANDS: &'static [&'static [u8]] = &[
+const COMMANDS: &[&[u8]] = &[
     b"PX 99 367 67ed98",
     b"PX 94 464 39b467",
     b"PX 235 311 eb4937",
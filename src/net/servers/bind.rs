@@ -0,0 +1,154 @@
+//!
+//! Binding servers to multiple local addresses
+//!
+
+use anyhow::anyhow;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+#[cfg(feature = "udp")]
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+
+/// The maximum number of pending connections of TCP listeners, which is the same that tokio uses
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Whether a socket which is bound to `addr` should only accept IPv6 traffic
+///
+/// An IPv6 wildcard address like `[::]` is bound dual-stack so that it alone serves IPv4 clients as well.
+/// If IPv4 addresses with the same port are bound too, that would conflict with them so the IPv6 socket is then
+/// restricted to IPv6 and the IPv4 sockets serve IPv4 clients.
+fn only_v6(addr: &SocketAddr, all: &[SocketAddr]) -> bool {
+    !addr.ip().is_unspecified() || all.iter().any(|i| i.is_ipv4() && i.port() == addr.port())
+}
+
+/// Create a non-blocking socket which is bound to `addr`
+fn bind_socket(
+    addr: &SocketAddr,
+    all: &[SocketAddr],
+    ty: Type,
+    protocol: Protocol,
+) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*addr), ty, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6(addr, all))?;
+    }
+    if ty == Type::STREAM {
+        // allow restarting the server while connections of its previous run are still in TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    Ok(socket)
+}
+
+/// Bind a TCP listener to each of the given addresses
+pub(crate) fn bind_tcp(addrs: &[SocketAddr]) -> anyhow::Result<Vec<TcpListener>> {
+    if addrs.is_empty() {
+        return Err(anyhow!("no address to bind to was given"));
+    }
+    addrs
+        .iter()
+        .map(|addr| {
+            let socket = bind_socket(addr, addrs, Type::STREAM, Protocol::TCP)
+                .map_err(|e| anyhow!("could not bind to {}: {}", addr, e))?;
+            socket.listen(LISTEN_BACKLOG)?;
+            Ok(TcpListener::from_std(socket.into())?)
+        })
+        .collect()
+}
+
+/// Bind a UDP socket to each of the given addresses
+#[cfg(feature = "udp")]
+pub(crate) fn bind_udp(addrs: &[SocketAddr]) -> anyhow::Result<Vec<UdpSocket>> {
+    if addrs.is_empty() {
+        return Err(anyhow!("no address to bind to was given"));
+    }
+    addrs
+        .iter()
+        .map(|addr| {
+            let socket = bind_socket(addr, addrs, Type::DGRAM, Protocol::UDP)
+                .map_err(|e| anyhow!("could not bind to {}: {}", addr, e))?;
+            Ok(UdpSocket::from_std(socket.into())?)
+        })
+        .collect()
+}
+
+/// Format addresses for log messages and listener names
+pub(crate) fn fmt_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Serve all bound sockets concurrently until one of them fails
+///
+/// The sockets are served by tasks of their own which are aborted once the returned future is dropped.
+pub(crate) async fn serve_all<T, F, Fut>(sockets: Vec<T>, mut serve: F) -> anyhow::Result<!>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = anyhow::Result<!>> + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for socket in sockets {
+        tasks.spawn(serve(socket));
+    }
+    match tasks.join_next().await {
+        Some(result) => result?,
+        None => Err(anyhow!("there are no sockets to serve")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_only_v6() {
+        let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 1234));
+        let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 1234));
+        let v6_local = SocketAddr::from((Ipv6Addr::LOCALHOST, 1234));
+        assert!(!only_v6(&v6, &[v6]));
+        assert!(only_v6(&v6, &[v4, v6]));
+        assert!(!only_v6(
+            &v6,
+            &[SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80)), v6]
+        ));
+        assert!(only_v6(&v6_local, &[v6_local]));
+    }
+
+    #[tokio::test]
+    async fn test_bind_multiple() {
+        let addrs = [
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        ];
+        let listeners = bind_tcp(&addrs).unwrap();
+        let bound = listeners
+            .iter()
+            .map(|i| i.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        assert_ne!(bound[0], bound[1]);
+
+        tokio::spawn(serve_all(listeners, |listener| async move {
+            loop {
+                let (mut stream, _) = listener.accept().await?;
+                stream.write_all(b"hi").await?;
+            }
+        }));
+        for addr in bound {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+        }
+        assert!(bind_tcp(&[]).is_err());
+    }
+}
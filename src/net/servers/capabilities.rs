@@ -0,0 +1,184 @@
+use crate::net::protocol::{Extension, Request};
+
+/// Optional protocol features which a server may offer to its clients
+///
+/// Setting and reading single pixels, `HELLO`, `SIZE` and `QUIET` are always available.
+/// `CLEAR` and `FILL` are controlled separately because they are disabled unless explicitly allowed anyway.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Capability {
+    /// Downloading many pixels at once via `STATE REGION`, `PXRECT` and batched `PX` reads
+    StateDumps,
+    /// Receiving pixel changes via `SUBSCRIBE`
+    Subscriptions,
+    /// Describing the protocol via `HELP`
+    Help,
+    /// Selecting one of the additional canvases via `CANVAS`
+    Canvases,
+    /// Authenticating and coordinating as a team via `AUTH`, `RESERVE`, `CLAIM` and `CLAIMS`
+    Teams,
+    /// Looking up written pixels via `STATS`
+    Stats,
+}
+
+impl Capability {
+    /// All capabilities that exist
+    pub const ALL: [Capability; 6] = [
+        Capability::StateDumps,
+        Capability::Subscriptions,
+        Capability::Help,
+        Capability::Canvases,
+        Capability::Teams,
+        Capability::Stats,
+    ];
+
+    /// The capability which a server must have to handle a request or `None` if every server handles it
+    pub fn required_by(request: &Request) -> Option<Self> {
+        match request {
+            Request::GetPixels { .. } | Request::GetRegion { .. } | Request::GetRect { .. } => {
+                Some(Capability::StateDumps)
+            }
+            Request::Subscribe(_) | Request::SubscribeRegion { .. } => Some(Capability::Subscriptions),
+            Request::Help(_) => Some(Capability::Help),
+            Request::SelectCanvas(_) => Some(Capability::Canvases),
+            Request::Auth { .. } | Request::Reserve { .. } | Request::Claim { .. } | Request::GetClaims => {
+                Some(Capability::Teams)
+            }
+            Request::GetStats => Some(Capability::Stats),
+            Request::Hello { .. }
+            | Request::GetSize
+            | Request::GetPixel { .. }
+            | Request::SetPixel { .. }
+            | Request::BlendPixel { .. }
+            | Request::Quiet(_)
+            | Request::Clear
+            | Request::Fill(_) => None,
+        }
+    }
+
+    /// The capability which a server must have to support a protocol extension or `None` if it is always supported
+    pub fn required_by_extension(extension: Extension) -> Option<Self> {
+        match extension {
+            Extension::StateRegion | Extension::PxBatch | Extension::PxRect => Some(Capability::StateDumps),
+            Extension::Subscribe => Some(Capability::Subscriptions),
+            Extension::Canvas => Some(Capability::Canvases),
+            Extension::Claims => Some(Capability::Teams),
+            Extension::Stats => Some(Capability::Stats),
+            Extension::BinaryPx | Extension::Fill => None,
+        }
+    }
+
+    /// The error with which requests are rejected when the server lacks this capability
+    pub(crate) fn rejection(&self) -> &'static str {
+        match self {
+            Capability::StateDumps => "downloading the canvas is not enabled on this server",
+            Capability::Subscriptions => "subscriptions are not enabled on this server",
+            Capability::Help => "HELP is not enabled on this server",
+            Capability::Canvases => "selecting canvases is not enabled on this server",
+            Capability::Teams => "team commands are not enabled on this server",
+            Capability::Stats => "STATS is not enabled on this server",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// A set of [`Capability`]s which a server offers
+///
+/// Servers offer all capabilities by default. Hardened servers start from [`Capabilities::none()`] and only offer
+/// what the operator grants explicitly.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capabilities {
+    bits: u8,
+}
+
+impl Capabilities {
+    /// A set of all capabilities
+    pub fn all() -> Self {
+        Capability::ALL.into_iter().collect()
+    }
+
+    /// An empty set which only allows the always available commands
+    pub fn none() -> Self {
+        Self { bits: 0 }
+    }
+
+    /// Add a capability to the set
+    pub fn with(mut self, capability: Capability) -> Self {
+        self.bits |= capability.bit();
+        self
+    }
+
+    /// Whether the set contains a capability
+    #[inline]
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.bits & capability.bit() != 0
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        iter.into_iter().fold(Self::none(), Self::with)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::servers::{handle_request, Session};
+    use crate::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::none().with(Capability::Help);
+        assert!(capabilities.contains(Capability::Help));
+        assert!(!capabilities.contains(Capability::StateDumps));
+        assert!(Capability::ALL
+            .into_iter()
+            .all(|i| Capabilities::all().contains(i)));
+    }
+
+    #[test]
+    fn test_hardened_session() {
+        let pixmap = Arc::new(
+            Pixmap::new(4, 4)
+                .unwrap()
+                .with_change_broadcast(16, Default::default()),
+        );
+        let mut session = Session::default().with_capabilities(Capabilities::none().with(Capability::Help));
+        let mut handle = |line: &str| handle_request(line.as_bytes(), &pixmap, &mut session);
+
+        assert_eq!(handle("PX 1 1 FF0000"), Ok(None));
+        assert!(handle("PX 1 1").is_ok());
+        assert!(handle("HELP").is_ok());
+        assert_eq!(
+            handle("STATE REGION 0 0 2 2 rgb64"),
+            Err("downloading the canvas is not enabled on this server".to_string())
+        );
+        assert_eq!(
+            handle("PX 0 0 1 1"),
+            Err("downloading the canvas is not enabled on this server".to_string())
+        );
+        assert_eq!(
+            handle("SUBSCRIBE"),
+            Err("subscriptions are not enabled on this server".to_string())
+        );
+        assert_eq!(
+            handle("CLAIMS"),
+            Err("team commands are not enabled on this server".to_string())
+        );
+        // extensions which the server doesn't offer are not confirmed
+        assert_eq!(
+            handle("HELLO test/1.0 state-region px-rect binary-px").map(|i| i.unwrap().to_string()),
+            Ok(format!("HELLO pixeldike/{} binary-px", env!("CARGO_PKG_VERSION")))
+        );
+    }
+}
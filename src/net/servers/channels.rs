@@ -0,0 +1,59 @@
+use crate::net::servers::Session;
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::mem;
+
+/// How many channels a single connection may use at once
+pub(crate) const MAX_CHANNELS: usize = 1024;
+
+/// State of the multiplexed channels of one connection
+///
+/// Every channel has a session of its own so that e.g. `QUIET`, `CANVAS` and `AUTH` on one channel don't affect the
+/// others. Rate limits, client statistics, the user agent, subscriptions and the connection summary belong to the
+/// connection though and are lent to a channel's session while it handles a request.
+#[derive(Debug, Default)]
+pub(crate) struct Channels {
+    /// Responses to one multiplexed line before they are framed with its channel id
+    pub(crate) buf: BytesMut,
+    sessions: HashMap<u32, Session>,
+}
+
+impl Channels {
+    /// Forget all channels, e.g. before the state is reused for another connection
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+        self.sessions.clear();
+    }
+
+    /// Run `f` with the session of `channel` which starts out like a new connection of `connection`
+    ///
+    /// Returns `None` without running `f` if the connection already uses too many channels.
+    pub(crate) fn with_session<T>(
+        &mut self,
+        channel: u32,
+        connection: &mut Session,
+        f: impl FnOnce(&mut Session) -> T,
+    ) -> Option<T> {
+        if self.sessions.len() >= MAX_CHANNELS && !self.sessions.contains_key(&channel) {
+            return None;
+        }
+        let session = self
+            .sessions
+            .entry(channel)
+            .or_insert_with(|| connection.for_channel());
+        lend(connection, session);
+        let result = f(session);
+        lend(session, connection);
+        Some(result)
+    }
+}
+
+/// Move the state which is shared by all channels of a connection from one session to another
+fn lend(from: &mut Session, to: &mut Session) {
+    mem::swap(&mut from.rate_limiter, &mut to.rate_limiter);
+    mem::swap(&mut from.client, &mut to.client);
+    mem::swap(&mut from.user_agent, &mut to.user_agent);
+    mem::swap(&mut from.summary, &mut to.summary);
+    mem::swap(&mut from.subscribed, &mut to.subscribed);
+    mem::swap(&mut from.watched_region, &mut to.watched_region);
+}
@@ -0,0 +1,458 @@
+//! Per-client statistics of written pixels whose memory stays bounded no matter how many clients there are
+//!
+//! Clients are grouped into buckets by a prefix of their IP address, e.g. a `/24` IPv4 network or a `/64` IPv6
+//! subnet, so that a client which rotates through the addresses of its network still counts as one.
+//! Since the source addresses of UDP datagrams can be spoofed, the number of buckets is unbounded nonetheless.
+//! Pixels are therefore counted in a count-min sketch of fixed size, which may overestimate but never underestimates
+//! the count of a bucket, and only the busiest buckets according to it are remembered by name.
+
+use std::fmt::{Display, Formatter, Write};
+use std::hash::{BuildHasher, RandomState};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Counters in each row of the count-min sketch
+const SKETCH_WIDTH: usize = 4096;
+
+/// Rows of the count-min sketch, each of which hashes buckets independently
+const SKETCH_DEPTH: usize = 4;
+
+/// Clients report their written pixels in batches of this size so that they don't contend on the shared counters
+const REPORT_BATCH: u64 = 1024;
+
+/// The statistics of this process once they have been enabled
+static CLIENT_STATS: OnceLock<ClientStats> = OnceLock::new();
+
+/// How clients are grouped and how many of them are remembered
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClientStatsOptions {
+    /// The prefix length by which IPv4 clients are grouped, 32 to count every address separately
+    pub ipv4_prefix: u8,
+    /// The prefix length by which IPv6 clients are grouped
+    pub ipv6_prefix: u8,
+    /// How many of the busiest buckets are remembered by name
+    pub top: usize,
+}
+
+impl Default for ClientStatsOptions {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            top: 32,
+        }
+    }
+}
+
+/// A network of IP addresses whose clients are counted together
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ClientBucket {
+    /// The first address of the network
+    pub network: IpAddr,
+    /// The number of leading bits which all addresses of the network share
+    pub prefix_len: u8,
+}
+
+impl ClientBucket {
+    /// The bucket into which `addr` falls when grouping by the given prefix lengths
+    ///
+    /// IPv4 addresses which are mapped into IPv6, as reported by dual-stack sockets, are treated as IPv4.
+    pub fn of(addr: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        match addr.to_canonical() {
+            IpAddr::V4(addr) => {
+                let prefix_len = ipv4_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+                Self {
+                    network: Ipv4Addr::from(u32::from(addr) & mask).into(),
+                    prefix_len,
+                }
+            }
+            IpAddr::V6(addr) => {
+                let prefix_len = ipv6_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+                Self {
+                    network: Ipv6Addr::from(u128::from(addr) & mask).into(),
+                    prefix_len,
+                }
+            }
+        }
+    }
+}
+
+impl Display for ClientBucket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// A bucket among the busiest with its estimated pixel count and the user agent which one of its clients last sent
+/// via `HELLO`
+pub type TopClient = (ClientBucket, u64, Option<Arc<str>>);
+
+/// Counts of the pixels which clients have written
+#[derive(Debug)]
+pub struct ClientStats {
+    options: ClientStatsOptions,
+    /// One randomly seeded hasher per row so that attackers can't craft addresses which collide in all rows
+    hashers: [RandomState; SKETCH_DEPTH],
+    /// The count-min sketch with its rows stored one after another
+    sketch: Box<[AtomicU64]>,
+    /// The pixels written by all clients
+    total: AtomicU64,
+    /// The estimated count which a bucket must reach to displace one of the top buckets, 0 while there is room
+    threshold: AtomicU64,
+    /// The busiest buckets with their estimated counts and the latest user agent of their clients in no particular
+    /// order
+    top: Mutex<Vec<TopClient>>,
+}
+
+impl ClientStats {
+    /// Create empty statistics
+    pub fn new(options: ClientStatsOptions) -> Self {
+        Self {
+            options,
+            hashers: std::array::from_fn(|_| RandomState::new()),
+            sketch: (0..SKETCH_WIDTH * SKETCH_DEPTH)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            total: AtomicU64::new(0),
+            threshold: AtomicU64::new(0),
+            top: Mutex::new(Vec::with_capacity(options.top)),
+        }
+    }
+
+    /// The options with which the statistics were created
+    pub fn options(&self) -> ClientStatsOptions {
+        self.options
+    }
+
+    /// The bucket in which the client with the given address is counted
+    pub fn bucket(&self, addr: IpAddr) -> ClientBucket {
+        ClientBucket::of(addr, self.options.ipv4_prefix, self.options.ipv6_prefix)
+    }
+
+    /// The indices of the counters of a bucket, one in every row of the sketch
+    fn counters(&self, bucket: ClientBucket) -> impl Iterator<Item = usize> + '_ {
+        self.hashers
+            .iter()
+            .enumerate()
+            .map(move |(row, hasher)| row * SKETCH_WIDTH + hasher.hash_one(bucket) as usize % SKETCH_WIDTH)
+    }
+
+    /// Count pixels which were written by the client with the given address
+    pub fn record(&self, addr: IpAddr, pixels: u64) {
+        self.record_agent(addr, pixels, None);
+    }
+
+    /// Count pixels like [`record`](Self::record) and remember the user agent which the client sent via `HELLO` if
+    /// its bucket is among the busiest
+    fn record_agent(&self, addr: IpAddr, pixels: u64, user_agent: Option<&Arc<str>>) {
+        if pixels == 0 {
+            return;
+        }
+        self.total.fetch_add(pixels, Ordering::Relaxed);
+        let bucket = self.bucket(addr);
+        let estimate = self
+            .counters(bucket)
+            .map(|i| self.sketch[i].fetch_add(pixels, Ordering::Relaxed) + pixels)
+            .min()
+            .unwrap_or(0);
+        // most clients are not among the busiest so the lock is only taken for those which might be
+        if estimate < self.threshold.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut top = self.top.lock().unwrap();
+        match top.iter().position(|(i, _, _)| *i == bucket) {
+            Some(i) => {
+                top[i].1 = estimate.max(top[i].1);
+                if user_agent.is_some() {
+                    top[i].2 = user_agent.cloned();
+                }
+            }
+            None if top.len() < self.options.top => top.push((bucket, estimate, user_agent.cloned())),
+            None => {
+                if let Some(min) = top.iter_mut().min_by_key(|(_, count, _)| *count) {
+                    if min.1 < estimate {
+                        *min = (bucket, estimate, user_agent.cloned());
+                    }
+                }
+            }
+        }
+        if top.len() >= self.options.top {
+            let min = top.iter().map(|(_, count, _)| *count).min().unwrap_or(u64::MAX);
+            self.threshold.store(min, Ordering::Relaxed);
+        }
+    }
+
+    /// The estimated number of pixels which were written from the bucket of the given address
+    pub fn estimate(&self, addr: IpAddr) -> u64 {
+        self.counters(self.bucket(addr))
+            .map(|i| self.sketch[i].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// The busiest buckets with their estimated pixel counts, busiest first
+    pub fn top(&self) -> Vec<(ClientBucket, u64)> {
+        self.top_clients()
+            .into_iter()
+            .map(|(bucket, count, _)| (bucket, count))
+            .collect()
+    }
+
+    /// The busiest buckets like [`top`](Self::top) together with their user agents
+    pub fn top_clients(&self) -> Vec<TopClient> {
+        let mut top = self.top.lock().unwrap().clone();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.network.cmp(&b.0.network)));
+        top
+    }
+
+    /// The number of pixels which were written by all clients together
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// A one-line summary of the busiest `len` client networks, e.g. for periodic log messages
+    pub fn leaderboard(&self, len: usize) -> String {
+        let top = self
+            .top_clients()
+            .into_iter()
+            .take(len)
+            .enumerate()
+            .map(|(i, (bucket, count, user_agent))| match user_agent {
+                Some(user_agent) => format!("{}. {} ({} px, {})", i + 1, bucket, count, user_agent),
+                None => format!("{}. {} ({} px)", i + 1, bucket, count),
+            })
+            .collect::<Vec<_>>();
+        match top.is_empty() {
+            true => format!("no client has written pixels, {} in total", self.total()),
+            false => format!("{}, {} px in total", top.join(", "), self.total()),
+        }
+    }
+}
+
+/// Start counting the pixels which are written by each client of the servers of this process
+///
+/// Statistics can only be enabled once, later calls return the existing statistics without changing their options.
+pub fn enable_client_stats(options: ClientStatsOptions) -> &'static ClientStats {
+    CLIENT_STATS.get_or_init(|| ClientStats::new(options))
+}
+
+/// The statistics of the servers of this process or `None` if they are not enabled
+pub fn client_stats() -> Option<&'static ClientStats> {
+    CLIENT_STATS.get()
+}
+
+/// Pixels which a connection has written but not yet reported to the [`ClientStats`]
+///
+/// The remaining pixels are reported when the counter is dropped.
+#[derive(Debug)]
+pub(crate) struct ClientCounter {
+    stats: &'static ClientStats,
+    addr: IpAddr,
+    user_agent: Option<Arc<str>>,
+    pending: u64,
+}
+
+impl ClientCounter {
+    /// Count the pixels of the client with the given address or return `None` if client statistics are disabled
+    #[cfg(any(feature = "tcp", feature = "udp", feature = "ws", test))]
+    pub(crate) fn new(addr: IpAddr) -> Option<Self> {
+        client_stats().map(|stats| Self {
+            stats,
+            addr,
+            user_agent: None,
+            pending: 0,
+        })
+    }
+
+    /// Count one written pixel
+    #[inline(always)]
+    pub(crate) fn count(&mut self) {
+        self.pending += 1;
+        if self.pending >= REPORT_BATCH {
+            self.flush();
+        }
+    }
+
+    /// Report all pending pixels right away, e.g. before the statistics are looked up
+    pub(crate) fn flush(&mut self) {
+        self.stats
+            .record_agent(self.addr, mem::take(&mut self.pending), self.user_agent.as_ref());
+    }
+
+    /// Attribute the pixels of the client to the user agent which it sent via `HELLO`
+    ///
+    /// The pixels which were counted before are reported first so that they are not attributed to it.
+    pub(crate) fn set_user_agent(&mut self, user_agent: Arc<str>) {
+        self.flush();
+        self.user_agent = Some(user_agent);
+    }
+
+    /// The address of the counted client
+    pub(crate) fn addr(&self) -> IpAddr {
+        self.addr
+    }
+}
+
+impl Clone for ClientCounter {
+    fn clone(&self) -> Self {
+        // the pending pixels are reported by the original
+        Self {
+            stats: self.stats,
+            addr: self.addr,
+            user_agent: self.user_agent.clone(),
+            pending: 0,
+        }
+    }
+}
+
+impl Drop for ClientCounter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Write the pixel counts of the busiest clients in the prometheus text exposition format
+pub(crate) fn write_prometheus(out: &mut impl Write) -> std::fmt::Result {
+    let Some(stats) = client_stats() else {
+        return Ok(());
+    };
+
+    const TOTAL: &str = "pixeldike_written_pixels_total";
+    writeln!(out, "# HELP {TOTAL} Pixels written by all clients")?;
+    writeln!(out, "# TYPE {TOTAL} counter")?;
+    writeln!(out, "{TOTAL} {}", stats.total())?;
+
+    const CLIENT: &str = "pixeldike_client_written_pixels_total";
+    writeln!(
+        out,
+        "# HELP {CLIENT} Estimated pixels written by the busiest client networks"
+    )?;
+    writeln!(out, "# TYPE {CLIENT} counter")?;
+    for (bucket, count) in stats.top() {
+        writeln!(out, "{CLIENT}{{client=\"{bucket}\"}} {count}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::Response;
+    use crate::net::servers::{handle_request, Session};
+    use crate::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_bucket() {
+        let bucket = |addr: &str| ClientBucket::of(addr.parse().unwrap(), 24, 48).to_string();
+        assert_eq!(bucket("192.0.2.17"), "192.0.2.0/24");
+        assert_eq!(bucket("::ffff:192.0.2.17"), "192.0.2.0/24");
+        assert_eq!(bucket("2001:db8:1:2:3::1"), "2001:db8:1::/48");
+        assert_eq!(
+            ClientBucket::of("192.0.2.17".parse().unwrap(), 0, 0).to_string(),
+            "0.0.0.0/0"
+        );
+    }
+
+    #[test]
+    fn test_top_clients_are_bounded() {
+        let stats = ClientStats::new(ClientStatsOptions {
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            top: 4,
+        });
+        let busy: IpAddr = "192.0.2.1".parse().unwrap();
+        // a flood of spoofed addresses which each write a few pixels
+        for i in 0..100_000u32 {
+            stats.record(IpAddr::V4(Ipv4Addr::from(0x0A00_0000 + i)), 2);
+            if i % 100 == 0 {
+                stats.record(busy, 10);
+            }
+        }
+
+        assert_eq!(stats.total(), 100_000 * 2 + 1000 * 10);
+        assert!(stats.estimate(busy) >= 10_000);
+        let top = stats.top();
+        assert_eq!(top.len(), 4);
+        assert_eq!(top[0].0.network, busy);
+        assert!(top[0].1 >= 10_000);
+    }
+
+    #[test]
+    fn test_sessions_count_pixels() {
+        let stats = enable_client_stats(ClientStatsOptions::default());
+        let addr: IpAddr = "198.51.100.7".parse().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut session = Session::default();
+        session.set_client_addr(addr);
+        for line in ["PX 0 0 ff0000", "PX 1 0 00ff0080", "PX 0 0", "PX 9 9 ff0000"] {
+            let _ = handle_request(line.as_bytes(), &pixmap, &mut session);
+        }
+        // pixels are reported once the connection is gone
+        drop(session);
+        assert_eq!(stats.estimate(addr), 2);
+
+        let mut out = String::new();
+        write_prometheus(&mut out).unwrap();
+        assert!(out.contains("pixeldike_client_written_pixels_total{client=\"198.51.100.7/32\"} 2\n"));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let stats = enable_client_stats(ClientStatsOptions::default());
+        let addr: IpAddr = "198.51.100.11".parse().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut session = Session::default();
+        session.set_client_addr(addr);
+        for line in ["HELLO pixelpwnr/0.1", "PX 0 0 ff0000", "PX 1 0 ff0000"] {
+            handle_request(line.as_bytes(), &pixmap, &mut session).unwrap();
+        }
+        drop(session);
+
+        let bucket = stats.bucket(addr);
+        let (_, _, user_agent) = stats
+            .top_clients()
+            .into_iter()
+            .find(|(i, _, _)| *i == bucket)
+            .unwrap();
+        assert_eq!(user_agent.as_deref(), Some("pixelpwnr/0.1"));
+        assert!(stats
+            .leaderboard(usize::MAX)
+            .contains("198.51.100.11/32 (2 px, pixelpwnr/0.1)"));
+    }
+
+    #[test]
+    fn test_stats_command() {
+        enable_client_stats(ClientStatsOptions::default());
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut session = Session::default();
+        session.set_client_addr("198.51.100.9".parse().unwrap());
+        for line in ["PX 0 0 ff0000", "PX 1 0 ff0000", "PX 2 0 ff0000"] {
+            handle_request(line.as_bytes(), &pixmap, &mut session).unwrap();
+        }
+
+        // pending pixels are included without waiting for the connection to close
+        let response = handle_request(b"STATS", &pixmap, &mut session).unwrap().unwrap();
+        let Response::Stats { pixels, total, rank } = response else {
+            panic!("unexpected response {response:?}");
+        };
+        assert_eq!(pixels, 3);
+        assert!(total >= 3);
+        assert!(rank.is_some());
+
+        // connections without an address have not written anything that could be attributed to them
+        assert!(matches!(
+            handle_request(b"STATS", &pixmap, &mut Session::default()),
+            Ok(Some(Response::Stats {
+                pixels: 0,
+                rank: None,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// Responses smaller than this are sent uncompressed because compression would barely save anything
+pub(crate) const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// The brotli quality which is used for responses
+///
+/// The highest qualities are too slow to compress a whole canvas for every change while this one still compresses
+/// much better than gzip.
+const BROTLI_QUALITY: u32 = 5;
+
+/// The base-2 logarithm of the brotli window size
+const BROTLI_WINDOW: u32 = 22;
+
+/// A `Content-Encoding` in which the HTTP server can send responses
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// Choose the best encoding which the client accepts according to its `Accept-Encoding` header
+    ///
+    /// Brotli is preferred over gzip regardless of the clients weights since it is considerably smaller for
+    /// canvas data. Encodings with a weight of `q=0` are never chosen.
+    pub(crate) fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let accepted = |name: &str| {
+            accept_encoding.unwrap_or_default().split(',').any(|i| {
+                let mut params = i.split(';').map(str::trim);
+                let matches = params
+                    .next()
+                    .is_some_and(|coding| coding.eq_ignore_ascii_case(name));
+                let refused = params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                matches && !refused
+            })
+        };
+        if accepted("br") {
+            ContentEncoding::Brotli
+        } else if accepted("gzip") {
+            ContentEncoding::Gzip
+        } else {
+            ContentEncoding::Identity
+        }
+    }
+
+    /// The value of the `Content-Encoding` header for this encoding if one is sent
+    pub(crate) fn header_value(&self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Brotli => Some("br"),
+        }
+    }
+
+    /// Encode `data` with this encoding
+    pub(crate) fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Identity => Ok(data.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder =
+                        brotli::CompressorWriter::new(&mut encoded, 64 * 1024, BROTLI_QUALITY, BROTLI_WINDOW);
+                    encoder.write_all(data)?;
+                    encoder.flush()?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// The compressed variants of one response for the canvas generation from which they were created
+///
+/// Encoding a whole canvas is expensive, so viewers which poll an unchanged canvas are served from this cache.
+/// See [`Pixmap::generation`](crate::pixmap::Pixmap::generation) for how generations are determined.
+#[derive(Debug, Default)]
+pub(crate) struct CompressionCache {
+    gzip: Mutex<Option<(u64, Arc<[u8]>)>>,
+    brotli: Mutex<Option<(u64, Arc<[u8]>)>>,
+}
+
+impl CompressionCache {
+    /// Get the response body in the given encoding for a canvas generation
+    ///
+    /// `render` creates the uncompressed body and is only called if the cache holds no variant of that generation.
+    /// Concurrent requests for the same variant wait for one of them to compress it.
+    pub(crate) fn get_or_compress(
+        &self,
+        encoding: ContentEncoding,
+        generation: u64,
+        render: impl FnOnce() -> Vec<u8>,
+    ) -> std::io::Result<Arc<[u8]>> {
+        let slot = match encoding {
+            ContentEncoding::Identity => return Ok(render().into()),
+            ContentEncoding::Gzip => &self.gzip,
+            ContentEncoding::Brotli => &self.brotli,
+        };
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+        match &*slot {
+            Some((cached_generation, body)) if *cached_generation == generation => Ok(body.clone()),
+            _ => {
+                let body: Arc<[u8]> = encoding.compress(&render())?.into();
+                *slot = Some((generation, body.clone()));
+                Ok(body)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(ContentEncoding::negotiate(None), ContentEncoding::Identity);
+        assert_eq!(
+            ContentEncoding::negotiate(Some("gzip, deflate, br")),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(
+            ContentEncoding::negotiate(Some("br;q=0, GZIP;q=0.5")),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            ContentEncoding::negotiate(Some("deflate, brotli")),
+            ContentEncoding::Identity
+        );
+    }
+
+    #[test]
+    fn test_compression_cache() {
+        let data = vec![7u8; 10_000];
+        let cache = CompressionCache::default();
+
+        let gzip = cache
+            .get_or_compress(ContentEncoding::Gzip, 1, || data.clone())
+            .unwrap();
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let brotli = cache
+            .get_or_compress(ContentEncoding::Brotli, 1, || data.clone())
+            .unwrap();
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&brotli[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let cached = cache
+            .get_or_compress(ContentEncoding::Gzip, 1, || unreachable!())
+            .unwrap();
+        assert!(Arc::ptr_eq(&cached, &gzip));
+        let renewed = cache
+            .get_or_compress(ContentEncoding::Gzip, 2, || data.clone())
+            .unwrap();
+        assert!(!Arc::ptr_eq(&renewed, &gzip));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// The message with which clients are rejected while a server already serves as many clients as it may
+pub(crate) const TOO_MANY_CONNECTIONS_MESSAGE: &str = "too many connections, please try again later";
+
+/// The message which is sent to clients before their connection is closed because it was idle
+pub(crate) const IDLE_TIMEOUT_MESSAGE: &str = "connection was idle for too long";
+
+/// Limits how many clients are served at once by all listeners of one server
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionLimit {
+    permits: Option<Arc<Semaphore>>,
+}
+
+/// Allows one client to be served and frees its slot again once it is dropped
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimit {
+    /// Create a limit of `max` concurrent connections or no limit at all
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            permits: max.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    /// Take a slot for a new connection unless all of them are in use
+    pub(crate) fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let permit = match &self.permits {
+            None => None,
+            Some(permits) => Some(permits.clone().try_acquire_owned().ok()?),
+        };
+        Some(ConnectionPermit { _permit: permit })
+    }
+}
+
+/// Tracks when a connection was last active so that it can be closed once it was idle for too long
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct IdleTimer {
+    timeout: Option<Duration>,
+    deadline: Instant,
+}
+
+impl IdleTimer {
+    /// Create a timer which expires once nothing happened for `timeout` or never if no timeout is given
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        let mut timer = Self {
+            timeout,
+            deadline: Instant::now(),
+        };
+        timer.reset();
+        timer
+    }
+
+    /// Note that the connection is active right now
+    pub(crate) fn reset(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.deadline = Instant::now() + timeout;
+        }
+    }
+
+    /// Wait until the connection has been idle for longer than the timeout
+    ///
+    /// This is cancel safe and never returns if no timeout is configured.
+    pub(crate) async fn expired(&self) {
+        match self.timeout {
+            None => std::future::pending().await,
+            Some(_) => tokio::time::sleep_until(self.deadline).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(Some(1));
+        let permit = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(permit);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = ConnectionLimit::new(None);
+        let _permits = (0..16)
+            .map(|_| unlimited.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+    }
+}
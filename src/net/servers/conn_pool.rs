@@ -0,0 +1,232 @@
+//! Reuse of the buffers and session state of stream connections
+//!
+//! Events typically see thousands of short-lived connections from clients which connect, flood a few lines and
+//! disconnect again.
+//! Instead of allocating fresh buffers and session state for each of them, stream servers take them from a
+//! [`ConnectionPool`] and return them once the client has disconnected.
+
+use crate::net::servers::{Channels, Session};
+use bytes::BytesMut;
+use std::fmt::Write;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// How many idle connection states a listener keeps by default
+pub const DEFAULT_CONNECTION_POOL_SIZE: usize = 256;
+
+/// Buffers which have grown beyond this capacity are not kept in a pool so that idle connections don't pin memory
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// All pools which currently exist so that their statistics can be exported
+static POOLS: Mutex<Vec<Weak<ConnectionPool>>> = Mutex::new(Vec::new());
+
+/// Everything that a stream connection needs while it is being handled
+#[derive(Debug)]
+pub(crate) struct ConnectionState {
+    /// Memory for the connection's read buffer
+    pub(crate) read_buf: BytesMut,
+    /// Responses which are waiting to be sent to the client
+    pub(crate) resp_buf: BytesMut,
+    /// The sessions of multiplexed channels and their scratch buffer
+    pub(crate) channels: Channels,
+    /// State of the client which is kept between requests
+    pub(crate) session: Session,
+}
+
+impl ConnectionState {
+    /// The largest capacity of any of the buffers
+    fn capacity(&self) -> usize {
+        [&self.read_buf, &self.resp_buf, &self.channels.buf]
+            .into_iter()
+            .map(BytesMut::capacity)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// A pool of connection states which are kept for reuse by the next clients of one listener
+#[derive(Debug)]
+pub(crate) struct ConnectionPool {
+    /// How the pool is identified in metrics, usually the listen url
+    name: String,
+    /// The session with which every new connection starts
+    template: Session,
+    /// How many idle states are kept at most
+    max_idle: usize,
+    idle: Mutex<Vec<ConnectionState>>,
+    /// How many connections got a reused state
+    hits: AtomicU64,
+    /// How many connections needed a newly allocated state
+    misses: AtomicU64,
+    /// How many states were dropped instead of being returned into the pool
+    discarded: AtomicU64,
+}
+
+impl ConnectionPool {
+    /// Create a pool which keeps up to `max_idle` states and hands them out with a session like `template`
+    pub(crate) fn new(name: impl Into<String>, template: Session, max_idle: usize) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            name: name.into(),
+            template,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        });
+        let mut pools = POOLS.lock().unwrap();
+        pools.retain(|pool| pool.strong_count() > 0);
+        pools.push(Arc::downgrade(&pool));
+        pool
+    }
+
+    /// Take a state for a newly connected client which is returned into the pool when it is dropped
+    pub(crate) fn acquire(self: &Arc<Self>) -> PooledConnection {
+        let state = match self.idle.lock().unwrap().pop() {
+            Some(mut state) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                state.session.reset_from(&self.template);
+                state
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                ConnectionState {
+                    read_buf: BytesMut::new(),
+                    resp_buf: BytesMut::new(),
+                    channels: Channels::default(),
+                    session: self.template.clone(),
+                }
+            }
+        };
+        PooledConnection {
+            state: Some(state),
+            pool: self.clone(),
+        }
+    }
+
+    fn release(&self, mut state: ConnectionState) {
+        state.read_buf.clear();
+        state.resp_buf.clear();
+        state.channels.clear();
+        // the client's pixels are reported when it disconnects instead of when the state is reused
+        state.session.client = None;
+        if state.capacity() <= MAX_POOLED_CAPACITY {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < self.max_idle {
+                idle.push(state);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection state which is borrowed from a [`ConnectionPool`]
+#[derive(Debug)]
+pub(crate) struct PooledConnection {
+    /// Always `Some` until the state is handed back on drop
+    state: Option<ConnectionState>,
+    pool: Arc<ConnectionPool>,
+}
+
+impl Deref for PooledConnection {
+    type Target = ConnectionState;
+
+    fn deref(&self) -> &Self::Target {
+        self.state.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.state.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.pool.release(state);
+        }
+    }
+}
+
+/// Write the statistics of all connection pools in the prometheus text exposition format
+pub(crate) fn write_prometheus(out: &mut impl Write) -> std::fmt::Result {
+    let pools = POOLS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    if pools.is_empty() {
+        return Ok(());
+    }
+
+    const ACQUIRED: &str = "pixeldike_connection_pool_acquired_total";
+    writeln!(
+        out,
+        "# HELP {ACQUIRED} Connection states taken from a pool, either reused or newly allocated"
+    )?;
+    writeln!(out, "# TYPE {ACQUIRED} counter")?;
+    for pool in &pools {
+        let name = &pool.name;
+        let hits = pool.hits.load(Ordering::Relaxed);
+        let misses = pool.misses.load(Ordering::Relaxed);
+        writeln!(out, "{ACQUIRED}{{pool=\"{name}\",result=\"reused\"}} {hits}")?;
+        writeln!(out, "{ACQUIRED}{{pool=\"{name}\",result=\"allocated\"}} {misses}")?;
+    }
+
+    const DISCARDED: &str = "pixeldike_connection_pool_discarded_total";
+    writeln!(
+        out,
+        "# HELP {DISCARDED} Connection states which were dropped because the pool was full or they were too large"
+    )?;
+    writeln!(out, "# TYPE {DISCARDED} counter")?;
+    for pool in &pools {
+        let discarded = pool.discarded.load(Ordering::Relaxed);
+        writeln!(out, "{DISCARDED}{{pool=\"{}\"}} {discarded}", pool.name)?;
+    }
+
+    const IDLE: &str = "pixeldike_connection_pool_idle";
+    writeln!(
+        out,
+        "# HELP {IDLE} Connection states which are currently kept for reuse"
+    )?;
+    writeln!(out, "# TYPE {IDLE} gauge")?;
+    for pool in &pools {
+        let idle = pool.idle.lock().unwrap().len();
+        writeln!(out, "{IDLE}{{pool=\"{}\"}} {idle}", pool.name)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let pool = ConnectionPool::new("test", Session::default(), 1);
+
+        let mut first = pool.acquire();
+        first.resp_buf.extend_from_slice(b"SIZE 800 600\n");
+        first.session.team = Some("red".to_string());
+        let second = pool.acquire();
+        drop(first);
+        drop(second);
+
+        // only one state fits into the pool and it is handed out again in a fresh state
+        let reused = pool.acquire();
+        assert!(reused.resp_buf.is_empty() && reused.resp_buf.capacity() > 0);
+        assert_eq!(reused.session.team, None);
+        assert_eq!(pool.hits.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.misses.load(Ordering::Relaxed), 2);
+        assert_eq!(pool.discarded.load(Ordering::Relaxed), 1);
+
+        let mut out = String::new();
+        write_prometheus(&mut out).unwrap();
+        assert!(out.contains("pixeldike_connection_pool_acquired_total{pool=\"test\",result=\"reused\"} 1\n"));
+    }
+}
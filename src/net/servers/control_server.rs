@@ -0,0 +1,495 @@
+use crate::net::servers::unix_sock_server::{remove_stale_socket, SocketFileGuard};
+use crate::net::servers::GenServer;
+use crate::pixmap::{Color, Effect, SharedPixmap};
+use crate::DaemonResult;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use itertools::Itertools;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{AbortHandle, JoinSet};
+
+/// How many ids of applied control commands are remembered
+pub const REQUEST_ID_CAPACITY: usize = 256;
+
+/// How long the id of a control command may be
+pub const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// The ids of the most recently applied control commands
+///
+/// They are shared by all control connections and should be kept when the control socket is restarted, e.g. after
+/// a resize, so that retries of a command are recognized on every new connection.
+#[derive(Debug, Default)]
+pub struct RequestIds {
+    ids: Mutex<VecDeque<String>>,
+}
+
+impl RequestIds {
+    /// Create an empty set of request ids
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.lock().unwrap().iter().any(|i| i == id)
+    }
+
+    /// Remember that the command with the given id was applied and forget the oldest id if there are too many
+    fn insert(&self, id: &str) {
+        let mut ids = self.ids.lock().unwrap();
+        if ids.len() >= REQUEST_ID_CAPACITY {
+            ids.pop_front();
+        }
+        ids.push_back(id.to_string());
+    }
+}
+
+/// Options with which the `ControlServer` is configured
+#[derive(Debug, Clone)]
+pub struct ControlServerOptions {
+    /// The path at which the control socket should be created
+    pub path: PathBuf,
+    /// Where `RESIZE` commands are passed on as `(width, height)` or `None` if the server can't be resized
+    ///
+    /// The receiver is responsible for replacing the canvas with a [resized](crate::pixmap::Pixmap::resize) copy
+    /// and for [retiring](crate::pixmap::Pixmap::retire) the current one, which is when the command is answered.
+    pub resize: Option<mpsc::Sender<(usize, usize)>>,
+    /// Where `EFFECT` commands change the [`Effect`] with which the canvas is displayed or `None` if the effect
+    /// pipeline is not enabled
+    pub effect: Option<Arc<watch::Sender<Effect>>>,
+    /// The ids of recently applied commands which are not applied again when they are repeated
+    pub request_ids: Arc<RequestIds>,
+}
+
+/// A server for operator workflows that should not be exposed via the public pixelflut protocol
+///
+/// It listens on a unix socket and speaks a simple line based protocol in which bulk data follows the command line
+/// as raw bytes:
+///
+/// - `EXPORT\n` is answered with `CANVAS <width> <height>\n` followed by `width * height` RGB byte triples.
+/// - `IMPORT <x> <y> <width> <height>\n` followed by `width * height` RGB byte triples writes these pixels onto the
+///   canvas and is answered with `OK\n`.
+/// - `FREEZE\n` freezes the canvas so that all client writes are rejected until `UNFREEZE\n` (see
+///   [`Pixmap::set_frozen`](crate::pixmap::Pixmap::set_frozen)). Both are answered with `OK\n`.
+/// - `RESIZE <width> <height>\n` asks for the canvas to be resized while keeping its content (see
+///   [`ControlServerOptions::resize`]). It is answered with `OK\n` once the canvas was replaced.
+/// - `EFFECT <effect>\n` changes the effect with which display sinks show the canvas (see
+///   [`ControlServerOptions::effect`]) and is answered with `OK\n`.
+/// - `METRICS\n` is answered with `METRICS <length>\n` followed by `length` bytes of request latency metrics in the
+///   prometheus text format (see [`crate::metrics`]).
+///
+/// Failed commands are answered with `ERROR <reason>\n`.
+///
+/// Commands which change the server state, i.e. all except `EXPORT` and `METRICS`, can be prefixed with
+/// `ID <request-id> ` so that they can safely be retried, e.g. after the connection broke before their answer
+/// arrived. The ids of the last [`REQUEST_ID_CAPACITY`] applied commands are remembered (see [`RequestIds`]) and a
+/// command with one of them is only answered with `OK\n` without being applied again. The image data of a repeated
+/// `IMPORT` must still be sent and is skipped.
+///
+/// Once the canvas is replaced, e.g. by a resized copy, every connection is answered with `ERROR <reason>\n` and
+/// closed so that operators reconnect and work on the replacement instead.
+#[derive(Debug, Clone)]
+pub struct ControlServer {
+    options: ControlServerOptions,
+}
+
+impl ControlServer {
+    #[tracing::instrument(skip_all)]
+    async fn handle_listener(
+        listener: UnixListener,
+        pixmap: SharedPixmap,
+        resize: Option<mpsc::Sender<(usize, usize)>>,
+        effect: Option<Arc<watch::Sender<Effect>>>,
+        request_ids: Arc<RequestIds>,
+        _guard: SocketFileGuard,
+    ) -> anyhow::Result<!> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let pixmap = pixmap.clone();
+            let resize = resize.clone();
+            let effect = effect.clone();
+            let request_ids = request_ids.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    ControlServer::handle_connection(stream, pixmap, resize, effect, &request_ids).await
+                {
+                    tracing::warn!("Got error while handling control connection: {e}");
+                }
+            });
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn handle_connection(
+        stream: UnixStream,
+        pixmap: SharedPixmap,
+        resize: Option<mpsc::Sender<(usize, usize)>>,
+        effect: Option<Arc<watch::Sender<Effect>>>,
+        request_ids: &RequestIds,
+    ) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
+        loop {
+            line.clear();
+            // commands which are still buffered must not be applied to a retired canvas
+            let n = tokio::select! {
+                biased;
+                _ = &mut retired => {
+                    writer.write_all(format!("ERROR {}\n", super::RETIRED_MESSAGE).as_bytes()).await?;
+                    return Ok(());
+                }
+                n = reader.read_line(&mut line) => n?,
+            };
+            if n == 0 {
+                return Ok(());
+            }
+
+            let (id, command) = match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+                ["ID", id, ref command @ ..] => (Some(id), command.to_vec()),
+                ref command => (None, command.to_vec()),
+            };
+            let result = match command[..] {
+                ["EXPORT"] => Self::export(&mut writer, &pixmap).await,
+                ["METRICS"] => Self::metrics(&mut writer).await,
+                _ if id.is_some_and(|id| id.len() > MAX_REQUEST_ID_LEN) => Err(anyhow!(
+                    "request ids must not be longer than {} bytes",
+                    MAX_REQUEST_ID_LEN
+                )),
+                _ if id.is_some_and(|id| request_ids.contains(id)) => {
+                    tracing::info!("Not applying repeated control command {:?} again", line.trim());
+                    // the client sends the image data again which is not needed anymore
+                    if let ["IMPORT", _, _, width, height] = command[..] {
+                        if let (Ok(width), Ok(height)) = (width.parse(), height.parse()) {
+                            Self::skip_image_data(&mut reader, width, height).await?;
+                        }
+                    }
+                    writer.write_all(b"OK\n").await.map_err(Into::into)
+                }
+                _ => {
+                    let result = match command[..] {
+                        ["FREEZE"] => Self::freeze(&pixmap, true),
+                        ["UNFREEZE"] => Self::freeze(&pixmap, false),
+                        ["IMPORT", x, y, width, height] => {
+                            match (x.parse(), y.parse(), width.parse(), height.parse()) {
+                                (Ok(x), Ok(y), Ok(width), Ok(height)) => {
+                                    Self::import(&mut reader, &pixmap, x, y, width, height).await
+                                }
+                                _ => Err(anyhow!("invalid IMPORT arguments")),
+                            }
+                        }
+                        ["RESIZE", width, height] => match (width.parse(), height.parse()) {
+                            (Ok(width), Ok(height)) => {
+                                Self::resize(&pixmap, resize.as_ref(), width, height).await
+                            }
+                            _ => Err(anyhow!("invalid RESIZE arguments")),
+                        },
+                        ["EFFECT", spec] => Self::effect(effect.as_deref(), spec),
+                        _ => Err(anyhow!("unknown control command {:?}", line.trim())),
+                    };
+                    // the id is remembered before answering so that a retry is recognized even if the answer is lost
+                    if let (Ok(()), Some(id)) = (&result, id) {
+                        request_ids.insert(id);
+                    }
+                    match result {
+                        Ok(()) => writer.write_all(b"OK\n").await.map_err(Into::into),
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            if let Err(e) = result {
+                writer.write_all(format!("ERROR {}\n", e).as_bytes()).await?;
+            }
+        }
+    }
+
+    /// Send the complete canvas to the control client
+    async fn export<W: AsyncWrite + Unpin>(writer: &mut W, pixmap: &SharedPixmap) -> anyhow::Result<()> {
+        tracing::info!("Exporting canvas via control socket");
+        let (width, height) = pixmap.get_size();
+        let data = unsafe { pixmap.get_color_data() }
+            .iter()
+            .flat_map(|c| Into::<[u8; 3]>::into(*c))
+            .collect::<Vec<_>>();
+        writer
+            .write_all(format!("CANVAS {} {}\n", width, height).as_bytes())
+            .await?;
+        writer.write_all(&data).await?;
+        Ok(())
+    }
+
+    /// Send the recorded request latencies to the control client
+    async fn metrics<W: AsyncWrite + Unpin>(writer: &mut W) -> anyhow::Result<()> {
+        let mut metrics = String::new();
+        crate::metrics::write_prometheus(&mut metrics)?;
+        writer
+            .write_all(format!("METRICS {}\n", metrics.len()).as_bytes())
+            .await?;
+        writer.write_all(metrics.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Freeze or unfreeze the canvas
+    fn freeze(pixmap: &SharedPixmap, frozen: bool) -> anyhow::Result<()> {
+        match frozen {
+            true => tracing::info!("Freezing canvas via control socket"),
+            false => tracing::info!("Unfreezing canvas via control socket"),
+        }
+        pixmap.set_frozen(frozen);
+        Ok(())
+    }
+
+    /// Pass a request to resize the canvas on to whoever is responsible for it and wait until it is replaced
+    async fn resize(
+        pixmap: &SharedPixmap,
+        resize: Option<&mpsc::Sender<(usize, usize)>>,
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<()> {
+        let resize = resize.ok_or_else(|| anyhow!("resizing is not supported by this server"))?;
+        if width == 0 || height == 0 {
+            return Err(anyhow!("width and height must both be greater than 0"));
+        }
+        tracing::info!("Resizing canvas to {}x{} via control socket", width, height);
+        resize
+            .send((width, height))
+            .await
+            .map_err(|_| anyhow!("the server is shutting down"))?;
+        pixmap.retired().await;
+        Ok(())
+    }
+
+    /// Change the effect with which the canvas is displayed
+    fn effect(effect: Option<&watch::Sender<Effect>>, spec: &str) -> anyhow::Result<()> {
+        let effect = effect.ok_or_else(|| anyhow!("effects are not enabled on this server"))?;
+        let new_effect = spec.parse::<Effect>()?;
+        tracing::info!("Changing effect to {} via control socket", new_effect);
+        effect.send_replace(new_effect);
+        Ok(())
+    }
+
+    /// Read the image data of an import without buffering it so that the next command can be read
+    async fn skip_image_data<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<()> {
+        let len = image_data_len(width, height)?;
+        tokio::io::copy(&mut reader.take(len as u64), &mut tokio::io::sink()).await?;
+        Ok(())
+    }
+
+    /// Receive image data from the control client and write it onto the canvas
+    async fn import<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        pixmap: &SharedPixmap,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<()> {
+        tracing::info!(
+            "Importing {}x{} pixels at {},{} via control socket",
+            width,
+            height,
+            x,
+            y
+        );
+        let len = image_data_len(width, height)?;
+        let (canvas_width, canvas_height) = pixmap.get_size();
+        let x_inside = x.checked_add(width).is_some_and(|x_max| x_max <= canvas_width);
+        let y_inside = y.checked_add(height).is_some_and(|y_max| y_max <= canvas_height);
+        if !x_inside || !y_inside {
+            Self::skip_image_data(reader, width, height).await?;
+            return Err(anyhow!(
+                "imported image of {}x{} pixels at {},{} does not fit onto the {}x{} canvas",
+                width,
+                height,
+                x,
+                y,
+                canvas_width,
+                canvas_height
+            ));
+        }
+
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf).await?;
+        let colors = buf
+            .into_iter()
+            .tuples::<(_, _, _)>()
+            .map(Color::from)
+            .collect::<Vec<_>>();
+        pixmap.set_region(x, y, width, height, &colors)?;
+        Ok(())
+    }
+}
+
+/// How many bytes of RGB data an imported image of the given size has
+fn image_data_len(width: usize, height: usize) -> anyhow::Result<usize> {
+    width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(3))
+        .ok_or_else(|| anyhow!("imported image is too large"))
+}
+
+#[async_trait]
+impl GenServer for ControlServer {
+    type Options = ControlServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        remove_stale_socket(&self.options.path).await?;
+        let listener = UnixListener::bind(&self.options.path)?;
+        let guard = SocketFileGuard(self.options.path.clone());
+        tracing::info!("Started control socket on {}", self.options.path.display());
+
+        let resize = self.options.resize;
+        let effect = self.options.effect;
+        let request_ids = self.options.request_ids;
+        let handle = join_set.build_task().name("control_server").spawn(async move {
+            ControlServer::handle_listener(listener, pixmap, resize, effect, request_ids, guard).await
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::clients::ControlClient;
+    use crate::pixmap::Pixmap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut join_set = JoinSet::new();
+        let (resize, mut resizes) = mpsc::channel(1);
+        let (effect, effects) = watch::channel(Effect::None);
+        ControlServer::new(ControlServerOptions {
+            path: path.clone(),
+            resize: Some(resize),
+            effect: Some(Arc::new(effect)),
+            request_ids: Arc::new(RequestIds::new()),
+        })
+        .start(pixmap.clone(), &mut join_set)
+        .await
+        .unwrap();
+        let mut client = ControlClient::connect(&path).await.unwrap();
+
+        let colors = [Color::from(0x111111), Color::from(0x222222)];
+        client.import_canvas(2, 3, 2, 1, &colors).await.unwrap();
+        assert_eq!(pixmap.get_pixel(3, 3).unwrap(), Color::from(0x222222));
+        assert!(client.import_canvas(3, 3, 2, 1, &colors).await.is_err());
+        assert!(client
+            .import_canvas(0, 0, 5, 4, &[Color::from(0x333333); 20])
+            .await
+            .is_err());
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), Color::default());
+
+        let (width, height, data) = client.export_canvas().await.unwrap();
+        assert_eq!((width, height), (4, 4));
+        assert_eq!(&data[14..], &colors);
+
+        client.set_frozen(true).await.unwrap();
+        assert!(pixmap.is_frozen());
+        client.set_frozen(false).await.unwrap();
+        assert!(!pixmap.is_frozen());
+
+        assert!(client.resize_canvas(0, 2).await.is_err());
+
+        client.set_effect(Effect::Quadrants).await.unwrap();
+        assert_eq!(*effects.borrow(), Effect::Quadrants);
+
+        // sizes which cannot be buffered are rejected before any image data is read
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(format!("IMPORT 0 0 {} 2\n", usize::MAX).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).await.unwrap();
+        assert_eq!(response, "ERROR imported image is too large\n");
+
+        // the connection does not work on the canvas anymore once it was replaced by a resized copy
+        let resized = tokio::spawn({
+            let pixmap = pixmap.clone();
+            async move {
+                let size = resizes.recv().await;
+                pixmap.retire();
+                size
+            }
+        });
+        client.resize_canvas(8, 2).await.unwrap();
+        assert_eq!(resized.await.unwrap(), Some((8, 2)));
+        assert!(client.set_frozen(true).await.is_err());
+        assert!(!pixmap.is_frozen());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_request_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let request_ids = Arc::new(RequestIds::new());
+        let mut join_set = JoinSet::new();
+        ControlServer::new(ControlServerOptions {
+            path: path.clone(),
+            resize: None,
+            effect: None,
+            request_ids: request_ids.clone(),
+        })
+        .start(pixmap.clone(), &mut join_set)
+        .await
+        .unwrap();
+
+        let mut client = ControlClient::connect(&path).await.unwrap();
+        client.set_request_id("freeze-1");
+        client.set_frozen(true).await.unwrap();
+        client.set_frozen(false).await.unwrap();
+        // a retry on another connection is confirmed without freezing the canvas again
+        let mut client = ControlClient::connect(&path).await.unwrap();
+        client.set_request_id("freeze-1");
+        client.set_frozen(true).await.unwrap();
+        assert!(!pixmap.is_frozen());
+
+        client.set_request_id("import-1");
+        client
+            .import_canvas(0, 0, 1, 1, &[Color::from(0x111111)])
+            .await
+            .unwrap();
+        pixmap.set_pixel(0, 0, Color::from(0x222222)).unwrap();
+        client.set_request_id("import-1");
+        client
+            .import_canvas(0, 0, 1, 1, &[Color::from(0x111111)])
+            .await
+            .unwrap();
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), Color::from(0x222222));
+        // the image data of the repeated import was skipped
+        let (_, _, data) = client.export_canvas().await.unwrap();
+        assert_eq!(data[0], Color::from(0x222222));
+
+        // failed commands are not remembered
+        client.set_request_id("resize-1");
+        assert!(client.resize_canvas(8, 8).await.is_err());
+        assert!(!request_ids.contains("resize-1"));
+        client.set_request_id(&"x".repeat(MAX_REQUEST_ID_LEN + 1));
+        assert!(client.set_frozen(true).await.is_err());
+        assert!(!pixmap.is_frozen());
+    }
+}
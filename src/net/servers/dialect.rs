@@ -0,0 +1,109 @@
+use crate::net::protocol::Request;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Which form of the pixelflut protocol a listener speaks
+///
+/// This allows exposing a strictly compatible port for legacy clients besides a feature-rich one.
+/// Which of the extended commands are available is further restricted by the [`Capabilities`](super::Capabilities)
+/// of the server.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Dialect {
+    /// All commands which the server supports, including `HELLO` negotiation and binary `PB` commands
+    #[default]
+    Extended,
+    /// Only the classic text commands `PX`, `SIZE` and `HELP`
+    Classic,
+    /// Only binary `PB` commands for drawing as well as `SIZE` and `HELP` so that clients can discover the canvas
+    Binary,
+}
+
+/// An error which indicates that a dialect name could not be parsed
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("invalid dialect {0:?}, expected one of extended, classic or binary")]
+pub struct InvalidDialectError(String);
+
+impl FromStr for Dialect {
+    type Err = InvalidDialectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "extended" => Ok(Dialect::Extended),
+            "classic" => Ok(Dialect::Classic),
+            "binary" => Ok(Dialect::Binary),
+            _ => Err(InvalidDialectError(s.to_string())),
+        }
+    }
+}
+
+impl Display for Dialect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dialect::Extended => f.write_str("extended"),
+            Dialect::Classic => f.write_str("classic"),
+            Dialect::Binary => f.write_str("binary"),
+        }
+    }
+}
+
+impl Dialect {
+    /// Check whether a request which was parsed from `line` belongs to this dialect
+    ///
+    /// Returns the error with which the request is rejected otherwise.
+    #[inline(always)]
+    pub(crate) fn check(&self, line: &[u8], request: &Request) -> Result<(), &'static str> {
+        let binary = line.starts_with(b"PB");
+        match (self, request) {
+            (Dialect::Extended, _) => Ok(()),
+            (_, Request::GetSize | Request::Help(_)) => Ok(()),
+            (Dialect::Classic, Request::GetPixel { .. } | Request::SetPixel { .. }) if !binary => Ok(()),
+            (Dialect::Classic, _) => {
+                Err("only the classic PX, SIZE and HELP commands are supported on this port")
+            }
+            (Dialect::Binary, _) if binary => Ok(()),
+            (Dialect::Binary, _) => Err("only binary PB commands, SIZE and HELP are supported on this port"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::servers::{handle_request, Session};
+    use crate::pixmap::{Color, Pixmap};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_dialect() {
+        assert_eq!("Classic".parse(), Ok(Dialect::Classic));
+        assert!("modern".parse::<Dialect>().is_err());
+        for dialect in [Dialect::Extended, Dialect::Classic, Dialect::Binary] {
+            assert_eq!(dialect.to_string().parse(), Ok(dialect));
+        }
+    }
+
+    #[test]
+    fn test_dialect_sessions() {
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let pb = b"PB\x01\x00\x02\x00\xab\xcd\xef\xff";
+
+        let mut classic = Session::default().with_dialect(Dialect::Classic);
+        assert_eq!(
+            handle_request(b"PX 1 1 FF0000\n", &pixmap, &mut classic),
+            Ok(None)
+        );
+        assert!(handle_request(b"PX 1 1\n", &pixmap, &mut classic).is_ok());
+        assert!(handle_request(b"SIZE\n", &pixmap, &mut classic).is_ok());
+        assert!(handle_request(b"HELLO legacy\n", &pixmap, &mut classic).is_err());
+        assert!(handle_request(b"PX 0 0 FF000080\n", &pixmap, &mut classic).is_err());
+        assert!(handle_request(pb, &pixmap, &mut classic).is_err());
+        assert_eq!(pixmap.get_pixel(1, 2).unwrap(), Color::from(0));
+
+        let mut binary = Session::default().with_dialect(Dialect::Binary);
+        assert_eq!(handle_request(pb, &pixmap, &mut binary), Ok(None));
+        assert_eq!(pixmap.get_pixel(1, 2).unwrap(), Color::from((0xab, 0xcd, 0xef)));
+        assert!(handle_request(b"SIZE\n", &pixmap, &mut binary).is_ok());
+        assert!(handle_request(b"PX 2 2 FF0000\n", &pixmap, &mut binary).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::metrics::Command;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Information about the client of a connection which is passed to [`ConnectionHooks`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PeerInfo {
+    /// The listener which accepted the connection, e.g. `tcp://0.0.0.0:1234` or `unix:/run/pixelflut.sock`
+    pub listener: String,
+    /// The network address of the client or `None` if the transport has none, like unix sockets
+    pub remote_addr: Option<SocketAddr>,
+    /// When the connection was accepted
+    pub connected_at: Instant,
+}
+
+impl PeerInfo {
+    /// Describe a client which has just connected to `listener`
+    pub fn new(listener: impl Into<String>, remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            listener: listener.into(),
+            remote_addr,
+            connected_at: Instant::now(),
+        }
+    }
+}
+
+/// What a client did during a connection which is passed to [`ConnectionHooks::on_connection_close`]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ConnectionSummary {
+    /// The number of requests which the client sent, including invalid ones
+    pub requests: u64,
+    /// The number of requests which failed
+    pub errors: u64,
+    /// The number of bytes which were received from the client
+    pub bytes_read: u64,
+    /// The number of bytes which were sent to the client
+    pub bytes_written: u64,
+    /// The number of valid requests per command, indexed like `Command::ALL`
+    commands: [u64; Command::ALL.len()],
+}
+
+impl ConnectionSummary {
+    /// Count one valid request of the given command
+    #[inline(always)]
+    pub(crate) fn count_command(&mut self, command: Command) {
+        self.commands[command as usize] += 1;
+    }
+
+    /// The number of valid requests of each command which the client used at least once
+    pub fn commands(&self) -> impl Iterator<Item = (Command, u64)> + '_ {
+        Command::ALL
+            .into_iter()
+            .zip(self.commands)
+            .filter(|(_, count)| *count > 0)
+    }
+}
+
+/// How a connection which was accepted by [`ConnectionHooks::on_connection_open`] is set up
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Admission {
+    /// A message which is sent to the client before anything else
+    ///
+    /// WebSocket clients only receive it on the protocol route because the other routes carry machine-readable data.
+    pub greeting: Option<String>,
+    /// The team as which the connection is authenticated, as if the client had sent `AUTH`
+    pub team: Option<String>,
+}
+
+/// Callbacks which embedders can use to observe and control the connections of stream-based servers
+///
+/// They are called by the TCP, TLS, unix socket and WebSocket servers. UDP has no connections and thus no hooks.
+/// Both methods do nothing by default so that implementations only need to override what they are interested in.
+#[async_trait]
+pub trait ConnectionHooks: Debug + Send + Sync {
+    /// Decide whether a newly accepted client is served
+    ///
+    /// Returning an error closes the connection after the error message was sent to the client.
+    /// Requests of the client are only handled once this returns.
+    async fn on_connection_open(&self, _peer: &PeerInfo) -> Result<Admission, String> {
+        Ok(Admission::default())
+    }
+
+    /// Observe that an admitted client disconnected or was disconnected
+    async fn on_connection_close(&self, _peer: &PeerInfo, _summary: &ConnectionSummary) {}
+}
+
+/// [`ConnectionHooks`] which admit every client and ignore all events
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoHooks;
+
+impl NoHooks {
+    /// Get the hooks in the form in which server options hold them
+    pub fn shared() -> Arc<dyn ConnectionHooks> {
+        Arc::new(NoHooks)
+    }
+}
+
+#[async_trait]
+impl ConnectionHooks for NoHooks {}
+
+/// Ask the hooks whether a client of a line-based stream server is served and send it the greeting or rejection
+///
+/// `None` is returned if the client was rejected.
+pub(super) async fn admit<S: AsyncWrite + Unpin>(
+    hooks: &dyn ConnectionHooks,
+    peer: &PeerInfo,
+    stream: &mut S,
+) -> std::io::Result<Option<Admission>> {
+    match hooks.on_connection_open(peer).await {
+        Ok(admission) => {
+            if let Some(greeting) = &admission.greeting {
+                stream.write_all(format!("{}\n", greeting).as_bytes()).await?;
+            }
+            Ok(Some(admission))
+        }
+        Err(reason) => {
+            tracing::debug!("Rejecting client {:?}: {}", peer.remote_addr, reason);
+            stream.write_all(format!("{}\n", reason).as_bytes()).await?;
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::servers::{GenServer, Reservations, UnixSocketOptions, UnixSocketServer};
+    use crate::pixmap::Pixmap;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::task::JoinSet;
+
+    /// Admits the first client as team red and rejects all others
+    #[derive(Debug, Default)]
+    struct FirstClientOnly {
+        opened: AtomicUsize,
+        closed: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ConnectionHooks for FirstClientOnly {
+        async fn on_connection_open(&self, peer: &PeerInfo) -> Result<Admission, String> {
+            assert!(peer.listener.starts_with("unix:"));
+            match self.opened.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(Admission {
+                    greeting: Some("welcome".to_string()),
+                    team: Some("red".to_string()),
+                }),
+                _ => Err("server is full".to_string()),
+            }
+        }
+
+        async fn on_connection_close(&self, _peer: &PeerInfo, _summary: &ConnectionSummary) {
+            self.closed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pixelflut.sock");
+        let hooks = Arc::new(FirstClientOnly::default());
+        let mut options = UnixSocketOptions::new(path.clone());
+        options.hooks = hooks.clone();
+        options.reservations = Arc::new(Reservations::new(
+            HashMap::from([("red".to_string(), "secret".to_string())]),
+            Duration::from_secs(60),
+        ));
+        let mut join_set = JoinSet::new();
+        UnixSocketServer::new(options)
+            .start(Arc::new(Pixmap::new(8, 8).unwrap()), &mut join_set)
+            .await
+            .unwrap();
+
+        // the admitted client is greeted and may reserve without sending AUTH itself
+        let mut first = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        let mut line = String::new();
+        first.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "welcome\n");
+        first.get_mut().write_all(b"RESERVE 0 0 2 2 10\n").await.unwrap();
+        line.clear();
+        first.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "RESERVED 0 0 2 2 10\n");
+
+        let mut second = BufReader::new(UnixStream::connect(&path).await.unwrap());
+        line.clear();
+        second.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "server is full\n");
+        line.clear();
+        assert_eq!(second.read_line(&mut line).await.unwrap(), 0);
+
+        drop(first);
+        for _ in 0..100 {
+            if hooks.closed.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // rejected clients were never admitted and are thus not reported as closed
+        assert_eq!(hooks.closed.load(Ordering::SeqCst), 1);
+    }
+}
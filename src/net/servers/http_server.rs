@@ -0,0 +1,1018 @@
+use crate::net::servers::bind::{bind_tcp, fmt_addrs, serve_all};
+use crate::net::servers::compression::{CompressionCache, ContentEncoding, MIN_COMPRESSED_SIZE};
+#[cfg(feature = "ws")]
+use crate::net::servers::ws_server::Route as WsRoute;
+use crate::net::servers::{client_stats, AllowedOrigins, GenServer, PeerInfo, Reservations};
+#[cfg(feature = "ws")]
+use crate::net::servers::{WsServer, WsServerOptions};
+use crate::pixmap::{encode_png, Canvases, Color, ColorStats, Pixmap, SharedPixmap};
+use crate::sinks::color_stats::LatestColorStats;
+use crate::watchdog::Watchdog;
+use crate::DaemonResult;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Take};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{AbortHandle, JoinSet};
+#[cfg(feature = "ws")]
+use {
+    tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    tokio_tungstenite::tungstenite::handshake::derive_accept_key,
+    tokio_tungstenite::tungstenite::protocol::Role,
+    tokio_tungstenite::WebSocketStream,
+};
+
+/// The maximum size of a request head (request line and headers) which is accepted
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// How long clients may take to send the request head before their connection is closed
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many of the most common colors are reported by `/stats`
+const DOMINANT_COLORS: usize = 5;
+
+/// The web page which is served at `/` and shows the live canvas
+const VIEWER: &str = include_str!("viewer.html");
+
+/// Options with which the `HttpServer` is configured
+#[derive(Debug, Clone)]
+pub struct HttpServerOptions {
+    /// The addresses to which the server binds
+    ///
+    /// An IPv6 wildcard address like `[::]` also accepts IPv4 clients unless IPv4 addresses with the same port are
+    /// given as well.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// The watchdog whose view of background tasks is reported by `/healthz`
+    pub watchdog: Option<Watchdog>,
+    /// Whether the server has finished starting up, as reported by `/readyz`
+    pub ready: Arc<AtomicBool>,
+    /// The canvas reservations which are listed by `/reservations`
+    pub reservations: Arc<Reservations>,
+    /// Additional canvases which are offered below `/canvas/<name>`
+    pub canvases: Arc<Canvases>,
+    /// The latest color statistics of the canvases which are reported by `/stats`
+    pub color_stats: Arc<LatestColorStats>,
+    /// How long written pixels stay visible in `/activity.png`
+    pub activity_decay: Duration,
+    /// The origins of web pages which may fetch the endpoints
+    pub allowed_origins: AllowedOrigins,
+    /// The options with which WebSocket connections are served on the same port, if any
+    ///
+    /// Their bind addresses are not used.
+    #[cfg(feature = "ws")]
+    pub websocket: Option<WsServerOptions>,
+}
+
+/// Render a map of the named canvas in which claimed pixels are white and all others black
+fn claims_map(width: usize, height: usize, reservations: &Reservations, canvas: &str) -> Vec<Color> {
+    let mut map = vec![Color::default(); width * height];
+    for claim in reservations.claims().into_iter().filter(|i| i.canvas == canvas) {
+        // claims are always inside the canvas they were made on but it may have been resized since
+        for row in map.chunks_exact_mut(width).skip(claim.y).take(claim.height) {
+            let end = (claim.x + claim.width).min(width);
+            if claim.x < end {
+                row[claim.x..end].fill(Color::from(0xFFFFFF));
+            }
+        }
+    }
+    map
+}
+
+/// Describe the canvas size, its average and most common colors and the number of active reservations on it as JSON
+///
+/// The colors are taken from the latest statistics of the canvas and reported as `null` and `[]` if it is not analyzed.
+pub(crate) fn stats_json(
+    pixmap: &Pixmap,
+    stats: Option<&ColorStats>,
+    reservations: &Reservations,
+    canvas: &str,
+) -> String {
+    let (width, height) = pixmap.get_size();
+    let average_color = match stats {
+        Some(stats) => format!("\"{}\"", stats.average()),
+        None => "null".to_string(),
+    };
+    let dominant_colors = stats
+        .map(|stats| stats.dominant_colors(DOMINANT_COLORS))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(color, fraction)| format!("{{\"color\":\"{}\",\"fraction\":{}}}", color, fraction))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"width\":{},\"height\":{},\"average_color\":{},\"dominant_colors\":[{}],\"active_reservations\":{}}}",
+        width,
+        height,
+        average_color,
+        dominant_colors,
+        reservations.active().iter().filter(|i| i.canvas == canvas).count()
+    )
+}
+
+/// Replace `line` with the next line of a request head which must end before the limit of `reader` is reached
+async fn read_head_line<R: AsyncBufRead + Unpin>(
+    reader: &mut Take<R>,
+    line: &mut String,
+) -> anyhow::Result<usize> {
+    line.clear();
+    let len = reader.read_line(line).await?;
+    if reader.limit() == 0 && !line.ends_with('\n') {
+        return Err(anyhow!("request head is larger than {} bytes", MAX_HEAD_SIZE));
+    }
+    Ok(len)
+}
+
+/// Quote `text` as a JSON string, e.g. for user agents which clients may choose freely
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A server which exposes the canvas and operational endpoints via HTTP
+///
+/// This allows web dashboards to observe the canvas without speaking the pixelflut protocol.
+/// The following endpoints are served:
+///
+/// - `GET /` responds with a web page which shows the live canvas and can be zoomed and panned.
+///   It renders the binary `/stream?encoding=delta&progressive=true` WebSocket route if WebSocket options are
+///   configured and polls `/canvas.raw` otherwise.
+/// - `GET /canvas.png` responds with the current canvas as PNG image.
+/// - `GET /canvas.raw` responds with the `r`, `g`, `b` bytes of all pixels row by row.
+/// - `GET /size` responds with the canvas size as JSON object, e.g. `{"width":800,"height":600}`.
+/// - `GET /stats` responds with a JSON object describing the canvas size, its average color and its most common
+///   colors as well as the number of active reservations.
+///   The colors are those which were last computed by the canvas' [`ColorStatsSink`](crate::sinks::color_stats::ColorStatsSink).
+/// - `GET /reservations` responds with a JSON array of all active reservations.
+/// - `GET /claims` responds with a JSON array of all active advisory claims.
+/// - `GET /claims.png` responds with a map of the canvas in which claimed pixels are bright.
+/// - `GET /leaderboard` responds with a JSON object of the total written pixels and the busiest client networks if
+///   client statistics are enabled (see [`enable_client_stats`](crate::net::servers::enable_client_stats)).
+///   Each network is reported with the user agent which one of its clients last sent via `HELLO`, if any.
+/// - `GET /activity.png` responds with an image in which recently written pixels are bright if the pixmap tracks
+///   activity (see [`Pixmap::with_activity_tracking`](crate::pixmap::Pixmap::with_activity_tracking)).
+/// - `GET /metrics` responds with the request latency metrics in the prometheus text format.
+/// - `GET /healthz` responds with `200 OK` unless the watchdog considers a background task stalled in which case it
+///   responds with `503 Service Unavailable` and lists the stalled tasks.
+/// - `GET /readyz` responds with `503 Service Unavailable` until `ready` is set and then behaves like `/healthz`.
+///
+/// The endpoints which describe a canvas, i.e. `/`, `/canvas.png`, `/canvas.raw`, `/size`, `/stats`, `/claims.png` and
+/// `/activity.png`, are also served below `/canvas/<name>` for each of the [`canvases`](HttpServerOptions::canvases)
+/// so that one port offers all of them to web clients, e.g. `/canvas/kids/canvas.png`.
+/// `/canvas/default` refers to the main canvas.
+///
+/// Responses carry CORS headers so that web pages of the allowed origins can fetch them and `OPTIONS` preflight
+/// requests are answered accordingly.
+/// Requests from pages of other origins are rejected with `403 Forbidden`.
+///
+/// Responses other than images are compressed with brotli or gzip if the client accepts it.
+/// Compressed variants of `/canvas.raw` are cached until the canvas changes because the whole canvas is large but
+/// usually compresses very well.
+///
+/// Every connection is closed after one response.
+///
+/// If WebSocket options are configured, requests which ask to upgrade the connection to a WebSocket on one of the
+/// paths of the [`WsServer`](crate::net::servers::WsServer) are handed over to it instead, so that deployments behind
+/// a single reverse proxy or load balancer only need to expose one port.
+#[derive(Debug, Clone)]
+pub struct HttpServer {
+    options: HttpServerOptions,
+}
+
+/// The parts of an HTTP request which are needed for routing it
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    /// The origin of the web page which made the request
+    origin: Option<String>,
+    /// The value of the `Accept-Encoding` header
+    accept_encoding: Option<String>,
+    /// The query part of the request target
+    #[cfg(feature = "ws")]
+    query: Option<String>,
+    /// The value of the `Upgrade` header
+    #[cfg(feature = "ws")]
+    upgrade: Option<String>,
+    /// The value of the `Sec-WebSocket-Key` header
+    #[cfg(feature = "ws")]
+    websocket_key: Option<String>,
+    /// The value of the `Sec-WebSocket-Version` header
+    #[cfg(feature = "ws")]
+    websocket_version: Option<String>,
+}
+
+/// A response which is sent back to the client
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    /// The encoding in which `body` is compressed, if any
+    content_encoding: Option<&'static str>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn text(status: u16, reason: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason,
+            content_type: "text/plain; charset=utf-8",
+            content_encoding: None,
+            body: body.into().into_bytes(),
+        }
+    }
+
+    fn ok(body: impl Into<String>) -> Self {
+        Self::text(200, "OK", body)
+    }
+
+    fn json(body: String) -> Self {
+        Self {
+            content_type: "application/json",
+            ..Self::ok(body)
+        }
+    }
+
+    fn html(body: &str) -> Self {
+        Self {
+            content_type: "text/html; charset=utf-8",
+            ..Self::ok(body)
+        }
+    }
+
+    fn png(width: usize, height: usize, colors: &[Color]) -> Self {
+        match encode_png(width, height, colors) {
+            Ok(body) => Self {
+                status: 200,
+                reason: "OK",
+                content_type: "image/png",
+                content_encoding: None,
+                body,
+            },
+            Err(e) => Self::text(
+                500,
+                "Internal Server Error",
+                format!("could not encode image: {}\n", e),
+            ),
+        }
+    }
+
+    fn unavailable(body: impl Into<String>) -> Self {
+        Self::text(503, "Service Unavailable", body)
+    }
+
+    /// Whether compressing the body could make it noticeably smaller
+    ///
+    /// Images are already compressed and small bodies are not worth the effort.
+    fn is_compressible(&self) -> bool {
+        !self.content_type.starts_with("image/")
+    }
+
+    /// Compress the body with the given encoding if that is worthwhile
+    fn compress(self, encoding: ContentEncoding) -> Self {
+        if encoding == ContentEncoding::Identity
+            || self.content_encoding.is_some()
+            || !self.is_compressible()
+            || self.body.len() < MIN_COMPRESSED_SIZE
+        {
+            return self;
+        }
+        match encoding.compress(&self.body) {
+            Ok(body) => Self {
+                content_encoding: encoding.header_value(),
+                body,
+                ..self
+            },
+            Err(e) => {
+                tracing::warn!("Could not compress http response: {e}");
+                self
+            }
+        }
+    }
+}
+
+impl HttpServer {
+    #[tracing::instrument(skip_all)]
+    async fn handle_listener(
+        listener: TcpListener,
+        pixmap: SharedPixmap,
+        options: HttpServerOptions,
+    ) -> anyhow::Result<!> {
+        let cache = Arc::new(CompressionCache::default());
+        let name = format!("http://{}", listener.local_addr()?);
+        loop {
+            let (stream, remote_addr) = listener.accept().await?;
+            let peer = PeerInfo::new(name.clone(), Some(remote_addr));
+            let pixmap = pixmap.clone();
+            let options = options.clone();
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                if let Err(e) = HttpServer::handle_connection(stream, peer, pixmap, options, cache).await {
+                    tracing::warn!("Got error while handling http connection: {e}");
+                }
+            });
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(remote = ?peer.remote_addr))]
+    async fn handle_connection(
+        stream: TcpStream,
+        peer: PeerInfo,
+        pixmap: SharedPixmap,
+        options: HttpServerOptions,
+        cache: Arc<CompressionCache>,
+    ) -> anyhow::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let request = tokio::time::timeout(HEAD_TIMEOUT, Self::read_request(&mut reader))
+            .await
+            .map_err(|_| anyhow!("client did not send a request within {:?}", HEAD_TIMEOUT))??;
+        #[cfg(feature = "ws")]
+        if let Some(websocket) = &options.websocket {
+            if let Some((route, name, canvas)) = Self::websocket_route(&request, websocket) {
+                return Self::upgrade_websocket(
+                    reader,
+                    writer,
+                    &request,
+                    route,
+                    (name, canvas),
+                    peer,
+                    pixmap,
+                    websocket,
+                )
+                .await;
+            }
+        }
+        let encoding = ContentEncoding::negotiate(request.accept_encoding.as_deref());
+        let mut response = Self::route(&request, &pixmap, &options, encoding, &cache).compress(encoding);
+        if request.method == "HEAD" {
+            response.body.clear();
+        }
+
+        let mut encoding_headers = String::new();
+        if let Some(content_encoding) = response.content_encoding {
+            encoding_headers.push_str(&format!("Content-Encoding: {}\r\n", content_encoding));
+        }
+        if response.is_compressible() {
+            encoding_headers.push_str("Vary: Accept-Encoding\r\n");
+        }
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Cache-Control: no-store\r\n{}Connection: close\r\n\r\n",
+            response.status,
+            response.reason,
+            response.content_type,
+            response.body.len(),
+            encoding_headers,
+            options.allowed_origins.cors_headers(request.origin.as_deref()),
+        );
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(&response.body).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Determine the WebSocket route to which a request asks to be upgraded
+    ///
+    /// Returns `None` for requests which are not valid WebSocket handshakes for one of the routes so that they are
+    /// answered like any other HTTP request.
+    /// Otherwise, the canvas which the path selects via a `/canvas/<name>` prefix is returned along with the route.
+    #[cfg(feature = "ws")]
+    fn websocket_route<'a>(
+        request: &'a HttpRequest,
+        websocket: &WsServerOptions,
+    ) -> Option<(WsRoute, &'a str, Option<SharedPixmap>)> {
+        let is_handshake = request.method == "GET"
+            && request
+                .upgrade
+                .as_deref()
+                .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+            && request.websocket_key.is_some()
+            && request.websocket_version.as_deref() == Some("13")
+            && websocket.allowed_origins.allows(request.origin.as_deref());
+        if !is_handshake {
+            return None;
+        }
+        let (path, name, canvas) = super::route_canvas(&request.path, &websocket.canvases)?;
+        Some((WsRoute::of_uri(path, request.query.as_deref())?, name, canvas))
+    }
+
+    /// Complete the WebSocket handshake of a request and serve the connection like the `WsServer` does
+    #[cfg(feature = "ws")]
+    #[allow(clippy::too_many_arguments)]
+    async fn upgrade_websocket(
+        reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+        request: &HttpRequest,
+        route: WsRoute,
+        (canvas_name, canvas): (&str, Option<SharedPixmap>),
+        peer: PeerInfo,
+        pixmap: SharedPixmap,
+        websocket: &WsServerOptions,
+    ) -> anyhow::Result<()> {
+        let key = request.websocket_key.as_deref().unwrap_or_default();
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            derive_accept_key(key.as_bytes())
+        );
+        writer.write_all(head.as_bytes()).await?;
+
+        // frames which the client sent right after its handshake may already be buffered
+        let buffered = reader.buffer().to_vec();
+        let stream = reader.into_inner().reunite(writer)?;
+        let stream = WebSocketStream::from_partially_read(stream, buffered, Role::Server, None).await;
+        let mut session = WsServer::session(websocket);
+        session.select_canvas(canvas_name, canvas);
+        WsServer::serve(stream, route, peer, pixmap, session, websocket.hooks.clone()).await
+    }
+
+    /// Read the request line and headers of one request
+    async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<HttpRequest> {
+        // the whole head is read through the limit so that clients can't make the server buffer endless lines
+        let mut reader = reader.take(MAX_HEAD_SIZE as u64);
+        let mut line = String::new();
+        read_head_line(&mut reader, &mut line).await?;
+        let mut request = match line.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+            [method, target, version] if version.starts_with("HTTP/1.") => HttpRequest {
+                method: method.to_string(),
+                path: target.split('?').next().unwrap_or(target).to_string(),
+                origin: None,
+                accept_encoding: None,
+                #[cfg(feature = "ws")]
+                query: target.split_once('?').map(|(_, query)| query.to_string()),
+                #[cfg(feature = "ws")]
+                upgrade: None,
+                #[cfg(feature = "ws")]
+                websocket_key: None,
+                #[cfg(feature = "ws")]
+                websocket_version: None,
+            },
+            _ => return Err(anyhow!("invalid request line {:?}", line.trim())),
+        };
+
+        // only some headers are needed but all of them must be consumed before responding
+        loop {
+            if read_head_line(&mut reader, &mut line).await? == 0 || line.trim_end().is_empty() {
+                return Ok(request);
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                let value = Some(value.trim().to_string());
+                match name.to_ascii_lowercase().as_str() {
+                    "origin" => request.origin = value,
+                    "accept-encoding" => request.accept_encoding = value,
+                    #[cfg(feature = "ws")]
+                    "upgrade" => request.upgrade = value,
+                    #[cfg(feature = "ws")]
+                    "sec-websocket-key" => request.websocket_key = value,
+                    #[cfg(feature = "ws")]
+                    "sec-websocket-version" => request.websocket_version = value,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn route(
+        request: &HttpRequest,
+        pixmap: &SharedPixmap,
+        options: &HttpServerOptions,
+        encoding: ContentEncoding,
+        cache: &CompressionCache,
+    ) -> HttpResponse {
+        if !options.allowed_origins.allows(request.origin.as_deref()) {
+            return HttpResponse::text(403, "Forbidden", "origin is not allowed\n");
+        }
+        if request.method == "OPTIONS" {
+            return HttpResponse {
+                status: 204,
+                reason: "No Content",
+                content_type: "text/plain; charset=utf-8",
+                content_encoding: None,
+                body: Vec::new(),
+            };
+        }
+        if request.method != "GET" && request.method != "HEAD" {
+            return HttpResponse::text(405, "Method Not Allowed", "only GET and HEAD are supported\n");
+        }
+        let Some((path, name, canvas)) = super::route_canvas(&request.path, &options.canvases) else {
+            return HttpResponse::text(404, "Not Found", "there is no such canvas\n");
+        };
+        let prefixed = path.len() != request.path.len();
+        let pixmap = canvas.as_ref().unwrap_or(pixmap);
+        let (width, height) = pixmap.get_size();
+        match path {
+            "/" | "/index.html" => HttpResponse::html(VIEWER),
+            "/canvas.png" => HttpResponse::png(width, height, unsafe { pixmap.get_color_data() }),
+            // the cache only holds variants of the main canvas
+            "/canvas.raw" if canvas.is_some() => {
+                Self::raw_canvas(pixmap, encoding, &CompressionCache::default())
+            }
+            "/canvas.raw" => Self::raw_canvas(pixmap, encoding, cache),
+            "/size" => HttpResponse::json(format!("{{\"width\":{},\"height\":{}}}", width, height)),
+            "/stats" => HttpResponse::json(stats_json(
+                pixmap,
+                options.color_stats.get(name).as_deref(),
+                &options.reservations,
+                name,
+            )),
+            "/claims.png" => HttpResponse::png(
+                width,
+                height,
+                &claims_map(width, height, &options.reservations, name),
+            ),
+            "/activity.png" => match pixmap.activity() {
+                Some(activity) => HttpResponse::png(width, height, &activity.render(options.activity_decay)),
+                None => HttpResponse::text(404, "Not Found", "activity tracking is disabled\n"),
+            },
+            // all other endpoints describe the whole server and are not offered per canvas
+            _ if prefixed => HttpResponse::text(404, "Not Found", "not found\n"),
+            "/reservations" => Self::reservations(options),
+            "/claims" => Self::claims(options),
+            "/leaderboard" => Self::leaderboard(),
+            "/metrics" => {
+                let mut metrics = String::new();
+                match crate::metrics::write_prometheus(&mut metrics) {
+                    Ok(()) => HttpResponse {
+                        content_type: "text/plain; version=0.0.4",
+                        ..HttpResponse::ok(metrics)
+                    },
+                    Err(_) => HttpResponse::text(500, "Internal Server Error", "could not render metrics\n"),
+                }
+            }
+            "/healthz" => Self::health(options),
+            "/readyz" if !options.ready.load(Ordering::Relaxed) => {
+                HttpResponse::unavailable("server is still starting\n")
+            }
+            "/readyz" => Self::health(options),
+            _ => HttpResponse::text(404, "Not Found", "not found\n"),
+        }
+    }
+
+    /// Send the raw canvas content, compressed from the cache if possible
+    fn raw_canvas(
+        pixmap: &SharedPixmap,
+        encoding: ContentEncoding,
+        cache: &CompressionCache,
+    ) -> HttpResponse {
+        let generation = pixmap.generation();
+        let render = || {
+            unsafe { pixmap.get_color_data() }
+                .iter()
+                .flat_map(|&color| <[u8; 3]>::from(color))
+                .collect()
+        };
+        match cache.get_or_compress(encoding, generation, render) {
+            Ok(body) => HttpResponse {
+                status: 200,
+                reason: "OK",
+                content_type: "application/octet-stream",
+                content_encoding: encoding.header_value(),
+                body: body.to_vec(),
+            },
+            Err(e) => HttpResponse::text(
+                500,
+                "Internal Server Error",
+                format!("could not compress canvas: {}\n", e),
+            ),
+        }
+    }
+
+    /// List all active reservations as JSON
+    fn reservations(options: &HttpServerOptions) -> HttpResponse {
+        let now = Instant::now();
+        let reservations = options
+            .reservations
+            .active()
+            .into_iter()
+            .map(|i| {
+                format!(
+                    "{{\"team\":\"{}\",\"canvas\":\"{}\",\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"expires_in_secs\":{}}}",
+                    i.team.replace('\\', "\\\\").replace('"', "\\\""),
+                    i.canvas.replace('\\', "\\\\").replace('"', "\\\""),
+                    i.x,
+                    i.y,
+                    i.width,
+                    i.height,
+                    i.expires_at.saturating_duration_since(now).as_secs()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        HttpResponse::json(format!("[{}]", reservations))
+    }
+
+    /// List all active claims as JSON
+    fn claims(options: &HttpServerOptions) -> HttpResponse {
+        let now = Instant::now();
+        let claims = options
+            .reservations
+            .claims()
+            .into_iter()
+            .map(|i| {
+                let team = match &i.team {
+                    Some(team) => format!("\"{}\"", team.replace('\\', "\\\\").replace('"', "\\\"")),
+                    None => "null".to_string(),
+                };
+                format!(
+                    "{{\"team\":{},\"canvas\":\"{}\",\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"expires_in_secs\":{}}}",
+                    team,
+                    i.canvas.replace('\\', "\\\\").replace('"', "\\\""),
+                    i.x,
+                    i.y,
+                    i.width,
+                    i.height,
+                    i.expires_at.saturating_duration_since(now).as_secs()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        HttpResponse::json(format!("[{}]", claims))
+    }
+
+    /// List the busiest client networks as JSON
+    fn leaderboard() -> HttpResponse {
+        let Some(stats) = client_stats() else {
+            return HttpResponse::text(404, "Not Found", "client statistics are disabled\n");
+        };
+        let clients = stats
+            .top_clients()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (bucket, pixels, user_agent))| {
+                format!(
+                    "{{\"rank\":{},\"client\":\"{}\",\"pixels\":{},\"user_agent\":{}}}",
+                    i + 1,
+                    bucket,
+                    pixels,
+                    user_agent.map_or("null".to_string(), |i| json_string(&i))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        HttpResponse::json(format!(
+            "{{\"total\":{},\"clients\":[{}]}}",
+            stats.total(),
+            clients
+        ))
+    }
+
+    /// Report whether all background tasks are making progress
+    fn health(options: &HttpServerOptions) -> HttpResponse {
+        let stalled = options
+            .watchdog
+            .as_ref()
+            .map(|watchdog| watchdog.stalled_tasks())
+            .unwrap_or_default();
+        match stalled.is_empty() {
+            true => HttpResponse::ok("ok\n"),
+            false => HttpResponse::unavailable(format!("stalled: {}\n", stalled.join(", "))),
+        }
+    }
+}
+
+#[async_trait]
+impl GenServer for HttpServer {
+    type Options = HttpServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let listeners = bind_tcp(&self.options.bind_addrs)?;
+        tracing::info!("Started HTTP Server on {}", fmt_addrs(&self.options.bind_addrs));
+
+        let options = self.options;
+        let handle = join_set.build_task().name("http_server").spawn(async move {
+            serve_all(listeners, |listener| {
+                HttpServer::handle_listener(listener, pixmap.clone(), options.clone())
+            })
+            .await
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap, DEFAULT_CANVAS};
+    use crate::sinks::color_stats::{ColorStatsSink, ColorStatsSinkOptions};
+    use std::collections::HashMap;
+    use std::io::Read;
+    use tokio::io::AsyncReadExt;
+
+    async fn start_server(
+        pixmap: SharedPixmap,
+        ready: Arc<AtomicBool>,
+    ) -> (SocketAddr, JoinSet<DaemonResult>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let bind_addr = listener.local_addr().unwrap();
+        drop(listener);
+        let mut join_set = JoinSet::new();
+        let kids = Arc::new(Pixmap::new(2, 2).unwrap());
+        kids.set_region(0, 0, 2, 2, &[Color::from(0x0000FF); 4]).unwrap();
+        let mut canvases = Canvases::default();
+        canvases.insert("kids".to_string(), kids.clone()).unwrap();
+        let canvases = Arc::new(canvases);
+        // the statistics are computed once when the sinks are created, which is enough for the tests
+        let mut color_stats = LatestColorStats::default();
+        for (name, canvas) in [(DEFAULT_CANVAS, &pixmap), ("kids", &kids)] {
+            let interval = tokio::time::interval(Duration::from_secs(60));
+            color_stats.insert(
+                name,
+                &ColorStatsSink::new(ColorStatsSinkOptions { interval }, canvas.clone()),
+            );
+        }
+        let color_stats = Arc::new(color_stats);
+        HttpServer::new(HttpServerOptions {
+            bind_addrs: vec![bind_addr],
+            watchdog: None,
+            ready,
+            reservations: Arc::new(Reservations::new(HashMap::new(), Duration::from_secs(60))),
+            canvases: canvases.clone(),
+            color_stats: color_stats.clone(),
+            activity_decay: Duration::from_secs(30),
+            allowed_origins: AllowedOrigins::only(["https://viewer.example".to_string()]),
+            #[cfg(feature = "ws")]
+            websocket: Some(WsServerOptions {
+                bind_addrs: Vec::new(),
+                strictness: Default::default(),
+                quiet: false,
+                reservations: Arc::new(Reservations::new(HashMap::new(), Duration::from_secs(60))),
+                region_limits: Arc::new([]),
+                canvases,
+                color_stats,
+                allow_fill: false,
+                capabilities: Default::default(),
+                dialect: Default::default(),
+                write_queue: Default::default(),
+                allowed_origins: AllowedOrigins::only(["https://viewer.example".to_string()]),
+                hooks: crate::net::servers::NoHooks::shared(),
+            }),
+        })
+        .start(pixmap, &mut join_set)
+        .await
+        .unwrap();
+        (bind_addr, join_set)
+    }
+
+    #[tokio::test]
+    async fn test_read_request_head_is_limited() {
+        let request = b"GET /stats HTTP/1.1\r\nOrigin: https://viewer.example\r\n\r\n";
+        let request = HttpServer::read_request(&mut &request[..]).await.unwrap();
+        assert_eq!(request.path, "/stats");
+        assert_eq!(request.origin.as_deref(), Some("https://viewer.example"));
+
+        // a line which never ends is not buffered beyond the limit
+        let endless = [b'a'; MAX_HEAD_SIZE * 4];
+        assert!(HttpServer::read_request(&mut &endless[..]).await.is_err());
+        let mut headers = b"GET / HTTP/1.1\r\n".to_vec();
+        headers.extend(std::iter::repeat_n(&b"X-Padding: 0123456789\r\n"[..], MAX_HEAD_SIZE / 16).flatten());
+        headers.extend_from_slice(b"\r\n");
+        assert!(HttpServer::read_request(&mut &headers[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("pixelpwnr/0.1"), "\"pixelpwnr/0.1\"");
+        assert_eq!(json_string("a\"b\\c\u{7}"), "\"a\\\"b\\\\c\\u0007\"");
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        request(addr, "GET", path, "").await
+    }
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, headers: &str) -> String {
+        String::from_utf8_lossy(&request_bytes(addr, method, path, headers).await).into_owned()
+    }
+
+    async fn request_bytes(addr: SocketAddr, method: &str, path: &str, headers: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    /// Split a response into its head and body
+    fn split_response(response: &[u8]) -> (String, &[u8]) {
+        let end = response.windows(4).position(|i| i == b"\r\n\r\n").unwrap();
+        (
+            String::from_utf8_lossy(&response[..end]).into_owned(),
+            &response[end + 4..],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let ready = Arc::new(AtomicBool::new(false));
+        let (addr, _join_set) = start_server(Arc::new(Pixmap::new(4, 4).unwrap()), ready.clone()).await;
+
+        assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 503 "));
+        ready.store(true, Ordering::Relaxed);
+        assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/nothing").await.starts_with("HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_canvas_endpoints() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        pixmap
+            .set_region(0, 0, 4, 2, &[Color::from(0xFF0000); 8])
+            .unwrap();
+        let (addr, _join_set) = start_server(pixmap, Arc::new(AtomicBool::new(true))).await;
+
+        assert!(get(addr, "/size")
+            .await
+            .ends_with("\r\n\r\n{\"width\":4,\"height\":2}"));
+        let stats = get(addr, "/stats").await;
+        assert!(stats.contains("\"average_color\":\"#FF0000\""));
+        assert!(stats.contains("\"active_reservations\":0"));
+        assert!(get(addr, "/claims").await.ends_with("\r\n\r\n[]"));
+        let png = get(addr, "/canvas.png").await;
+        assert!(png.contains("Content-Type: image/png\r\n"));
+        assert!(png.contains("\r\n\r\n\u{FFFD}PNG"));
+        assert!(get(addr, "/activity.png").await.starts_with("HTTP/1.1 404 "));
+        let viewer = get(addr, "/").await;
+        assert!(viewer.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(viewer.contains("/stream?encoding=delta&progressive=true"));
+
+        // the named canvases are offered below their own paths
+        assert!(get(addr, "/canvas/kids/size")
+            .await
+            .ends_with("\r\n\r\n{\"width\":2,\"height\":2}"));
+        assert!(get(addr, "/canvas/kids/stats")
+            .await
+            .contains("\"average_color\":\"#0000FF\""));
+        let raw = request_bytes(addr, "GET", "/canvas/kids/canvas.raw", "").await;
+        assert_eq!(split_response(&raw).1, [0, 0, 255].repeat(4));
+        assert!(get(addr, "/canvas/kids").await.contains("text/html"));
+        assert!(get(addr, "/canvas/default/size")
+            .await
+            .ends_with("\r\n\r\n{\"width\":4,\"height\":2}"));
+        assert!(get(addr, "/canvas/kids/claims")
+            .await
+            .starts_with("HTTP/1.1 404 "));
+        assert!(get(addr, "/canvas/adults/size")
+            .await
+            .starts_with("HTTP/1.1 404 "));
+    }
+
+    #[test]
+    fn test_claims_map() {
+        let reservations = Reservations::default();
+        reservations
+            .claim(DEFAULT_CANVAS, None, 1, 1, 2, 2, Duration::from_secs(10))
+            .unwrap();
+        reservations
+            .claim(DEFAULT_CANVAS, Some("red"), 3, 0, 5, 1, Duration::from_secs(10))
+            .unwrap();
+        reservations
+            .claim("kids", None, 0, 2, 1, 1, Duration::from_secs(10))
+            .unwrap();
+        let map = claims_map(4, 3, &reservations, DEFAULT_CANVAS);
+        let white = Color::from(0xFFFFFF);
+        let black = Color::default();
+        assert_eq!(
+            map,
+            vec![
+                black, black, black, white, //
+                black, white, white, black, //
+                black, white, white, black,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let pixmap = Arc::new(Pixmap::new(64, 64).unwrap());
+        pixmap.set_pixel(3, 0, Color::from(0x123456)).unwrap();
+        let (addr, _join_set) = start_server(pixmap.clone(), Arc::new(AtomicBool::new(true))).await;
+        let expected = |pixmap: &Pixmap| -> Vec<u8> {
+            unsafe { pixmap.get_color_data() }
+                .iter()
+                .flat_map(|&color| <[u8; 3]>::from(color))
+                .collect()
+        };
+
+        let plain = request_bytes(addr, "GET", "/canvas.raw", "").await;
+        let (head, body) = split_response(&plain);
+        assert!(!head.contains("Content-Encoding"));
+        assert_eq!(body, expected(&pixmap));
+
+        let gzip = request_bytes(addr, "GET", "/canvas.raw", "Accept-Encoding: gzip\r\n").await;
+        let (head, body) = split_response(&gzip);
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Vary: Accept-Encoding\r\n"));
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(body)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected(&pixmap));
+
+        // the cached variant must not outlive a change of the canvas
+        pixmap.set_pixel(5, 5, Color::from(0xABCDEF)).unwrap();
+        let brotli = request_bytes(addr, "GET", "/canvas.raw", "Accept-Encoding: gzip, br\r\n").await;
+        let (head, body) = split_response(&brotli);
+        assert!(head.contains("Content-Encoding: br\r\n"));
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(body, 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected(&pixmap));
+        let gzip = request_bytes(addr, "GET", "/canvas.raw", "Accept-Encoding: gzip\r\n").await;
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(split_response(&gzip).1)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, expected(&pixmap));
+
+        // small responses are not worth compressing
+        let size = request(addr, "GET", "/size", "Accept-Encoding: gzip\r\n").await;
+        assert!(!size.contains("Content-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_origins() {
+        let (addr, _join_set) = start_server(
+            Arc::new(Pixmap::new(4, 4).unwrap()),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .await;
+
+        let allowed = request(addr, "GET", "/size", "Origin: https://viewer.example\r\n").await;
+        assert!(allowed.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(allowed.contains("Access-Control-Allow-Origin: https://viewer.example\r\n"));
+        let preflight = request(
+            addr,
+            "OPTIONS",
+            "/canvas.png",
+            "Origin: https://viewer.example\r\n",
+        )
+        .await;
+        assert!(preflight.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Methods: GET, HEAD, OPTIONS\r\n"));
+
+        let hostile = request(addr, "GET", "/size", "Origin: https://evil.example\r\n").await;
+        assert!(hostile.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!hostile.contains("Access-Control-Allow-Origin"));
+        // requests which don't come from web pages are always served
+        assert!(get(addr, "/size").await.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[cfg(feature = "ws")]
+    #[tokio::test]
+    async fn test_websocket_upgrade() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (addr, _join_set) = start_server(
+            Arc::new(Pixmap::new(4, 4).unwrap()),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .await;
+
+        let (mut protocol, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await
+            .unwrap();
+        protocol.send(Message::Text("SIZE".to_string())).await.unwrap();
+        let size = protocol.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(size, "SIZE 4 4");
+
+        let (mut kids, _) = tokio_tungstenite::connect_async(format!("ws://{}/canvas/kids/ws", addr))
+            .await
+            .unwrap();
+        kids.send(Message::Text("SIZE".to_string())).await.unwrap();
+        let size = kids.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(size, "SIZE 2 2");
+
+        // the same paths are still served as plain http
+        assert!(get(addr, "/stats").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/size", addr))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use crate::net::clients::MemoryClient;
+use crate::pixmap::SharedPixmap;
+
+/// A server which is only reachable from within the same process through [`MemoryClient`]s
+///
+/// Requests of connected clients are passed directly to the request handler without going through any sockets.
+/// This makes it useful for exercising protocol logic deterministically in tests.
+#[derive(Debug, Clone)]
+pub struct MemoryServer {
+    pixmap: SharedPixmap,
+}
+
+impl MemoryServer {
+    /// Create a new server that serves the given pixmap
+    pub fn new(pixmap: SharedPixmap) -> Self {
+        Self { pixmap }
+    }
+
+    /// Connect a new client to this server
+    pub fn connect(&self) -> MemoryClient {
+        MemoryClient::new(self.pixmap.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{Request, Response, StateEncoding};
+    use crate::pixmap::{Color, Pixmap};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_set_and_get_pixel() {
+        let server = MemoryServer::new(Arc::new(Pixmap::new(10, 10).unwrap()));
+        let mut client = server.connect();
+
+        let color = Color::from(0xABCDEF);
+        client
+            .send_request(Request::SetPixel { x: 2, y: 3, color })
+            .await
+            .unwrap();
+        let response = client.exchange(Request::GetPixel { x: 2, y: 3 }).await.unwrap();
+        assert_eq!(response, Response::PxData { x: 2, y: 3, color });
+    }
+
+    #[tokio::test]
+    async fn test_get_region() {
+        let server = MemoryServer::new(Arc::new(Pixmap::new(10, 10).unwrap()));
+        let mut client = server.connect();
+
+        let response = client
+            .exchange(Request::GetRegion {
+                x: 8,
+                y: 8,
+                width: 2,
+                height: 2,
+                encoding: StateEncoding::Rgb64,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Response::Region {
+                x: 8,
+                y: 8,
+                width: 2,
+                height: 2,
+                encoding: StateEncoding::Rgb64,
+                data: vec![Color::default(); 4],
+            }
+        );
+
+        // out-of-bounds requests are answered with an error
+        assert!(client.exchange(Request::GetPixel { x: 10, y: 0 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_quiet_mode() {
+        let server = MemoryServer::new(Arc::new(Pixmap::new(10, 10).unwrap()));
+        let mut client = server.connect();
+
+        client.send_request(Request::Quiet(true)).await.unwrap();
+        let error = client
+            .exchange(Request::GetPixel { x: 10, y: 0 })
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "server has not sent a response");
+        // requested data is still sent
+        assert_eq!(
+            client.exchange(Request::GetSize).await.unwrap(),
+            Response::Size {
+                width: 10,
+                height: 10
+            }
+        );
+
+        client.send_request(Request::Quiet(false)).await.unwrap();
+        assert!(client
+            .exchange(Request::GetPixel { x: 10, y: 0 })
+            .await
+            .unwrap_err()
+            .to_string()
+            .starts_with("server responded with an error"));
+    }
+
+    #[tokio::test]
+    async fn test_get_pixels() {
+        let server = MemoryServer::new(Arc::new(Pixmap::new(10, 10).unwrap()));
+        let mut client = server.connect();
+
+        let color = Color::from(0xABCDEF);
+        client
+            .send_request(Request::SetPixel { x: 2, y: 3, color })
+            .await
+            .unwrap();
+        let response = client
+            .exchange(Request::GetPixels {
+                pixels: vec![(2, 3), (0, 0)],
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Response::PxDataBatch {
+                pixels: vec![(2, 3, color), (0, 0, Color::default())],
+            }
+        );
+
+        assert!(client
+            .exchange(Request::GetPixels {
+                pixels: vec![(2, 3), (10, 0)],
+            })
+            .await
+            .is_err());
+    }
+}
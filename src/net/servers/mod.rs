@@ -1,38 +1,349 @@
 //! Server implementations for different transport protocols
 
+mod access_log;
+mod bind;
+mod capabilities;
+mod channels;
+mod client_stats;
+mod compression;
+mod conn_limits;
+mod conn_pool;
+mod control_server;
+mod dialect;
 mod gen_server;
+mod hooks;
+mod http_server;
+mod memory_server;
+mod origins;
+mod progressive;
+mod read_buffer;
+mod region_limits;
+mod reservations;
+mod subscription;
+mod write_queue;
 
 #[cfg(test)]
 mod benchmark;
 
+pub use access_log::{AccessLog, AccessLogFormat, AccessLogOptions};
+pub use capabilities::{Capabilities, Capability};
+pub(crate) use channels::Channels;
+pub(crate) use client_stats::write_prometheus as write_client_metrics;
+pub use client_stats::{
+    client_stats, enable_client_stats, ClientBucket, ClientStats, ClientStatsOptions, TopClient,
+};
+pub(crate) use conn_pool::write_prometheus as write_pool_metrics;
+pub use conn_pool::DEFAULT_CONNECTION_POOL_SIZE;
+pub use control_server::{
+    ControlServer, ControlServerOptions, RequestIds, MAX_REQUEST_ID_LEN, REQUEST_ID_CAPACITY,
+};
+pub use dialect::{Dialect, InvalidDialectError};
 pub use gen_server::GenServer;
+pub use hooks::{Admission, ConnectionHooks, ConnectionSummary, NoHooks, PeerInfo};
+pub use http_server::{HttpServer, HttpServerOptions};
+pub use memory_server::MemoryServer;
+pub use origins::AllowedOrigins;
+pub use read_buffer::ReadBufferLimits;
+pub use region_limits::RegionRateLimit;
+pub use reservations::{Claim, Reservation, Reservations};
+pub use write_queue::{LagPolicy, WriteQueueLimits};
 
 #[cfg(feature = "tcp")]
 mod tcp_server;
+#[cfg(feature = "tls")]
+mod tcp_tls_server;
 #[cfg(feature = "udp")]
 mod udp_server;
 mod unix_sock_server;
 #[cfg(feature = "ws")]
 mod ws_server;
 
-use crate::net::protocol::{parse_request_bin, Request, Response};
-use crate::pixmap::SharedPixmap;
+use crate::metrics::{Command, Phase};
+use crate::net::protocol::{
+    parse_request_bin_with, write_channel_framed, DecoderEvent, Extension, Request, RequestDecoder, Response,
+    Strictness, MAX_BATCH_PIXELS, MAX_REGION_PIXELS,
+};
+use crate::pixmap::{Canvases, Color, Pixmap, ProtectedWrites, SharedPixmap, WatchedRegion, DEFAULT_CANVAS};
+use crate::sinks::color_stats::LatestColorStats;
+use bytes::{BufMut, BytesMut};
+use client_stats::ClientCounter;
+use region_limits::RegionRateLimiter;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What clients are told before they are disconnected because their canvas was [retired](Pixmap::retire)
+pub(crate) const RETIRED_MESSAGE: &str = "canvas was resized, please reconnect";
+
+/// The path prefix below which the HTTP and WebSocket servers offer each of the named canvases
+pub(crate) const CANVAS_PATH_PREFIX: &str = "/canvas/";
+
+/// Resolve the canvas which a requested path selects via a `/canvas/<name>` prefix
+///
+/// Returns the path without the prefix together with the name of the selected canvas and the canvas itself, which is
+/// `None` for the main canvas and for paths without a prefix, or `None` altogether if there is no canvas with that
+/// name.
+pub(crate) fn route_canvas<'a>(
+    path: &'a str,
+    canvases: &Canvases,
+) -> Option<(&'a str, &'a str, Option<SharedPixmap>)> {
+    let Some(rest) = path.strip_prefix(CANVAS_PATH_PREFIX) else {
+        return Some((path, DEFAULT_CANVAS, None));
+    };
+    let (name, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let canvas = canvases.get(name)?;
+    Some((path, name, canvas.cloned()))
+}
 
 #[cfg(feature = "tcp")]
 pub use tcp_server::{TcpServer, TcpServerOptions};
+#[cfg(feature = "tls")]
+pub use tcp_tls_server::{TcpTlsServer, TcpTlsServerOptions};
 #[cfg(feature = "udp")]
 pub use udp_server::{UdpServer, UdpServerOptions};
 pub use unix_sock_server::{UnixSocketOptions, UnixSocketServer};
 #[cfg(feature = "ws")]
 pub use ws_server::{WsServer, WsServerOptions};
 
+/// The protocol extensions which are supported by all servers
+const SUPPORTED_EXTENSIONS: &[Extension] = &[
+    Extension::StateRegion,
+    Extension::PxBatch,
+    Extension::BinaryPx,
+    Extension::Subscribe,
+    Extension::Canvas,
+    Extension::PxRect,
+    Extension::Claims,
+    Extension::Stats,
+];
+
+/// State of one client connection which is kept between requests
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    /// How forgiving the request parser is about whitespace in received lines
+    strictness: Strictness,
+    /// Which form of the protocol the connection may use
+    dialect: Dialect,
+    /// Whether errors and acknowledgements are suppressed so that flooding clients don't receive unread responses
+    quiet: bool,
+    /// Whether new connections and channels start out quiet as configured for the listener
+    quiet_by_default: bool,
+    /// The canvas reservations which are enforced for this connection
+    reservations: Arc<Reservations>,
+    /// The team as which the connection has authenticated itself
+    team: Option<String>,
+    /// How many more pixels the connection may currently set in rate limited regions
+    rate_limiter: RegionRateLimiter,
+    /// Whether the client wants to receive pixel changes
+    ///
+    /// Servers which support subscriptions observe this after handling requests and start or stop sending changes.
+    subscribed: bool,
+    /// The rectangle to which the subscription is limited or `None` if the client wants all changes
+    watched_region: Option<WatchedRegion>,
+    /// Whether the server can send pixel changes over the connection at all
+    subscriptions_supported: bool,
+    /// The additional canvases which the connection may select
+    canvases: Arc<Canvases>,
+    /// The latest color statistics of the canvases, e.g. for the `/stats` endpoint of the websocket server
+    color_stats: Arc<LatestColorStats>,
+    /// The canvas selected via `CANVAS` or `None` if the connection operates on the main canvas of the server
+    canvas: Option<SharedPixmap>,
+    /// The name of the selected canvas under which its reservations and claims are kept, `None` for the main canvas
+    canvas_name: Option<String>,
+    /// Whether the connection may overwrite the whole canvas via `CLEAR` and `FILL`
+    fill_allowed: bool,
+    /// The optional protocol features which the connection may use
+    capabilities: Capabilities,
+    /// How many responses are queued before they are sent and how lagging subscriptions are treated
+    write_queue: WriteQueueLimits,
+    /// Counts the pixels which the client writes if client statistics are enabled and its address is known
+    client: Option<ClientCounter>,
+    /// The user agent which the client sent via `HELLO`
+    user_agent: Option<Arc<str>>,
+    /// What the client has done so far, which servers report to their hooks when the connection is closed
+    pub(crate) summary: ConnectionSummary,
+}
+
+impl Session {
+    /// Create the state for a new, unauthenticated connection
+    pub(crate) fn new(
+        strictness: Strictness,
+        quiet: bool,
+        reservations: Arc<Reservations>,
+        region_limits: Arc<[RegionRateLimit]>,
+    ) -> Self {
+        Self {
+            strictness,
+            dialect: Dialect::default(),
+            quiet,
+            quiet_by_default: quiet,
+            reservations,
+            team: None,
+            rate_limiter: RegionRateLimiter::new(region_limits),
+            subscribed: false,
+            watched_region: None,
+            subscriptions_supported: true,
+            canvases: Arc::new(Canvases::default()),
+            color_stats: Arc::default(),
+            canvas: None,
+            canvas_name: None,
+            fill_allowed: false,
+            capabilities: Capabilities::default(),
+            write_queue: WriteQueueLimits::default(),
+            client: None,
+            user_agent: None,
+            summary: ConnectionSummary::default(),
+        }
+    }
+
+    /// Allow the connection to select one of the given canvases instead of the main canvas
+    pub(crate) fn with_canvases(mut self, canvases: Arc<Canvases>) -> Self {
+        self.canvases = canvases;
+        self
+    }
+
+    /// Report the color statistics of the canvases which are collected in `color_stats`
+    #[cfg(feature = "ws")]
+    pub(crate) fn with_color_stats(mut self, color_stats: Arc<LatestColorStats>) -> Self {
+        self.color_stats = color_stats;
+        self
+    }
+
+    /// Allow or forbid the connection to overwrite the whole canvas via `CLEAR` and `FILL`
+    pub(crate) fn with_fill(mut self, allowed: bool) -> Self {
+        self.fill_allowed = allowed;
+        self
+    }
+
+    /// Restrict the connection to the given form of the protocol
+    pub(crate) fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Restrict the connection to the given optional protocol features
+    pub(crate) fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Limit how many responses are queued for the connection before they have to be sent
+    pub(crate) fn with_write_queue(mut self, limits: WriteQueueLimits) -> Self {
+        self.write_queue = limits;
+        self
+    }
+
+    /// The canvas on which requests of this session operate
+    pub(crate) fn canvas<'a>(&'a self, server_pixmap: &'a SharedPixmap) -> &'a SharedPixmap {
+        self.canvas.as_ref().unwrap_or(server_pixmap)
+    }
+
+    /// The name of the canvas on which requests of this session operate
+    pub(crate) fn canvas_name(&self) -> &str {
+        self.canvas_name.as_deref().unwrap_or(DEFAULT_CANVAS)
+    }
+
+    /// Operate on the canvas with the given name which is `None` for the main canvas of the server
+    pub(crate) fn select_canvas(&mut self, name: &str, canvas: Option<SharedPixmap>) {
+        self.canvas_name = canvas.as_ref().map(|_| name.to_string());
+        self.canvas = canvas;
+    }
+
+    /// Attribute the pixels which are written in this session to the client with the given address
+    #[cfg(any(feature = "tcp", feature = "udp", feature = "ws", test))]
+    pub(crate) fn set_client_addr(&mut self, addr: std::net::IpAddr) {
+        self.client = ClientCounter::new(addr);
+        if let (Some(client), Some(user_agent)) = (&mut self.client, &self.user_agent) {
+            client.set_user_agent(user_agent.clone());
+        }
+    }
+
+    /// Remember the user agent which the client sent via `HELLO` and attribute its pixels to it
+    fn set_user_agent(&mut self, user_agent: &str) {
+        let user_agent = Arc::<str>::from(user_agent);
+        if let Some(client) = &mut self.client {
+            client.set_user_agent(user_agent.clone());
+        }
+        self.user_agent = Some(user_agent);
+    }
+
+    /// Reject `SUBSCRIBE` because the server has no connection over which it could send changes
+    pub(crate) fn without_subscriptions(mut self) -> Self {
+        self.subscriptions_supported = false;
+        self
+    }
+
+    /// Create the session of a new multiplexed channel which starts out like a new connection
+    ///
+    /// The state which belongs to the whole connection is lent to it by [`Channels`].
+    fn for_channel(&self) -> Session {
+        Session {
+            quiet: self.quiet_by_default,
+            team: None,
+            subscribed: false,
+            watched_region: None,
+            canvas: None,
+            canvas_name: None,
+            client: None,
+            user_agent: None,
+            summary: ConnectionSummary::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Turn this session back into a copy of the fresh session `template` while reusing its memory
+    pub(crate) fn reset_from(&mut self, template: &Session) {
+        self.strictness = template.strictness;
+        self.dialect = template.dialect;
+        self.write_queue = template.write_queue;
+        self.quiet = template.quiet_by_default;
+        self.quiet_by_default = template.quiet_by_default;
+        self.reservations = template.reservations.clone();
+        self.team = None;
+        self.rate_limiter.reset_from(&template.rate_limiter);
+        self.subscribed = false;
+        self.watched_region = None;
+        self.subscriptions_supported = template.subscriptions_supported;
+        self.canvases = template.canvases.clone();
+        self.color_stats = template.color_stats.clone();
+        self.canvas = None;
+        self.canvas_name = None;
+        self.fill_allowed = template.fill_allowed;
+        self.capabilities = template.capabilities;
+        self.client = None;
+        self.user_agent = None;
+        self.summary = ConnectionSummary::default();
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(
+            Strictness::default(),
+            false,
+            Arc::new(Reservations::default()),
+            Arc::new([]),
+        )
+    }
+}
+
 /// Handle a single request
 ///
 /// This is the core request handling method that is run by all servers.
 /// It parses requests, handles them and generates responses.
 /// The actual IO is left to the specific server though.
-#[allow(unused)]
-fn handle_request(line: &[u8], pixmap: &SharedPixmap) -> Result<Option<Response>, String> {
+///
+/// Writes are applied to the pixmap before this returns, which gives clients read-your-writes consistency as long
+/// as servers call this in the order in which requests of a connection arrive.
+/// Servers must therefore neither reorder requests of one connection nor hold back its writes.
+/// Only datagrams of UDP clients may be handled out of order because they don't form a connection.
+pub(crate) fn handle_request(
+    line: &[u8],
+    pixmap: &SharedPixmap,
+    session: &mut Session,
+) -> Result<Option<Response>, String> {
     tracing::trace!(
         "Handling single request {:?}",
         match line.is_ascii() {
@@ -41,23 +352,715 @@ fn handle_request(line: &[u8], pixmap: &SharedPixmap) -> Result<Option<Response>
         }
     );
 
-    let parse_result = parse_request_bin(line);
-    match parse_result {
-        Err(e) => Err(e.to_string()),
-        Ok(request) => match request {
-            Request::Help(topic) => Ok(Some(Response::Help(topic))),
-            Request::GetSize => {
-                let (width, height) = pixmap.get_size();
-                Ok(Some(Response::Size { width, height }))
+    let result = match crate::metrics::latency_metrics_enabled() {
+        false => parse_request(line, session).and_then(|request| {
+            session.summary.count_command(Command::of_request(&request));
+            execute_request(request, pixmap, session)
+        }),
+        true => handle_request_timed(line, pixmap, session),
+    };
+    session.summary.requests += 1;
+    session.summary.errors += u64::from(result.is_err());
+
+    // quiet clients only receive the data which they explicitly asked for
+    match result {
+        Err(_) if session.quiet => Ok(None),
+        Ok(Some(Response::Authenticated { .. } | Response::Reserved { .. } | Response::Claimed { .. }))
+            if session.quiet =>
+        {
+            Ok(None)
+        }
+        result => result,
+    }
+}
+
+/// Handle all complete requests which `decoder` finds in `buf` and write their responses into `resp_buf`
+///
+/// Multiplexed requests are handled in the session of their channel and their responses are framed with the channel
+/// id. Incomplete data is left in `buf` until more of it has been received.
+///
+/// Handling stops early once the responses reach the capacity of the session's write queue. In that case `true` is
+/// returned and the caller has to send the responses before calling this again to handle the remaining requests.
+pub(crate) fn handle_frames(
+    decoder: &RequestDecoder,
+    buf: &mut BytesMut,
+    pixmap: &SharedPixmap,
+    session: &mut Session,
+    resp_buf: &mut BytesMut,
+    channels: &mut Channels,
+) -> bool {
+    let write_result = |result: Result<Option<Response>, String>, out: &mut BytesMut| match result {
+        Err(e) => write_error(&e, out),
+        Ok(Some(response)) => write_response(&response, out),
+        Ok(None) => {}
+    };
+    while let Some(event) = decoder.decode(buf) {
+        match event {
+            DecoderEvent::Request { channel: None, frame } => {
+                write_result(handle_request(&frame, pixmap, session), resp_buf);
+            }
+            DecoderEvent::Request {
+                channel: Some(channel),
+                frame,
+            } => {
+                let result = channels
+                    .with_session(channel, session, |session| {
+                        handle_request(&frame, pixmap, session)
+                    })
+                    .unwrap_or_else(|| Err("too many channels".to_string()));
+                write_result(result, &mut channels.buf);
+                write_channel_framed(channel, &channels.buf, &mut (&mut *resp_buf).writer()).unwrap();
+                channels.buf.clear();
             }
-            Request::GetPixel { x, y } => {
-                let color = pixmap.get_pixel(x, y).map_err(|e| format!("{}", e))?;
-                Ok(Some(Response::PxData { x, y, color }))
+            DecoderEvent::LineTooLong { discarded } => {
+                tracing::warn!(
+                    "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
+                    discarded
+                );
+                resp_buf.put_slice(b"line too long\n");
             }
-            Request::SetPixel { x, y, color } => {
+        }
+        if resp_buf.len() >= session.write_queue.capacity {
+            return true;
+        }
+    }
+    false
+}
+
+/// Parse a single request which must belong to the dialect of the session
+#[inline(always)]
+fn parse_request(line: &[u8], session: &Session) -> Result<Request, String> {
+    let request = parse_request_bin_with(line, session.strictness).map_err(|e| e.to_string())?;
+    session.dialect.check(line, &request)?;
+    Ok(request)
+}
+
+/// Handle a single request while recording how long each phase takes
+fn handle_request_timed(
+    line: &[u8],
+    pixmap: &SharedPixmap,
+    session: &mut Session,
+) -> Result<Option<Response>, String> {
+    let start = Instant::now();
+    let request = parse_request(line, session)?;
+    let command = Command::of_request(&request);
+    crate::metrics::record(command, Phase::Parse, start.elapsed());
+    session.summary.count_command(command);
+    let start = Instant::now();
+    let result = execute_request(request, pixmap, session);
+    if !matches!(
+        command,
+        Command::Hello
+            | Command::Help
+            | Command::Auth
+            | Command::Reserve
+            | Command::Claim
+            | Command::Quiet
+            | Command::Subscribe
+            | Command::Canvas
+            | Command::Fill
+            | Command::Stats
+    ) {
+        crate::metrics::record(command, Phase::PixmapAccess, start.elapsed());
+    }
+    result
+}
+
+/// Serialize a response into the output buffer of a server
+pub(crate) fn write_response(response: &Response, buf: &mut BytesMut) {
+    if !crate::metrics::latency_metrics_enabled() {
+        return response.encode(buf);
+    }
+
+    let start = Instant::now();
+    response.encode(buf);
+    crate::metrics::record(
+        Command::of_response(response),
+        Phase::ResponseWrite,
+        start.elapsed(),
+    );
+}
+
+/// Serialize the error message of a failed request into the output buffer of a server
+pub(crate) fn write_error(error: &str, buf: &mut BytesMut) {
+    buf.put_slice(error.as_bytes());
+    buf.put_u8(b'\n');
+}
+
+/// Check that the session may currently write the pixel at (x,y) of `pixmap`
+///
+/// `Ok(false)` means that the write must be dropped without telling the client.
+#[inline(always)]
+fn authorize_pixel_write(pixmap: &Pixmap, session: &mut Session, x: usize, y: usize) -> Result<bool, String> {
+    if pixmap.is_frozen() {
+        return Err("the canvas is frozen".to_string());
+    }
+    if pixmap.is_protected(x, y) {
+        return match pixmap.protected_writes() {
+            ProtectedWrites::Drop => Ok(false),
+            ProtectedWrites::Reject => Err("pixel is protected".to_string()),
+        };
+    }
+    if !session
+        .reservations
+        .may_write(session.canvas_name(), session.team.as_deref(), x, y)
+    {
+        return Err("pixel is reserved by another team".to_string());
+    }
+    if let Err(limit) = session.rate_limiter.acquire(x, y) {
+        return Err(format!(
+            "pixels in this region may only be set {} times per second",
+            limit.pixels_per_sec
+        ));
+    }
+    Ok(true)
+}
+
+/// Execute an already parsed request on the canvas which is selected by the session
+#[inline(always)]
+fn execute_request(
+    request: Request,
+    pixmap: &SharedPixmap,
+    session: &mut Session,
+) -> Result<Option<Response>, String> {
+    if let Some(capability) = Capability::required_by(&request) {
+        if !session.capabilities.contains(capability) {
+            return Err(capability.rejection().to_string());
+        }
+    }
+    if let Request::SelectCanvas(name) = request {
+        let canvas = session
+            .canvases
+            .get(&name)
+            .ok_or_else(|| format!("there is no canvas named {}", name))?;
+        session.select_canvas(&name, canvas.cloned());
+        return Ok(None);
+    }
+
+    // the selected canvas is moved out of the session instead of cloned so that its reference count is not touched
+    // for every request
+    let canvas = session.canvas.take();
+    let result = execute_on_canvas(request, canvas.as_ref().unwrap_or(pixmap), session);
+    session.canvas = canvas;
+    result
+}
+
+/// Read the colors of a region which a client requested to download
+///
+/// Regions must not be empty and may contain at most [`MAX_REGION_PIXELS`] pixels.
+fn read_region(
+    pixmap: &Pixmap,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> Result<Vec<Color>, String> {
+    if width == 0 || height == 0 {
+        return Err("region must not be empty".to_string());
+    }
+    if width.saturating_mul(height) > MAX_REGION_PIXELS {
+        return Err(format!(
+            "region must not contain more than {} pixels",
+            MAX_REGION_PIXELS
+        ));
+    }
+    pixmap
+        .get_region(x, y, width, height)
+        .map_err(|e| format!("{}", e))
+}
+
+/// Execute an already parsed request on the given pixmap
+#[inline(always)]
+fn execute_on_canvas(
+    request: Request,
+    pixmap: &SharedPixmap,
+    session: &mut Session,
+) -> Result<Option<Response>, String> {
+    match request {
+        Request::Hello {
+            user_agent,
+            extensions,
+        } => {
+            tracing::debug!("Client identified itself as {:?}", user_agent);
+            session.set_user_agent(&user_agent);
+            Ok(Some(Response::Hello {
+                server_agent: concat!("pixeldike/", env!("CARGO_PKG_VERSION")).to_string(),
+                extensions: extensions
+                    .into_iter()
+                    .filter(|i| {
+                        SUPPORTED_EXTENSIONS.contains(i) || (*i == Extension::Fill && session.fill_allowed)
+                    })
+                    .filter(|i| {
+                        Capability::required_by_extension(*i).is_none_or(|i| session.capabilities.contains(i))
+                    })
+                    .collect(),
+            }))
+        }
+        Request::Help(topic) => Ok(Some(Response::Help(topic))),
+        Request::GetSize => {
+            let (width, height) = pixmap.get_size();
+            Ok(Some(Response::Size { width, height }))
+        }
+        Request::GetPixel { x, y } => {
+            let color = pixmap.get_pixel(x, y).map_err(|e| format!("{}", e))?;
+            Ok(Some(Response::PxData { x, y, color }))
+        }
+        Request::GetPixels { pixels } => {
+            if pixels.len() > MAX_BATCH_PIXELS {
+                return Err(format!(
+                    "batch must not contain more than {} pixels",
+                    MAX_BATCH_PIXELS
+                ));
+            }
+            let pixels = pixels
+                .into_iter()
+                .map(|(x, y)| Ok((x, y, pixmap.get_pixel(x, y).map_err(|e| format!("{}", e))?)))
+                .collect::<Result<_, String>>()?;
+            Ok(Some(Response::PxDataBatch { pixels }))
+        }
+        Request::SetPixel { x, y, color } => {
+            if authorize_pixel_write(pixmap, session, x, y)? {
                 pixmap.set_pixel(x, y, color).map_err(|e| format!("{}", e))?;
-                Ok(None)
+                if let Some(client) = &mut session.client {
+                    client.count();
+                }
+            }
+            Ok(None)
+        }
+        Request::BlendPixel { x, y, color, alpha } => {
+            if authorize_pixel_write(pixmap, session, x, y)? {
+                pixmap
+                    .blend_pixel(x, y, color, alpha)
+                    .map_err(|e| format!("{}", e))?;
+                if let Some(client) = &mut session.client {
+                    client.count();
+                }
+            }
+            Ok(None)
+        }
+        Request::GetRegion {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+        } => Ok(Some(Response::Region {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+            data: read_region(pixmap, x, y, width, height)?,
+        })),
+        Request::GetRect {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+        } => Ok(Some(Response::Rect {
+            x,
+            y,
+            width,
+            height,
+            encoding,
+            data: read_region(pixmap, x, y, width, height)?,
+        })),
+        Request::Quiet(quiet) => {
+            session.quiet = quiet;
+            Ok(None)
+        }
+        Request::Subscribe(subscribe) => {
+            if !session.subscriptions_supported {
+                return Err("subscriptions require a connection-oriented transport".to_string());
+            }
+            if pixmap.changes().is_none() {
+                return Err("subscriptions are not enabled on this server".to_string());
+            }
+            session.subscribed = subscribe;
+            session.watched_region = None;
+            Ok(None)
+        }
+        Request::SubscribeRegion { x, y, width, height } => {
+            if !session.subscriptions_supported {
+                return Err("subscriptions require a connection-oriented transport".to_string());
+            }
+            if pixmap.changes().is_none() {
+                return Err("subscriptions are not enabled on this server".to_string());
+            }
+            if width == 0 || height == 0 {
+                return Err("region must not be empty".to_string());
+            }
+            let (canvas_width, canvas_height) = pixmap.get_size();
+            if x.saturating_add(width) > canvas_width || y.saturating_add(height) > canvas_height {
+                return Err("region is not inside the canvas".to_string());
+            }
+            session.subscribed = true;
+            session.watched_region = Some(WatchedRegion { x, y, width, height });
+            Ok(None)
+        }
+        Request::SelectCanvas(_) => unreachable!("canvases are selected before executing requests on one"),
+        Request::Auth { team, token } => {
+            if !session.reservations.authenticate(&team, &token) {
+                return Err("invalid team or token".to_string());
+            }
+            tracing::info!("Client authenticated as team {}", team);
+            session.team = Some(team.clone());
+            Ok(Some(Response::Authenticated { team }))
+        }
+        Request::Reserve {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        } => {
+            let Some(team) = &session.team else {
+                return Err("reserving requires authentication via AUTH".to_string());
+            };
+            let (canvas_width, canvas_height) = pixmap.get_size();
+            if x.saturating_add(width) > canvas_width || y.saturating_add(height) > canvas_height {
+                return Err("reservation is not inside the canvas".to_string());
+            }
+            session.reservations.reserve(
+                session.canvas_name(),
+                team,
+                x,
+                y,
+                width,
+                height,
+                Duration::from_secs(seconds),
+            )?;
+            Ok(Some(Response::Reserved {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            }))
+        }
+        Request::Claim {
+            x,
+            y,
+            width,
+            height,
+            seconds,
+        } => {
+            let (canvas_width, canvas_height) = pixmap.get_size();
+            if x.saturating_add(width) > canvas_width || y.saturating_add(height) > canvas_height {
+                return Err("claim is not inside the canvas".to_string());
             }
-        },
+            session.reservations.claim(
+                session.canvas_name(),
+                session.team.as_deref(),
+                x,
+                y,
+                width,
+                height,
+                Duration::from_secs(seconds),
+            )?;
+            Ok(Some(Response::Claimed {
+                x,
+                y,
+                width,
+                height,
+                seconds,
+            }))
+        }
+        Request::GetClaims => {
+            let now = Instant::now();
+            let claims = session
+                .reservations
+                .claims()
+                .into_iter()
+                .filter(|i| i.canvas == session.canvas_name())
+                .map(|i| {
+                    // rounded up so that claims which are about to expire aren't reported with zero seconds
+                    let remaining = i.expires_at.saturating_duration_since(now);
+                    let seconds = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                    (i.x, i.y, i.width, i.height, seconds)
+                })
+                .collect();
+            Ok(Some(Response::Claims { claims }))
+        }
+        Request::Clear => fill_canvas(pixmap, session, Color::default()),
+        Request::Fill(color) => fill_canvas(pixmap, session, color),
+        Request::GetStats => {
+            let stats = client_stats().ok_or("client statistics are not enabled on this server")?;
+            // connections without an address, e.g. on unix sockets, are not counted
+            let (pixels, rank) = match &mut session.client {
+                Some(client) => {
+                    client.flush();
+                    let bucket = stats.bucket(client.addr());
+                    let rank = stats.top().iter().position(|(i, _)| *i == bucket).map(|i| i + 1);
+                    (stats.estimate(client.addr()), rank)
+                }
+                None => (0, None),
+            };
+            Ok(Some(Response::Stats {
+                pixels,
+                total: stats.total(),
+                rank,
+            }))
+        }
+    }
+}
+
+/// Overwrite the whole canvas with one color if the session is allowed to do so
+///
+/// Since the canvas is written in bulk, this is refused as a whole if any pixel could not be written individually
+/// because it is protected or reserved by another team.
+fn fill_canvas(pixmap: &Pixmap, session: &Session, color: Color) -> Result<Option<Response>, String> {
+    if !session.fill_allowed {
+        return Err("CLEAR and FILL are not enabled on this server".to_string());
+    }
+    if pixmap.is_frozen() {
+        return Err("the canvas is frozen".to_string());
+    }
+    if !pixmap.protected_regions().is_empty() {
+        return Err("the canvas has protected regions".to_string());
+    }
+    if session
+        .reservations
+        .active()
+        .iter()
+        .any(|i| i.canvas == session.canvas_name() && Some(i.team.as_str()) != session.team.as_deref())
+    {
+        return Err("parts of the canvas are reserved by another team".to_string());
+    }
+    pixmap.fill(color);
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::StateEncoding;
+    use crate::pixmap::ProtectedRegion;
+
+    #[test]
+    fn test_protected_writes() {
+        let region = ProtectedRegion {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let dropping = Arc::new(Pixmap::new(20, 20).unwrap());
+        dropping.protect(region).unwrap();
+        let rejecting = Arc::new(
+            Pixmap::new(20, 20)
+                .unwrap()
+                .with_protected_writes(ProtectedWrites::Reject),
+        );
+        rejecting.protect(region).unwrap();
+        let mut session = Session::default();
+
+        assert_eq!(
+            handle_request(b"PX 5 5 FF0000\n", &dropping, &mut session),
+            Ok(None)
+        );
+        assert!(handle_request(b"PX 5 5 FF0000\n", &rejecting, &mut session).is_err());
+        assert!(handle_request(b"PX 5 5 FF000080\n", &rejecting, &mut session).is_err());
+        assert_eq!(dropping.get_pixel(5, 5).unwrap(), Color::default());
+        assert_eq!(rejecting.get_pixel(5, 5).unwrap(), Color::default());
+
+        assert_eq!(
+            handle_request(b"PX 10 5 FF0000\n", &rejecting, &mut session),
+            Ok(None)
+        );
+        assert_eq!(rejecting.get_pixel(10, 5).unwrap(), Color::from(0xFF0000));
+    }
+
+    #[test]
+    fn test_write_queue_capacity() {
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut session = Session::default().with_write_queue(WriteQueueLimits {
+            capacity: 12,
+            ..Default::default()
+        });
+        let decoder = RequestDecoder::new();
+        let mut buf = BytesMut::from(&b"SIZE\nSIZE\nSIZE\n"[..]);
+        let (mut resp_buf, mut channels) = (BytesMut::new(), Channels::default());
+
+        // handling stops once the queued responses reach the capacity and continues after they were sent
+        assert!(handle_frames(
+            &decoder,
+            &mut buf,
+            &pixmap,
+            &mut session,
+            &mut resp_buf,
+            &mut channels
+        ));
+        assert_eq!(&resp_buf[..], b"SIZE 4 4\nSIZE 4 4\n");
+        assert_eq!(&buf[..], b"SIZE\n");
+        resp_buf.clear();
+        assert!(!handle_frames(
+            &decoder,
+            &mut buf,
+            &pixmap,
+            &mut session,
+            &mut resp_buf,
+            &mut channels
+        ));
+        assert_eq!(&resp_buf[..], b"SIZE 4 4\n");
+    }
+
+    #[test]
+    fn test_channel_sessions() {
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        let mut canvases = Canvases::default();
+        canvases
+            .insert("kids".to_string(), Arc::new(Pixmap::new(2, 2).unwrap()))
+            .unwrap();
+        let mut session = Session::default().with_canvases(Arc::new(canvases));
+        let decoder = RequestDecoder::new().with_multiplexing(true);
+        let mut buf = BytesMut::from(
+            &b"@1 QUIET ON\n@2 CANVAS kids\n@1 PX 9 9\n@2 PX 9 9\n@1 SIZE\n@2 SIZE\nPX 9 9\nSIZE\n"[..],
+        );
+        let (mut resp_buf, mut channels) = (BytesMut::new(), Channels::default());
+        handle_frames(
+            &decoder,
+            &mut buf,
+            &pixmap,
+            &mut session,
+            &mut resp_buf,
+            &mut channels,
+        );
+
+        // only channel 1 is quiet and only channel 2 operates on the other canvas
+        let lines = std::str::from_utf8(&resp_buf)
+            .unwrap()
+            .lines()
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("@2 "));
+        assert_eq!(lines[1], "@1 SIZE 4 4");
+        assert_eq!(lines[2], "@2 SIZE 2 2");
+        assert!(!lines[3].starts_with('@'));
+        assert_eq!(lines[4], "SIZE 4 4");
+        assert_eq!(session.summary.requests, 8);
+    }
+
+    #[test]
+    fn test_route_canvas() {
+        let kids = Arc::new(Pixmap::new(2, 2).unwrap());
+        let mut canvases = Canvases::default();
+        canvases.insert("kids".to_string(), kids.clone()).unwrap();
+
+        assert!(matches!(
+            route_canvas("/stream", &canvases),
+            Some(("/stream", DEFAULT_CANVAS, None))
+        ));
+        assert!(matches!(
+            route_canvas("/canvas.png", &canvases),
+            Some(("/canvas.png", DEFAULT_CANVAS, None))
+        ));
+        assert!(matches!(
+            route_canvas("/canvas/default/size", &canvases),
+            Some(("/size", DEFAULT_CANVAS, None))
+        ));
+        let Some(("/", "kids", Some(canvas))) = route_canvas("/canvas/kids", &canvases) else {
+            panic!("canvas is not selected");
+        };
+        assert!(Arc::ptr_eq(&canvas, &kids));
+        assert!(matches!(
+            route_canvas("/canvas/kids/stream", &canvases),
+            Some(("/stream", "kids", Some(_)))
+        ));
+        assert!(route_canvas("/canvas/adults/stream", &canvases).is_none());
+        assert!(route_canvas("/canvas/", &canvases).is_none());
+    }
+
+    #[test]
+    fn test_fill() {
+        let pixmap = Arc::new(Pixmap::new(20, 20).unwrap());
+        let mut session = Session::default();
+        assert!(handle_request(b"FILL 00FF00\n", &pixmap, &mut session).is_err());
+        assert_eq!(pixmap.get_pixel(5, 5).unwrap(), Color::default());
+        assert_eq!(
+            handle_request(b"HELLO test fill\n", &pixmap, &mut session),
+            Ok(Some(Response::Hello {
+                server_agent: concat!("pixeldike/", env!("CARGO_PKG_VERSION")).to_string(),
+                extensions: vec![],
+            }))
+        );
+
+        let mut session = Session::default().with_fill(true);
+        assert_eq!(
+            handle_request(b"HELLO test fill\n", &pixmap, &mut session),
+            Ok(Some(Response::Hello {
+                server_agent: concat!("pixeldike/", env!("CARGO_PKG_VERSION")).to_string(),
+                extensions: vec![Extension::Fill],
+            }))
+        );
+        assert_eq!(handle_request(b"FILL 00FF00\n", &pixmap, &mut session), Ok(None));
+        assert_eq!(pixmap.get_pixel(5, 5).unwrap(), Color::from(0x00FF00));
+        assert_eq!(handle_request(b"CLEAR\n", &pixmap, &mut session), Ok(None));
+        assert_eq!(pixmap.get_pixel(19, 19).unwrap(), Color::default());
+
+        pixmap
+            .protect(ProtectedRegion {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+            })
+            .unwrap();
+        assert!(handle_request(b"FILL 00FF00\n", &pixmap, &mut session).is_err());
+        assert_eq!(pixmap.get_pixel(5, 5).unwrap(), Color::default());
+    }
+
+    #[test]
+    fn test_frozen() {
+        let pixmap = Arc::new(Pixmap::new(20, 20).unwrap());
+        let mut session = Session::default().with_fill(true);
+
+        pixmap.set_frozen(true);
+        assert_eq!(
+            handle_request(b"PX 5 5 FF0000\n", &pixmap, &mut session),
+            Err("the canvas is frozen".to_string())
+        );
+        assert!(handle_request(b"PX 5 5 FF000080\n", &pixmap, &mut session).is_err());
+        assert!(handle_request(b"FILL 00FF00\n", &pixmap, &mut session).is_err());
+        assert_eq!(
+            handle_request(b"PX 5 5\n", &pixmap, &mut session),
+            Ok(Some(Response::PxData {
+                x: 5,
+                y: 5,
+                color: Color::default()
+            }))
+        );
+
+        pixmap.set_frozen(false);
+        assert_eq!(
+            handle_request(b"PX 5 5 FF0000\n", &pixmap, &mut session),
+            Ok(None)
+        );
+        assert_eq!(pixmap.get_pixel(5, 5).unwrap(), Color::from(0xFF0000));
+    }
+
+    #[test]
+    fn test_px_rect() {
+        let pixmap = Arc::new(Pixmap::new(200, 200).unwrap());
+        pixmap.set_pixel(1, 0, Color::from(0xFF0000)).unwrap();
+        let mut session = Session::default();
+
+        let mut data = vec![Color::default(); 4];
+        data[1] = Color::from(0xFF0000);
+        assert_eq!(
+            handle_request(b"PXRECT 0 0 2 2\n", &pixmap, &mut session),
+            Ok(Some(Response::Rect {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+                encoding: StateEncoding::Rle64,
+                data,
+            }))
+        );
+        assert!(handle_request(b"PXRECT 0 0 0 2\n", &pixmap, &mut session).is_err());
+        assert!(handle_request(b"PXRECT 199 0 2 2\n", &pixmap, &mut session).is_err());
+        assert!(handle_request(b"PXRECT 0 0 200 200\n", &pixmap, &mut session).is_err());
     }
 }
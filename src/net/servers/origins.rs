@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+/// The web origins whose pages may use the HTTP and WebSocket listeners
+///
+/// Browsers send the origin of the page which makes a request in the `Origin` header.
+/// Requests without that header don't come from a web page (e.g. bots or curl) and are always allowed.
+#[derive(Debug, Clone, Default)]
+pub struct AllowedOrigins {
+    /// The allowed origins or `None` if all origins are allowed
+    origins: Option<Arc<[String]>>,
+}
+
+impl AllowedOrigins {
+    /// Allow pages of all origins, e.g. so that public viewers can be embedded anywhere
+    pub fn any() -> Self {
+        Self { origins: None }
+    }
+
+    /// Only allow pages of the given origins like `https://example.org`
+    pub fn only(origins: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            origins: Some(origins.into_iter().map(|i| normalize(&i)).collect()),
+        }
+    }
+
+    /// Whether a request with the given `Origin` header may be answered
+    pub fn allows(&self, origin: Option<&str>) -> bool {
+        match (&self.origins, origin) {
+            (None, _) | (_, None) => true,
+            (Some(origins), Some(origin)) => origins.contains(&normalize(origin)),
+        }
+    }
+
+    /// The CORS headers with which a response to a request with the given `Origin` header is sent
+    ///
+    /// The allowed methods are included so that the headers also answer preflight requests.
+    pub(crate) fn cors_headers(&self, origin: Option<&str>) -> String {
+        const METHODS: &str = "Access-Control-Allow-Methods: GET, HEAD, OPTIONS\r\n";
+        match (&self.origins, origin) {
+            (None, _) => format!("Access-Control-Allow-Origin: *\r\n{}", METHODS),
+            (Some(_), Some(origin)) if self.allows(Some(origin)) => format!(
+                "Access-Control-Allow-Origin: {}\r\n{}Vary: Origin\r\n",
+                origin.trim(),
+                METHODS
+            ),
+            (Some(_), _) => "Vary: Origin\r\n".to_string(),
+        }
+    }
+}
+
+/// Bring an origin into the form in which it is compared
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowed_origins() {
+        let any = AllowedOrigins::any();
+        assert!(any.allows(Some("https://evil.example")));
+        assert!(any
+            .cors_headers(None)
+            .starts_with("Access-Control-Allow-Origin: *\r\n"));
+
+        let only = AllowedOrigins::only(["https://Viewer.example.org/".to_string()]);
+        assert!(only.allows(None));
+        assert!(only.allows(Some("https://viewer.example.org")));
+        assert!(!only.allows(Some("https://evil.example")));
+        assert!(!only.allows(Some("http://viewer.example.org")));
+        assert!(only
+            .cors_headers(Some("https://viewer.example.org"))
+            .starts_with("Access-Control-Allow-Origin: https://viewer.example.org\r\n"));
+        assert_eq!(only.cors_headers(None), "Vary: Origin\r\n");
+    }
+}
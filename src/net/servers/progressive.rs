@@ -0,0 +1,157 @@
+//! Progressive transfer of keyframes to viewers on slow links
+//!
+//! Instead of the whole content of a canvas at once, a [`ProgressiveKeyframe`] first sends a coarse preview of it in
+//! which every block of pixels is represented by its average color.
+//! Afterwards, it refines the canvas tile by tile with smaller blocks until the tiles carry every single pixel.
+//! This way, viewers show something within milliseconds and the full canvas arrives with little overhead because
+//! every pass only has a fraction of the size of the next one.
+//!
+//! Since every part of the keyframe is read from the canvas right before it is sent, changes which are sent in
+//! between are never overwritten by older content. They might only be hidden by a coarse block until the
+//! refinement of their tile arrives.
+
+use crate::net::protocol::{write_delta_blocks, write_delta_region};
+use crate::pixmap::{Color, Pixmap, WatchedRegion};
+use bytes::BytesMut;
+
+/// How many blocks the preview of a keyframe consists of at most
+const MAX_PREVIEW_BLOCKS: usize = 64 * 64;
+
+/// By how much the size of the blocks shrinks from one pass to the next
+const REFINEMENT_FACTOR: usize = 4;
+
+/// The size of the square tiles in which the preview is refined
+const TILE_SIZE: usize = 128;
+
+/// The parts of a keyframe which still have to be sent
+#[derive(Debug)]
+pub(crate) struct ProgressiveKeyframe {
+    region: WatchedRegion,
+    /// The size of the blocks in the current pass
+    block: usize,
+    /// The size of the tiles in the current pass
+    tile_size: usize,
+    /// The index of the next tile of the current pass, counted row by row
+    next_tile: usize,
+}
+
+impl ProgressiveKeyframe {
+    /// Prepare the keyframe of a region of the canvas
+    pub(crate) fn new(region: WatchedRegion) -> Self {
+        let mut block = 1;
+        while region.width.div_ceil(block) * region.height.div_ceil(block) > MAX_PREVIEW_BLOCKS {
+            block *= REFINEMENT_FACTOR;
+        }
+        Self {
+            region,
+            block,
+            // the preview covers the whole region at once so that viewers learn its size from the first message
+            tile_size: usize::max(1, usize::max(region.width, region.height)),
+            next_tile: 0,
+        }
+    }
+
+    /// Serialize the next part of the keyframe into `buf` and return whether there are more parts to send
+    pub(crate) fn write_next(&mut self, pixmap: &Pixmap, buf: &mut BytesMut) -> bool {
+        let WatchedRegion { x, y, width, height } = self.region;
+        let columns = width.div_ceil(self.tile_size);
+        let tiles = columns * height.div_ceil(self.tile_size);
+        if tiles > 0 {
+            let tile_x = x + self.next_tile % columns * self.tile_size;
+            let tile_y = y + self.next_tile / columns * self.tile_size;
+            let tile_width = usize::min(self.tile_size, x + width - tile_x);
+            let tile_height = usize::min(self.tile_size, y + height - tile_y);
+            if let Ok(data) = pixmap.get_region(tile_x, tile_y, tile_width, tile_height) {
+                match self.block {
+                    1 => write_delta_region(tile_x, tile_y, tile_width, tile_height, &data, buf),
+                    block => {
+                        let blocks = average_blocks(&data, tile_width, tile_height, block);
+                        write_delta_blocks(tile_x, tile_y, tile_width, tile_height, block, &blocks, buf)
+                    }
+                }
+            }
+        }
+
+        self.next_tile += 1;
+        if self.next_tile < tiles {
+            return true;
+        }
+        if self.block == 1 {
+            return false;
+        }
+        self.block = usize::max(1, self.block / REFINEMENT_FACTOR);
+        self.tile_size = TILE_SIZE;
+        self.next_tile = 0;
+        true
+    }
+}
+
+/// Compute the average color of every block of `block` by `block` pixels in the pixels of a region
+fn average_blocks(data: &[Color], width: usize, height: usize, block: usize) -> Vec<Color> {
+    let columns = width.div_ceil(block);
+    let mut sums = vec![[0u32; 4]; columns * height.div_ceil(block)];
+    for (i, &color) in data.iter().enumerate() {
+        let sum = &mut sums[i / width / block * columns + i % width / block];
+        let [r, g, b] = <[u8; 3]>::from(color);
+        sum[0] += u32::from(r);
+        sum[1] += u32::from(g);
+        sum[2] += u32::from(b);
+        sum[3] += 1;
+    }
+    sums.into_iter()
+        .map(|[r, g, b, n]| Color::from(((r / n) as u8, (g / n) as u8, (b / n) as u8)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{parse_canvas_delta, CanvasDelta};
+
+    #[test]
+    fn test_progressive_keyframe() {
+        let pixmap = Pixmap::new(600, 300).unwrap();
+        pixmap.set_pixel(599, 299, Color::from(0xFF0000)).unwrap();
+        let mut keyframe = ProgressiveKeyframe::new(WatchedRegion {
+            x: 0,
+            y: 0,
+            width: 600,
+            height: 300,
+        });
+
+        let mut messages = Vec::new();
+        loop {
+            let mut buf = BytesMut::new();
+            let more = keyframe.write_next(&pixmap, &mut buf);
+            messages.push(parse_canvas_delta(&buf).unwrap());
+            if !more {
+                break;
+            }
+        }
+
+        // a preview of 38x19 blocks is followed by 5x3 tiles of blocks which are 4 times smaller and 5x3 full tiles
+        assert_eq!(messages.len(), 1 + 15 + 15);
+        // the last block of the preview only covers 8x12 pixels of which one is red
+        assert!(matches!(
+            &messages[0][..],
+            [CanvasDelta::Blocks { x: 0, y: 0, width: 600, height: 300, block: 16, data }]
+                if data.len() == 38 * 19 && data[38 * 19 - 1] == Color::from((255 / 96, 0, 0))
+        ));
+        assert!(matches!(
+            &messages[1][..],
+            [CanvasDelta::Blocks {
+                x: 0,
+                y: 0,
+                width: 128,
+                height: 128,
+                block: 4,
+                ..
+            }]
+        ));
+        assert!(matches!(
+            &messages[30][..],
+            [CanvasDelta::Region { x: 512, y: 256, width: 88, height: 44, data }]
+                if data[88 * 44 - 1] == Color::from(0xFF0000)
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+use bytes::{BufMut, BytesMut};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The capacity with which read buffers start out and to which they shrink back after a burst is over
+const BASE_CAPACITY: usize = 8 * 1024;
+
+/// Limits on how much memory stream based servers may use for buffering data received from clients
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ReadBufferLimits {
+    /// The maximum number of bytes which are buffered for a single connection
+    pub per_connection: usize,
+    /// The maximum number of bytes which are buffered for all connections of one listener combined
+    ///
+    /// Connections which would exceed this budget are not allowed to grow their buffer, and new connections are
+    /// rejected if not even their initial buffer fits into it.
+    pub total: usize,
+}
+
+impl Default for ReadBufferLimits {
+    fn default() -> Self {
+        Self {
+            per_connection: 64 * 1024,
+            total: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Accounting of how much buffer memory is in use by all connections of one listener
+#[derive(Debug)]
+pub(crate) struct ReadBufferBudget {
+    limits: ReadBufferLimits,
+    used: AtomicUsize,
+}
+
+impl ReadBufferBudget {
+    pub(crate) fn new(limits: ReadBufferLimits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    fn try_reserve(&self, n: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(n).filter(|&used| used <= self.limits.total)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, n: usize) {
+        self.used.fetch_sub(n, Ordering::AcqRel);
+    }
+}
+
+/// A buffer for data read from a client stream
+///
+/// The buffer starts out small and doubles its capacity whenever a read fills it completely, up to the
+/// per-connection limit and only as long as the listener's budget allows it.
+/// Once reads become small again, the buffer is shrunk back to its initial size and the memory returned to the budget.
+#[derive(Debug)]
+pub(crate) struct ReadBuffer {
+    buf: BytesMut,
+    capacity: usize,
+    last_read: usize,
+    budget: Arc<ReadBufferBudget>,
+}
+
+impl ReadBuffer {
+    /// Create a new buffer which draws its memory from the given budget
+    ///
+    /// The memory of `buf` is reused if it is large enough, which allows keeping buffers of closed connections around
+    /// for new ones.
+    /// Returns `None` if the budget is already exhausted.
+    pub(crate) fn new(budget: Arc<ReadBufferBudget>, mut buf: BytesMut) -> Option<Self> {
+        let capacity = usize::min(BASE_CAPACITY, budget.limits.per_connection);
+        if !budget.try_reserve(capacity) {
+            return None;
+        }
+        buf.clear();
+        buf.reserve(capacity);
+        Some(Self {
+            buf,
+            capacity,
+            last_read: 0,
+            budget,
+        })
+    }
+
+    /// Return the underlying memory to the budget and hand it out for reuse
+    pub(crate) fn into_inner(mut self) -> BytesMut {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Read more data from `reader` into the buffer without exceeding the buffers capacity
+    ///
+    /// Returns the number of bytes that were read which is only 0 if the reader is exhausted or if the buffer
+    /// is completely filled.
+    pub(crate) async fn read_from<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> std::io::Result<usize> {
+        self.adapt_capacity();
+
+        let spare = self.capacity.saturating_sub(self.buf.len());
+        self.buf.reserve(spare);
+        let n = reader.read_buf(&mut (&mut self.buf).limit(spare)).await?;
+        self.last_read = if n == spare { usize::MAX } else { n };
+        Ok(n)
+    }
+
+    /// Grow the buffer if the previous read filled it and shrink it if the previous read was small
+    fn adapt_capacity(&mut self) {
+        let base = usize::min(BASE_CAPACITY, self.budget.limits.per_connection);
+        if self.last_read == usize::MAX && self.capacity < self.budget.limits.per_connection {
+            let grown = usize::min(self.capacity * 2, self.budget.limits.per_connection);
+            if self.budget.try_reserve(grown - self.capacity) {
+                tracing::trace!("Growing read buffer to {}KiB", grown / 1024);
+                self.capacity = grown;
+            }
+        } else if self.last_read < base / 2 && self.capacity > base && self.buf.len() <= base {
+            tracing::trace!("Shrinking read buffer back to {}KiB", base / 1024);
+            let mut shrunk = BytesMut::with_capacity(base);
+            shrunk.extend_from_slice(&self.buf);
+            self.buf = shrunk;
+            self.budget.release(self.capacity - base);
+            self.capacity = base;
+        }
+    }
+}
+
+impl Deref for ReadBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for ReadBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for ReadBuffer {
+    fn drop(&mut self) {
+        self.budget.release(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_grow_and_shrink() {
+        let budget = ReadBufferBudget::new(ReadBufferLimits {
+            per_connection: 4 * BASE_CAPACITY,
+            total: 10 * BASE_CAPACITY,
+        });
+        let mut buf = ReadBuffer::new(budget.clone(), BytesMut::new()).unwrap();
+        let data = vec![b'x'; 16 * BASE_CAPACITY];
+        let mut reader = &data[..];
+
+        // a burst grows the buffer up to the per-connection limit but not beyond
+        for _ in 0..4 {
+            buf.read_from(&mut reader).await.unwrap();
+            buf.clear();
+        }
+        assert_eq!(buf.capacity, 4 * BASE_CAPACITY);
+        assert_eq!(budget.used.load(Ordering::Acquire), 4 * BASE_CAPACITY);
+
+        // small reads shrink it back again
+        let mut reader = &b"PX 0 0\n"[..];
+        buf.read_from(&mut reader).await.unwrap();
+        buf.clear();
+        buf.read_from(&mut reader).await.unwrap();
+        assert_eq!(buf.capacity, BASE_CAPACITY);
+
+        drop(buf);
+        assert_eq!(budget.used.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_budget_exhaustion() {
+        let budget = ReadBufferBudget::new(ReadBufferLimits {
+            per_connection: BASE_CAPACITY,
+            total: 2 * BASE_CAPACITY,
+        });
+        let first = ReadBuffer::new(budget.clone(), BytesMut::new());
+        let second = ReadBuffer::new(budget.clone(), BytesMut::new());
+        assert!(first.is_some() && second.is_some());
+        assert!(ReadBuffer::new(budget.clone(), BytesMut::new()).is_none());
+
+        drop(first);
+        assert!(ReadBuffer::new(budget, BytesMut::new()).is_some());
+    }
+}
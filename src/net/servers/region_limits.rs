@@ -0,0 +1,127 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A limit on how many pixels a single client may set per second inside a rectangle of the canvas
+///
+/// This allows making some parts of the canvas (e.g. its center) harder to claim than others.
+/// Limits are tracked per connection so they don't apply to UDP clients whose datagrams are handled independently.
+/// Where rectangles overlap, all of their limits apply.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RegionRateLimit {
+    /// The x coordinate of the rectangles top-left corner
+    pub x: usize,
+    /// The y coordinate of the rectangles top-left corner
+    pub y: usize,
+    /// The width of the rectangle
+    pub width: usize,
+    /// The height of the rectangle
+    pub height: usize,
+    /// How many pixels a client may set inside the rectangle per second
+    pub pixels_per_sec: u32,
+}
+
+impl RegionRateLimit {
+    #[inline(always)]
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+/// A token bucket which allows bursts of up to one second worth of pixels
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// The write budget which one connection has left in each rate limited region
+#[derive(Debug, Clone)]
+pub(crate) struct RegionRateLimiter {
+    limits: Arc<[RegionRateLimit]>,
+    /// One bucket per entry in `limits`
+    buckets: Vec<Bucket>,
+}
+
+impl RegionRateLimiter {
+    /// Create a limiter in which every region starts with a full budget
+    pub(crate) fn new(limits: Arc<[RegionRateLimit]>) -> Self {
+        let now = Instant::now();
+        let buckets = limits
+            .iter()
+            .map(|limit| Bucket {
+                tokens: limit.pixels_per_sec as f64,
+                last_refill: now,
+            })
+            .collect();
+        Self { limits, buckets }
+    }
+
+    /// Turn this limiter back into a copy of `template` in which every region has a full budget
+    ///
+    /// Unlike cloning the template, this reuses the memory of the existing buckets.
+    pub(crate) fn reset_from(&mut self, template: &RegionRateLimiter) {
+        let now = Instant::now();
+        self.limits = template.limits.clone();
+        self.buckets.clear();
+        self.buckets.extend(self.limits.iter().map(|limit| Bucket {
+            tokens: limit.pixels_per_sec as f64,
+            last_refill: now,
+        }));
+    }
+
+    /// Take budget for setting the pixel at (x,y)
+    ///
+    /// Returns the exceeded limit if the pixel lies in a region whose budget is used up.
+    #[inline(always)]
+    pub(crate) fn acquire(&mut self, x: usize, y: usize) -> Result<(), RegionRateLimit> {
+        if self.limits.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        for (limit, bucket) in self.limits.iter().zip(self.buckets.iter_mut()) {
+            if !limit.contains(x, y) {
+                continue;
+            }
+            let rate = limit.pixels_per_sec as f64;
+            bucket.tokens = f64::min(
+                rate,
+                bucket.tokens + (now - bucket.last_refill).as_secs_f64() * rate,
+            );
+            bucket.last_refill = now;
+            if bucket.tokens < 1.0 {
+                return Err(*limit);
+            }
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_region_rate_limit() {
+        let mut limiter = RegionRateLimiter::new(Arc::new([RegionRateLimit {
+            x: 10,
+            y: 10,
+            width: 10,
+            height: 10,
+            pixels_per_sec: 20,
+        }]));
+
+        for _ in 0..20 {
+            assert!(limiter.acquire(15, 15).is_ok());
+        }
+        assert!(limiter.acquire(15, 15).is_err());
+        // pixels outside the region are not limited
+        assert!(limiter.acquire(0, 0).is_ok());
+        assert!(limiter.acquire(20, 15).is_ok());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limiter.acquire(19, 19).is_ok());
+    }
+}
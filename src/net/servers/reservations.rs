@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// The longest time for which a rectangle can be claimed at once
+pub const MAX_CLAIM_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// How many claims can be active at the same time
+pub const MAX_CLAIMS: usize = 1024;
+
+/// A rectangle of the canvas which only one team may draw on until it expires
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Reservation {
+    /// The team which owns the reservation
+    pub team: String,
+    /// The name of the canvas on which the rectangle is reserved
+    pub canvas: String,
+    /// The x coordinate of the reserved rectangles top-left corner
+    pub x: usize,
+    /// The y coordinate of the reserved rectangles top-left corner
+    pub y: usize,
+    /// The width of the reserved rectangle
+    pub width: usize,
+    /// The height of the reserved rectangle
+    pub height: usize,
+    /// The point in time at which the reservation is automatically released
+    pub expires_at: Instant,
+}
+
+impl Reservation {
+    #[inline(always)]
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+
+    fn overlaps(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        x < self.x + self.width && self.x < x + width && y < self.y + self.height && self.y < y + height
+    }
+}
+
+/// A rectangle of the canvas which a client announced to be drawing on until it expires
+///
+/// Unlike a [`Reservation`], a claim is purely advisory and does not keep anybody from drawing there.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Claim {
+    /// The team as which the claiming connection was authenticated, if it was
+    pub team: Option<String>,
+    /// The name of the canvas on which the rectangle is claimed
+    pub canvas: String,
+    /// The x coordinate of the claimed rectangles top-left corner
+    pub x: usize,
+    /// The y coordinate of the claimed rectangles top-left corner
+    pub y: usize,
+    /// The width of the claimed rectangle
+    pub width: usize,
+    /// The height of the claimed rectangle
+    pub height: usize,
+    /// The point in time at which the claim is automatically released
+    pub expires_at: Instant,
+}
+
+impl Claim {
+    fn overlaps(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        x < self.x + self.width && self.x < x + width && y < self.y + self.height && self.y < y + height
+    }
+
+    fn is(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        (self.x, self.y, self.width, self.height) == (x, y, width, height)
+    }
+}
+
+/// Registry of teams and the canvas regions which they have reserved or claimed
+///
+/// Teams authenticate on a connection with `AUTH <team> <token>` and can then claim a rectangle of the canvas with
+/// `RESERVE <x> <y> <width> <height> <seconds>`.
+/// Until the reservation expires, pixels inside the rectangle can only be set by connections which are
+/// authenticated as the owning team.
+/// Reservations and claims only apply to the canvas on which they were made.
+/// Each team holds at most one reservation per canvas so reserving again replaces a teams previous reservation if
+/// the new one succeeds.
+///
+/// Additionally, any client can announce that it is drawing on a rectangle with
+/// `CLAIM <x> <y> <width> <height> <seconds>` so that cooperative clients which look up the active claims with
+/// `CLAIMS` can avoid painting over each other.
+/// Claims are never enforced.
+#[derive(Debug, Default)]
+pub struct Reservations {
+    /// Access tokens of all known teams, indexed by team name
+    teams: HashMap<String, String>,
+    /// The longest time for which a rectangle can be reserved at once
+    max_duration: Duration,
+    active: RwLock<Vec<Reservation>>,
+    /// How many entries `active` has so that pixel writes can skip locking it when nothing is reserved
+    len: AtomicUsize,
+    claims: Mutex<Vec<Claim>>,
+}
+
+impl Reservations {
+    /// Create a registry for the given teams (name and access token) in which reservations last at most `max_duration`
+    pub fn new(teams: HashMap<String, String>, max_duration: Duration) -> Self {
+        Self {
+            teams,
+            max_duration,
+            active: RwLock::new(Vec::new()),
+            len: AtomicUsize::new(0),
+            claims: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Check whether `token` is the access token of `team`
+    pub fn authenticate(&self, team: &str, token: &str) -> bool {
+        self.teams.get(team).is_some_and(|expected| expected == token)
+    }
+
+    /// Reserve a rectangle of `canvas` for `team`, replacing its previous reservation on that canvas
+    ///
+    /// This fails if the rectangle overlaps with another teams reservation or the duration is longer than allowed.
+    /// The previous reservation is kept if this fails.
+    #[allow(clippy::too_many_arguments)]
+    pub fn reserve(
+        &self,
+        canvas: &str,
+        team: &str,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        duration: Duration,
+    ) -> Result<Reservation, String> {
+        if width == 0 || height == 0 {
+            return Err("reservation must not be empty".to_string());
+        }
+        if duration > self.max_duration {
+            return Err(format!(
+                "reservations must not last longer than {} seconds",
+                self.max_duration.as_secs()
+            ));
+        }
+
+        let now = Instant::now();
+        let mut active = self.active.write().unwrap();
+        active.retain(|i| i.expires_at > now);
+        self.len.store(active.len(), Ordering::Relaxed);
+        if let Some(other) = active
+            .iter()
+            .find(|i| i.canvas == canvas && i.team != team && i.overlaps(x, y, width, height))
+        {
+            return Err(format!("region is already reserved by team {}", other.team));
+        }
+
+        active.retain(|i| i.canvas != canvas || i.team != team);
+        let reservation = Reservation {
+            team: team.to_string(),
+            canvas: canvas.to_string(),
+            x,
+            y,
+            width,
+            height,
+            expires_at: now + duration,
+        };
+        active.push(reservation.clone());
+        self.len.store(active.len(), Ordering::Relaxed);
+        tracing::info!(
+            "Team {} reserved {}x{} pixels at {},{} of canvas {} for {}s",
+            team,
+            width,
+            height,
+            x,
+            y,
+            canvas,
+            duration.as_secs()
+        );
+        Ok(reservation)
+    }
+
+    /// Check whether a connection that is authenticated as `team` may set the pixel at (x,y) of `canvas`
+    #[inline(always)]
+    pub fn may_write(&self, canvas: &str, team: Option<&str>, x: usize, y: usize) -> bool {
+        if self.len.load(Ordering::Relaxed) == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        self.active
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|i| i.expires_at > now && i.canvas == canvas && i.contains(x, y))
+            .all(|i| Some(i.team.as_str()) == team)
+    }
+
+    /// All reservations on any canvas which have not yet expired
+    pub fn active(&self) -> Vec<Reservation> {
+        let now = Instant::now();
+        self.active
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|i| i.expires_at > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Announce that a rectangle of `canvas` is being drawn on for `duration`
+    ///
+    /// Claiming a rectangle which is already claimed with exactly the same position and size renews that claim for
+    /// the new duration so that clients can keep their claims alive. A duration of zero releases it instead.
+    /// This fails if the rectangle overlaps any other claim on the same canvas or the duration is longer than
+    /// [`MAX_CLAIM_DURATION`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn claim(
+        &self,
+        canvas: &str,
+        team: Option<&str>,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        duration: Duration,
+    ) -> Result<Claim, String> {
+        if width == 0 || height == 0 {
+            return Err("claim must not be empty".to_string());
+        }
+        if duration > MAX_CLAIM_DURATION {
+            return Err(format!(
+                "claims must not last longer than {} seconds",
+                MAX_CLAIM_DURATION.as_secs()
+            ));
+        }
+
+        let now = Instant::now();
+        let mut claims = self.claims.lock().unwrap();
+        claims.retain(|i| i.expires_at > now && !(i.canvas == canvas && i.is(x, y, width, height)));
+        if let Some(other) = claims
+            .iter()
+            .find(|i| i.canvas == canvas && i.overlaps(x, y, width, height))
+        {
+            return Err(format!(
+                "region is already claimed at {},{} with size {}x{}",
+                other.x, other.y, other.width, other.height
+            ));
+        }
+        let claim = Claim {
+            team: team.map(str::to_string),
+            canvas: canvas.to_string(),
+            x,
+            y,
+            width,
+            height,
+            expires_at: now + duration,
+        };
+        if !duration.is_zero() {
+            if claims.len() >= MAX_CLAIMS {
+                return Err("too many regions are claimed".to_string());
+            }
+            claims.push(claim.clone());
+        }
+        Ok(claim)
+    }
+
+    /// All claims on any canvas which have not yet expired
+    pub fn claims(&self) -> Vec<Claim> {
+        let now = Instant::now();
+        self.claims
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| i.expires_at > now)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::Response;
+    use crate::net::servers::{handle_request, Session};
+    use crate::pixmap::{Color, Pixmap, DEFAULT_CANVAS};
+    use std::sync::Arc;
+
+    fn reservations() -> Arc<Reservations> {
+        Arc::new(Reservations::new(
+            HashMap::from([
+                ("red".to_string(), "secret1".to_string()),
+                ("blue".to_string(), "secret2".to_string()),
+            ]),
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[test]
+    fn test_reserve() {
+        let reservations = reservations();
+        reservations
+            .reserve(DEFAULT_CANVAS, "red", 10, 10, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert!(reservations
+            .reserve(DEFAULT_CANVAS, "blue", 14, 14, 5, 5, Duration::from_secs(30))
+            .is_err());
+        assert!(reservations
+            .reserve(DEFAULT_CANVAS, "blue", 15, 10, 5, 5, Duration::from_secs(90))
+            .is_err());
+        reservations
+            .reserve(DEFAULT_CANVAS, "blue", 15, 10, 5, 5, Duration::from_secs(30))
+            .unwrap();
+
+        assert!(reservations.may_write(DEFAULT_CANVAS, Some("red"), 10, 10));
+        assert!(!reservations.may_write(DEFAULT_CANVAS, Some("blue"), 14, 14));
+        assert!(!reservations.may_write(DEFAULT_CANVAS, None, 14, 14));
+        assert!(reservations.may_write(DEFAULT_CANVAS, None, 20, 10));
+
+        // reserving again moves the teams reservation
+        reservations
+            .reserve(DEFAULT_CANVAS, "red", 0, 0, 1, 1, Duration::from_secs(30))
+            .unwrap();
+        assert!(reservations.may_write(DEFAULT_CANVAS, None, 14, 14));
+        assert_eq!(reservations.active().len(), 2);
+    }
+
+    #[test]
+    fn test_failed_reserve_keeps_reservation() {
+        let reservations = reservations();
+        reservations
+            .reserve(DEFAULT_CANVAS, "red", 0, 0, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        reservations
+            .reserve(DEFAULT_CANVAS, "blue", 10, 10, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert!(reservations
+            .reserve(DEFAULT_CANVAS, "red", 12, 12, 5, 5, Duration::from_secs(30))
+            .is_err());
+        assert!(!reservations.may_write(DEFAULT_CANVAS, None, 0, 0));
+        assert_eq!(reservations.active().len(), 2);
+    }
+
+    #[test]
+    fn test_reservations_per_canvas() {
+        let reservations = reservations();
+        reservations
+            .reserve(DEFAULT_CANVAS, "red", 0, 0, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        reservations
+            .reserve("kids", "blue", 0, 0, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert!(!reservations.may_write(DEFAULT_CANVAS, Some("blue"), 0, 0));
+        assert!(reservations.may_write("kids", Some("blue"), 0, 0));
+        assert!(!reservations.may_write("kids", Some("red"), 0, 0));
+        assert!(reservations.may_write("other", None, 0, 0));
+
+        // a team holds one reservation per canvas
+        reservations
+            .reserve("kids", "red", 10, 10, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(reservations.active().len(), 3);
+
+        reservations
+            .claim(DEFAULT_CANVAS, None, 0, 0, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        reservations
+            .claim("kids", None, 0, 0, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(reservations.claims().len(), 2);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let reservations = reservations();
+        reservations
+            .reserve(DEFAULT_CANVAS, "red", 0, 0, 5, 5, Duration::from_millis(10))
+            .unwrap();
+        assert!(!reservations.may_write(DEFAULT_CANVAS, None, 0, 0));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(reservations.may_write(DEFAULT_CANVAS, None, 0, 0));
+        assert!(reservations.active().is_empty());
+    }
+
+    #[test]
+    fn test_claim() {
+        let reservations = reservations();
+        reservations
+            .claim(DEFAULT_CANVAS, None, 10, 10, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert!(reservations
+            .claim(DEFAULT_CANVAS, Some("red"), 14, 14, 5, 5, Duration::from_secs(30))
+            .is_err());
+        assert!(reservations
+            .claim(
+                DEFAULT_CANVAS,
+                None,
+                0,
+                0,
+                5,
+                5,
+                MAX_CLAIM_DURATION + Duration::from_secs(1)
+            )
+            .is_err());
+        reservations
+            .claim(DEFAULT_CANVAS, Some("red"), 15, 15, 5, 5, Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(reservations.claims().len(), 2);
+
+        // claims are advisory and don't keep anybody from drawing
+        assert!(reservations.may_write(DEFAULT_CANVAS, None, 10, 10));
+
+        // the same rectangle can be renewed and released again
+        reservations
+            .claim(DEFAULT_CANVAS, None, 10, 10, 5, 5, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(reservations.claims().len(), 2);
+        reservations
+            .claim(DEFAULT_CANVAS, None, 10, 10, 5, 5, Duration::ZERO)
+            .unwrap();
+        assert_eq!(reservations.claims().len(), 1);
+        assert_eq!(reservations.claims()[0].team.as_deref(), Some("red"));
+    }
+
+    #[test]
+    fn test_reservation_commands() {
+        let pixmap = Arc::new(Pixmap::new(100, 100).unwrap());
+        let reservations = reservations();
+        let mut red = Session::new(Default::default(), false, reservations.clone(), Arc::new([]));
+        let mut other = Session::new(Default::default(), false, reservations, Arc::new([]));
+
+        assert!(handle_request(b"RESERVE 0 0 10 10 30\n", &pixmap, &mut red).is_err());
+        assert!(handle_request(b"AUTH red wrong\n", &pixmap, &mut red).is_err());
+        assert!(handle_request(b"AUTH red secret1\n", &pixmap, &mut red).is_ok());
+        assert!(handle_request(b"RESERVE 0 0 10 10 30\n", &pixmap, &mut red).is_ok());
+        assert!(handle_request(b"RESERVE 95 95 10 10 30\n", &pixmap, &mut red).is_err());
+
+        assert!(handle_request(b"PX 5 5 FF0000\n", &pixmap, &mut red).is_ok());
+        assert!(handle_request(b"PX 5 5 0000FF\n", &pixmap, &mut other).is_err());
+        assert!(handle_request(b"PX 10 10 0000FF\n", &pixmap, &mut other).is_ok());
+        assert_eq!(pixmap.get_pixel(5, 5).unwrap(), Color::from(0xFF0000));
+    }
+
+    #[test]
+    fn test_claim_commands() {
+        let pixmap = Arc::new(Pixmap::new(100, 100).unwrap());
+        let mut session = Session::new(Default::default(), false, reservations(), Arc::new([]));
+
+        assert_eq!(
+            handle_request(b"CLAIM 0 0 10 10 30\n", &pixmap, &mut session),
+            Ok(Some(Response::Claimed {
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 10,
+                seconds: 30
+            }))
+        );
+        assert!(handle_request(b"CLAIM 5 5 10 10 30\n", &pixmap, &mut session).is_err());
+        assert!(handle_request(b"CLAIM 95 95 10 10 30\n", &pixmap, &mut session).is_err());
+        assert_eq!(
+            handle_request(b"CLAIMS\n", &pixmap, &mut session),
+            Ok(Some(Response::Claims {
+                claims: vec![(0, 0, 10, 10, 30)]
+            }))
+        );
+        assert!(handle_request(b"PX 5 5 FF0000\n", &pixmap, &mut session).is_ok());
+    }
+}
@@ -0,0 +1,540 @@
+//! Delivery of pixel changes to clients which sent `SUBSCRIBE`
+//!
+//! A subscribed connection first receives a keyframe consisting of the current canvas content as `STATE` regions
+//! and afterwards a `PX` response for every change.
+//! Whenever the connection is too slow to keep up with the changes, it receives a new keyframe instead of the changes
+//! which it missed or is disconnected, depending on the [`LagPolicy`] of the server.
+//! Subscriptions follow the canvas which the connection selected via `CANVAS` and start over with a keyframe of the
+//! new canvas when another one is selected.
+//! Subscriptions of a region only receive a keyframe of that region and the changes inside of it, which are filtered
+//! by the [`ChangeSubscription`] before anything is serialized.
+
+use crate::net::protocol::{
+    write_delta_pixel, write_delta_region, Response, StateEncoding, MAX_REGION_PIXELS,
+};
+use crate::net::servers::progressive::ProgressiveKeyframe;
+use crate::net::servers::{LagPolicy, Session, WriteQueueLimits};
+use crate::pixmap::{ChangeSubscription, PixelChange, Pixmap, SharedPixmap, WatchedRegion};
+use bytes::BytesMut;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time::Instant;
+
+/// How many changes are serialized at most before they are sent to the client
+const MAX_CHANGES_PER_SEND: usize = 4096;
+
+/// How pixel changes are serialized for the client
+// only the WebSocket server sends binary changes
+#[cfg_attr(not(feature = "ws"), allow(dead_code))]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ChangeEncoding {
+    /// `STATE` and `PX` responses of the pixelflut protocol
+    #[default]
+    Text,
+    /// Records of the binary canvas delta encoding (see [`crate::net::protocol::CanvasDelta`])
+    Delta,
+    /// Like [`Delta`](ChangeEncoding::Delta) but the keyframe at the start of the subscription is sent progressively
+    /// (see [`ProgressiveKeyframe`])
+    ProgressiveDelta,
+}
+
+/// An error which indicates that a subscribed client could not keep up with the changes and has to be disconnected
+#[derive(Debug, Error, Copy, Clone, Eq, PartialEq)]
+#[error("the connection is too slow to receive all changes of the canvas")]
+pub(crate) struct SubscriptionLagged;
+
+/// The subscription state of one connection
+#[derive(Debug, Default)]
+pub(crate) enum Subscription {
+    /// The client is not subscribed
+    #[default]
+    Inactive,
+    /// The client is subscribed but waits for its keyframe which is delayed during warm-up
+    Pending {
+        /// When the keyframe is sent
+        keyframe_at: Instant,
+        /// The canvas whose changes are sent
+        pixmap: SharedPixmap,
+        /// The rectangle to which the subscription is limited
+        region: Option<WatchedRegion>,
+        /// How much is sent at once and what happens when the client cannot keep up
+        limits: WriteQueueLimits,
+    },
+    /// The client receives changes
+    Active {
+        /// The changes of the canvas
+        changes: ChangeSubscription,
+        /// The canvas whose changes are sent
+        pixmap: SharedPixmap,
+        /// How much is sent at once and what happens when the client cannot keep up
+        limits: WriteQueueLimits,
+        /// The rest of a keyframe which is sent progressively
+        keyframe: Option<ProgressiveKeyframe>,
+    },
+}
+
+impl Subscription {
+    /// Start or stop the subscription depending on what the client last requested
+    pub(crate) fn update(&mut self, session: &Session, server_pixmap: &SharedPixmap) {
+        let canvas = session.canvas(server_pixmap);
+        let follows_request = match self {
+            Subscription::Inactive => false,
+            Subscription::Pending { pixmap, region, .. } => {
+                Arc::ptr_eq(pixmap, canvas) && *region == session.watched_region
+            }
+            Subscription::Active { pixmap, changes, .. } => {
+                Arc::ptr_eq(pixmap, canvas) && changes.region() == session.watched_region
+            }
+        };
+        match (session.subscribed, follows_request) {
+            (true, false) => {
+                *self = match canvas.changes() {
+                    Some(changes) => Subscription::Pending {
+                        keyframe_at: changes.keyframe_at().into(),
+                        pixmap: canvas.clone(),
+                        region: session.watched_region,
+                        limits: session.write_queue,
+                    },
+                    None => Subscription::Inactive,
+                }
+            }
+            (false, _) => *self = Subscription::Inactive,
+            (true, true) => {}
+        }
+    }
+
+    /// Wait until there is something to send to the client and serialize it into `buf`
+    ///
+    /// This never returns while the client is not subscribed and is cancel safe.
+    /// Returns an error if the client missed changes and has to be disconnected because of the [`LagPolicy`].
+    pub(crate) async fn write_next(
+        &mut self,
+        encoding: ChangeEncoding,
+        buf: &mut BytesMut,
+    ) -> Result<(), SubscriptionLagged> {
+        match self {
+            Subscription::Inactive => std::future::pending().await,
+            Subscription::Pending {
+                keyframe_at,
+                pixmap,
+                region,
+                limits,
+            } => {
+                tokio::time::sleep_until(*keyframe_at).await;
+                let (pixmap, limits) = (pixmap.clone(), *limits);
+                let Some(changes) = pixmap.changes() else {
+                    *self = Subscription::Inactive;
+                    return Ok(());
+                };
+                // subscribe before reading the canvas so that no change between the two is lost
+                let changes = match region {
+                    Some(region) => changes.subscribe_region(*region),
+                    None => changes.subscribe(),
+                };
+                let keyframe = match encoding {
+                    ChangeEncoding::ProgressiveDelta => {
+                        let mut keyframe = ProgressiveKeyframe::new(full_region(&pixmap, changes.region()));
+                        keyframe.write_next(&pixmap, buf).then_some(keyframe)
+                    }
+                    _ => {
+                        write_full_keyframe(&pixmap, changes.region(), encoding, buf);
+                        None
+                    }
+                };
+                *self = Subscription::Active {
+                    changes,
+                    pixmap,
+                    limits,
+                    keyframe,
+                };
+                Ok(())
+            }
+            Subscription::Active {
+                changes: subscription,
+                pixmap,
+                limits,
+                keyframe,
+            } => {
+                let pixmap = &**pixmap;
+                let region = subscription.region();
+                let mut wrote_keyframe = false;
+                let mut write = |buf: &mut BytesMut, change| match change {
+                    Ok(change) => {
+                        write_change(change, pixmap, encoding, buf);
+                        Ok(())
+                    }
+                    Err(_) if limits.lag_policy == LagPolicy::Disconnect => Err(SubscriptionLagged),
+                    Err(_) => {
+                        write_full_keyframe(pixmap, region, encoding, buf);
+                        wrote_keyframe = true;
+                        Ok(())
+                    }
+                };
+                let mut keyframe_sent = false;
+                match keyframe {
+                    // the keyframe is refined without waiting for changes which are sent in between its parts
+                    Some(rest) => keyframe_sent = !rest.write_next(pixmap, buf),
+                    None => match subscription.recv().await {
+                        Err(RecvError::Closed) => {
+                            *self = Subscription::Inactive;
+                            return Ok(());
+                        }
+                        Err(RecvError::Lagged(_)) => write(buf, Err(()))?,
+                        Ok(change) => write(buf, Ok(change))?,
+                    },
+                }
+
+                // send changes which are already available together as long as they fit into the write queue
+                for _ in 1..MAX_CHANGES_PER_SEND {
+                    if buf.len() >= limits.capacity {
+                        break;
+                    }
+                    match subscription.try_recv() {
+                        Ok(change) => write(buf, Ok(change))?,
+                        Err(TryRecvError::Lagged(_)) => write(buf, Err(()))?,
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                    }
+                }
+                // a full keyframe after missed changes also replaces the rest of a progressive one
+                if keyframe_sent || wrote_keyframe {
+                    *keyframe = None;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Serialize one change
+fn write_change(change: PixelChange, pixmap: &Pixmap, encoding: ChangeEncoding, buf: &mut BytesMut) {
+    match (change, encoding) {
+        (PixelChange::Pixel { x, y, color }, ChangeEncoding::Text) => {
+            super::write_response(&Response::PxData { x, y, color }, buf)
+        }
+        (PixelChange::Pixel { x, y, color }, ChangeEncoding::Delta | ChangeEncoding::ProgressiveDelta) => {
+            write_delta_pixel(x, y, color, buf)
+        }
+        (PixelChange::Region { x, y, width, height }, _) => {
+            write_keyframe(pixmap, x, y, width, height, encoding, buf)
+        }
+    }
+}
+
+/// The rectangle which a subscription watches, which is the whole canvas if it is not limited to a region
+fn full_region(pixmap: &Pixmap, region: Option<WatchedRegion>) -> WatchedRegion {
+    let (width, height) = pixmap.get_size();
+    region.unwrap_or(WatchedRegion {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    })
+}
+
+/// Serialize the current content of everything that a subscription watches
+fn write_full_keyframe(
+    pixmap: &Pixmap,
+    region: Option<WatchedRegion>,
+    encoding: ChangeEncoding,
+    buf: &mut BytesMut,
+) {
+    let region = full_region(pixmap, region);
+    write_keyframe(
+        pixmap,
+        region.x,
+        region.y,
+        region.width,
+        region.height,
+        encoding,
+        buf,
+    );
+}
+
+/// Serialize the current content of a region
+///
+/// As text, the region is split into `STATE` responses which don't exceed the maximum region size.
+fn write_keyframe(
+    pixmap: &Pixmap,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    encoding: ChangeEncoding,
+    buf: &mut BytesMut,
+) {
+    if encoding != ChangeEncoding::Text {
+        if let Ok(data) = pixmap.get_region(x, y, width, height) {
+            write_delta_region(x, y, width, height, &data, buf);
+        }
+        return;
+    }
+
+    let tile = (MAX_REGION_PIXELS as f64).sqrt() as usize;
+    for tile_y in (y..y + height).step_by(tile) {
+        for tile_x in (x..x + width).step_by(tile) {
+            let tile_width = usize::min(tile, x + width - tile_x);
+            let tile_height = usize::min(tile, y + height - tile_y);
+            let Ok(data) = pixmap.get_region(tile_x, tile_y, tile_width, tile_height) else {
+                continue;
+            };
+            let response = Response::Region {
+                x: tile_x,
+                y: tile_y,
+                width: tile_width,
+                height: tile_height,
+                encoding: StateEncoding::Rgb64,
+                data,
+            };
+            super::write_response(&response, buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{parse_canvas_delta, parse_response_bin, CanvasDelta};
+    use crate::net::servers::handle_request;
+    use crate::pixmap::{Canvases, Color};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_subscription() {
+        let pixmap = Arc::new(
+            Pixmap::new(300, 300)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let mut session = Session::default();
+        let mut subscription = Subscription::default();
+        let mut buf = BytesMut::new();
+
+        // pixmaps without a change broadcast don't support subscriptions
+        let unsupported = Arc::new(Pixmap::new(1, 1).unwrap());
+        assert!(handle_request(b"SUBSCRIBE", &unsupported, &mut session).is_err());
+        handle_request(b"SUBSCRIBE", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+        assert!(matches!(subscription, Subscription::Pending { .. }));
+
+        // the keyframe is split into tiles
+        pixmap.set_pixel(299, 299, Color::from(0x00FF00)).unwrap();
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        let lines = buf[..]
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 9);
+        let Response::Region { data, .. } = parse_response_bin(lines[8]).unwrap() else {
+            panic!("keyframe does not consist of regions");
+        };
+        assert_eq!(data.last(), Some(&Color::from(0x00FF00)));
+
+        buf.clear();
+        pixmap.set_pixel(1, 2, Color::from(0xFF0000)).unwrap();
+        pixmap.set_pixel(3, 4, Color::from(0x0000FF)).unwrap();
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf[..], b"PX 1 2 FF0000\nPX 3 4 0000FF\n");
+
+        // selecting another canvas restarts the subscription with a keyframe of that canvas
+        let stage = Arc::new(
+            Pixmap::new(2, 2)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let mut canvases = Canvases::default();
+        canvases.insert("stage".to_string(), stage).unwrap();
+        session = session.with_canvases(Arc::new(canvases));
+        handle_request(b"CANVAS stage", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+        buf.clear();
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        assert!(matches!(
+            parse_response_bin(&buf[..buf.len() - 1]).unwrap(),
+            Response::Region {
+                width: 2,
+                height: 2,
+                ..
+            }
+        ));
+
+        handle_request(b"UNSUBSCRIBE", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+        assert!(matches!(subscription, Subscription::Inactive));
+    }
+
+    #[tokio::test]
+    async fn test_region_subscription() {
+        let pixmap = Arc::new(
+            Pixmap::new(300, 300)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let mut session = Session::default();
+        let mut subscription = Subscription::default();
+        let mut buf = BytesMut::new();
+
+        assert!(handle_request(b"SUBSCRIBE 290 0 20 20", &pixmap, &mut session).is_err());
+        assert!(handle_request(b"SUBSCRIBE 0 0 0 20", &pixmap, &mut session).is_err());
+        handle_request(b"SUBSCRIBE 10 10 5 5", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+
+        // the keyframe only covers the region
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        assert!(matches!(
+            parse_response_bin(&buf[..buf.len() - 1]).unwrap(),
+            Response::Region {
+                x: 10,
+                y: 10,
+                width: 5,
+                height: 5,
+                ..
+            }
+        ));
+
+        // changes outside of the region are skipped and region changes are clipped
+        buf.clear();
+        pixmap.set_pixel(0, 0, Color::from(0xFF0000)).unwrap();
+        pixmap.set_pixel(12, 12, Color::from(0x00FF00)).unwrap();
+        pixmap
+            .set_region(0, 0, 12, 11, &[Color::from(0x0000FF); 12 * 11])
+            .unwrap();
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        let lines = buf[..]
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| parse_response_bin(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            Response::PxData {
+                x: 12,
+                y: 12,
+                color: Color::from(0x00FF00)
+            }
+        );
+        assert!(matches!(
+            lines[1],
+            Response::Region {
+                x: 10,
+                y: 10,
+                width: 2,
+                height: 1,
+                ..
+            }
+        ));
+        assert_eq!(lines.len(), 2);
+
+        // subscribing to the whole canvas again starts over with a full keyframe
+        handle_request(b"SUBSCRIBE", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+        assert!(matches!(subscription, Subscription::Pending { region: None, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber() {
+        let pixmap = Arc::new(
+            Pixmap::new(4, 4)
+                .unwrap()
+                .with_change_broadcast(2, Duration::ZERO),
+        );
+        let mut session = Session::default().with_write_queue(WriteQueueLimits {
+            capacity: 10,
+            lag_policy: LagPolicy::Disconnect,
+        });
+        let mut subscription = Subscription::default();
+        let mut buf = BytesMut::new();
+        handle_request(b"SUBSCRIBE", &pixmap, &mut session).unwrap();
+        subscription.update(&session, &pixmap);
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+
+        // batching stops once the write queue is full
+        buf.clear();
+        pixmap.set_pixel(1, 2, Color::from(0xFF0000)).unwrap();
+        pixmap.set_pixel(3, 3, Color::from(0x0000FF)).unwrap();
+        subscription
+            .write_next(ChangeEncoding::Text, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf[..], b"PX 1 2 FF0000\n");
+
+        // a client which misses changes is disconnected instead of receiving a new keyframe
+        buf.clear();
+        for x in 0..4 {
+            pixmap.set_pixel(x, 0, Color::from(0x00FF00)).unwrap();
+        }
+        assert_eq!(
+            subscription.write_next(ChangeEncoding::Text, &mut buf).await,
+            Err(SubscriptionLagged)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_progressive_subscription() {
+        let pixmap = Arc::new(
+            Pixmap::new(300, 300)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let mut session = Session::default();
+        handle_request(b"SUBSCRIBE", &pixmap, &mut session).unwrap();
+        let mut subscription = Subscription::default();
+        subscription.update(&session, &pixmap);
+        let mut buf = BytesMut::new();
+
+        // the preview is sent first
+        subscription
+            .write_next(ChangeEncoding::ProgressiveDelta, &mut buf)
+            .await
+            .unwrap();
+        assert!(matches!(
+            &parse_canvas_delta(&buf).unwrap()[..],
+            [CanvasDelta::Blocks { block: 16, .. }]
+        ));
+
+        // changes are sent together with the parts of the keyframe which refine it
+        buf.clear();
+        pixmap.set_pixel(1, 2, Color::from(0xFF0000)).unwrap();
+        subscription
+            .write_next(ChangeEncoding::ProgressiveDelta, &mut buf)
+            .await
+            .unwrap();
+        assert!(matches!(
+            &parse_canvas_delta(&buf).unwrap()[..],
+            [
+                CanvasDelta::Blocks { block: 4, .. },
+                CanvasDelta::Pixel { x: 1, y: 2, .. }
+            ]
+        ));
+
+        // the refinement ends with the full resolution tiles after which only changes are sent
+        let mut parts = 2;
+        while !matches!(subscription, Subscription::Active { keyframe: None, .. }) {
+            subscription
+                .write_next(ChangeEncoding::ProgressiveDelta, &mut buf)
+                .await
+                .unwrap();
+            parts += 1;
+        }
+        assert_eq!(parts, 1 + 9 + 9);
+    }
+}
@@ -1,94 +1,305 @@
-use crate::net::servers::GenServer;
-use crate::pixmap::SharedPixmap;
+use crate::net::protocol::{RequestDecoder, Strictness};
+use crate::net::servers::bind::{bind_tcp, fmt_addrs, serve_all};
+use crate::net::servers::conn_limits::{
+    ConnectionLimit, IdleTimer, IDLE_TIMEOUT_MESSAGE, TOO_MANY_CONNECTIONS_MESSAGE,
+};
+use crate::net::servers::conn_pool::{ConnectionPool, ConnectionState, PooledConnection};
+use crate::net::servers::hooks::admit;
+use crate::net::servers::read_buffer::{ReadBuffer, ReadBufferBudget, ReadBufferLimits};
+use crate::net::servers::subscription::{ChangeEncoding, Subscription};
+use crate::net::servers::{
+    Capabilities, ConnectionHooks, Dialect, GenServer, PeerInfo, RegionRateLimit, Reservations, Session,
+    WriteQueueLimits,
+};
+use crate::pixmap::{Canvases, SharedPixmap};
 use crate::DaemonResult;
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
-use std::io::Write;
+use std::future::Future;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::{AbortHandle, JoinSet};
 
 /// Options with which the `TcpServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct TcpServerOptions {
-    /// The address to which the server binds
-    pub bind_addr: SocketAddr,
+    /// The addresses to which the server binds
+    ///
+    /// An IPv6 wildcard address like `[::]` also accepts IPv4 clients unless IPv4 addresses with the same port are
+    /// given as well.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// Whether lines may carry a channel prefix so that multiple logical command streams can share one connection
+    ///
+    /// See [`split_channel`] for details about the framing.
+    pub multiplexing: bool,
+    /// Limits on how much data is buffered for connected clients
+    pub read_buffer: ReadBufferLimits,
+    /// How forgiving the request parser is about whitespace in received lines
+    pub strictness: Strictness,
+    /// Whether clients of this server start out in quiet mode in which errors and acknowledgements are not sent
+    pub quiet: bool,
+    /// The canvas reservations which are enforced for clients of this server
+    pub reservations: Arc<Reservations>,
+    /// Limits on how fast each client may set pixels in certain regions of the canvas
+    pub region_limits: Arc<[RegionRateLimit]>,
+    /// Additional canvases which clients of this server may select via `CANVAS`
+    pub canvases: Arc<Canvases>,
+    /// Whether clients of this server may overwrite the whole canvas via `CLEAR` and `FILL`
+    pub allow_fill: bool,
+    /// The optional protocol features which clients of this server may use
+    pub capabilities: Capabilities,
+    /// Which form of the protocol clients of this server speak
+    pub dialect: Dialect,
+    /// How many responses are queued for each client and how clients which cannot keep up with subscriptions are treated
+    pub write_queue: WriteQueueLimits,
+    /// How many buffers and session states of disconnected clients are kept for reuse by new clients
+    pub connection_pool_size: usize,
+    /// How many clients are served at once by all listeners combined or `None` to serve any number of them
+    ///
+    /// Clients which connect while the limit is reached receive an error message and are disconnected.
+    pub max_connections: Option<usize>,
+    /// After how long without receiving requests or sending subscribed changes the connection of a client is closed
+    pub idle_timeout: Option<Duration>,
+    /// Callbacks which are invoked when clients connect and disconnect
+    pub hooks: Arc<dyn ConnectionHooks>,
 }
 
 /// A server implementation using TCP to transport pixelflut messages.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct TcpServer {
     options: TcpServerOptions,
 }
 
 impl TcpServer {
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: TcpListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listeners(
+        listeners: Vec<TcpListener>,
+        pixmap: SharedPixmap,
+        options: TcpServerOptions,
+    ) -> anyhow::Result<!> {
+        Self::serve(listeners, pixmap, options, "tcp", |stream| {
+            std::future::ready(Ok(stream))
+        })
+        .await
+    }
+
+    /// Accept clients on all listeners and serve them once `upgrade` prepared their stream, e.g. by performing a
+    /// TLS handshake
+    ///
+    /// Clients of all listeners share the read buffer budget and connection pool.
+    pub(super) async fn serve<S, F, Fut>(
+        listeners: Vec<TcpListener>,
+        pixmap: SharedPixmap,
+        options: TcpServerOptions,
+        scheme: &str,
+        upgrade: F,
+    ) -> anyhow::Result<!>
+    where
+        F: Fn(TcpStream) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let budget = ReadBufferBudget::new(options.read_buffer);
+        let limit = ConnectionLimit::new(options.max_connections);
+        let pool = ConnectionPool::new(
+            format!("{}://{}", scheme, fmt_addrs(&options.bind_addrs)),
+            Session::new(
+                options.strictness,
+                options.quiet,
+                options.reservations.clone(),
+                options.region_limits.clone(),
+            )
+            .with_canvases(options.canvases.clone())
+            .with_fill(options.allow_fill)
+            .with_capabilities(options.capabilities)
+            .with_dialect(options.dialect)
+            .with_write_queue(options.write_queue),
+            options.connection_pool_size,
+        );
+        serve_all(listeners, |listener| {
+            let name = match listener.local_addr() {
+                Ok(addr) => format!("{}://{}", scheme, addr),
+                Err(_) => format!("{}://{}", scheme, fmt_addrs(&options.bind_addrs)),
+            };
+            Self::accept(
+                listener,
+                name,
+                pixmap.clone(),
+                options.clone(),
+                budget.clone(),
+                limit.clone(),
+                pool.clone(),
+                upgrade.clone(),
+            )
+        })
+        .await
+    }
+
+    /// Accept clients on one listener
+    #[allow(clippy::too_many_arguments)]
+    async fn accept<S, F, Fut>(
+        listener: TcpListener,
+        name: String,
+        pixmap: SharedPixmap,
+        options: TcpServerOptions,
+        budget: Arc<ReadBufferBudget>,
+        limit: ConnectionLimit,
+        pool: Arc<ConnectionPool>,
+        upgrade: F,
+    ) -> anyhow::Result<!>
+    where
+        F: Fn(TcpStream) -> Fut,
+        Fut: Future<Output = std::io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         loop {
             let (stream, remote_addr) = listener.accept().await?;
+            let upgrading = upgrade(stream);
+            let Some(permit) = limit.try_acquire() else {
+                tracing::warn!("Connection limit is reached, rejecting client {}", remote_addr);
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = upgrading.await {
+                        let message = format!("{}\n", TOO_MANY_CONNECTIONS_MESSAGE);
+                        let _ = stream.write_all(message.as_bytes()).await;
+                    }
+                });
+                continue;
+            };
             let pixmap = pixmap.clone();
+            let budget = budget.clone();
+            let multiplexing = options.multiplexing;
+            let idle_timeout = options.idle_timeout;
+            let mut connection = pool.acquire();
+            let hooks = options.hooks.clone();
+            let peer = PeerInfo::new(name.clone(), Some(remote_addr));
             tokio::spawn(async move {
-                if let Err(e) = TcpServer::handle_connection(stream, remote_addr, pixmap).await {
+                let _permit = permit;
+                let mut stream = match upgrading.await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tracing::warn!("Could not set up connection from {}: {e}", remote_addr);
+                        return;
+                    }
+                };
+                match admit(&*hooks, &peer, &mut stream).await {
+                    Ok(Some(admission)) => {
+                        connection.session.team = admission.team;
+                        connection.session.set_client_addr(remote_addr.ip());
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        tracing::warn!("Could not greet client {}: {e}", remote_addr);
+                        return;
+                    }
+                }
+                if let Err(e) = TcpServer::handle_connection(
+                    stream,
+                    remote_addr,
+                    pixmap,
+                    multiplexing,
+                    idle_timeout,
+                    budget,
+                    &mut connection,
+                )
+                .await
+                {
                     tracing::warn!("Got error while handling tcp connection: {e}");
                 }
+                hooks
+                    .on_connection_close(&peer, &connection.session.summary)
+                    .await;
             });
         }
     }
 
     #[tracing::instrument(skip_all, fields(remote = _remote_addr.to_string()))]
-    async fn handle_connection(
-        mut stream: TcpStream,
+    async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        mut stream: S,
         _remote_addr: SocketAddr,
         pixmap: SharedPixmap,
+        multiplexing: bool,
+        idle_timeout: Option<Duration>,
+        budget: Arc<ReadBufferBudget>,
+        connection: &mut PooledConnection,
     ) -> anyhow::Result<()> {
-        const MAX_LINE_LEN: usize = 32;
         tracing::debug!("Client connected");
 
-        let mut req_buf = BytesMut::with_capacity(8 * 1024);
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
-        loop {
-            // fill the line buffer from the network
-            let n = stream.read_buf(&mut req_buf).await?;
-            if n == 0 {
-                tracing::debug!("Client stream exhausted, likely disconnected");
-                return Ok(());
-            }
-            tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, req_buf);
+        let Some(mut req_buf) = ReadBuffer::new(budget, std::mem::take(&mut connection.read_buf)) else {
+            tracing::warn!("Read buffer budget is exhausted, rejecting client");
+            stream.write_all("server is busy\n".as_bytes()).await?;
+            return Ok(());
+        };
+        let ConnectionState {
+            resp_buf,
+            channels,
+            session,
+            ..
+        } = &mut **connection;
+        let decoder = RequestDecoder::new().with_multiplexing(multiplexing);
+        let mut subscription = Subscription::default();
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
+        let mut idle = IdleTimer::new(idle_timeout);
+        let result = async {
+            loop {
+                idle.reset();
 
-            // handle all lines contained in the buffer
-            while let Some((i, _)) = req_buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-                let line = req_buf.split_to(i + 1);
-                let result = super::handle_request(&line, &pixmap);
-                match result {
-                    Err(e) => {
-                        resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
+                // fill the line buffer from the network unless there are pixel changes for a subscribed client
+                let n = tokio::select! {
+                    n = req_buf.read_from(&mut stream) => n?,
+                    result = subscription.write_next(ChangeEncoding::Text, resp_buf) => {
+                        if let Err(e) = result {
+                            tracing::debug!("Disconnecting client: {}", e);
+                            resp_buf.clear();
+                            stream.write_all(format!("{}\n", e).as_bytes()).await?;
+                            return Ok(());
+                        }
+                        session.summary.bytes_written += resp_buf.len() as u64;
+                        stream.write_all_buf(resp_buf).await?;
+                        continue;
+                    }
+                    _ = &mut retired => {
+                        stream.write_all(format!("{}\n", super::RETIRED_MESSAGE).as_bytes()).await?;
+                        return Ok(());
                     }
-                    Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                    Ok(None) => {}
+                    _ = idle.expired() => {
+                        tracing::debug!("Closing idle connection");
+                        stream.write_all(format!("{}\n", IDLE_TIMEOUT_MESSAGE).as_bytes()).await?;
+                        return Ok(());
+                    }
+                };
+                if n == 0 {
+                    tracing::debug!("Client stream exhausted, likely disconnected");
+                    return Ok(());
                 }
-            }
+                session.summary.bytes_read += n as u64;
+                tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, *req_buf);
 
-            // clear the buffer if someone is deliberately not sending a newline
-            if req_buf.len() > MAX_LINE_LEN {
-                tracing::warn!(
-                    "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
-                    req_buf.len()
-                );
-                req_buf.clear();
-                resp_buf.write_all("line too long\n".as_bytes()).unwrap();
-            }
+                // handle all requests contained in the buffer, sending responses whenever the write queue is full
+                while super::handle_frames(&decoder, &mut req_buf, &pixmap, session, resp_buf, channels) {
+                    session.summary.bytes_written += resp_buf.len() as u64;
+                    stream.write_all_buf(resp_buf).await?;
+                }
+                subscription.update(session, &pixmap);
 
-            // write accumulated responses back to the sender
-            if !resp_buf.get_ref().is_empty() {
-                tracing::trace!(
-                    "Sending back {}KiB response: {:?}",
-                    resp_buf.get_ref().len() / 1024,
-                    resp_buf.get_ref()
-                );
-                stream.write_all_buf(resp_buf.get_mut()).await?;
+                // write accumulated responses back to the sender
+                if !resp_buf.is_empty() {
+                    tracing::trace!(
+                        "Sending back {}KiB response: {:?}",
+                        resp_buf.len() / 1024,
+                        resp_buf
+                    );
+                    session.summary.bytes_written += resp_buf.len() as u64;
+                    stream.write_all_buf(resp_buf).await?;
+                }
             }
         }
+        .await;
+
+        connection.read_buf = req_buf.into_inner();
+        result
     }
 }
 
@@ -105,13 +316,14 @@ impl GenServer for TcpServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = TcpListener::bind(self.options.bind_addr).await?;
-        tracing::info!("Started TCP Server on {}", self.options.bind_addr);
+        let listeners = bind_tcp(&self.options.bind_addrs)?;
+        tracing::info!("Started TCP Server on {}", fmt_addrs(&self.options.bind_addrs));
 
+        let options = self.options;
         let handle = join_set
             .build_task()
             .name("tcp_server")
-            .spawn(async move { TcpServer::handle_listener(listener, pixmap).await })?;
+            .spawn(async move { TcpServer::handle_listeners(listeners, pixmap, options).await })?;
         Ok(handle)
     }
 }
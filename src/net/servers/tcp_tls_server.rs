@@ -0,0 +1,130 @@
+use crate::net::servers::bind::{bind_tcp, fmt_addrs};
+use crate::net::servers::{GenServer, TcpServer, TcpServerOptions};
+use crate::net::tls::{server_config, TlsAcceptor};
+use crate::pixmap::SharedPixmap;
+use crate::DaemonResult;
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Options with which the `TcpTlsServer` is configured
+#[derive(Debug, Clone)]
+pub struct TcpTlsServerOptions {
+    /// How connections are handled once they are encrypted
+    pub tcp: TcpServerOptions,
+    /// Path to a PEM file containing the certificate chain which is presented to clients
+    pub certificate_chain: PathBuf,
+    /// Path to a PEM file containing the private key of the certificate
+    pub private_key: PathBuf,
+}
+
+/// A server implementation which transports pixelflut messages over TLS encrypted TCP connections
+///
+/// Apart from the encryption, it behaves exactly like a [`TcpServer`].
+#[derive(Debug, Clone)]
+pub struct TcpTlsServer {
+    options: TcpTlsServerOptions,
+}
+
+#[async_trait]
+impl GenServer for TcpTlsServer {
+    type Options = TcpTlsServerOptions;
+
+    fn new(options: Self::Options) -> Self {
+        Self { options }
+    }
+
+    async fn start(
+        self,
+        pixmap: SharedPixmap,
+        join_set: &mut JoinSet<DaemonResult>,
+    ) -> anyhow::Result<AbortHandle> {
+        let acceptor = TlsAcceptor::from(server_config(
+            &self.options.certificate_chain,
+            &self.options.private_key,
+        )?);
+        let listeners = bind_tcp(&self.options.tcp.bind_addrs)?;
+        tracing::info!(
+            "Started TLS Server on {}",
+            fmt_addrs(&self.options.tcp.bind_addrs)
+        );
+
+        let options = self.options.tcp;
+        let handle = join_set.build_task().name("tcp_tls_server").spawn(async move {
+            TcpServer::serve(listeners, pixmap, options, "tcps", move |stream| {
+                acceptor.accept(stream)
+            })
+            .await
+        })?;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::clients::TcpClient;
+    use crate::net::protocol::{Request, Response};
+    use crate::net::servers::DEFAULT_CONNECTION_POOL_SIZE;
+    use crate::net::tls::client_config;
+    use crate::pixmap::{Color, Pixmap};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_tls_roundtrip() {
+        let resources = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/tls");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = TcpServerOptions {
+            bind_addrs: vec![addr],
+            multiplexing: false,
+            read_buffer: Default::default(),
+            strictness: Default::default(),
+            quiet: false,
+            reservations: Default::default(),
+            region_limits: Arc::new([]),
+            canvases: Default::default(),
+            allow_fill: false,
+            capabilities: Default::default(),
+            dialect: Default::default(),
+            write_queue: Default::default(),
+            connection_pool_size: DEFAULT_CONNECTION_POOL_SIZE,
+            max_connections: None,
+            idle_timeout: None,
+            hooks: crate::net::servers::NoHooks::shared(),
+        };
+        let acceptor = TlsAcceptor::from(
+            server_config(&resources.join("test-cert.pem"), &resources.join("test-key.pem")).unwrap(),
+        );
+        let pixmap = Arc::new(Pixmap::new(4, 4).unwrap());
+        tokio::spawn(TcpServer::serve(
+            vec![listener],
+            pixmap.clone(),
+            options,
+            "tcps",
+            move |stream| acceptor.accept(stream),
+        ));
+
+        let config = client_config(&resources.join("test-ca.pem")).unwrap();
+        let mut client = TcpClient::connect_tls(&addr, "localhost", config).await.unwrap();
+        client
+            .send_request(Request::SetPixel {
+                x: 1,
+                y: 2,
+                color: Color::from(0xABCDEF),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            client.exchange(Request::GetPixel { x: 1, y: 2 }).await.unwrap(),
+            Response::PxData {
+                x: 1,
+                y: 2,
+                color: Color::from(0xABCDEF)
+            }
+        );
+        assert_eq!(pixmap.get_pixel(1, 2).unwrap(), Color::from(0xABCDEF));
+    }
+}
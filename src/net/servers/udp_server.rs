@@ -1,58 +1,115 @@
+use crate::net::protocol::{RequestDecoder, Strictness};
+use crate::net::servers::bind::{bind_udp, fmt_addrs, serve_all};
 use crate::net::servers::gen_server::GenServer;
-use crate::pixmap::SharedPixmap;
+use crate::net::servers::{Capabilities, Channels, Dialect, RegionRateLimit, Reservations, Session};
+use crate::pixmap::{Canvases, SharedPixmap};
 use crate::DaemonResult;
 use async_trait::async_trait;
-use bytes::{BufMut, Bytes, BytesMut};
-use std::io::Write;
+use bytes::BytesMut;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::task::{AbortHandle, JoinSet};
 
 /// Options with which the `UdpServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct UdpServerOptions {
-    /// The address to which the server binds
-    pub bind_addr: SocketAddr,
+    /// The addresses to which the server binds
+    ///
+    /// An IPv6 wildcard address like `[::]` also receives IPv4 datagrams unless IPv4 addresses with the same port are
+    /// given as well.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// How forgiving the request parser is about whitespace in received lines
+    pub strictness: Strictness,
+    /// Whether clients of this server start out in quiet mode in which errors and acknowledgements are not sent
+    pub quiet: bool,
+    /// The canvas reservations which are enforced for clients of this server
+    pub reservations: Arc<Reservations>,
+    /// Limits on how fast each client may set pixels in certain regions of the canvas
+    pub region_limits: Arc<[RegionRateLimit]>,
+    /// Additional canvases which clients of this server may select via `CANVAS`
+    pub canvases: Arc<Canvases>,
+    /// Whether clients of this server may overwrite the whole canvas via `CLEAR` and `FILL`
+    pub allow_fill: bool,
+    /// The optional protocol features which clients of this server may use
+    pub capabilities: Capabilities,
+    /// Which form of the protocol clients of this server speak
+    pub dialect: Dialect,
 }
 
 /// A server implementation using UDP to receive pixelflut messages.
 ///
 /// *Note*: This server **never** sends data back.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct UdpServer {
     options: UdpServerOptions,
 }
 
 impl UdpServer {
-    /// Start `n` server processes
+    /// The state with which every received datagram starts
+    fn session(&self) -> Session {
+        Session::new(
+            self.options.strictness,
+            self.options.quiet,
+            self.options.reservations.clone(),
+            self.options.region_limits.clone(),
+        )
+        .with_canvases(self.options.canvases.clone())
+        .with_fill(self.options.allow_fill)
+        .with_capabilities(self.options.capabilities)
+        .with_dialect(self.options.dialect)
+        .without_subscriptions()
+    }
+
+    /// Bind sockets to all configured addresses
+    fn bind(&self) -> anyhow::Result<Vec<Arc<UdpSocket>>> {
+        Ok(bind_udp(&self.options.bind_addrs)?
+            .into_iter()
+            .map(Arc::new)
+            .collect())
+    }
+
+    /// Start `n` server processes which each receive datagrams on all sockets
     pub async fn start_many(
         self,
         pixmap: SharedPixmap,
         n: usize,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<Vec<AbortHandle>> {
-        let socket = Arc::new(UdpSocket::bind(self.options.bind_addr).await?);
+        let sockets = self.bind()?;
         tracing::info!(
             "Started UDP Server on {} with {} tasks",
-            self.options.bind_addr,
+            fmt_addrs(&self.options.bind_addrs),
             n
         );
         (0..n)
             .map(|i| {
                 let pixmap = pixmap.clone();
-                let socket = socket.clone();
+                let sockets = sockets.clone();
+                let session = self.session();
                 let handle = join_set
                     .build_task()
                     .name(&format!("udp_server{}", i))
-                    .spawn(async move { UdpServer::listen(pixmap, socket).await })?;
+                    .spawn(async move { UdpServer::listen_all(pixmap, sockets, session).await })?;
                 Ok(handle)
             })
             .collect::<anyhow::Result<Vec<_>>>()
     }
 
+    /// Receive datagrams on all sockets
+    async fn listen_all(
+        pixmap: SharedPixmap,
+        sockets: Vec<Arc<UdpSocket>>,
+        session: Session,
+    ) -> anyhow::Result<!> {
+        serve_all(sockets, |socket| {
+            UdpServer::listen(pixmap.clone(), socket, session.clone())
+        })
+        .await
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn listen(pixmap: SharedPixmap, socket: Arc<UdpSocket>) -> anyhow::Result<!> {
+    async fn listen(pixmap: SharedPixmap, socket: Arc<UdpSocket>, session: Session) -> anyhow::Result<!> {
         loop {
             // fill a buffer from the network
             let mut req_buf = BytesMut::with_capacity(4 * 1024);
@@ -61,8 +118,10 @@ impl UdpServer {
             // process received commands in the background
             let pixmap = pixmap.clone();
             let socket = socket.clone();
+            // datagrams are independent of each other so every one of them gets a fresh session
+            let session = session.clone();
             tokio::spawn(
-                async move { Self::handle_requests(sender, req_buf.freeze(), pixmap, socket).await },
+                async move { Self::handle_requests(sender, req_buf, pixmap, socket, session).await },
             );
         }
     }
@@ -70,37 +129,43 @@ impl UdpServer {
     #[tracing::instrument(skip_all, fields(remote = sender.to_string()))]
     async fn handle_requests(
         sender: SocketAddr,
-        mut buf: Bytes,
+        mut buf: BytesMut,
         pixmap: SharedPixmap,
         socket: Arc<UdpSocket>,
+        mut session: Session,
     ) {
         tracing::trace!("Received {}KiB UDP datagram: {:?}", buf.len() / 1024, buf);
 
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
+        let mut resp_buf = BytesMut::with_capacity(2 * 1024);
+        session.set_client_addr(sender.ip());
 
-        // handle all lines contained in the request buffer
-        while let Some((i, _)) = buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-            let line = buf.split_to(i + 1);
-            let result = super::handle_request(&line, &pixmap);
-            match result {
-                Err(e) => {
-                    resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
+        // handle all requests contained in the datagram, an incomplete one at its end is simply dropped
+        let decoder = RequestDecoder::new().with_max_line_len(usize::MAX);
+        loop {
+            let more = super::handle_frames(
+                &decoder,
+                &mut buf,
+                &pixmap,
+                &mut session,
+                &mut resp_buf,
+                &mut Channels::default(),
+            );
+
+            // write accumulated responses back to the sender
+            if !resp_buf.is_empty() {
+                tracing::trace!(
+                    "Sending back {}KiB response: {:?}",
+                    resp_buf.len() / 1024,
+                    &resp_buf
+                );
+                if let Err(e) = socket.send_to(&resp_buf, sender).await {
+                    tracing::error!("Error while writing response to {}: {}", sender, e);
+                    return;
                 }
-                Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                Ok(None) => {}
+                resp_buf.clear();
             }
-        }
-
-        // write accumulated responses back to the sender
-        let resp_buf = resp_buf.into_inner();
-        if !resp_buf.is_empty() {
-            tracing::trace!(
-                "Sending back {}KiB response: {:?}",
-                resp_buf.len() / 1024,
-                &resp_buf
-            );
-            if let Err(e) = socket.send_to(&resp_buf, sender).await {
-                tracing::error!("Error while writing response to {}: {}", sender, e);
+            if !more {
+                return;
             }
         }
     }
@@ -119,13 +184,14 @@ impl GenServer for UdpServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let socket = Arc::new(UdpSocket::bind(self.options.bind_addr).await?);
-        tracing::info!("Started UDP Server on {}", self.options.bind_addr);
+        let sockets = self.bind()?;
+        tracing::info!("Started UDP Server on {}", fmt_addrs(&self.options.bind_addrs));
+        let session = self.session();
 
         let handle = join_set
             .build_task()
             .name("udp_server")
-            .spawn(async move { UdpServer::listen(pixmap, socket).await })?;
+            .spawn(async move { UdpServer::listen_all(pixmap, sockets, session).await })?;
         Ok(handle)
     }
 }
@@ -1,90 +1,319 @@
-use crate::net::servers::GenServer;
-use crate::pixmap::SharedPixmap;
+use crate::net::protocol::{RequestDecoder, Strictness};
+use crate::net::servers::conn_limits::{
+    ConnectionLimit, IdleTimer, IDLE_TIMEOUT_MESSAGE, TOO_MANY_CONNECTIONS_MESSAGE,
+};
+use crate::net::servers::conn_pool::{ConnectionPool, ConnectionState, PooledConnection};
+use crate::net::servers::hooks::admit;
+use crate::net::servers::read_buffer::{ReadBuffer, ReadBufferBudget, ReadBufferLimits};
+use crate::net::servers::subscription::{ChangeEncoding, Subscription};
+use crate::net::servers::{
+    Capabilities, ConnectionHooks, Dialect, GenServer, NoHooks, PeerInfo, RegionRateLimit, Reservations,
+    Session, WriteQueueLimits, DEFAULT_CONNECTION_POOL_SIZE,
+};
+use crate::pixmap::{Canvases, SharedPixmap};
 use crate::DaemonResult;
+use anyhow::anyhow;
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
-use std::io::Write;
-use std::path::PathBuf;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io::ErrorKind;
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::task::{AbortHandle, JoinSet};
 
 /// Options with which the `UnixSocketServer` is configured
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct UnixSocketOptions {
     /// The path at which a socket should be created
     pub path: PathBuf,
+    /// Whether `path` should be interpreted as a name in the abstract socket namespace (Linux only)
+    ///
+    /// Abstract sockets don't have a representation on the filesystem so the `mode`, `owner` and `group` options
+    /// as well as stale socket cleanup don't apply to them.
+    pub abstract_namespace: bool,
+    /// The file mode (permission bits) which the socket file should have e.g. `0o660`
+    ///
+    /// If `None`, the mode is determined by the process umask.
+    pub mode: Option<u32>,
+    /// The user id which should own the socket file
+    pub owner: Option<u32>,
+    /// The group id which should own the socket file
+    pub group: Option<u32>,
+    /// Limits on how much data is buffered for connected clients
+    pub read_buffer: ReadBufferLimits,
+    /// How forgiving the request parser is about whitespace in received lines
+    pub strictness: Strictness,
+    /// Whether clients of this server start out in quiet mode in which errors and acknowledgements are not sent
+    pub quiet: bool,
+    /// The canvas reservations which are enforced for clients of this server
+    pub reservations: Arc<Reservations>,
+    /// Limits on how fast each client may set pixels in certain regions of the canvas
+    pub region_limits: Arc<[RegionRateLimit]>,
+    /// Additional canvases which clients of this server may select via `CANVAS`
+    pub canvases: Arc<Canvases>,
+    /// Whether clients of this server may overwrite the whole canvas via `CLEAR` and `FILL`
+    pub allow_fill: bool,
+    /// The optional protocol features which clients of this server may use
+    pub capabilities: Capabilities,
+    /// Which form of the protocol clients of this server speak
+    pub dialect: Dialect,
+    /// How many responses are queued for each client and how clients which cannot keep up with subscriptions are treated
+    pub write_queue: WriteQueueLimits,
+    /// How many buffers and session states of disconnected clients are kept for reuse by new clients
+    pub connection_pool_size: usize,
+    /// How many clients are served at once or `None` to serve any number of them
+    ///
+    /// Clients which connect while the limit is reached receive an error message and are disconnected.
+    pub max_connections: Option<usize>,
+    /// After how long without receiving requests or sending subscribed changes the connection of a client is closed
+    pub idle_timeout: Option<Duration>,
+    /// Callbacks which are invoked when clients connect and disconnect
+    pub hooks: Arc<dyn ConnectionHooks>,
+}
+
+impl UnixSocketOptions {
+    /// Create options for a socket at the given path while leaving ownership and permissions at their defaults
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            abstract_namespace: false,
+            mode: None,
+            owner: None,
+            group: None,
+            read_buffer: ReadBufferLimits::default(),
+            strictness: Strictness::default(),
+            quiet: false,
+            reservations: Arc::new(Reservations::default()),
+            region_limits: Arc::new([]),
+            canvases: Arc::new(Canvases::default()),
+            allow_fill: false,
+            capabilities: Capabilities::default(),
+            dialect: Dialect::default(),
+            write_queue: WriteQueueLimits::default(),
+            connection_pool_size: DEFAULT_CONNECTION_POOL_SIZE,
+            max_connections: None,
+            idle_timeout: None,
+            hooks: NoHooks::shared(),
+        }
+    }
+}
+
+/// A guard which removes the socket file once the listener that created it is dropped
+#[derive(Debug)]
+pub(super) struct SocketFileGuard(pub(super) PathBuf);
+
+impl Drop for SocketFileGuard {
+    fn drop(&mut self) {
+        tracing::debug!("Removing unix socket file {}", self.0.display());
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            tracing::warn!("Could not remove unix socket file {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Remove a leftover socket file from a previous run
+///
+/// The file is only removed if it is a socket to which no server is listening anymore.
+pub(super) async fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{} exists but is not a unix socket", path.display()));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(anyhow!(
+            "another server is already listening on {}",
+            path.display()
+        )),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            tracing::info!("Removing stale unix socket file {}", path.display());
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
 /// A server implementation using unix domain sockets to transport pixelflut messages.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct UnixSocketServer {
     options: UnixSocketOptions,
 }
 
 impl UnixSocketServer {
+    /// Apply the configured ownership and permissions to the socket file
+    fn apply_permissions(&self) -> anyhow::Result<()> {
+        let path = &self.options.path;
+        if self.options.owner.is_some() || self.options.group.is_some() {
+            chown(path, self.options.owner, self.options.group)?;
+        }
+        if let Some(mode) = self.options.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    /// Bind a listener to the given name in the abstract socket namespace
+    #[cfg(target_os = "linux")]
+    fn bind_abstract(name: &Path) -> std::io::Result<UnixListener> {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_os_str().as_bytes())?;
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+        listener.set_nonblocking(true)?;
+        UnixListener::from_std(listener)
+    }
+
+    /// Bind a listener to the given name in the abstract socket namespace
+    #[cfg(not(target_os = "linux"))]
+    fn bind_abstract(_name: &Path) -> std::io::Result<UnixListener> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "abstract unix sockets are only supported on linux",
+        ))
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: UnixListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listener(
+        listener: UnixListener,
+        pixmap: SharedPixmap,
+        budget: Arc<ReadBufferBudget>,
+        pool: Arc<ConnectionPool>,
+        name: String,
+        options: UnixSocketOptions,
+        _guard: Option<SocketFileGuard>,
+    ) -> anyhow::Result<!> {
+        let limit = ConnectionLimit::new(options.max_connections);
         loop {
-            let (stream, _) = listener.accept().await?;
+            let (mut stream, _) = listener.accept().await?;
+            let Some(permit) = limit.try_acquire() else {
+                tracing::warn!("Connection limit is reached, rejecting unix socket client");
+                tokio::spawn(async move {
+                    let message = format!("{}\n", TOO_MANY_CONNECTIONS_MESSAGE);
+                    let _ = stream.write_all(message.as_bytes()).await;
+                });
+                continue;
+            };
             let pixmap = pixmap.clone();
+            let budget = budget.clone();
+            let mut connection = pool.acquire();
+            let hooks = options.hooks.clone();
+            let idle_timeout = options.idle_timeout;
+            let peer = PeerInfo::new(name.clone(), None);
             tokio::spawn(async move {
-                if let Err(e) = UnixSocketServer::handle_connection(stream, pixmap).await {
+                let _permit = permit;
+                match admit(&*hooks, &peer, &mut stream).await {
+                    Ok(Some(admission)) => connection.session.team = admission.team,
+                    Ok(None) => return,
+                    Err(e) => {
+                        tracing::warn!("Could not greet unix socket client: {e}");
+                        return;
+                    }
+                }
+                if let Err(e) =
+                    UnixSocketServer::handle_connection(stream, pixmap, idle_timeout, budget, &mut connection)
+                        .await
+                {
                     tracing::warn!("Got error while handling unix socket stream: {e}");
                 }
+                hooks
+                    .on_connection_close(&peer, &connection.session.summary)
+                    .await;
             });
         }
     }
 
     #[tracing::instrument(skip_all)]
-    async fn handle_connection(mut stream: UnixStream, pixmap: SharedPixmap) -> anyhow::Result<()> {
-        const MAX_LINE_LEN: usize = 32;
+    async fn handle_connection(
+        mut stream: UnixStream,
+        pixmap: SharedPixmap,
+        idle_timeout: Option<Duration>,
+        budget: Arc<ReadBufferBudget>,
+        connection: &mut PooledConnection,
+    ) -> anyhow::Result<()> {
         tracing::debug!("Client connected");
 
-        let mut req_buf = BytesMut::with_capacity(16 * 1024);
-        let mut resp_buf = BytesMut::with_capacity(2 * 1024).writer();
-        loop {
-            // fill the line buffer from the socket
-            let n = stream.read_buf(&mut req_buf).await?;
-            if n == 0 {
-                tracing::debug!("Client stream exhausted, likely disconnected");
-                return Ok(());
-            }
-            tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, req_buf);
+        let Some(mut req_buf) = ReadBuffer::new(budget, std::mem::take(&mut connection.read_buf)) else {
+            tracing::warn!("Read buffer budget is exhausted, rejecting client");
+            stream.write_all("server is busy\n".as_bytes()).await?;
+            return Ok(());
+        };
+        let ConnectionState {
+            resp_buf,
+            channels,
+            session,
+            ..
+        } = &mut **connection;
+        let decoder = RequestDecoder::new();
+        let mut subscription = Subscription::default();
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
+        let mut idle = IdleTimer::new(idle_timeout);
+        let result = async {
+            loop {
+                idle.reset();
 
-            // handle all lines contained in the buffer
-            while let Some((i, _)) = req_buf.iter().enumerate().find(|(_, &b)| b == b'\n') {
-                let line = req_buf.split_to(i + 1);
-                let result = super::handle_request(&line, &pixmap);
-                match result {
-                    Err(e) => {
-                        resp_buf.write_fmt(format_args!("{}\n", e)).unwrap();
+                // fill the line buffer from the socket unless there are pixel changes for a subscribed client
+                let n = tokio::select! {
+                    n = req_buf.read_from(&mut stream) => n?,
+                    result = subscription.write_next(ChangeEncoding::Text, resp_buf) => {
+                        if let Err(e) = result {
+                            tracing::debug!("Disconnecting client: {}", e);
+                            resp_buf.clear();
+                            stream.write_all(format!("{}\n", e).as_bytes()).await?;
+                            return Ok(());
+                        }
+                        session.summary.bytes_written += resp_buf.len() as u64;
+                        stream.write_all_buf(resp_buf).await?;
+                        continue;
                     }
-                    Ok(Some(response)) => response.write(&mut resp_buf).unwrap(),
-                    Ok(None) => {}
+                    _ = &mut retired => {
+                        stream.write_all(format!("{}\n", super::RETIRED_MESSAGE).as_bytes()).await?;
+                        return Ok(());
+                    }
+                    _ = idle.expired() => {
+                        tracing::debug!("Closing idle connection");
+                        stream.write_all(format!("{}\n", IDLE_TIMEOUT_MESSAGE).as_bytes()).await?;
+                        return Ok(());
+                    }
+                };
+                if n == 0 {
+                    tracing::debug!("Client stream exhausted, likely disconnected");
+                    return Ok(());
                 }
-            }
+                session.summary.bytes_read += n as u64;
+                tracing::trace!("Received {}KiB stream data: {:?}", n / 1024, *req_buf);
 
-            // clear the buffer if someone is deliberately not sending a newline
-            if req_buf.len() > MAX_LINE_LEN {
-                tracing::warn!(
-                    "Request buffer has {}B but no lines left in it. Client is probably misbehaving.",
-                    req_buf.len()
-                );
-                req_buf.clear();
-                resp_buf.write_all("line too long\n".as_bytes()).unwrap();
-            }
+                // handle all requests contained in the buffer, sending responses whenever the write queue is full
+                while super::handle_frames(&decoder, &mut req_buf, &pixmap, session, resp_buf, channels) {
+                    session.summary.bytes_written += resp_buf.len() as u64;
+                    stream.write_all_buf(resp_buf).await?;
+                }
+                subscription.update(session, &pixmap);
 
-            // write accumulated responses back to the sender
-            if !resp_buf.get_ref().is_empty() {
-                tracing::trace!(
-                    "Sending back {}KiB response: {:?}",
-                    resp_buf.get_ref().len() / 1024,
-                    resp_buf.get_ref()
-                );
-                stream.write_all_buf(resp_buf.get_mut()).await?;
+                // write accumulated responses back to the sender
+                if !resp_buf.is_empty() {
+                    tracing::trace!(
+                        "Sending back {}KiB response: {:?}",
+                        resp_buf.len() / 1024,
+                        resp_buf
+                    );
+                    session.summary.bytes_written += resp_buf.len() as u64;
+                    stream.write_all_buf(resp_buf).await?;
+                }
             }
         }
+        .await;
+
+        connection.read_buf = req_buf.into_inner();
+        result
     }
 }
 
@@ -101,13 +330,138 @@ impl GenServer for UnixSocketServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = UnixListener::bind(&self.options.path)?;
-        tracing::info!("Started unix listener on {}", self.options.path.display());
+        let (listener, guard) = if self.options.abstract_namespace {
+            let listener = Self::bind_abstract(&self.options.path)?;
+            tracing::info!("Started unix listener on @{}", self.options.path.display());
+            (listener, None)
+        } else {
+            remove_stale_socket(&self.options.path).await?;
+            let listener = UnixListener::bind(&self.options.path)?;
+            let guard = SocketFileGuard(self.options.path.clone());
+            self.apply_permissions()?;
+            tracing::info!("Started unix listener on {}", self.options.path.display());
+            (listener, Some(guard))
+        };
 
-        let handle = join_set
-            .build_task()
-            .name("unix_listener")
-            .spawn(async move { UnixSocketServer::handle_listener(listener, pixmap).await })?;
+        let budget = ReadBufferBudget::new(self.options.read_buffer);
+        let name = match self.options.abstract_namespace {
+            true => format!("unix-abstract:{}", self.options.path.display()),
+            false => format!("unix:{}", self.options.path.display()),
+        };
+        let pool = ConnectionPool::new(
+            name.clone(),
+            Session::new(
+                self.options.strictness,
+                self.options.quiet,
+                self.options.reservations.clone(),
+                self.options.region_limits.clone(),
+            )
+            .with_canvases(self.options.canvases.clone())
+            .with_fill(self.options.allow_fill)
+            .with_capabilities(self.options.capabilities)
+            .with_dialect(self.options.dialect)
+            .with_write_queue(self.options.write_queue),
+            self.options.connection_pool_size,
+        );
+        let options = self.options.clone();
+        let handle = join_set.build_task().name("unix_listener").spawn(async move {
+            UnixSocketServer::handle_listener(listener, pixmap, budget, pool, name, options, guard).await
+        })?;
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::clients::UnixSocketClient;
+    use crate::net::protocol::{Request, Response};
+    use crate::pixmap::{Color, Pixmap};
+
+    #[tokio::test]
+    async fn test_read_your_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pixelflut.sock");
+        let pixmap = Arc::new(Pixmap::new(8, 8).unwrap());
+        let mut join_set = JoinSet::new();
+        UnixSocketServer::new(UnixSocketOptions::new(path.clone()))
+            .start(pixmap, &mut join_set)
+            .await
+            .unwrap();
+        let mut client = UnixSocketClient::connect(&path).await.unwrap();
+
+        // writes and reads are pipelined so that the server receives them together
+        let mut requests = Vec::new();
+        for i in 0..64u32 {
+            let (x, y) = (i as usize % 8, i as usize / 8);
+            let color = Color::from(i * 0x010203);
+            match i % 2 {
+                0 => Request::SetPixel { x, y, color }.write(&mut requests).unwrap(),
+                _ => Request::SetPixel { x, y, color }
+                    .write_binary(&mut requests)
+                    .unwrap(),
+            }
+            Request::GetPixel { x, y }.write(&mut requests).unwrap();
+        }
+        Request::BlendPixel {
+            x: 0,
+            y: 0,
+            color: Color::from(0xFFFFFF),
+            alpha: 0x80,
+        }
+        .write(&mut requests)
+        .unwrap();
+        Request::GetPixel { x: 0, y: 0 }.write(&mut requests).unwrap();
+        client.get_writer().write_all(&requests).await.unwrap();
+        client.flush().await.unwrap();
+
+        for i in 0..64u32 {
+            let (x, y) = (i as usize % 8, i as usize / 8);
+            let color = Color::from(i * 0x010203);
+            assert_eq!(
+                client.await_response().await.unwrap(),
+                Response::PxData { x, y, color }
+            );
+        }
+        let blended = Pixmap::new(1, 1).unwrap();
+        blended.blend_pixel(0, 0, Color::from(0xFFFFFF), 0x80).unwrap();
+        let color = blended.get_pixel(0, 0).unwrap();
+        assert_eq!(
+            client.await_response().await.unwrap(),
+            Response::PxData { x: 0, y: 0, color }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pixelflut.sock");
+        let mut options = UnixSocketOptions::new(path.clone());
+        options.max_connections = Some(1);
+        options.idle_timeout = Some(Duration::from_millis(200));
+        let mut join_set = JoinSet::new();
+        UnixSocketServer::new(options)
+            .start(Arc::new(Pixmap::new(8, 8).unwrap()), &mut join_set)
+            .await
+            .unwrap();
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"SIZE\n").await.unwrap();
+        let mut buf = [0; 9];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SIZE 8 8\n");
+
+        // the second client is rejected while the first one is connected
+        let mut rejected = UnixStream::connect(&path).await.unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, format!("{}\n", TOO_MANY_CONNECTIONS_MESSAGE));
+
+        // the first client is disconnected once it has been idle for too long
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, format!("{}\n", IDLE_TIMEOUT_MESSAGE));
+    }
+}
@@ -0,0 +1,32 @@
+/// What happens when a subscribed client does not read changes as fast as the canvas changes
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LagPolicy {
+    /// The changes which the client missed are dropped and it receives the current content of the canvas instead
+    #[default]
+    Resync,
+    /// The client is disconnected so that it cannot hold on to server resources
+    Disconnect,
+}
+
+/// Limits on the data which is queued for sending to one client of a stream based server
+///
+/// Servers only read more requests of a client once its queued responses were written to the network, so a client
+/// which does not read its responses stops being served instead of making the server buffer them indefinitely.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WriteQueueLimits {
+    /// How many bytes are queued at most before they are sent and the server waits for the client to receive them
+    ///
+    /// Single responses which are larger than this, e.g. a keyframe of a large canvas, are still queued as a whole.
+    pub capacity: usize,
+    /// What happens to subscription updates when the client cannot keep up with them
+    pub lag_policy: LagPolicy,
+}
+
+impl Default for WriteQueueLimits {
+    fn default() -> Self {
+        Self {
+            capacity: 1024 * 1024,
+            lag_policy: LagPolicy::default(),
+        }
+    }
+}
@@ -1,67 +1,387 @@
-use crate::net::servers::GenServer;
-use crate::pixmap::SharedPixmap;
+use crate::net::protocol::Strictness;
+use crate::net::servers::bind::{bind_tcp, fmt_addrs, serve_all};
+use crate::net::servers::subscription::{ChangeEncoding, Subscription};
+use crate::net::servers::{
+    AllowedOrigins, Capabilities, ConnectionHooks, Dialect, GenServer, PeerInfo, RegionRateLimit,
+    Reservations, Session, WriteQueueLimits,
+};
+use crate::pixmap::{Canvases, SharedPixmap, DEFAULT_CANVAS};
+use crate::sinks::color_stats::LatestColorStats;
 use crate::DaemonResult;
 use anyhow::anyhow;
 use async_trait::async_trait;
+use bytes::BytesMut;
 use futures_util::{SinkExt, StreamExt};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::{AbortHandle, JoinSet};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// How often clients of `/stats` receive updated statistics
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Options with which the `WsServer` is configured
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub struct WsServerOptions {
-    /// The address to which the server binds
-    pub bind_addr: SocketAddr,
+    /// The addresses to which the server binds
+    ///
+    /// An IPv6 wildcard address like `[::]` also accepts IPv4 clients unless IPv4 addresses with the same port are
+    /// given as well.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// How forgiving the request parser is about whitespace in received lines
+    pub strictness: Strictness,
+    /// Whether clients of this server start out in quiet mode in which errors and acknowledgements are not sent
+    pub quiet: bool,
+    /// The canvas reservations which are enforced for clients of this server
+    pub reservations: Arc<Reservations>,
+    /// Limits on how fast each client may set pixels in certain regions of the canvas
+    pub region_limits: Arc<[RegionRateLimit]>,
+    /// Additional canvases which clients of this server may select via `CANVAS`
+    pub canvases: Arc<Canvases>,
+    /// The latest color statistics of the canvases which are pushed to clients of `/stats`
+    pub color_stats: Arc<LatestColorStats>,
+    /// Whether clients of this server may overwrite the whole canvas via `CLEAR` and `FILL`
+    pub allow_fill: bool,
+    /// The optional protocol features which clients of this server may use
+    pub capabilities: Capabilities,
+    /// Which form of the protocol clients of this server speak
+    pub dialect: Dialect,
+    /// How many responses are queued for each client and how clients which cannot keep up with subscriptions are treated
+    pub write_queue: WriteQueueLimits,
+    /// The origins of web pages which may connect
+    pub allowed_origins: AllowedOrigins,
+    /// Callbacks which are invoked when clients connect and disconnect
+    pub hooks: Arc<dyn ConnectionHooks>,
 }
 
 /// A server implementation using WebSocket to transport pixelflut messages
-#[derive(Debug, Copy, Clone)]
+///
+/// What a connection is used for depends on the path which is requested during the handshake:
+///
+/// - `/ws` (and `/` for compatibility) speaks the pixelflut protocol with one request or response per message.
+/// - `/stream` pushes the canvas as `STATE` regions followed by a `PX` message for every change, like a protocol
+///   connection after `SUBSCRIBE`, e.g. for browser viewers.
+///   With `/stream?encoding=delta`, the canvas and its changes are instead sent as binary messages in the compact
+///   encoding of [`CanvasDelta`](crate::net::protocol::CanvasDelta) which browsers can render without parsing text.
+///   `/stream?encoding=delta&progressive=true` sends the canvas as a low resolution preview first and refines it tile
+///   by tile in the following messages so that viewers on slow links show something right away.
+/// - `/stats` pushes the canvas statistics of the HTTP server's `/stats` endpoint as JSON every second.
+///
+/// Each route is also offered below `/canvas/<name>`, e.g. `/canvas/kids/stream`, which connects the client to one of
+/// the [`canvases`](WsServerOptions::canvases) as if it had selected it via `CANVAS` first.
+/// `/canvas/default` refers to the main canvas.
+///
+/// Handshakes for all other paths are rejected with `404 Not Found` and handshakes of web pages from origins which
+/// are not allowed are rejected with `403 Forbidden`.
+///
+/// The same routes can also be served from the port of an [`HttpServer`](crate::net::servers::HttpServer) via
+/// [`HttpServerOptions::websocket`](crate::net::servers::HttpServerOptions::websocket).
+#[derive(Debug, Clone)]
 pub struct WsServer {
     options: WsServerOptions,
 }
 
+/// What a WebSocket connection is used for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Route {
+    /// Requests and responses of the pixelflut protocol
+    Protocol,
+    /// Pushing the canvas and all of its changes in the given encoding
+    Stream(ChangeEncoding),
+    /// Pushing canvas statistics
+    Stats,
+}
+
+impl Route {
+    /// Determine the route of a requested path and query
+    pub(crate) fn of_uri(path: &str, query: Option<&str>) -> Option<Self> {
+        match (path, query) {
+            ("/" | "/ws", _) => Some(Route::Protocol),
+            ("/stream", None | Some("encoding=text")) => Some(Route::Stream(ChangeEncoding::Text)),
+            ("/stream", Some("encoding=delta")) => Some(Route::Stream(ChangeEncoding::Delta)),
+            ("/stream", Some("encoding=delta&progressive=true")) => {
+                Some(Route::Stream(ChangeEncoding::ProgressiveDelta))
+            }
+            ("/stats", _) => Some(Route::Stats),
+            _ => None,
+        }
+    }
+}
+
 impl WsServer {
+    /// Create the state of a connection which is served with the given options
+    pub(crate) fn session(options: &WsServerOptions) -> Session {
+        Session::new(
+            options.strictness,
+            options.quiet,
+            options.reservations.clone(),
+            options.region_limits.clone(),
+        )
+        .with_canvases(options.canvases.clone())
+        .with_color_stats(options.color_stats.clone())
+        .with_fill(options.allow_fill)
+        .with_capabilities(options.capabilities)
+        .with_dialect(options.dialect)
+        .with_write_queue(options.write_queue)
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn handle_listener(listener: TcpListener, pixmap: SharedPixmap) -> anyhow::Result<!> {
+    async fn handle_listener(
+        listener: TcpListener,
+        pixmap: SharedPixmap,
+        session: Session,
+        allowed_origins: AllowedOrigins,
+        hooks: Arc<dyn ConnectionHooks>,
+    ) -> anyhow::Result<!> {
+        let name = format!("ws://{}", listener.local_addr()?);
         loop {
             let (stream, remote_addr) = listener.accept().await?;
             let pixmap = pixmap.clone();
+            let session = session.clone();
+            let allowed_origins = allowed_origins.clone();
+            let hooks = hooks.clone();
+            let peer = PeerInfo::new(name.clone(), Some(remote_addr));
             tokio::spawn(async move {
-                if let Err(e) = WsServer::handle_connection(stream, remote_addr, pixmap).await {
+                if let Err(e) =
+                    WsServer::handle_connection(stream, peer, pixmap, session, allowed_origins, hooks).await
+                {
                     tracing::error!("Got error while handling WebSocket connection: {e}");
                 }
             });
         }
     }
 
-    #[tracing::instrument(skip_all, fields(remote = _remote_addr.to_string()))]
+    // the handshake callback's error type is dictated by tungstenite
+    #[allow(clippy::result_large_err)]
+    #[tracing::instrument(skip_all, fields(remote = ?peer.remote_addr))]
     async fn handle_connection(
         stream: TcpStream,
-        _remote_addr: SocketAddr,
+        peer: PeerInfo,
         pixmap: SharedPixmap,
+        mut session: Session,
+        allowed_origins: AllowedOrigins,
+        hooks: Arc<dyn ConnectionHooks>,
     ) -> anyhow::Result<()> {
         tracing::debug!("Client connected; performing WebSocket handshake");
-        let mut stream = tokio_tungstenite::accept_async(stream).await?;
+        let mut route = None;
+        let mut canvas = (DEFAULT_CANVAS.to_string(), None);
+        let stream = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            let reject = |status, reason: &str| {
+                let mut response = ErrorResponse::new(Some(reason.to_string()));
+                *response.status_mut() = status;
+                Err(response)
+            };
+            let origin = request
+                .headers()
+                .get("origin")
+                .map(|i| i.to_str().unwrap_or_default());
+            if !allowed_origins.allows(origin) {
+                return reject(StatusCode::FORBIDDEN, "origin is not allowed");
+            }
+            let Some((path, name, selected)) = super::route_canvas(request.uri().path(), &session.canvases)
+            else {
+                return reject(StatusCode::NOT_FOUND, "there is no such canvas");
+            };
+            route = Route::of_uri(path, request.uri().query());
+            canvas = (name.to_string(), selected);
+            match route {
+                Some(_) => Ok(response),
+                None => reject(StatusCode::NOT_FOUND, "not found"),
+            }
+        })
+        .await?;
+
+        let route = route.expect("handshakes for unknown paths are rejected");
+        session.select_canvas(&canvas.0, canvas.1);
+        Self::serve(stream, route, peer, pixmap, session, hooks).await
+    }
+
+    /// Serve a client whose WebSocket handshake has been completed
+    ///
+    /// Besides the server's own listeners, this also serves connections which the HTTP server upgraded.
+    pub(crate) async fn serve(
+        mut stream: WebSocketStream<TcpStream>,
+        route: Route,
+        peer: PeerInfo,
+        pixmap: SharedPixmap,
+        mut session: Session,
+        hooks: Arc<dyn ConnectionHooks>,
+    ) -> anyhow::Result<()> {
+        let admission = match hooks.on_connection_open(&peer).await {
+            Ok(admission) => admission,
+            Err(reason) => {
+                tracing::debug!("Rejecting client: {}", reason);
+                stream.send(Message::Text(reason)).await?;
+                stream.close(None).await?;
+                return Ok(());
+            }
+        };
+        session.team = admission.team;
+        if let Some(addr) = peer.remote_addr {
+            session.set_client_addr(addr.ip());
+        }
+        let result = match route {
+            Route::Protocol => {
+                if let Some(greeting) = admission.greeting {
+                    stream.send(Message::Text(greeting)).await?;
+                }
+                Self::serve_protocol(stream, pixmap, &mut session).await
+            }
+            Route::Stream(encoding) => {
+                session.subscribed = true;
+                Self::serve_stream(stream, pixmap, &mut session, encoding).await
+            }
+            Route::Stats => Self::serve_stats(stream, pixmap, &mut session).await,
+        };
+        hooks.on_connection_close(&peer, &session.summary).await;
+        result
+    }
+
+    /// Handle pixelflut requests and send changes to subscribed clients
+    async fn serve_protocol(
+        mut stream: WebSocketStream<TcpStream>,
+        pixmap: SharedPixmap,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let mut subscription = Subscription::default();
+        let mut changes_buf = BytesMut::new();
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
 
         loop {
-            let request = stream.next().await;
+            // receive the next request unless there are pixel changes for a subscribed client
+            let request = tokio::select! {
+                request = stream.next() => request,
+                result = subscription.write_next(ChangeEncoding::Text, &mut changes_buf) => {
+                    if let Err(e) = result {
+                        tracing::debug!("Disconnecting client: {}", e);
+                        stream.send(Message::Text(e.to_string())).await?;
+                        return Ok(());
+                    }
+                    let changes = String::from_utf8_lossy(&changes_buf).into_owned();
+                    changes_buf.clear();
+                    session.summary.bytes_written += changes.len() as u64;
+                    stream.send(Message::Text(changes)).await?;
+                    continue;
+                }
+                _ = &mut retired => {
+                    stream.send(Message::Text(super::RETIRED_MESSAGE.to_string())).await?;
+                    return Ok(());
+                }
+            };
             let request = match &request {
                 None => return Err(anyhow!("stream is closed")),
                 Some(Err(e)) => return Err(anyhow!("{}", e)),
                 Some(Ok(msg)) => match msg {
                     Message::Text(msg) => msg.as_bytes(),
-                    Message::Binary(msg) => &msg,
+                    Message::Binary(msg) => msg,
                     Message::Close(_) => return Err(anyhow!("WebSocket connection was closed")),
                     _ => return Err(anyhow!("Got unexpected websocket message: {msg:?}")),
                 },
             };
-            let result = super::handle_request(request, &pixmap);
-            match result {
-                Err(e) => stream.send(Message::Text(e)).await?,
-                Ok(Some(response)) => stream.send(Message::Text(format!("{}", response))).await?,
-                Ok(None) => {}
+            session.summary.bytes_read += request.len() as u64;
+            let response = match super::handle_request(request, &pixmap, session) {
+                Err(e) => Some(e),
+                Ok(Some(response)) => Some(response.to_string()),
+                Ok(None) => None,
+            };
+            if let Some(response) = response {
+                session.summary.bytes_written += response.len() as u64;
+                stream.send(Message::Text(response)).await?;
+            }
+            subscription.update(session, &pixmap);
+        }
+    }
+
+    /// Send the canvas and all of its changes until the client disconnects
+    ///
+    /// Messages of the client are ignored so that viewers can't draw.
+    async fn serve_stream(
+        mut stream: WebSocketStream<TcpStream>,
+        pixmap: SharedPixmap,
+        session: &mut Session,
+        encoding: ChangeEncoding,
+    ) -> anyhow::Result<()> {
+        let mut subscription = Subscription::default();
+        subscription.update(session, &pixmap);
+        if matches!(subscription, Subscription::Inactive) {
+            stream
+                .send(Message::Text(
+                    "subscriptions are not enabled on this server".to_string(),
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        let mut changes_buf = BytesMut::new();
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
+        loop {
+            tokio::select! {
+                result = subscription.write_next(encoding, &mut changes_buf) => {
+                    if let Err(e) = result {
+                        tracing::debug!("Disconnecting client: {}", e);
+                        stream.send(Message::Text(e.to_string())).await?;
+                        return Ok(());
+                    }
+                    let message = match encoding {
+                        ChangeEncoding::Text => Message::Text(String::from_utf8_lossy(&changes_buf).into_owned()),
+                        ChangeEncoding::Delta | ChangeEncoding::ProgressiveDelta => {
+                            Message::Binary(changes_buf.to_vec())
+                        }
+                    };
+                    session.summary.bytes_written += changes_buf.len() as u64;
+                    changes_buf.clear();
+                    stream.send(message).await?;
+                }
+                _ = &mut retired => {
+                    stream.send(Message::Text(super::RETIRED_MESSAGE.to_string())).await?;
+                    return Ok(());
+                }
+                message = stream.next() => match message {
+                    None | Some(Ok(Message::Close(_))) => return Ok(()),
+                    Some(Err(e)) => return Err(anyhow!("{}", e)),
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+
+    /// Periodically send canvas statistics until the client disconnects
+    async fn serve_stats(
+        mut stream: WebSocketStream<TcpStream>,
+        pixmap: SharedPixmap,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(STATS_INTERVAL);
+        let retired = pixmap.retired();
+        tokio::pin!(retired);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let stats = super::http_server::stats_json(
+                        session.canvas(&pixmap),
+                        session.color_stats.get(session.canvas_name()).as_deref(),
+                        &session.reservations,
+                        session.canvas_name(),
+                    );
+                    session.summary.bytes_written += stats.len() as u64;
+                    stream.send(Message::Text(stats)).await?;
+                }
+                _ = &mut retired => {
+                    stream.send(Message::Text(super::RETIRED_MESSAGE.to_string())).await?;
+                    return Ok(());
+                }
+                message = stream.next() => match message {
+                    None | Some(Ok(Message::Close(_))) => return Ok(()),
+                    Some(Err(e)) => return Err(anyhow!("{}", e)),
+                    Some(Ok(_)) => {}
+                },
             }
         }
     }
@@ -80,13 +400,169 @@ impl GenServer for WsServer {
         pixmap: SharedPixmap,
         join_set: &mut JoinSet<DaemonResult>,
     ) -> anyhow::Result<AbortHandle> {
-        let listener = TcpListener::bind(self.options.bind_addr).await?;
-        tracing::info!("Started WebSocket Server on {}", self.options.bind_addr);
+        let listeners = bind_tcp(&self.options.bind_addrs)?;
+        tracing::info!(
+            "Started WebSocket Server on {}",
+            fmt_addrs(&self.options.bind_addrs)
+        );
+        let session = Self::session(&self.options);
+        let allowed_origins = self.options.allowed_origins.clone();
+        let hooks = self.options.hooks.clone();
 
-        let handle = join_set
-            .build_task()
-            .name("ws_server")
-            .spawn(async move { WsServer::handle_listener(listener, pixmap).await })?;
+        let handle = join_set.build_task().name("ws_server").spawn(async move {
+            serve_all(listeners, |listener| {
+                WsServer::handle_listener(
+                    listener,
+                    pixmap.clone(),
+                    session.clone(),
+                    allowed_origins.clone(),
+                    hooks.clone(),
+                )
+            })
+            .await
+        })?;
         Ok(handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::net::protocol::{
+        parse_canvas_delta, parse_response_str, CanvasDelta, Response as PxResponse, DELTA_PIXEL,
+    };
+    use crate::pixmap::{Color, Pixmap};
+    use crate::sinks::color_stats::{ColorStatsSink, ColorStatsSinkOptions};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    #[tokio::test]
+    async fn test_routes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pixmap = Arc::new(
+            Pixmap::new(4, 4)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let kids = Arc::new(
+            Pixmap::new(2, 2)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let mut canvases = Canvases::default();
+        canvases.insert("kids".to_string(), kids.clone()).unwrap();
+        // only the colors of the kids canvas are analyzed
+        let mut color_stats = LatestColorStats::default();
+        let interval = tokio::time::interval(Duration::from_secs(60));
+        color_stats.insert(
+            "kids",
+            &ColorStatsSink::new(ColorStatsSinkOptions { interval }, kids),
+        );
+        let allowed_origins = AllowedOrigins::only(["https://viewer.example".to_string()]);
+        tokio::spawn(WsServer::handle_listener(
+            listener,
+            pixmap,
+            Session::default()
+                .with_canvases(Arc::new(canvases))
+                .with_color_stats(Arc::new(color_stats)),
+            allowed_origins,
+            crate::net::servers::NoHooks::shared(),
+        ));
+        let url = |path: &str| format!("ws://{}{}", addr, path);
+
+        let (mut protocol, _) = tokio_tungstenite::connect_async(url("/ws")).await.unwrap();
+        protocol.send(Message::Text("SIZE".to_string())).await.unwrap();
+        let size = protocol.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(size, "SIZE 4 4");
+
+        let (mut stream, _) = tokio_tungstenite::connect_async(url("/stream")).await.unwrap();
+        let keyframe = stream.next().await.unwrap().unwrap().into_text().unwrap();
+        assert!(matches!(
+            parse_response_str(&keyframe).unwrap(),
+            PxResponse::Region {
+                width: 4,
+                height: 4,
+                ..
+            }
+        ));
+        protocol
+            .send(Message::Text("PX 1 2 FF0000".to_string()))
+            .await
+            .unwrap();
+        let change = stream.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(change, "PX 1 2 FF0000\n");
+
+        // the delta encoding is sent as binary messages
+        let (mut delta, _) = tokio_tungstenite::connect_async(url("/stream?encoding=delta"))
+            .await
+            .unwrap();
+        let keyframe = delta.next().await.unwrap().unwrap().into_data();
+        assert!(matches!(
+            &parse_canvas_delta(&keyframe).unwrap()[..],
+            [CanvasDelta::Region { width: 4, height: 4, data, .. }] if data[9] == Color::from(0xFF0000)
+        ));
+        protocol
+            .send(Message::Text("PX 3 3 00FF00".to_string()))
+            .await
+            .unwrap();
+        let change = delta.next().await.unwrap().unwrap().into_data();
+        assert_eq!(change, [DELTA_PIXEL, 3, 0, 3, 0, 0x00, 0xFF, 0x00]);
+
+        // canvases which are this small don't need a preview so they are sent completely right away
+        let (mut progressive, _) =
+            tokio_tungstenite::connect_async(url("/stream?encoding=delta&progressive=true"))
+                .await
+                .unwrap();
+        let keyframe = progressive.next().await.unwrap().unwrap().into_data();
+        assert!(matches!(
+            &parse_canvas_delta(&keyframe).unwrap()[..],
+            [CanvasDelta::Region { width: 4, height: 4, data, .. }] if data[15] == Color::from(0x00FF00)
+        ));
+
+        let (mut stats, _) = tokio_tungstenite::connect_async(url("/stats")).await.unwrap();
+        let stats = stats.next().await.unwrap().unwrap().into_text().unwrap();
+        assert!(stats.starts_with("{\"width\":4,\"height\":4,\"average_color\":null,\"dominant_colors\":[],"));
+
+        assert!(tokio_tungstenite::connect_async(url("/unknown")).await.is_err());
+
+        // named canvases are selected by the path
+        let (mut kids, _) = tokio_tungstenite::connect_async(url("/canvas/kids/stream"))
+            .await
+            .unwrap();
+        let keyframe = kids.next().await.unwrap().unwrap().into_text().unwrap();
+        assert!(matches!(
+            parse_response_str(&keyframe).unwrap(),
+            PxResponse::Region {
+                width: 2,
+                height: 2,
+                ..
+            }
+        ));
+        let (mut stats, _) = tokio_tungstenite::connect_async(url("/canvas/kids/stats"))
+            .await
+            .unwrap();
+        let stats = stats.next().await.unwrap().unwrap().into_text().unwrap();
+        assert!(stats.starts_with("{\"width\":2,\"height\":2,\"average_color\":\"#000000\","));
+        let (mut main, _) = tokio_tungstenite::connect_async(url("/canvas/default/ws"))
+            .await
+            .unwrap();
+        main.send(Message::Text("SIZE".to_string())).await.unwrap();
+        let size = main.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(size, "SIZE 4 4");
+        assert!(tokio_tungstenite::connect_async(url("/canvas/adults/ws"))
+            .await
+            .is_err());
+
+        // pages of other origins are rejected
+        let mut request = url("/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Origin", "https://evil.example".parse().unwrap());
+        assert!(tokio_tungstenite::connect_async(request).await.is_err());
+        let mut request = url("/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Origin", "https://viewer.example".parse().unwrap());
+        assert!(tokio_tungstenite::connect_async(request).await.is_ok());
+    }
+}
@@ -0,0 +1,55 @@
+//!
+//! Loading of certificates and keys for TLS encrypted TCP connections
+//!
+
+use anyhow::{anyhow, Context};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Read all certificates from a PEM file
+fn load_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Could not read certificates from {}", path.display()))?;
+    match certificates.is_empty() {
+        true => Err(anyhow!("{} contains no certificates", path.display())),
+        false => Ok(certificates),
+    }
+}
+
+/// Read the first private key from a PEM file
+fn load_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Could not read private key from {}", path.display()))?
+        .ok_or_else(|| anyhow!("{} contains no private key", path.display()))
+}
+
+/// Create the TLS configuration of a server from PEM files containing its certificate chain and private key
+pub fn server_config(certificate_chain: &Path, private_key: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder().with_no_client_auth().with_single_cert(
+        load_certificates(certificate_chain)?,
+        load_private_key(private_key)?,
+    )?;
+    Ok(Arc::new(config))
+}
+
+/// Create the TLS configuration of a client which trusts the certificate authorities in the given PEM file
+///
+/// Pixelflut servers usually run at events with self-signed certificates, so no system certificates are trusted.
+pub fn client_config(ca_certificates: &Path) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_certificates)? {
+        roots.add(certificate)?;
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,91 @@
+use crate::pixmap::Color;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// The granularity with which write times are recorded
+const RESOLUTION: Duration = Duration::from_millis(100);
+
+/// Record of when each pixel of a [`Pixmap`](super::Pixmap) was last written
+///
+/// Reading the system clock for every pixel write would be too expensive, so writes are stamped with a coarse clock
+/// which is only advanced when the map is rendered.
+/// Pixels which are written between two renders therefore appear as if they were written at the earlier render.
+#[derive(Debug)]
+pub struct ActivityMap {
+    epoch: Instant,
+    /// The current time in units of [`RESOLUTION`] since `epoch`
+    ///
+    /// This starts at 1 so that a stamp of 0 can mean that the pixel was never written.
+    clock: AtomicU32,
+    /// The clock value at the last write of each pixel
+    last_writes: Vec<AtomicU32>,
+}
+
+impl ActivityMap {
+    /// Create a map for a pixmap with the given number of pixels in which no pixel has been written yet
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            epoch: Instant::now(),
+            clock: AtomicU32::new(1),
+            last_writes: (0..len).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Record that the pixel at `index` has just been written
+    #[inline(always)]
+    pub(crate) fn touch(&self, index: usize) {
+        let now = self.clock.load(Ordering::Relaxed);
+        // Safety: the pixmap only passes indices which are valid for its data and this map has the same length
+        unsafe { self.last_writes.get_unchecked(index) }.store(now, Ordering::Relaxed);
+    }
+
+    /// Advance the coarse clock to the current time and return it
+    fn advance_clock(&self) -> u32 {
+        let now = (self.epoch.elapsed().as_millis() / RESOLUTION.as_millis()) as u32 + 1;
+        self.clock.store(now, Ordering::Relaxed);
+        now
+    }
+
+    /// Render the recency of writes as brightness, ordered row by row like the pixmap data
+    ///
+    /// Pixels which have just been written are white and fade to black over the course of `decay`.
+    /// Pixels which were never written or not within `decay` are black.
+    pub fn render(&self, decay: Duration) -> Vec<Color> {
+        let now = self.advance_clock();
+        let decay = (decay.as_millis() / RESOLUTION.as_millis()).max(1) as u32;
+        self.last_writes
+            .iter()
+            .map(|stamp| match stamp.load(Ordering::Relaxed) {
+                0 => Color::default(),
+                stamp => {
+                    let remaining = decay.saturating_sub(now.saturating_sub(stamp));
+                    let brightness = (remaining * 255 / decay) as u8;
+                    Color::from((brightness, brightness, brightness))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pixmap::{Color, Pixmap};
+    use std::time::Duration;
+
+    #[test]
+    fn test_render_activity() {
+        let pixmap = Pixmap::new(4, 1).unwrap().with_activity_tracking();
+        pixmap.set_pixel(1, 0, Color::from(0x123456)).unwrap();
+        pixmap.set_pixels(&[(2, 0, Color::from(0x123456))]).unwrap();
+
+        let activity = pixmap.activity().unwrap().render(Duration::from_secs(10));
+        assert_eq!(activity[0], Color::default());
+        assert_eq!(activity[1], Color::from(0xFFFFFF));
+        assert_eq!(activity[2], Color::from(0xFFFFFF));
+        assert_eq!(activity[3], Color::default());
+
+        std::thread::sleep(Duration::from_millis(250));
+        let activity = pixmap.activity().unwrap().render(Duration::from_millis(200));
+        assert_eq!(activity[1], Color::default());
+    }
+}
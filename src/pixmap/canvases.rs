@@ -0,0 +1,77 @@
+use crate::pixmap::SharedPixmap;
+use std::collections::BTreeMap;
+
+/// The name under which clients select the main canvas of a server
+pub const DEFAULT_CANVAS: &str = "default";
+
+/// Additional named canvases which a server offers next to its main canvas
+///
+/// Clients switch between them with `CANVAS <name>` and select the main canvas again with
+/// `CANVAS` [`DEFAULT_CANVAS`].
+#[derive(Debug, Default, Clone)]
+pub struct Canvases {
+    canvases: BTreeMap<String, SharedPixmap>,
+}
+
+impl Canvases {
+    /// Register a canvas under the given name
+    ///
+    /// Fails if the name is already taken or is the name of the main canvas.
+    pub fn insert(&mut self, name: String, pixmap: SharedPixmap) -> Result<(), String> {
+        if name == DEFAULT_CANVAS || self.canvases.contains_key(&name) {
+            return Err(format!("a canvas named {} already exists", name));
+        }
+        self.canvases.insert(name, pixmap);
+        Ok(())
+    }
+
+    /// Look up a canvas by name
+    ///
+    /// `Some(None)` is returned for [`DEFAULT_CANVAS`] because the main canvas is not part of the registry.
+    pub fn get(&self, name: &str) -> Option<Option<&SharedPixmap>> {
+        match name {
+            DEFAULT_CANVAS => Some(None),
+            name => self.canvases.get(name).map(Some),
+        }
+    }
+
+    /// Iterate over the names and pixmaps of all additional canvases
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SharedPixmap)> {
+        self.canvases.iter().map(|(name, pixmap)| (name.as_str(), pixmap))
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::*;
+    use crate::net::protocol::Response;
+    use crate::net::servers::{handle_request, Session};
+    use crate::pixmap::{Color, Pixmap};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_select_canvas() {
+        let main = Arc::new(Pixmap::new(4, 4).unwrap());
+        let stage = Arc::new(Pixmap::new(2, 2).unwrap());
+        let mut canvases = Canvases::default();
+        canvases.insert("stage".to_string(), stage.clone()).unwrap();
+        assert!(canvases
+            .insert(DEFAULT_CANVAS.to_string(), stage.clone())
+            .is_err());
+        let mut session = Session::default().with_canvases(Arc::new(canvases));
+
+        assert!(handle_request(b"CANVAS missing", &main, &mut session).is_err());
+        handle_request(b"CANVAS stage", &main, &mut session).unwrap();
+        handle_request(b"PX 1 1 FF0000", &main, &mut session).unwrap();
+        assert_eq!(
+            handle_request(b"SIZE", &main, &mut session).unwrap(),
+            Some(Response::Size { width: 2, height: 2 })
+        );
+        assert_eq!(stage.get_pixel(1, 1).unwrap(), Color::from(0xFF0000));
+        assert_eq!(main.get_pixel(1, 1).unwrap(), Color::from(0));
+
+        handle_request(b"CANVAS default", &main, &mut session).unwrap();
+        handle_request(b"PX 3 3 00FF00", &main, &mut session).unwrap();
+        assert_eq!(main.get_pixel(3, 3).unwrap(), Color::from(0x00FF00));
+    }
+}
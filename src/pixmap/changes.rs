@@ -0,0 +1,264 @@
+use crate::pixmap::Color;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// How many changes are buffered for subscribers by default before slow ones miss some of them
+pub const DEFAULT_CHANGE_CAPACITY: usize = 1 << 16;
+
+/// Into how many slots the keyframes of subscriptions are spread during the warm-up period
+const KEYFRAME_SLOTS: u32 = 16;
+
+/// A change of pixel data which is announced to subscribers
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelChange {
+    /// One pixel was set to a new color
+    Pixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The new color of the pixel
+        color: Color,
+    },
+    /// All pixels in a rectangular region were overwritten at once
+    ///
+    /// Subscribers should read the region again instead of being told about every single pixel.
+    Region {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+    },
+}
+
+/// A rectangle of the canvas to whose changes a [`ChangeSubscription`] is limited
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WatchedRegion {
+    /// The x coordinate of the rectangles top-left corner
+    pub x: usize,
+    /// The y coordinate of the rectangles top-left corner
+    pub y: usize,
+    /// The width of the rectangle
+    pub width: usize,
+    /// The height of the rectangle
+    pub height: usize,
+}
+
+impl WatchedRegion {
+    /// The part of a change which lies inside this rectangle or `None` if it lies completely outside of it
+    ///
+    /// Region changes are clipped to the rectangle.
+    pub fn clip(&self, change: PixelChange) -> Option<PixelChange> {
+        match change {
+            PixelChange::Pixel { x, y, .. } => {
+                let inside =
+                    x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height;
+                inside.then_some(change)
+            }
+            PixelChange::Region { x, y, width, height } => {
+                let x_min = x.max(self.x);
+                let y_min = y.max(self.y);
+                let x_max = (x + width).min(self.x + self.width);
+                let y_max = (y + height).min(self.y + self.height);
+                (x_min < x_max && y_min < y_max).then_some(PixelChange::Region {
+                    x: x_min,
+                    y: y_min,
+                    width: x_max - x_min,
+                    height: y_max - y_min,
+                })
+            }
+        }
+    }
+}
+
+/// Announcement of all writes to a [`Pixmap`](super::Pixmap) to interested subscribers
+///
+/// Writes are only announced while somebody is subscribed so that the broadcast costs next to nothing otherwise.
+#[derive(Debug)]
+pub struct ChangeBroadcast {
+    sender: broadcast::Sender<PixelChange>,
+    /// How many changes are buffered for each subscriber
+    capacity: usize,
+    /// How many [`ChangeSubscription`]s currently exist
+    subscribers: Arc<AtomicUsize>,
+    /// When the warm-up period during which keyframes are staggered ends
+    warm_up_end: Instant,
+    warm_up: Duration,
+    /// How many keyframes have been scheduled so far
+    keyframes: AtomicUsize,
+}
+
+impl ChangeBroadcast {
+    /// Create a broadcast which buffers up to `capacity` changes for each subscriber
+    ///
+    /// Keyframes of subscriptions which are made during the next `warm_up` are spread over that period.
+    pub(crate) fn new(capacity: usize, warm_up: Duration) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+            capacity,
+            subscribers: Arc::new(AtomicUsize::new(0)),
+            warm_up_end: Instant::now() + warm_up,
+            warm_up,
+            keyframes: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many changes are buffered for each subscriber
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Announce a change if anyone is subscribed
+    #[inline(always)]
+    pub(crate) fn publish(&self, change: PixelChange) {
+        if self.subscribers.load(Ordering::Relaxed) > 0 {
+            let _ = self.sender.send(change);
+        }
+    }
+
+    /// Start receiving all changes which are made from now on
+    pub fn subscribe(&self) -> ChangeSubscription {
+        self.subscribers.fetch_add(1, Ordering::Relaxed);
+        ChangeSubscription {
+            receiver: self.sender.subscribe(),
+            subscribers: self.subscribers.clone(),
+            region: None,
+        }
+    }
+
+    /// Start receiving the changes inside a rectangle which are made from now on
+    ///
+    /// Changes outside of the rectangle are skipped by the subscription and region changes are clipped to it.
+    pub fn subscribe_region(&self, region: WatchedRegion) -> ChangeSubscription {
+        let mut subscription = self.subscribe();
+        subscription.region = Some(region);
+        subscription
+    }
+
+    /// Determine when a new subscriber should receive its keyframe
+    ///
+    /// Outside of the warm-up period this is right away.
+    /// During the warm-up period, keyframes are spread over it so that they don't all have to be encoded at once.
+    pub fn keyframe_at(&self) -> Instant {
+        let now = Instant::now();
+        if now >= self.warm_up_end {
+            return now;
+        }
+        let slot = (self.keyframes.fetch_add(1, Ordering::Relaxed) % KEYFRAME_SLOTS as usize) as u32;
+        let start = self.warm_up_end - self.warm_up;
+        Instant::max(now, start + self.warm_up * slot / KEYFRAME_SLOTS)
+    }
+}
+
+/// The receiving end of a [`ChangeBroadcast`]
+#[derive(Debug)]
+pub struct ChangeSubscription {
+    receiver: broadcast::Receiver<PixelChange>,
+    subscribers: Arc<AtomicUsize>,
+    /// The rectangle outside of which changes are skipped
+    region: Option<WatchedRegion>,
+}
+
+impl ChangeSubscription {
+    /// Wait for the next change
+    ///
+    /// This is cancel safe.
+    /// If the subscriber was too slow and changes were dropped, [`broadcast::error::RecvError::Lagged`] is returned.
+    pub async fn recv(&mut self) -> Result<PixelChange, broadcast::error::RecvError> {
+        loop {
+            let change = self.receiver.recv().await?;
+            if let Some(change) = self.filter(change) {
+                return Ok(change);
+            }
+        }
+    }
+
+    /// Get the next change if one is already available
+    pub fn try_recv(&mut self) -> Result<PixelChange, broadcast::error::TryRecvError> {
+        loop {
+            let change = self.receiver.try_recv()?;
+            if let Some(change) = self.filter(change) {
+                return Ok(change);
+            }
+        }
+    }
+
+    /// The rectangle to which this subscription is limited, if any
+    pub fn region(&self) -> Option<WatchedRegion> {
+        self.region
+    }
+
+    #[inline(always)]
+    fn filter(&self, change: PixelChange) -> Option<PixelChange> {
+        match &self.region {
+            None => Some(change),
+            Some(region) => region.clip(change),
+        }
+    }
+}
+
+impl Drop for ChangeSubscription {
+    fn drop(&mut self) {
+        self.subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pixmap::{Color, PixelChange, Pixmap};
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[test]
+    fn test_subscribe() {
+        let pixmap = Pixmap::new(4, 4)
+            .unwrap()
+            .with_change_broadcast(2, Duration::ZERO);
+        // changes without subscribers are not buffered
+        pixmap.set_pixel(0, 0, Color::from(0x111111)).unwrap();
+
+        let mut subscription = pixmap.changes().unwrap().subscribe();
+        pixmap.blend_pixel(1, 2, Color::from(0xFFFFFF), 0xFF).unwrap();
+        pixmap.set_region(0, 0, 1, 1, &[Color::from(0x222222)]).unwrap();
+        assert_eq!(
+            subscription.try_recv().unwrap(),
+            PixelChange::Pixel {
+                x: 1,
+                y: 2,
+                color: Color::from(0xFFFFFF)
+            }
+        );
+        assert_eq!(
+            subscription.try_recv().unwrap(),
+            PixelChange::Region {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1
+            }
+        );
+
+        // slow subscribers are told that they missed changes
+        for x in 0..3 {
+            pixmap.set_pixel(x, 0, Color::from(0x333333)).unwrap();
+        }
+        assert_eq!(subscription.try_recv(), Err(TryRecvError::Lagged(1)));
+    }
+
+    #[test]
+    fn test_staggered_keyframes() {
+        let pixmap = Pixmap::new(4, 4)
+            .unwrap()
+            .with_change_broadcast(2, Duration::from_secs(16));
+        let changes = pixmap.changes().unwrap();
+        let first = changes.keyframe_at();
+        let second = changes.keyframe_at();
+        assert!(second - first > Duration::from_millis(900));
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::{Formatter, LowerHex, UpperHex};
+use std::fmt::{Display, Formatter, LowerHex, UpperHex};
 
 #[cfg(test)]
 use quickcheck::{Arbitrary, Gen};
@@ -10,6 +10,21 @@ use quickcheck::{Arbitrary, Gen};
 #[repr(C)]
 pub struct Color(u32);
 
+impl Color {
+    /// Blend `over` onto this color with the given opacity
+    ///
+    /// An `alpha` of 0 keeps this color unchanged while 255 results in exactly `over`.
+    #[inline(always)]
+    pub fn blend(self, over: Color, alpha: u8) -> Color {
+        let [_, r1, g1, b1] = self.0.to_be_bytes();
+        let [_, r2, g2, b2] = over.0.to_be_bytes();
+        let alpha = alpha as u32;
+        let mix =
+            |under: u8, over: u8| ((over as u32 * alpha + under as u32 * (255 - alpha) + 127) / 255) as u8;
+        Color::from((mix(r1, r2), mix(g1, g2), mix(b1, b2)))
+    }
+}
+
 impl From<[u8; 3]> for Color {
     fn from(data: [u8; 3]) -> Self {
         Self(u32::from_be_bytes([0, data[0], data[1], data[2]]))
@@ -55,10 +70,13 @@ impl From<Color> for Vec<u8> {
     }
 }
 
-impl ToString for Color {
-    fn to_string(&self) -> String {
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let channels: [u8; 3] = (*self).into();
-        format!("#{:02X}{:02X}{:02X}", channels[0], channels[1], channels[2])
+        f.write_fmt(format_args!(
+            "#{:02X}{:02X}{:02X}",
+            channels[0], channels[1], channels[2]
+        ))
     }
 }
 
@@ -109,3 +127,13 @@ fn test_conversion() {
     run_test([0xAA, 0xBB, 0xCC], Color(0x00AABBCC));
     run_test(0x00AABBCC, Color(0x00AABBCC));
 }
+
+#[cfg(test)]
+#[test]
+fn test_blend() {
+    let under = Color::from((0x00, 0x80, 0xFF));
+    let over = Color::from((0xFF, 0x00, 0xFF));
+    assert_eq!(under.blend(over, 0), under);
+    assert_eq!(under.blend(over, 0xFF), over);
+    assert_eq!(under.blend(over, 0x80), Color::from((0x80, 0x40, 0xFF)));
+}
@@ -0,0 +1,191 @@
+//!
+//! Compositing of colors with different blend modes
+//!
+//! The functions here operate on whole rows of pixels and dispatch on the blend mode only once per row so that the
+//! compiler can vectorize the per-pixel arithmetic.
+//!
+
+use crate::pixmap::Color;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// How the colors of a layer are combined with the colors beneath it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+pub enum BlendMode {
+    /// The layer is painted over the pixels beneath it
+    #[default]
+    Over,
+    /// The channels of both colors are added and saturate at full intensity, which only ever brightens pixels
+    Add,
+    /// The channels of both colors are multiplied, which only ever darkens pixels like overlapping slides
+    Multiply,
+    /// The inverted channels are multiplied and the result is inverted again, which only ever brightens pixels
+    Screen,
+}
+
+/// An error which indicates that a blend mode name is not known
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("unknown blend mode {0:?}, expected one of over, add, multiply or screen")]
+pub struct UnknownBlendModeError(String);
+
+impl FromStr for BlendMode {
+    type Err = UnknownBlendModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "over" => Ok(BlendMode::Over),
+            "add" => Ok(BlendMode::Add),
+            "multiply" => Ok(BlendMode::Multiply),
+            "screen" => Ok(BlendMode::Screen),
+            _ => Err(UnknownBlendModeError(s.to_string())),
+        }
+    }
+}
+
+impl Display for BlendMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlendMode::Over => f.write_str("over"),
+            BlendMode::Add => f.write_str("add"),
+            BlendMode::Multiply => f.write_str("multiply"),
+            BlendMode::Screen => f.write_str("screen"),
+        }
+    }
+}
+
+/// Divide a value in the range of 0..=255*255 by 255 with rounding
+#[inline(always)]
+fn div255(value: u32) -> u32 {
+    (value + 128 + ((value + 128) >> 8)) >> 8
+}
+
+#[inline(always)]
+fn over(_under: u32, over: u32) -> u32 {
+    over
+}
+
+#[inline(always)]
+fn add(under: u32, over: u32) -> u32 {
+    (under + over).min(255)
+}
+
+#[inline(always)]
+fn multiply(under: u32, over: u32) -> u32 {
+    div255(under * over)
+}
+
+#[inline(always)]
+fn screen(under: u32, over: u32) -> u32 {
+    255 - div255((255 - under) * (255 - over))
+}
+
+/// Blend every pixel of `layer` onto the corresponding pixel of `target` with the per-channel function `blend`
+#[inline(always)]
+fn compose_with(target: &mut [Color], layer: &[Color], alpha: u8, blend: impl Fn(u32, u32) -> u32) {
+    let alpha = alpha as u32;
+    for (under, &over) in target.iter_mut().zip(layer) {
+        let (under_bits, over_bits) = (u32::from(*under), u32::from(over));
+        let mut result = 0;
+        for shift in [16, 8, 0] {
+            let u = (under_bits >> shift) & 0xFF;
+            let o = (over_bits >> shift) & 0xFF;
+            let blended = blend(u, o);
+            result |= div255(blended * alpha + u * (255 - alpha)) << shift;
+        }
+        *under = Color::from(result);
+    }
+}
+
+/// Blend a row of `layer` colors onto the `target` colors beneath them
+///
+/// `alpha` is the opacity of the whole layer from 0 (invisible) to 255 (opaque).
+/// Only as many pixels as both slices contain are composed.
+pub fn compose_row(mode: BlendMode, target: &mut [Color], layer: &[Color], alpha: u8) {
+    match mode {
+        BlendMode::Over => compose_with(target, layer, alpha, over),
+        BlendMode::Add => compose_with(target, layer, alpha, add),
+        BlendMode::Multiply => compose_with(target, layer, alpha, multiply),
+        BlendMode::Screen => compose_with(target, layer, alpha, screen),
+    }
+}
+
+/// Blend a single `layer` color onto the `target` color beneath it
+pub fn compose(mode: BlendMode, target: Color, layer: Color, alpha: u8) -> Color {
+    let mut result = [target];
+    compose_row(mode, &mut result, &[layer], alpha);
+    result[0]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::quickcheck;
+
+    /// A straightforward floating point implementation of the blend modes to compare against
+    fn reference(mode: BlendMode, under: [u8; 3], over: [u8; 3], alpha: u8) -> [u8; 3] {
+        let alpha = alpha as f64 / 255.0;
+        let mut result = [0; 3];
+        for i in 0..3 {
+            let (u, o) = (under[i] as f64 / 255.0, over[i] as f64 / 255.0);
+            let blended = match mode {
+                BlendMode::Over => o,
+                BlendMode::Add => (u + o).min(1.0),
+                BlendMode::Multiply => u * o,
+                BlendMode::Screen => 1.0 - (1.0 - u) * (1.0 - o),
+            };
+            result[i] = ((blended * alpha + u * (1.0 - alpha)) * 255.0).round() as u8;
+        }
+        result
+    }
+
+    quickcheck! {
+        fn test_compose_matches_reference(under: Color, over: Color, alpha: u8) -> bool {
+            [BlendMode::Over, BlendMode::Add, BlendMode::Multiply, BlendMode::Screen]
+                .into_iter()
+                .all(|mode| {
+                    let expected = reference(mode, under.into(), over.into(), alpha);
+                    let actual = <[u8; 3]>::from(compose(mode, under, over, alpha));
+                    // the integer arithmetic may round intermediate results differently
+                    actual.iter().zip(expected).all(|(a, e)| a.abs_diff(e) <= 1)
+                })
+        }
+    }
+
+    #[test]
+    fn test_compose_row() {
+        let under = [0x000000, 0x808080, 0xFFFFFF, 0x204060].map(Color::from);
+        let layer = [0xFF8000, 0x808080, 0x000000, 0x402010].map(Color::from);
+        let cases = [
+            (BlendMode::Over, 0xFF, [0xFF8000, 0x808080, 0x000000, 0x402010]),
+            (BlendMode::Over, 0x00, [0x000000, 0x808080, 0xFFFFFF, 0x204060]),
+            (BlendMode::Add, 0xFF, [0xFF8000, 0xFFFFFF, 0xFFFFFF, 0x606070]),
+            (
+                BlendMode::Multiply,
+                0xFF,
+                [0x000000, 0x404040, 0x000000, 0x080806],
+            ),
+            (BlendMode::Screen, 0xFF, [0xFF8000, 0xC0C0C0, 0xFFFFFF, 0x58586A]),
+            (BlendMode::Over, 0x80, [0x804000, 0x808080, 0x7F7F7F, 0x303038]),
+        ];
+        for (mode, alpha, expected) in cases {
+            let mut target = under;
+            compose_row(mode, &mut target, &layer, alpha);
+            assert_eq!(target, expected.map(Color::from), "{mode} with alpha {alpha}");
+        }
+    }
+
+    #[test]
+    fn test_parse_blend_mode() {
+        for mode in [
+            BlendMode::Over,
+            BlendMode::Add,
+            BlendMode::Multiply,
+            BlendMode::Screen,
+        ] {
+            assert_eq!(mode.to_string().parse(), Ok(mode));
+        }
+        assert_eq!("SCREEN".parse(), Ok(BlendMode::Screen));
+        assert!("darken".parse::<BlendMode>().is_err());
+    }
+}
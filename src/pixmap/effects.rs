@@ -0,0 +1,217 @@
+//!
+//! Visual effects which make symmetric images out of a canvas, e.g. for art installations
+//!
+//! Every effect maps each pixel of its output to one pixel of the source canvas. The mapping only depends on the
+//! effect and the canvas size so that it can be computed once and applied to many frames.
+//!
+
+use crate::pixmap::{CanvasSnapshot, Color};
+use std::f64::consts::TAU;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The number of segments of a kaleidoscope if none is given
+const DEFAULT_SEGMENTS: u32 = 6;
+
+/// The number of folds of a rotation if none is given
+const DEFAULT_FOLDS: u32 = 4;
+
+/// How the canvas is transformed before it is displayed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Hash)]
+pub enum Effect {
+    /// The canvas is shown as it is
+    #[default]
+    None,
+    /// The left half is mirrored onto the right half
+    MirrorHorizontal,
+    /// The top half is mirrored onto the bottom half
+    MirrorVertical,
+    /// The top-left quadrant is mirrored into the other three quadrants
+    Quadrants,
+    /// A wedge at the center is mirrored around it like in a kaleidoscope with the given number of segments
+    Kaleidoscope {
+        /// How many mirrored wedges make up the full circle
+        segments: u32,
+    },
+    /// A wedge at the center is repeated around it so that the image has rotational symmetry
+    Rotation {
+        /// How many times the wedge is repeated over the full circle
+        folds: u32,
+    },
+}
+
+/// An error which indicates that an effect description could not be parsed
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error(
+    "invalid effect {0:?}, expected one of none, mirror-horizontal, mirror-vertical, quadrants, \
+    kaleidoscope[:<segments>] or rotation[:<folds>] with at least 2 segments or folds"
+)]
+pub struct InvalidEffectError(String);
+
+impl FromStr for Effect {
+    type Err = InvalidEffectError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || InvalidEffectError(s.to_string());
+        let lower = s.to_ascii_lowercase();
+        let (name, given) = match lower.split_once(':') {
+            None => (lower.as_str(), None),
+            Some((name, count)) => (name, Some(count.parse::<u32>().map_err(|_| error())?)),
+        };
+        let count = |default: u32| match given.unwrap_or(default) {
+            0 | 1 => Err(error()),
+            count => Ok(count),
+        };
+        match (name, given) {
+            ("none", None) => Ok(Effect::None),
+            ("mirror-horizontal", None) => Ok(Effect::MirrorHorizontal),
+            ("mirror-vertical", None) => Ok(Effect::MirrorVertical),
+            ("quadrants", None) => Ok(Effect::Quadrants),
+            ("kaleidoscope", _) => Ok(Effect::Kaleidoscope {
+                segments: count(DEFAULT_SEGMENTS)?,
+            }),
+            ("rotation", _) => Ok(Effect::Rotation {
+                folds: count(DEFAULT_FOLDS)?,
+            }),
+            _ => Err(error()),
+        }
+    }
+}
+
+impl Display for Effect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Effect::None => f.write_str("none"),
+            Effect::MirrorHorizontal => f.write_str("mirror-horizontal"),
+            Effect::MirrorVertical => f.write_str("mirror-vertical"),
+            Effect::Quadrants => f.write_str("quadrants"),
+            Effect::Kaleidoscope { segments } => write!(f, "kaleidoscope:{}", segments),
+            Effect::Rotation { folds } => write!(f, "rotation:{}", folds),
+        }
+    }
+}
+
+impl Effect {
+    /// Apply the effect to a snapshot of a canvas
+    ///
+    /// Applying the same effect to many frames is cheaper via [`Effect::source_indices`] and [`gather`].
+    pub fn apply(&self, source: &CanvasSnapshot) -> Vec<Color> {
+        let (width, height) = source.get_size();
+        gather(source, &self.source_indices(width, height))
+    }
+
+    /// Calculate for every pixel of the output, row by row, the index of the source pixel which it shows
+    pub fn source_indices(&self, width: usize, height: usize) -> Vec<usize> {
+        let mirror = |i: usize, len: usize| i.min(len - 1 - i);
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (x, y) = match *self {
+                    Effect::None => (x, y),
+                    Effect::MirrorHorizontal => (mirror(x, width), y),
+                    Effect::MirrorVertical => (x, mirror(y, height)),
+                    Effect::Quadrants => (mirror(x, width), mirror(y, height)),
+                    Effect::Kaleidoscope { segments } => fold_angle(x, y, width, height, segments, true),
+                    Effect::Rotation { folds } => fold_angle(x, y, width, height, folds, false),
+                };
+                y * width + x
+            })
+            .collect()
+    }
+}
+
+/// Map a pixel onto the wedge which starts at an angle of zero around the center of the canvas
+///
+/// The full circle is divided into `count` wedges. Every other wedge is mirrored if `mirrored` is set so that
+/// neighbouring wedges meet seamlessly like in a kaleidoscope.
+fn fold_angle(x: usize, y: usize, width: usize, height: usize, count: u32, mirrored: bool) -> (usize, usize) {
+    let (cx, cy) = ((width - 1) as f64 / 2.0, (height - 1) as f64 / 2.0);
+    let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+    let wedge = TAU / count as f64;
+    let mut angle = dy.atan2(dx).rem_euclid(TAU) % wedge;
+    if mirrored && angle > wedge / 2.0 {
+        angle = wedge - angle;
+    }
+    let radius = dx.hypot(dy);
+    let clamp = |v: f64, len: usize| (v.round().max(0.0) as usize).min(len - 1);
+    (
+        clamp(cx + radius * angle.cos(), width),
+        clamp(cy + radius * angle.sin(), height),
+    )
+}
+
+/// Pick the pixels with the given indices out of a snapshot
+pub fn gather(source: &CanvasSnapshot, indices: &[usize]) -> Vec<Color> {
+    let pixels = source.pixels();
+    indices.iter().map(|&i| pixels[i]).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+
+    #[test]
+    fn test_parse_effect() {
+        assert_eq!("quadrants".parse(), Ok(Effect::Quadrants));
+        assert_eq!(
+            "kaleidoscope".parse(),
+            Ok(Effect::Kaleidoscope {
+                segments: DEFAULT_SEGMENTS
+            })
+        );
+        assert_eq!("Rotation:3".parse(), Ok(Effect::Rotation { folds: 3 }));
+        assert!("rotation:1".parse::<Effect>().is_err());
+        assert!("quadrants:2".parse::<Effect>().is_err());
+        assert!("swirl".parse::<Effect>().is_err());
+        for effect in [Effect::MirrorVertical, Effect::Kaleidoscope { segments: 8 }] {
+            assert_eq!(effect.to_string().parse(), Ok(effect));
+        }
+    }
+
+    #[test]
+    fn test_mirror() {
+        let pixmap = Pixmap::new(3, 2).unwrap();
+        let colors = (0..6).map(Color::from).collect::<Vec<_>>();
+        pixmap.set_region(0, 0, 3, 2, &colors).unwrap();
+        let snapshot = pixmap.checkpoint();
+
+        assert_eq!(Effect::None.apply(&snapshot), colors);
+        let color = |i: &[u32]| i.iter().copied().map(Color::from).collect::<Vec<_>>();
+        assert_eq!(
+            Effect::MirrorHorizontal.apply(&snapshot),
+            color(&[0, 1, 0, 3, 4, 3])
+        );
+        assert_eq!(
+            Effect::MirrorVertical.apply(&snapshot),
+            color(&[0, 1, 2, 0, 1, 2])
+        );
+        assert_eq!(Effect::Quadrants.apply(&snapshot), color(&[0, 1, 0, 0, 1, 0]));
+    }
+
+    #[test]
+    fn test_rotational_symmetry() {
+        let (width, height) = (9, 9);
+        for effect in [
+            Effect::Rotation { folds: 4 },
+            Effect::Kaleidoscope { segments: 4 },
+        ] {
+            let indices = effect.source_indices(width, height);
+            // turning the output by a quarter around the center doesn't change it
+            for y in 0..height {
+                for x in 0..width {
+                    let (rx, ry) = (height - 1 - y, x);
+                    assert_eq!(
+                        indices[y * width + x],
+                        indices[ry * width + rx],
+                        "{} at {},{}",
+                        effect,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+}
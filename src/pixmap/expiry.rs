@@ -0,0 +1,120 @@
+use crate::pixmap::Color;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Into how many periods the TTL is divided
+///
+/// Pixels are reset between one TTL and one period after it, so more periods make expiry more precise at the cost of
+/// one bit per pixel for each of them.
+const PERIODS_PER_TTL: u32 = 16;
+
+/// Record of which pixels of a [`Pixmap`](super::Pixmap) have to be reset after they were not written for some time
+///
+/// Time is divided into periods of a fraction of the TTL, each of which has a bucket that marks the pixels that were
+/// written during it as a bitset. The buckets are used as a ring: whenever a period ends, the oldest bucket is
+/// drained and its pixels expire unless they were written again in a newer period.
+/// This keeps writes lock-free since they only need to set a bit in the bucket of the current period.
+#[derive(Debug)]
+pub struct PixelExpiry {
+    ttl: Duration,
+    background: Color,
+    /// One bitset per period with two more than [`PERIODS_PER_TTL`] so that the bucket of the current period is
+    /// always empty when it starts and the drained bucket is exactly one TTL old
+    buckets: Box<[Box<[AtomicU64]>]>,
+    /// The index of the bucket of the current period
+    current: AtomicUsize,
+}
+
+impl PixelExpiry {
+    /// Create a record for a pixmap with the given number of pixels in which no pixel has been written yet
+    pub(crate) fn new(len: usize, ttl: Duration, background: Color) -> Self {
+        let words = len.div_ceil(64);
+        Self {
+            ttl,
+            background,
+            buckets: (0..PERIODS_PER_TTL + 2)
+                .map(|_| (0..words).map(|_| AtomicU64::new(0)).collect())
+                .collect(),
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// How long pixels keep their color after they were last written
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The color to which expired pixels are reset
+    pub fn background(&self) -> Color {
+        self.background
+    }
+
+    /// How often [`Pixmap::expire_pixels`](super::Pixmap::expire_pixels) needs to be called
+    pub fn period(&self) -> Duration {
+        self.ttl / PERIODS_PER_TTL
+    }
+
+    /// Record that the pixel at `index` has just been written
+    #[inline(always)]
+    pub(crate) fn touch(&self, index: usize) {
+        let bucket = &self.buckets[self.current.load(Ordering::Relaxed)];
+        // Safety: the pixmap only passes indices which are valid for its data and the bitsets cover all of them
+        let word = unsafe { bucket.get_unchecked(index / 64) };
+        let mask = 1 << (index % 64);
+        // only loading the word keeps its cache line shared between cores while the bit is already set
+        if word.load(Ordering::Relaxed) & mask == 0 {
+            word.fetch_or(mask, Ordering::Relaxed);
+        }
+    }
+
+    /// End the current period and pass the index of every pixel which was last written one TTL ago to `expire`
+    pub(crate) fn rotate(&self, mut expire: impl FnMut(usize)) {
+        let len = self.buckets.len();
+        let current = (self.current.load(Ordering::Relaxed) + 1) % len;
+        self.current.store(current, Ordering::Relaxed);
+
+        let oldest = (current + 1) % len;
+        for (i, word) in self.buckets[oldest].iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::Relaxed);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let rewritten =
+                    self.buckets.iter().enumerate().any(|(j, bucket)| {
+                        j != oldest && bucket[i].load(Ordering::Relaxed) & (1 << bit) != 0
+                    });
+                if !rewritten {
+                    expire(i * 64 + bit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let expiry = PixelExpiry::new(130, Duration::from_secs(16), Color::default());
+        assert_eq!(expiry.period(), Duration::from_secs(1));
+        expiry.touch(3);
+        expiry.touch(129);
+        let mut expired = Vec::new();
+        for _ in 0..PERIODS_PER_TTL {
+            expiry.rotate(|i| expired.push(i));
+        }
+        assert!(expired.is_empty());
+
+        // pixel 3 is written again shortly before it would have expired
+        expiry.touch(3);
+        expiry.rotate(|i| expired.push(i));
+        assert_eq!(expired, vec![129]);
+
+        for _ in 0..PERIODS_PER_TTL {
+            expiry.rotate(|i| expired.push(i));
+        }
+        assert_eq!(expired, vec![129, 3]);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::pixmap::{CanvasSnapshot, Color};
+use std::sync::{Arc, Mutex};
+
+/// The front and back buffer from which sinks render frames of a pixmap
+///
+/// Readers only ever see the front buffer, which is a complete copy of the canvas taken at a frame boundary.
+/// Presenting a new frame copies the canvas into the back buffer and then swaps both buffers, so readers never look
+/// at a canvas that is being drawn on and writers never wait for a reader to finish.
+/// The previous front buffer becomes the next back buffer once no reader holds it anymore, which avoids allocating
+/// a new buffer for every frame.
+#[derive(Debug, Default)]
+pub(crate) struct FrameBuffers {
+    front: Mutex<Option<CanvasSnapshot>>,
+    /// Held while a frame is copied so that concurrent presenters copy the canvas only once
+    back: Mutex<Option<Arc<[Color]>>>,
+}
+
+impl FrameBuffers {
+    /// Get the current front buffer if it shows the given generation of the canvas
+    fn front(&self, generation: u64) -> Option<CanvasSnapshot> {
+        let front = self.front.lock().unwrap_or_else(|e| e.into_inner());
+        front.as_ref().filter(|i| i.generation() == generation).cloned()
+    }
+
+    /// Copy `data` into the back buffer and swap it to the front unless the front buffer already shows `generation`
+    pub(crate) fn present(
+        &self,
+        width: usize,
+        height: usize,
+        generation: u64,
+        data: &[Color],
+    ) -> CanvasSnapshot {
+        if let Some(frame) = self.front(generation) {
+            return frame;
+        }
+        let mut back = self.back.lock().unwrap_or_else(|e| e.into_inner());
+        // another reader may have presented the same generation while we were waiting
+        if let Some(frame) = self.front(generation) {
+            return frame;
+        }
+
+        let pixels = match back.take().filter(|i| i.len() == data.len()) {
+            Some(mut pixels) => match Arc::get_mut(&mut pixels) {
+                Some(buffer) => {
+                    buffer.copy_from_slice(data);
+                    pixels
+                }
+                // a reader still holds the old frame
+                None => data.into(),
+            },
+            None => data.into(),
+        };
+        let frame = CanvasSnapshot::new(width, height, pixels, generation);
+        let previous = self
+            .front
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(frame.clone());
+        *back = previous.map(CanvasSnapshot::into_pixels);
+        frame
+    }
+}
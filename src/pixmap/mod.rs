@@ -6,10 +6,35 @@ use std::sync::Arc;
 
 pub use color::*;
 
+mod activity;
+mod canvases;
+mod changes;
 mod color;
+mod compose;
+mod effects;
+mod expiry;
+mod frames;
+#[cfg(feature = "server")]
+mod png;
+mod protection;
+mod snapshot;
+mod stats;
 mod storage;
 
-pub use storage::{InvalidCoordinatesError, Pixmap};
+pub use activity::ActivityMap;
+pub use canvases::{Canvases, DEFAULT_CANVAS};
+pub use changes::{ChangeBroadcast, ChangeSubscription, PixelChange, WatchedRegion, DEFAULT_CHANGE_CAPACITY};
+pub use compose::{compose, compose_row, BlendMode, UnknownBlendModeError};
+pub use effects::{gather, Effect, InvalidEffectError};
+pub use expiry::PixelExpiry;
+#[cfg(feature = "server")]
+pub(crate) use png::PNG_SIGNATURE;
+#[cfg(feature = "server")]
+pub use png::{decode_png, encode_png};
+pub use protection::{ProtectedRegion, ProtectedWrites};
+pub use snapshot::CanvasSnapshot;
+pub use stats::ColorStats;
+pub use storage::{InvalidCoordinatesError, InvalidDataShapeError, InvalidSizeError, Pixmap, SHARD_ROWS};
 
 /// A [`Pixmap`] which can be used throughout multiple threads
 ///
@@ -0,0 +1,55 @@
+//! Encoding of pixel data as PNG images
+
+use crate::pixmap::Color;
+
+/// Encode pixel data which is ordered row by row into an RGB PNG image
+pub fn encode_png(width: usize, height: usize, colors: &[Color]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    // canvases are mostly served to live viewers which prefer low latency over small files
+    encoder.set_compression(png::Compression::Fast);
+    let data = colors
+        .iter()
+        .flat_map(|c| Into::<[u8; 3]>::into(*c))
+        .collect::<Vec<_>>();
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(out)
+}
+
+/// The bytes with which every PNG image starts
+pub(crate) const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Decode a PNG image into its size and pixel data which is ordered row by row
+///
+/// Grayscale and indexed images are converted to RGB and any alpha channel is discarded.
+pub fn decode_png(data: &[u8]) -> anyhow::Result<(usize, usize, Vec<Color>)> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let channels = info.color_type.samples();
+    let colors = buf[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|px| match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => Color::from((px[0], px[0], px[0])),
+            _ => Color::from((px[0], px[1], px[2])),
+        })
+        .collect();
+    Ok((info.width as usize, info.height as usize, colors))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_png_roundtrip() {
+        let colors = (0..12).map(|i| Color::from(i * 0x151515)).collect::<Vec<_>>();
+        let data = encode_png(4, 3, &colors).unwrap();
+        assert!(data.starts_with(PNG_SIGNATURE));
+        assert_eq!(decode_png(&data).unwrap(), (4, 3, colors));
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// A rectangle of a pixmap which clients may not draw on
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ProtectedRegion {
+    /// The x coordinate of the rectangles top-left corner
+    pub x: usize,
+    /// The y coordinate of the rectangles top-left corner
+    pub y: usize,
+    /// The width of the rectangle
+    pub width: usize,
+    /// The height of the rectangle
+    pub height: usize,
+}
+
+impl ProtectedRegion {
+    /// Whether the pixel at (x,y) lies inside this region
+    #[inline(always)]
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+/// What happens to client writes into a protected region
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ProtectedWrites {
+    /// The write is silently dropped like it never happened
+    #[default]
+    Drop,
+    /// The write is dropped and the client receives an error
+    Reject,
+}
+
+/// The protected regions of one pixmap
+#[derive(Debug, Default)]
+pub(crate) struct Protection {
+    regions: RwLock<Vec<ProtectedRegion>>,
+    /// Whether `regions` has any entries so that pixel writes can skip locking it when nothing is protected
+    any: AtomicBool,
+    mode: ProtectedWrites,
+}
+
+impl Protection {
+    pub(crate) fn set_mode(&mut self, mode: ProtectedWrites) {
+        self.mode = mode;
+    }
+
+    pub(crate) fn mode(&self) -> ProtectedWrites {
+        self.mode
+    }
+
+    pub(crate) fn add(&self, region: ProtectedRegion) {
+        let mut regions = self.regions.write().unwrap();
+        if !regions.contains(&region) {
+            regions.push(region);
+        }
+        self.any.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn remove(&self, region: &ProtectedRegion) -> bool {
+        let mut regions = self.regions.write().unwrap();
+        let len = regions.len();
+        regions.retain(|i| i != region);
+        self.any.store(!regions.is_empty(), Ordering::Relaxed);
+        regions.len() != len
+    }
+
+    pub(crate) fn regions(&self) -> Vec<ProtectedRegion> {
+        self.regions.read().unwrap().clone()
+    }
+
+    #[inline(always)]
+    pub(crate) fn contains(&self, x: usize, y: usize) -> bool {
+        self.any.load(Ordering::Relaxed) && self.regions.read().unwrap().iter().any(|i| i.contains(x, y))
+    }
+}
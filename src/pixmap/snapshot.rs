@@ -0,0 +1,68 @@
+use crate::pixmap::Color;
+use std::sync::Arc;
+
+/// An immutable copy of a pixmaps content at one point in time
+///
+/// Snapshots are created with [`Pixmap::checkpoint`](crate::pixmap::Pixmap::checkpoint) and put back with
+/// [`Pixmap::restore`](crate::pixmap::Pixmap::restore).
+/// Cloning a snapshot is cheap because all clones share the same pixel data until one of them is modified via
+/// [`CanvasSnapshot::pixels_mut`] which then copies the data first.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CanvasSnapshot {
+    width: usize,
+    height: usize,
+    pixels: Arc<[Color]>,
+    generation: u64,
+}
+
+impl CanvasSnapshot {
+    pub(crate) fn new(width: usize, height: usize, pixels: Arc<[Color]>, generation: u64) -> Self {
+        debug_assert_eq!(pixels.len(), width * height);
+        Self {
+            width,
+            height,
+            pixels,
+            generation,
+        }
+    }
+
+    /// Get the size of the snapshotted canvas as `(width, height)` tuple
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Get the color of the pixel at (x,y) or `None` if the coordinates are outside of the canvas
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Color> {
+        match x < self.width && y < self.height {
+            true => Some(self.pixels[y * self.width + x]),
+            false => None,
+        }
+    }
+
+    /// Get the colors of all pixels row by row
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    /// Get mutable access to the colors of all pixels row by row
+    ///
+    /// The data is copied first if it is shared with other clones of this snapshot.
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        Arc::make_mut(&mut self.pixels)
+    }
+
+    /// The [generation](crate::pixmap::Pixmap::generation) of the pixmap at the time the snapshot was taken
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Take the pixel data out of the snapshot
+    pub(crate) fn into_pixels(self) -> Arc<[Color]> {
+        self.pixels
+    }
+
+    /// Whether this snapshot shares its pixel data with `other` because neither was modified since they were taken
+    pub fn shares_data_with(&self, other: &CanvasSnapshot) -> bool {
+        Arc::ptr_eq(&self.pixels, &other.pixels)
+    }
+}
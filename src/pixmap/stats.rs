@@ -0,0 +1,117 @@
+//! Statistics about the colors that are present on a pixmap
+
+use crate::pixmap::{Color, Pixmap};
+
+/// How many bits of each color channel are used to determine a colors histogram bucket
+const BUCKET_BITS: u32 = 4;
+const BUCKET_COUNT: usize = 1 << (3 * BUCKET_BITS);
+
+/// A summary of the colors present on a pixmap
+///
+/// Colors are quantized into buckets of similar colors so that the histogram stays small and that slightly
+/// different shades still count towards the same dominant color.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ColorStats {
+    /// How many pixels fall into each color bucket
+    buckets: Vec<usize>,
+    /// How many pixels were analyzed in total
+    pixel_count: usize,
+    /// The average color of all analyzed pixels
+    average: Color,
+}
+
+impl ColorStats {
+    /// Compute color statistics of the whole pixmap
+    pub fn compute(pixmap: &Pixmap) -> Self {
+        Self::from_colors(unsafe { pixmap.get_color_data() })
+    }
+
+    /// Compute color statistics from a list of colors
+    pub fn from_colors(colors: &[Color]) -> Self {
+        let mut buckets = vec![0usize; BUCKET_COUNT];
+        let mut sums = [0u64; 3];
+        for color in colors {
+            let (r, g, b): (u8, u8, u8) = (*color).into();
+            buckets[bucket_index(r, g, b)] += 1;
+            sums[0] += r as u64;
+            sums[1] += g as u64;
+            sums[2] += b as u64;
+        }
+
+        let n = (colors.len() as u64).max(1);
+        Self {
+            buckets,
+            pixel_count: colors.len(),
+            average: Color::from(((sums[0] / n) as u8, (sums[1] / n) as u8, (sums[2] / n) as u8)),
+        }
+    }
+
+    /// How many pixels were analyzed
+    pub fn pixel_count(&self) -> usize {
+        self.pixel_count
+    }
+
+    /// The average color of all pixels
+    pub fn average(&self) -> Color {
+        self.average
+    }
+
+    /// Get up to `n` of the most common colors together with the fraction of pixels which have that color
+    ///
+    /// The returned colors are representatives of their color bucket and are ordered from most to least common.
+    pub fn dominant_colors(&self, n: usize) -> Vec<(Color, f32)> {
+        let mut buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .collect::<Vec<_>>();
+        buckets.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+        buckets
+            .into_iter()
+            .take(n)
+            .map(|(i, &count)| (bucket_color(i), count as f32 / self.pixel_count as f32))
+            .collect()
+    }
+}
+
+/// Calculate the histogram bucket into which a color falls
+#[inline(always)]
+fn bucket_index(r: u8, g: u8, b: u8) -> usize {
+    const SHIFT: u32 = 8 - BUCKET_BITS;
+    ((r as usize >> SHIFT) << (2 * BUCKET_BITS))
+        | ((g as usize >> SHIFT) << BUCKET_BITS)
+        | (b as usize >> SHIFT)
+}
+
+/// Calculate the color in the center of a histogram bucket
+fn bucket_color(i: usize) -> Color {
+    const SHIFT: u32 = 8 - BUCKET_BITS;
+    const MASK: usize = (1 << BUCKET_BITS) - 1;
+    const HALF: u8 = 1 << (SHIFT - 1);
+    let channel = |v: usize| ((v & MASK) as u8) << SHIFT | HALF;
+    Color::from((
+        channel(i >> (2 * BUCKET_BITS)),
+        channel(i >> BUCKET_BITS),
+        channel(i),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dominant_colors() {
+        let mut colors = vec![Color::from(0xFF0000); 6];
+        colors.extend([Color::from(0x0000FF); 3]);
+        colors.push(Color::from(0x00FF00));
+        let stats = ColorStats::from_colors(&colors);
+
+        let dominant = stats.dominant_colors(2);
+        assert_eq!(dominant.len(), 2);
+        assert_eq!(dominant[0], (Color::from(0xF80808), 0.6));
+        assert_eq!(dominant[1], (Color::from(0x0808F8), 0.3));
+        assert_eq!(stats.average(), Color::from((153, 25, 76)));
+    }
+}
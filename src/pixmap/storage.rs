@@ -1,13 +1,94 @@
-use crate::pixmap::Color;
+use crate::pixmap::frames::FrameBuffers;
+use crate::pixmap::protection::Protection;
+use crate::pixmap::{
+    compose_row, ActivityMap, BlendMode, CanvasSnapshot, ChangeBroadcast, Color, PixelChange, PixelExpiry,
+    ProtectedRegion, ProtectedWrites,
+};
 use std::cell::SyncUnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::Notify;
 
 /// A fast pixel storage implementation
+///
+/// The canvas is split into bands of [`SHARD_ROWS`] rows which each have a lock of their own. Writes hold the lock
+/// of the band they change so that e.g. concurrent blends of the same pixel are never lost, while connections which
+/// are handled on different worker threads only wait for each other if they draw into the same band at the same time.
+/// Reads don't lock at all and may observe writes which are still in progress.
 #[derive(Debug)]
 pub struct Pixmap {
     data: SyncUnsafeCell<Vec<Color>>,
     width: usize,
     height: usize,
+    layout: Layout,
+    /// The write locks of all bands of rows from top to bottom
+    shards: Box<[Shard]>,
+    /// When each pixel was last written, if that is being tracked
+    activity: Option<ActivityMap>,
+    /// Which pixels are reset after they were not written for a while, if that is enabled
+    expiry: Option<PixelExpiry>,
+    /// Where writes are announced to subscribers, if that is enabled
+    changes: Option<ChangeBroadcast>,
+    /// Whether pixels were written since [`Pixmap::generation`] was last called
+    written: AtomicBool,
+    /// How often [`Pixmap::generation`] observed that pixels were written
+    generation: AtomicU64,
+    /// The most recent [`Pixmap::checkpoint`] which is handed out again while the canvas does not change
+    last_checkpoint: Mutex<Option<CanvasSnapshot>>,
+    /// The buffers from which [`Pixmap::present_frame`] hands out frames
+    frames: FrameBuffers,
+    /// The regions which clients may not draw on
+    protection: Protection,
+    /// Whether clients may currently not draw on the canvas at all
+    frozen: AtomicBool,
+    /// Whether the pixmap was replaced, e.g. by a resized copy
+    retired: AtomicBool,
+    /// Wakes everyone who waits in [`Pixmap::retired`]
+    retirement: Notify,
+}
+
+/// How many rows of the canvas share one write lock
+pub const SHARD_ROWS: usize = 8;
+
+/// The write lock of a band of rows
+///
+/// Every lock gets a cache line of its own so that cores which write into neighbouring bands don't contend for it.
+#[derive(Debug, Default)]
+#[repr(align(64))]
+struct Shard(Mutex<()>);
+
+/// How pixel indices are calculated for a pixmap
+///
+/// Common sizes are known at compile time so that the index calculation and bounds checks for them are done with
+/// constants which the compiler can optimize much better than the generic calculation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Layout {
+    Dynamic,
+    Fixed1280x720,
+    Fixed1920x1080,
+    Fixed3840x2160,
+}
+
+impl Layout {
+    fn for_size(width: usize, height: usize) -> Self {
+        match (width, height) {
+            (1280, 720) => Layout::Fixed1280x720,
+            (1920, 1080) => Layout::Fixed1920x1080,
+            (3840, 2160) => Layout::Fixed3840x2160,
+            _ => Layout::Dynamic,
+        }
+    }
+}
+
+/// Calculate the index of the pixel at position (x,y) in a pixmap whose size is known at compile time
+#[inline(always)]
+fn fixed_pixel_index<const WIDTH: usize, const HEIGHT: usize>(x: usize, y: usize) -> Option<usize> {
+    match x < WIDTH && y < HEIGHT {
+        true => Some(y * WIDTH + x),
+        false => None,
+    }
 }
 
 /// An error which indicates that invalid coordinates could not be accessed
@@ -26,6 +107,7 @@ pub struct InvalidSizeError {
     details: &'static str,
 }
 
+/// An error which indicates that some data cannot be put into a pixmap because it has the wrong size
 #[derive(Debug, Error, Copy, Clone)]
 #[error("Cannot put data with size {data_len} into pixmap of dimensions {}x{} (expected data size = {}) ", .pixmap_size.0, .pixmap_size.1, .pixmap_size.0 * .pixmap_size.1)]
 pub struct InvalidDataShapeError {
@@ -47,41 +129,625 @@ impl Pixmap {
             data: SyncUnsafeCell::new(vec![Color::default(); width * height]),
             width,
             height,
+            layout: Layout::for_size(width, height),
+            shards: (0..height.div_ceil(SHARD_ROWS))
+                .map(|_| Shard::default())
+                .collect(),
+            activity: None,
+            expiry: None,
+            changes: None,
+            written: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            last_checkpoint: Mutex::new(None),
+            frames: FrameBuffers::default(),
+            protection: Protection::default(),
+            frozen: AtomicBool::new(false),
+            retired: AtomicBool::new(false),
+            retirement: Notify::new(),
         })
     }
 
+    /// Create a copy of this pixmap with a different size which keeps the existing content
+    ///
+    /// The content stays anchored at the top-left corner, so it is cropped when the pixmap shrinks and padded with
+    /// black pixels when it grows. Protected regions are cropped likewise and dropped once they lie completely
+    /// outside. Activity tracking and the change broadcast are carried over but start out empty.
+    /// If pixels expire, the kept content counts as just written so that it expires one TTL after the resize.
+    ///
+    /// The size of a pixmap never changes so that pixels can be accessed without any synchronization. Everyone who
+    /// uses this pixmap has to switch over to the copy instead, which they can be told about via [`Pixmap::retire`].
+    pub fn resize(&self, width: usize, height: usize) -> Result<Pixmap, InvalidSizeError> {
+        let mut resized = Pixmap::new(width, height)?;
+        let (copy_width, copy_height) = (width.min(self.width), height.min(self.height));
+        let source = unsafe { self.get_color_data() };
+        let target = resized.data.get_mut();
+        for y in 0..copy_height {
+            target[y * width..y * width + copy_width]
+                .copy_from_slice(&source[y * self.width..y * self.width + copy_width]);
+        }
+
+        if self.activity.is_some() {
+            resized = resized.with_activity_tracking();
+        }
+        if let Some(changes) = &self.changes {
+            resized = resized.with_change_broadcast(changes.capacity(), Duration::ZERO);
+        }
+        if let Some(expiry) = &self.expiry {
+            resized = resized.with_pixel_ttl(expiry.ttl(), expiry.background());
+            let expiry = resized.expiry.as_ref().unwrap();
+            for y in 0..copy_height {
+                (y * width..y * width + copy_width).for_each(|i| expiry.touch(i));
+            }
+        }
+        resized.protection.set_mode(self.protection.mode());
+        for region in self.protection.regions() {
+            if region.x < width && region.y < height {
+                resized.protection.add(ProtectedRegion {
+                    width: region.width.min(width - region.x),
+                    height: region.height.min(height - region.y),
+                    ..region
+                });
+            }
+        }
+        resized.set_frozen(self.is_frozen());
+        Ok(resized)
+    }
+
+    /// Mark the pixmap as replaced, e.g. by a [resized](Pixmap::resize) copy
+    ///
+    /// Servers disconnect clients which still use a retired pixmap so that they reconnect to its replacement.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+        self.retirement.notify_waiters();
+    }
+
+    /// Whether the pixmap has been [retired](Pixmap::retire)
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Wait until the pixmap is [retired](Pixmap::retire)
+    ///
+    /// Waiting registers with the pixmap once, so long-running loops should create the future once and poll it
+    /// repeatedly.
+    pub async fn retired(&self) {
+        let notified = self.retirement.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.is_retired() {
+            notified.await;
+        }
+    }
+
+    /// Additionally keep track of when each pixel was last written
+    ///
+    /// This makes writing pixels slightly more expensive.
+    pub fn with_activity_tracking(mut self) -> Self {
+        self.activity = Some(ActivityMap::new(self.width * self.height));
+        self
+    }
+
+    /// Reset every pixel to `background` once it was not written for `ttl` so that the canvas cleans itself up
+    ///
+    /// Pixels are reset by [`Pixmap::expire_pixels`] which has to be called periodically, see
+    /// [`PixelExpiry::period`]. Protected regions are never reset.
+    /// This makes writing pixels slightly more expensive.
+    pub fn with_pixel_ttl(mut self, ttl: Duration, background: Color) -> Self {
+        self.expiry = Some(PixelExpiry::new(self.width * self.height, ttl, background));
+        self
+    }
+
+    /// Get the record of which pixels expire if a [TTL](Pixmap::with_pixel_ttl) is configured
+    pub fn expiry(&self) -> Option<&PixelExpiry> {
+        self.expiry.as_ref()
+    }
+
+    /// Reset all pixels which were not written for the configured [TTL](Pixmap::with_pixel_ttl) to the background
+    ///
+    /// This has to be called once per [`PixelExpiry::period`] and returns how many pixels were reset.
+    /// Resetting a pixel is announced to subscribers but does not count as a write for activity tracking.
+    pub fn expire_pixels(&self) -> usize {
+        let Some(expiry) = &self.expiry else {
+            return 0;
+        };
+        let data = unsafe { self.get_color_data() };
+        let background = expiry.background();
+        let mut count = 0;
+        expiry.rotate(|index| {
+            if self.is_protected(index % self.width, index / self.width) {
+                return;
+            }
+            let _shard = self.lock_shard(index / self.width);
+            data[index] = background;
+            self.publish(index, background);
+            count += 1;
+        });
+        if count > 0 {
+            self.written.store(true, Ordering::Release);
+        }
+        count
+    }
+
+    /// Additionally announce every write so that clients can subscribe to changes
+    ///
+    /// Up to `capacity` changes are buffered for each subscriber.
+    /// Keyframes of subscriptions which are made during the next `warm_up` are spread over that period.
+    /// Writing pixels only gets more expensive while somebody is subscribed.
+    pub fn with_change_broadcast(mut self, capacity: usize, warm_up: Duration) -> Self {
+        self.changes = Some(ChangeBroadcast::new(capacity, warm_up));
+        self
+    }
+
+    /// Choose what happens to client writes into [protected regions](Pixmap::protect)
+    pub fn with_protected_writes(mut self, mode: ProtectedWrites) -> Self {
+        self.protection.set_mode(mode);
+        self
+    }
+
+    /// Mark a rectangular region as read-only for clients, e.g. to keep a logo or information banner intact
+    ///
+    /// Servers drop client writes into protected regions as configured via [`Pixmap::with_protected_writes`].
+    /// Writes which are done through the methods of the pixmap itself are not affected so that operators can still
+    /// update protected content.
+    pub fn protect(&self, region: ProtectedRegion) -> Result<(), InvalidCoordinatesError> {
+        let x_max = region
+            .x
+            .checked_add(region.width)
+            .filter(|&x_max| x_max <= self.width);
+        let y_max = region
+            .y
+            .checked_add(region.height)
+            .filter(|&y_max| y_max <= self.height);
+        if x_max.is_none() || y_max.is_none() {
+            return Err(InvalidCoordinatesError {
+                target: (
+                    region.x.saturating_add(region.width),
+                    region.y.saturating_add(region.height),
+                ),
+                pixmap_size: self.get_size(),
+            });
+        }
+        self.protection.add(region);
+        Ok(())
+    }
+
+    /// Allow clients to draw on a previously protected region again
+    ///
+    /// Returns whether the region was protected.
+    pub fn unprotect(&self, region: &ProtectedRegion) -> bool {
+        self.protection.remove(region)
+    }
+
+    /// All regions which are currently protected
+    pub fn protected_regions(&self) -> Vec<ProtectedRegion> {
+        self.protection.regions()
+    }
+
+    /// Whether the pixel at (x,y) lies inside a protected region
+    #[inline(always)]
+    pub fn is_protected(&self, x: usize, y: usize) -> bool {
+        self.protection.contains(x, y)
+    }
+
+    /// What happens to client writes into protected regions
+    pub fn protected_writes(&self) -> ProtectedWrites {
+        self.protection.mode()
+    }
+
+    /// Freeze or unfreeze the canvas
+    ///
+    /// While the canvas is frozen, servers reject all writes of their clients while reads and sinks keep working,
+    /// e.g. so that the artworks stay untouched during an award ceremony.
+    pub fn set_frozen(&self, frozen: bool) {
+        self.frozen.store(frozen, Ordering::Relaxed);
+    }
+
+    /// Whether the canvas is currently frozen
+    #[inline(always)]
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    /// Get the broadcast of pixel changes if it is enabled
+    pub fn changes(&self) -> Option<&ChangeBroadcast> {
+        self.changes.as_ref()
+    }
+
+    /// Announce that the pixel at `index` was set to `color`
+    #[inline(always)]
+    fn publish(&self, index: usize, color: Color) {
+        if let Some(changes) = &self.changes {
+            changes.publish(PixelChange::Pixel {
+                x: index % self.width,
+                y: index / self.width,
+                color,
+            });
+        }
+    }
+
+    /// Get the record of when each pixel was last written if activity tracking is enabled
+    pub fn activity(&self) -> Option<&ActivityMap> {
+        self.activity.as_ref()
+    }
+
+    /// Record a write to the pixel at `index` in the activity map and for the next [`Pixmap::generation`]
+    #[inline(always)]
+    fn touch(&self, index: usize) {
+        if let Some(activity) = &self.activity {
+            activity.touch(index);
+        }
+        if let Some(expiry) = &self.expiry {
+            expiry.touch(index);
+        }
+        // only loading the flag keeps its cache line shared between cores while it is already set
+        if !self.written.load(Ordering::Relaxed) {
+            self.written.store(true, Ordering::Release);
+        }
+    }
+
+    /// Get a number which changes whenever pixels were written since it was last queried
+    ///
+    /// This allows caching data which is derived from the canvas content, e.g. encoded images, for as long as the
+    /// returned generation stays the same.
+    pub fn generation(&self) -> u64 {
+        match self.written.swap(false, Ordering::Acquire) {
+            true => self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+            false => self.generation.load(Ordering::Relaxed),
+        }
+    }
+
     /// Get the size of this pixmap as `(width, height)` tuple
     pub fn get_size(&self) -> (usize, usize) {
         (self.width, self.height)
     }
 
+    /// Lock the band of rows which contains row `y` for writing
+    #[inline(always)]
+    fn lock_shard(&self, y: usize) -> MutexGuard<'_, ()> {
+        self.shards[y / SHARD_ROWS]
+            .0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock all bands which contain the rows from `y_min` to `y_max` (inclusive) for writing
+    ///
+    /// Whoever holds more than one lock takes them from top to bottom, so this cannot deadlock.
+    fn lock_shards(&self, y_min: usize, y_max: usize) -> Vec<MutexGuard<'_, ()>> {
+        (y_min / SHARD_ROWS..=y_max / SHARD_ROWS)
+            .map(|i| self.lock_shard(i * SHARD_ROWS))
+            .collect()
+    }
+
+    /// Calculate the index of the pixel at position (x,y) in the underlying data or `None` if it is out of bounds
+    ///
+    /// A returned index is always valid for the underlying data since it holds exactly `width * height` pixels.
+    #[inline(always)]
+    fn pixel_index(&self, x: usize, y: usize) -> Option<usize> {
+        match self.layout {
+            Layout::Fixed1280x720 => fixed_pixel_index::<1280, 720>(x, y),
+            Layout::Fixed1920x1080 => fixed_pixel_index::<1920, 1080>(x, y),
+            Layout::Fixed3840x2160 => fixed_pixel_index::<3840, 2160>(x, y),
+            Layout::Dynamic => match x < self.width && y < self.height {
+                true => Some(y * self.width + x),
+                false => None,
+            },
+        }
+    }
+
     /// Get the color value of the pixel at position (x,y)
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<Color, InvalidCoordinatesError> {
-        let i = y.saturating_mul(self.width).saturating_add(x);
-        match unsafe { self.get_color_data() }.get(i) {
+        match self.pixel_index(x, y) {
             None => Err(InvalidCoordinatesError {
                 target: (x, y),
                 pixmap_size: self.get_size(),
             }),
-            Some(color) => Ok(*color),
+            // Safety: pixel_index() only returns indices inside the data
+            Some(i) => Ok(*unsafe { self.get_color_data().get_unchecked(i) }),
         }
     }
 
     /// Set the pixel value at position (x,y) to the specified color
     pub fn set_pixel(&self, x: usize, y: usize, color: Color) -> Result<(), InvalidCoordinatesError> {
-        let i = y.saturating_mul(self.width).saturating_add(x);
-        match unsafe { self.get_color_data() }.get_mut(i) {
+        match self.pixel_index(x, y) {
+            None => Err(InvalidCoordinatesError {
+                target: (x, y),
+                pixmap_size: self.get_size(),
+            }),
+            Some(i) => {
+                let _shard = self.lock_shard(y);
+                // Safety: pixel_index() only returns indices inside the data
+                *unsafe { self.get_color_data().get_unchecked_mut(i) } = color;
+                self.touch(i);
+                self.publish(i, color);
+                Ok(())
+            }
+        }
+    }
+
+    /// Blend `color` with the given opacity onto the pixel at position (x,y)
+    ///
+    /// The pixel is read and written while holding the lock of its band, so concurrent blends of the same pixel all
+    /// take effect.
+    pub fn blend_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        color: Color,
+        alpha: u8,
+    ) -> Result<(), InvalidCoordinatesError> {
+        match self.pixel_index(x, y) {
             None => Err(InvalidCoordinatesError {
                 target: (x, y),
                 pixmap_size: self.get_size(),
             }),
-            Some(stored_color) => {
-                *stored_color = color;
+            Some(i) => {
+                let _shard = self.lock_shard(y);
+                // Safety: pixel_index() only returns indices inside the data
+                let pixel = unsafe { self.get_color_data().get_unchecked_mut(i) };
+                *pixel = pixel.blend(color, alpha);
+                self.touch(i);
+                self.publish(i, *pixel);
                 Ok(())
             }
         }
     }
 
+    /// Set multiple pixels at once
+    ///
+    /// All coordinates are validated before any pixel is written so that either all pixels are set or, if any of them
+    /// lies outside the pixmap, none are.
+    /// The locks of all affected bands are taken once for the whole batch and subscribers are told about a single
+    /// region change which covers all pixels, so batches of nearby pixels are much cheaper than scattered ones.
+    pub fn set_pixels(&self, pixels: &[(usize, usize, Color)]) -> Result<(), InvalidCoordinatesError> {
+        if let Some(&(x, y, _)) = pixels.iter().find(|(x, y, _)| self.pixel_index(*x, *y).is_none()) {
+            return Err(InvalidCoordinatesError {
+                target: (x, y),
+                pixmap_size: self.get_size(),
+            });
+        }
+        let Some((x_min, x_max, y_min, y_max)) =
+            pixels.iter().fold(None, |bounds, &(x, y, _)| match bounds {
+                None => Some((x, x, y, y)),
+                Some((x_min, x_max, y_min, y_max)) => {
+                    Some((x_min.min(x), x_max.max(x), y_min.min(y), y_max.max(y)))
+                }
+            })
+        else {
+            return Ok(());
+        };
+
+        let shards = self.lock_shards(y_min, y_max);
+        let data = unsafe { self.get_color_data() };
+        for &(x, y, color) in pixels {
+            // Safety: all coordinates have been validated above
+            unsafe {
+                let i = self.pixel_index(x, y).unwrap_unchecked();
+                *data.get_unchecked_mut(i) = color;
+                self.touch(i);
+            }
+        }
+        drop(shards);
+        if let Some(changes) = &self.changes {
+            changes.publish(PixelChange::Region {
+                x: x_min,
+                y: y_min,
+                width: x_max - x_min + 1,
+                height: y_max - y_min + 1,
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the color values of all pixels in the rectangular region starting at (x,y)
+    ///
+    /// The returned colors are ordered row by row.
+    pub fn get_region(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<Color>, InvalidCoordinatesError> {
+        let mut colors = Vec::new();
+        self.read_region_into(x, y, width, height, &mut colors)?;
+        Ok(colors)
+    }
+
+    /// Append the color values of all pixels in the rectangular region starting at (x,y) to `buf`
+    ///
+    /// The colors are ordered row by row and every row is copied at once, so this is much faster than reading
+    /// the pixels individually.
+    /// Readers which repeatedly download regions can reuse `buf` to avoid allocating.
+    /// Nothing is appended if the region is not inside the pixmap.
+    pub fn read_region_into(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        buf: &mut Vec<Color>,
+    ) -> Result<(), InvalidCoordinatesError> {
+        let x_max = x.checked_add(width).filter(|&x_max| x_max <= self.width);
+        let y_max = y.checked_add(height).filter(|&y_max| y_max <= self.height);
+        let (Some(x_max), Some(y_max)) = (x_max, y_max) else {
+            return Err(InvalidCoordinatesError {
+                target: (x.saturating_add(width), y.saturating_add(height)),
+                pixmap_size: self.get_size(),
+            });
+        };
+
+        let data = unsafe { self.get_color_data() };
+        buf.reserve(width * height);
+        for y in y..y_max {
+            buf.extend_from_slice(&data[y * self.width + x..y * self.width + x_max]);
+        }
+        Ok(())
+    }
+
+    /// Overwrite all pixels in the rectangular region starting at (x,y) with the given colors
+    ///
+    /// The colors must be ordered row by row and contain exactly `width * height` entries.
+    pub fn set_region(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        colors: &[Color],
+    ) -> anyhow::Result<()> {
+        if colors.len() != width * height {
+            return Err(InvalidDataShapeError {
+                pixmap_size: (width, height),
+                data_len: colors.len(),
+            }
+            .into());
+        }
+        let x_max = x.checked_add(width).filter(|&x_max| x_max <= self.width);
+        let y_max = y.checked_add(height).filter(|&y_max| y_max <= self.height);
+        if x_max.is_none() || y_max.is_none() {
+            return Err(InvalidCoordinatesError {
+                target: (x.saturating_add(width), y.saturating_add(height)),
+                pixmap_size: self.get_size(),
+            }
+            .into());
+        }
+
+        let data = unsafe { self.get_color_data() };
+        for (i, row) in colors.chunks_exact(width.max(1)).enumerate() {
+            let start = (y + i) * self.width + x;
+            let _shard = self.lock_shard(y + i);
+            data[start..start + width].copy_from_slice(row);
+            (start..start + width).for_each(|i| self.touch(i));
+        }
+        if let Some(changes) = &self.changes {
+            changes.publish(PixelChange::Region { x, y, width, height });
+        }
+        Ok(())
+    }
+
+    /// Blend a layer of colors onto the rectangular region starting at (x,y)
+    ///
+    /// The colors must be ordered row by row and contain exactly `width * height` entries.
+    /// `alpha` is the opacity of the whole layer from 0 (invisible) to 255 (opaque).
+    #[allow(clippy::too_many_arguments)]
+    pub fn compose_region(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        layer: &[Color],
+        mode: BlendMode,
+        alpha: u8,
+    ) -> anyhow::Result<()> {
+        if layer.len() != width * height {
+            return Err(InvalidDataShapeError {
+                pixmap_size: (width, height),
+                data_len: layer.len(),
+            }
+            .into());
+        }
+        let x_max = x.checked_add(width).filter(|&x_max| x_max <= self.width);
+        let y_max = y.checked_add(height).filter(|&y_max| y_max <= self.height);
+        if x_max.is_none() || y_max.is_none() {
+            return Err(InvalidCoordinatesError {
+                target: (x.saturating_add(width), y.saturating_add(height)),
+                pixmap_size: self.get_size(),
+            }
+            .into());
+        }
+
+        let data = unsafe { self.get_color_data() };
+        for (i, row) in layer.chunks_exact(width.max(1)).enumerate() {
+            let start = (y + i) * self.width + x;
+            let _shard = self.lock_shard(y + i);
+            compose_row(mode, &mut data[start..start + width], row, alpha);
+            (start..start + width).for_each(|i| self.touch(i));
+        }
+        if let Some(changes) = &self.changes {
+            changes.publish(PixelChange::Region { x, y, width, height });
+        }
+        Ok(())
+    }
+
+    /// Take a snapshot of the current content of the pixmap
+    ///
+    /// Taking another checkpoint while no pixels were written in between returns a snapshot which shares the data
+    /// of the previous one instead of copying the canvas again.
+    /// Pixels which are written while the snapshot is taken may or may not be part of it.
+    pub fn checkpoint(&self) -> CanvasSnapshot {
+        // the generation is determined before copying so that writes during the copy lead to a new one
+        let generation = self.generation();
+        let mut last_checkpoint = self.last_checkpoint.lock().unwrap_or_else(|e| e.into_inner());
+        match &*last_checkpoint {
+            Some(snapshot) if snapshot.generation() == generation => snapshot.clone(),
+            _ => {
+                let pixels = unsafe { self.get_color_data() }.into();
+                let snapshot = CanvasSnapshot::new(self.width, self.height, pixels, generation);
+                *last_checkpoint = Some(snapshot.clone());
+                snapshot
+            }
+        }
+    }
+
+    /// Get the frame which sinks should render, presenting a new one if pixels were written since the last one
+    ///
+    /// Frames are double-buffered: the canvas is copied into a back buffer which is then swapped atomically with the
+    /// frame that was handed out before.
+    /// Renderers should call this once per frame and render everything from the returned frame so that it shows the
+    /// canvas of a single point in time even if rendering takes a while.
+    /// While no pixels are written, all renderers share the same frame without copying the canvas again.
+    pub fn present_frame(&self) -> CanvasSnapshot {
+        let generation = self.generation();
+        self.frames.present(self.width, self.height, generation, unsafe {
+            self.get_color_data()
+        })
+    }
+
+    /// Overwrite the whole canvas with the content of a snapshot
+    ///
+    /// The snapshot must have the same size as this pixmap.
+    /// Restoring counts as a write of every pixel so subscribers and derived data are updated accordingly.
+    pub fn restore(&self, snapshot: &CanvasSnapshot) -> Result<(), InvalidDataShapeError> {
+        if snapshot.get_size() != self.get_size() {
+            let (width, height) = snapshot.get_size();
+            return Err(InvalidDataShapeError {
+                pixmap_size: self.get_size(),
+                data_len: width * height,
+            });
+        }
+        let shards = self.lock_shards(0, self.height - 1);
+        let data = unsafe { self.get_color_data() };
+        data.copy_from_slice(snapshot.pixels());
+        drop(shards);
+        self.touch_all();
+        Ok(())
+    }
+
+    /// Set every pixel of the pixmap to the given color
+    ///
+    /// This writes the whole canvas at once which is much faster than setting each pixel individually.
+    pub fn fill(&self, color: Color) {
+        let shards = self.lock_shards(0, self.height - 1);
+        unsafe { self.get_color_data() }.fill(color);
+        drop(shards);
+        self.touch_all();
+    }
+
+    /// Record and announce a write of every pixel
+    fn touch_all(&self) {
+        (0..self.width * self.height).for_each(|i| self.touch(i));
+        if let Some(changes) = &self.changes {
+            changes.publish(PixelChange::Region {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            });
+        }
+    }
+
     /// Get a (usable) handle to the raw data that is contained in the pixmap
     ///
     /// # Safety
@@ -103,7 +769,10 @@ impl Pixmap {
 #[cfg(test)]
 mod test {
     use super::*;
+    use ::test::Bencher;
     use quickcheck::{quickcheck, TestResult};
+    use std::hint::black_box;
+    use std::sync::Arc;
 
     quickcheck! {
         fn test_set_and_get_pixel(x: usize, y: usize) -> TestResult {
@@ -118,4 +787,332 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_set_pixels() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        pixmap
+            .set_pixels(&[(0, 0, Color::from(0x111111)), (3, 3, Color::from(0x222222))])
+            .unwrap();
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), Color::from(0x111111));
+        assert_eq!(pixmap.get_pixel(3, 3).unwrap(), Color::from(0x222222));
+
+        // nothing is written if one of the pixels is out of bounds
+        assert!(pixmap
+            .set_pixels(&[(1, 1, Color::from(0x333333)), (4, 0, Color::from(0x333333))])
+            .is_err());
+        assert_eq!(pixmap.get_pixel(1, 1).unwrap(), Color::default());
+        assert!(pixmap.set_pixels(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_set_pixels_publishes_one_change() {
+        let pixmap = Pixmap::new(4, 4)
+            .unwrap()
+            .with_change_broadcast(16, Duration::ZERO);
+        let mut subscription = pixmap.changes().unwrap().subscribe();
+        pixmap
+            .set_pixels(&[
+                (2, 1, Color::from(0x111111)),
+                (1, 3, Color::from(0x222222)),
+                (3, 2, Color::from(0x333333)),
+            ])
+            .unwrap();
+        assert_eq!(
+            subscription.try_recv().unwrap(),
+            PixelChange::Region {
+                x: 1,
+                y: 1,
+                width: 3,
+                height: 3
+            }
+        );
+        assert!(subscription.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_resize() {
+        let pixmap = Pixmap::new(4, 3)
+            .unwrap()
+            .with_change_broadcast(16, Duration::ZERO);
+        pixmap.set_pixel(1, 1, Color::from(0x111111)).unwrap();
+        pixmap.set_pixel(3, 2, Color::from(0x222222)).unwrap();
+        pixmap
+            .protect(ProtectedRegion {
+                x: 1,
+                y: 0,
+                width: 3,
+                height: 3,
+            })
+            .unwrap();
+
+        let grown = pixmap.resize(6, 5).unwrap();
+        assert_eq!(grown.get_size(), (6, 5));
+        assert_eq!(grown.get_pixel(1, 1).unwrap(), Color::from(0x111111));
+        assert_eq!(grown.get_pixel(3, 2).unwrap(), Color::from(0x222222));
+        assert_eq!(grown.get_pixel(5, 4).unwrap(), Color::default());
+        assert_eq!(grown.protected_regions(), pixmap.protected_regions());
+        assert!(grown.changes().is_some());
+
+        let shrunk = pixmap.resize(2, 2).unwrap();
+        assert_eq!(shrunk.get_pixel(1, 1).unwrap(), Color::from(0x111111));
+        assert_eq!(
+            shrunk.protected_regions(),
+            vec![ProtectedRegion {
+                x: 1,
+                y: 0,
+                width: 1,
+                height: 2,
+            }]
+        );
+        assert!(pixmap.resize(0, 2).is_err());
+    }
+
+    #[test]
+    fn test_expire_pixels() {
+        let background = Color::from(0x0000ff);
+        let pixmap = Pixmap::new(4, 1)
+            .unwrap()
+            .with_pixel_ttl(Duration::from_secs(16), background);
+        let period = pixmap.expiry().unwrap().period();
+        assert_eq!(period, Duration::from_secs(1));
+        pixmap.set_pixel(0, 0, Color::from(0x111111)).unwrap();
+        pixmap.set_pixel(3, 0, Color::from(0x222222)).unwrap();
+        pixmap
+            .protect(ProtectedRegion {
+                x: 3,
+                y: 0,
+                width: 1,
+                height: 1,
+            })
+            .unwrap();
+
+        let generation = pixmap.generation();
+        assert_eq!((0..16).map(|_| pixmap.expire_pixels()).sum::<usize>(), 0);
+        assert_eq!(pixmap.expire_pixels(), 1);
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), background);
+        assert_ne!(pixmap.generation(), generation);
+        // protected pixels keep their color
+        assert_eq!(pixmap.get_pixel(3, 0).unwrap(), Color::from(0x222222));
+        assert_eq!(Pixmap::new(1, 1).unwrap().expire_pixels(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retire() {
+        let pixmap = Arc::new(Pixmap::new(2, 2).unwrap());
+        let retired = tokio::spawn({
+            let pixmap = pixmap.clone();
+            async move { pixmap.retired().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!pixmap.is_retired());
+        pixmap.retire();
+        retired.await.unwrap();
+        // waiting for a pixmap which is already retired returns immediately
+        pixmap.retired().await;
+    }
+
+    #[test]
+    fn test_generation() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        let initial = pixmap.generation();
+        assert_eq!(pixmap.generation(), initial);
+        pixmap.set_pixel(1, 1, Color::from(0xFF0000)).unwrap();
+        let written = pixmap.generation();
+        assert_ne!(written, initial);
+        assert_eq!(pixmap.generation(), written);
+        pixmap.set_region(0, 0, 2, 1, &[Color::from(0); 2]).unwrap();
+        assert_ne!(pixmap.generation(), written);
+    }
+
+    #[test]
+    fn test_protect() {
+        let pixmap = Pixmap::new(10, 10).unwrap();
+        let region = ProtectedRegion {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 2,
+        };
+        assert!(!pixmap.is_protected(2, 3));
+        pixmap.protect(region).unwrap();
+        assert!(pixmap.is_protected(2, 3));
+        assert!(pixmap.is_protected(5, 4));
+        assert!(!pixmap.is_protected(6, 4));
+        assert!(!pixmap.is_protected(5, 5));
+        assert_eq!(pixmap.protected_regions(), vec![region]);
+
+        assert!(pixmap.unprotect(&region));
+        assert!(!pixmap.unprotect(&region));
+        assert!(!pixmap.is_protected(2, 3));
+        assert!(pixmap
+            .protect(ProtectedRegion {
+                x: 8,
+                y: 0,
+                width: 3,
+                height: 1
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_compose_region() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        pixmap.set_pixel(1, 1, Color::from(0x808080)).unwrap();
+        let layer = [Color::from(0x808080); 4];
+        pixmap
+            .compose_region(1, 1, 2, 2, &layer, BlendMode::Multiply, 0xFF)
+            .unwrap();
+        assert_eq!(pixmap.get_pixel(1, 1).unwrap(), Color::from(0x404040));
+        assert_eq!(pixmap.get_pixel(2, 2).unwrap(), Color::from(0x000000));
+        pixmap
+            .compose_region(0, 0, 2, 2, &layer, BlendMode::Screen, 0xFF)
+            .unwrap();
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), Color::from(0x808080));
+        assert!(pixmap
+            .compose_region(3, 3, 2, 2, &layer, BlendMode::Over, 0xFF)
+            .is_err());
+        assert!(pixmap
+            .compose_region(0, 0, 2, 1, &layer, BlendMode::Over, 0xFF)
+            .is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        pixmap.set_pixel(1, 2, Color::from(0x111111)).unwrap();
+        let checkpoint = pixmap.checkpoint();
+        assert_eq!(checkpoint.get_pixel(1, 2), Some(Color::from(0x111111)));
+        assert_eq!(checkpoint.get_pixel(4, 0), None);
+
+        // unchanged canvases are not copied again
+        assert!(pixmap.checkpoint().shares_data_with(&checkpoint));
+
+        pixmap.set_pixel(1, 2, Color::from(0x222222)).unwrap();
+        let changed = pixmap.checkpoint();
+        assert!(!changed.shares_data_with(&checkpoint));
+        assert_eq!(checkpoint.get_pixel(1, 2), Some(Color::from(0x111111)));
+
+        let mut edited = checkpoint.clone();
+        edited.pixels_mut()[0] = Color::from(0x333333);
+        assert!(!edited.shares_data_with(&checkpoint));
+        assert_eq!(checkpoint.get_pixel(0, 0), Some(Color::default()));
+
+        pixmap.restore(&checkpoint).unwrap();
+        assert_eq!(pixmap.get_pixel(1, 2).unwrap(), Color::from(0x111111));
+        assert_ne!(pixmap.checkpoint().generation(), changed.generation());
+        assert!(Pixmap::new(2, 8).unwrap().restore(&checkpoint).is_err());
+    }
+
+    #[test]
+    fn test_present_frame() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        pixmap.set_pixel(1, 2, Color::from(0x111111)).unwrap();
+        let frame = pixmap.present_frame();
+        assert_eq!(frame.get_pixel(1, 2), Some(Color::from(0x111111)));
+        assert!(pixmap.present_frame().shares_data_with(&frame));
+
+        // the front buffer stays unchanged while it is being read
+        pixmap.set_pixel(1, 2, Color::from(0x222222)).unwrap();
+        let next = pixmap.present_frame();
+        assert!(!next.shares_data_with(&frame));
+        assert_eq!(frame.get_pixel(1, 2), Some(Color::from(0x111111)));
+        assert_eq!(next.get_pixel(1, 2), Some(Color::from(0x222222)));
+
+        // the buffer of a frame which nobody holds anymore is reused
+        let reused = Arc::as_ptr(&frame.into_pixels());
+        pixmap.set_pixel(0, 0, Color::from(0x333333)).unwrap();
+        let last = pixmap.present_frame();
+        assert_eq!(Arc::as_ptr(&last.clone().into_pixels()), reused);
+        assert_eq!(last.get_pixel(0, 0), Some(Color::from(0x333333)));
+        assert_eq!(last.get_pixel(1, 2), Some(Color::from(0x222222)));
+    }
+
+    #[test]
+    fn test_get_region() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        pixmap.set_pixel(1, 1, Color::from(0x111111)).unwrap();
+        pixmap.set_pixel(2, 2, Color::from(0x222222)).unwrap();
+
+        let region = pixmap.get_region(1, 1, 2, 2).unwrap();
+        assert_eq!(
+            region,
+            vec![
+                Color::from(0x111111),
+                Color::default(),
+                Color::default(),
+                Color::from(0x222222)
+            ]
+        );
+        assert!(pixmap.get_region(3, 3, 2, 1).is_err());
+
+        pixmap.set_region(2, 0, 2, 1, &region[..2]).unwrap();
+        assert_eq!(pixmap.get_pixel(2, 0).unwrap(), Color::from(0x111111));
+        assert!(pixmap.set_region(3, 0, 2, 1, &region[..2]).is_err());
+        assert!(pixmap.set_region(0, 0, 2, 2, &region[..2]).is_err());
+        assert!(pixmap.get_region(usize::MAX, 0, 2, 1).is_err());
+
+        let mut buf = vec![Color::from(0x333333)];
+        pixmap.read_region_into(0, 0, 4, 1, &mut buf).unwrap();
+        assert_eq!(buf.len(), 5);
+        assert_eq!(buf[0], Color::from(0x333333));
+        assert_eq!(buf[3], Color::from(0x111111));
+        assert!(pixmap.read_region_into(0, 3, 1, 2, &mut buf).is_err());
+        assert_eq!(buf.len(), 5);
+    }
+
+    #[test]
+    fn test_concurrent_compose() {
+        let pixmap = Pixmap::new(4, 2 * SHARD_ROWS).unwrap();
+        let layer = [Color::from(0x010101); 4];
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        pixmap
+                            .compose_region(1, SHARD_ROWS - 1, 2, 2, &layer, BlendMode::Add, 0xFF)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        // no increment is lost even though the region spans two bands
+        assert_eq!(
+            pixmap.get_pixel(1, SHARD_ROWS - 1).unwrap(),
+            Color::from(0xC8C8C8)
+        );
+        assert_eq!(pixmap.get_pixel(2, SHARD_ROWS).unwrap(), Color::from(0xC8C8C8));
+        assert_eq!(pixmap.get_pixel(0, 0).unwrap(), Color::default());
+    }
+
+    #[test]
+    fn test_fixed_layout_bounds() {
+        let pixmap = Pixmap::new(1280, 720).unwrap();
+        assert_eq!(pixmap.layout, Layout::Fixed1280x720);
+        assert!(pixmap.set_pixel(1279, 719, Color::from(0x111111)).is_ok());
+        assert_eq!(pixmap.get_pixel(1279, 719).unwrap(), Color::from(0x111111));
+        assert!(pixmap.set_pixel(1280, 0, Color::default()).is_err());
+        assert!(pixmap.get_pixel(0, 720).is_err());
+    }
+
+    /// Write pseudo-random pixels into a pixmap of the given size
+    fn bench_set_pixel(b: &mut Bencher, width: usize, height: usize) {
+        let pixmap = Pixmap::new(width, height).unwrap();
+        b.iter(|| {
+            for i in 0..1000 {
+                let (x, y) = (black_box(i * 7919 % 1920), black_box(i * 104729 % 1080));
+                let _ = pixmap.set_pixel(x, y, Color::from(0xABCDEF));
+            }
+        })
+    }
+
+    #[bench]
+    fn bench_set_pixel_fixed_layout(b: &mut Bencher) {
+        bench_set_pixel(b, 1920, 1080);
+    }
+
+    #[bench]
+    fn bench_set_pixel_dynamic_layout(b: &mut Bencher) {
+        bench_set_pixel(b, 1921, 1081);
+    }
 }
@@ -0,0 +1,180 @@
+//!
+//! Ed25519 signatures which prove that a canvas snapshot was written by a specific server and not modified since
+//!
+//! Keys and signatures are stored as single lines of base64 text so that they can easily be published next to an
+//! archived snapshot. A signature always covers the complete content of the file it belongs to and is stored
+//! alongside it with an additional `.sig` extension.
+//!
+
+use base64::prelude::*;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// The extension which is appended to the path of a signed file to get the path of its signature
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// An error which indicates that a signature does not prove the authenticity of some data
+#[derive(Debug, Error, Copy, Clone, Eq, PartialEq)]
+pub enum VerificationError {
+    /// The signature is not a base64 encoded ed25519 signature
+    #[error("the signature is malformed")]
+    Malformed,
+    /// The signature was not made with the matching secret key or the data was modified afterwards
+    #[error("the signature does not match the data, it was modified or signed with a different key")]
+    Mismatch,
+}
+
+/// An error which indicates that a key could not be parsed
+#[derive(Debug, Error, Copy, Clone, Eq, PartialEq)]
+#[error("invalid key, expected 32 base64 encoded bytes")]
+pub struct InvalidKeyError;
+
+/// Decode a base64 encoded key of exactly 32 bytes
+fn decode_key(s: &str) -> Result<[u8; 32], InvalidKeyError> {
+    BASE64_STANDARD
+        .decode(s.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(InvalidKeyError)
+}
+
+/// A secret key with which snapshots are signed
+#[derive(Debug, Clone)]
+pub struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Derive a signer from 32 bytes of secret key material which should be generated randomly
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// Load a secret key which was previously stored with [`Signer::secret_key_string`]
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(Self::from_seed(decode_key(&content)?))
+    }
+
+    /// Encode the secret key so that it can be stored and later loaded again
+    pub fn secret_key_string(&self) -> String {
+        BASE64_STANDARD.encode(self.key.to_bytes())
+    }
+
+    /// The public key with which signatures of this signer are verified
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.key.verifying_key())
+    }
+
+    /// Sign the given data and encode the signature as base64
+    pub fn sign(&self, data: &[u8]) -> String {
+        use ed25519_dalek::Signer as _;
+        BASE64_STANDARD.encode(self.key.sign(data).to_bytes())
+    }
+
+    /// Sign the given content of the file at `path` and store the signature next to it
+    ///
+    /// The signature is written into a temporary file first which then replaces the previous signature so that
+    /// readers never see a partially written one.
+    pub async fn write_signature(&self, path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let signature = self.sign(data);
+        let sig_path = signature_path(path);
+        let mut tmp_path = sig_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, format!("{}\n", signature)).await?;
+        tokio::fs::rename(&tmp_path, &sig_path).await?;
+        Ok(())
+    }
+}
+
+/// A public key with which signatures are verified
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    /// Load a public key which was previously stored in its textual form
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    /// Check that `signature` was made over exactly `data` with the secret key belonging to this public key
+    pub fn verify(&self, data: &[u8], signature: &str) -> Result<(), VerificationError> {
+        let signature = BASE64_STANDARD
+            .decode(signature.trim())
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(VerificationError::Malformed)?;
+        self.0
+            .verify_strict(data, &signature)
+            .map_err(|_| VerificationError::Mismatch)
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&BASE64_STANDARD.encode(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = InvalidKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        VerifyingKey::from_bytes(&decode_key(s)?)
+            .map(Self)
+            .map_err(|_| InvalidKeyError)
+    }
+}
+
+/// The path at which the signature of the file at `path` is stored
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig_path = path.to_path_buf().into_os_string();
+    sig_path.push(".");
+    sig_path.push(SIGNATURE_EXTENSION);
+    sig_path.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = Signer::from_seed([7; 32]);
+        let public_key = signer.public_key();
+        let signature = signer.sign(b"PIXELFLUT artwork");
+        assert_eq!(public_key.verify(b"PIXELFLUT artwork", &signature), Ok(()));
+        assert_eq!(
+            public_key.verify(b"PIXELFLUT artw0rk", &signature),
+            Err(VerificationError::Mismatch)
+        );
+        assert_eq!(
+            Signer::from_seed([8; 32])
+                .public_key()
+                .verify(b"PIXELFLUT artwork", &signature),
+            Err(VerificationError::Mismatch)
+        );
+        assert_eq!(
+            public_key.verify(b"PIXELFLUT artwork", "not a signature"),
+            Err(VerificationError::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_key_encoding() {
+        let signer = Signer::from_seed([42; 32]);
+        let restored = Signer::from_seed(decode_key(&signer.secret_key_string()).unwrap());
+        assert_eq!(restored.public_key(), signer.public_key());
+        assert_eq!(signer.public_key().to_string().parse(), Ok(signer.public_key()));
+        assert_eq!("AAAA".parse::<PublicKey>(), Err(InvalidKeyError));
+        assert_eq!(
+            signature_path(Path::new("/tmp/canvas.png")),
+            PathBuf::from("/tmp/canvas.png.sig")
+        );
+    }
+}
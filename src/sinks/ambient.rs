@@ -0,0 +1,277 @@
+//! A sink which drives ambient lighting (WLED strips or Philips Hue lights) with the canvas colors
+
+use crate::pixmap::{Color, ColorStats, Pixmap, SharedPixmap};
+use crate::watchdog::Heartbeat;
+use crate::DaemonResult;
+use anyhow::anyhow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How long a Hue bridge may take to apply a state update before it is given up
+const HUE_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The device which is driven by an [`AmbientSink`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AmbientTarget {
+    /// A WLED controller which is addressed via its UDP realtime protocol (DRGB)
+    Wled {
+        /// The address of the WLED controllers realtime UDP port (usually 21324)
+        addr: SocketAddr,
+        /// How many LEDs the strip has
+        ///
+        /// If it is 1, the dominant canvas color is sent.
+        /// Otherwise the LEDs are treated as running clockwise around the canvas, starting at its top-left corner,
+        /// and each one receives the average color of the canvas edge next to it (ambilight style).
+        leds: usize,
+    },
+    /// A Philips Hue light which is addressed via the REST API of its bridge
+    Hue {
+        /// The address of the Hue bridges HTTP API
+        bridge: SocketAddr,
+        /// The API username which is registered on the bridge
+        username: String,
+        /// The id of the light that should be controlled
+        light: u32,
+    },
+}
+
+/// Configuration options for the [`AmbientSink`]
+#[derive(Debug)]
+pub struct AmbientSinkOptions {
+    /// The device to which colors are sent
+    pub target: AmbientTarget,
+    /// The interval in which colors are sent to the device
+    pub interval: Interval,
+    /// The latest color statistics of the pixmap from which the dominant color is taken
+    ///
+    /// They are usually shared with the other users of the statistics, see
+    /// [`ColorStatsSink`](crate::sinks::color_stats::ColorStatsSink).
+    pub color_stats: watch::Receiver<Arc<ColorStats>>,
+    /// Through which the sink reports each update to a [`Watchdog`](crate::watchdog::Watchdog)
+    pub heartbeat: Option<Heartbeat>,
+}
+
+/// A sink that periodically sends colors derived from the canvas to an ambient lighting device
+#[derive(Debug)]
+pub struct AmbientSink {
+    options: AmbientSinkOptions,
+    pixmap: SharedPixmap,
+}
+
+impl AmbientSink {
+    /// Create a new sink which drives the configured device with colors from the given pixmap
+    pub fn new(options: AmbientSinkOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Start the background task which periodically updates the device
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        let handle = join_set
+            .build_task()
+            .name("ambient")
+            .spawn(async move { self.run().await })?;
+        Ok(handle)
+    }
+
+    /// Execute the main loop which periodically sends colors to the device
+    async fn run(mut self) -> anyhow::Result<!> {
+        self.options
+            .interval
+            .set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let socket = match &self.options.target {
+            AmbientTarget::Wled { addr, .. } => {
+                let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+                socket.connect(addr).await?;
+                Some(socket)
+            }
+            AmbientTarget::Hue { .. } => None,
+        };
+
+        loop {
+            self.options.interval.tick().await;
+            if let Some(heartbeat) = &self.options.heartbeat {
+                heartbeat.beat();
+            }
+            match &self.options.target {
+                AmbientTarget::Wled { leds, .. } => {
+                    let colors = match leds {
+                        1 => vec![dominant_color(&self.options.color_stats.borrow())],
+                        n => edge_colors(&self.pixmap, *n),
+                    };
+                    // controllers which are offline are not fatal either because they usually come back
+                    if let Err(e) = socket.as_ref().unwrap().send(&encode_wled_drgb(&colors)).await {
+                        tracing::warn!("Could not update WLED controller: {}", e);
+                    }
+                }
+                AmbientTarget::Hue {
+                    bridge,
+                    username,
+                    light,
+                } => {
+                    // unreachable bridges are not fatal because they usually come back
+                    let color = dominant_color(&self.options.color_stats.borrow());
+                    if let Err(e) = send_hue_state(*bridge, username, *light, color).await {
+                        tracing::warn!("Could not update hue light {}: {}", light, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Determine the most common color of the analyzed pixmap
+fn dominant_color(stats: &ColorStats) -> Color {
+    stats
+        .dominant_colors(1)
+        .first()
+        .map(|(color, _)| *color)
+        .unwrap_or_default()
+}
+
+/// Compute `n` colors by averaging the pixmap along its edges
+///
+/// The edges are split into `n` equally long segments, starting at the top-left corner and running clockwise.
+fn edge_colors(pixmap: &Pixmap, n: usize) -> Vec<Color> {
+    let (width, height) = pixmap.get_size();
+    let depth = usize::max(1, usize::min(width, height) / 20);
+    let perimeter = 2 * (width + height);
+
+    (0..n)
+        .map(|i| {
+            let mut sums = [0usize; 3];
+            let mut count = 0usize;
+            for pos in (i * perimeter / n)..((i + 1) * perimeter / n) {
+                for d in 0..depth {
+                    // walk along the perimeter and sample the pixels towards the canvas center
+                    let (x, y) = if pos < width {
+                        (pos, d)
+                    } else if pos < width + height {
+                        (width - 1 - d, pos - width)
+                    } else if pos < 2 * width + height {
+                        (width - 1 - (pos - width - height), height - 1 - d)
+                    } else {
+                        (d, height - 1 - (pos - 2 * width - height))
+                    };
+                    if let Ok(color) = pixmap.get_pixel(x, y) {
+                        let (r, g, b): (u8, u8, u8) = color.into();
+                        sums[0] += r as usize;
+                        sums[1] += g as usize;
+                        sums[2] += b as usize;
+                        count += 1;
+                    }
+                }
+            }
+            let count = count.max(1);
+            Color::from((
+                (sums[0] / count) as u8,
+                (sums[1] / count) as u8,
+                (sums[2] / count) as u8,
+            ))
+        })
+        .collect()
+}
+
+/// Encode colors as a WLED realtime DRGB packet
+fn encode_wled_drgb(colors: &[Color]) -> Vec<u8> {
+    /// The protocol identifier of DRGB packets
+    const PROTOCOL_DRGB: u8 = 2;
+    /// How many seconds WLED should wait before returning to normal operation if no more packets arrive
+    const TIMEOUT_SECS: u8 = 2;
+
+    let mut packet = vec![PROTOCOL_DRGB, TIMEOUT_SECS];
+    packet.extend(colors.iter().flat_map(|c| Into::<[u8; 3]>::into(*c)));
+    packet
+}
+
+/// Convert an sRGB color into the CIE xy color space and brightness used by the Hue API
+fn rgb_to_hue_xy(color: Color) -> (f32, f32, u8) {
+    let (r, g, b): (u8, u8, u8) = color.into();
+    let gamma = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c > 0.04045 {
+            ((c + 0.055) / 1.055).powf(2.4)
+        } else {
+            c / 12.92
+        }
+    };
+    let (r, g, b) = (gamma(r), gamma(g), gamma(b));
+    let x = r * 0.664_511 + g * 0.154_324 + b * 0.162_028;
+    let y = r * 0.283_881 + g * 0.668_433 + b * 0.047_685;
+    let z = r * 0.000_088 + g * 0.072_310 + b * 0.986_039;
+    let sum = x + y + z;
+    let brightness = u8::max((r.max(g).max(b) * 254.0) as u8, 1);
+    if sum == 0.0 {
+        (0.0, 0.0, brightness)
+    } else {
+        (x / sum, y / sum, brightness)
+    }
+}
+
+/// Set the color of a Hue light by sending a state update to its bridge
+///
+/// Fails if the bridge does not respond within [`HUE_REQUEST_TIMEOUT`] so that an unresponsive bridge doesn't stall
+/// the sink.
+async fn send_hue_state(bridge: SocketAddr, username: &str, light: u32, color: Color) -> anyhow::Result<()> {
+    let (x, y, brightness) = rgb_to_hue_xy(color);
+    let body = format!("{{\"on\":true,\"xy\":[{x:.4},{y:.4}],\"bri\":{brightness}}}");
+    let request = format!(
+        "PUT /api/{username}/lights/{light}/state HTTP/1.1\r\n\
+        Host: {bridge}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len()
+    );
+
+    let response = tokio::time::timeout(HUE_REQUEST_TIMEOUT, async {
+        let mut stream = TcpStream::connect(bridge).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        anyhow::Ok(response)
+    })
+    .await
+    .map_err(|_| anyhow!("bridge did not respond within {:?}", HUE_REQUEST_TIMEOUT))??;
+    tracing::trace!(
+        "Hue bridge responded with {:?}",
+        String::from_utf8_lossy(&response)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edge_colors() {
+        let pixmap = Pixmap::new(4, 4).unwrap();
+        for x in 0..4 {
+            pixmap.set_pixel(x, 0, Color::from(0xFF0000)).unwrap();
+            pixmap.set_pixel(x, 3, Color::from(0x0000FF)).unwrap();
+        }
+
+        // top, right, bottom, left
+        let colors = edge_colors(&pixmap, 4);
+        assert_eq!(colors[0], Color::from(0xFF0000));
+        assert_eq!(colors[2], Color::from(0x0000FF));
+    }
+
+    #[tokio::test]
+    async fn test_hue_timeout() {
+        // connections to the bridge are established by the OS but it never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bridge = listener.local_addr().unwrap();
+
+        let result = send_hue_state(bridge, "user", 1, Color::from(0xFF0000)).await;
+        assert!(result.unwrap_err().to_string().contains("did not respond"));
+    }
+}
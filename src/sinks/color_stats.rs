@@ -0,0 +1,130 @@
+//! A sink which periodically computes color statistics of the canvas
+
+use crate::pixmap::{ColorStats, SharedPixmap};
+use crate::DaemonResult;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Interval;
+
+/// Configuration options for the [`ColorStatsSink`]
+#[derive(Debug)]
+pub struct ColorStatsSinkOptions {
+    /// The interval in which statistics are recomputed
+    pub interval: Interval,
+}
+
+/// A sink that periodically analyzes the colors of the pixmap
+///
+/// The latest statistics can be observed by obtaining a receiver via [`ColorStatsSink::subscribe`].
+#[derive(Debug)]
+pub struct ColorStatsSink {
+    options: ColorStatsSinkOptions,
+    pixmap: SharedPixmap,
+    sender: watch::Sender<Arc<ColorStats>>,
+}
+
+impl ColorStatsSink {
+    /// Create a new sink which analyzes the given pixmap
+    pub fn new(options: ColorStatsSinkOptions, pixmap: SharedPixmap) -> Self {
+        let (sender, _) = watch::channel(Arc::new(ColorStats::compute(&pixmap)));
+        Self {
+            options,
+            pixmap,
+            sender,
+        }
+    }
+
+    /// Get a receiver through which the latest statistics can be observed
+    pub fn subscribe(&self) -> watch::Receiver<Arc<ColorStats>> {
+        self.sender.subscribe()
+    }
+
+    /// Start the background task which periodically recomputes statistics
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        let handle = join_set
+            .build_task()
+            .name("color_stats")
+            .spawn(async move { self.run().await })?;
+        Ok(handle)
+    }
+
+    /// Execute the main loop which periodically computes statistics
+    async fn run(mut self) -> anyhow::Result<!> {
+        loop {
+            self.options.interval.tick().await;
+            let pixmap = self.pixmap.clone();
+            let stats = tokio::task::spawn_blocking(move || ColorStats::compute(&pixmap)).await?;
+            tracing::debug!(
+                "Computed color statistics: average={}, dominant={:?}",
+                stats.average(),
+                stats.dominant_colors(3)
+            );
+            self.sender.send_replace(Arc::new(stats));
+        }
+    }
+}
+
+/// The latest color statistics of several canvases, keyed by canvas name
+///
+/// Every canvas is analyzed by a [`ColorStatsSink`] of its own whose results are collected here so that e.g. servers
+/// can report them without analyzing the canvas on every request.
+#[derive(Debug, Clone, Default)]
+pub struct LatestColorStats {
+    receivers: HashMap<String, watch::Receiver<Arc<ColorStats>>>,
+}
+
+impl LatestColorStats {
+    /// Collect the results of the sink which analyzes the canvas with the given name
+    pub fn insert(&mut self, canvas: impl Into<String>, sink: &ColorStatsSink) {
+        self.receivers.insert(canvas.into(), sink.subscribe());
+    }
+
+    /// Get the most recently computed statistics of the named canvas or `None` if it is not analyzed
+    pub fn get(&self, canvas: &str) -> Option<Arc<ColorStats>> {
+        self.receivers
+            .get(canvas)
+            .map(|receiver| receiver.borrow().clone())
+    }
+
+    /// Get a receiver through which the statistics of the named canvas can be observed if it is analyzed
+    pub fn subscribe(&self, canvas: &str) -> Option<watch::Receiver<Arc<ColorStats>>> {
+        self.receivers.get(canvas).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_latest_color_stats() {
+        let pixmap = Arc::new(Pixmap::new(2, 2).unwrap());
+        pixmap.fill(Color::from(0xFF0000));
+        let sink = ColorStatsSink::new(
+            ColorStatsSinkOptions {
+                interval: tokio::time::interval(Duration::from_millis(10)),
+            },
+            pixmap.clone(),
+        );
+        let mut latest = LatestColorStats::default();
+        latest.insert("default", &sink);
+        assert_eq!(latest.get("default").unwrap().average(), Color::from(0xFF0000));
+        assert!(latest.get("other").is_none());
+        assert!(latest.subscribe("other").is_none());
+
+        // results of the running sink replace the initial statistics
+        let mut receiver = sink.subscribe();
+        let mut join_set = JoinSet::new();
+        sink.start(&mut join_set).await.unwrap();
+        pixmap.fill(Color::from(0x0000FF));
+        receiver
+            .wait_for(|stats| stats.average() == Color::from(0x0000FF))
+            .await
+            .unwrap();
+        assert_eq!(latest.get("default").unwrap().average(), Color::from(0x0000FF));
+    }
+}
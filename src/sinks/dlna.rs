@@ -0,0 +1,928 @@
+//! A sink which announces the canvas as a DLNA media server so that smart TVs and other renderers in the local
+//! network can show it without extra hardware
+//!
+//! The server is announced via SSDP on the multicast group [`SSDP_GROUP`] and answers searches of control points
+//! which have private, link-local or loopback addresses.
+//! Its content directory contains the current canvas as PNG image and optionally the url of a live stream, e.g. an
+//! HLS playlist which is published next to the RTMP or RTSP stream.
+//! The device description, the content directory and the canvas image are served by a small HTTP server of its own.
+//!
+//! Chromecast devices are not supported because they don't take part in DLNA and require the Google Cast protocol
+//! instead.
+
+use crate::pixmap::{encode_png, SharedPixmap};
+use crate::DaemonResult;
+use anyhow::anyhow;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::hash::Hasher;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::{AbortHandle, JoinSet};
+use url::Url;
+
+/// The multicast address on which SSDP announcements and searches are sent
+pub const SSDP_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// How long control points may remember an announcement, which is twice the interval in which it is repeated
+const MAX_AGE: Duration = Duration::from_secs(1800);
+
+/// The largest request head and body that is accepted by the HTTP server
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// How long clients of the HTTP server may take to send a request before their connection is closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// Configuration options for the [`DlnaSink`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DlnaOptions {
+    /// The address of the HTTP server which serves the device description and the canvas image
+    pub listen: SocketAddr,
+    /// The name under which the server is shown on renderers
+    pub name: String,
+    /// The url of a live stream of the canvas which is offered next to the image
+    pub stream: Option<Url>,
+}
+
+/// A sink that offers the canvas to DLNA renderers in the local network
+#[derive(Debug)]
+pub struct DlnaSink {
+    options: DlnaOptions,
+    pixmap: SharedPixmap,
+}
+
+impl DlnaSink {
+    /// Create a new sink which offers the given pixmap
+    pub fn new(options: DlnaOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Start the background task which announces the server and serves its content
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        let listener = TcpListener::bind(self.options.listen)
+            .await
+            .map_err(|e| anyhow!("could not bind to {}: {}", self.options.listen, e))?;
+        let ssdp = bind_ssdp().map_err(|e| anyhow!("could not join the SSDP multicast group: {}", e))?;
+        let device = Arc::new(Device {
+            uuid: device_uuid(&self.options.name),
+            port: listener.local_addr()?.port(),
+            options: self.options,
+            pixmap: self.pixmap,
+        });
+        tracing::info!(
+            "Announcing canvas to DLNA renderers as {} on http://{}",
+            device.options.name,
+            listener.local_addr()?
+        );
+
+        let handle = join_set.build_task().name("dlna").spawn(async move {
+            tokio::select! {
+                result = serve_http(listener, device.clone()) => result,
+                result = serve_ssdp(ssdp, device) => result,
+            }
+        })?;
+        Ok(handle)
+    }
+}
+
+/// Everything that is announced about the server
+#[derive(Debug)]
+struct Device {
+    options: DlnaOptions,
+    pixmap: SharedPixmap,
+    uuid: String,
+    port: u16,
+}
+
+impl Device {
+    /// The notification types under which the device and its services are announced together with their USNs
+    fn notification_types(&self) -> [(String, String); 5] {
+        let uuid = format!("uuid:{}", self.uuid);
+        [
+            (
+                "upnp:rootdevice".to_string(),
+                format!("{}::upnp:rootdevice", uuid),
+            ),
+            (uuid.clone(), uuid.clone()),
+            (DEVICE_TYPE.to_string(), format!("{}::{}", uuid, DEVICE_TYPE)),
+            (
+                CONTENT_DIRECTORY.to_string(),
+                format!("{}::{}", uuid, CONTENT_DIRECTORY),
+            ),
+            (
+                CONNECTION_MANAGER.to_string(),
+                format!("{}::{}", uuid, CONNECTION_MANAGER),
+            ),
+        ]
+    }
+
+    /// The url of the device description when the server is reached via `ip`
+    fn location(&self, ip: IpAddr) -> String {
+        format!("http://{}/description.xml", SocketAddr::new(ip, self.port))
+    }
+
+    /// The address at which the server is reachable for a peer
+    ///
+    /// If the HTTP server listens on an unspecified address, the address of the interface which routes to the peer is
+    /// used.
+    fn ip_towards(&self, peer: SocketAddr) -> std::io::Result<IpAddr> {
+        let ip = self.options.listen.ip();
+        if !ip.is_unspecified() {
+            return Ok(ip);
+        }
+        let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(peer)?;
+        Ok(socket.local_addr()?.ip())
+    }
+}
+
+/// A stable identifier of the device which is derived from its name so that renderers recognize it after a restart
+fn device_uuid(name: &str) -> String {
+    let hash = |seed: u64| {
+        // FNV-1a because the hashers of the standard library are not guaranteed to be stable between releases
+        let mut hasher = Fnv(0xcbf29ce484222325 ^ seed);
+        hasher.write(name.as_bytes());
+        hasher.finish()
+    };
+    let bytes = ((hash(0) as u128) << 64 | hash(1) as u128).to_be_bytes();
+    let hex = bytes.iter().map(|i| format!("{:02x}", i)).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Bind a socket which receives the searches that are sent to the SSDP multicast group
+fn bind_ssdp() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // other media servers on the same host listen on the same port
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_GROUP.port())).into())?;
+    socket.join_multicast_v4(SSDP_GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(2)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Announce the device periodically and answer searches for it
+async fn serve_ssdp(socket: UdpSocket, device: Arc<Device>) -> anyhow::Result<!> {
+    let _goodbye = Goodbye(device.clone());
+    let mut interval = tokio::time::interval(MAX_AGE / 2);
+    let mut buffer = [0u8; 2048];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = notify(&device, "ssdp:alive") {
+                    tracing::warn!("Could not announce DLNA server: {}", e);
+                }
+            }
+            result = socket.recv_from(&mut buffer) => {
+                // errors like ECONNREFUSED only report that an earlier reply could not be delivered
+                let (len, peer) = match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::debug!("Could not receive SSDP message: {}", e);
+                        continue;
+                    }
+                };
+                // searches can be sent with a forged source address, so only peers which can be reached directly
+                // are answered to keep the server from being abused to flood others with replies
+                if !is_local_peer(peer.ip()) {
+                    continue;
+                }
+                let Ok(message) = std::str::from_utf8(&buffer[..len]) else {
+                    continue;
+                };
+                let Some(target) = search_target(message) else {
+                    continue;
+                };
+                let ip = match device.ip_towards(peer) {
+                    Ok(ip) => ip,
+                    Err(e) => {
+                        tracing::debug!("Could not answer SSDP search of {}: {}", peer, e);
+                        continue;
+                    }
+                };
+                // a peer which became unreachable must not stop the announcements for everybody else
+                for response in search_responses(&device, target, ip) {
+                    if let Err(e) = socket.send_to(response.as_bytes(), peer).await {
+                        tracing::debug!("Could not answer SSDP search of {}: {}", peer, e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether `ip` belongs to the local network whose renderers are answered, which is the case for link-local,
+/// private and loopback addresses
+fn is_local_peer(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_loopback(),
+    }
+}
+
+/// The search target of an SSDP search request or `None` if the message is something else
+fn search_target(message: &str) -> Option<&str> {
+    let mut lines = message.lines();
+    if !lines.next()?.starts_with("M-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let mut target = None;
+    let mut discover = false;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_ascii_uppercase().as_str() {
+                "ST" => target = Some(value.trim()),
+                "MAN" => discover = value.trim() == "\"ssdp:discover\"",
+                _ => {}
+            }
+        }
+    }
+    target.filter(|_| discover)
+}
+
+/// The responses to a search for `target` which tell the searcher to reach the server via `ip`
+fn search_responses(device: &Device, target: &str, ip: IpAddr) -> Vec<String> {
+    device
+        .notification_types()
+        .into_iter()
+        .filter(|(nt, _)| target == "ssdp:all" || target == nt)
+        .map(|(nt, usn)| {
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                CACHE-CONTROL: max-age={}\r\n\
+                EXT:\r\n\
+                LOCATION: {}\r\n\
+                SERVER: {}\r\n\
+                ST: {}\r\n\
+                USN: {}\r\n\r\n",
+                MAX_AGE.as_secs(),
+                device.location(ip),
+                server_header(),
+                nt,
+                usn
+            )
+        })
+        .collect()
+}
+
+/// Send an announcement with the given notification sub type to the multicast group
+fn notify(device: &Device, nts: &str) -> std::io::Result<()> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(2)?;
+    let ip = device.ip_towards(SSDP_GROUP.into())?;
+    for (nt, usn) in device.notification_types() {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\n\
+            HOST: {}\r\n\
+            CACHE-CONTROL: max-age={}\r\n\
+            LOCATION: {}\r\n\
+            NT: {}\r\n\
+            NTS: {}\r\n\
+            SERVER: {}\r\n\
+            USN: {}\r\n\r\n",
+            SSDP_GROUP,
+            MAX_AGE.as_secs(),
+            device.location(ip),
+            nt,
+            nts,
+            server_header(),
+            usn
+        );
+        socket.send_to(message.as_bytes(), SSDP_GROUP)?;
+    }
+    Ok(())
+}
+
+/// Tells renderers that the server is gone when it is dropped
+struct Goodbye(Arc<Device>);
+
+impl Drop for Goodbye {
+    fn drop(&mut self) {
+        if let Err(e) = notify(&self.0, "ssdp:byebye") {
+            tracing::warn!("Could not withdraw DLNA announcement: {}", e);
+        }
+    }
+}
+
+fn server_header() -> &'static str {
+    concat!("Linux UPnP/1.0 pixeldike/", env!("CARGO_PKG_VERSION"))
+}
+
+/// A request to the HTTP server of the device
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    path: String,
+    soap_action: Option<String>,
+    body: String,
+}
+
+/// A response of the HTTP server of the device
+#[derive(Debug)]
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn xml(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/xml; charset=\"utf-8\"",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            status: "404 Not Found",
+            content_type: "text/plain",
+            body: b"not found".to_vec(),
+        }
+    }
+}
+
+/// Serve the device description, the control endpoints and the canvas image
+async fn serve_http(listener: TcpListener, device: Arc<Device>) -> anyhow::Result<!> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let device = device.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &device).await {
+                tracing::debug!("Could not answer DLNA request of {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer a single request and close the connection
+async fn handle_connection(stream: TcpStream, device: &Device) -> anyhow::Result<()> {
+    let host = stream.local_addr()?;
+    let mut stream = BufReader::new(stream);
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| anyhow!("client did not send a request within {:?}", REQUEST_TIMEOUT))??;
+    let response = respond(device, &request, host);
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+        Content-Type: {}\r\n\
+        Content-Length: {}\r\n\
+        Server: {}\r\n\
+        Connection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        server_header()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    if request.method != "HEAD" {
+        stream.write_all(&response.body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request line, the interesting headers and the body of a request
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Request> {
+    let mut request = Request::default();
+    let mut line = String::new();
+    let mut content_length = 0;
+    // the head is read through the limit so that clients can't make the server buffer endless lines
+    let mut head = (&mut *reader).take(MAX_REQUEST_SIZE as u64);
+    loop {
+        line.clear();
+        let len = head.read_line(&mut line).await?;
+        if head.limit() == 0 && !line.ends_with('\n') {
+            return Err(anyhow!("request head is larger than {} bytes", MAX_REQUEST_SIZE));
+        }
+        if len == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if request.method.is_empty() {
+            let mut parts = line.split_ascii_whitespace();
+            request.method = parts.next().unwrap_or_default().to_string();
+            request.path = parts.next().unwrap_or_default().to_string();
+        } else if let Some((name, value)) = line.split_once(':') {
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse()?,
+                "soapaction" => request.soap_action = Some(value.trim().trim_matches('"').to_string()),
+                _ => {}
+            }
+        }
+    }
+    if content_length > MAX_REQUEST_SIZE {
+        return Err(anyhow!("request body is larger than {} bytes", MAX_REQUEST_SIZE));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    request.body = String::from_utf8(body)?;
+    Ok(request)
+}
+
+/// Route a request to the description, a service or the canvas image
+///
+/// `host` is the address at which the requester reached the server which is also used in the urls of the content.
+fn respond(device: &Device, request: &Request, host: SocketAddr) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET" | "HEAD", "/description.xml") => Response::xml("200 OK", description(device)),
+        ("GET" | "HEAD", "/ContentDirectory.xml") => Response::xml("200 OK", content_directory_scpd()),
+        ("GET" | "HEAD", "/ConnectionManager.xml") => Response::xml("200 OK", connection_manager_scpd()),
+        ("GET" | "HEAD", "/canvas.png") => {
+            let (width, height) = device.pixmap.get_size();
+            let frame = device.pixmap.present_frame();
+            match encode_png(width, height, frame.pixels()) {
+                Ok(body) => Response {
+                    status: "200 OK",
+                    content_type: "image/png",
+                    body,
+                },
+                Err(e) => Response {
+                    status: "500 Internal Server Error",
+                    content_type: "text/plain",
+                    body: e.to_string().into_bytes(),
+                },
+            }
+        }
+        ("POST", "/ContentDirectory/control") => control(request, CONTENT_DIRECTORY, |action| {
+            content_directory_action(device, action, &request.body, host)
+        }),
+        ("POST", "/ConnectionManager/control") => {
+            control(request, CONNECTION_MANAGER, connection_manager_action)
+        }
+        _ => Response::not_found(),
+    }
+}
+
+/// Answer a SOAP request for an action of `service` with its output arguments or a fault if the action fails
+///
+/// Requests whose SOAPAction names another service are rejected so that only known names end up in the response.
+fn control(
+    request: &Request,
+    service: &str,
+    action: impl FnOnce(&str) -> Result<String, (u16, &'static str)>,
+) -> Response {
+    let result = match request.soap_action.as_deref().and_then(|i| i.split_once('#')) {
+        Some((requested, name)) if requested == service => action(name).map(|arguments| (name, arguments)),
+        _ => Err((401, "Invalid Action")),
+    };
+    match result {
+        Ok((name, arguments)) => Response::xml(
+            "200 OK",
+            envelope(&format!(
+                "<u:{name}Response xmlns:u=\"{service}\">{arguments}</u:{name}Response>"
+            )),
+        ),
+        Err((code, description)) => Response::xml(
+            "500 Internal Server Error",
+            envelope(&format!(
+                "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail>\
+                <UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{code}</errorCode>\
+                <errorDescription>{description}</errorDescription></UPnPError></detail></s:Fault>"
+            )),
+        ),
+    }
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>{body}</s:Body></s:Envelope>"
+    )
+}
+
+/// The output arguments of a content directory action
+fn content_directory_action(
+    device: &Device,
+    action: &str,
+    body: &str,
+    host: SocketAddr,
+) -> Result<String, (u16, &'static str)> {
+    match action {
+        "Browse" => {
+            let argument = |name: &str| xml_element(body, name).unwrap_or_default();
+            let objects = browse(device, argument("ObjectID"), argument("BrowseFlag"), host)
+                .ok_or((701, "No such object"))?;
+            let total = objects.len();
+            let start = argument("StartingIndex").parse().unwrap_or(0);
+            let count = match argument("RequestedCount").parse().unwrap_or(0) {
+                0 => usize::MAX,
+                count => count,
+            };
+            let objects = objects.into_iter().skip(start).take(count).collect::<Vec<_>>();
+            let didl = format!(
+                "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+                xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+                xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">{}</DIDL-Lite>",
+                objects.concat()
+            );
+            Ok(format!(
+                "<Result>{}</Result><NumberReturned>{}</NumberReturned><TotalMatches>{}</TotalMatches>\
+                <UpdateID>1</UpdateID>",
+                xml_escape(&didl),
+                objects.len(),
+                total
+            ))
+        }
+        "GetSystemUpdateID" => Ok("<Id>1</Id>".to_string()),
+        "GetSearchCapabilities" => Ok("<SearchCaps></SearchCaps>".to_string()),
+        "GetSortCapabilities" => Ok("<SortCaps></SortCaps>".to_string()),
+        _ => Err((401, "Invalid Action")),
+    }
+}
+
+/// The output arguments of a connection manager action
+fn connection_manager_action(action: &str) -> Result<String, (u16, &'static str)> {
+    match action {
+        "GetProtocolInfo" => Ok("<Source>http-get:*:image/png:*</Source><Sink></Sink>".to_string()),
+        "GetCurrentConnectionIDs" => Ok("<ConnectionIDs>0</ConnectionIDs>".to_string()),
+        _ => Err((401, "Invalid Action")),
+    }
+}
+
+/// The DIDL-Lite objects which are returned when browsing an object or `None` if it doesn't exist
+///
+/// The content directory consists of a root container with the canvas image and the optional live stream.
+fn browse(device: &Device, object: &str, flag: &str, host: SocketAddr) -> Option<Vec<String>> {
+    let (width, height) = device.pixmap.get_size();
+    let name = xml_escape(&device.options.name);
+    let canvas = format!(
+        "<item id=\"canvas\" parentID=\"0\" restricted=\"1\"><dc:title>{name}</dc:title>\
+        <upnp:class>object.item.imageItem.photo</upnp:class>\
+        <res protocolInfo=\"http-get:*:image/png:*\" resolution=\"{width}x{height}\">http://{host}/canvas.png</res>\
+        </item>"
+    );
+    let stream = device.options.stream.as_ref().map(|url| {
+        format!(
+            "<item id=\"stream\" parentID=\"0\" restricted=\"1\"><dc:title>{name} (live)</dc:title>\
+            <upnp:class>object.item.videoItem</upnp:class>\
+            <res protocolInfo=\"http-get:*:{}:*\">{}</res></item>",
+            stream_mime_type(url),
+            xml_escape(url.as_str())
+        )
+    });
+    let children = [Some(canvas.clone()), stream.clone()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+    match (object, flag) {
+        ("0", "BrowseDirectChildren") => Some(children),
+        ("0", "BrowseMetadata") => Some(vec![format!(
+            "<container id=\"0\" parentID=\"-1\" restricted=\"1\" childCount=\"{}\"><dc:title>{name}</dc:title>\
+            <upnp:class>object.container.storageFolder</upnp:class></container>",
+            children.len()
+        )]),
+        ("canvas", "BrowseMetadata") => Some(vec![canvas]),
+        ("stream", "BrowseMetadata") => stream.map(|i| vec![i]),
+        _ => None,
+    }
+}
+
+/// Guess the MIME type of a stream from the extension of its url
+fn stream_mime_type(url: &Url) -> &'static str {
+    match url.path().rsplit_once('.').map(|(_, extension)| extension) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("mpd") => "application/dash+xml",
+        Some("ts") => "video/mp2t",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mjpg" | "mjpeg") => "video/x-motion-jpeg",
+        _ => "video/mpeg",
+    }
+}
+
+/// The text content of the first element with the given name, ignoring namespace prefixes
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        let local = tag.split_ascii_whitespace().next()?;
+        let local = local.rsplit_once(':').map_or(local, |(_, i)| i);
+        if local == name && !tag.ends_with('/') {
+            let content = &rest[end + 1..];
+            return Some(&content[..content.find("</")?]);
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The UPnP device description of the media server
+fn description(device: &Device) -> String {
+    let service = |ty: &str, id: &str| {
+        format!(
+            "<service><serviceType>{ty}</serviceType><serviceId>urn:upnp-org:serviceId:{id}</serviceId>\
+            <SCPDURL>/{id}.xml</SCPDURL><controlURL>/{id}/control</controlURL>\
+            <eventSubURL>/{id}/events</eventSubURL></service>"
+        )
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+        <root xmlns=\"urn:schemas-upnp-org:device-1-0\" xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">\
+        <specVersion><major>1</major><minor>0</minor></specVersion><device>\
+        <deviceType>{DEVICE_TYPE}</deviceType><dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>\
+        <friendlyName>{}</friendlyName><manufacturer>pixeldike</manufacturer>\
+        <manufacturerURL>https://github.com/ftsell/pixeldike</manufacturerURL>\
+        <modelName>pixeldike</modelName><modelNumber>{}</modelNumber><UDN>uuid:{}</UDN>\
+        <serviceList>{}{}</serviceList></device></root>",
+        xml_escape(&device.options.name),
+        env!("CARGO_PKG_VERSION"),
+        device.uuid,
+        service(CONTENT_DIRECTORY, "ContentDirectory"),
+        service(CONNECTION_MANAGER, "ConnectionManager"),
+    )
+}
+
+/// An argument of an action given as `(name, direction, state variable)`
+type Argument = (&'static str, &'static str, &'static str);
+
+/// A service description with actions given as `(name, arguments)` and state variables given as `(name, data type)`
+fn scpd(actions: &[(&str, &[Argument])], variables: &[(&str, &str)]) -> String {
+    let actions = actions
+        .iter()
+        .map(|(name, arguments)| {
+            let arguments = arguments
+                .iter()
+                .map(|(argument, direction, variable)| {
+                    format!(
+                        "<argument><name>{argument}</name><direction>{direction}</direction>\
+                        <relatedStateVariable>{variable}</relatedStateVariable></argument>"
+                    )
+                })
+                .collect::<String>();
+            format!("<action><name>{name}</name><argumentList>{arguments}</argumentList></action>")
+        })
+        .collect::<String>();
+    let variables = variables
+        .iter()
+        .map(|(name, ty)| {
+            format!("<stateVariable sendEvents=\"no\"><name>{name}</name><dataType>{ty}</dataType></stateVariable>")
+        })
+        .collect::<String>();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+        <specVersion><major>1</major><minor>0</minor></specVersion>\
+        <actionList>{actions}</actionList><serviceStateTable>{variables}</serviceStateTable></scpd>"
+    )
+}
+
+fn content_directory_scpd() -> String {
+    scpd(
+        &[
+            (
+                "Browse",
+                &[
+                    ("ObjectID", "in", "A_ARG_TYPE_ObjectID"),
+                    ("BrowseFlag", "in", "A_ARG_TYPE_BrowseFlag"),
+                    ("Filter", "in", "A_ARG_TYPE_Filter"),
+                    ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+                    ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+                    ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+                    ("Result", "out", "A_ARG_TYPE_Result"),
+                    ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+                    ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+                    ("UpdateID", "out", "A_ARG_TYPE_UpdateID"),
+                ],
+            ),
+            ("GetSystemUpdateID", &[("Id", "out", "SystemUpdateID")]),
+            (
+                "GetSearchCapabilities",
+                &[("SearchCaps", "out", "SearchCapabilities")],
+            ),
+            ("GetSortCapabilities", &[("SortCaps", "out", "SortCapabilities")]),
+        ],
+        &[
+            ("A_ARG_TYPE_ObjectID", "string"),
+            ("A_ARG_TYPE_BrowseFlag", "string"),
+            ("A_ARG_TYPE_Filter", "string"),
+            ("A_ARG_TYPE_Index", "ui4"),
+            ("A_ARG_TYPE_Count", "ui4"),
+            ("A_ARG_TYPE_SortCriteria", "string"),
+            ("A_ARG_TYPE_Result", "string"),
+            ("A_ARG_TYPE_UpdateID", "ui4"),
+            ("SystemUpdateID", "ui4"),
+            ("SearchCapabilities", "string"),
+            ("SortCapabilities", "string"),
+        ],
+    )
+}
+
+fn connection_manager_scpd() -> String {
+    scpd(
+        &[
+            (
+                "GetProtocolInfo",
+                &[
+                    ("Source", "out", "SourceProtocolInfo"),
+                    ("Sink", "out", "SinkProtocolInfo"),
+                ],
+            ),
+            (
+                "GetCurrentConnectionIDs",
+                &[("ConnectionIDs", "out", "CurrentConnectionIDs")],
+            ),
+        ],
+        &[
+            ("SourceProtocolInfo", "string"),
+            ("SinkProtocolInfo", "string"),
+            ("CurrentConnectionIDs", "string"),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Pixmap;
+
+    fn device(stream: Option<&str>) -> Device {
+        Device {
+            options: DlnaOptions {
+                listen: "0.0.0.0:8200".parse().unwrap(),
+                name: "Pixel & Party".to_string(),
+                stream: stream.map(|i| Url::parse(i).unwrap()),
+            },
+            pixmap: Arc::new(Pixmap::new(4, 2).unwrap()),
+            uuid: device_uuid("Pixel & Party"),
+            port: 8200,
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let device = device(None);
+        assert_eq!(device.uuid, device_uuid("Pixel & Party"));
+        assert_ne!(device.uuid, device_uuid("Pixel Party"));
+        assert_eq!(device.uuid.len(), 36);
+
+        let search =
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+                      ST: urn:schemas-upnp-org:device:MediaServer:1\r\n\r\n";
+        let target = search_target(search).unwrap();
+        let responses = search_responses(&device, target, "192.0.2.1".parse().unwrap());
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains("LOCATION: http://192.0.2.1:8200/description.xml\r\n"));
+        assert!(responses[0].contains(&format!("USN: uuid:{}::{}\r\n", device.uuid, DEVICE_TYPE)));
+
+        assert_eq!(
+            search_responses(&device, "ssdp:all", "192.0.2.1".parse().unwrap()).len(),
+            5
+        );
+        assert!(search_responses(
+            &device,
+            "urn:schemas-upnp-org:device:MediaRenderer:1",
+            Ipv4Addr::LOCALHOST.into()
+        )
+        .is_empty());
+        assert_eq!(search_target("NOTIFY * HTTP/1.1\r\nST: ssdp:all\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_only_local_peers_are_answered() {
+        for ip in [
+            "192.168.1.20",
+            "10.0.0.1",
+            "172.16.5.4",
+            "169.254.1.1",
+            "127.0.0.1",
+            "fe80::1",
+        ] {
+            assert!(is_local_peer(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["198.51.100.7", "8.8.8.8", "2001:db8::1"] {
+            assert!(!is_local_peer(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_head_is_limited() {
+        let request = b"POST /ContentDirectory/control HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        let request = read_request(&mut &request[..]).await.unwrap();
+        assert_eq!(
+            (request.path.as_str(), request.body.as_str()),
+            ("/ContentDirectory/control", "body")
+        );
+
+        let endless = vec![b'a'; MAX_REQUEST_SIZE * 2];
+        assert!(read_request(&mut &endless[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_browse() {
+        let device = device(Some("http://192.0.2.1/live/canvas.m3u8"));
+        let host = "192.0.2.1:8200".parse().unwrap();
+        let browse = |object: &str, flag: &str| {
+            respond(
+                &device,
+                &Request {
+                    method: "POST".to_string(),
+                    path: "/ContentDirectory/control".to_string(),
+                    soap_action: Some(format!("{}#Browse", CONTENT_DIRECTORY)),
+                    body: envelope(&format!(
+                        "<u:Browse xmlns:u=\"{CONTENT_DIRECTORY}\"><ObjectID>{object}</ObjectID>\
+                        <BrowseFlag>{flag}</BrowseFlag><Filter>*</Filter><StartingIndex>0</StartingIndex>\
+                        <RequestedCount>0</RequestedCount><SortCriteria/></u:Browse>"
+                    )),
+                },
+                host,
+            )
+        };
+
+        let response = browse("0", "BrowseDirectChildren");
+        assert_eq!(response.status, "200 OK");
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(xml_element(&body, "NumberReturned"), Some("2"));
+        let didl = xml_element(&body, "Result").unwrap();
+        assert!(didl.contains("http://192.0.2.1:8200/canvas.png"));
+        assert!(didl.contains("http-get:*:application/vnd.apple.mpegurl:*"));
+        assert!(didl.contains("Pixel &amp;amp; Party"));
+
+        let body = String::from_utf8(browse("0", "BrowseMetadata").body).unwrap();
+        assert!(xml_element(&body, "Result")
+            .unwrap()
+            .contains("childCount=&quot;2&quot;"));
+
+        let response = browse("missing", "BrowseMetadata");
+        assert_eq!(response.status, "500 Internal Server Error");
+        assert_eq!(
+            xml_element(&String::from_utf8(response.body).unwrap(), "errorCode"),
+            Some("701")
+        );
+
+        // actions of other services are rejected without echoing the service
+        let response = respond(
+            &device,
+            &Request {
+                method: "POST".to_string(),
+                path: "/ContentDirectory/control".to_string(),
+                soap_action: Some("\"><script/>#Browse".to_string()),
+                ..Request::default()
+            },
+            host,
+        );
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(xml_element(&body, "errorCode"), Some("401"));
+        assert!(!body.contains("<script/>"));
+
+        let request = |path: &str| Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            ..Request::default()
+        };
+        let description =
+            String::from_utf8(respond(&device, &request("/description.xml"), host).body).unwrap();
+        assert_eq!(
+            xml_element(&description, "friendlyName"),
+            Some("Pixel &amp; Party")
+        );
+        assert!(respond(&device, &request("/canvas.png"), host)
+            .body
+            .starts_with(b"\x89PNG"));
+        assert_eq!(
+            respond(&device, &request("/missing"), host).status,
+            "404 Not Found"
+        );
+    }
+}
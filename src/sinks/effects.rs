@@ -0,0 +1,128 @@
+//! A sink which renders the canvas through a visual effect into a second pixmap from which other sinks display it
+
+use crate::pixmap::{gather, CanvasSnapshot, Effect, Pixmap, SharedPixmap};
+use crate::watchdog::Heartbeat;
+use crate::DaemonResult;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Interval;
+
+/// Configuration options for the [`EffectSink`]
+#[derive(Debug)]
+pub struct EffectSinkOptions {
+    /// The interval in which the output is rendered
+    pub interval: Interval,
+    /// The effect which is applied, changing it at runtime takes effect with the next rendered frame
+    pub effect: watch::Receiver<Effect>,
+    /// A heartbeat which is beaten after every rendered frame
+    pub heartbeat: Option<Heartbeat>,
+}
+
+/// A sink that renders the pixmap through an [`Effect`] into an output pixmap
+///
+/// Clients keep writing to the original pixmap while display sinks are attached to [`EffectSink::output`] so that
+/// they show the transformed image.
+#[derive(Debug)]
+pub struct EffectSink {
+    options: EffectSinkOptions,
+    pixmap: SharedPixmap,
+    output: SharedPixmap,
+}
+
+impl EffectSink {
+    /// Create a new sink which renders the given pixmap into an output pixmap of the same size
+    pub fn new(options: EffectSinkOptions, pixmap: SharedPixmap) -> Self {
+        let (width, height) = pixmap.get_size();
+        let output = Pixmap::new(width, height).expect("the output has the size of an existing pixmap");
+        Self {
+            options,
+            pixmap,
+            output: Arc::new(output),
+        }
+    }
+
+    /// Get the pixmap into which the transformed canvas is rendered
+    pub fn output(&self) -> SharedPixmap {
+        self.output.clone()
+    }
+
+    /// Start the background task which periodically renders the output
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        let handle = join_set
+            .build_task()
+            .name("effects")
+            .spawn(async move { self.run().await })?;
+        Ok(handle)
+    }
+
+    /// Execute the main loop which renders a new frame whenever the canvas or the effect changed
+    async fn run(mut self) -> anyhow::Result<!> {
+        let (width, height) = self.pixmap.get_size();
+        let mut rendered = None;
+        let mut indices: Arc<[usize]> = Arc::new([]);
+        loop {
+            self.options.interval.tick().await;
+            if let Some(heartbeat) = &self.options.heartbeat {
+                heartbeat.beat();
+            }
+
+            let effect = *self.options.effect.borrow_and_update();
+            let snapshot = self.pixmap.checkpoint();
+            if rendered == Some((effect, snapshot.generation())) {
+                continue;
+            }
+            if rendered.is_none_or(|(previous, _)| previous != effect) {
+                tracing::info!("Rendering the canvas with the {} effect", effect);
+                indices = tokio::task::spawn_blocking(move || effect.source_indices(width, height))
+                    .await?
+                    .into();
+            }
+
+            let generation = snapshot.generation();
+            let output = self.output.clone();
+            let frame_indices = indices.clone();
+            tokio::task::spawn_blocking(move || {
+                let pixels = gather(&snapshot, &frame_indices);
+                output.restore(&CanvasSnapshot::new(width, height, pixels.into(), generation))
+            })
+            .await??;
+            rendered = Some((effect, generation));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::Color;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_render_effect() {
+        let pixmap = Arc::new(Pixmap::new(4, 2).unwrap());
+        pixmap.set_pixel(0, 0, Color::from(0xff0000)).unwrap();
+        let (effect, receiver) = watch::channel(Effect::None);
+        let sink = EffectSink::new(
+            EffectSinkOptions {
+                interval: tokio::time::interval(Duration::from_millis(5)),
+                effect: receiver,
+                heartbeat: None,
+            },
+            pixmap.clone(),
+        );
+        let output = sink.output();
+        let mut join_set = JoinSet::new();
+        sink.start(&mut join_set).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(output.get_pixel(0, 0).unwrap(), Color::from(0xff0000));
+        assert_eq!(output.get_pixel(3, 0).unwrap(), Color::from(0));
+
+        effect.send_replace(Effect::MirrorHorizontal);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(output.get_pixel(3, 0).unwrap(), Color::from(0xff0000));
+        // the canvas itself is left untouched
+        assert_eq!(pixmap.get_pixel(3, 0).unwrap(), Color::from(0));
+    }
+}
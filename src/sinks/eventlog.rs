@@ -0,0 +1,513 @@
+//! A sink which appends every change of the canvas to a binary event log so that the history of the canvas can be
+//! analyzed or replayed with exact data after an event
+//!
+//! A log starts with the magic bytes [`EVENT_LOG_MAGIC`] followed by the canvas width and height as big-endian u32.
+//! Every entry then consists of a big-endian u64 unix timestamp in milliseconds, a tag byte and the tag's payload:
+//!
+//! - `0x01`: a single pixel as u32 `x`, u32 `y` and the `r`, `g`, `b` bytes of its new color
+//! - `0x02`: a region as u32 `x`, `y`, `width` and `height` followed by the `r`, `g`, `b` bytes of all of its pixels
+//!   row by row
+//!
+//! All integers are big-endian. Whenever recording starts and whenever the sink could not keep up with the changes,
+//! the whole canvas is written as one region so that replaying a log always reproduces the canvas exactly.
+//! Logs of an earlier run are continued if the canvas still has the same size. Otherwise, e.g. after the canvas was
+//! resized, the old log is moved to a numbered file next to it like `canvas.1.evlog` and a new one is started.
+
+use crate::pixmap::{Color, PixelChange, Pixmap, SharedPixmap};
+use crate::DaemonResult;
+use anyhow::anyhow;
+use std::io::{ErrorKind, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+/// The bytes with which every event log starts
+pub const EVENT_LOG_MAGIC: &[u8; 8] = b"PXEVLOG1";
+
+/// The tag of an entry which contains a single pixel
+const TAG_PIXEL: u8 = 0x01;
+
+/// The tag of an entry which contains a region
+const TAG_REGION: u8 = 0x02;
+
+/// Something that happened to the canvas
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// One pixel was set to a new color
+    Pixel {
+        /// The x coordinate of the pixel
+        x: usize,
+        /// The y coordinate of the pixel
+        y: usize,
+        /// The new color of the pixel
+        color: Color,
+    },
+    /// All pixels in a rectangular region were overwritten
+    Region {
+        /// The x coordinate of the regions top-left corner
+        x: usize,
+        /// The y coordinate of the regions top-left corner
+        y: usize,
+        /// The width of the region
+        width: usize,
+        /// The height of the region
+        height: usize,
+        /// The new colors of all pixels in the region, row by row
+        data: Vec<Color>,
+    },
+}
+
+impl Event {
+    /// Make the same change to `pixmap`
+    pub fn apply(&self, pixmap: &Pixmap) -> anyhow::Result<()> {
+        match self {
+            Event::Pixel { x, y, color } => pixmap.set_pixel(*x, *y, *color)?,
+            Event::Region {
+                x,
+                y,
+                width,
+                height,
+                data,
+            } => pixmap.set_region(*x, *y, *width, *height, data)?,
+        }
+        Ok(())
+    }
+}
+
+/// One entry of an event log
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LogEntry {
+    /// When the event was recorded, with millisecond precision
+    pub timestamp: SystemTime,
+    /// What happened
+    pub event: Event,
+}
+
+impl LogEntry {
+    /// Record that `event` happened right now
+    pub fn now(event: Event) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            event,
+        }
+    }
+
+    /// Append the binary form of this entry to `buf`
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let millis = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        buf.extend_from_slice(&millis.to_be_bytes());
+        match &self.event {
+            Event::Pixel { x, y, color } => {
+                buf.push(TAG_PIXEL);
+                buf.extend_from_slice(&(*x as u32).to_be_bytes());
+                buf.extend_from_slice(&(*y as u32).to_be_bytes());
+                buf.extend_from_slice(&<[u8; 3]>::from(*color));
+            }
+            Event::Region {
+                x,
+                y,
+                width,
+                height,
+                data,
+            } => {
+                buf.push(TAG_REGION);
+                for i in [x, y, width, height] {
+                    buf.extend_from_slice(&(*i as u32).to_be_bytes());
+                }
+                buf.extend(data.iter().flat_map(|&color| <[u8; 3]>::from(color)));
+            }
+        }
+    }
+}
+
+/// Write the header of a new log for a canvas of the given size into `buf`
+pub fn write_header(width: usize, height: usize, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(EVENT_LOG_MAGIC);
+    buf.extend_from_slice(&(width as u32).to_be_bytes());
+    buf.extend_from_slice(&(height as u32).to_be_bytes());
+}
+
+/// Reads the entries of an event log one after another
+#[derive(Debug)]
+pub struct EventLogReader<R> {
+    reader: R,
+    width: usize,
+    height: usize,
+    /// How many bytes of complete entries and the header have been read
+    position: u64,
+}
+
+impl<R: Read> EventLogReader<R> {
+    /// Read the header of an event log
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != EVENT_LOG_MAGIC {
+            return Err(anyhow!("not a pixeldike event log"));
+        }
+        Ok(Self {
+            reader,
+            width: u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize,
+            height: u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize,
+            position: header.len() as u64,
+        })
+    }
+
+    /// The size of the canvas whose events are logged
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// How many bytes of the log have been read up to the end of the last complete entry
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read the next entry or `None` if the log ends
+    fn read_entry(&mut self) -> anyhow::Result<Option<LogEntry>> {
+        let mut head = [0u8; 9];
+        match self.reader.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let millis = u64::from_be_bytes(head[..8].try_into().unwrap());
+        let mut read_u32s = |values: &mut [usize]| -> std::io::Result<()> {
+            for value in values {
+                let mut bytes = [0u8; 4];
+                self.reader.read_exact(&mut bytes)?;
+                *value = u32::from_be_bytes(bytes) as usize;
+            }
+            Ok(())
+        };
+        let (event, len) = match head[8] {
+            TAG_PIXEL => {
+                let mut coords = [0; 2];
+                read_u32s(&mut coords)?;
+                let mut rgb = [0u8; 3];
+                self.reader.read_exact(&mut rgb)?;
+                let [x, y] = coords;
+                (
+                    Event::Pixel {
+                        x,
+                        y,
+                        color: rgb.into(),
+                    },
+                    11,
+                )
+            }
+            TAG_REGION => {
+                let mut rect = [0; 4];
+                read_u32s(&mut rect)?;
+                let [x, y, width, height] = rect;
+                if x.saturating_add(width) > self.width || y.saturating_add(height) > self.height {
+                    return Err(anyhow!("logged region is not inside the canvas"));
+                }
+                let mut rgb = vec![0u8; width * height * 3];
+                self.reader.read_exact(&mut rgb)?;
+                let data = rgb
+                    .chunks_exact(3)
+                    .map(|rgb| Color::from((rgb[0], rgb[1], rgb[2])))
+                    .collect();
+                let event = Event::Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data,
+                };
+                (event, 16 + rgb.len() as u64)
+            }
+            tag => return Err(anyhow!("unknown event log entry tag {:#04x}", tag)),
+        };
+        self.position += head.len() as u64 + len;
+        Ok(Some(LogEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(millis),
+            event,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for EventLogReader<R> {
+    type Item = anyhow::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// Configuration options for the [`EventLogSink`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EventLogOptions {
+    /// The path of the log
+    pub path: PathBuf,
+    /// How often buffered entries are written to the file at the latest
+    pub flush_interval: Duration,
+}
+
+/// A sink that appends every change of the canvas to an event log
+#[derive(Debug)]
+pub struct EventLogSink {
+    options: EventLogOptions,
+    pixmap: SharedPixmap,
+}
+
+impl EventLogSink {
+    /// Create a new sink which records the changes of the given pixmap
+    pub fn new(options: EventLogOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Open the log and start the background task which records changes into it
+    ///
+    /// An existing log is continued after its last complete entry or moved aside if it was recorded for a canvas of
+    /// a different size. This fails if the canvas doesn't announce its changes.
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        if self.pixmap.changes().is_none() {
+            return Err(anyhow!("event logs require a canvas which announces its changes"));
+        }
+        let (width, height) = self.pixmap.get_size();
+        let mut file = open_log(&self.options.path).await?;
+        let mut len = file.metadata().await?.len();
+        if len > 0 {
+            let path = self.options.path.clone();
+            match tokio::task::spawn_blocking(move || valid_len(&path, (width, height))).await?? {
+                Some(valid) => {
+                    // an entry which was only partially written when the server stopped is discarded
+                    file.set_len(valid).await?;
+                    len = valid;
+                }
+                None => {
+                    drop(file);
+                    let rotated = super::unused_numbered_path(&self.options.path);
+                    tokio::fs::rename(&self.options.path, &rotated).await?;
+                    tracing::info!(
+                        "Event log {} was recorded for a canvas of a different size, moved it to {}",
+                        self.options.path.display(),
+                        rotated.display()
+                    );
+                    file = open_log(&self.options.path).await?;
+                    len = 0;
+                }
+            }
+        }
+        if len == 0 {
+            let mut header = Vec::new();
+            write_header(width, height, &mut header);
+            file.write_all(&header).await?;
+            len = header.len() as u64;
+        }
+        file.seek(SeekFrom::Start(len)).await?;
+        let file = BufWriter::new(file);
+
+        let handle = join_set
+            .build_task()
+            .name("eventlog")
+            .spawn(async move { self.run(file).await })?;
+        Ok(handle)
+    }
+
+    /// Record changes until the canvas stops announcing them
+    async fn run(self, mut file: BufWriter<File>) -> anyhow::Result<!> {
+        let mut subscription = self
+            .pixmap
+            .changes()
+            .ok_or(anyhow!("event logs require a canvas which announces its changes"))?
+            .subscribe();
+        let mut flush = tokio::time::interval(self.options.flush_interval);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut buf = Vec::new();
+        LogEntry::now(keyframe(&self.pixmap)?).write(&mut buf);
+        loop {
+            file.write_all(&buf).await?;
+            buf.clear();
+            let event = loop {
+                tokio::select! {
+                    change = subscription.recv() => break change,
+                    _ = flush.tick() => file.flush().await?,
+                }
+            };
+            let event = match event {
+                Ok(PixelChange::Pixel { x, y, color }) => Event::Pixel { x, y, color },
+                Ok(PixelChange::Region { x, y, width, height }) => Event::Region {
+                    x,
+                    y,
+                    width,
+                    height,
+                    data: self.pixmap.get_region(x, y, width, height)?,
+                },
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!(
+                        "Event log missed {} changes, recording the whole canvas instead",
+                        n
+                    );
+                    keyframe(&self.pixmap)?
+                }
+                Err(RecvError::Closed) => {
+                    file.flush().await?;
+                    return Err(anyhow!("the canvas stopped announcing its changes"));
+                }
+            };
+            LogEntry::now(event).write(&mut buf);
+        }
+    }
+}
+
+/// An event which sets the whole canvas to its current content
+fn keyframe(pixmap: &Pixmap) -> anyhow::Result<Event> {
+    let (width, height) = pixmap.get_size();
+    Ok(Event::Region {
+        x: 0,
+        y: 0,
+        width,
+        height,
+        data: pixmap.get_region(0, 0, width, height)?,
+    })
+}
+
+/// Open the log at `path` for reading and writing without truncating it
+async fn open_log(path: &Path) -> std::io::Result<File> {
+    tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .await
+}
+
+/// Determine how many bytes of an existing log consist of complete entries or `None` if it was recorded for a canvas
+/// of a different size
+fn valid_len(path: &Path, size: (usize, usize)) -> anyhow::Result<Option<u64>> {
+    let mut reader = EventLogReader::new(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    if reader.size() != size {
+        return Ok(None);
+    }
+    while let Some(Ok(_)) = reader.next() {}
+    Ok(Some(reader.position()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_read_written_entries() {
+        let entries = [
+            LogEntry::now(Event::Pixel {
+                x: 1,
+                y: 2,
+                color: Color::from(0xFF0000),
+            }),
+            LogEntry::now(Event::Region {
+                x: 0,
+                y: 1,
+                width: 2,
+                height: 1,
+                data: vec![Color::from(0x00FF00), Color::from(0x0000FF)],
+            }),
+        ];
+        let mut buf = Vec::new();
+        write_header(4, 4, &mut buf);
+        for entry in &entries {
+            entry.write(&mut buf);
+        }
+        let complete = buf.len() as u64;
+        // a partially written entry at the end is not returned
+        buf.extend_from_slice(&[0, 0, 1]);
+
+        let mut reader = EventLogReader::new(&buf[..]).unwrap();
+        assert_eq!(reader.size(), (4, 4));
+        let read = (&mut reader).map_while(Result::ok).collect::<Vec<_>>();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(&entries) {
+            assert_eq!(read.event, written.event);
+            assert_eq!(
+                read.timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis(),
+                written.timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis()
+            );
+        }
+        assert_eq!(reader.position(), complete);
+        assert!(EventLogReader::new(&b"PXEVLOG0\0\0\0\x04\0\0\0\x04"[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("canvas.evlog");
+        let pixmap = Arc::new(
+            Pixmap::new(4, 4)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        pixmap.set_pixel(0, 0, Color::from(0x123456)).unwrap();
+        let options = EventLogOptions {
+            path: path.clone(),
+            flush_interval: Duration::from_millis(10),
+        };
+        let mut join_set = JoinSet::new();
+        EventLogSink::new(options.clone(), pixmap.clone())
+            .start(&mut join_set)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pixmap.set_pixel(3, 3, Color::from(0xFF0000)).unwrap();
+        pixmap
+            .set_region(1, 1, 1, 2, &[Color::from(0x00FF00); 2])
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        join_set.shutdown().await;
+
+        // replaying the log reproduces the canvas
+        let replayed = Pixmap::new(4, 4).unwrap();
+        let reader = EventLogReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let entries = reader.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            entry.event.apply(&replayed).unwrap();
+        }
+        assert_eq!(
+            replayed.get_region(0, 0, 4, 4).unwrap(),
+            pixmap.get_region(0, 0, 4, 4).unwrap()
+        );
+
+        // the log is continued by the next run
+        EventLogSink::new(options.clone(), pixmap.clone())
+            .start(&mut join_set)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        join_set.shutdown().await;
+        let reader = EventLogReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.count(), 4);
+
+        // and moved aside once the canvas has a different size
+        let smaller = Arc::new(
+            Pixmap::new(2, 2)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        EventLogSink::new(options, smaller)
+            .start(&mut join_set)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        join_set.shutdown().await;
+        let reader = EventLogReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.size(), (2, 2));
+        assert_eq!(reader.count(), 1);
+        let rotated = dir.path().join("canvas.1.evlog");
+        let reader = EventLogReader::new(std::fs::File::open(rotated).unwrap()).unwrap();
+        assert_eq!(reader.size(), (4, 4));
+        assert_eq!(reader.count(), 4);
+    }
+}
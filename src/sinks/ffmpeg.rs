@@ -1,6 +1,7 @@
 //! A sink which pipes the canvas into ffmpeg for video encoding or streaming
 
 use crate::pixmap::SharedPixmap;
+use crate::watchdog::Heartbeat;
 use crate::DaemonResult;
 use anyhow::anyhow;
 use std::process::Stdio;
@@ -22,13 +23,16 @@ use tokio::task::{AbortHandle, JoinSet};
 ///
 /// ```rust
 /// # use pixeldike::sinks::ffmpeg::FfmpegOptions;
+/// # use std::time::Duration;
 ///
 /// const FPS: usize = 10;
 /// let options = FfmpegOptions {
 ///     framerate: FPS,
 ///     synthesize_audio: true,
 ///     log_level: "warning".to_string(),
-///     output_spec: FfmpegOptions::make_rtsp_out_spec("rtsp://localhost:8554/pixelflut", FPS)
+///     output_spec: FfmpegOptions::make_rtsp_out_spec("rtsp://localhost:8554/pixelflut", FPS),
+///     heartbeat: None,
+///     start_delay: Duration::ZERO,
 /// };
 /// ```
 ///
@@ -36,6 +40,7 @@ use tokio::task::{AbortHandle, JoinSet};
 ///
 /// ```rust
 /// # use pixeldike::sinks::ffmpeg::FfmpegOptions;
+/// # use std::time::Duration;
 ///
 /// const FPS: usize = 10;
 /// let options = FfmpegOptions {
@@ -48,7 +53,9 @@ use tokio::task::{AbortHandle, JoinSet};
 ///     ]
 ///     .into_iter()
 ///     .flatten()
-///     .collect()
+///     .collect(),
+///     heartbeat: None,
+///     start_delay: Duration::ZERO,
 /// };
 /// ```
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -69,6 +76,12 @@ pub struct FfmpegOptions {
 
     /// Additional ffmpeg arguments that should be placed in the output part of the generated command.
     pub output_spec: Vec<String>,
+
+    /// Through which the sink reports each frame to a [`Watchdog`](crate::watchdog::Watchdog).
+    pub heartbeat: Option<Heartbeat>,
+
+    /// How long to wait before the first frame is emitted, e.g. to stagger expensive work during startup.
+    pub start_delay: Duration,
 }
 
 impl FfmpegOptions {
@@ -192,9 +205,9 @@ impl FfmpegSink {
             .arg("rgb24")
             // provide metadata since it is not included in the rawvideo format
             .arg("-video_size")
-            .arg(&format!("{}x{}", width, height))
+            .arg(format!("{}x{}", width, height))
             .arg("-framerate")
-            .arg(&self.options.framerate.to_string())
+            .arg(self.options.framerate.to_string())
             // tell ffmpeg that it should read input from stdin
             .arg("-i")
             .arg("/dev/stdin");
@@ -223,18 +236,22 @@ impl FfmpegSink {
             return Err(anyhow!("ffmpegs stdin is not attached"));
         };
 
+        tokio::time::sleep(self.options.start_delay).await;
         let mut interval =
             tokio::time::interval(Duration::from_secs_f64(1.0 / self.options.framerate as f64));
 
         loop {
-            let data = unsafe {
-                self.pixmap
-                    .get_color_data()
-                    .iter()
-                    .flat_map(|c| Into::<[u8; 3]>::into(*c))
-                    .collect::<Vec<_>>()
-            };
+            let data = self
+                .pixmap
+                .present_frame()
+                .pixels()
+                .iter()
+                .flat_map(|c| Into::<[u8; 3]>::into(*c))
+                .collect::<Vec<_>>();
             channel.write_all(&data).await.expect("Could not write to ffmpeg");
+            if let Some(heartbeat) = &self.options.heartbeat {
+                heartbeat.beat();
+            }
 
             interval.tick().await;
         }
@@ -1,6 +1,7 @@
 //! A sink implementation for drawing on a linux framebuffer
 
-use crate::pixmap::{Color, SharedPixmap};
+use crate::pixmap::{CanvasSnapshot, Color, Pixmap, SharedPixmap};
+use crate::watchdog::Heartbeat;
 use crate::DaemonResult;
 use anyhow::Context;
 use framebuffer::{Bitfield, Framebuffer};
@@ -10,54 +11,36 @@ use tokio::task::{AbortHandle, JoinSet};
 use tokio::time::{interval, Instant, MissedTickBehavior};
 use tracing::info;
 
+/// Which pixmap pixels are shown on which screen pixels when the screen and pixmap have different sizes
 #[derive(Debug, Clone, Eq, PartialEq)]
 struct Sampler {
-    /// A mapping of screen-pixel-index to pixmap-pixel-index
+    /// A mapping of screen-column to pixmap-column
     ///
     /// If it is `None`, no mapping needs to be done because the screen and pixmap have the same sizes
-    mapping: Option<Vec<u32>>,
+    columns: Option<Vec<usize>>,
+    /// A mapping of screen-row to pixmap-row
+    rows: Vec<usize>,
 }
 
 impl Sampler {
     pub fn new(src_width: usize, src_height: usize, out_width: usize, out_height: usize) -> Self {
+        let rows = (0..out_height)
+            .map(|screen_y| (screen_y * src_height) / out_height)
+            .collect();
         if src_width == out_width && src_height == out_height {
-            Self { mapping: None }
+            Self { columns: None, rows }
         } else {
             tracing::warn!("Framebuffer has size {}x{} while pixmap has size {}x{}. This requires an additional sampling step which slows down rendering", out_width, out_height, src_width, src_height);
             Self {
-                mapping: Some(
-                    (0..out_width * out_height)
-                        .map(|i_screen_px| {
-                            let screen_x = i_screen_px % out_width;
-                            let screen_y = i_screen_px / out_width;
-                            let px_x = (screen_x * src_width) / out_width;
-                            let px_y = (screen_y * src_height) / out_height;
-                            (px_y * src_width + px_x) as u32
-                        })
+                columns: Some(
+                    (0..out_width)
+                        .map(|screen_x| (screen_x * src_width) / out_width)
                         .collect(),
                 ),
+                rows,
             }
         }
     }
-
-    pub fn needs_sampling(&self) -> bool {
-        self.mapping.is_some()
-    }
-
-    #[allow(unused)]
-    #[inline(always)]
-    pub fn get_mapping(&self, i_screen_px: usize) -> Option<usize> {
-        self.mapping.as_ref()?.get(i_screen_px).map(|x| *x as usize)
-    }
-
-    #[inline(always)]
-    pub unsafe fn get_mappin_unchecked(&self, i_screen_px: usize) -> usize {
-        *self
-            .mapping
-            .as_ref()
-            .unwrap_unchecked()
-            .get_unchecked(i_screen_px) as usize
-    }
 }
 
 /// Options for configuring a [`FramebufferSink`]
@@ -67,6 +50,10 @@ pub struct FramebufferSinkOptions {
     pub path: PathBuf,
     /// How many frames per second should be rendered
     pub framerate: usize,
+    /// Through which the sink reports each rendered frame to a [`Watchdog`](crate::watchdog::Watchdog)
+    pub heartbeat: Option<Heartbeat>,
+    /// How long to wait before the first frame is rendered, e.g. to stagger expensive work during startup
+    pub start_delay: Duration,
 }
 
 /// A sink that periodically renders pixmap data onto a framebuffer device
@@ -113,6 +100,7 @@ impl FramebufferSink {
 
     /// Render in a loop at the desired framerate (or as close to it as possible)
     async fn render(self, mut fb: Framebuffer) -> anyhow::Result<!> {
+        tokio::time::sleep(self.options.start_delay).await;
         let mut interval = interval(Duration::from_secs_f64(1.0 / self.options.framerate as f64));
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
@@ -121,13 +109,12 @@ impl FramebufferSink {
         let screen_height = fb.var_screen_info.yres as usize;
         let sampler = Sampler::new(pixmap_width, pixmap_height, screen_width, screen_height);
 
-        let fb_pixels = screen_width * screen_height;
         let encoder = Encoder {
             r: fb.var_screen_info.red.clone(),
             g: fb.var_screen_info.green.clone(),
             b: fb.var_screen_info.blue.clone(),
         };
-        let renderer = Renderer { sampler, encoder };
+        let mut renderer = Renderer::new(sampler, encoder);
 
         let bits_per_pixel = fb.var_screen_info.bits_per_pixel as usize;
         let render_once_fn = match bits_per_pixel {
@@ -138,17 +125,16 @@ impl FramebufferSink {
                 bits_per_pixel
             ),
         };
+        let line_length = fb.fix_screen_info.line_length as usize;
 
         loop {
             let t1 = Instant::now();
-            render_once_fn(
-                &&renderer,
-                unsafe { self.pixmap.get_color_data() },
-                &mut fb,
-                fb_pixels,
-            );
+            let rows = render_once_fn(&mut renderer, &self.pixmap, &mut fb.frame[..], line_length);
             let t2 = Instant::now();
-            info!("Render: {}ms", (t2 - t1).as_millis());
+            info!("Render: {}ms ({} rows changed)", (t2 - t1).as_millis(), rows);
+            if let Some(heartbeat) = &self.options.heartbeat {
+                heartbeat.beat();
+            }
             interval.tick().await;
         }
     }
@@ -157,55 +143,106 @@ impl FramebufferSink {
 /// A little helper struct that provides a generic render method.
 /// You can call render with any type T, as long as T: Copy and the
 /// Encoder can encode Pixels to T.
+///
+/// Only the screen rows whose pixmap rows changed since the previous frame are converted and written.
 #[derive(Debug, Clone)]
 pub struct Renderer {
     encoder: Encoder,
     sampler: Sampler,
+    /// The pixmap content from which the previous frame was rendered
+    ///
+    /// It is empty before the first frame.
+    previous: Vec<Color>,
+    /// The pixmap generation from which the previous frame was rendered
+    generation: Option<u64>,
 }
 
 impl Renderer {
-    fn render<T: Copy>(&self, pixel_data: &[Color], fb: &mut Framebuffer, fb_pixels: usize)
+    fn new(sampler: Sampler, encoder: Encoder) -> Self {
+        Self {
+            encoder,
+            sampler,
+            previous: Vec::new(),
+            generation: None,
+        }
+    }
+
+    /// Update the snapshot of the pixmap content from a frame and determine which of its rows changed
+    fn dirty_rows(&mut self, frame: &CanvasSnapshot) -> Vec<bool> {
+        let (width, height) = frame.get_size();
+        let data = frame.pixels();
+        if self.previous.len() != data.len() {
+            self.previous = data.to_vec();
+            return vec![true; height];
+        }
+        data.chunks_exact(width)
+            .zip(self.previous.chunks_exact_mut(width))
+            .map(|(row, previous)| {
+                let dirty = row != previous;
+                if dirty {
+                    previous.copy_from_slice(row);
+                }
+                dirty
+            })
+            .collect()
+    }
+
+    /// Render all changed rows of the pixmap into a frame whose rows are `line_length` bytes apart
+    ///
+    /// Returns how many screen rows were written.
+    fn render<T: Copy + Default>(&mut self, pixmap: &Pixmap, frame: &mut [u8], line_length: usize) -> usize
     where
         Encoder: Encode<T>,
     {
-        // encode pixel data into framebuffer format
-        let encoded: Vec<T> = self.encoder.encode_vec(pixel_data);
+        let content = pixmap.present_frame();
+        if self.generation == Some(content.generation()) {
+            return 0;
+        }
+        self.generation = Some(content.generation());
+        let dirty_rows = self.dirty_rows(&content);
+        // release the presented frame so that its buffer can be reused for the next one
+        drop(content);
 
-        // sample pixels to framebuffer size
-        let pixels = if self.sampler.needs_sampling() {
-            let sampled = sample_vec(&self.sampler, &encoded, fb_pixels);
-            sampled
-        } else {
-            encoded
-        };
+        let (width, _) = pixmap.get_size();
+        let mut encoded = vec![T::default(); width];
+        let mut sampled = vec![T::default(); self.sampler.columns.as_ref().map_or(0, Vec::len)];
+        let mut encoded_row = None;
+        let mut written = 0;
+        for (screen_y, &y) in self.sampler.rows.iter().enumerate() {
+            if !dirty_rows[y] {
+                continue;
+            }
+            // neighbouring screen rows may show the same pixmap row when the screen is larger than the pixmap
+            if encoded_row != Some(y) {
+                self.encoder
+                    .encode_into(&self.previous[y * width..(y + 1) * width], &mut encoded);
+                encoded_row = Some(y);
+            }
 
-        // transmute and copy to framebuffer
-        let pixel_bytes = unsafe {
-            let (prefix, bytes, suffix) = pixels.align_to::<u8>();
-            assert_eq!(prefix.len(), 0);
-            assert_eq!(suffix.len(), 0);
-            bytes
-        };
-        fb.write_frame(&pixel_bytes);
-    }
-}
+            // sample pixels to framebuffer size
+            let pixels = match &self.sampler.columns {
+                None => &encoded,
+                Some(columns) => {
+                    for (pixel, &x) in sampled.iter_mut().zip(columns) {
+                        *pixel = encoded[x];
+                    }
+                    &sampled
+                }
+            };
 
-#[inline(always)]
-fn sample_iter<'e, 's: 'e, T: Copy>(
-    sampler: &'s Sampler,
-    encoded: &'e [T],
-    px: usize,
-) -> impl Iterator<Item = T> + 'e {
-    let iter = (0..px).map(|px| unsafe {
-        let sample_px = sampler.get_mappin_unchecked(px);
-        *encoded.get_unchecked(sample_px)
-    });
-    iter
-}
-
-#[inline(always)]
-fn sample_vec<T: Copy>(sampler: &Sampler, encoded: &[T], px: usize) -> Vec<T> {
-    sample_iter(sampler, encoded, px).collect()
+            // transmute and copy to framebuffer
+            let pixel_bytes = unsafe {
+                let (prefix, bytes, suffix) = pixels.align_to::<u8>();
+                assert_eq!(prefix.len(), 0);
+                assert_eq!(suffix.len(), 0);
+                bytes
+            };
+            let offset = screen_y * line_length;
+            frame[offset..offset + pixel_bytes.len()].copy_from_slice(pixel_bytes);
+            written += 1;
+        }
+        written
+    }
 }
 
 /// A Pixel encoder.
@@ -222,9 +259,22 @@ struct Encoder {
 /// The Encode<Target> trait represents the encoding of pixels (Pixel -> Target).
 trait Encode<Target> {
     fn encode_single(&self, px: Color) -> Target;
+
+    /// Encode all pixels of `src` into `dst` which must have the same length
     #[inline(always)]
-    fn encode_vec(&self, pixmap: &[Color]) -> Vec<Target> {
-        pixmap.iter().map(|px| self.encode_single(*px)).collect()
+    fn encode_into(&self, src: &[Color], dst: &mut [Target]) {
+        for (encoded, px) in dst.iter_mut().zip(src) {
+            *encoded = self.encode_single(*px);
+        }
+    }
+}
+
+impl Encoder {
+    /// Whether the framebuffer uses the same XRGB8888 layout in which colors are stored in a pixmap
+    fn is_native_layout(&self) -> bool {
+        [(&self.r, 16), (&self.g, 8), (&self.b, 0)]
+            .iter()
+            .all(|(field, offset)| field.length == 8 && field.offset == *offset)
     }
 }
 
@@ -237,6 +287,158 @@ impl Encode<u32> for Encoder {
         let encoded_c = (px.2 as u32 >> (8 - self.b.length)) << (self.b.offset);
         encoded_r | encoded_b | encoded_c
     }
+
+    fn encode_into(&self, src: &[Color], dst: &mut [u32]) {
+        if self.is_native_layout() {
+            // no conversion necessary, the data can be copied as-is
+            for (encoded, &px) in dst.iter_mut().zip(src) {
+                *encoded = u32::from(px);
+            }
+            return;
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // Safety: NEON support has just been verified
+            return unsafe { neon::encode_u32(self, src, dst) };
+        }
+
+        simd::encode_u32(self, src, dst)
+    }
+}
+
+/// Portable SIMD color encoding which converts [`simd::LANES`] pixels at once on all targets
+mod simd {
+    use super::{Encode, Encoder};
+    use crate::pixmap::Color;
+    use framebuffer::Bitfield;
+    use std::simd::num::SimdUint;
+    use std::simd::Simd;
+
+    /// How many pixels are converted at once
+    pub(super) const LANES: usize = 8;
+
+    /// Shift amounts and mask needed to move one color channel from the 0RGB pixmap format into a framebuffer field
+    struct ChannelShift {
+        right: Simd<u32, LANES>,
+        mask: Simd<u32, LANES>,
+        left: Simd<u32, LANES>,
+    }
+
+    impl ChannelShift {
+        fn new(src_offset: u32, field: &Bitfield) -> Self {
+            Self {
+                right: Simd::splat(src_offset + 8 - field.length),
+                mask: Simd::splat((1 << field.length) - 1),
+                left: Simd::splat(field.offset),
+            }
+        }
+
+        #[inline(always)]
+        fn apply(&self, px: Simd<u32, LANES>) -> Simd<u32, LANES> {
+            ((px >> self.right) & self.mask) << self.left
+        }
+    }
+
+    /// The shifts of all three channels of an encoder
+    struct Shifts([ChannelShift; 3]);
+
+    impl Shifts {
+        fn new(encoder: &Encoder) -> Self {
+            Self([
+                ChannelShift::new(16, &encoder.r),
+                ChannelShift::new(8, &encoder.g),
+                ChannelShift::new(0, &encoder.b),
+            ])
+        }
+
+        #[inline(always)]
+        fn apply(&self, chunk: &[Color]) -> Simd<u32, LANES> {
+            let px = Simd::from_array(std::array::from_fn(|i| u32::from(chunk[i])));
+            let [r, g, b] = &self.0;
+            r.apply(px) | g.apply(px) | b.apply(px)
+        }
+    }
+
+    pub(super) fn encode_u32(encoder: &Encoder, src: &[Color], dst: &mut [u32]) {
+        let shifts = Shifts::new(encoder);
+        let chunks = src.chunks_exact(LANES);
+        let remainder = chunks.remainder();
+        let mut dst_chunks = dst.chunks_exact_mut(LANES);
+        for (chunk, encoded) in chunks.zip(&mut dst_chunks) {
+            shifts.apply(chunk).copy_to_slice(encoded);
+        }
+        for (encoded, px) in dst_chunks.into_remainder().iter_mut().zip(remainder) {
+            *encoded = Encode::<u32>::encode_single(encoder, *px);
+        }
+    }
+
+    pub(super) fn encode_u16(encoder: &Encoder, src: &[Color], dst: &mut [u16]) {
+        let shifts = Shifts::new(encoder);
+        let chunks = src.chunks_exact(LANES);
+        let remainder = chunks.remainder();
+        let mut dst_chunks = dst.chunks_exact_mut(LANES);
+        for (chunk, encoded) in chunks.zip(&mut dst_chunks) {
+            shifts.apply(chunk).cast::<u16>().copy_to_slice(encoded);
+        }
+        for (encoded, px) in dst_chunks.into_remainder().iter_mut().zip(remainder) {
+            *encoded = Encode::<u16>::encode_single(encoder, *px);
+        }
+    }
+}
+
+/// NEON accelerated color encoding which converts four pixels at once, mainly intended for Raspberry Pis
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::{Encode, Encoder};
+    use crate::pixmap::Color;
+    use framebuffer::Bitfield;
+    use std::arch::aarch64::*;
+
+    /// Shift amounts and mask needed to move one color channel from the 0RGB pixmap format into a framebuffer field
+    struct ChannelShift {
+        right: int32x4_t,
+        mask: uint32x4_t,
+        left: int32x4_t,
+    }
+
+    impl ChannelShift {
+        #[target_feature(enable = "neon")]
+        unsafe fn new(src_offset: u32, field: &Bitfield) -> Self {
+            Self {
+                // vshlq shifts right when given a negative shift amount
+                right: vdupq_n_s32(-((src_offset + 8 - field.length) as i32)),
+                mask: vdupq_n_u32((1 << field.length) - 1),
+                left: vdupq_n_s32(field.offset as i32),
+            }
+        }
+
+        #[inline]
+        #[target_feature(enable = "neon")]
+        unsafe fn apply(&self, px: uint32x4_t) -> uint32x4_t {
+            vshlq_u32(vandq_u32(vshlq_u32(px, self.right), self.mask), self.left)
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn encode_u32(encoder: &Encoder, src: &[Color], dst: &mut [u32]) {
+        let r = ChannelShift::new(16, &encoder.r);
+        let g = ChannelShift::new(8, &encoder.g);
+        let b = ChannelShift::new(0, &encoder.b);
+
+        let chunks = src.chunks_exact(4);
+        let remainder = chunks.remainder();
+        let mut dst_chunks = dst.chunks_exact_mut(4);
+        for (chunk, encoded) in chunks.zip(&mut dst_chunks) {
+            // Color is a repr(C) wrapper around u32 so four of them can be loaded as one vector
+            let px = vld1q_u32(chunk.as_ptr() as *const u32);
+            let result = vorrq_u32(vorrq_u32(r.apply(px), g.apply(px)), b.apply(px));
+            vst1q_u32(encoded.as_mut_ptr(), result);
+        }
+        for (encoded, px) in dst_chunks.into_remainder().iter_mut().zip(remainder) {
+            *encoded = Encode::<u32>::encode_single(encoder, *px);
+        }
+    }
 }
 
 impl Encode<u16> for Encoder {
@@ -248,4 +450,84 @@ impl Encode<u16> for Encoder {
         let encoded_c = (px.2 as u16 >> (8 - self.b.length as u16)) << (self.b.offset);
         encoded_r | encoded_b | encoded_c
     }
+
+    fn encode_into(&self, src: &[Color], dst: &mut [u16]) {
+        simd::encode_u16(self, src, dst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bitfield(offset: u32, length: u32) -> Bitfield {
+        Bitfield {
+            offset,
+            length,
+            msb_right: 0,
+        }
+    }
+
+    quickcheck! {
+        fn test_encode_into_matches_single(colors: Vec<u32>) -> bool {
+            let colors: Vec<Color> = colors.into_iter().map(|c| Color::from(c & 0xFFFFFF)).collect();
+            [
+                // XRGB8888
+                (bitfield(16, 8), bitfield(8, 8), bitfield(0, 8)),
+                // XBGR8888
+                (bitfield(0, 8), bitfield(8, 8), bitfield(16, 8)),
+                // RGB565 stored in 32 bits
+                (bitfield(11, 5), bitfield(5, 6), bitfield(0, 5)),
+            ]
+            .into_iter()
+            .all(|(r, g, b)| {
+                let encoder = Encoder { r, g, b };
+                let expected: Vec<u32> = colors.iter().map(|c| encoder.encode_single(*c)).collect();
+                let mut encoded = vec![0u32; colors.len()];
+                Encode::<u32>::encode_into(&encoder, &colors, &mut encoded);
+                encoded == expected
+            })
+        }
+
+        fn test_encode_u16_matches_single(colors: Vec<u32>) -> bool {
+            let colors: Vec<Color> = colors.into_iter().map(|c| Color::from(c & 0xFFFFFF)).collect();
+            // RGB565
+            let encoder = Encoder { r: bitfield(11, 5), g: bitfield(5, 6), b: bitfield(0, 5) };
+            let expected: Vec<u16> = colors.iter().map(|c| encoder.encode_single(*c)).collect();
+            let mut encoded = vec![0u16; colors.len()];
+            Encode::<u16>::encode_into(&encoder, &colors, &mut encoded);
+            encoded == expected
+        }
+    }
+
+    #[test]
+    fn test_render_dirty_rows() {
+        let pixmap = Pixmap::new(4, 2).unwrap();
+        let encoder = Encoder {
+            r: bitfield(16, 8),
+            g: bitfield(8, 8),
+            b: bitfield(0, 8),
+        };
+        // a screen with twice the size of the pixmap and padded rows
+        let line_length = 8 * 4 + 16;
+        let mut frame = vec![0xAAu8; line_length * 4];
+        let mut renderer = Renderer::new(Sampler::new(4, 2, 8, 4), encoder);
+        let pixel = |frame: &[u8], x: usize, y: usize| {
+            u32::from_ne_bytes(frame[y * line_length + x * 4..][..4].try_into().unwrap())
+        };
+
+        assert_eq!(renderer.render::<u32>(&pixmap, &mut frame, line_length), 4);
+        assert_eq!(pixel(&frame, 7, 3), 0);
+        assert_eq!(frame[8 * 4], 0xAA);
+        assert_eq!(renderer.render::<u32>(&pixmap, &mut frame, line_length), 0);
+
+        pixmap.set_pixel(3, 1, Color::from(0x123456)).unwrap();
+        frame.fill(0xAA);
+        assert_eq!(renderer.render::<u32>(&pixmap, &mut frame, line_length), 2);
+        assert_eq!(pixel(&frame, 6, 2), 0x123456);
+        assert_eq!(pixel(&frame, 7, 3), 0x123456);
+        assert_eq!(pixel(&frame, 5, 3), 0);
+        // rows of the unchanged pixmap row were not written again
+        assert_eq!(pixel(&frame, 0, 0), 0xAAAAAAAA);
+    }
 }
@@ -2,8 +2,35 @@
 //! Support for saving pixelflut canvases into various sinks
 //!
 
+pub mod ambient;
+pub mod color_stats;
+pub mod dlna;
+pub mod effects;
+pub mod eventlog;
 pub mod ffmpeg;
 pub mod framebuffer;
 pub mod pixmap_file;
+pub mod timelapse;
+pub mod webhook;
 #[cfg(feature = "windowing")]
 pub mod window;
+
+use std::path::{Path, PathBuf};
+
+/// The first of `<stem>.1.<ext>`, `<stem>.2.<ext>`, ... next to `path` which doesn't exist yet
+///
+/// Sinks move files which they can't continue there instead of overwriting them.
+pub(crate) fn unused_numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let numbered = |i: usize| {
+        let name = match path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, i, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, i),
+        };
+        path.with_file_name(name)
+    };
+    (1..)
+        .map(numbered)
+        .find(|path| !path.exists())
+        .expect("there is an unused number")
+}
@@ -1,24 +1,46 @@
 //! A sink for periodically snapshotting the canvas into a pixmap file
+//!
+//! Snapshots are either written in pixeldike's own uncompressed pixmap format or as standard PNG images which can
+//! be opened by any image viewer.
 
-use crate::pixmap::{Pixmap, SharedPixmap};
+use crate::pixmap::{decode_png, encode_png, Pixmap, SharedPixmap, PNG_SIGNATURE};
+#[cfg(feature = "signing")]
+use crate::signing::Signer;
+use crate::watchdog::Heartbeat;
 use crate::DaemonResult;
 use anyhow::anyhow;
 use itertools::Itertools;
 use std::io::SeekFrom;
-use std::mem;
 use std::path::{Path, PathBuf};
+#[cfg(feature = "signing")]
+use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::task::{AbortHandle, JoinSet};
 use tokio::time::Interval;
 
 const FILE_MAGIC: &[u8] = b"PIXELFLUT";
-const HEADER_SIZE: usize = mem::size_of::<u64>() * 2; // enough space for width and height
+const HEADER_SIZE: usize = size_of::<u64>() * 2; // enough space for width and height
 
 const SEEK_MAGIC: SeekFrom = SeekFrom::Start(0);
 const SEEK_HEADER: SeekFrom = SeekFrom::Start(FILE_MAGIC.len() as u64);
 const SEEK_DATA: SeekFrom = SeekFrom::Start((FILE_MAGIC.len() + HEADER_SIZE) as u64);
 
+/// The file format in which a [`FileSink`] stores snapshots
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum SnapshotFormat {
+    /// pixeldike's own format consisting of a small header followed by raw RGB data
+    ///
+    /// It is cheap to write because the file is allocated once and then overwritten in place.
+    #[default]
+    Pixmap,
+    /// A standard PNG image
+    ///
+    /// Every snapshot is written into a temporary file which then replaces the previous one so that readers never
+    /// see a partially written image.
+    Png,
+}
+
 /// Configuration options for the [`FileSink`]
 #[derive(Debug)]
 pub struct FileSinkOptions {
@@ -28,7 +50,26 @@ pub struct FileSinkOptions {
     pub interval: Interval,
 
     /// The path at which the snapshot should be placed
+    ///
+    /// If the path is `-`, snapshots are written to stdout instead.
+    /// Since stdout cannot be overwritten, each snapshot is appended as a complete record so that the output
+    /// can later be restored with [`load_pixmap_file`].
     pub path: PathBuf,
+
+    /// The format in which snapshots are written
+    ///
+    /// When writing PNG images to stdout, each snapshot is appended as a complete image.
+    pub format: SnapshotFormat,
+
+    /// Through which the sink reports each snapshot to a [`Watchdog`](crate::watchdog::Watchdog)
+    pub heartbeat: Option<Heartbeat>,
+
+    /// A key with which every snapshot is signed
+    ///
+    /// The signature covers the complete file and is stored next to it with an additional `.sig` extension.
+    /// Snapshots which are written to stdout are not signed.
+    #[cfg(feature = "signing")]
+    pub signer: Option<Arc<Signer>>,
 }
 
 /// A sink that periodically snapshots pixmap data into a file
@@ -46,6 +87,21 @@ impl FileSink {
 
     /// Open the target file and start the background tasks for periodic snapshotting
     pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        if self.options.format == SnapshotFormat::Png {
+            return Ok(join_set
+                .build_task()
+                .name("png_file_sink")
+                .spawn(async move { self.run_png().await })?);
+        }
+
+        if self.options.path == Path::new(STDIO_PATH) {
+            let handle = join_set
+                .build_task()
+                .name("file_sink")
+                .spawn(async move { self.run_stream(tokio::io::stdout()).await })?;
+            return Ok(handle);
+        }
+
         let mut file = self.open_file().await?;
         self.write_header(&mut file).await?;
         let handle = join_set
@@ -60,6 +116,7 @@ impl FileSink {
         Ok(File::options()
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&self.options.path)
             .await?)
     }
@@ -100,42 +157,181 @@ impl FileSink {
         file.flush().await?;
         file.sync_all().await?;
 
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.options.signer {
+            let (width, height) = self.pixmap.get_size();
+            let mut content = Vec::with_capacity(FILE_MAGIC.len() + HEADER_SIZE + data.len());
+            content.extend_from_slice(FILE_MAGIC);
+            content.extend_from_slice(&(width as u64).to_be_bytes());
+            content.extend_from_slice(&(height as u64).to_be_bytes());
+            content.extend_from_slice(&data);
+            signer.write_signature(&self.options.path, &content).await?;
+        }
+
         Ok(())
     }
 
+    /// Report a completed snapshot to the watchdog
+    fn beat(&self) {
+        if let Some(heartbeat) = &self.options.heartbeat {
+            heartbeat.beat();
+        }
+    }
+
     /// Execute the main loop which periodically snapshots data into the file
     async fn run(mut self, mut file: File) -> anyhow::Result<!> {
         loop {
             self.write_data(&mut file).await?;
+            self.beat();
+            self.options.interval.tick().await;
+        }
+    }
+
+    /// Write a complete snapshot record (magic bytes, header and data) to a stream
+    async fn write_record<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> anyhow::Result<()> {
+        let (width, height) = self.pixmap.get_size();
+        let data = unsafe { self.pixmap.get_color_data() };
+        let data = data
+            .iter()
+            .flat_map(|c| Into::<[u8; 3]>::into(*c))
+            .collect::<Vec<_>>();
+
+        writer.write_all(FILE_MAGIC).await?;
+        writer.write_u64(width as u64).await?;
+        writer.write_u64(height as u64).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Execute the main loop which periodically appends snapshot records to a stream
+    async fn run_stream<W: AsyncWrite + Unpin>(mut self, mut writer: W) -> anyhow::Result<!> {
+        loop {
+            self.write_record(&mut writer).await?;
+            self.beat();
+            self.options.interval.tick().await;
+        }
+    }
+
+    /// Encode the current canvas content as PNG image
+    async fn encode_png(&self) -> anyhow::Result<Vec<u8>> {
+        let pixmap = self.pixmap.clone();
+        tokio::task::spawn_blocking(move || {
+            let (width, height) = pixmap.get_size();
+            encode_png(width, height, unsafe { pixmap.get_color_data() })
+        })
+        .await?
+    }
+
+    /// Replace the configured file with a PNG image of the current canvas content
+    async fn write_png(&self) -> anyhow::Result<()> {
+        let data = self.encode_png().await?;
+        if self.options.path == Path::new(STDIO_PATH) {
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&data).await?;
+            stdout.flush().await?;
+            return Ok(());
+        }
+
+        let mut tmp_path = self.options.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.options.path).await?;
+
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.options.signer {
+            signer.write_signature(&self.options.path, &data).await?;
+        }
+        Ok(())
+    }
+
+    /// Execute the main loop which periodically writes PNG images
+    async fn run_png(mut self) -> anyhow::Result<!> {
+        loop {
+            self.write_png().await?;
+            self.beat();
             self.options.interval.tick().await;
         }
     }
 }
 
+/// The path which designates stdin or stdout instead of a real file
+const STDIO_PATH: &str = "-";
+
 /// Restore a previously saved pixmap snapshot
+///
+/// Both pixmap files and PNG images are supported and told apart by their content.
+///
+/// If the path is `-`, the snapshot is read from stdin instead.
+/// Should stdin contain multiple consecutive pixmap snapshots, the last one is restored once stdin is closed.
+/// A PNG image on stdin is restored once stdin is closed.
 pub async fn load_pixmap_file(path: &Path) -> anyhow::Result<Pixmap> {
-    let mut file = File::open(path).await?;
+    if path == Path::new(STDIO_PATH) {
+        let mut stdin = tokio::io::stdin();
+        let mut signature = Vec::with_capacity(PNG_SIGNATURE.len());
+        (&mut stdin)
+            .take(PNG_SIGNATURE.len() as u64)
+            .read_to_end(&mut signature)
+            .await?;
+        if signature == PNG_SIGNATURE {
+            let mut data = signature;
+            stdin.read_to_end(&mut data).await?;
+            return load_png(&data);
+        }
+        return load_last_record((&signature[..]).chain(stdin)).await;
+    }
+
+    let data = tokio::fs::read(path).await?;
+    if data.starts_with(PNG_SIGNATURE) {
+        return load_png(&data);
+    }
+    match read_record(&mut &data[..]).await? {
+        Some(pixmap) => Ok(pixmap),
+        None => Err(anyhow!("File at {} is empty", path.display())),
+    }
+}
+
+/// Restore a pixmap from a PNG image
+fn load_png(data: &[u8]) -> anyhow::Result<Pixmap> {
+    let (width, height, colors) = decode_png(data)?;
+    let pixmap = Pixmap::new(width, height)?;
+    pixmap.set_region(0, 0, width, height, &colors)?;
+    Ok(pixmap)
+}
+
+/// Restore the last complete snapshot record contained in a stream
+async fn load_last_record<R: AsyncRead + Unpin>(mut reader: R) -> anyhow::Result<Pixmap> {
+    let mut last = None;
+    while let Some(pixmap) = read_record(&mut reader).await? {
+        last = Some(pixmap);
+    }
+    last.ok_or_else(|| anyhow!("Stream did not contain any pixmap snapshots"))
+}
 
+/// Read one snapshot record from a stream
+///
+/// Returns `None` if the stream has already ended before the record began.
+async fn read_record<R: AsyncRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<Pixmap>> {
     // verify magic bytes
     let mut file_magic = [0u8; FILE_MAGIC.len()];
-    file.seek(SEEK_MAGIC).await?;
-    file.read_exact(&mut file_magic).await?;
+    match reader.read_exact(&mut file_magic).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
     if file_magic != FILE_MAGIC {
-        return Err(anyhow!(
-            "File at {} does not contain valid pixmap data",
-            path.display()
-        ));
+        return Err(anyhow!("Data does not contain a valid pixmap snapshot"));
     }
 
     // load size information from header
-    file.seek(SEEK_HEADER).await?;
-    let width = file.read_u64().await? as usize;
-    let height = file.read_u64().await? as usize;
+    let width = reader.read_u64().await? as usize;
+    let height = reader.read_u64().await? as usize;
 
     // load the file data into memory
     let mut buf = vec![0u8; width * height * 3];
-    file.seek(SEEK_DATA).await?;
-    file.read_exact(&mut buf).await?;
+    reader.read_exact(&mut buf).await?;
 
     // construct a pixmap with the loaded data
     let pixmap = Pixmap::new(width, height)?;
@@ -144,7 +340,7 @@ pub async fn load_pixmap_file(path: &Path) -> anyhow::Result<Pixmap> {
         pixmap_data[i] = i_color.into()
     }
 
-    Ok(pixmap)
+    Ok(Some(pixmap))
 }
 
 #[cfg(test)]
@@ -176,6 +372,10 @@ mod test {
                 FileSinkOptions {
                     path: file_path.clone(),
                     interval: interval(Duration::from_secs(1)),
+                    format: SnapshotFormat::Pixmap,
+                    heartbeat: None,
+                    #[cfg(feature = "signing")]
+                    signer: None,
                 },
                 original_pixmap.clone(),
             );
@@ -192,4 +392,106 @@ mod test {
         let restored_data = unsafe { restored_pixmap.get_color_data() };
         assert_eq!(original_data, restored_data);
     }
+
+    #[tokio::test]
+    async fn test_stream_store_and_load_last() {
+        let pixmap = Arc::new(Pixmap::new(5, 5).unwrap());
+        let sink = FileSink::new(
+            FileSinkOptions {
+                path: PathBuf::from(STDIO_PATH),
+                interval: interval(Duration::from_secs(1)),
+                format: SnapshotFormat::Pixmap,
+                heartbeat: None,
+                #[cfg(feature = "signing")]
+                signer: None,
+            },
+            pixmap.clone(),
+        );
+
+        // write two consecutive snapshots into the stream
+        let mut stream = Vec::new();
+        sink.write_record(&mut stream).await.unwrap();
+        pixmap.set_pixel(1, 1, Color::from((0xab, 0xab, 0xab))).unwrap();
+        sink.write_record(&mut stream).await.unwrap();
+
+        // the last one should be restored
+        let restored_pixmap = load_last_record(&stream[..]).await.unwrap();
+        assert_eq!(
+            restored_pixmap.get_pixel(1, 1).unwrap(),
+            Color::from((0xab, 0xab, 0xab))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_png() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("test.png");
+        let pixmap = Arc::new(Pixmap::new(5, 3).unwrap());
+        pixmap.set_pixel(4, 2, Color::from((0xab, 0xcd, 0xef))).unwrap();
+
+        let sink = FileSink::new(
+            FileSinkOptions {
+                path: file_path.clone(),
+                interval: interval(Duration::from_secs(1)),
+                format: SnapshotFormat::Png,
+                heartbeat: None,
+                #[cfg(feature = "signing")]
+                signer: None,
+            },
+            pixmap.clone(),
+        );
+        sink.write_png().await.unwrap();
+
+        let restored_pixmap = load_pixmap_file(&file_path).await.unwrap();
+        assert_eq!(restored_pixmap.get_size(), (5, 3));
+        assert_eq!(unsafe { restored_pixmap.get_color_data() }, unsafe {
+            pixmap.get_color_data()
+        });
+    }
+
+    #[cfg(feature = "signing")]
+    #[tokio::test]
+    async fn test_signed_snapshot() {
+        use crate::signing::signature_path;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pixmap = Arc::new(Pixmap::new(5, 3).unwrap());
+        pixmap.set_pixel(1, 2, Color::from((0xab, 0xcd, 0xef))).unwrap();
+        let signer = Arc::new(Signer::from_seed([3; 32]));
+
+        for (name, format) in [
+            ("test.pixmap", SnapshotFormat::Pixmap),
+            ("test.png", SnapshotFormat::Png),
+        ] {
+            let file_path = dir.path().join(name);
+            let sink = FileSink::new(
+                FileSinkOptions {
+                    path: file_path.clone(),
+                    interval: interval(Duration::from_secs(1)),
+                    format,
+                    heartbeat: None,
+                    signer: Some(signer.clone()),
+                },
+                pixmap.clone(),
+            );
+            match format {
+                SnapshotFormat::Pixmap => {
+                    let mut file = sink.open_file().await.unwrap();
+                    sink.write_header(&mut file).await.unwrap();
+                    sink.write_data(&mut file).await.unwrap();
+                }
+                SnapshotFormat::Png => sink.write_png().await.unwrap(),
+            }
+
+            // the signature covers the file exactly as it is stored on disk
+            let content = std::fs::read(&file_path).unwrap();
+            let signature = std::fs::read_to_string(signature_path(&file_path)).unwrap();
+            assert_eq!(
+                signer.public_key().verify(&content, &signature),
+                Ok(()),
+                "{}",
+                name
+            );
+        }
+    }
 }
@@ -0,0 +1,475 @@
+//! A sink which records the canvas into an animated GIF or APNG image, e.g. to produce a recap of an event
+//!
+//! Frames are appended to the file as they are captured.
+//! The file is a complete animation after every frame so that a recording which is interrupted, e.g. because the
+//! server is stopped, can still be played.
+//! Since an animation can't be continued, e.g. after the canvas was resized, an existing recording is moved to a
+//! numbered file next to it like `recap.1.gif` when the sink starts.
+
+use crate::pixmap::{Pixmap, SharedPixmap};
+use crate::watchdog::Heartbeat;
+use crate::DaemonResult;
+use anyhow::anyhow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How hard the GIF encoder tries to find a good palette for each frame (1 = best, 30 = fastest)
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+
+/// The animated image format in which a [`TimelapseSink`] records frames
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TimelapseFormat {
+    /// An animated GIF
+    ///
+    /// Every frame is reduced to at most 256 colors and the canvas may be at most 65535 pixels wide and high.
+    Gif,
+    /// An animated PNG which keeps all colors of the canvas exactly
+    Apng,
+}
+
+impl TimelapseFormat {
+    /// Determine the format from the extension of a path (`.gif`, `.png` or `.apng`)
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gif" => Some(TimelapseFormat::Gif),
+            "png" | "apng" => Some(TimelapseFormat::Apng),
+            _ => None,
+        }
+    }
+}
+
+/// Configuration options for the [`TimelapseSink`]
+#[derive(Debug)]
+pub struct TimelapseOptions {
+    /// The path of the animated image which is created
+    ///
+    /// An existing file is overwritten.
+    pub path: PathBuf,
+    /// The format of the animated image
+    pub format: TimelapseFormat,
+    /// The interval in which the canvas is captured
+    pub interval: Interval,
+    /// How long each frame is shown when the animation is played
+    pub frame_delay: Duration,
+    /// After how many frames the recording stops, if at all
+    pub max_frames: Option<usize>,
+    /// How often the animation is played, `0` plays it forever
+    pub loops: u16,
+    /// Through which the sink reports each interval to a [`Watchdog`](crate::watchdog::Watchdog)
+    pub heartbeat: Option<Heartbeat>,
+}
+
+/// A sink that periodically appends the canvas to an animated image
+#[derive(Debug)]
+pub struct TimelapseSink {
+    options: TimelapseOptions,
+    pixmap: SharedPixmap,
+}
+
+impl TimelapseSink {
+    /// Create a new sink which records the given pixmap
+    pub fn new(options: TimelapseOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Create the target file and start the background task which records frames into it
+    ///
+    /// An existing file is moved aside instead of being overwritten.
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        let (width, height) = self.pixmap.get_size();
+        if tokio::fs::try_exists(&self.options.path).await? {
+            let rotated = super::unused_numbered_path(&self.options.path);
+            tokio::fs::rename(&self.options.path, &rotated).await?;
+            tracing::info!(
+                "Moved the previous timelapse {} to {}",
+                self.options.path.display(),
+                rotated.display()
+            );
+        }
+        let recorder = Recorder::new(
+            File::create_new(&self.options.path)?,
+            self.options.format,
+            width,
+            height,
+            self.options.frame_delay,
+            self.options.loops,
+        )?;
+        let handle = join_set
+            .build_task()
+            .name("timelapse")
+            .spawn(async move { self.run(recorder).await })?;
+        Ok(handle)
+    }
+
+    /// Execute the main loop which periodically captures the canvas
+    async fn run(mut self, mut recorder: Recorder) -> anyhow::Result<!> {
+        self.options
+            .interval
+            .set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut frames = 0;
+        loop {
+            self.options.interval.tick().await;
+            if self
+                .options
+                .max_frames
+                .is_none_or(|max_frames| frames < max_frames)
+            {
+                let snapshot = self.pixmap.checkpoint();
+                // encoding a frame can take a while for big canvases
+                recorder = tokio::task::spawn_blocking(move || {
+                    let rgb = snapshot
+                        .pixels()
+                        .iter()
+                        .flat_map(|&color| <[u8; 3]>::from(color))
+                        .collect::<Vec<_>>();
+                    recorder.write_frame(&rgb)?;
+                    anyhow::Ok(recorder)
+                })
+                .await??;
+                frames += 1;
+                if self.options.max_frames == Some(frames) {
+                    tracing::info!(
+                        "Finished timelapse {} with {} frames",
+                        self.options.path.display(),
+                        frames
+                    );
+                }
+            }
+            // the heartbeat continues after the recording finished because the sink is still healthy
+            if let Some(heartbeat) = &self.options.heartbeat {
+                heartbeat.beat();
+            }
+        }
+    }
+}
+
+/// Records frames of a pixmap which is not shared with a server into an animated image, e.g. while replaying an
+/// [event log](crate::sinks::eventlog)
+#[derive(Debug)]
+pub struct TimelapseRecorder {
+    recorder: Recorder,
+    frames: usize,
+}
+
+impl TimelapseRecorder {
+    /// Create the animated image at `path`, overwriting an existing file
+    pub fn create(
+        path: &Path,
+        format: TimelapseFormat,
+        width: usize,
+        height: usize,
+        frame_delay: Duration,
+        loops: u16,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            recorder: Recorder::new(File::create(path)?, format, width, height, frame_delay, loops)?,
+            frames: 0,
+        })
+    }
+
+    /// Append the current content of `pixmap` as the next frame
+    ///
+    /// The pixmap must have the size with which the recorder was created.
+    pub fn write_frame(&mut self, pixmap: &Pixmap) -> anyhow::Result<()> {
+        let rgb = pixmap
+            .checkpoint()
+            .pixels()
+            .iter()
+            .flat_map(|&color| <[u8; 3]>::from(color))
+            .collect::<Vec<_>>();
+        self.recorder.write_frame(&rgb)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// How many frames were written so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+}
+
+/// The encoder of the configured format together with the file it writes
+#[derive(Debug)]
+enum Recorder {
+    Gif(GifRecorder),
+    Apng(ApngRecorder),
+}
+
+impl Recorder {
+    /// Write the start of an animation with the given size into the empty `file`
+    fn new(
+        file: File,
+        format: TimelapseFormat,
+        width: usize,
+        height: usize,
+        frame_delay: Duration,
+        loops: u16,
+    ) -> anyhow::Result<Self> {
+        let file = BufWriter::new(file);
+        Ok(match format {
+            TimelapseFormat::Gif => Recorder::Gif(GifRecorder::new(file, width, height, frame_delay, loops)?),
+            TimelapseFormat::Apng => {
+                Recorder::Apng(ApngRecorder::new(file, width, height, frame_delay, loops)?)
+            }
+        })
+    }
+
+    /// Append one frame with the given `r`, `g`, `b` bytes of all pixels and keep the file complete
+    fn write_frame(&mut self, rgb: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Recorder::Gif(recorder) => recorder.write_frame(rgb),
+            Recorder::Apng(recorder) => recorder.write_frame(rgb),
+        }
+    }
+}
+
+/// Records frames into an animated GIF
+struct GifRecorder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    width: u16,
+    height: u16,
+    /// How long each frame is shown in units of 10ms
+    delay: u16,
+}
+
+impl std::fmt::Debug for GifRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GifRecorder")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("delay", &self.delay)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GifRecorder {
+    fn new(
+        file: BufWriter<File>,
+        width: usize,
+        height: usize,
+        frame_delay: Duration,
+        loops: u16,
+    ) -> anyhow::Result<Self> {
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err(anyhow!(
+                "a {}x{} canvas is too large for a GIF timelapse, use APNG instead",
+                width,
+                height
+            ));
+        };
+        let mut encoder = gif::Encoder::new(file, width, height, &[])?;
+        // the repetition count of GIFs excludes the first play and no count means playing only once
+        match loops {
+            0 => encoder.set_repeat(gif::Repeat::Infinite)?,
+            1 => {}
+            loops => encoder.set_repeat(gif::Repeat::Finite(loops - 1))?,
+        }
+        let delay = (frame_delay.as_millis() / 10).clamp(1, u16::MAX as u128) as u16;
+        Ok(Self {
+            encoder,
+            width,
+            height,
+            delay,
+        })
+    }
+
+    fn write_frame(&mut self, rgb: &[u8]) -> anyhow::Result<()> {
+        let mut frame = gif::Frame::from_rgb_speed(self.width, self.height, rgb, GIF_QUANTIZATION_SPEED);
+        frame.delay = self.delay;
+        self.encoder.write_frame(&frame)?;
+
+        // terminate the file but let the next frame overwrite the trailer again
+        let file = self.encoder.get_mut();
+        file.write_all(&[0x3B])?;
+        file.flush()?;
+        file.seek(SeekFrom::Current(-1))?;
+        Ok(())
+    }
+}
+
+/// The position of the `acTL` chunk which holds the number of frames, right after the signature and `IHDR` chunk
+const APNG_ACTL_POSITION: u64 = 8 + 25;
+
+/// Records frames into an animated PNG
+///
+/// The `png` crate needs to know the number of frames in advance, so the few chunks of an APNG are written here.
+/// Every frame covers the whole canvas.
+#[derive(Debug)]
+struct ApngRecorder {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    /// How long each frame is shown in milliseconds
+    delay: u16,
+    loops: u16,
+    /// How many frames were written
+    frames: u32,
+    /// The sequence number of the next `fcTL` or `fdAT` chunk
+    sequence: u32,
+    /// The position of the `IEND` chunk which is overwritten by the next frame
+    end: u64,
+}
+
+impl ApngRecorder {
+    fn new(
+        mut file: BufWriter<File>,
+        width: usize,
+        height: usize,
+        frame_delay: Duration,
+        loops: u16,
+    ) -> anyhow::Result<Self> {
+        file.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'])?;
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&u32::try_from(width)?.to_be_bytes());
+        header.extend_from_slice(&u32::try_from(height)?.to_be_bytes());
+        // 8 bits per channel, truecolor, deflate, no filter method extensions, no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_png_chunk(&mut file, b"IHDR", &header)?;
+        write_png_chunk(
+            &mut file,
+            b"acTL",
+            &[[0; 4], u32::from(loops).to_be_bytes()].concat(),
+        )?;
+        let end = file.stream_position()?;
+        Ok(Self {
+            file,
+            width,
+            height,
+            delay: frame_delay.as_millis().min(u16::MAX as u128) as u16,
+            loops,
+            frames: 0,
+            sequence: 0,
+            end,
+        })
+    }
+
+    fn write_frame(&mut self, rgb: &[u8]) -> anyhow::Result<()> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        for row in rgb.chunks_exact(self.width * 3) {
+            // every scanline starts with its filter type which is always "none"
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+        let data = encoder.finish()?;
+
+        let mut control = Vec::with_capacity(26);
+        control.extend_from_slice(&self.next_sequence().to_be_bytes());
+        control.extend_from_slice(&(self.width as u32).to_be_bytes());
+        control.extend_from_slice(&(self.height as u32).to_be_bytes());
+        control.extend_from_slice(&[0; 8]); // x and y offset
+        control.extend_from_slice(&self.delay.to_be_bytes());
+        control.extend_from_slice(&1000u16.to_be_bytes());
+        control.extend_from_slice(&[0, 0]); // no disposal and no blending
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        write_png_chunk(&mut self.file, b"fcTL", &control)?;
+        match self.frames {
+            // the first frame doubles as the default image which non-animated viewers show
+            0 => write_png_chunk(&mut self.file, b"IDAT", &data)?,
+            _ => {
+                let sequence = self.next_sequence().to_be_bytes();
+                write_png_chunk(&mut self.file, b"fdAT", &[&sequence[..], &data].concat())?
+            }
+        }
+        self.end = self.file.stream_position()?;
+        write_png_chunk(&mut self.file, b"IEND", &[])?;
+
+        self.frames += 1;
+        self.file.seek(SeekFrom::Start(APNG_ACTL_POSITION))?;
+        let animation_control = [self.frames.to_be_bytes(), u32::from(self.loops).to_be_bytes()].concat();
+        write_png_chunk(&mut self.file, b"acTL", &animation_control)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence += 1;
+        self.sequence - 1
+    }
+}
+
+/// Write one PNG chunk consisting of its length, type, data and checksum
+fn write_png_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.sum().to_be_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{Color, Pixmap};
+    use std::sync::Arc;
+
+    async fn record(path: &Path, pixmap: SharedPixmap, max_frames: usize) {
+        let mut join_set = JoinSet::new();
+        TimelapseSink::new(
+            TimelapseOptions {
+                path: path.to_owned(),
+                format: TimelapseFormat::from_path(path).unwrap(),
+                interval: tokio::time::interval(Duration::from_millis(10)),
+                frame_delay: Duration::from_millis(100),
+                max_frames: Some(max_frames),
+                loops: 0,
+                heartbeat: None,
+            },
+            pixmap.clone(),
+        )
+        .start(&mut join_set)
+        .await
+        .unwrap();
+        for i in 0..max_frames {
+            pixmap.set_pixel(i, 0, Color::from(0xFF0000)).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        join_set.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_timelapse() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("recap.png");
+        record(&path, Arc::new(Pixmap::new(4, 2).unwrap()), 3).await;
+        let mut reader = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let animation = reader.info().animation_control.unwrap();
+        assert_eq!((animation.num_frames, animation.num_plays), (3, 0));
+        let mut buf = vec![0; reader.output_buffer_size()];
+        for _ in 0..3 {
+            reader.next_frame(&mut buf).unwrap();
+        }
+        assert_eq!(&buf[..3], &[0xFF, 0, 0]);
+
+        let path = dir.path().join("recap.gif");
+        record(&path, Arc::new(Pixmap::new(4, 2).unwrap()), 3).await;
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let mut frames = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 10);
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+
+        // a second recording doesn't overwrite the first one
+        record(&path, Arc::new(Pixmap::new(2, 2).unwrap()), 1).await;
+        let rotated = dir.path().join("recap.1.gif");
+        let frames = |path: &Path| {
+            let mut decoder = gif::DecodeOptions::new()
+                .read_info(File::open(path).unwrap())
+                .unwrap();
+            std::iter::from_fn(|| decoder.read_next_frame().unwrap().map(|_| ())).count()
+        };
+        assert_eq!((frames(&path), frames(&rotated)), (1, 3));
+    }
+}
@@ -0,0 +1,234 @@
+//! A sink which notifies a webhook when watched regions of the canvas have changed and then stayed the same for a
+//! while, e.g. to automatically archive finished artworks in designated gallery slots
+//!
+//! Every region is observed through a region subscription on the canvas's change broadcast.
+//! Once a change has happened and no further change followed for the settle time, the current content of the region is
+//! posted to the webhook as a PNG image.
+//! The `X-Pixeldike-Region` header carries the name of the region and `X-Pixeldike-Rect` its position as
+//! `<x>,<y>,<width>,<height>`.
+
+use crate::pixmap::{encode_png, Color, SharedPixmap, WatchedRegion};
+use crate::DaemonResult;
+use anyhow::anyhow;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::{AbortHandle, JoinSet};
+use url::Url;
+
+/// A named region of the canvas which is watched for changes
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WebhookRegion {
+    /// The name with which the webhook is told which region changed
+    pub name: String,
+    /// The watched rectangle
+    pub region: WatchedRegion,
+}
+
+/// Configuration options for the [`WebhookSink`]
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    /// The `http://` url to which the PNG images of changed regions are posted
+    pub url: Url,
+    /// The regions which are watched
+    pub regions: Vec<WebhookRegion>,
+    /// How long a region must stay unchanged after a change before the webhook is notified
+    pub settle: Duration,
+}
+
+/// A sink that posts regions of the canvas to a webhook once they changed and stabilized
+#[derive(Debug)]
+pub struct WebhookSink {
+    options: WebhookOptions,
+    pixmap: SharedPixmap,
+}
+
+impl WebhookSink {
+    /// Create a new sink which watches regions of the given pixmap
+    pub fn new(options: WebhookOptions, pixmap: SharedPixmap) -> Self {
+        Self { options, pixmap }
+    }
+
+    /// Start the background task which watches all regions
+    ///
+    /// This fails if the url is not an `http://` url, a region is not inside the canvas or the canvas doesn't
+    /// announce its changes.
+    pub async fn start(self, join_set: &mut JoinSet<DaemonResult>) -> anyhow::Result<AbortHandle> {
+        if self.options.url.scheme() != "http" {
+            return Err(anyhow!("only http:// webhooks are supported"));
+        }
+        if self.pixmap.changes().is_none() {
+            return Err(anyhow!("webhooks require a canvas which announces its changes"));
+        }
+        let (width, height) = self.pixmap.get_size();
+        if let Some(region) = self.options.regions.iter().find(|i| {
+            i.region.width == 0
+                || i.region.height == 0
+                || i.region.x.saturating_add(i.region.width) > width
+                || i.region.y.saturating_add(i.region.height) > height
+        }) {
+            return Err(anyhow!("webhook region {} is not inside the canvas", region.name));
+        }
+
+        let handle = join_set
+            .build_task()
+            .name("webhook")
+            .spawn(async move { self.run().await })?;
+        Ok(handle)
+    }
+
+    /// Watch all regions until one of the watchers fails
+    async fn run(self) -> anyhow::Result<!> {
+        let mut watchers = JoinSet::new();
+        for region in self.options.regions {
+            watchers.spawn(watch(
+                self.pixmap.clone(),
+                self.options.url.clone(),
+                region,
+                self.options.settle,
+            ));
+        }
+        match watchers.join_next().await {
+            Some(result) => result?,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Notify the webhook whenever the region changed and then stayed unchanged for `settle`
+///
+/// The webhook is not notified if the region ends up with the content which was last posted, e.g. because a change
+/// was reverted.
+async fn watch(pixmap: SharedPixmap, url: Url, region: WebhookRegion, settle: Duration) -> anyhow::Result<!> {
+    let changes = pixmap
+        .changes()
+        .ok_or(anyhow!("webhooks require a canvas which announces its changes"))?;
+    let mut subscription = changes.subscribe_region(region.region);
+    let WatchedRegion { x, y, width, height } = region.region;
+    let mut last_posted = pixmap.get_region(x, y, width, height)?;
+
+    loop {
+        wait_for_change(subscription.recv().await)?;
+        // every further change postpones the notification
+        while let Ok(change) = tokio::time::timeout(settle, subscription.recv()).await {
+            wait_for_change(change)?;
+        }
+
+        let content = pixmap.get_region(x, y, width, height)?;
+        if content == last_posted {
+            continue;
+        }
+        tracing::info!("Region {} changed, notifying webhook", region.name);
+        if let Err(e) = notify(&url, &region, content.clone()).await {
+            tracing::warn!("Could not notify webhook about region {}: {}", region.name, e);
+        }
+        last_posted = content;
+    }
+}
+
+/// Check the result of waiting for a change
+///
+/// Missing changes because the watcher was too slow still means that something changed.
+fn wait_for_change<T>(result: Result<T, RecvError>) -> anyhow::Result<()> {
+    match result {
+        Ok(_) | Err(RecvError::Lagged(_)) => Ok(()),
+        Err(RecvError::Closed) => Err(anyhow!("the canvas stopped announcing its changes")),
+    }
+}
+
+/// Post the content of a region to the webhook as PNG image
+async fn notify(url: &Url, region: &WebhookRegion, content: Vec<Color>) -> anyhow::Result<()> {
+    let url = url.clone();
+    let region = region.clone();
+    tokio::task::spawn_blocking(move || {
+        let WatchedRegion { x, y, width, height } = region.region;
+        let png = encode_png(width, height, &content)?;
+        crate::watchdog::post(
+            &url,
+            "image/png",
+            &[
+                ("X-Pixeldike-Region", region.name),
+                ("X-Pixeldike-Rect", format!("{x},{y},{width},{height}")),
+            ],
+            &png,
+        )
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pixmap::{decode_png, Pixmap};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pixmap = Arc::new(
+            Pixmap::new(10, 10)
+                .unwrap()
+                .with_change_broadcast(16, Duration::ZERO),
+        );
+        let sink = WebhookSink::new(
+            WebhookOptions {
+                url: Url::parse(&format!("http://{}/gallery", listener.local_addr().unwrap())).unwrap(),
+                regions: vec![WebhookRegion {
+                    name: "slot1".to_string(),
+                    region: WatchedRegion {
+                        x: 2,
+                        y: 2,
+                        width: 2,
+                        height: 2,
+                    },
+                }],
+                settle: Duration::from_millis(100),
+            },
+            pixmap.clone(),
+        );
+        let mut join_set = JoinSet::new();
+        sink.start(&mut join_set).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // changes outside of the region are ignored
+        pixmap.set_pixel(0, 0, Color::from(0xFFFFFF)).unwrap();
+        pixmap.set_pixel(2, 2, Color::from(0xFF0000)).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pixmap.set_pixel(3, 3, Color::from(0x00FF00)).unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let header_end = loop {
+            stream.read_buf(&mut request).await.unwrap();
+            if let Some(i) = request.windows(4).position(|i| i == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+        assert!(head.starts_with("POST /gallery HTTP/1.1\r\n"));
+        assert!(head.contains("Content-Type: image/png\r\n"));
+        assert!(head.contains("X-Pixeldike-Region: slot1\r\n"));
+        assert!(head.contains("X-Pixeldike-Rect: 2,2,2,2\r\n"));
+        let content_length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        while request.len() < header_end + content_length {
+            stream.read_buf(&mut request).await.unwrap();
+        }
+        let (width, height, colors) = decode_png(&request[header_end..]).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(
+            colors,
+            vec![
+                Color::from(0xFF0000),
+                Color::default(),
+                Color::default(),
+                Color::from(0x00FF00)
+            ]
+        );
+    }
+}
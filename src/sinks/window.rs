@@ -1,47 +1,259 @@
 //! A sink for drawing on an X or Wayland window
 
-use crate::pixmap::SharedPixmap;
+use crate::pixmap::{Color, SharedPixmap};
 use crate::DaemonResult;
 use anyhow::anyhow;
-use minifb::{Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
 use std::mem;
 use std::time::Duration;
 use tokio::task::{AbortHandle, JoinSet};
 use tokio::time::MissedTickBehavior;
 
+/// How the canvas is scaled when the window has a different size
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum ScalingFilter {
+    /// Every window pixel shows the closest canvas pixel which keeps the canvas crisp
+    #[default]
+    Nearest,
+    /// Every window pixel is interpolated from the four closest canvas pixels which looks smoother
+    Bilinear,
+}
+
+/// Options for configuring the window sink
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WindowSinkOptions {
+    /// The size with which the window is opened or `None` to open it with the size of the canvas
+    pub size: Option<(usize, usize)>,
+    /// How the canvas is scaled to the size of the window
+    pub filter: ScalingFilter,
+    /// Whether the canvas keeps its aspect ratio by leaving black bars at the edges of the window
+    pub keep_aspect_ratio: bool,
+    /// Whether the window covers the screen from the start
+    pub fullscreen: bool,
+    /// How long it takes for a written pixel to fade to black in the activity view
+    pub activity_decay: Duration,
+}
+
 /// Start the window in the background.
 ///
 /// Note that handles to X/Wayland windows are not Send so the background task must always be scheduled on the same thread.
 /// This is achieved by passing an existing `LocalSet` in which the background task will execute.
-pub fn start(join_set: &mut JoinSet<DaemonResult>, pixmap: SharedPixmap) -> anyhow::Result<AbortHandle> {
-    let (width, height) = pixmap.get_size();
-    let mut window = Window::new("pixelflut", width, height, WindowOptions::default())?;
-
-    window.set_title("Pixelflut Server");
-
+///
+/// The window can be resized freely and the canvas is scaled to fit it.
+/// Pressing `F` in the window toggles fullscreen mode and `Q` closes it which, like closing it any other way, stops
+/// the server.
+/// If the pixmap tracks activity, pressing `A` toggles between the canvas and a view in which recently written pixels
+/// are bright and fade to black over the course of `activity_decay`.
+pub fn start(
+    join_set: &mut JoinSet<DaemonResult>,
+    pixmap: SharedPixmap,
+    options: WindowSinkOptions,
+) -> anyhow::Result<AbortHandle> {
+    let window = open_window(window_size(&pixmap, &options), options.fullscreen)?;
     let handle = join_set
         .build_task()
         .name("window_renderer")
-        .spawn_local(async move { render(pixmap, window).await })?;
+        .spawn_local(async move { render(pixmap, window, options).await })?;
     Ok(handle)
 }
 
-async fn render(pixmap: SharedPixmap, mut window: Window) -> anyhow::Result<!> {
+/// The size with which the window is opened
+fn window_size(pixmap: &SharedPixmap, options: &WindowSinkOptions) -> (usize, usize) {
+    options.size.unwrap_or_else(|| pixmap.get_size())
+}
+
+/// Open a window of the given size
+///
+/// minifb has no real fullscreen mode, so a fullscreen window is a borderless window in the top-left corner of the
+/// screen which is as large as possible while still fitting onto it.
+fn open_window((width, height): (usize, usize), fullscreen: bool) -> anyhow::Result<Window> {
+    let options = WindowOptions {
+        borderless: fullscreen,
+        title: !fullscreen,
+        resize: !fullscreen,
+        topmost: fullscreen,
+        scale: match fullscreen {
+            true => Scale::FitScreen,
+            false => Scale::X1,
+        },
+        scale_mode: ScaleMode::Stretch,
+        ..WindowOptions::default()
+    };
+    let mut window = Window::new("Pixelflut Server", width, height, options)?;
+    if fullscreen {
+        window.set_position(0, 0);
+    }
+    Ok(window)
+}
+
+async fn render(pixmap: SharedPixmap, mut window: Window, options: WindowSinkOptions) -> anyhow::Result<!> {
     let (width, height) = pixmap.get_size();
+    let mut scaler = Scaler::new(options.filter, options.keep_aspect_ratio);
+    let mut fullscreen = options.fullscreen;
+    let mut show_activity = false;
     let mut interval = tokio::time::interval(Duration::from_millis(1000 / 60));
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
-        if !window.is_open() {
+        if !window.is_open() || window.is_key_pressed(Key::Q, KeyRepeat::No) {
             return Err(anyhow!(
                 "rendering window has been closed, assuming server should exit"
             ));
         }
 
-        let buffer = unsafe { mem::transmute::<_, &[u32]>(pixmap.get_color_data()) };
-        window
-            .update_with_buffer(buffer, width, height)
-            .expect("Could not update window data");
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            fullscreen = !fullscreen;
+            window = open_window(window_size(&pixmap, &options), fullscreen)?;
+        }
+        if pixmap.activity().is_some() && window.is_key_pressed(Key::A, KeyRepeat::No) {
+            show_activity = !show_activity;
+        }
+
+        let window_size = window.get_size();
+        match (show_activity, pixmap.activity()) {
+            (true, Some(activity)) => {
+                let colors = activity.render(options.activity_decay);
+                let buffer = scaler.scale(&colors, (width, height), window_size);
+                window.update_with_buffer(buffer, window_size.0, window_size.1)
+            }
+            _ => {
+                let frame = pixmap.present_frame();
+                let buffer = scaler.scale(frame.pixels(), (width, height), window_size);
+                window.update_with_buffer(buffer, window_size.0, window_size.1)
+            }
+        }
+        .expect("Could not update window data");
 
         interval.tick().await;
     }
 }
+
+/// Scales canvas pixels to the size of the window
+#[derive(Debug)]
+struct Scaler {
+    filter: ScalingFilter,
+    keep_aspect_ratio: bool,
+    /// The canvas and window size for which `buffer` is laid out
+    sizes: Option<((usize, usize), (usize, usize))>,
+    /// Where the canvas is placed inside the window as `(x, y, width, height)`
+    target: (usize, usize, usize, usize),
+    buffer: Vec<u32>,
+}
+
+impl Scaler {
+    fn new(filter: ScalingFilter, keep_aspect_ratio: bool) -> Self {
+        Self {
+            filter,
+            keep_aspect_ratio,
+            sizes: None,
+            target: (0, 0, 0, 0),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Scale the pixels of a canvas with size `src` to a window with size `dst`
+    fn scale<'a>(&'a mut self, pixels: &'a [Color], src: (usize, usize), dst: (usize, usize)) -> &'a [u32] {
+        if src == dst {
+            return unsafe { mem::transmute::<&[Color], &[u32]>(pixels) };
+        }
+        if self.sizes != Some((src, dst)) {
+            self.resize(src, dst);
+        }
+
+        let (src_width, src_height) = src;
+        let (dst_width, _) = dst;
+        let (target_x, target_y, target_width, target_height) = self.target;
+        // pixel centers of the target are mapped onto the canvas
+        let ratio_x = src_width as f32 / target_width as f32;
+        let ratio_y = src_height as f32 / target_height as f32;
+        for y in 0..target_height {
+            let src_y = ((y as f32 + 0.5) * ratio_y - 0.5).clamp(0.0, (src_height - 1) as f32);
+            let row = &mut self.buffer[(target_y + y) * dst_width + target_x..][..target_width];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let src_x = ((x as f32 + 0.5) * ratio_x - 0.5).clamp(0.0, (src_width - 1) as f32);
+                let color = match self.filter {
+                    ScalingFilter::Nearest => {
+                        pixels[src_y.round() as usize * src_width + src_x.round() as usize]
+                    }
+                    ScalingFilter::Bilinear => bilinear(pixels, src_width, src_x, src_y),
+                };
+                *pixel = color.into();
+            }
+        }
+        &self.buffer
+    }
+
+    /// Lay the buffer out for new canvas and window sizes
+    fn resize(&mut self, (src_width, src_height): (usize, usize), (dst_width, dst_height): (usize, usize)) {
+        self.target = match self.keep_aspect_ratio {
+            false => (0, 0, dst_width, dst_height),
+            true => {
+                let scale = f32::min(
+                    dst_width as f32 / src_width as f32,
+                    dst_height as f32 / src_height as f32,
+                );
+                let width = ((src_width as f32 * scale).round() as usize).clamp(1, dst_width.max(1));
+                let height = ((src_height as f32 * scale).round() as usize).clamp(1, dst_height.max(1));
+                ((dst_width - width) / 2, (dst_height - height) / 2, width, height)
+            }
+        };
+        // the bars next to the canvas stay black
+        self.buffer.clear();
+        self.buffer.resize(dst_width * dst_height, 0);
+        self.sizes = Some(((src_width, src_height), (dst_width, dst_height)));
+    }
+}
+
+/// Interpolate the color at a fractional position of the canvas from the four surrounding pixels
+fn bilinear(pixels: &[Color], width: usize, x: f32, y: f32) -> Color {
+    let height = pixels.len() / width;
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+    let channels = |x: usize, y: usize| <[u8; 3]>::from(pixels[y * width + x]).map(f32::from);
+    let (top_left, top_right) = (channels(x0, y0), channels(x1, y0));
+    let (bottom_left, bottom_right) = (channels(x0, y1), channels(x1, y1));
+    let channel = |i: usize| {
+        let top = top_left[i] + (top_right[i] - top_left[i]) * tx;
+        let bottom = bottom_left[i] + (bottom_right[i] - bottom_left[i]) * tx;
+        (top + (bottom - top) * ty).round() as u8
+    };
+    Color::from((channel(0), channel(1), channel(2)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scale_keeps_aspect_ratio() {
+        let black = Color::default();
+        let white = Color::from(0xFFFFFF);
+        let pixels = [white, black, black, white];
+
+        let mut scaler = Scaler::new(ScalingFilter::Nearest, true);
+        let scaled = scaler.scale(&pixels, (2, 2), (6, 4)).to_vec();
+        let w = u32::from(white);
+        #[rustfmt::skip]
+        assert_eq!(scaled, vec![
+            0, w, w, 0, 0, 0,
+            0, w, w, 0, 0, 0,
+            0, 0, 0, w, w, 0,
+            0, 0, 0, w, w, 0,
+        ]);
+
+        let mut scaler = Scaler::new(ScalingFilter::Nearest, false);
+        let scaled = scaler.scale(&pixels, (2, 2), (4, 2)).to_vec();
+        assert_eq!(scaled, vec![w, w, 0, 0, 0, 0, w, w]);
+    }
+
+    #[test]
+    fn test_scale_bilinear() {
+        let pixels = [Color::from(0x000000), Color::from(0xFFFFFF)];
+        let mut scaler = Scaler::new(ScalingFilter::Bilinear, false);
+        let scaled = scaler.scale(&pixels, (2, 1), (4, 1)).to_vec();
+        assert_eq!(
+            scaled,
+            [0x000000, 0x404040, 0xBFBFBF, 0xFFFFFF].map(|i| u32::from(Color::from(i)))
+        );
+    }
+}
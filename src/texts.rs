@@ -5,6 +5,17 @@ Available subcommands are:\n\
 HELP\t- This help message\n\
 SIZE\t- Get the current canvas size\n\
 PX\t- Get or set one specific pixels color\n\
+PXRECT\t- Get the colors of a rectangular block of pixels\n\
+CANVAS\t- Select the canvas on which following commands operate, e.g. 'CANVAS default'\n\
+SUBSCRIBE\t- Receive the canvas as STATE regions followed by a PX line for every change until UNSUBSCRIBE\n\
+\t  'SUBSCRIBE <x> <y> <width> <height>' only receives the changes inside that rectangle\n\
+STATE\t- Download a rectangular region of the canvas\n\
+QUIET\t- Stop receiving errors and acknowledgements\n\
+AUTH\t- Authenticate as a member of a team\n\
+RESERVE\t- Reserve a rectangle of the canvas for your team\n\
+CLAIM\t- Announce that you are drawing on a rectangle of the canvas, list claims with CLAIMS\n\
+STATS\t- Get how many pixels you have written if the server counts them\n\
+FILL\t- Set the whole canvas to one color if the server allows it\n\
 \n\
 More detailed descriptions about these subcommands is available by sending 'HELP <subcommand>'\n\
 \n\
@@ -19,14 +30,94 @@ Returns the current canvas size.\n\
 This server does not support changing the canvas size at runtime so the result can safely be cached\n";
 
 pub static HELP_PX: &str = "HELP PX\n\
-Syntax:\t\tPX <x> <y> [<rgb>]\n\
+Syntax:\t\tPX <x> <y> [<rgb>|<rgba>]\n\
+\t\tPX <x1> <y1> <x2> <y2> ...\n\
+\t\tPXRECT <x> <y> <width> <height> [<encoding>]\n\
 Response:\t[PX <x> <y> <rgb>]\n\
+\t\tPX <x1> <y1> <rgb1> <x2> <y2> <rgb2> ...\n\
+\t\tPXRECT <x> <y> <width> <height> <encoding> <data>\n\
 \n\
 Gets or sets the pixel color addressed by the coordinates <x> and <y>.\n\
 The mode of operation is determined by the third argument (<rgb>) being present or not.\n\
 If it is present, the pixel will be set to that color and no response will be sent.\n\
 It it is not present, the current color will be returned.\n\
+When more than one coordinate pair is given, the colors of all pixels are returned in a single line.\n\
+PXRECT returns all pixels of the rectangle whose top-left corner is at <x> and <y> like STATE REGION does\n\
+and uses the 'rle64' encoding unless another one is given (see HELP STATE).\n\
 \n\
 <x>\t- X position on the canvas counted from the left side\n\
 <y>\t- Y position on the canvas counted from the top\n\
-<rgb>\t- HEX encoded rgb color (000000 - FFFFFF)\n";
+<rgb>\t- HEX encoded rgb color (000000 - FFFFFF)\n\
+<rgba>\t- HEX encoded rgb color followed by an alpha value (00000000 - FFFFFFFF)\n\
+\t  which is blended onto the current color of the pixel\n\
+\n\
+Pixels can also be set with the 10 byte binary command 'PB' <x:u16> <y:u16> <r> <g> <b> <a>\n\
+in which coordinates are little-endian and no newline follows. The alpha byte is used like in <rgba>.\n";
+
+pub static HELP_STATE: &str = "HELP STATE\n\
+Syntax:\t\tSTATE REGION <x> <y> <width> <height> <encoding>\n\
+Response:\tSTATE REGION <x> <y> <width> <height> <encoding> <data>\n\
+\n\
+Returns the colors of all pixels in the rectangle whose top-left corner is at <x> and <y>.\n\
+A single region may contain at most 16384 pixels (e.g. 128x128) so larger parts of the canvas\n\
+need to be requested as several regions.\n\
+\n\
+<encoding>\t- How <data> is encoded. Either 'rgb64' which are 3-byte RGB values for every pixel\n\
+\t\t  row by row, encoded as base64, or 'rle64' which are 4-byte records of a run length\n\
+\t\t  (1 - 255) followed by the RGB value of the run's pixels, encoded as base64\n";
+
+pub static HELP_QUIET: &str = "HELP QUIET\n\
+Syntax:\t\tQUIET [ON|OFF]\n\
+Response:\tnone\n\
+\n\
+Enables or disables quiet mode for the connection.\n\
+In quiet mode, errors and acknowledgements like those of AUTH and RESERVE are not sent\n\
+so that clients which never read from the connection don't waste bandwidth.\n\
+Responses with data that was explicitly requested, e.g. by PX or SIZE, are still sent.\n";
+
+pub static HELP_AUTH: &str = "HELP AUTH\n\
+Syntax:\t\tAUTH <team> <token>\n\
+Response:\tAUTH <team>\n\
+\n\
+Authenticates the connection as a member of <team> using the access token that was handed out\n\
+by the server operator. Authentication is required before reserving parts of the canvas.\n";
+
+pub static HELP_RESERVE: &str = "HELP RESERVE\n\
+Syntax:\t\tRESERVE <x> <y> <width> <height> <seconds>\n\
+Response:\tRESERVED <x> <y> <width> <height> <seconds>\n\
+\n\
+Reserves the rectangle whose top-left corner is at <x> and <y> of the selected canvas for the\n\
+authenticated team so that only its members can draw there for the given number of seconds.\n\
+Each team holds at most one reservation per canvas which is replaced by the next successful RESERVE.\n\
+The request fails if the rectangle overlaps the reservation of another team.\n";
+
+pub static HELP_CLAIM: &str = "HELP CLAIM\n\
+Syntax:\t\tCLAIM <x> <y> <width> <height> <seconds>\n\
+\t\tCLAIMS\n\
+Response:\tCLAIMED <x> <y> <width> <height> <seconds>\n\
+\t\tCLAIMS <x1> <y1> <width1> <height1> <seconds1> <x2> ...\n\
+\n\
+Announces that you are drawing on the rectangle whose top-left corner is at <x> and <y>\n\
+for the given number of seconds (at most 3600).\n\
+Claims are advisory: they don't keep anybody from drawing but let cooperative clients avoid\n\
+painting over each other. The request fails if the rectangle overlaps another claim.\n\
+Claiming exactly the same rectangle again renews the claim and 0 seconds release it.\n\
+CLAIMS lists all active claims together with the seconds until they expire.\n";
+
+pub static HELP_FILL: &str = "HELP FILL\n\
+Syntax:\t\tCLEAR\n\
+\t\tFILL <rgb>\n\
+Response:\tnone\n\
+\n\
+Sets every pixel of the canvas to black (CLEAR) or to the color <rgb> (FILL).\n\
+These commands are disabled unless the server operator enabled them.\n\
+They fail if parts of the canvas are protected or reserved by another team.\n";
+
+pub static HELP_STATS: &str = "HELP STATS\n\
+Syntax:\t\tSTATS\n\
+Response:\tSTATS <pixels> <total> [<rank>]\n\
+\n\
+Returns how many pixels were written from your network and by all clients together.\n\
+<rank> is your place among the busiest networks and missing if you are not among them.\n\
+The server groups clients by networks, e.g. all addresses of a /24 network count as one,\n\
+and may overestimate <pixels> slightly. The command fails if the server doesn't count pixels.\n";
@@ -0,0 +1,257 @@
+//!
+//! Detection of background tasks which have stopped making progress
+//!
+//! Background tasks like sinks regularly report progress through a [`Heartbeat`] which they obtained from a
+//! [`Watchdog`].
+//! The watchdog runs on its own OS thread so that it keeps working even when the async runtime is completely
+//! blocked, e.g. by a hanging framebuffer ioctl.
+//!
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// What the watchdog does when it detects a stalled task
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchdogAction {
+    /// Only log an error
+    Log,
+    /// Exit the process with a non-zero status so that a supervisor like systemd restarts it
+    Restart,
+}
+
+/// Configuration options for the [`Watchdog`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WatchdogOptions {
+    /// How much longer than its usual interval a task may go without a heartbeat before it is considered stalled
+    pub timeout: Duration,
+    /// What to do once a stalled task is detected
+    pub action: WatchdogAction,
+    /// An `http://` url to which a JSON alert is posted whenever a task stalls or recovers
+    pub webhook: Option<Url>,
+}
+
+/// A handle through which one background task reports that it is still making progress
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    name: Arc<str>,
+    /// The interval in which the task usually beats
+    interval: Duration,
+    epoch: Instant,
+    /// Milliseconds between `epoch` and the last beat
+    last_beat: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    /// Report that the task has just made progress
+    pub fn beat(&self) {
+        self.last_beat
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// How long ago the last beat was
+    fn silence(&self) -> Duration {
+        self.epoch
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_beat.load(Ordering::Relaxed)))
+    }
+}
+
+impl PartialEq for Heartbeat {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.last_beat, &other.last_beat)
+    }
+}
+
+impl Eq for Heartbeat {}
+
+/// A watchdog that notices when background tasks stop reporting heartbeats
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    options: WatchdogOptions,
+    epoch: Instant,
+    heartbeats: Arc<Mutex<Vec<Heartbeat>>>,
+}
+
+impl Watchdog {
+    /// Create a new watchdog which does not watch any tasks yet
+    pub fn new(options: WatchdogOptions) -> Self {
+        Self {
+            options,
+            epoch: Instant::now(),
+            heartbeats: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Register a task which is expected to beat at least once every `interval`
+    pub fn heartbeat(&self, name: &str, interval: Duration) -> Heartbeat {
+        let heartbeat = Heartbeat {
+            name: name.into(),
+            interval,
+            epoch: self.epoch,
+            last_beat: Arc::new(AtomicU64::new(0)),
+        };
+        heartbeat.beat();
+        self.heartbeats.lock().unwrap().push(heartbeat.clone());
+        heartbeat
+    }
+
+    /// Start watching all registered tasks on a background thread
+    ///
+    /// Tasks which are registered later on are watched as well.
+    pub fn start(&self) -> std::io::Result<JoinHandle<()>> {
+        let watchdog = self.clone();
+        std::thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || watchdog.run())
+    }
+
+    /// Stop watching all registered tasks, e.g. because they were stopped to be restarted
+    pub fn forget_heartbeats(&self) {
+        self.heartbeats.lock().unwrap().clear();
+    }
+
+    /// The names of all registered tasks which currently count as stalled
+    pub fn stalled_tasks(&self) -> Vec<String> {
+        self.heartbeats
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|heartbeat| self.is_stalled(heartbeat))
+            .map(|heartbeat| heartbeat.name.to_string())
+            .collect()
+    }
+
+    fn is_stalled(&self, heartbeat: &Heartbeat) -> bool {
+        heartbeat.silence() > heartbeat.interval + self.options.timeout
+    }
+
+    fn run(self) {
+        tracing::info!(
+            "Started watchdog with {}s timeout",
+            self.options.timeout.as_secs_f32()
+        );
+        let mut stalled = Vec::<Heartbeat>::new();
+        loop {
+            std::thread::sleep(Duration::max(
+                self.options.timeout / 4,
+                Duration::from_millis(100),
+            ));
+            let heartbeats = self.heartbeats.lock().unwrap().clone();
+            for heartbeat in heartbeats {
+                let silence = heartbeat.silence();
+                let is_stalled = self.is_stalled(&heartbeat);
+                let was_stalled = stalled.contains(&heartbeat);
+                if is_stalled && !was_stalled {
+                    tracing::error!(
+                        "Task {} has not made progress for {}s",
+                        heartbeat.name,
+                        silence.as_secs()
+                    );
+                    self.alert(&heartbeat.name, "stalled", silence);
+                    if self.options.action == WatchdogAction::Restart {
+                        tracing::error!("Exiting so that the server gets restarted");
+                        std::process::exit(1);
+                    }
+                    stalled.push(heartbeat);
+                } else if !is_stalled && was_stalled {
+                    tracing::info!("Task {} is making progress again", heartbeat.name);
+                    self.alert(&heartbeat.name, "recovered", silence);
+                    stalled.retain(|i| *i != heartbeat);
+                }
+            }
+        }
+    }
+
+    /// Post an alert to the configured webhook
+    fn alert(&self, task: &str, state: &str, silence: Duration) {
+        let Some(url) = &self.options.webhook else {
+            return;
+        };
+        let body = format!(
+            "{{\"task\":\"{task}\",\"state\":\"{state}\",\"silence_secs\":{}}}",
+            silence.as_secs()
+        );
+        if let Err(e) = post(url, "application/json", &[], body.as_bytes()) {
+            tracing::warn!("Could not send watchdog alert to {}: {}", url, e);
+        }
+    }
+}
+
+/// Send a body with the given content type and additional headers to an `http://` url via a blocking POST request
+///
+/// This is used for all webhooks of the server.
+pub(crate) fn post(
+    url: &Url,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> anyhow::Result<()> {
+    if url.scheme() != "http" {
+        return Err(anyhow::anyhow!("only http:// webhooks are supported"));
+    }
+    let host = url.host_str().ok_or(anyhow::anyhow!("webhook url has no host"))?;
+    let addr = (host, url.port().unwrap_or(80))
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow::anyhow!("could not resolve {}", host))?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    tracing::trace!("Webhook responded with {:?}", String::from_utf8_lossy(&response));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_stall_is_alerted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let watchdog = Watchdog::new(WatchdogOptions {
+            timeout: Duration::from_millis(200),
+            action: WatchdogAction::Log,
+            webhook: Some(Url::parse(&format!("http://{}/alert", listener.local_addr().unwrap())).unwrap()),
+        });
+        let healthy = watchdog.heartbeat("healthy", Duration::from_millis(10));
+        let _stuck = watchdog.heartbeat("stuck", Duration::from_millis(10));
+        watchdog.start().unwrap();
+        std::thread::spawn(move || loop {
+            healthy.beat();
+            std::thread::sleep(Duration::from_millis(10));
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = String::new();
+        stream.read_to_string(&mut request).unwrap();
+        assert!(request.starts_with("POST /alert HTTP/1.1\r\n"));
+        assert!(request.ends_with("\"task\":\"stuck\",\"state\":\"stalled\",\"silence_secs\":0}"));
+    }
+}